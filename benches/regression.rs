@@ -0,0 +1,103 @@
+//! Regression benchmarks
+//!
+//! Compiles a few representative programs, times the compiler on the host
+//! and runs each on the host VM and the Z80 emulator. Instructions and
+//! T-states don't depend on the machine running the benchmark, so they are
+//! checked against `benches/baseline.txt`: a program that got slower fails
+//! the run. `cargo bench -- --bless` writes the current counts as the new
+//! baseline.
+
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use kz80_microperl::testing::{self, Outcome};
+use kz80_microperl::Options;
+
+/// Name, source and the output it must print
+const PROGRAMS: &[(&str, &str, &str)] = &[
+    (
+        "fib",
+        "my $x = 0;\nmy $y = 1;\nmy $i = 0;\nwhile ($i < 18) {\n    my $t = $x + $y;\n    $x = $y;\n    $y = $t;\n    $i++;\n}\nprint $x, \"\\n\";\n",
+        "2584\n",
+    ),
+    (
+        "strings",
+        "my $i = 0;\nwhile ($i < 12) {\n    my $bar = \"=\" x $i;\n    print $bar, \"|\\n\";\n    $i++;\n}\n",
+        "|\n=|\n==|\n===|\n====|\n=====|\n======|\n=======|\n========|\n=========|\n==========|\n===========|\n",
+    ),
+    (
+        "regex",
+        "my $hits = 0;\nmy $i = 0;\nwhile ($i < 50) {\n    my $line = \"id 4 status ok\";\n    if ($line =~ /s.atus ok/) { $hits++; }\n    if ($line =~ /id 5./) { $hits++; }\n    $i++;\n}\nprint $hits, \"\\n\";\n",
+        "50\n",
+    ),
+];
+
+const BASELINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/baseline.txt");
+
+/// Compiles per program, taking the fastest
+const COMPILES: u32 = 20;
+
+fn main() -> ExitCode {
+    let bless = std::env::args().any(|arg| arg == "--bless");
+    let baseline = std::fs::read_to_string(BASELINE).map(|text| parse_baseline(&text)).unwrap_or_default();
+    let options = Options::default();
+    let mut failed = false;
+    let mut counts = Vec::new();
+
+    println!("{:<10} {:>10} {:>12} {:>12}", "program", "compile", "VM steps", "T-states");
+    for &(name, source, expected) in PROGRAMS {
+        let compile = (0..COMPILES)
+            .map(|_| {
+                let start = Instant::now();
+                testing::compile(source, &options).unwrap_or_else(|e| panic!("{} doesn't compile:\n{}", name, e));
+                start.elapsed()
+            })
+            .min()
+            .unwrap_or(Duration::ZERO);
+        let vm = run(name, "VM", testing::run_vm(source, &options, b""), expected);
+        let z80 = run(name, "Z80", testing::run_z80(source, &options, b""), expected);
+        println!("{:<10} {:>8}us {:>12} {:>12}", name, compile.as_micros(), vm, z80);
+
+        match baseline.get(name) {
+            Some(&(steps, tstates)) if vm > steps || z80 > tstates => {
+                println!("  slower than the baseline of {} VM steps and {} T-states", steps, tstates);
+                failed = true;
+            }
+            Some(&(steps, tstates)) if vm < steps || z80 < tstates => {
+                println!("  faster than the baseline of {} VM steps and {} T-states; --bless to keep it", steps, tstates);
+            }
+            Some(_) => {}
+            None => println!("  not in the baseline; --bless to add it"),
+        }
+        counts.push(format!("{} {} {}\n", name, vm, z80));
+    }
+
+    if bless {
+        let text = format!("# program, VM steps, Z80 T-states; written by cargo bench -- --bless\n{}", counts.concat());
+        std::fs::write(BASELINE, text).expect("baseline is writable");
+        println!("Wrote {}", BASELINE);
+    } else if failed {
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Instructions or T-states of a run that printed `expected`
+fn run(name: &str, machine: &str, outcome: Result<Outcome, String>, expected: &str) -> u64 {
+    let outcome = outcome.unwrap_or_else(|e| panic!("{} doesn't compile:\n{}", name, e));
+    assert!(outcome.success(), "{} stopped on the {}: {:?}", name, machine, outcome.status);
+    assert_eq!(outcome.stdout, expected, "{} printed the wrong output on the {}", name, machine);
+    outcome.cycles
+}
+
+/// Program -> (VM steps, T-states)
+fn parse_baseline(text: &str) -> BTreeMap<String, (u64, u64)> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [name, steps, tstates] => Some((name.to_string(), (steps.parse().ok()?, tstates.parse().ok()?))),
+            _ => None,
+        })
+        .collect()
+}
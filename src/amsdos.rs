@@ -0,0 +1,64 @@
+//! Amstrad CPC binary files
+//!
+//! AMSDOS stores a 128-byte header in front of a binary file giving its name,
+//! load and entry addresses; `RUN"NAME"` loads the file and calls the entry.
+
+/// Header file type for binaries
+const TYPE_BINARY: u8 = 2;
+
+/// Size of the header in front of the data
+const HEADER_LEN: usize = 128;
+
+/// Wrap `data` in an AMSDOS header that loads it at `load` and runs `entry`
+pub fn binary_file(name: &str, load: u16, entry: u16, data: &[u8]) -> Vec<u8> {
+    let mut h = vec![0u8; HEADER_LEN];
+
+    // Name is 8 + 3 characters, upper case, padded with spaces
+    let mut stem: Vec<u8> = name
+        .bytes()
+        .filter(u8::is_ascii_alphanumeric)
+        .map(|b| b.to_ascii_uppercase())
+        .take(8)
+        .collect();
+    if stem.is_empty() {
+        stem = b"PROGRAM".to_vec();
+    }
+    stem.resize(8, b' ');
+    h[1..9].copy_from_slice(&stem);
+    h[9..12].copy_from_slice(b"BIN");
+
+    let len = data.len() as u16;
+    h[18] = TYPE_BINARY;
+    h[21..23].copy_from_slice(&load.to_le_bytes());
+    h[23] = 0xFF;
+    h[24..26].copy_from_slice(&len.to_le_bytes());
+    h[26..28].copy_from_slice(&entry.to_le_bytes());
+    h[64..67].copy_from_slice(&(data.len() as u32).to_le_bytes()[..3]);
+
+    let checksum: u16 = h[..67].iter().map(|&b| b as u16).sum();
+    h[67..69].copy_from_slice(&checksum.to_le_bytes());
+
+    h.extend_from_slice(data);
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_header() {
+        let file = binary_file("hello-world.mpl", 0x4000, 0x4003, &[1, 2, 3]);
+        assert_eq!(file.len(), HEADER_LEN + 3);
+        assert_eq!(&file[1..12], b"HELLOWORBIN");
+        assert_eq!(file[18], TYPE_BINARY);
+        assert_eq!(u16::from_le_bytes([file[21], file[22]]), 0x4000);
+        assert_eq!(u16::from_le_bytes([file[24], file[25]]), 3);
+        assert_eq!(u16::from_le_bytes([file[26], file[27]]), 0x4003);
+        assert_eq!(&file[64..67], &[3, 0, 0]);
+
+        let sum: u16 = file[..67].iter().map(|&b| b as u16).sum();
+        assert_eq!(u16::from_le_bytes([file[67], file[68]]), sum);
+        assert_eq!(&file[HEADER_LEN..], &[1, 2, 3]);
+    }
+}
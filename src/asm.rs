@@ -0,0 +1,743 @@
+//! Symbolic Z80 assembler
+//!
+//! A small builder for emitting Z80 machine code with named labels and
+//! automatically patched forward references, used to generate the runtime.
+
+/// 8-bit register operands, in Z80 encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg8 {
+    B = 0,
+    C = 1,
+    D = 2,
+    E = 3,
+    H = 4,
+    L = 5,
+    /// (HL) - memory addressed by HL
+    HLInd = 6,
+    A = 7,
+}
+
+/// 16-bit register pairs, in Z80 encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg16 {
+    BC = 0,
+    DE = 1,
+    HL = 2,
+    /// SP for arithmetic/loads, AF for push/pop
+    SP = 3,
+}
+
+/// Register pairs usable with PUSH/POP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackReg {
+    BC = 0,
+    DE = 1,
+    HL = 2,
+    AF = 3,
+}
+
+/// Branch conditions, in Z80 encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    NZ = 0,
+    Z = 1,
+    NC = 2,
+    C = 3,
+    PO = 4,
+    PE = 5,
+    P = 6,
+    M = 7,
+}
+
+/// 8-bit accumulator operations, in Z80 encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alu {
+    Add = 0,
+    Adc = 1,
+    Sub = 2,
+    Sbc = 3,
+    And = 4,
+    Xor = 5,
+    Or = 6,
+    Cp = 7,
+}
+
+/// CB-prefixed rotate/shift operations, in Z80 encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rot {
+    Rlc = 0,
+    Rrc = 1,
+    Rl = 2,
+    Rr = 3,
+    Sla = 4,
+    Sra = 5,
+    Sll = 6,
+    Srl = 7,
+}
+
+/// A code location, possibly not yet bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Clone, Copy)]
+enum FixupKind {
+    /// 16-bit absolute address
+    Abs16,
+    /// 8-bit signed displacement relative to the following byte
+    Rel8,
+}
+
+#[derive(Debug, Clone)]
+struct Fixup {
+    pos: usize,
+    label: Label,
+    kind: FixupKind,
+}
+
+/// Z80 code builder
+pub struct Asm {
+    org: u16,
+    code: Vec<u8>,
+    labels: Vec<Option<u16>>,
+    names: Vec<String>,
+    fixups: Vec<Fixup>,
+    /// (address, length) of bytes emitted as data rather than instructions
+    data: Vec<(u16, usize)>,
+}
+
+impl Asm {
+    /// Create an assembler whose first byte will live at `org`
+    pub fn new(org: u16) -> Self {
+        Asm {
+            org,
+            code: Vec::new(),
+            labels: Vec::new(),
+            names: Vec::new(),
+            fixups: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Address of the next emitted byte
+    pub fn here(&self) -> u16 {
+        self.org.wrapping_add(self.code.len() as u16)
+    }
+
+    /// Number of bytes emitted so far
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Whether no bytes have been emitted yet
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Create a new, unbound label
+    pub fn label(&mut self, name: &str) -> Label {
+        self.labels.push(None);
+        self.names.push(name.to_string());
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind a label to the current address
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label {} bound twice", self.names[label.0]);
+        self.labels[label.0] = Some(self.here());
+    }
+
+    /// Create a label bound to the current address
+    pub fn here_label(&mut self, name: &str) -> Label {
+        let label = self.label(name);
+        self.bind(label);
+        label
+    }
+
+    /// Address of a bound label
+    pub fn addr(&self, label: Label) -> Option<u16> {
+        self.labels[label.0]
+    }
+
+    /// All bound labels as (name, address), in creation order
+    pub fn symbols(&self) -> Vec<(String, u16)> {
+        self.labels
+            .iter()
+            .zip(&self.names)
+            .filter_map(|(addr, name)| addr.map(|a| (name.clone(), a)))
+            .collect()
+    }
+
+    /// Spans emitted with `defb`, `defw` or `pad_to`, as (address, length)
+    pub fn data(&self) -> &[(u16, usize)] {
+        &self.data
+    }
+
+    /// Resolve all forward references and return the machine code
+    pub fn finish(mut self) -> Vec<u8> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let target = self.labels[fixup.label.0]
+                .unwrap_or_else(|| panic!("unbound label: {}", self.names[fixup.label.0]));
+            match fixup.kind {
+                FixupKind::Abs16 => {
+                    self.code[fixup.pos] = target as u8;
+                    self.code[fixup.pos + 1] = (target >> 8) as u8;
+                }
+                FixupKind::Rel8 => {
+                    let next = self.org as i32 + fixup.pos as i32 + 1;
+                    let offset = target as i32 - next;
+                    assert!(
+                        (-128..=127).contains(&offset),
+                        "relative jump to {} out of range ({})",
+                        self.names[fixup.label.0],
+                        offset
+                    );
+                    self.code[fixup.pos] = offset as i8 as u8;
+                }
+            }
+        }
+        self.code
+    }
+
+    // === Raw emission ===
+
+    /// Emit a single byte
+    pub fn db(&mut self, b: u8) {
+        self.code.push(b);
+    }
+
+    /// Emit a sequence of bytes
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Emit a 16-bit little-endian word
+    pub fn dw(&mut self, w: u16) {
+        self.code.push(w as u8);
+        self.code.push((w >> 8) as u8);
+    }
+
+    /// Pad with `fill` up to (not including) the given address
+    pub fn pad_to(&mut self, addr: u16, fill: u8) {
+        assert!(self.here() <= addr, "already past 0x{:04X}", addr);
+        self.mark_data((addr - self.here()) as usize);
+        while self.here() < addr {
+            self.code.push(fill);
+        }
+    }
+
+    /// Emit bytes that are data, not instructions (disassembly shows them
+    /// as DB)
+    pub fn defb(&mut self, bytes: &[u8]) {
+        self.mark_data(bytes.len());
+        self.bytes(bytes);
+    }
+
+    /// Emit a data word, such as the inline address after a RST
+    pub fn defw(&mut self, w: u16) {
+        self.mark_data(2);
+        self.dw(w);
+    }
+
+    fn mark_data(&mut self, len: usize) {
+        if len > 0 {
+            self.data.push((self.here(), len));
+        }
+    }
+
+    fn ref_abs(&mut self, label: Label) {
+        self.fixups.push(Fixup { pos: self.code.len(), label, kind: FixupKind::Abs16 });
+        self.dw(0);
+    }
+
+    fn ref_rel(&mut self, label: Label) {
+        self.fixups.push(Fixup { pos: self.code.len(), label, kind: FixupKind::Rel8 });
+        self.db(0);
+    }
+
+    // === Loads ===
+
+    /// LD r,r'
+    pub fn ld(&mut self, dst: Reg8, src: Reg8) {
+        assert!(!(dst == Reg8::HLInd && src == Reg8::HLInd), "LD (HL),(HL) is HALT");
+        self.db(0x40 | (dst as u8) << 3 | src as u8);
+    }
+
+    /// LD r,n
+    pub fn ld_n(&mut self, dst: Reg8, n: u8) {
+        self.db(0x06 | (dst as u8) << 3);
+        self.db(n);
+    }
+
+    /// LD rr,nn
+    pub fn ld_nn(&mut self, dst: Reg16, nn: u16) {
+        self.db(0x01 | (dst as u8) << 4);
+        self.dw(nn);
+    }
+
+    /// LD rr,label
+    pub fn ld_label(&mut self, dst: Reg16, label: Label) {
+        self.db(0x01 | (dst as u8) << 4);
+        self.ref_abs(label);
+    }
+
+    /// LD rr,(nn)
+    pub fn ld_from(&mut self, dst: Reg16, addr: u16) {
+        if dst == Reg16::HL {
+            self.db(0x2A);
+        } else {
+            self.db(0xED);
+            self.db(0x4B | (dst as u8) << 4);
+        }
+        self.dw(addr);
+    }
+
+    /// LD (nn),rr
+    pub fn ld_to(&mut self, addr: u16, src: Reg16) {
+        if src == Reg16::HL {
+            self.db(0x22);
+        } else {
+            self.db(0xED);
+            self.db(0x43 | (src as u8) << 4);
+        }
+        self.dw(addr);
+    }
+
+    /// LD A,(nn)
+    pub fn ld_a_from(&mut self, addr: u16) {
+        self.db(0x3A);
+        self.dw(addr);
+    }
+
+    /// LD (nn),A
+    pub fn ld_a_to(&mut self, addr: u16) {
+        self.db(0x32);
+        self.dw(addr);
+    }
+
+    /// LD A,(BC) / LD A,(DE)
+    pub fn ld_a_ind(&mut self, src: Reg16) {
+        match src {
+            Reg16::BC => self.db(0x0A),
+            Reg16::DE => self.db(0x1A),
+            _ => panic!("LD A,({:?}) is not encodable", src),
+        }
+    }
+
+    /// LD (BC),A / LD (DE),A
+    pub fn ld_ind_a(&mut self, dst: Reg16) {
+        match dst {
+            Reg16::BC => self.db(0x02),
+            Reg16::DE => self.db(0x12),
+            _ => panic!("LD ({:?}),A is not encodable", dst),
+        }
+    }
+
+    /// LD SP,HL
+    pub fn ld_sp_hl(&mut self) {
+        self.db(0xF9);
+    }
+
+    /// EX DE,HL
+    pub fn ex_de_hl(&mut self) {
+        self.db(0xEB);
+    }
+
+    /// EX (SP),HL
+    pub fn ex_sp_hl(&mut self) {
+        self.db(0xE3);
+    }
+
+    /// LDIR
+    pub fn ldir(&mut self) {
+        self.db(0xED);
+        self.db(0xB0);
+    }
+
+    /// LDDR
+    pub fn lddr(&mut self) {
+        self.db(0xED);
+        self.db(0xB8);
+    }
+
+    // === Stack ===
+
+    /// PUSH rr
+    pub fn push(&mut self, rr: StackReg) {
+        self.db(0xC5 | (rr as u8) << 4);
+    }
+
+    /// POP rr
+    pub fn pop(&mut self, rr: StackReg) {
+        self.db(0xC1 | (rr as u8) << 4);
+    }
+
+    // === Index register IX ===
+
+    /// PUSH IX
+    pub fn push_ix(&mut self) {
+        self.bytes(&[0xDD, 0xE5]);
+    }
+
+    /// POP IX
+    pub fn pop_ix(&mut self) {
+        self.bytes(&[0xDD, 0xE1]);
+    }
+
+    /// LD IX,nn
+    pub fn ld_ix_nn(&mut self, nn: u16) {
+        self.bytes(&[0xDD, 0x21]);
+        self.dw(nn);
+    }
+
+    /// ADD IX,SP
+    pub fn add_ix_sp(&mut self) {
+        self.bytes(&[0xDD, 0x39]);
+    }
+
+    /// LD SP,IX
+    pub fn ld_sp_ix(&mut self) {
+        self.bytes(&[0xDD, 0xF9]);
+    }
+
+    /// LD r,(IX+d)
+    pub fn ld_from_ix(&mut self, dst: Reg8, d: i8) {
+        assert!(dst != Reg8::HLInd, "LD (HL),(IX+d) does not exist");
+        self.bytes(&[0xDD, 0x46 | (dst as u8) << 3, d as u8]);
+    }
+
+    /// LD (IX+d),r
+    pub fn ld_to_ix(&mut self, d: i8, src: Reg8) {
+        assert!(src != Reg8::HLInd, "LD (IX+d),(HL) does not exist");
+        self.bytes(&[0xDD, 0x70 | src as u8, d as u8]);
+    }
+
+    // === Arithmetic ===
+
+    /// ADD/ADC/SUB/SBC/AND/XOR/OR/CP r
+    pub fn alu(&mut self, op: Alu, src: Reg8) {
+        self.db(0x80 | (op as u8) << 3 | src as u8);
+    }
+
+    /// ADD/ADC/SUB/SBC/AND/XOR/OR/CP n
+    pub fn alu_n(&mut self, op: Alu, n: u8) {
+        self.db(0xC6 | (op as u8) << 3);
+        self.db(n);
+    }
+
+    /// CP n
+    pub fn cp_n(&mut self, n: u8) {
+        self.alu_n(Alu::Cp, n);
+    }
+
+    /// OR r
+    pub fn or(&mut self, src: Reg8) {
+        self.alu(Alu::Or, src);
+    }
+
+    /// XOR r
+    pub fn xor(&mut self, src: Reg8) {
+        self.alu(Alu::Xor, src);
+    }
+
+    /// INC r
+    pub fn inc(&mut self, r: Reg8) {
+        self.db(0x04 | (r as u8) << 3);
+    }
+
+    /// DEC r
+    pub fn dec(&mut self, r: Reg8) {
+        self.db(0x05 | (r as u8) << 3);
+    }
+
+    /// INC rr
+    pub fn inc16(&mut self, rr: Reg16) {
+        self.db(0x03 | (rr as u8) << 4);
+    }
+
+    /// DEC rr
+    pub fn dec16(&mut self, rr: Reg16) {
+        self.db(0x0B | (rr as u8) << 4);
+    }
+
+    /// ADD HL,rr
+    pub fn add_hl(&mut self, rr: Reg16) {
+        self.db(0x09 | (rr as u8) << 4);
+    }
+
+    /// SBC HL,rr
+    pub fn sbc_hl(&mut self, rr: Reg16) {
+        self.db(0xED);
+        self.db(0x42 | (rr as u8) << 4);
+    }
+
+    /// ADC HL,rr
+    pub fn adc_hl(&mut self, rr: Reg16) {
+        self.db(0xED);
+        self.db(0x4A | (rr as u8) << 4);
+    }
+
+    /// NEG
+    pub fn neg(&mut self) {
+        self.db(0xED);
+        self.db(0x44);
+    }
+
+    /// CPL
+    pub fn cpl(&mut self) {
+        self.db(0x2F);
+    }
+
+    /// SCF
+    pub fn scf(&mut self) {
+        self.db(0x37);
+    }
+
+    /// CCF
+    pub fn ccf(&mut self) {
+        self.db(0x3F);
+    }
+
+    /// RLCA
+    pub fn rlca(&mut self) {
+        self.db(0x07);
+    }
+
+    // === Bit operations ===
+
+    /// RLC/RRC/RL/RR/SLA/SRA/SLL/SRL r
+    pub fn rot(&mut self, op: Rot, r: Reg8) {
+        self.db(0xCB);
+        self.db((op as u8) << 3 | r as u8);
+    }
+
+    /// BIT b,r
+    pub fn bit(&mut self, b: u8, r: Reg8) {
+        assert!(b < 8);
+        self.db(0xCB);
+        self.db(0x40 | b << 3 | r as u8);
+    }
+
+    /// SET b,r
+    pub fn set(&mut self, b: u8, r: Reg8) {
+        assert!(b < 8);
+        self.db(0xCB);
+        self.db(0xC0 | b << 3 | r as u8);
+    }
+
+    /// RES b,r
+    pub fn res(&mut self, b: u8, r: Reg8) {
+        assert!(b < 8);
+        self.db(0xCB);
+        self.db(0x80 | b << 3 | r as u8);
+    }
+
+    // === Control flow ===
+
+    /// JP label
+    pub fn jp(&mut self, target: Label) {
+        self.db(0xC3);
+        self.ref_abs(target);
+    }
+
+    /// JP cc,label
+    pub fn jp_cc(&mut self, cc: Cond, target: Label) {
+        self.db(0xC2 | (cc as u8) << 3);
+        self.ref_abs(target);
+    }
+
+    /// JP nn
+    pub fn jp_addr(&mut self, addr: u16) {
+        self.db(0xC3);
+        self.dw(addr);
+    }
+
+    /// JP (HL)
+    pub fn jp_hl(&mut self) {
+        self.db(0xE9);
+    }
+
+    /// JR label
+    pub fn jr(&mut self, target: Label) {
+        self.db(0x18);
+        self.ref_rel(target);
+    }
+
+    /// JR cc,label (only NZ, Z, NC and C are encodable)
+    pub fn jr_cc(&mut self, cc: Cond, target: Label) {
+        assert!((cc as u8) < 4, "JR {:?} is not encodable", cc);
+        self.db(0x20 | (cc as u8) << 3);
+        self.ref_rel(target);
+    }
+
+    /// DJNZ label
+    pub fn djnz(&mut self, target: Label) {
+        self.db(0x10);
+        self.ref_rel(target);
+    }
+
+    /// CALL label
+    pub fn call(&mut self, target: Label) {
+        self.db(0xCD);
+        self.ref_abs(target);
+    }
+
+    /// CALL cc,label
+    pub fn call_cc(&mut self, cc: Cond, target: Label) {
+        self.db(0xC4 | (cc as u8) << 3);
+        self.ref_abs(target);
+    }
+
+    /// CALL nn
+    pub fn call_addr(&mut self, addr: u16) {
+        self.db(0xCD);
+        self.dw(addr);
+    }
+
+    /// RET
+    pub fn ret(&mut self) {
+        self.db(0xC9);
+    }
+
+    /// RET cc
+    pub fn ret_cc(&mut self, cc: Cond) {
+        self.db(0xC0 | (cc as u8) << 3);
+    }
+
+    /// RETI
+    pub fn reti(&mut self) {
+        self.db(0xED);
+        self.db(0x4D);
+    }
+
+    /// RST p
+    pub fn rst(&mut self, p: u8) {
+        assert!(p & !0x38 == 0, "invalid restart vector 0x{:02X}", p);
+        self.db(0xC7 | p);
+    }
+
+    // === CPU control and I/O ===
+
+    /// NOP
+    pub fn nop(&mut self) {
+        self.db(0x00);
+    }
+
+    /// HALT
+    pub fn halt(&mut self) {
+        self.db(0x76);
+    }
+
+    /// DI
+    pub fn di(&mut self) {
+        self.db(0xF3);
+    }
+
+    /// EI
+    pub fn ei(&mut self) {
+        self.db(0xFB);
+    }
+
+    /// IM 0/1/2
+    pub fn im(&mut self, mode: u8) {
+        self.db(0xED);
+        self.db(match mode {
+            0 => 0x46,
+            1 => 0x56,
+            2 => 0x5E,
+            _ => panic!("invalid interrupt mode {}", mode),
+        });
+    }
+
+    /// OUT (n),A
+    pub fn out_n(&mut self, port: u8) {
+        self.db(0xD3);
+        self.db(port);
+    }
+
+    /// IN A,(n)
+    pub fn in_n(&mut self, port: u8) {
+        self.db(0xDB);
+        self.db(port);
+    }
+
+    /// OUT (C),r
+    pub fn out_c(&mut self, r: Reg8) {
+        assert!(r != Reg8::HLInd);
+        self.db(0xED);
+        self.db(0x41 | (r as u8) << 3);
+    }
+
+    /// IN r,(C)
+    pub fn in_c(&mut self, r: Reg8) {
+        assert!(r != Reg8::HLInd);
+        self.db(0xED);
+        self.db(0x40 | (r as u8) << 3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut a = Asm::new(0);
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.ld_n(Reg8::B, 0x12);
+        a.ld_nn(Reg16::SP, 0xFFFE);
+        a.ld_from(Reg16::HL, 0x3000);
+        a.ld_from(Reg16::DE, 0x3006);
+        a.ld_to(0x3000, Reg16::HL);
+        a.push(StackReg::AF);
+        a.pop(StackReg::BC);
+        a.alu(Alu::Or, Reg8::E);
+        a.cp_n(0xF0);
+        a.sbc_hl(Reg16::DE);
+        a.bit(7, Reg8::H);
+        a.rot(Rot::Srl, Reg8::H);
+        a.rlca();
+        assert_eq!(
+            a.finish(),
+            vec![
+                0x7E, 0x06, 0x12, 0x31, 0xFE, 0xFF, 0x2A, 0x00, 0x30, 0xED, 0x5B, 0x06,
+                0x30, 0x22, 0x00, 0x30, 0xF5, 0xC1, 0xB3, 0xFE, 0xF0, 0xED, 0x52, 0xCB,
+                0x7C, 0xCB, 0x3C, 0x07,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_and_backward_labels() {
+        let mut a = Asm::new(0x100);
+        let top = a.here_label("top");
+        let end = a.label("end");
+        a.jp_cc(Cond::Z, end);
+        a.jr(top);
+        a.bind(end);
+        a.djnz(top);
+        let code = a.finish();
+        assert_eq!(code, vec![0xCA, 0x05, 0x01, 0x18, 0xFB, 0x10, 0xF9]);
+    }
+
+    #[test]
+    fn test_data_spans() {
+        let mut a = Asm::new(0x10);
+        a.nop();
+        a.pad_to(0x14, 0xFF);
+        a.rst(0x28);
+        a.defw(0x4504);
+        a.defb(b"ok");
+        a.pad_to(0x1A, 0);
+        assert_eq!(a.data(), &[(0x11, 3), (0x15, 2), (0x17, 2), (0x19, 1)]);
+        assert_eq!(a.finish(), vec![0x00, 0xFF, 0xFF, 0xFF, 0xEF, 0x04, 0x45, b'o', b'k', 0x00]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unbound label")]
+    fn test_unbound_label_panics() {
+        let mut a = Asm::new(0);
+        let missing = a.label("missing");
+        a.jp(missing);
+        a.finish();
+    }
+}
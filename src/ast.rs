@@ -15,6 +15,7 @@ pub enum Expr {
     // Array/Hash access
     ArrayIndex(Box<Expr>, Box<Expr>),   // $arr[idx]
     HashIndex(Box<Expr>, Box<Expr>),    // $hash{key}
+    HashSlice(Box<Expr>, Box<Expr>),    // @hash{keys}, the keys an array
 
     // Binary operations
     BinOp(Box<Expr>, BinOp, Box<Expr>),
@@ -61,6 +62,43 @@ pub enum Expr {
     Deref(Box<Expr>),
 }
 
+/// Constructors that box their operands, for building trees in code
+impl Expr {
+    pub fn scalar(name: &str) -> Expr {
+        Expr::ScalarVar(name.to_string())
+    }
+
+    pub fn call(name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call(name.to_string(), args)
+    }
+
+    pub fn bin(left: Expr, op: BinOp, right: Expr) -> Expr {
+        Expr::BinOp(Box::new(left), op, Box::new(right))
+    }
+
+    pub fn unary(op: UnaryOp, e: Expr) -> Expr {
+        Expr::UnaryOp(op, Box::new(e))
+    }
+
+    pub fn assign(target: Expr, value: Expr) -> Expr {
+        Expr::Assign(Box::new(target), Box::new(value))
+    }
+
+    pub fn op_assign(target: Expr, op: BinOp, value: Expr) -> Expr {
+        Expr::OpAssign(Box::new(target), op, Box::new(value))
+    }
+
+    /// `base[index]`
+    pub fn index(base: Expr, index: Expr) -> Expr {
+        Expr::ArrayIndex(Box::new(base), Box::new(index))
+    }
+
+    /// `base{key}`
+    pub fn key(base: Expr, key: Expr) -> Expr {
+        Expr::HashIndex(Box::new(base), Box::new(key))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum BinOp {
     // Arithmetic
@@ -69,6 +107,7 @@ pub enum BinOp {
     Mul,
     Div,
     Mod,
+    Repeat,
     Pow,
 
     // String
@@ -112,6 +151,22 @@ pub enum UnaryOp {
     Ref,
 }
 
+/// Filehandle named in `print STDERR ...`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Handle {
+    Stdout,
+    Stderr,
+}
+
+impl Handle {
+    pub fn name(self) -> &'static str {
+        match self {
+            Handle::Stdout => "STDOUT",
+            Handle::Stderr => "STDERR",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Stmt {
     // Expression statement
@@ -163,27 +218,55 @@ pub enum Stmt {
         name: String,
         params: Vec<String>,
         body: Vec<Stmt>,
+        /// Marked `:native`, to be compiled to Z80 code in the ROM
+        native: bool,
+        /// The last of `params` is an array, `@rest`, holding the
+        /// arguments past the others
+        variadic: bool,
     },
 
     // Print statements
-    Print(Vec<Expr>),
-    Say(Vec<Expr>),
+    Print(Option<Handle>, Vec<Expr>),
+    Say(Option<Handle>, Vec<Expr>),
+    // printf FORMAT, ARGS;
+    Printf(Option<Handle>, Vec<Expr>),
+
+    // assert COND, MESSAGE;
+    Assert(Expr, Option<Expr>),
 
     // Block
     Block(Vec<Stmt>),
+    /// `BEGIN { ... }`, run on the host while compiling
+    Begin(Vec<Stmt>),
 
     // Use/Package (minimal support)
     Use(String),
     Package(String),
+    /// `do "FILE";`, or `require "FILE";` when `once`, spliced in by the
+    /// loader
+    Include { file: String, once: bool },
+
+    // use constant NAME => value;
+    Constant(String, Expr),
 }
 
 #[derive(Debug, Clone)]
 pub struct Program {
     pub statements: Vec<Stmt>,
+
+    /// Source line of every statement, nested ones included, and of each
+    /// elsif, in the order the parser met them (pre-order)
+    pub lines: Vec<usize>,
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Program {
     pub fn new() -> Self {
-        Program { statements: Vec::new() }
+        Program { statements: Vec::new(), lines: Vec::new() }
     }
 }
@@ -0,0 +1,246 @@
+//! Machine-readable AST dumps
+//!
+//! `--ast-format json` and `--ast-format sexp` write the parse tree for
+//! tools. Every node has a `type`; statements also carry the source `line`
+//! from the parser's line table. Operators are written as in the source.
+//! The S-expression form is the same tree: `(Type :field value ...)` for a
+//! node, `(...)` for a list, `nil` for a missing part.
+
+use crate::ast::{Expr, Handle, Program, Stmt, UnaryOp};
+use crate::json::Json;
+use crate::printer;
+
+/// `program` as a JSON array of statements
+pub fn json(program: &Program) -> Json {
+    let mut dumper = Dumper { lines: &program.lines, next: 0 };
+    dumper.block(&program.statements)
+}
+
+/// `program` as an S-expression, one top-level statement per line
+pub fn sexp(program: &Program) -> String {
+    let Json::Array(stmts) = json(program) else {
+        unreachable!("a program dumps as an array");
+    };
+    stmts.iter().map(|s| format!("{}\n", sexp_value(s))).collect()
+}
+
+fn sexp_value(value: &Json) -> String {
+    match value {
+        Json::Null => "nil".to_string(),
+        Json::Bool(b) => if *b { "t" } else { "nil" }.to_string(),
+        Json::Number(_) | Json::String(_) => value.to_string(),
+        Json::Array(items) => {
+            let items: Vec<String> = items.iter().map(sexp_value).collect();
+            format!("({})", items.join(" "))
+        }
+        Json::Object(members) => {
+            let mut out = String::from("(");
+            for (key, value) in members {
+                match (key.as_str(), value) {
+                    ("type", Json::String(kind)) => out.push_str(kind),
+                    _ => out.push_str(&format!(" :{} {}", key, sexp_value(value))),
+                }
+            }
+            out.push(')');
+            out
+        }
+    }
+}
+
+/// Walks statements in the parser's pre-order to pair them with lines
+struct Dumper<'a> {
+    lines: &'a [usize],
+    next: usize,
+}
+
+impl Dumper<'_> {
+    fn block(&mut self, stmts: &[Stmt]) -> Json {
+        Json::Array(stmts.iter().map(|s| self.stmt(s)).collect())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Json {
+        let line = self.lines.get(self.next).copied();
+        self.next += 1;
+        let node = |kind: &str, fields: Vec<(&str, Json)>| {
+            let mut members = vec![("type".to_string(), Json::from(kind)), ("line".to_string(), line.into())];
+            members.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+            Json::Object(members)
+        };
+        match stmt {
+            Stmt::Expr(e) => node("Expr", vec![("expr", expr(e))]),
+            Stmt::My(vars, init) => node("My", vec![("vars", names(vars)), ("init", opt(init))]),
+            Stmt::Our(vars, init) => node("Our", vec![("vars", names(vars)), ("init", opt(init))]),
+            Stmt::If { cond, then_block, elsif_blocks, else_block } => {
+                let cond = expr(cond);
+                let then = self.block(then_block);
+                let elsif = elsif_blocks
+                    .iter()
+                    .map(|(c, b)| {
+                        let line = self.lines.get(self.next).copied();
+                        self.next += 1;
+                        Json::object([("line", line.into()), ("cond", expr(c)), ("body", self.block(b))])
+                    })
+                    .collect();
+                let other = else_block.as_deref().map_or(Json::Null, |b| self.block(b));
+                node("If", vec![("cond", cond), ("then", then), ("elsif", Json::Array(elsif)), ("else", other)])
+            }
+            Stmt::Unless { cond, then_block, else_block } => {
+                let cond = expr(cond);
+                let then = self.block(then_block);
+                let other = else_block.as_deref().map_or(Json::Null, |b| self.block(b));
+                node("Unless", vec![("cond", cond), ("then", then), ("else", other)])
+            }
+            Stmt::While { cond, body } => node("While", vec![("cond", expr(cond)), ("body", self.block(body))]),
+            Stmt::Until { cond, body } => node("Until", vec![("cond", expr(cond)), ("body", self.block(body))]),
+            Stmt::For { init, cond, step, body } => {
+                let init = init.as_deref().map_or(Json::Null, |s| self.stmt(s));
+                let body = self.block(body);
+                node("For", vec![("init", init), ("cond", opt(cond)), ("step", opt(step)), ("body", body)])
+            }
+            Stmt::Foreach { var, list, body } => {
+                node("Foreach", vec![("var", var.as_str().into()), ("list", expr(list)), ("body", self.block(body))])
+            }
+            Stmt::Last => node("Last", vec![]),
+            Stmt::Next => node("Next", vec![]),
+            Stmt::Return(value) => node("Return", vec![("value", opt(value))]),
+            Stmt::Sub { name, params, body, native, variadic } => {
+                node("Sub", vec![
+                    ("name", name.as_str().into()),
+                    ("params", names(params)),
+                    ("body", self.block(body)),
+                    ("native", Json::Bool(*native)),
+                    ("variadic", Json::Bool(*variadic)),
+                ])
+            }
+            Stmt::Print(h, args) => node("Print", vec![("handle", handle(h)), ("args", exprs(args))]),
+            Stmt::Say(h, args) => node("Say", vec![("handle", handle(h)), ("args", exprs(args))]),
+            Stmt::Printf(h, args) => node("Printf", vec![("handle", handle(h)), ("args", exprs(args))]),
+            Stmt::Assert(cond, message) => node("Assert", vec![("cond", expr(cond)), ("message", opt(message))]),
+            Stmt::Block(body) => node("Block", vec![("body", self.block(body))]),
+            Stmt::Begin(body) => node("Begin", vec![("body", self.block(body))]),
+            Stmt::Use(name) => node("Use", vec![("name", name.as_str().into())]),
+            Stmt::Package(name) => node("Package", vec![("name", name.as_str().into())]),
+            Stmt::Include { file, once } => {
+                node("Include", vec![("file", file.as_str().into()), ("once", Json::Bool(*once))])
+            }
+            Stmt::Constant(name, value) => node("Constant", vec![("name", name.as_str().into()), ("value", expr(value))]),
+        }
+    }
+}
+
+fn names(names: &[String]) -> Json {
+    Json::Array(names.iter().map(|n| n.as_str().into()).collect())
+}
+
+fn exprs(items: &[Expr]) -> Json {
+    Json::Array(items.iter().map(expr).collect())
+}
+
+fn handle(h: &Option<Handle>) -> Json {
+    h.map_or(Json::Null, |h| h.name().into())
+}
+
+fn opt(e: &Option<Expr>) -> Json {
+    e.as_ref().map_or(Json::Null, expr)
+}
+
+fn expr(e: &Expr) -> Json {
+    let node = |kind: &str, fields: Vec<(&str, Json)>| {
+        let mut members = vec![("type".to_string(), Json::from(kind))];
+        members.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+        Json::Object(members)
+    };
+    let boxed = |e: &Expr| expr(e);
+    match e {
+        Expr::Integer(n) => node("Integer", vec![("value", Json::Number(*n as f64))]),
+        Expr::Float(f) => node("Float", vec![("value", Json::Number(*f))]),
+        Expr::String(s) => node("String", vec![("value", s.as_str().into())]),
+        Expr::ScalarVar(name) => node("ScalarVar", vec![("name", name.as_str().into())]),
+        Expr::ArrayVar(name) => node("ArrayVar", vec![("name", name.as_str().into())]),
+        Expr::HashVar(name) => node("HashVar", vec![("name", name.as_str().into())]),
+        Expr::ArrayIndex(base, index) => node("ArrayIndex", vec![("base", boxed(base)), ("index", boxed(index))]),
+        Expr::HashIndex(base, key) => node("HashIndex", vec![("base", boxed(base)), ("key", boxed(key))]),
+        Expr::HashSlice(hash, keys) => node("HashSlice", vec![("hash", boxed(hash)), ("keys", boxed(keys))]),
+        Expr::BinOp(left, op, right) => node(
+            "BinOp",
+            vec![("op", printer::binop(op).0.into()), ("left", boxed(left)), ("right", boxed(right))],
+        ),
+        Expr::UnaryOp(op, operand) => {
+            let op = match op {
+                UnaryOp::Neg => "-",
+                UnaryOp::Not => "!",
+                UnaryOp::BitNot => "~",
+                UnaryOp::Ref => "\\",
+            };
+            node("UnaryOp", vec![("op", op.into()), ("operand", boxed(operand))])
+        }
+        Expr::PreIncrement(target) => node("PreIncrement", vec![("target", boxed(target))]),
+        Expr::PreDecrement(target) => node("PreDecrement", vec![("target", boxed(target))]),
+        Expr::PostIncrement(target) => node("PostIncrement", vec![("target", boxed(target))]),
+        Expr::PostDecrement(target) => node("PostDecrement", vec![("target", boxed(target))]),
+        Expr::Assign(target, value) => node("Assign", vec![("target", boxed(target)), ("value", boxed(value))]),
+        Expr::OpAssign(target, op, value) => node(
+            "OpAssign",
+            vec![("op", printer::binop(op).0.into()), ("target", boxed(target)), ("value", boxed(value))],
+        ),
+        Expr::Call(name, args) => node("Call", vec![("name", name.as_str().into()), ("args", exprs(args))]),
+        Expr::MethodCall(obj, name, args) => node(
+            "MethodCall",
+            vec![("object", boxed(obj)), ("name", name.as_str().into()), ("args", exprs(args))],
+        ),
+        Expr::List(items) => node("List", vec![("items", exprs(items))]),
+        Expr::Hash(pairs) => {
+            let pairs = pairs.iter().map(|(k, v)| Json::object([("key", expr(k)), ("value", expr(v))])).collect();
+            node("Hash", vec![("pairs", Json::Array(pairs))])
+        }
+        Expr::Range(from, to) => node("Range", vec![("from", boxed(from)), ("to", boxed(to))]),
+        Expr::Ternary(cond, then, other) => {
+            node("Ternary", vec![("cond", boxed(cond)), ("then", boxed(then)), ("else", boxed(other))])
+        }
+        Expr::Match(subject, pattern, flags) | Expr::NotMatch(subject, pattern, flags) => node(
+            if matches!(e, Expr::Match(..)) { "Match" } else { "NotMatch" },
+            vec![("subject", boxed(subject)), ("pattern", pattern.as_str().into()), ("flags", flags.as_str().into())],
+        ),
+        Expr::Ref(target) => node("Ref", vec![("target", boxed(target))]),
+        Expr::Deref(target) => node("Deref", vec![("target", boxed(target))]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> Program {
+        Parser::new(Lexer::new(source).tokenize()).parse().unwrap()
+    }
+
+    #[test]
+    fn test_json_dump() {
+        let program = parse("my $x = 1 + 2;\nfor (my $i = 0; $i < 3; $i++) {\n    print $x;\n}\n");
+        let dump = json(&program);
+        let stmts = dump.as_array().unwrap();
+        assert_eq!(
+            stmts[0].to_string(),
+            r#"{"type":"My","line":1,"vars":["x"],"init":{"type":"BinOp","op":"+","left":{"type":"Integer","value":1},"right":{"type":"Integer","value":2}}}"#
+        );
+        let body = stmts[1].get("body").and_then(Json::as_array).unwrap();
+        assert_eq!(stmts[1].get("init").and_then(|s| s.get("line")).and_then(Json::as_usize), Some(2));
+        assert_eq!(body[0].get("line").and_then(Json::as_usize), Some(3));
+        // Output parses back as JSON
+        assert_eq!(json::parse(&dump.to_string()).unwrap(), dump);
+    }
+
+    #[test]
+    fn test_sexp_dump() {
+        let program = parse("if ($x eq \"a\") { say f(1); } else { return; }\n");
+        assert_eq!(
+            sexp(&program),
+            "(If :line 1 :cond (BinOp :op \"eq\" :left (ScalarVar :name \"x\") :right (String :value \"a\")) \
+             :then ((Say :line 1 :handle nil :args ((Call :name \"f\" :args ((Integer :value 1)))))) :elsif () \
+             :else ((Return :line 1 :value nil)))\n"
+        );
+    }
+}
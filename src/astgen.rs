@@ -0,0 +1,276 @@
+//! Random ASTs for property tests
+//!
+//! `Generator` builds programs from a seed, using only the shapes the
+//! parser can produce, so printing one with `printer::program` and parsing
+//! the text must give the same tree back. `round_trip` checks exactly that;
+//! a failure prints the source, and the seed reproduces it.
+
+use crate::ast::{BinOp, Expr, Handle, Program, Stmt, UnaryOp};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::printer;
+
+const SCALARS: &[&str] = &["a", "b", "n", "total"];
+const NAMES: &[&str] = &["f", "g", "size"];
+const MODULES: &[&str] = &["Math", "Strings", "constant"];
+
+const BINARY: &[BinOp] = &[
+    BinOp::Or, BinOp::And,
+    BinOp::Eq, BinOp::Ne, BinOp::Lt, BinOp::Gt, BinOp::Le, BinOp::Ge, BinOp::Cmp,
+    BinOp::StrEq, BinOp::StrNe, BinOp::StrLt, BinOp::StrGt, BinOp::StrLe, BinOp::StrGe, BinOp::StrCmp,
+    BinOp::Add, BinOp::Sub, BinOp::Concat,
+    BinOp::Mul, BinOp::Div, BinOp::Mod,
+];
+const ASSIGNING: &[BinOp] = &[BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div, BinOp::Concat];
+const UNARY: &[UnaryOp] = &[UnaryOp::Neg, UnaryOp::Not, UnaryOp::BitNot];
+
+/// Seeded generator of parseable ASTs
+pub struct Generator {
+    state: u64,
+    /// Expression nesting left
+    expr_depth: usize,
+    /// Block nesting left
+    block_depth: usize,
+}
+
+impl Generator {
+    /// Expressions up to 4 deep and blocks up to 3 deep
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Generator { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1, expr_depth: 4, block_depth: 3 }
+    }
+
+    /// Limit how deep expressions nest
+    pub fn expr_depth(mut self, depth: usize) -> Self {
+        self.expr_depth = depth;
+        self
+    }
+
+    /// Limit how deep blocks nest
+    pub fn block_depth(mut self, depth: usize) -> Self {
+        self.block_depth = depth;
+        self
+    }
+
+    /// A program of `statements` top-level statements
+    pub fn program(&mut self, statements: usize) -> Program {
+        Program { statements: (0..statements).map(|_| self.stmt()).collect(), lines: Vec::new() }
+    }
+
+    pub fn stmt(&mut self) -> Stmt {
+        let compound = if self.block_depth == 0 { 0 } else { 8 };
+        match self.below(12 + compound) {
+            0..=3 => Stmt::Expr(self.expr()),
+            4 => {
+                // The parser reads a hash assigned to a list as its pairs
+                let vars = self.vars();
+                let init = match self.maybe_expr() {
+                    Some(hash @ Expr::HashVar(_)) if vars.len() > 1 => Some(Expr::List(vec![hash])),
+                    init => init,
+                };
+                Stmt::My(vars, init)
+            }
+            5 => Stmt::Our(self.vars(), self.maybe_expr()),
+            6 => Stmt::Last,
+            7 => Stmt::Next,
+            8 => Stmt::Return(self.maybe_expr()),
+            9 => Stmt::Print(self.handle(), self.exprs(3)),
+            10 => match self.below(3) {
+                0 => Stmt::Say(self.handle(), self.exprs(3)),
+                1 => Stmt::Printf(self.handle(), [vec![self.expr()], self.exprs(2)].concat()),
+                _ => Stmt::Assert(self.expr(), self.maybe_expr()),
+            },
+            11 => match self.below(3) {
+                0 => Stmt::Use(self.pick(MODULES).to_string()),
+                1 => Stmt::Package(self.pick(MODULES).to_string()),
+                _ => Stmt::Constant(self.pick(NAMES).to_uppercase(), self.expr()),
+            },
+            12 => match self.below(4) {
+                0 => Stmt::Begin(self.block()),
+                _ => Stmt::Block(self.block()),
+            },
+            13 => {
+                let elsif_blocks = (0..self.below(3)).map(|_| (self.expr(), self.block())).collect();
+                Stmt::If { cond: self.expr(), then_block: self.block(), elsif_blocks, else_block: self.maybe_block() }
+            }
+            14 => Stmt::Unless { cond: self.expr(), then_block: self.block(), else_block: self.maybe_block() },
+            15 => Stmt::While { cond: self.expr(), body: self.block() },
+            16 => Stmt::Until { cond: self.expr(), body: self.block() },
+            17 => {
+                let init = match self.below(3) {
+                    0 => None,
+                    1 => Some(Stmt::My(vec![self.pick(SCALARS).to_string()], Some(self.expr()))),
+                    _ => Some(Stmt::Expr(self.expr())),
+                };
+                Stmt::For {
+                    init: init.map(Box::new),
+                    cond: self.maybe_expr(),
+                    step: self.maybe_expr(),
+                    body: self.block(),
+                }
+            }
+            18 => Stmt::Foreach { var: self.pick(SCALARS).to_string(), list: self.expr(), body: self.block() },
+            _ => {
+                let params = (0..self.below(3)).map(|_| self.pick(SCALARS).to_string()).collect();
+                Stmt::Sub { name: self.pick(NAMES).to_string(), params, body: self.block(), native: false, variadic: false }
+            }
+        }
+    }
+
+    pub fn expr(&mut self) -> Expr {
+        if self.expr_depth == 0 {
+            return self.leaf();
+        }
+        self.expr_depth -= 1;
+        let e = match self.below(20) {
+            0..=4 => self.leaf(),
+            5..=8 => Expr::bin(self.expr(), self.pick(BINARY), self.expr()),
+            9 => Expr::unary(self.pick(UNARY), self.expr()),
+            10 => match self.below(4) {
+                0 => Expr::PreIncrement(Box::new(self.expr())),
+                1 => Expr::PreDecrement(Box::new(self.expr())),
+                2 => Expr::PostIncrement(Box::new(self.expr())),
+                _ => Expr::PostDecrement(Box::new(self.expr())),
+            },
+            11 => match self.below(2) {
+                0 => Expr::assign(self.expr(), self.expr()),
+                _ => Expr::op_assign(self.expr(), self.pick(ASSIGNING), self.expr()),
+            },
+            12 => Expr::Ternary(Box::new(self.expr()), Box::new(self.expr()), Box::new(self.expr())),
+            13 => Expr::call(self.pick(NAMES), self.exprs(3)),
+            14 => Expr::MethodCall(Box::new(self.expr()), self.pick(NAMES).to_string(), self.exprs(2)),
+            15 => match self.below(4) {
+                0 => Expr::index(self.expr(), self.expr()),
+                // `@a{...}` would be a slice
+                1 => match self.expr() {
+                    Expr::ArrayVar(name) => Expr::key(Expr::ScalarVar(name), self.expr()),
+                    base => Expr::key(base, self.expr()),
+                },
+                2 => Expr::index(Expr::Deref(Box::new(self.expr())), self.expr()),
+                _ => Expr::key(Expr::Deref(Box::new(self.expr())), self.expr()),
+            },
+            16 => Expr::List(self.exprs(3)),
+            17 => Expr::Hash((0..self.below(3)).map(|_| (self.expr(), self.expr())).collect()),
+            18 => Expr::Ref(Box::new(self.expr())),
+            _ => {
+                let pattern = ["a", "b+", "^x", "[0-9]"][self.below(4)].to_string();
+                let flags = ["", "i", "g"][self.below(3)].to_string();
+                match self.below(2) {
+                    0 => Expr::Match(Box::new(self.expr()), pattern, flags),
+                    _ => Expr::NotMatch(Box::new(self.expr()), pattern, flags),
+                }
+            }
+        };
+        self.expr_depth += 1;
+        e
+    }
+
+    fn leaf(&mut self) -> Expr {
+        match self.below(8) {
+            0 | 1 => Expr::Integer(self.below(40000) as i32),
+            2 => Expr::Float(self.below(4000) as f64 / 8.0),
+            3 => {
+                let chars = ['a', 'Z', ' ', '"', '\\', '\n', '\t', '$', '@', '#', '\'', '\0', 'é'];
+                Expr::String((0..self.below(5)).map(|_| chars[self.below(chars.len())]).collect())
+            }
+            4 | 5 => Expr::scalar(self.pick(SCALARS)),
+            6 => Expr::ArrayVar(self.pick(SCALARS).to_string()),
+            _ => Expr::HashVar(self.pick(SCALARS).to_string()),
+        }
+    }
+
+    fn handle(&mut self) -> Option<Handle> {
+        match self.below(4) {
+            0 => Some(Handle::Stdout),
+            1 => Some(Handle::Stderr),
+            _ => None,
+        }
+    }
+
+    fn maybe_expr(&mut self) -> Option<Expr> {
+        match self.below(2) {
+            0 => None,
+            _ => Some(self.expr()),
+        }
+    }
+
+    fn exprs(&mut self, most: usize) -> Vec<Expr> {
+        (0..self.below(most + 1)).map(|_| self.expr()).collect()
+    }
+
+    fn vars(&mut self) -> Vec<String> {
+        (0..1 + self.below(3)).map(|_| self.pick(SCALARS).to_string()).collect()
+    }
+
+    fn block(&mut self) -> Vec<Stmt> {
+        self.block_depth -= 1;
+        let body = (0..self.below(4)).map(|_| self.stmt()).collect();
+        self.block_depth += 1;
+        body
+    }
+
+    fn maybe_block(&mut self) -> Option<Vec<Stmt>> {
+        match self.below(2) {
+            0 => None,
+            _ => Some(self.block()),
+        }
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())].clone()
+    }
+
+    /// A number in `0..n`
+    fn below(&mut self, n: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % n as u64) as usize
+    }
+}
+
+/// Print `program`, parse the text and check the same statements come back
+pub fn round_trip(program: &Program) -> Result<(), String> {
+    let source = printer::program(program);
+    let mut lexer = Lexer::new(&source);
+    let tokens = lexer.tokenize();
+    if let Some((c, line, _)) = lexer.unknown() {
+        return Err(format!("line {}: Unexpected character {:?} in:\n{}", line, c, source));
+    }
+    let mut parser = Parser::new(tokens);
+    let parsed = parser.parse().map_err(|e| format!("line {}: {} in:\n{}", parser.location().0, e, source))?;
+    if parsed.statements != program.statements {
+        return Err(format!("Parsed to a different AST:\n{}", source));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let a = Generator::new(7).program(10);
+        let b = Generator::new(7).program(10);
+        assert_eq!(a.statements, b.statements);
+        assert_ne!(a.statements, Generator::new(8).program(10).statements);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for seed in 0..2000 {
+            let program = Generator::new(seed).program(8);
+            if let Err(e) = round_trip(&program) {
+                panic!("seed {}: {}", seed, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_reports_differences() {
+        // The parser never makes negative literals
+        let program = Program { statements: vec![Stmt::Expr(Expr::Integer(-1))], lines: Vec::new() };
+        assert_eq!(round_trip(&program).unwrap_err(), "Parsed to a different AST:\n-1;\n");
+    }
+}
@@ -0,0 +1,462 @@
+//! Console backends
+//!
+//! A backend drives one kind of console from the runtime. It sets the device
+//! up at startup, writes a byte and waits for one. The runtime emits all
+//! console code through `ConsoleBackend`, so a target is a memory layout plus
+//! a backend.
+
+use crate::asm::{Alu, Asm, Cond, Label, Reg8, StackReg};
+use crate::z80::{PORT_CONSOLE, PORT_STATUS};
+
+/// Emits the console code of the runtime
+pub trait ConsoleBackend {
+    /// Emit startup code that sets the console up. Runs once the Z80 stack is
+    /// set, with interrupts off on bare metal.
+    fn emit_init(&self, _a: &mut Asm, _irq_input: bool) {}
+
+    /// Emit code writing A to the console where the runtime prints. The
+    /// default calls the putc routine.
+    fn emit_write(&self, a: &mut Asm, putc: Label) {
+        a.call(putc);
+    }
+
+    /// Emit the putc routine at `putc`: write A, preserving all registers but
+    /// AF. Backends whose `emit_write` works inline need not emit it.
+    fn emit_putc(&self, a: &mut Asm, putc: Label);
+
+    /// Emit the body of the getc routine, already bound at `getc`: wait for a
+    /// byte and return it in A, preserving BC, DE and HL
+    fn emit_getc(&self, a: &mut Asm, getc: Label, putc: Label);
+
+    /// Data port the IM1 receive handler reads, for consoles that can
+    /// interrupt when a byte arrives
+    fn rx_port(&self) -> Option<u8> {
+        None
+    }
+
+    /// Status port and the bit in it set while a received byte is waiting,
+    /// for consoles a task switch can poll without reading the byte
+    fn rx_ready(&self) -> Option<(u8, u8)> {
+        None
+    }
+}
+
+/// RetroShield console: data and status ports that never make writes wait
+pub struct RetroShieldPort;
+
+impl ConsoleBackend for RetroShieldPort {
+    fn emit_write(&self, a: &mut Asm, _putc: Label) {
+        a.out_n(PORT_CONSOLE);
+    }
+
+    fn emit_putc(&self, _a: &mut Asm, _putc: Label) {}
+
+    fn emit_getc(&self, a: &mut Asm, getc: Label, _putc: Label) {
+        emit_poll_getc(a, getc, PORT_STATUS, 0x01, PORT_CONSOLE);
+    }
+
+    fn rx_port(&self) -> Option<u8> {
+        Some(PORT_CONSOLE)
+    }
+
+    fn rx_ready(&self) -> Option<(u8, u8)> {
+        Some((PORT_STATUS, 0x01))
+    }
+}
+
+/// A console with an error port beside it: writes go to whichever the byte
+/// at `channel` picks, 0 the console and 1 the error port
+pub struct Channels {
+    pub console: Box<dyn ConsoleBackend>,
+    pub port: u8,
+    pub channel: u16,
+}
+
+impl ConsoleBackend for Channels {
+    /// Starts on the console
+    fn emit_init(&self, a: &mut Asm, irq_input: bool) {
+        self.console.emit_init(a, irq_input);
+        a.xor(Reg8::A);
+        a.ld_a_to(self.channel);
+    }
+
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        a.bind(putc);
+        a.push(StackReg::AF);
+        a.ld_a_from(self.channel);
+        a.or(Reg8::A);
+        let console = a.label("putc_console");
+        a.jr_cc(Cond::Z, console);
+        a.pop(StackReg::AF);
+        a.out_n(self.port);
+        a.ret();
+        a.bind(console);
+        a.pop(StackReg::AF);
+        let console_putc = a.label("console_putc");
+        self.console.emit_write(a, console_putc);
+        a.ret();
+        self.console.emit_putc(a, console_putc);
+    }
+
+    fn emit_getc(&self, a: &mut Asm, getc: Label, putc: Label) {
+        self.console.emit_getc(a, getc, putc);
+    }
+
+    fn rx_port(&self) -> Option<u8> {
+        self.console.rx_port()
+    }
+
+    fn rx_ready(&self) -> Option<(u8, u8)> {
+        self.console.rx_ready()
+    }
+}
+
+/// 68B50 ACIA: control (write) and status (read) share a port
+pub struct Acia {
+    pub control: u8,
+    pub data: u8,
+}
+
+const ACIA_RESET: u8 = 0x03;        // Master reset
+const ACIA_8N1: u8 = 0x16;          // Clock / 64, 8N1, RTS low
+const ACIA_RX_IRQ: u8 = 0x80;       // Interrupt when a byte arrives
+const ACIA_RDRF: u8 = 0x01;         // Status: receive data register full
+const ACIA_TDRE: u8 = 0x02;         // Status: transmit data register empty
+
+impl ConsoleBackend for Acia {
+    fn emit_init(&self, a: &mut Asm, irq_input: bool) {
+        let irq = if irq_input { ACIA_RX_IRQ } else { 0 };
+        emit_out(a, self.control, &[ACIA_RESET, ACIA_8N1 | irq]);
+    }
+
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        emit_wait_putc(a, putc, self.control, ACIA_TDRE, self.data);
+    }
+
+    fn emit_getc(&self, a: &mut Asm, getc: Label, _putc: Label) {
+        emit_poll_getc(a, getc, self.control, ACIA_RDRF, self.data);
+    }
+
+    fn rx_port(&self) -> Option<u8> {
+        Some(self.data)
+    }
+
+    fn rx_ready(&self) -> Option<(u8, u8)> {
+        Some((self.control, ACIA_RDRF))
+    }
+}
+
+/// Z80 SIO/2 channel: control (write register select/value, RR0 on read)
+/// and data ports
+pub struct Sio {
+    pub control: u8,
+    pub data: u8,
+}
+
+const SIO_RX_IRQ: u8 = 0x18;        // WR1: interrupt on every received byte
+const SIO_RX_AVAILABLE: u8 = 0x01;  // RR0: receive character available
+const SIO_TX_EMPTY: u8 = 0x04;      // RR0: transmit buffer empty
+
+impl ConsoleBackend for Sio {
+    fn emit_init(&self, a: &mut Asm, irq_input: bool) {
+        let irq = if irq_input { SIO_RX_IRQ } else { 0 };
+        emit_out(a, self.control, &[
+            0x18,       // WR0: channel reset
+            0x04, 0xC4, // WR4: clock / 64, 1 stop bit, no parity
+            0x01, irq,  // WR1: receive interrupts
+            0x03, 0xE1, // WR3: receive 8 bits, auto enables, enable
+            0x05, 0xEA, // WR5: DTR, transmit 8 bits, enable, RTS
+        ]);
+    }
+
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        emit_wait_putc(a, putc, self.control, SIO_TX_EMPTY, self.data);
+    }
+
+    fn emit_getc(&self, a: &mut Asm, getc: Label, _putc: Label) {
+        emit_poll_getc(a, getc, self.control, SIO_RX_AVAILABLE, self.data);
+    }
+
+    fn rx_port(&self) -> Option<u8> {
+        Some(self.data)
+    }
+
+    fn rx_ready(&self) -> Option<(u8, u8)> {
+        Some((self.control, SIO_RX_AVAILABLE))
+    }
+}
+
+/// Spectrum ROM entry points and system variables
+#[cfg(feature = "target-spectrum")]
+pub(crate) const ZX_CHAN_OPEN: u16 = 0x1601;    // Open the stream in A
+#[cfg(feature = "target-spectrum")]
+pub(crate) const ZX_PRINT: u8 = 0x10;           // RST 16: print the character in A
+#[cfg(feature = "target-spectrum")]
+const ZX_LAST_K: u16 = 0x5C08;                  // Last key pressed
+#[cfg(feature = "target-spectrum")]
+const ZX_FLAGS: u16 = 0x5C3B;                   // Bit 5 set when a new key is in LAST_K
+#[cfg(feature = "target-spectrum")]
+const ZX_SCR_CT: u16 = 0x5C8C;                  // Lines left before "scroll?"
+
+/// ZX Spectrum: upper screen through RST 16, keyboard as scanned by the ROM
+#[cfg(feature = "target-spectrum")]
+pub struct SpectrumRom;
+
+#[cfg(feature = "target-spectrum")]
+impl ConsoleBackend for SpectrumRom {
+    fn emit_init(&self, a: &mut Asm, _irq_input: bool) {
+        // Stream 2 is the upper screen
+        a.ld_n(Reg8::A, 2);
+        a.call_addr(ZX_CHAN_OPEN);
+    }
+
+    /// Turns LF into the Spectrum's ENTER and suppresses the "scroll?" prompt
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        a.bind(putc);
+        a.push(StackReg::BC);
+        a.push(StackReg::DE);
+        a.push(StackReg::HL);
+        a.cp_n(b'\n');
+        let print = a.label("putc_print");
+        a.jr_cc(Cond::NZ, print);
+        a.ld_n(Reg8::A, b'\r');
+        a.bind(print);
+        a.push(StackReg::AF);
+        a.ld_n(Reg8::A, 0xFF);
+        a.ld_a_to(ZX_SCR_CT);
+        a.pop(StackReg::AF);
+        a.rst(ZX_PRINT);
+        a.pop(StackReg::HL);
+        a.pop(StackReg::DE);
+        a.pop(StackReg::BC);
+        a.ret();
+    }
+
+    fn emit_getc(&self, a: &mut Asm, getc: Label, putc: Label) {
+        // The ROM's interrupt handler scans the keyboard and flags each new
+        // key in FLAGS
+        a.ld_a_from(ZX_FLAGS);
+        a.bit(5, Reg8::A);
+        a.jr_cc(Cond::Z, getc);
+        a.res(5, Reg8::A);
+        a.ld_a_to(ZX_FLAGS);
+        a.ld_a_from(ZX_LAST_K);
+        emit_echo_ret(a, putc);
+    }
+}
+
+/// CPC firmware jumpblock
+#[cfg(feature = "target-cpc")]
+const CPC_KM_WAIT_CHAR: u16 = 0xBB06;           // Wait for a key, character in A
+#[cfg(feature = "target-cpc")]
+pub(crate) const CPC_TXT_OUTPUT: u16 = 0xBB5A;  // Print A, preserving all registers
+
+/// Amstrad CPC: text screen and keyboard through the firmware
+#[cfg(feature = "target-cpc")]
+pub struct CpcFirmware;
+
+#[cfg(feature = "target-cpc")]
+impl ConsoleBackend for CpcFirmware {
+    /// Expands LF to CR LF
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        a.bind(putc);
+        a.cp_n(b'\n');
+        let print = a.label("putc_print");
+        a.jr_cc(Cond::NZ, print);
+        a.ld_n(Reg8::A, b'\r');
+        a.call_addr(CPC_TXT_OUTPUT);
+        a.ld_n(Reg8::A, b'\n');
+        a.bind(print);
+        a.jp_addr(CPC_TXT_OUTPUT);
+    }
+
+    fn emit_getc(&self, a: &mut Asm, _getc: Label, putc: Label) {
+        a.call_addr(CPC_KM_WAIT_CHAR);
+        emit_echo_ret(a, putc);
+    }
+}
+
+/// TRS-80 Level II ROM routines
+#[cfg(feature = "target-trs80")]
+const TRS80_KBWAIT: u16 = 0x0049;               // Wait for a key, character in A
+#[cfg(feature = "target-trs80")]
+pub(crate) const TRS80_DSP: u16 = 0x0033;       // Display A at the cursor
+
+/// TRS-80 Model I/III: video and keyboard through the ROM
+#[cfg(feature = "target-trs80")]
+pub struct Trs80Rom;
+
+#[cfg(feature = "target-trs80")]
+impl ConsoleBackend for Trs80Rom {
+    /// Turns LF into the TRS-80's newline (CR)
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        a.bind(putc);
+        a.push(StackReg::BC);
+        a.push(StackReg::DE);
+        a.push(StackReg::HL);
+        a.cp_n(b'\n');
+        let print = a.label("putc_print");
+        a.jr_cc(Cond::NZ, print);
+        a.ld_n(Reg8::A, b'\r');
+        a.bind(print);
+        a.call_addr(TRS80_DSP);
+        a.pop(StackReg::HL);
+        a.pop(StackReg::DE);
+        a.pop(StackReg::BC);
+        a.ret();
+    }
+
+    fn emit_getc(&self, a: &mut Asm, _getc: Label, putc: Label) {
+        a.push(StackReg::DE);
+        a.call_addr(TRS80_KBWAIT);
+        a.pop(StackReg::DE);
+        emit_echo_ret(a, putc);
+    }
+}
+
+/// TI-OS system routines, called with RST 28h followed by the address
+#[cfg(feature = "target-ti83")]
+pub(crate) const TI_BCALL: u8 = 0x28;
+#[cfg(feature = "target-ti83")]
+pub(crate) const TI_PUTC: u16 = 0x4504;         // Display A on the home screen
+#[cfg(feature = "target-ti83")]
+pub(crate) const TI_NEWLINE: u16 = 0x452E;
+#[cfg(feature = "target-ti83")]
+const TI_CLR_LCD_FULL: u16 = 0x4540;
+#[cfg(feature = "target-ti83")]
+const TI_HOME_UP: u16 = 0x4558;                 // Cursor to the top left
+#[cfg(feature = "target-ti83")]
+const TI_GET_KEY: u16 = 0x4972;                 // Wait for a key, key code in A
+#[cfg(feature = "target-ti83")]
+const TI_DEL_RES: u16 = 0x4A20;                 // Invalidate statVars
+
+/// TI-OS key codes
+#[cfg(feature = "target-ti83")]
+const TI_K_ENTER: u8 = 0x05;
+#[cfg(feature = "target-ti83")]
+const TI_K_0: u8 = 0x8E;
+#[cfg(feature = "target-ti83")]
+const TI_K_SPACE: u8 = 0x99;
+#[cfg(feature = "target-ti83")]
+const TI_K_CAP_A: u8 = 0x9A;
+
+/// TI-83+/84+: home screen and keys through TI-OS bcalls
+#[cfg(feature = "target-ti83")]
+pub struct TiOs;
+
+#[cfg(feature = "target-ti83")]
+impl ConsoleBackend for TiOs {
+    fn emit_init(&self, a: &mut Asm, _irq_input: bool) {
+        // statVars hold the VM state from here on
+        emit_bcall(a, TI_DEL_RES);
+        emit_bcall(a, TI_CLR_LCD_FULL);
+        emit_bcall(a, TI_HOME_UP);
+    }
+
+    /// Shows LF as a new line
+    fn emit_putc(&self, a: &mut Asm, putc: Label) {
+        a.bind(putc);
+        a.push(StackReg::BC);
+        a.push(StackReg::DE);
+        a.push(StackReg::HL);
+        a.cp_n(b'\n');
+        let newline = a.label("putc_newline");
+        let done = a.label("putc_done");
+        a.jr_cc(Cond::Z, newline);
+        emit_bcall(a, TI_PUTC);
+        a.jr(done);
+        a.bind(newline);
+        emit_bcall(a, TI_NEWLINE);
+        a.bind(done);
+        a.pop(StackReg::HL);
+        a.pop(StackReg::DE);
+        a.pop(StackReg::BC);
+        a.ret();
+    }
+
+    /// Accepts ENTER, digits, letters (with ALPHA) and space as ASCII,
+    /// ignoring other keys
+    fn emit_getc(&self, a: &mut Asm, _getc: Label, putc: Label) {
+        a.push(StackReg::BC);
+        a.push(StackReg::DE);
+        a.push(StackReg::HL);
+        let wait = a.here_label("getkey_wait");
+        let done = a.label("getkey_done");
+        emit_bcall(a, TI_GET_KEY);
+        // Map key codes first..first+count to ascii..
+        let range = |a: &mut Asm, first: u8, count: u8, ascii: u8| {
+            let next = a.label(&format!("getkey_not_{:02x}", first));
+            a.alu_n(Alu::Sub, first);
+            a.cp_n(count);
+            a.jr_cc(Cond::NC, next);
+            a.alu_n(Alu::Add, ascii);
+            a.jr(done);
+            a.bind(next);
+            a.alu_n(Alu::Add, first);
+        };
+        range(a, TI_K_ENTER, 1, b'\r');
+        range(a, TI_K_0, 10, b'0');
+        range(a, TI_K_SPACE, 1, b' ');
+        range(a, TI_K_CAP_A, 26, b'A');
+        a.jr(wait);
+        a.bind(done);
+        a.pop(StackReg::HL);
+        a.pop(StackReg::DE);
+        a.pop(StackReg::BC);
+        emit_echo_ret(a, putc);
+    }
+}
+
+/// Emit `values` written to `port` in order
+fn emit_out(a: &mut Asm, port: u8, values: &[u8]) {
+    for &value in values {
+        a.ld_n(Reg8::A, value);
+        a.out_n(port);
+    }
+}
+
+/// Emit a getc body that polls `status` until `ready` is set, then reads
+/// `data`
+fn emit_poll_getc(a: &mut Asm, getc: Label, status: u8, ready: u8, data: u8) {
+    a.in_n(status);
+    a.alu_n(Alu::And, ready);
+    a.jr_cc(Cond::Z, getc);
+    a.in_n(data);
+    a.ret();
+}
+
+/// Emit a putc routine that waits for `ready` in `status` before writing
+/// `data`. Preserves all registers.
+fn emit_wait_putc(a: &mut Asm, putc: Label, status: u8, ready: u8, data: u8) {
+    a.bind(putc);
+    a.push(StackReg::AF);
+    let wait = a.here_label("putc_wait");
+    a.in_n(status);
+    a.alu_n(Alu::And, ready);
+    a.jr_cc(Cond::Z, wait);
+    a.pop(StackReg::AF);
+    a.out_n(data);
+    a.ret();
+}
+
+/// Echo the key in A and return it, for keyboards with no terminal to echo.
+/// ENTER comes back as CR, echoed as a newline.
+#[cfg(any(feature = "target-spectrum", feature = "target-cpc", feature = "target-trs80", feature = "target-ti83"))]
+fn emit_echo_ret(a: &mut Asm, putc: Label) {
+    a.push(StackReg::AF);
+    a.cp_n(b'\r');
+    let echo = a.label("getc_echo");
+    a.jr_cc(Cond::NZ, echo);
+    a.ld_n(Reg8::A, b'\n');
+    a.bind(echo);
+    a.call(putc);
+    a.pop(StackReg::AF);
+    a.ret();
+}
+
+/// Emit a TI-OS system call
+#[cfg(feature = "target-ti83")]
+fn emit_bcall(a: &mut Asm, addr: u16) {
+    a.rst(TI_BCALL);
+    a.defw(addr);
+}
@@ -0,0 +1,182 @@
+//! Bank-switched bytecode
+//!
+//! Code that does not fit the address space is split into 16K pages of ROM.
+//! Writing a page number to the bank port maps that page into the window at
+//! 0x4000-0x7FFF, and the runtime maps in the page holding each instruction
+//! before fetching it. The header and string table stay in the fixed page at
+//! 0x0000 with the runtime.
+
+use crate::bytecode::{Module, Op, HEADER, MAGIC, UNBOUNDED_STACK};
+
+/// Size of a ROM page and of the window it is mapped into
+pub const PAGE_SIZE: usize = 0x4000;
+
+/// Where the selected page appears
+pub const WINDOW: u16 = 0x4000;
+
+/// Pages a 16-bit bytecode PC can address
+pub const MAX_PAGES: usize = 4;
+
+/// How the board selects the page in the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Banking {
+    /// Output port of the window's page register
+    pub port: u8,
+    /// ROM page holding the first 16K of code
+    pub first_page: u8,
+}
+
+impl Default for Banking {
+    /// Window 1 of the RC2014 512K ROM 512K RAM module, code from ROM page 1
+    fn default() -> Self {
+        Banking { port: 0x79, first_page: 1 }
+    }
+}
+
+/// Pad `module` with Nops so no instruction straddles a page, relocating
+/// jumps, calls, subs and the line table to match. `fixed_space` is the room
+/// for the header and string table in the fixed page.
+pub fn paginate(module: &Module, fixed_space: usize) -> Result<Module, String> {
+    let fixed = fixed_image(module).len();
+    if fixed > fixed_space {
+        return Err(format!(
+            "Header and strings need {} bytes, but the fixed page has room for {}",
+            fixed, fixed_space
+        ));
+    }
+
+    // New offset of each instruction, and where the address operands are
+    let mut moved = vec![0u16; module.code.len() + 1];
+    let mut targets = Vec::new();
+    let mut code = Vec::new();
+    let mut pc = 0;
+    while pc < module.code.len() {
+        let op = Op::from_byte(module.code[pc]);
+        let size = op.size().min(module.code.len() - pc);
+        if code.len() % PAGE_SIZE + size > PAGE_SIZE {
+            code.resize(code.len().next_multiple_of(PAGE_SIZE), Op::Nop as u8);
+        }
+        if code.len() + size > MAX_PAGES * PAGE_SIZE - 1 {
+            return Err(format!(
+                "Code does not fit in {} banked pages of {}K",
+                MAX_PAGES,
+                PAGE_SIZE / 1024
+            ));
+        }
+        moved[pc] = code.len() as u16;
+        if matches!(op, Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef | Op::Call | Op::Spawn) && size == 3 {
+            targets.push(code.len() + 1);
+        }
+        code.extend_from_slice(&module.code[pc..pc + size]);
+        pc += size;
+    }
+    moved[pc] = code.len() as u16;
+
+    let relocate = |addr: u16| moved.get(addr as usize).copied().unwrap_or(addr);
+    for pos in targets {
+        let addr = relocate(u16::from_le_bytes([code[pos], code[pos + 1]]));
+        code[pos..pos + 2].copy_from_slice(&addr.to_le_bytes());
+    }
+
+    let mut out = module.clone();
+    out.code = code;
+    out.entry = relocate(module.entry);
+    for sub in &mut out.subs {
+        sub.1 = relocate(sub.1);
+    }
+    for line in &mut out.lines {
+        line.0 = relocate(line.0);
+    }
+    Ok(out)
+}
+
+/// The bytecode image for the fixed page: the header, with the string table
+/// straight after it, then the strings. The code lives in the banked pages.
+pub fn fixed_image(module: &Module) -> Vec<u8> {
+    let mut img = MAGIC.to_vec();
+    img.extend((HEADER as u16).to_le_bytes());
+    img.extend((module.code.len() as u16).to_le_bytes());
+    img.extend(module.entry.to_le_bytes());
+    img.extend(module.max_stack().unwrap_or(UNBOUNDED_STACK).to_le_bytes());
+    img.push(module.strings.len() as u8);
+    for s in &module.strings {
+        img.push(s.len() as u8);
+        img.extend_from_slice(s.as_bytes());
+    }
+    img
+}
+
+/// The whole ROM: `fixed` as page 0, then the code from `first_page` on,
+/// with `fill` in between
+pub fn rom_image(fixed: &[u8], code: &[u8], banking: Banking, fill: u8) -> Vec<u8> {
+    assert!(fixed.len() <= PAGE_SIZE, "fixed page overflows into the window");
+    let mut rom = fixed.to_vec();
+    rom.resize(banking.first_page as usize * PAGE_SIZE, fill);
+    rom.extend_from_slice(code);
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` Push instructions, then a jump back to the start
+    fn pushes(count: usize) -> Module {
+        let mut m = Module::new();
+        for i in 0..count {
+            m.emit_word(Op::Push, i as u16);
+        }
+        m.subs.push(("f".to_string(), 3, 0));
+        m.mark_line(7);
+        m.emit_word(Op::Jump, 3);
+        m.emit(Op::Halt);
+        m
+    }
+
+    #[test]
+    fn test_paginate_pads_page_boundaries() {
+        // 5462 * 3 = 16386, so the 5462nd push would straddle the boundary
+        let module = pushes(5462);
+        let paged = paginate(&module, 0x1000).unwrap();
+        assert_eq!(&paged.code[5461 * 3..PAGE_SIZE], &[Op::Nop as u8]);
+        assert_eq!(&paged.code[PAGE_SIZE..PAGE_SIZE + 3], &[Op::Push as u8, 0x55, 0x15]);
+
+        // Nothing after the padding moves but the code itself
+        let jump = PAGE_SIZE + 3;
+        assert_eq!(&paged.code[jump..jump + 3], &[Op::Jump as u8, 3, 0]);
+        assert_eq!(paged.subs[0].1, 3);
+        assert_eq!(paged.lines, vec![(jump as u16, 7)]);
+        assert_eq!(paged.code.len(), module.code.len() + 1);
+
+        // Already paginated code is left alone
+        assert_eq!(paginate(&paged, 0x1000).unwrap().code, paged.code);
+    }
+
+    #[test]
+    fn test_paginate_relocates_targets() {
+        let mut module = pushes(5462);
+        // Jump to the instruction that moves
+        module.emit_word(Op::Jump, 5461 * 3);
+        let paged = paginate(&module, 0x1000).unwrap();
+        let end = paged.code.len();
+        assert_eq!(&paged.code[end - 3..], &[Op::Jump as u8, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn test_paginate_limits() {
+        let mut module = pushes(1);
+        module.add_string(&"x".repeat(200));
+        assert!(paginate(&module, 100).unwrap_err().contains("fixed page"));
+        assert!(paginate(&pushes(22000), 0x1000).unwrap_err().contains("4 banked pages"));
+    }
+
+    #[test]
+    fn test_rom_image() {
+        let banking = Banking { port: 0x79, first_page: 2 };
+        let rom = rom_image(&[1, 2, 3], &[4, 5], banking, 0xFF);
+        assert_eq!(rom.len(), 2 * PAGE_SIZE + 2);
+        assert_eq!(&rom[..3], &[1, 2, 3]);
+        assert_eq!(rom[PAGE_SIZE], 0xFF);
+        assert_eq!(&rom[2 * PAGE_SIZE..], &[4, 5]);
+    }
+}
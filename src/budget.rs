@@ -0,0 +1,122 @@
+//! ROM size budget
+//!
+//! Breaks an image down into the runtime, the padding up to the bytecode,
+//! the bytecode header, code and string table, and checks it against the
+//! limits given with `--max-rom-size` and friends, so a program that has
+//! outgrown its EPROM fails the build rather than the board.
+
+use std::fmt;
+
+use crate::bytecode::{Module, HEADER};
+use crate::z80::{self, RomOptions};
+
+/// Where the bytes of an image go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomSize {
+    pub runtime: usize,
+    /// Fill up to the bytecode origin, and to page boundaries when banked
+    pub padding: usize,
+    pub header: usize,
+    pub bytecode: usize,
+    pub strings: usize,
+}
+
+impl RomSize {
+    pub fn total(&self) -> usize {
+        self.runtime + self.padding + self.header + self.bytecode + self.strings
+    }
+}
+
+impl fmt::Display for RomSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, bytes) in [
+            ("runtime", self.runtime),
+            ("padding", self.padding),
+            ("header", self.header),
+            ("bytecode", self.bytecode),
+            ("strings", self.strings),
+        ] {
+            writeln!(f, "  {:<9}{:>6}", name, bytes)?;
+        }
+        write!(f, "  {:<9}{:>6}", "total", self.total())
+    }
+}
+
+/// Size of the image `z80::generate_rom` builds for `module`
+pub fn measure(module: &Module, options: &RomOptions) -> RomSize {
+    let total = z80::generate_rom(module, options).len();
+    let runtime = z80::runtime_size(&options.for_module(module));
+    let strings = 1 + module.strings.iter().map(|s| 1 + s.len()).sum::<usize>();
+    let bytecode = module.code.len();
+    RomSize { runtime, padding: total - runtime - HEADER - bytecode - strings, header: HEADER, bytecode, strings }
+}
+
+/// Upper bounds on the image and its parts, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub rom: Option<usize>,
+    pub bytecode: Option<usize>,
+    pub strings: Option<usize>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// Check `size`, listing every limit exceeded and the breakdown
+    pub fn check(&self, size: &RomSize) -> Result<(), String> {
+        let mut over = Vec::new();
+        for (what, limit, bytes) in [
+            ("ROM image", self.rom, size.total()),
+            ("Bytecode", self.bytecode, size.bytecode),
+            ("String table", self.strings, size.strings),
+        ] {
+            if let Some(limit) = limit.filter(|&limit| bytes > limit) {
+                over.push(format!("{} is {} bytes, {} over the limit of {}", what, bytes, bytes - limit, limit));
+            }
+        }
+        if over.is_empty() {
+            return Ok(());
+        }
+        Err(format!("{}\n{}", over.join("\n"), size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn module(source: &str) -> Module {
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        Compiler::new().compile(&program).unwrap()
+    }
+
+    #[test]
+    fn test_measure_matches_rom() {
+        let module = module("print \"hello\", 1 + 2;");
+        let options = RomOptions::default();
+        let size = measure(&module, &options);
+        assert_eq!(size.total(), z80::generate_rom(&module, &options).len());
+        assert_eq!(size.bytecode, module.code.len());
+        assert_eq!(size.strings, 1 + 1 + 5);
+        assert_eq!(size.runtime + size.padding, options.target.layout().bytecode_org as usize);
+    }
+
+    #[test]
+    fn test_limits() {
+        let size = RomSize { runtime: 3000, padding: 1000, header: 10, bytecode: 200, strings: 50 };
+        assert!(Limits::default().check(&size).is_ok());
+        assert!(Limits { rom: Some(4260), ..Limits::default() }.check(&size).is_ok());
+
+        let err = Limits { rom: Some(4096), strings: Some(40), ..Limits::default() }.check(&size).unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(lines[0], "ROM image is 4260 bytes, 164 over the limit of 4096");
+        assert_eq!(lines[1], "String table is 50 bytes, 10 over the limit of 40");
+        assert_eq!(lines[2], "  runtime    3000");
+        assert_eq!(lines[7], "  total      4260");
+    }
+}
@@ -4,6 +4,8 @@
 //! All values are 16-bit (matching Z80's register pairs).
 //! Strings and arrays are heap-allocated with 16-bit pointers.
 
+use crate::native::NativeSub;
+
 /// Bytecode opcodes (1 byte each)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -16,10 +18,11 @@ pub enum Op {
     Dup = 0x04,         // Duplicate top of stack
     Swap = 0x05,        // Swap top two stack values
     Over = 0x06,        // Copy second item to top
+    Rot = 0x07,         // Move the third item to the top: [a, b, c] -> [b, c, a]
 
     // Local variables (indexed from frame pointer)
-    LoadLocal = 0x10,   // Load local variable: LDLOC idx
-    StoreLocal = 0x11,  // Store to local variable: STLOC idx
+    LoadLocal = 0x10,   // Load local variable, idx signed words from FP: LDLOC idx
+    StoreLocal = 0x11,  // Store to local variable, idx signed words from FP: STLOC idx
 
     // Global variables (indexed from global table)
     LoadGlobal = 0x12,  // Load global variable: LDGLOB idx_lo idx_hi
@@ -32,6 +35,7 @@ pub enum Op {
     StrIdx = 0x1B,      // Get character at index
     StrCmp = 0x1C,      // Compare strings (-1, 0, 1)
     Substr = 0x1D,      // Substring: substr(str, start, len)
+    Repeat = 0x1E,      // String repeated n times: str x n
 
     // Array operations
     NewArray = 0x20,    // Create new array: NEWARR size
@@ -40,6 +44,8 @@ pub enum Op {
     ArrSet = 0x23,      // Set array element: arr[idx] = val
     ArrPush = 0x24,     // Push onto array end
     ArrPop = 0x25,      // Pop from array end
+    CheckIdx = 0x26,    // Trap unless [arr, idx] on top of stack is in range
+    Argv = 0x27,        // Push the argument array in the parameter block
 
     // Hash operations
     NewHash = 0x28,     // Create new hash
@@ -47,6 +53,7 @@ pub enum Op {
     HashSet = 0x2A,     // Set hash value: hash{key} = val
     HashDel = 0x2B,     // Delete hash key
     HashKeys = 0x2C,    // Get array of keys
+    ArrRepeat = 0x2D,   // Array of the elements repeated n times: (list) x n
 
     // Arithmetic (operate on top two stack values)
     Add = 0x30,         // a + b
@@ -97,14 +104,24 @@ pub enum Op {
     // Subroutine calls
     Call = 0x68,        // Call subroutine: CALL addr_lo addr_hi
     CallNative = 0x69,  // Call native function: CALLNAT idx
-    Return = 0x6A,      // Return from subroutine
-    ReturnVal = 0x6B,   // Return with value
+    Return = 0x6A,      // Return undef, dropping the frame and its arguments: RET argc
+    ReturnVal = 0x6B,   // Return the value on top, dropping the frame and its arguments: RETVAL argc
+    Native = 0x6C,      // Run the sub's Z80 code instead, if the image has it: NATIVE addr_lo addr_hi
+    TailCall = 0x6D,    // Move argc values over the frame's arguments, before a Jump to a sub: TAILCALL argc
 
     // Frame management
-    EnterFrame = 0x70,  // Set up new stack frame: ENTER num_locals
+    EnterFrame = 0x70,  // Set up new stack frame, reserving its `my` slots: ENTER num_locals
     LeaveFrame = 0x71,  // Tear down stack frame
 
+    // Memory
+    Peek = 0x72,        // Read the byte or word at the address on top: PEEK width
+    Poke = 0x73,        // Write the value on top to the address below it, leaving the value: POKE width
+    CheckPoke = 0x74,   // Trap unless the address under [lo, hi] on top is in lo..=hi
+
     // I/O
+    PortOut = 0x75,     // Write the byte on top to the port below it, leaving the byte
+    PortIn = 0x76,      // Read the port on top
+    Select = 0x77,      // Send output to a channel (0 console, 1 errors): SELECT n
     Print = 0x78,       // Print top of stack (auto-detect type)
     PrintStr = 0x79,    // Print as string
     PrintNum = 0x7A,    // Print as number
@@ -112,6 +129,7 @@ pub enum Op {
     PrintLn = 0x7C,     // Print newline
     Input = 0x7D,       // Read line of input
     InputChar = 0x7E,   // Read single character
+    Printf = 0x7F,      // Print args formatted by the string below them: PRINTF argc
 
     // Type operations
     ToNum = 0x80,       // Convert to number
@@ -123,8 +141,21 @@ pub enum Op {
     Match = 0x88,       // Match string against pattern
     Subst = 0x89,       // Substitute pattern
 
+    // Checked arithmetic (runtime error on signed 16-bit overflow)
+    AddChk = 0x90,      // a + b
+    SubChk = 0x91,      // a - b
+    MulChk = 0x92,      // a * b
+
     // Special
     Halt = 0xF0,        // Stop execution
+    Count = 0xF1,       // Bump a coverage counter: COUNT idx
+    Die = 0xF2,         // Stop with the string on top of stack as the error
+    Suspend = 0xF3,     // Leave a snapshot for the next reset, push 1 and stop
+    Resumed = 0xF4,     // Push 1 if this run resumed from a snapshot, else 0
+    Ticks = 0xF5,       // Push the count of timer interrupts
+    Spawn = 0xF6,       // Start a task at addr, pushing its number or 0: SPAWN addr_lo addr_hi
+    Yield = 0xF7,       // Push 0 and run the next task
+    TaskEnd = 0xF8,     // End the running task and run the next
     Debug = 0xFE,       // Debug breakpoint
     Invalid = 0xFF,     // Invalid opcode
 }
@@ -134,32 +165,62 @@ impl Op {
     pub fn size(&self) -> usize {
         match self {
             // No operands
-            Op::Nop | Op::Pop | Op::Dup | Op::Swap | Op::Over |
-            Op::StrLen | Op::StrCat | Op::StrIdx | Op::StrCmp | Op::Substr |
-            Op::ArrLen | Op::ArrGet | Op::ArrSet | Op::ArrPush | Op::ArrPop |
-            Op::NewHash | Op::HashGet | Op::HashSet | Op::HashDel | Op::HashKeys |
+            Op::Nop | Op::Pop | Op::Dup | Op::Swap | Op::Over | Op::Rot |
+            Op::StrLen | Op::StrCat | Op::StrIdx | Op::StrCmp | Op::Substr | Op::Repeat |
+            Op::ArrLen | Op::ArrGet | Op::ArrSet | Op::ArrPush | Op::ArrPop | Op::CheckIdx | Op::Argv |
+            Op::NewHash | Op::HashGet | Op::HashSet | Op::HashDel | Op::HashKeys | Op::ArrRepeat |
             Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::Neg | Op::Inc | Op::Dec |
             Op::BitAnd | Op::BitOr | Op::BitXor | Op::BitNot | Op::Shl | Op::Shr |
             Op::CmpEq | Op::CmpNe | Op::CmpLt | Op::CmpGt | Op::CmpLe | Op::CmpGe | Op::Cmp |
             Op::StrEq | Op::StrNe | Op::StrLt | Op::StrGt | Op::StrLe | Op::StrGe |
             Op::Not | Op::And | Op::Or |
-            Op::Return | Op::ReturnVal | Op::LeaveFrame |
+            Op::LeaveFrame |
             Op::Print | Op::PrintStr | Op::PrintNum | Op::PrintChar | Op::PrintLn |
-            Op::Input | Op::InputChar |
+            Op::Input | Op::InputChar | Op::PortOut | Op::PortIn | Op::CheckPoke |
             Op::ToNum | Op::ToStr | Op::TypeOf | Op::IsDef |
             Op::Match | Op::Subst |
-            Op::Halt | Op::Debug | Op::Invalid => 1,
+            Op::AddChk | Op::SubChk | Op::MulChk |
+            Op::Halt | Op::Die | Op::Suspend | Op::Resumed | Op::Ticks | Op::Yield | Op::TaskEnd | Op::Debug | Op::Invalid => 1,
 
             // 1-byte operand
             Op::PushByte | Op::LoadLocal | Op::StoreLocal |
-            Op::NewArray | Op::CallNative | Op::EnterFrame => 2,
+            Op::NewArray | Op::CallNative | Op::TailCall | Op::EnterFrame | Op::Return | Op::ReturnVal | Op::Printf | Op::Select | Op::Count |
+            Op::Peek | Op::Poke => 2,
 
             // 2-byte operand
             Op::Push | Op::LoadGlobal | Op::StoreGlobal | Op::PushStr |
-            Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef | Op::Call => 3,
+            Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef | Op::Call | Op::Native | Op::Spawn => 3,
         }
     }
 
+    /// Values the instruction pops, then pushes, when that is fixed. Calls,
+    /// returns and frame changes, those that take the count from their
+    /// operand or the stack, and those no runtime has give None.
+    pub fn stack_effect(&self) -> Option<(u8, u8)> {
+        Some(match self {
+            Op::Nop | Op::Jump | Op::Select | Op::Count | Op::CheckIdx | Op::PrintLn |
+            Op::Halt | Op::TaskEnd => (0, 0),
+            Op::Push | Op::PushByte | Op::LoadLocal | Op::LoadGlobal | Op::PushStr | Op::NewArray | Op::Argv |
+            Op::NewHash | Op::Input | Op::InputChar | Op::Suspend | Op::Resumed | Op::Ticks | Op::Spawn |
+            Op::Yield => (0, 1),
+            Op::Pop | Op::StoreLocal | Op::StoreGlobal | Op::JumpIf | Op::JumpIfNot | Op::Print | Op::Die => (1, 0),
+            Op::StrLen | Op::ArrLen | Op::Neg | Op::Inc | Op::Dec | Op::BitNot | Op::Not | Op::IsDef |
+            Op::PortIn | Op::Peek => (1, 1),
+            Op::Dup => (1, 2),
+            Op::CheckPoke => (2, 0),
+            Op::StrCat | Op::Repeat | Op::StrCmp | Op::StrEq | Op::StrNe | Op::StrLt | Op::StrGt | Op::StrLe |
+            Op::StrGe | Op::ArrGet | Op::ArrRepeat | Op::HashGet |
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl |
+            Op::Shr | Op::CmpEq | Op::CmpNe | Op::CmpLt | Op::CmpGt | Op::CmpLe | Op::CmpGe | Op::Cmp |
+            Op::And | Op::Or | Op::AddChk | Op::SubChk | Op::MulChk | Op::PortOut | Op::Poke | Op::Match => (2, 1),
+            Op::Swap => (2, 2),
+            Op::Over => (2, 3),
+            Op::ArrSet | Op::HashSet => (3, 0),
+            Op::Rot => (3, 3),
+            _ => return None,
+        })
+    }
+
     /// Convert from byte
     pub fn from_byte(b: u8) -> Self {
         match b {
@@ -170,6 +231,7 @@ impl Op {
             0x04 => Op::Dup,
             0x05 => Op::Swap,
             0x06 => Op::Over,
+            0x07 => Op::Rot,
             0x10 => Op::LoadLocal,
             0x11 => Op::StoreLocal,
             0x12 => Op::LoadGlobal,
@@ -180,17 +242,21 @@ impl Op {
             0x1B => Op::StrIdx,
             0x1C => Op::StrCmp,
             0x1D => Op::Substr,
+            0x1E => Op::Repeat,
             0x20 => Op::NewArray,
             0x21 => Op::ArrLen,
             0x22 => Op::ArrGet,
             0x23 => Op::ArrSet,
             0x24 => Op::ArrPush,
             0x25 => Op::ArrPop,
+            0x26 => Op::CheckIdx,
+            0x27 => Op::Argv,
             0x28 => Op::NewHash,
             0x29 => Op::HashGet,
             0x2A => Op::HashSet,
             0x2B => Op::HashDel,
             0x2C => Op::HashKeys,
+            0x2D => Op::ArrRepeat,
             0x30 => Op::Add,
             0x31 => Op::Sub,
             0x32 => Op::Mul,
@@ -229,8 +295,16 @@ impl Op {
             0x69 => Op::CallNative,
             0x6A => Op::Return,
             0x6B => Op::ReturnVal,
+            0x6C => Op::Native,
+            0x6D => Op::TailCall,
             0x70 => Op::EnterFrame,
             0x71 => Op::LeaveFrame,
+            0x72 => Op::Peek,
+            0x73 => Op::Poke,
+            0x74 => Op::CheckPoke,
+            0x75 => Op::PortOut,
+            0x76 => Op::PortIn,
+            0x77 => Op::Select,
             0x78 => Op::Print,
             0x79 => Op::PrintStr,
             0x7A => Op::PrintNum,
@@ -238,13 +312,25 @@ impl Op {
             0x7C => Op::PrintLn,
             0x7D => Op::Input,
             0x7E => Op::InputChar,
+            0x7F => Op::Printf,
             0x80 => Op::ToNum,
             0x81 => Op::ToStr,
             0x82 => Op::TypeOf,
             0x83 => Op::IsDef,
             0x88 => Op::Match,
             0x89 => Op::Subst,
+            0x90 => Op::AddChk,
+            0x91 => Op::SubChk,
+            0x92 => Op::MulChk,
             0xF0 => Op::Halt,
+            0xF1 => Op::Count,
+            0xF2 => Op::Die,
+            0xF3 => Op::Suspend,
+            0xF4 => Op::Resumed,
+            0xF5 => Op::Ticks,
+            0xF6 => Op::Spawn,
+            0xF7 => Op::Yield,
+            0xF8 => Op::TaskEnd,
             0xFE => Op::Debug,
             _ => Op::Invalid,
         }
@@ -265,6 +351,11 @@ pub enum NativeFunc {
     Chr = 6,
     Ord = 7,
     Sprintf = 8,
+    Pack = 9,
+    Unpack = 10,
+    BufNew = 11,
+    BufAppend = 12,
+    BufStr = 13,
 
     // Array functions
     Push = 16,
@@ -275,12 +366,18 @@ pub enum NativeFunc {
     Sort = 21,
     Join = 22,
     Split = 23,
+    Splice = 24,
+    Reserve = 25,
 
     // Hash functions
     Keys = 32,
     Values = 33,
     Exists = 34,
     Delete = 35,
+    Each = 36,
+    Pairs = 37,
+    Slice = 38,
+    KeyExists = 39,
 
     // Math functions
     Abs = 48,
@@ -302,8 +399,122 @@ pub enum NativeFunc {
     Exit = 83,
     Sleep = 84,
     Time = 85,
+    Caller = 86,
+    MemStats = 87,
 }
 
+impl NativeFunc {
+    /// The native a call to `name` compiles to and its argument count, for
+    /// the natives the host VM implements
+    pub fn lookup(name: &str) -> Option<(NativeFunc, usize)> {
+        Some(match name {
+            "open" => (NativeFunc::Open, 2),
+            "close" => (NativeFunc::Close, 1),
+            "readline" => (NativeFunc::Read, 1),
+            "write" => (NativeFunc::Write, 2),
+            "eof" => (NativeFunc::Eof, 1),
+            "uc" => (NativeFunc::Uc, 1),
+            "lc" => (NativeFunc::Lc, 1),
+            "each" => (NativeFunc::Each, 1),
+            "unpack" => (NativeFunc::Unpack, 2),
+            "buf_new" => (NativeFunc::BufNew, 0),
+            "buf_append" => (NativeFunc::BufAppend, 2),
+            "buf_str" => (NativeFunc::BufStr, 1),
+            "reserve" => (NativeFunc::Reserve, 2),
+            _ => return None,
+        })
+    }
+
+    /// Arguments the host VM pops for the native, before pushing its
+    /// result; None for those that take a count on top of the stack, and
+    /// those it doesn't have
+    pub fn args(self) -> Option<u8> {
+        Some(match self {
+            NativeFunc::BufNew | NativeFunc::MemStats => 0,
+            NativeFunc::Uc | NativeFunc::Lc | NativeFunc::BufStr | NativeFunc::Each | NativeFunc::Pairs |
+            NativeFunc::Close | NativeFunc::Read | NativeFunc::Eof | NativeFunc::Caller => 1,
+            NativeFunc::Unpack | NativeFunc::BufAppend | NativeFunc::Reserve | NativeFunc::Slice | NativeFunc::Exists |
+            NativeFunc::KeyExists | NativeFunc::Delete | NativeFunc::Open | NativeFunc::Write => 2,
+            NativeFunc::Splice => 4,
+            _ => return None,
+        })
+    }
+
+    pub fn from_byte(b: u8) -> Option<Self> {
+        Some(match b {
+            0 => NativeFunc::Length,
+            1 => NativeFunc::Substr,
+            2 => NativeFunc::Index,
+            3 => NativeFunc::Rindex,
+            4 => NativeFunc::Lc,
+            5 => NativeFunc::Uc,
+            6 => NativeFunc::Chr,
+            7 => NativeFunc::Ord,
+            8 => NativeFunc::Sprintf,
+            9 => NativeFunc::Pack,
+            10 => NativeFunc::Unpack,
+            11 => NativeFunc::BufNew,
+            12 => NativeFunc::BufAppend,
+            13 => NativeFunc::BufStr,
+            16 => NativeFunc::Push,
+            17 => NativeFunc::Pop,
+            18 => NativeFunc::Shift,
+            19 => NativeFunc::Unshift,
+            20 => NativeFunc::Reverse,
+            21 => NativeFunc::Sort,
+            22 => NativeFunc::Join,
+            23 => NativeFunc::Split,
+            24 => NativeFunc::Splice,
+            25 => NativeFunc::Reserve,
+            32 => NativeFunc::Keys,
+            33 => NativeFunc::Values,
+            34 => NativeFunc::Exists,
+            35 => NativeFunc::Delete,
+            36 => NativeFunc::Each,
+            37 => NativeFunc::Pairs,
+            38 => NativeFunc::Slice,
+            39 => NativeFunc::KeyExists,
+            48 => NativeFunc::Abs,
+            49 => NativeFunc::Int,
+            50 => NativeFunc::Rand,
+            51 => NativeFunc::Srand,
+            64 => NativeFunc::Open,
+            65 => NativeFunc::Close,
+            66 => NativeFunc::Read,
+            67 => NativeFunc::Write,
+            68 => NativeFunc::Eof,
+            80 => NativeFunc::Defined,
+            81 => NativeFunc::Ref,
+            82 => NativeFunc::Die,
+            83 => NativeFunc::Exit,
+            84 => NativeFunc::Sleep,
+            85 => NativeFunc::Time,
+            86 => NativeFunc::Caller,
+            87 => NativeFunc::MemStats,
+            _ => return None,
+        })
+    }
+}
+
+/// Bytecode image header: magic, string table offset, code length, entry,
+/// VM stack bytes
+pub const HEADER: usize = 12;
+
+/// First bytes of a bytecode image, with the format's version
+pub const MAGIC: &[u8; 4] = b"MPL\x02";
+
+/// VM stack bytes in the header of a program whose depth has no bound
+pub const UNBOUNDED_STACK: u16 = 0xFFFF;
+
+/// Coverage counters a module can have, one per basic block
+pub const COUNTERS: usize = 256;
+
+/// Tasks that can run at once, the main program's included
+pub const MAX_TASKS: usize = 4;
+
+/// Bytes of heap each spawned task takes for its VM stack
+pub const TASK_STACK: u16 = 256;
+
 /// Compiled bytecode module
 #[derive(Debug, Clone)]
 pub struct Module {
@@ -321,6 +532,29 @@ pub struct Module {
 
     /// Entry point address
     pub entry: u16,
+
+    /// Line table: (code offset, source line) where each statement starts
+    pub lines: Vec<(u16, usize)>,
+
+    /// Main program variable names by local slot, empty for a slot a
+    /// block's variable takes (for the ROM shell)
+    pub locals: Vec<String>,
+
+    /// Subs marked `:native`, compiled to Z80 code when the image is built
+    pub native: Vec<NativeSub>,
+
+    /// Subs whose last parameter is an array of the remaining arguments
+    pub variadic: Vec<String>,
+
+    /// Subs whose result can be undefined, which return their defined bit
+    /// over it
+    pub undef_subs: Vec<String>,
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Module {
@@ -331,6 +565,11 @@ impl Module {
             subs: Vec::new(),
             code: Vec::new(),
             entry: 0,
+            lines: Vec::new(),
+            locals: Vec::new(),
+            native: Vec::new(),
+            variadic: Vec::new(),
+            undef_subs: Vec::new(),
         }
     }
 
@@ -367,9 +606,274 @@ impl Module {
         self.code.len() as u16
     }
 
+    /// Record that code emitted from here on comes from source `line`
+    pub fn mark_line(&mut self, line: usize) {
+        let pos = self.pos();
+        match self.lines.last_mut() {
+            Some(last) if last.1 == line => {}
+            // A statement that emitted nothing yields to the next one
+            Some(last) if last.0 == pos => last.1 = line,
+            _ => self.lines.push((pos, line)),
+        }
+    }
+
+    /// Source line of the code at `offset`
+    pub fn line_at(&self, offset: u16) -> Option<usize> {
+        let idx = self.lines.partition_point(|&(pos, _)| pos <= offset);
+        idx.checked_sub(1).map(|i| self.lines[i].1)
+    }
+
+    /// Name of the sub whose body holds the code at `offset`. Each body is
+    /// skipped over by the Jump just before it.
+    pub fn sub_at(&self, offset: u16) -> Option<&str> {
+        self.subs
+            .iter()
+            .filter(|(_, addr, _)| {
+                let jump = *addr as usize - 3;
+                let end = self.code.get(jump + 1..jump + 3).map(|w| u16::from_le_bytes([w[0], w[1]]));
+                *addr <= offset && self.code.get(jump) == Some(&(Op::Jump as u8)) && end.is_some_and(|end| offset < end)
+            })
+            .max_by_key(|(_, addr, _)| *addr)
+            .map(|(name, _, _)| name.as_str())
+    }
+
+    /// Whether the code reads input, a line or a key
+    pub fn reads_input(&self) -> bool {
+        let mut pc = 0;
+        while pc < self.code.len() {
+            let op = Op::from_byte(self.code[pc]);
+            if matches!(op, Op::Input | Op::InputChar) {
+                return true;
+            }
+            pc += op.size();
+        }
+        false
+    }
+
+    /// Emit a numbered menu of `items`, (label, address): print it, then
+    /// wait for a key from 1 and echo it. With `call`, Call the chosen
+    /// address and show the menu again when it returns; otherwise Jump
+    /// there. Other keys are ignored. Returns the menu's offset.
+    pub fn emit_menu(&mut self, items: &[(String, u16)], call: bool) -> u16 {
+        let menu = self.pos();
+        for (i, (label, _)) in items.iter().enumerate() {
+            let idx = self.add_string(&format!("{}) {}\n", i + 1, label));
+            self.emit_word(Op::PushStr, idx);
+            self.emit(Op::Print);
+        }
+        let prompt = self.add_string("? ");
+        self.emit_word(Op::PushStr, prompt);
+        self.emit(Op::Print);
+        let key = self.pos();
+        self.emit(Op::InputChar);
+        for (i, &(_, addr)) in items.iter().enumerate() {
+            let digit = b'1' + i as u8;
+            self.emit(Op::Dup);
+            self.emit_byte(Op::PushByte, digit);
+            self.emit(Op::CmpEq);
+            let skip = self.pos() as usize + 1;
+            self.emit_word(Op::JumpIfNot, 0);
+            self.emit(Op::Pop);
+            let echo = self.add_string(&format!("{}\n", digit as char));
+            self.emit_word(Op::PushStr, echo);
+            self.emit(Op::Print);
+            if call {
+                self.emit_word(Op::Call, addr);
+                self.emit(Op::Pop);
+                self.emit_word(Op::Jump, menu);
+            } else {
+                self.emit_word(Op::Jump, addr);
+            }
+            let next = self.pos();
+            self.patch_addr(skip, next);
+        }
+        self.emit(Op::Pop);
+        self.emit_word(Op::Jump, key);
+        menu
+    }
+
     /// Patch a 16-bit address at the given position
     pub fn patch_addr(&mut self, pos: usize, addr: u16) {
         self.code[pos] = addr as u8;
         self.code[pos + 1] = (addr >> 8) as u8;
     }
+
+    /// Check that the module fits the image format: 16-bit offsets, and
+    /// string lengths and the string count in a byte
+    pub fn check_limits(&self) -> Result<(), String> {
+        if HEADER + self.code.len() > u16::MAX as usize {
+            return Err(format!(
+                "Program too large: {} bytes of bytecode, the limit is {}",
+                self.code.len(),
+                u16::MAX as usize - HEADER
+            ));
+        }
+        if let Some(s) = self.strings.iter().find(|s| s.len() > u8::MAX as usize) {
+            return Err(format!("String constant too long: {} bytes, the limit is {}", s.len(), u8::MAX));
+        }
+        if self.strings.len() > u8::MAX as usize {
+            return Err(format!("Too many string constants: {}, the limit is {}", self.strings.len(), u8::MAX));
+        }
+        Ok(())
+    }
+
+    /// Most bytes the VM stack holds while the program runs, or None when
+    /// that has no bound (see `stack`)
+    pub fn max_stack(&self) -> Option<u16> {
+        crate::stack::max_depth(self)
+    }
+
+    /// Bytecode image: header, code and string table, as loaded at
+    /// BYTECODE_ORG
+    pub fn image(&self) -> Vec<u8> {
+        let mut img = Vec::new();
+
+        img.extend_from_slice(MAGIC);
+
+        // String table offset (after header + code)
+        // Header: magic(4) + strtab_offset(2) + code_len(2) + entry(2) + stack(2) = 12 bytes
+        let code_start = HEADER as u16;
+        let string_table_offset = code_start + self.code.len() as u16;
+        img.push(string_table_offset as u8);
+        img.push((string_table_offset >> 8) as u8);
+
+        // Code length
+        img.push(self.code.len() as u8);
+        img.push((self.code.len() >> 8) as u8);
+
+        // Entry point
+        img.push(self.entry as u8);
+        img.push((self.entry >> 8) as u8);
+
+        // VM stack the runtime keeps clear of the heap
+        img.extend(self.max_stack().unwrap_or(UNBOUNDED_STACK).to_le_bytes());
+
+        // Bytecode
+        img.extend_from_slice(&self.code);
+
+        // String table
+        img.push(self.strings.len() as u8);
+        for s in &self.strings {
+            img.push(s.len() as u8);
+            img.extend_from_slice(s.as_bytes());
+        }
+
+        img
+    }
+
+    /// Read back an image written by `image`. Bytes past its string table
+    /// are left alone, so it may sit at the start of a larger ROM. Sub
+    /// names and the line table aren't in an image, so those come back
+    /// empty.
+    pub fn from_image(img: &[u8]) -> Result<Module, String> {
+        let word = |at: usize| img.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+        if !img.starts_with(MAGIC) {
+            return Err("Not a MicroPerl bytecode image".to_string());
+        }
+        let (Some(strings_at), Some(code_len), Some(entry)) = (word(4), word(6), word(8)) else {
+            return Err("Bytecode image header is cut short".to_string());
+        };
+        if strings_at != HEADER + code_len || entry > code_len {
+            return Err("Bytecode image header doesn't match its code".to_string());
+        }
+        let Some(code) = img.get(HEADER..strings_at) else {
+            return Err("Bytecode image is cut short in its code".to_string());
+        };
+
+        let mut module = Module::new();
+        module.code = code.to_vec();
+        module.entry = entry as u16;
+        let mut at = strings_at + 1;
+        for _ in 0..*img.get(strings_at).ok_or("Bytecode image has no string table")? {
+            let len = *img.get(at).ok_or("Bytecode image is cut short in its strings")? as usize;
+            let text = img.get(at + 1..at + 1 + len).ok_or("Bytecode image is cut short in its strings")?;
+            module.strings.push(String::from_utf8_lossy(text).into_owned());
+            at += 1 + len;
+        }
+        Ok(module)
+    }
+
+    /// The first bytecode image in `rom` that reads back whole, with its
+    /// offset
+    pub fn find_image(rom: &[u8]) -> Option<(usize, Module)> {
+        rom.windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == MAGIC)
+            .find_map(|(at, _)| Module::from_image(&rom[at..]).ok().map(|m| (at, m)))
+    }
+}
+
+/// Listing of `code`, one instruction per line with its offset and operand
+pub fn disassemble(code: &[u8]) -> String {
+    let mut out = String::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = Op::from_byte(code[pc]);
+        let size = op.size();
+
+        out.push_str(&format!("  {:04X}: {:?}", pc, op));
+
+        match size {
+            2 if pc + 1 < code.len() => {
+                out.push_str(&format!(" 0x{:02X}", code[pc + 1]));
+            }
+            3 if pc + 2 < code.len() => {
+                let addr = code[pc + 1] as u16 | ((code[pc + 2] as u16) << 8);
+                out.push_str(&format!(" 0x{:04X}", addr));
+            }
+            _ => {}
+        }
+        out.push('\n');
+
+        pc += size;
+    }
+    out
+}
+
+/// Apply a checked operator to `a` and `b` as signed numbers. Returns None
+/// on overflow.
+pub(crate) fn checked(op: Op, a: u16, b: u16) -> Option<u16> {
+    let (a, b) = (a as i16, b as i16);
+    let v = match op {
+        Op::AddChk => a.checked_add(b),
+        Op::SubChk => a.checked_sub(b),
+        _ => a.checked_mul(b),
+    };
+    v.map(|v| v as u16)
+}
+
+/// Apply a binary operator to `a` (second from top) and `b` (top). Returns
+/// None on division by zero.
+pub(crate) fn binary(op: Op, a: u16, b: u16) -> Option<u16> {
+    // Ordering follows the runtime: the sign bit of the 16-bit difference
+    let lt = |x: u16, y: u16| x.wrapping_sub(y) & 0x8000 != 0;
+    Some(match op {
+        Op::Add => a.wrapping_add(b),
+        Op::Sub => a.wrapping_sub(b),
+        Op::Mul => a.wrapping_mul(b),
+        Op::Div => a.checked_div(b)?,
+        Op::Mod => a.checked_rem(b)?,
+        Op::BitAnd => a & b,
+        Op::BitOr => a | b,
+        Op::BitXor => a ^ b,
+        Op::Shl => a.checked_shl(b as u32).unwrap_or(0),
+        Op::Shr => a.checked_shr(b as u32).unwrap_or(0),
+        Op::CmpEq => (a == b) as u16,
+        Op::CmpNe => (a != b) as u16,
+        Op::CmpLt => lt(a, b) as u16,
+        Op::CmpGt => lt(b, a) as u16,
+        Op::CmpLe => !lt(b, a) as u16,
+        Op::CmpGe => !lt(a, b) as u16,
+        Op::Cmp => {
+            if a == b {
+                0
+            } else if lt(a, b) {
+                0xFFFF
+            } else {
+                1
+            }
+        }
+        Op::And => (a != 0 && b != 0) as u16,
+        _ => (a != 0 || b != 0) as u16,
+    })
 }
@@ -0,0 +1,141 @@
+//! C source and Intel HEX exports of the ROM image
+//!
+//! The RetroShield's Arduino sketch serves the Z80's ROM reads from a PROGMEM
+//! array, so the image is written out as C source to drop into the sketch.
+//! Other firmware can include it as a plain header instead, and EPROM
+//! programmers take Intel HEX.
+
+/// Bytes per line of array initialiser
+const ROW: usize = 16;
+
+/// Arduino sketch fragment holding `rom` as the `rom_bin` PROGMEM array with
+/// its address range, in the form the RetroShield Z80 sketch expects.
+/// `name` only appears in the comment at the top.
+pub fn arduino_sketch(name: &str, rom: &[u8], org: u16) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("// MicroPerl ROM for {}: {} bytes at 0x{:04X}\n", name, rom.len(), org));
+    out.push_str("// Replace the rom_bin array and ROM_START/ROM_END in the RetroShield Z80\n");
+    out.push_str("// sketch with the lines below.\n\n");
+    out.push_str(&format!("#define ROM_START   0x{:04X}\n", org));
+    out.push_str("#define ROM_END     (ROM_START + sizeof(rom_bin))\n\n");
+    out.push_str("PROGMEM const unsigned char rom_bin[] = {\n");
+    out.push_str(&byte_rows(rom));
+    out.push_str("};\n");
+    out
+}
+
+/// C header declaring `image` as `<name>_rom`, with `<NAME>_LEN`,
+/// `<NAME>_LOAD_ADDR` and `<NAME>_BYTECODE_ADDR` macros. `name` is reduced
+/// to a C identifier.
+pub fn c_header(name: &str, image: &[u8], org: u16, bytecode_org: u16) -> String {
+    let ident = identifier(name);
+    let upper = ident.to_ascii_uppercase();
+    let mut out = String::new();
+    out.push_str(&format!("/* MicroPerl image for {}, generated by microperl */\n", name));
+    out.push_str(&format!("#ifndef {}_ROM_H\n#define {}_ROM_H\n\n", upper, upper));
+    out.push_str("#include <stdint.h>\n\n");
+    out.push_str(&format!("#define {}_LEN {}u\n", upper, image.len()));
+    out.push_str(&format!("#define {}_LOAD_ADDR 0x{:04X}u\n", upper, org));
+    out.push_str(&format!("#define {}_BYTECODE_ADDR 0x{:04X}u\n\n", upper, bytecode_org));
+    out.push_str(&format!("static const uint8_t {}_rom[{}_LEN] = {{\n", ident, upper));
+    out.push_str(&byte_rows(image));
+    out.push_str("};\n\n");
+    out.push_str(&format!("#endif /* {}_ROM_H */\n", upper));
+    out
+}
+
+/// `image` at `org` as Intel HEX data records and an end-of-file record
+pub fn intel_hex(image: &[u8], org: u16) -> String {
+    let mut out = String::new();
+    let mut record = |kind: u8, address: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        out.push(':');
+        out.extend(bytes.iter().map(|b| format!("{:02X}", b)));
+        out.push('\n');
+    };
+    for (i, row) in image.chunks(ROW).enumerate() {
+        record(0x00, org.wrapping_add((i * ROW) as u16), row);
+    }
+    record(0x01, 0, &[]);
+    out
+}
+
+/// `name` with anything not allowed in a C identifier replaced by `_`
+fn identifier(name: &str) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    if !ident.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        ident.insert_str(0, "mpl_");
+    }
+    ident
+}
+
+/// Array initialiser rows, each indented and ending in a comma
+fn byte_rows(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for row in bytes.chunks(ROW) {
+        let hex: Vec<String> = row.iter().map(|b| format!("0x{:02X}", b)).collect();
+        out.push_str("    ");
+        out.push_str(&hex.join(", "));
+        out.push_str(",\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the bytes back out of an array initialiser
+    fn parse_array(src: &str) -> Vec<u8> {
+        let body = &src[src.find('{').unwrap() + 1..src.find("};").unwrap()];
+        body.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| u8::from_str_radix(s.trim_start_matches("0x"), 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_arduino_sketch() {
+        let rom: Vec<u8> = (0..40).collect();
+        let sketch = arduino_sketch("demo", &rom, 0x0000);
+        assert!(sketch.contains("#define ROM_START   0x0000\n"));
+        assert!(sketch.contains("PROGMEM const unsigned char rom_bin[] = {\n"));
+        assert!(sketch.contains("    0x00, 0x01, 0x02,"));
+        // 40 bytes make two full rows and a partial one
+        assert_eq!(sketch.lines().filter(|l| l.starts_with("    0x")).count(), 3);
+        assert_eq!(parse_array(&sketch), rom);
+    }
+
+    #[test]
+    fn test_c_header() {
+        let header = c_header("2-demo", &[0xC3, 0x00, 0x10], 0x8000, 0x9000);
+        assert!(header.starts_with("/* MicroPerl image for 2-demo"));
+        assert!(header.contains("#ifndef MPL_2_DEMO_ROM_H\n#define MPL_2_DEMO_ROM_H\n"));
+        assert!(header.contains("#define MPL_2_DEMO_LEN 3u\n"));
+        assert!(header.contains("#define MPL_2_DEMO_LOAD_ADDR 0x8000u\n"));
+        assert!(header.contains("#define MPL_2_DEMO_BYTECODE_ADDR 0x9000u\n"));
+        assert!(header.contains("static const uint8_t mpl_2_demo_rom[MPL_2_DEMO_LEN] = {"));
+        assert!(header.trim_end().ends_with("#endif /* MPL_2_DEMO_ROM_H */"));
+        assert_eq!(parse_array(&header), [0xC3, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn test_intel_hex() {
+        let image: Vec<u8> = (0..20).collect();
+        let hex = intel_hex(&image, 0x1000);
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], ":10100000000102030405060708090A0B0C0D0E0F68");
+        assert_eq!(lines[1], ":041010001011121396");
+        assert_eq!(lines[2], ":00000001FF");
+    }
+}
@@ -1,11 +1,23 @@
 //! Bytecode compiler for MicroPerl
 
-use std::collections::HashMap;
-
-use crate::ast::{BinOp, Expr, Program, Stmt, UnaryOp};
-use crate::bytecode::{Module, Op};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::ast::{BinOp, Expr, Handle, Program, Stmt, UnaryOp};
+use crate::bytecode::{self, Module, NativeFunc, Op};
+use crate::linker;
+use crate::loader;
+use crate::native::NativeSub;
+use crate::optimizer;
+use crate::pack;
+use crate::printer;
+use crate::types;
+
+/// Instructions a BEGIN block may run before compiling gives up on it
+#[cfg(feature = "host-vm")]
+const BEGIN_STEPS: u64 = 10_000_000;
 
 /// Compiler state
+#[derive(Clone)]
 pub struct Compiler {
     module: Module,
 
@@ -17,12 +29,99 @@ pub struct Compiler {
 
     /// Subroutine addresses: name -> (address, num_params)
     subs: HashMap<String, (u16, u8)>,
+    /// Every sub the program defines, from the declaration pass before
+    /// code generation: name -> num_params
+    declared: HashMap<String, u8>,
+    /// Subs marked `:native`: name -> num_params
+    native_subs: HashMap<String, usize>,
+    /// Subs whose last parameter takes the rest of the arguments as an array
+    variadic: HashSet<String>,
+    /// Subs whose result can be undefined. A call pushes a slot for the
+    /// result under the arguments, and the sub returns its defined bit, so
+    /// both are left on the stack, the bit on top
+    undef_subs: HashSet<String>,
+
+    /// Constants from `use constant`, and from `define`, which win
+    constants: HashMap<String, Expr>,
+    defines: HashMap<String, Expr>,
+    /// The read-only %ENV, from `set_env`
+    env: BTreeMap<String, Expr>,
 
     /// Loop context for last/next: (continue_addr, break_addr)
     loop_stack: Vec<(u16, Vec<usize>)>,
 
-    /// Forward references to patch
-    forward_refs: Vec<(String, usize)>,
+    /// Forward references to patch: (sub, operand position, arguments,
+    /// line of the call)
+    forward_refs: Vec<(String, usize, usize, Option<usize>)>,
+
+    /// Statement lines from the parser, consumed in the same pre-order
+    lines: Vec<usize>,
+    next_stmt: usize,
+    /// Once past the statements, the line an error points at: a forward
+    /// reference's call, or none for the module's limits
+    late_line: Option<Option<usize>>,
+    /// Code of the error `compile` stopped with, see `Diagnostic::code`
+    error_code: &'static str,
+    /// How the token the error is about can be written in the source, such
+    /// as `@a` or `$a` for an array, for the error to point at it
+    error_tokens: Vec<String>,
+
+    /// Emit a Count at the start of each basic block
+    coverage: bool,
+    counters: usize,
+    /// The next statement starts a basic block
+    block_start: bool,
+
+    /// Compile + - * to opcodes that trap on overflow
+    checked: bool,
+    /// Check array indexes before ArrGet and ArrSet
+    bounds_check: bool,
+    /// The addresses poke and poke16 may write, lo..=hi
+    poke_range: Option<(u16, u16)>,
+    /// Use buffers for `.=` in loops, for a module run on the host VM
+    string_buffers: bool,
+    /// The target has a port for STDERR
+    stderr: bool,
+    /// Scalars holding a buf_new buffer for the loop being compiled, which
+    /// `.=` appends to in place
+    buffered: HashSet<String>,
+    /// Scalars that can be undefined, each declared with a hidden scalar
+    /// beside it, its defined bit, that is 1 while it holds a value
+    undefinable: HashSet<String>,
+    /// Run the jump optimizer over the finished module
+    optimize: bool,
+    /// Source file name, for __FILE__
+    file: String,
+    /// Start at this sub instead of the main code
+    entry: Option<String>,
+    /// Start at a menu that runs one of these subs
+    menu: Vec<String>,
+
+    /// Inline calls to subs whose body returns at most this many bytes of
+    /// code (0: never)
+    inline_limit: usize,
+    /// Subs small enough to inline: name -> (parameters, returned expression)
+    inlinable: HashMap<String, (Vec<String>, Expr)>,
+    /// Parameters of the sub being compiled, whose frame a tail call reuses
+    frame_params: Option<u8>,
+    /// Local index of the result slot of the sub being compiled, when it is
+    /// one of `undef_subs`
+    result_slot: Option<u8>,
+    /// Leave the next call's defined bit on the stack, over its result
+    keep_bit: bool,
+    /// `my` slots taken in the frame being compiled: the main program's
+    /// above VM_STACK, or the sub's under its saved frame pointer
+    frame_locals: u8,
+}
+
+/// Byte limit for `--inline`: a call costs about this much in pushes, Call,
+/// EnterFrame and ReturnVal, before the time spent in them
+pub const INLINE_LIMIT: usize = 16;
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
@@ -32,18 +131,175 @@ impl Compiler {
             globals: HashMap::new(),
             locals: vec![HashMap::new()],
             subs: HashMap::new(),
+            declared: HashMap::new(),
+            native_subs: HashMap::new(),
+            variadic: HashSet::new(),
+            undef_subs: HashSet::new(),
+            constants: HashMap::new(),
+            defines: HashMap::new(),
+            env: BTreeMap::new(),
             loop_stack: Vec::new(),
             forward_refs: Vec::new(),
+            lines: Vec::new(),
+            next_stmt: 0,
+            late_line: None,
+            error_code: "compile-error",
+            error_tokens: Vec::new(),
+            coverage: false,
+            counters: 0,
+            block_start: true,
+            checked: false,
+            bounds_check: false,
+            poke_range: None,
+            string_buffers: false,
+            stderr: true,
+            buffered: HashSet::new(),
+            undefinable: HashSet::new(),
+            optimize: true,
+            file: "-".to_string(),
+            entry: None,
+            menu: Vec::new(),
+            inline_limit: 0,
+            inlinable: HashMap::new(),
+            frame_params: None,
+            result_slot: None,
+            keep_bit: false,
+            frame_locals: 0,
         }
     }
 
-    pub fn compile(mut self, program: &Program) -> Result<Module, String> {
-        // First pass: collect subroutine declarations
-        for stmt in &program.statements {
-            if let Stmt::Sub { name, params, .. } = stmt {
-                self.subs.insert(name.clone(), (0, params.len() as u8));
+    /// Count how often each basic block runs, for `coverage`
+    pub fn set_coverage(&mut self, on: bool) {
+        self.coverage = on;
+    }
+
+    /// Make + - * (and their assignments) a runtime error on signed 16-bit
+    /// overflow instead of wrapping
+    pub fn set_checked(&mut self, on: bool) {
+        self.checked = on;
+    }
+
+    /// Check each array index against the array's length before using it,
+    /// unless NDEBUG is defined
+    pub fn set_bounds_check(&mut self, on: bool) {
+        self.bounds_check = on;
+    }
+
+    /// Only let poke and poke16 write addresses in `range`, lo..=hi: a
+    /// constant address is checked here, any other at run time
+    pub fn set_poke_range(&mut self, range: Option<(u16, u16)>) {
+        self.poke_range = range;
+    }
+
+    /// Fill a buffer in place for `.=` in loops instead of copying the
+    /// string each pass. The Z80 runtime has no buffers, so this is for a
+    /// module the host VM runs (off by default).
+    pub fn set_string_buffers(&mut self, on: bool) {
+        self.string_buffers = on;
+    }
+
+    /// Whether the target has a port for STDERR (on by default): without
+    /// one, `print STDERR` is an error rather than going to the console
+    pub fn set_stderr(&mut self, on: bool) {
+        self.stderr = on;
+    }
+
+    /// Inline calls to a sub that only returns an expression of its scalar
+    /// parameters, when that expression compiles to at most `limit` bytes
+    /// and the arguments have no side effects. 0 turns it off.
+    pub fn set_inline(&mut self, limit: usize) {
+        self.inline_limit = limit;
+    }
+
+    /// Start the program at `sub`, which takes no arguments, instead of
+    /// at the main code
+    pub fn set_entry(&mut self, sub: Option<String>) {
+        self.entry = sub;
+    }
+
+    /// Start the program at a numbered menu of `subs`, which take no
+    /// arguments: a key from 1 runs one, and the menu comes back when it
+    /// returns. Several programs can share one ROM this way.
+    pub fn set_menu(&mut self, subs: Vec<String>) {
+        self.menu = subs;
+    }
+
+    /// Run the jump optimizer over the finished module (on by default).
+    /// Off leaves the code as generated, constants folded, as `--dump-after
+    /// fold` shows it.
+    pub fn set_optimize(&mut self, on: bool) {
+        self.optimize = on;
+    }
+
+    /// Name the source file, for __FILE__
+    pub fn set_file(&mut self, name: &str) {
+        self.file = name.to_string();
+    }
+
+    /// Whether NDEBUG is defined, which strips asserts and bounds checks
+    fn ndebug(&self) -> bool {
+        self.defines.contains_key("NDEBUG") || self.constants.contains_key("NDEBUG")
+    }
+
+    /// Send output to `channel` (0 the console, 1 errors) for STDERR
+    fn select(&mut self, handle: Option<Handle>, channel: u8) -> Result<(), String> {
+        if handle == Some(Handle::Stderr) {
+            if !self.stderr {
+                return Err("STDERR needs a target with an error port (retroshield, or --error-port)".to_string());
             }
+            self.module.emit_byte(Op::Select, channel);
+        }
+        Ok(())
+    }
+
+    /// pack(`template`, `values`) as a string, when they are all literals and
+    /// the result is ASCII (the string table holds UTF-8) of at most 255
+    /// bytes
+    fn pack_constant(&self, template: &Expr, values: &[Expr]) -> Result<Option<String>, String> {
+        let Expr::String(template) = template else {
+            return Ok(None);
+        };
+        let mut args = Vec::new();
+        for value in values {
+            args.push(match value {
+                Expr::Integer(n) => (*n as u16, n.to_string().into_bytes()),
+                Expr::String(s) => (pack::number(s.as_bytes()), s.clone().into_bytes()),
+                _ => return Ok(None),
+            });
+        }
+        let bytes = pack::pack(template, &args)?;
+        Ok(String::from_utf8(bytes).ok().filter(|s| s.is_ascii() && s.len() <= u8::MAX as usize))
+    }
+
+    /// Check the [arr, idx] on top of the stack in bounds-check mode
+    fn check_index(&mut self) {
+        if self.bounds_check && !self.ndebug() {
+            self.module.emit(Op::CheckIdx);
+        }
+    }
+
+    /// The opcode for `op`, with its checked form in checked mode
+    fn arith(&self, op: Op) -> Op {
+        match (self.checked, op) {
+            (true, Op::Add) => Op::AddChk,
+            (true, Op::Sub) => Op::SubChk,
+            (true, Op::Mul) => Op::MulChk,
+            _ => op,
         }
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<Module, String> {
+        self.lines = program.lines.clone();
+        self.late_line = None;
+        self.error_code = "compile-error";
+        self.error_tokens.clear();
+
+        // First pass: collect subroutine declarations
+        self.declare(&program.statements)?;
+        self.check_entry()?;
+        let (names, subs) = self.undefined_values(&program.statements);
+        self.undefinable = names;
+        self.undef_subs = subs;
 
         // Compile main code
         for stmt in &program.statements {
@@ -52,44 +308,585 @@ impl Compiler {
 
         // Add halt at end
         self.module.emit(Op::Halt);
+        self.compile_entry();
+        self.late_line = Some(None);
 
-        // Patch forward references
-        for (name, patch_pos) in &self.forward_refs {
-            if let Some((addr, _)) = self.subs.get(name) {
-                self.module.patch_addr(*patch_pos, *addr);
-            } else {
+        self.patch_forward_refs()?;
+
+        // Copy sub info to module, in address order to keep output stable
+        for (name, (addr, params)) in &self.subs {
+            self.module.subs.push((name.clone(), *addr, *params));
+        }
+        self.module.subs.sort_by_key(|&(_, addr, _)| addr);
+        self.module.variadic = self.variadic_names();
+        self.module.undef_subs = sorted(&self.undef_subs);
+        if self.optimize {
+            optimizer::optimize(&mut self.module);
+        }
+
+        // By slot, with a gap for each taken by a variable in a block
+        self.module.locals = vec![String::new(); self.frame_locals as usize];
+        // Less the hidden ones, whose names have a space
+        for (name, &idx) in self.locals[0].iter().filter(|(name, _)| !name.contains(' ')) {
+            self.module.locals[idx as usize] = name.clone();
+        }
+
+        self.module.check_limits()?;
+        Ok(std::mem::take(&mut self.module))
+    }
+
+    /// Compile `program` as a continuation of what this compiler has already
+    /// seen, for the REPL: variables and subs from earlier calls stay in
+    /// scope and the new code replaces the previous Halt. Returns the whole
+    /// module so far and the offset of the new code. With `keep_value`, a
+    /// trailing expression statement leaves its value on the stack.
+    pub fn compile_more(&mut self, program: &Program, keep_value: bool) -> Result<(Module, u16), String> {
+        if self.module.code.last() == Some(&(Op::Halt as u8)) {
+            self.module.code.pop();
+        }
+        let start = self.module.pos();
+        self.lines = program.lines.clone();
+        self.next_stmt = 0;
+        self.late_line = None;
+        self.error_code = "compile-error";
+        self.error_tokens.clear();
+
+        // Subs defined further down are left to the forward references
+        self.declare(&program.statements)?;
+        let (names, subs) = self.undefined_values(&program.statements);
+        self.undefinable.extend(names);
+        self.undef_subs.extend(subs);
+        let (last, statements) = match program.statements.split_last() {
+            Some((Stmt::Expr(expr), rest)) if keep_value => (Some(expr), rest),
+            _ => (None, program.statements.as_slice()),
+        };
+        for stmt in statements {
+            self.compile_stmt(stmt)?;
+        }
+        if let Some(expr) = last {
+            self.begin_stmt()?;
+            self.compile_expr(expr)?;
+        }
+
+        self.module.emit(Op::Halt);
+        self.late_line = Some(None);
+
+        self.patch_forward_refs()?;
+
+        self.module.subs = self.subs.iter()
+            .map(|(name, (addr, params))| (name.clone(), *addr, *params))
+            .collect();
+        self.module.subs.sort_by_key(|&(_, addr, _)| addr);
+        self.module.variadic = self.variadic_names();
+        self.module.undef_subs = sorted(&self.undef_subs);
+        self.module.check_limits()?;
+
+        Ok((self.module.clone(), start))
+    }
+
+    /// Record the name and parameters of every sub in `stmts`, nested ones
+    /// included, so calls ahead of a definition are checked and compiled
+    /// as the definition will want
+    fn declare(&mut self, stmts: &[Stmt]) -> Result<(), String> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Sub { name, params, body, native, variadic } => {
+                    self.declared.insert(name.clone(), param_count(name, params)?);
+                    if *native {
+                        self.native_subs.insert(name.clone(), params.len());
+                    }
+                    if *variadic {
+                        self.variadic.insert(name.clone());
+                    }
+                    self.declare(body)?;
+                }
+                Stmt::If { then_block, elsif_blocks, else_block, .. } => {
+                    self.declare(then_block)?;
+                    for (_, block) in elsif_blocks {
+                        self.declare(block)?;
+                    }
+                    if let Some(block) = else_block {
+                        self.declare(block)?;
+                    }
+                }
+                Stmt::Unless { then_block, else_block, .. } => {
+                    self.declare(then_block)?;
+                    if let Some(block) = else_block {
+                        self.declare(block)?;
+                    }
+                }
+                Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::For { body, .. }
+                | Stmt::Foreach { body, .. } | Stmt::Block(body) => self.declare(body)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Parameters of sub `name`, if it is defined, linked or declared
+    fn sub_params(&self, name: &str) -> Option<u8> {
+        self.subs.get(name).map(|&(_, params)| params).or_else(|| self.declared.get(name).copied())
+    }
+
+    /// Whether `name` is a sub, which wins over a builtin of the same name
+    fn is_sub(&self, name: &str) -> bool {
+        self.sub_params(name).is_some()
+    }
+
+    /// Emit `op` to sub `name`: a Call or the Jump of a tail call. A sub
+    /// further down gets its address once it is compiled.
+    fn emit_sub_addr(&mut self, op: Op, name: &str, args: usize) {
+        if let Some(&(addr, _)) = self.subs.get(name) {
+            self.module.emit_word(op, addr);
+        } else {
+            self.forward_refs.push((name.to_string(), self.module.pos() as usize + 1, args, self.line()));
+            self.module.emit_word(op, 0);
+        }
+    }
+
+    /// Check the `set_entry` and `set_menu` subs, before any statement so
+    /// an error points at none
+    fn check_entry(&self) -> Result<(), String> {
+        if self.menu.len() > 9 {
+            return Err(format!("A menu takes at most 9 subs, not {}", self.menu.len()));
+        }
+        for name in self.entry.iter().chain(&self.menu) {
+            match self.sub_params(name) {
+                None => return Err(format!("Entry sub {} is not defined", name)),
+                Some(params) if params > 0 => {
+                    return Err(format!("Entry sub {} takes {}, but must take none", name, arguments(params as usize)));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Code after the Halt that calls the `set_entry` sub, or shows the
+    /// `set_menu` menu, and make it the module's entry. Every sub is
+    /// compiled by now.
+    fn compile_entry(&mut self) {
+        if let Some(name) = &self.entry {
+            self.module.entry = self.module.pos();
+            self.module.emit_word(Op::Call, self.subs[name].0);
+            self.module.emit(Op::Pop);
+            self.module.emit(Op::Halt);
+        }
+        if !self.menu.is_empty() {
+            let items: Vec<(String, u16)> = self.menu.iter().map(|name| (name.clone(), self.subs[name].0)).collect();
+            self.module.entry = self.module.emit_menu(&items, true);
+        }
+    }
+
+    /// Point each call to a sub defined after it at the sub
+    fn patch_forward_refs(&mut self) -> Result<(), String> {
+        for (name, patch_pos, args, line) in &self.forward_refs {
+            let Some(&(addr, params)) = self.subs.get(name) else {
+                self.late_line = Some(*line);
+                self.error_code = "undefined-sub";
+                self.error_tokens = vec![name.clone()];
                 return Err(format!("Undefined subroutine: {}", name));
+            };
+            // Declared subs were checked at the call; this catches a REPL
+            // line defining a sub an earlier line called differently
+            if *args != params as usize {
+                self.late_line = Some(*line);
+                self.error_code = "sub-arity";
+                self.error_tokens = vec![name.clone()];
+                return Err(format!("Sub {} takes {} but is called with {}", name, arguments(params as usize), args));
             }
+            self.module.patch_addr(*patch_pos, addr);
         }
+        Ok(())
+    }
 
-        // Copy sub info to module
-        for (name, (addr, params)) in &self.subs {
-            self.module.subs.push((name.clone(), *addr, *params));
+    /// Names of the variadic subs, in a stable order
+    fn variadic_names(&self) -> Vec<String> {
+        sorted(&self.variadic)
+    }
+
+    /// The scalars in `stmts` that can be undefined, and the subs whose
+    /// result can be, each depending on the other
+    fn undefined_values(&self, stmts: &[Stmt]) -> (HashSet<String>, HashSet<String>) {
+        // Subs compiled already, and ones called from code that doesn't
+        // push the result slot, keep the plain convention
+        let mut started: HashSet<String> = self.entry.iter().chain(&self.menu).chain(self.subs.keys()).cloned().collect();
+        each_stmt(stmts, &mut |stmt| {
+            for expr in stmt_exprs(stmt) {
+                each_expr(expr, &mut |e| {
+                    if let Expr::Ref(target) = e {
+                        if let Expr::Call(name, _) = &**target {
+                            started.insert(name.clone());
+                        }
+                    }
+                });
+            }
+        });
+        let mut subs = self.undef_subs.clone();
+        loop {
+            let names = undefinable(stmts, &subs);
+            let more = undef_results(stmts, &names, &subs, &started);
+            if more == subs {
+                return (names, subs);
+            }
+            subs = more;
+        }
+    }
+
+    /// Define constant `name`, as `--define NAME=VALUE` does. `value` is a
+    /// number (decimal or 0x hex), or else a string. It overrides a `use
+    /// constant` of the same name, so source can carry defaults.
+    pub fn define(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("Invalid constant name: {}", name));
+        }
+        let value = Self::constant_value(name, value)?;
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Set `$ENV{name}` for the program, as `--env NAME=VALUE` does. The
+    /// value is a number or a string as for `define`, and %ENV is fixed at
+    /// compile time, so reads with a constant key become constants.
+    pub fn set_env(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let value = Self::constant_value(name, value)?;
+        self.env.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Run a BEGIN block's `body` on the host VM. Each `use constant` at its
+    /// top level is computed there, with loops, subs and strings, and its
+    /// value becomes an ordinary constant for the code after the block.
+    /// Nothing else it does reaches the program; its output is dropped.
+    #[cfg(feature = "host-vm")]
+    fn run_begin(&mut self, body: &[Stmt], lines: Vec<usize>) -> Result<(), String> {
+        use crate::vm::{Exit, Vm};
+        use crate::z80emu::Console;
+
+        // Each constant is kept in a local no program can name
+        let local = |name: &str| format!("{} constant", name);
+        let mut names = Vec::new();
+        let statements = body
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Constant(name, value) => {
+                    names.push(name.clone());
+                    Stmt::My(vec![local(name)], Some(value.clone()))
+                }
+                _ => stmt.clone(),
+            })
+            .collect();
+
+        let mut begin = Compiler::new();
+        begin.constants = self.constants.clone();
+        begin.defines = self.defines.clone();
+        begin.env = self.env.clone();
+        begin.checked = self.checked;
+        begin.bounds_check = self.bounds_check;
+        begin.poke_range = self.poke_range;
+        let program = Program { statements, lines };
+        let module = begin.compile(&program).map_err(|e| format!("In BEGIN: {}", e))?;
+        let mut vm = Vm::new(&module, Console::scripted(b""));
+        match vm.run(Some(BEGIN_STEPS)) {
+            Ok(Exit::Halted) => {}
+            Ok(Exit::StepLimit) => return Err(format!("BEGIN block still running after {} instructions", BEGIN_STEPS)),
+            Ok(Exit::InputExhausted) => return Err("BEGIN block read input, which it has none of".to_string()),
+            Err(e) => return Err(format!("BEGIN block failed: {}", e)),
+        }
+
+        for name in names {
+            let value = vm.local(begin.locals[0][&local(&name)]);
+            // A word of 4096 or more is as likely a number: it is only a
+            // string if the block always makes one, or, where its code
+            // doesn't say, if the word points at one
+            let string = types::holds_string(&program, &local(&name)).unwrap_or_else(|| vm.is_string(value));
+            let value = if string {
+                Expr::String(String::from_utf8_lossy(&vm.text(value)).into_owned())
+            } else {
+                Expr::Integer(value as i16 as i32)
+            };
+            if !self.defines.contains_key(&name) {
+                self.constants.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "host-vm"))]
+    fn run_begin(&mut self, _body: &[Stmt], _lines: Vec<usize>) -> Result<(), String> {
+        Err("BEGIN blocks need the host VM (the host-vm feature)".to_string())
+    }
+
+    /// Whether `expr` names the build-time %ENV rather than a variable
+    fn is_env(&self, expr: &Expr) -> bool {
+        matches!(expr, Expr::ScalarVar(name) | Expr::HashVar(name)
+            if name == "ENV" && self.find_local(name).is_none() && !self.globals.contains_key(name))
+    }
+
+    /// Build %ENV as a hash. Each use gets its own copy, so the program
+    /// can't change what later reads see.
+    fn compile_env(&mut self) -> Result<(), String> {
+        self.module.emit(Op::NewHash);
+        for (name, value) in self.env.clone() {
+            self.module.emit(Op::Dup);
+            self.compile_expr(&Expr::String(name))?;
+            self.compile_expr(&value)?;
+            self.module.emit(Op::HashSet);
+        }
+        Ok(())
+    }
+
+    /// `value` of constant `name` from the command line: a number (decimal
+    /// or 0x hex) if it looks like one, else a string
+    fn constant_value(name: &str, value: &str) -> Result<Expr, String> {
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let number = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+            Some(hex) => i32::from_str_radix(hex, 16).ok(),
+            None => digits.parse::<i32>().ok(),
+        };
+        let value = match number {
+            Some(n) if (-0x8000..=0xFFFF).contains(&n) => Expr::Integer(if negative { -n } else { n }),
+            Some(_) => return Err(format!("Constant {} is out of 16-bit range: {}", name, value)),
+            None => Expr::String(value.to_string()),
+        };
+        Ok(value)
+    }
+
+    /// Whether `return name(args)` can jump to the sub in the current frame:
+    /// it's a plain sub taking as many arguments as this one, so they fit
+    /// where this sub's are and its return drops them all
+    fn is_tail_call(&self, name: &str, args: &[Expr]) -> bool {
+        let (Some(frame), Some(params)) = (self.frame_params, self.sub_params(name)) else {
+            return false;
+        };
+        args.len() == params as usize
+            && params == frame
+            && !self.variadic.contains(name)
+            && !self.native_subs.contains_key(name)
+            && !self.inlinable.contains_key(name)
+            && self.undef_subs.contains(name) == self.result_slot.is_some()
+    }
+
+    /// Whether a sub returning `expr` can be inlined: it reads only its
+    /// scalar `params` and constants, and calls nothing, so it means the
+    /// same at any call site
+    fn inline_body(&self, expr: &Expr, params: &[String]) -> bool {
+        match expr {
+            Expr::Integer(_) | Expr::String(_) => true,
+            Expr::ScalarVar(name) => params.contains(name),
+            Expr::Call(_, args) => args.is_empty() && self.fold(expr).is_some(),
+            Expr::UnaryOp(op, e) => *op != UnaryOp::Ref && self.inline_body(e, params),
+            Expr::BinOp(left, _, right) => self.inline_body(left, params) && self.inline_body(right, params),
+            Expr::Ternary(cond, then_expr, else_expr) => {
+                self.inline_body(cond, params) && self.inline_body(then_expr, params) && self.inline_body(else_expr, params)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `arg` can stand in for a parameter of an inlined sub: it
+    /// has no side effects, so evaluating it once per use, in the body's
+    /// order, is the same as evaluating it once before the call
+    fn inline_arg(&self, arg: &Expr) -> bool {
+        match arg {
+            Expr::Integer(_) | Expr::String(_) => true,
+            // Checked here, as an unused parameter never compiles it
+            Expr::ScalarVar(name) => {
+                self.find_local(name).is_some() || self.globals.contains_key(name) || name == "ARGV"
+            }
+            Expr::Call(_, args) => args.is_empty() && self.fold(arg).is_some(),
+            Expr::UnaryOp(op, e) => *op != UnaryOp::Ref && self.inline_arg(e),
+            Expr::BinOp(left, _, right) => self.inline_arg(left) && self.inline_arg(right),
+            _ => false,
+        }
+    }
+
+    /// Value of `expr` if it is known at compile time: a literal, a
+    /// constant, or an operator applied to those, computed as the VM would
+    fn fold(&self, expr: &Expr) -> Option<Expr> {
+        let number = |v: u16| Expr::Integer(v as i16 as i32);
+        match expr {
+            Expr::Integer(_) | Expr::String(_) => Some(expr.clone()),
+            Expr::Call(name, args) if args.is_empty() => {
+                self.defines.get(name).or_else(|| self.constants.get(name)).cloned()
+                    .or_else(|| if self.is_sub(name) { None } else { char_constant(name) })
+            }
+            Expr::Call(name, args) if name == "ctrl" && !self.is_sub(name) => match args.as_slice() {
+                [arg] => match self.fold(arg)? {
+                    Expr::String(s) => ctrl(&s),
+                    _ => None,
+                },
+                _ => None,
+            },
+            Expr::UnaryOp(op, e) => {
+                let Expr::Integer(n) = self.fold(e)? else {
+                    return None;
+                };
+                let n = n as u16;
+                match op {
+                    UnaryOp::Neg => Some(number(n.wrapping_neg())),
+                    UnaryOp::Not => Some(number((n == 0) as u16)),
+                    UnaryOp::BitNot => Some(number(!n)),
+                    UnaryOp::Ref => None,
+                }
+            }
+            Expr::BinOp(left, op, right) => match (self.fold(left)?, op, self.fold(right)?) {
+                (Expr::String(a), BinOp::Concat, Expr::String(b)) => Some(Expr::String(a + &b)),
+                (Expr::String(a), BinOp::Repeat, Expr::Integer(n)) => repeat(&a, n),
+                (Expr::Integer(a), BinOp::Repeat, Expr::Integer(n)) => repeat(&(a as i16).to_string(), n),
+                (Expr::Integer(a), op, Expr::Integer(b)) => {
+                    let op = match op {
+                        BinOp::Add => Op::Add,
+                        BinOp::Sub => Op::Sub,
+                        BinOp::Mul => Op::Mul,
+                        BinOp::Div => Op::Div,
+                        BinOp::Mod => Op::Mod,
+                        BinOp::BitAnd => Op::BitAnd,
+                        BinOp::BitOr => Op::BitOr,
+                        BinOp::BitXor => Op::BitXor,
+                        BinOp::ShiftLeft => Op::Shl,
+                        BinOp::ShiftRight => Op::Shr,
+                        BinOp::Eq => Op::CmpEq,
+                        BinOp::Ne => Op::CmpNe,
+                        BinOp::Lt => Op::CmpLt,
+                        BinOp::Gt => Op::CmpGt,
+                        BinOp::Le => Op::CmpLe,
+                        BinOp::Ge => Op::CmpGe,
+                        _ => return None,
+                    };
+                    // Division by zero, and overflow in checked mode, are left
+                    // to fail at run time
+                    match self.arith(op) {
+                        op @ (Op::AddChk | Op::SubChk | Op::MulChk) => bytecode::checked(op, a as u16, b as u16),
+                        op => bytecode::binary(op, a as u16, b as u16),
+                    }
+                    .map(number)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Put a precompiled library at the end of the code so far, ahead of
+    /// the program, with its subs and globals in scope
+    pub fn link(&mut self, library: &Module) -> Result<(), String> {
+        let base = self.module.pos();
+        let strings: Vec<u16> = library.strings.iter().map(|s| self.module.add_string(s)).collect();
+        let mut globals = Vec::new();
+        for name in &library.globals {
+            let next = self.globals.len() as u16;
+            let idx = *self.globals.entry(name.clone()).or_insert(next);
+            if idx == next {
+                self.module.globals.push(name.clone());
+            }
+            globals.push(idx);
+        }
+        let code = linker::relocate(&library.code, base, &strings, &globals)?;
+        self.module.code.extend(code);
+        for (name, addr, params) in &library.subs {
+            self.subs.insert(name.clone(), (base + addr, *params));
+        }
+        self.variadic.extend(library.variadic.iter().cloned());
+        self.undef_subs.extend(library.undef_subs.iter().cloned());
+        Ok(())
+    }
+
+    /// The integer and string constants, from `use constant` and `define`,
+    /// that native code can use
+    pub fn constants(&self) -> HashMap<String, Expr> {
+        self.constants.iter().chain(&self.defines)
+            .filter(|(_, value)| matches!(value, Expr::Integer(_) | Expr::String(_)))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Code of the error `compile` stopped with
+    pub fn error_code(&self) -> &'static str {
+        self.error_code
+    }
+
+    /// The ways the token the error is about can be written, or none when
+    /// the error is about the whole statement
+    pub fn error_tokens(&self) -> &[String] {
+        &self.error_tokens
+    }
+
+    /// `message`, for an error of kind `code`
+    fn error(&mut self, code: &'static str, message: String) -> String {
+        self.error_code = code;
+        message
+    }
+
+    /// `message`, for an error of kind `code` about the token written as
+    /// one of `tokens`
+    fn error_at(&mut self, code: &'static str, tokens: &[&str], message: String) -> String {
+        self.error_tokens = tokens.iter().map(|token| token.to_string()).collect();
+        self.error(code, message)
+    }
+
+    /// Source line of the statement being compiled when `compile` stopped,
+    /// or of the call to a sub that turned out undefined
+    pub fn line(&self) -> Option<usize> {
+        self.late_line.unwrap_or_else(|| self.next_stmt.checked_sub(1).and_then(|i| self.lines.get(i).copied()))
+    }
+
+    /// Mark the line of the next statement, and count its block if it
+    /// starts one
+    fn begin_stmt(&mut self) -> Result<(), String> {
+        if let Some(&line) = self.lines.get(self.next_stmt) {
+            self.module.mark_line(line);
+        }
+        self.next_stmt += 1;
+        if std::mem::take(&mut self.block_start) && self.coverage {
+            self.count()?;
+        }
+        Ok(())
+    }
+
+    /// Mark the code from here on, a loop's way back to its test, as the
+    /// loop's `line`
+    fn retest(&mut self, line: Option<usize>) {
+        if let Some(line) = line {
+            self.module.mark_line(line);
         }
+    }
 
-        Ok(self.module)
+    /// Take the line of the next entry in the line table that isn't a
+    /// statement: an elsif
+    fn next_line(&mut self) -> Option<usize> {
+        let line = self.lines.get(self.next_stmt).copied();
+        self.next_stmt += 1;
+        line
     }
 
     fn compile_stmt(&mut self, stmt: &Stmt) -> Result<(), String> {
+        self.begin_stmt()?;
+        // The statement's own line, for `retest`
+        let line = self.next_stmt.checked_sub(1).and_then(|i| self.lines.get(i).copied());
+        let buffers = self.buffer_appends(stmt)?;
+
         match stmt {
-            Stmt::Expr(expr) => {
-                self.compile_expr(expr)?;
-                self.module.emit(Op::Pop); // Discard result
-            }
+            Stmt::Expr(expr) => self.compile_effect(expr)?,
 
             Stmt::My(vars, init) => {
                 // Allocate local variables
                 for var in vars {
-                    let idx = self.locals.last().unwrap().len() as u8;
-                    self.locals.last_mut().unwrap().insert(var.clone(), idx);
+                    self.new_var(var)?;
                 }
 
                 // Initialize if provided
                 if let Some(init_expr) = init {
                     if vars.len() == 1 {
-                        self.compile_expr(init_expr)?;
+                        self.compile_assigned(&Expr::ScalarVar(vars[0].clone()), init_expr)?;
                         let idx = *self.locals.last().unwrap().get(&vars[0]).unwrap();
                         self.module.emit_byte(Op::StoreLocal, idx);
+                        self.mark_defined(&vars[0], Some(init_expr))?;
                     } else {
                         // List assignment - compile expr and distribute
                         self.compile_expr(init_expr)?;
@@ -98,73 +895,96 @@ impl Compiler {
                                 self.module.emit(Op::Dup);
                             }
                             self.module.emit_word(Op::Push, i as u16);
+                            self.check_index();
                             self.module.emit(Op::ArrGet);
                             let idx = *self.locals.last().unwrap().get(var).unwrap();
                             self.module.emit_byte(Op::StoreLocal, idx);
+                            self.mark_defined(var, None)?;
                         }
                     }
+                } else {
+                    for var in vars {
+                        let idx = *self.locals.last().unwrap().get(var).unwrap();
+                        self.module.emit_word(Op::Push, 0);
+                        self.module.emit_byte(Op::StoreLocal, idx);
+                        self.mark_defined(var, Some(&Expr::call("undef", Vec::new())))?;
+                    }
                 }
             }
 
             Stmt::Our(vars, init) => {
                 // Allocate global variables
                 for var in vars {
-                    let idx = self.globals.len() as u16;
-                    self.globals.insert(var.clone(), idx);
-                    self.module.globals.push(var.clone());
+                    let bit = self.undefinable.contains(var).then(|| defined_bit(var));
+                    for name in std::iter::once(var).chain(&bit) {
+                        let idx = self.globals.len() as u16;
+                        self.globals.insert(name.clone(), idx);
+                        self.module.globals.push(name.clone());
+                    }
                 }
 
                 if let Some(init_expr) = init {
                     if vars.len() == 1 {
-                        self.compile_expr(init_expr)?;
+                        self.compile_assigned(&Expr::ScalarVar(vars[0].clone()), init_expr)?;
                         let idx = *self.globals.get(&vars[0]).unwrap();
                         self.module.emit_word(Op::StoreGlobal, idx);
+                        self.mark_defined(&vars[0], Some(init_expr))?;
+                    }
+                } else {
+                    for var in vars {
+                        self.module.emit_word(Op::Push, 0);
+                        self.module.emit_word(Op::StoreGlobal, self.globals[var]);
+                        self.mark_defined(var, Some(&Expr::call("undef", Vec::new())))?;
                     }
                 }
             }
 
             Stmt::If { cond, then_block, elsif_blocks, else_block } => {
-                self.compile_expr(cond)?;
-
-                // Jump to elsif/else if false
-                let jump_pos = self.module.pos() as usize + 1;
-                self.module.emit_word(Op::JumpIfNot, 0); // Placeholder
-
-                // Then block
-                for s in then_block {
-                    self.compile_stmt(s)?;
-                }
-
-                // Jump over else blocks
+                // A branch whose condition is known is compiled alone, and
+                // the ones it rules out not at all
                 let mut end_jumps = vec![];
-                if !elsif_blocks.is_empty() || else_block.is_some() {
-                    end_jumps.push(self.module.pos() as usize + 1);
-                    self.module.emit_word(Op::Jump, 0);
-                }
+                let mut taken = false;
+                let arms = std::iter::once((cond, then_block)).chain(elsif_blocks.iter().map(|(c, b)| (c, b)));
+                for (i, (cond, body)) in arms.enumerate() {
+                    // An elsif's condition is on its own line
+                    let line = match i {
+                        0 => None,
+                        _ => self.next_line(),
+                    };
+                    match self.truth(cond) {
+                        _ if taken => self.skip(body),
+                        Some(false) => self.skip(body),
+                        Some(true) => {
+                            self.compile_body(body)?;
+                            taken = true;
+                        }
+                        None => {
+                            if let Some(line) = line {
+                                self.module.mark_line(line);
+                            }
+                            self.compile_expr(cond)?;
 
-                // Patch jump to here
-                self.module.patch_addr(jump_pos, self.module.pos());
+                            // Jump to the next elsif/else if false
+                            let jump_pos = self.module.pos() as usize + 1;
+                            self.module.emit_word(Op::JumpIfNot, 0); // Placeholder
 
-                // Elsif blocks
-                for (elsif_cond, elsif_body) in elsif_blocks {
-                    self.compile_expr(elsif_cond)?;
-                    let elsif_jump = self.module.pos() as usize + 1;
-                    self.module.emit_word(Op::JumpIfNot, 0);
+                            self.compile_body(body)?;
 
-                    for s in elsif_body {
-                        self.compile_stmt(s)?;
+                            // Jump over the rest
+                            if i < elsif_blocks.len() || else_block.is_some() {
+                                end_jumps.push(self.module.pos() as usize + 1);
+                                self.module.emit_word(Op::Jump, 0);
+                            }
+                            self.module.patch_addr(jump_pos, self.module.pos());
+                        }
                     }
-
-                    end_jumps.push(self.module.pos() as usize + 1);
-                    self.module.emit_word(Op::Jump, 0);
-
-                    self.module.patch_addr(elsif_jump, self.module.pos());
                 }
 
                 // Else block
                 if let Some(else_body) = else_block {
-                    for s in else_body {
-                        self.compile_stmt(s)?;
+                    match taken {
+                        true => self.skip(else_body),
+                        false => self.compile_body(else_body)?,
                     }
                 }
 
@@ -175,24 +995,34 @@ impl Compiler {
                 }
             }
 
+            Stmt::Unless { cond, then_block, else_block } if self.truth(cond).is_some() => {
+                let run_then = self.truth(cond) == Some(false);
+                match run_then {
+                    true => self.compile_body(then_block)?,
+                    false => self.skip(then_block),
+                }
+                if let Some(else_body) = else_block {
+                    match run_then {
+                        true => self.skip(else_body),
+                        false => self.compile_body(else_body)?,
+                    }
+                }
+            }
+
             Stmt::Unless { cond, then_block, else_block } => {
                 self.compile_expr(cond)?;
 
                 let jump_pos = self.module.pos() as usize + 1;
                 self.module.emit_word(Op::JumpIf, 0); // Jump if TRUE (opposite of if)
 
-                for s in then_block {
-                    self.compile_stmt(s)?;
-                }
+                self.compile_body(then_block)?;
 
                 if let Some(else_body) = else_block {
                     let end_jump = self.module.pos() as usize + 1;
                     self.module.emit_word(Op::Jump, 0);
                     self.module.patch_addr(jump_pos, self.module.pos());
 
-                    for s in else_body {
-                        self.compile_stmt(s)?;
-                    }
+                    self.compile_body(else_body)?;
 
                     self.module.patch_addr(end_jump, self.module.pos());
                 } else {
@@ -208,10 +1038,9 @@ impl Compiler {
                 let exit_jump = self.module.pos() as usize + 1;
                 self.module.emit_word(Op::JumpIfNot, 0);
 
-                for s in body {
-                    self.compile_stmt(s)?;
-                }
+                self.compile_body(body)?;
 
+                self.retest(line);
                 self.module.emit_word(Op::Jump, loop_start);
 
                 let end_pos = self.module.pos();
@@ -232,10 +1061,9 @@ impl Compiler {
                 let exit_jump = self.module.pos() as usize + 1;
                 self.module.emit_word(Op::JumpIf, 0); // Exit if TRUE
 
-                for s in body {
-                    self.compile_stmt(s)?;
-                }
+                self.compile_body(body)?;
 
+                self.retest(line);
                 self.module.emit_word(Op::Jump, loop_start);
 
                 let end_pos = self.module.pos();
@@ -268,14 +1096,12 @@ impl Compiler {
                     None
                 };
 
-                for s in body {
-                    self.compile_stmt(s)?;
-                }
+                self.compile_body(body)?;
 
                 // Step expression
+                self.retest(line);
                 if let Some(step_expr) = step {
-                    self.compile_expr(step_expr)?;
-                    self.module.emit(Op::Pop);
+                    self.compile_effect(step_expr)?;
                 }
 
                 self.module.emit_word(Op::Jump, loop_start);
@@ -297,8 +1123,7 @@ impl Compiler {
                 self.locals.push(HashMap::new());
 
                 // Allocate loop variable
-                let var_idx = 0u8;
-                self.locals.last_mut().unwrap().insert(var.clone(), var_idx);
+                let var_idx = self.new_var(var)?;
 
                 // Compile list and get iterator index
                 self.compile_expr(list)?;
@@ -307,26 +1132,26 @@ impl Compiler {
                 let loop_start = self.module.pos();
                 self.loop_stack.push((loop_start, vec![]));
 
-                // Check if index < array length
+                // Leave once the index reaches the array length
                 self.module.emit(Op::Over);  // [arr, idx, arr]
                 self.module.emit(Op::ArrLen); // [arr, idx, len]
                 self.module.emit(Op::Over);  // [arr, idx, len, idx]
-                self.module.emit(Op::CmpLt); // [arr, idx, idx<len]
+                self.module.emit(Op::CmpLe); // [arr, idx, len<=idx]
 
                 let exit_jump = self.module.pos() as usize + 1;
-                self.module.emit_word(Op::JumpIfNot, 0);
+                self.module.emit_word(Op::JumpIf, 0);
 
                 // Get current element
                 self.module.emit(Op::Over);  // [arr, idx, arr]
                 self.module.emit(Op::Over);  // [arr, idx, arr, idx]
                 self.module.emit(Op::ArrGet); // [arr, idx, elem]
                 self.module.emit_byte(Op::StoreLocal, var_idx);
+                self.mark_defined(var, None)?;
 
-                for s in body {
-                    self.compile_stmt(s)?;
-                }
+                self.compile_body(body)?;
 
                 // Increment index
+                self.retest(line);
                 self.module.emit(Op::Inc);
                 self.module.emit_word(Op::Jump, loop_start);
 
@@ -362,80 +1187,288 @@ impl Compiler {
                 }
             }
 
+            Stmt::Return(Some(Expr::Call(name, args))) if self.is_tail_call(name, args) => {
+                // The arguments replace this sub's own, so recursion like
+                // this runs in one frame
+                for arg in args {
+                    self.compile_expr(arg)?;
+                }
+                self.module.emit_byte(Op::TailCall, args.len() as u8);
+                self.emit_sub_addr(Op::Jump, name, args.len());
+            }
+
+            // The result goes in the caller's slot, and the defined bit is
+            // what's returned
+            Stmt::Return(expr) if self.result_slot.is_some() => {
+                let slot = self.result_slot.unwrap();
+                match expr {
+                    Some(e) if self.undef_call(e) => {
+                        self.keep_bit = true;
+                        self.compile_expr(e)?;
+                        self.module.emit(Op::Over);
+                        self.module.emit_byte(Op::StoreLocal, slot);
+                    }
+                    Some(e) if !self.is_undef(e) => {
+                        self.compile_expr(e)?;
+                        self.module.emit_byte(Op::StoreLocal, slot);
+                        self.compile_bit(e)?;
+                    }
+                    _ => self.module.emit_word(Op::Push, 0),
+                }
+                self.module.emit_byte(Op::ReturnVal, self.frame_params.unwrap_or(0));
+            }
+
             Stmt::Return(expr) => {
+                let params = self.frame_params.unwrap_or(0);
                 if let Some(e) = expr {
                     self.compile_expr(e)?;
-                    self.module.emit(Op::ReturnVal);
+                    self.module.emit_byte(Op::ReturnVal, params);
                 } else {
-                    self.module.emit(Op::Return);
+                    self.module.emit_byte(Op::Return, params);
                 }
             }
 
-            Stmt::Sub { name, params, body } => {
+            Stmt::Sub { name, params, body, native, variadic } => {
                 // Jump over subroutine body
                 let skip_jump = self.module.pos() as usize + 1;
                 self.module.emit_word(Op::Jump, 0);
 
                 // Record subroutine address
                 let sub_addr = self.module.pos();
-                self.subs.insert(name.clone(), (sub_addr, params.len() as u8));
+                let count = param_count(name, params)?;
+                self.subs.insert(name.clone(), (sub_addr, count));
+                if *variadic {
+                    self.variadic.insert(name.clone());
+                }
 
-                // Set up frame
-                self.locals.push(HashMap::new());
-                self.module.emit_byte(Op::EnterFrame, params.len() as u8);
+                if *native {
+                    if *variadic {
+                        return Err(self.error_at("native-sub", &[name], format!("Sub {} is :native, which can't take a list of arguments", name)));
+                    }
+                    let constants = self.constants();
+                    let sub = NativeSub {
+                        name: name.clone(),
+                        addr: sub_addr,
+                        params: params.clone(),
+                        body: body.clone(),
+                        constants,
+                    };
+                    self.native_subs.insert(name.clone(), params.len());
+                    #[cfg(feature = "z80-backend")]
+                    crate::native::check(&sub, &self.native_subs).map_err(|e| self.error("native-sub", e))?;
+                    self.module.native.push(sub);
+                    self.module.emit_word(Op::Native, 0);
+                }
 
-                // Parameters are already on stack, map them to locals
+                // Set up frame, its `my` slots counted once the body is in
+                self.locals.push(HashMap::new());
+                let outer_params = self.frame_params.replace(count);
+                let outer_slot = std::mem::replace(&mut self.result_slot, self.undef_subs.contains(name).then_some(count));
+                let outer_locals = std::mem::take(&mut self.frame_locals);
+                let enter = self.module.pos() as usize + 1;
+                self.module.emit_byte(Op::EnterFrame, 0);
+
+                // Parameters are already on stack, the last on top where
+                // the frame starts, so they map to locals in reverse
                 for (i, param) in params.iter().enumerate() {
-                    self.locals.last_mut().unwrap().insert(param.clone(), i as u8);
+                    self.locals.last_mut().unwrap().insert(param.clone(), count - 1 - i as u8);
+                }
+                for param in params {
+                    if self.undefinable.contains(param) {
+                        self.new_local(&defined_bit(param))?;
+                        self.mark_defined(param, None)?;
+                    }
                 }
 
                 // Compile body
-                for s in body {
-                    self.compile_stmt(s)?;
+                let body_start = self.module.pos();
+                self.compile_body(body)?;
+                self.inlinable.remove(name);
+                if let [Stmt::Return(Some(value))] = body.as_slice() {
+                    // Less the ReturnVal
+                    let size = (self.module.pos() - body_start) as usize - Op::ReturnVal.size();
+                    if size <= self.inline_limit && !*native && !*variadic && !self.coverage
+                        && self.result_slot.is_none() && self.inline_body(value, params)
+                    {
+                        self.inlinable.insert(name.clone(), (params.clone(), value.clone()));
+                    }
                 }
 
-                // Default return
-                self.module.emit(Op::LeaveFrame);
-                self.module.emit(Op::Return);
+                // Default return, of 0, which is defined when it counts
+                if self.result_slot.is_some() {
+                    self.module.emit_word(Op::Push, 1);
+                    self.module.emit_byte(Op::ReturnVal, count);
+                } else {
+                    self.module.emit_byte(Op::Return, count);
+                }
 
+                self.module.code[enter] = self.frame_locals;
                 self.locals.pop();
+                self.frame_params = outer_params;
+                self.result_slot = outer_slot;
+                self.frame_locals = outer_locals;
 
                 // Patch skip jump
                 self.module.patch_addr(skip_jump, self.module.pos());
             }
 
-            Stmt::Print(exprs) => {
+            Stmt::Print(handle, exprs) => {
+                self.select(*handle, 1)?;
                 for expr in exprs {
-                    self.compile_expr(expr)?;
-                    self.module.emit(Op::Print);
+                    self.compile_print(expr, *handle, "print")?;
                 }
+                self.select(*handle, 0)?;
             }
 
-            Stmt::Say(exprs) => {
+            Stmt::Say(handle, exprs) => {
+                self.select(*handle, 1)?;
                 for expr in exprs {
-                    self.compile_expr(expr)?;
-                    self.module.emit(Op::Print);
+                    self.compile_print(expr, *handle, "say")?;
                 }
                 self.module.emit(Op::PrintLn);
+                self.select(*handle, 0)?;
             }
 
-            Stmt::Block(stmts) => {
-                self.locals.push(HashMap::new());
-                for s in stmts {
+            Stmt::Printf(handle, args) => {
+                let argc = u8::try_from(args.len() - 1)
+                    .map_err(|_| format!("printf with {} arguments, the limit is {}", args.len() - 1, u8::MAX))?;
+                self.select(*handle, 1)?;
+                for arg in args {
+                    self.compile_expr(arg)?;
+                }
+                self.module.emit_byte(Op::Printf, argc);
+                self.select(*handle, 0)?;
+            }
+
+            Stmt::Assert(cond, message) => {
+                // Defining NDEBUG compiles asserts out, condition and all
+                if !self.ndebug() {
+                    self.compile_expr(cond)?;
+                    let ok_jump = self.module.pos() as usize + 1;
+                    self.module.emit_word(Op::JumpIf, 0);
+                    let prefix = Expr::String("Assertion failed: ".to_string());
+                    let message = message.clone().unwrap_or_else(|| Expr::String(printer::expr(cond)));
+                    self.compile_expr(&Expr::bin(prefix, BinOp::Concat, message))?;
+                    self.module.emit(Op::Die);
+                    self.module.patch_addr(ok_jump, self.module.pos());
+                }
+            }
+
+            Stmt::Block(stmts) => {
+                self.locals.push(HashMap::new());
+                for s in stmts {
                     self.compile_stmt(s)?;
                 }
                 self.locals.pop();
             }
 
+            Stmt::Constant(name, value) => {
+                if !self.defines.contains_key(name) {
+                    let value = self
+                        .fold(value)
+                        .ok_or_else(|| format!("Constant {} needs a value known at compile time", name))?;
+                    self.constants.insert(name.clone(), value);
+                }
+            }
+
+            Stmt::Begin(body) => {
+                // Its statements run now, on the host, and not in the program
+                let count = body.iter().map(loader::count).sum::<usize>();
+                let end = (self.next_stmt + count).min(self.lines.len());
+                let lines = self.lines.get(self.next_stmt..end).unwrap_or_default().to_vec();
+                self.next_stmt += count;
+                self.run_begin(body, lines)?;
+            }
+
             Stmt::Use(_) | Stmt::Package(_) => {
                 // Ignored for now
             }
+
+            // Left in only when the program didn't go through the loader
+            Stmt::Include { file, .. } => {
+                return Err(format!("{} can only be included by the loader", file));
+            }
         }
 
+        // Code after these is reached by a jump, or not at all
+        if matches!(
+            stmt,
+            Stmt::If { .. } | Stmt::Unless { .. } | Stmt::While { .. } | Stmt::Until { .. } | Stmt::For { .. }
+                | Stmt::Foreach { .. } | Stmt::Sub { .. } | Stmt::Last | Stmt::Next | Stmt::Return(_)
+        ) {
+            self.block_start = true;
+        }
+        for name in buffers {
+            self.buffered.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// Ahead of a loop, turn each scalar it only appends to into a buffer,
+    /// so its `.=` statements fill that in place instead of copying the
+    /// string each pass. Nothing else in the loop sees the scalar, so no
+    /// copy of it can change. Returns the scalars turned.
+    fn buffer_appends(&mut self, stmt: &Stmt) -> Result<Vec<String>, String> {
+        if !self.string_buffers || !matches!(stmt, Stmt::While { .. } | Stmt::Until { .. } | Stmt::For { .. } | Stmt::Foreach { .. }) {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        // Subs could see a global, so only `my` variables qualify
+        for name in append_only(std::slice::from_ref(stmt)) {
+            if self.buffered.contains(&name) || self.find_local(&name).is_none() {
+                continue;
+            }
+            let var = Expr::ScalarVar(name.clone());
+            self.module.emit_byte(Op::CallNative, NativeFunc::BufNew as u8);
+            self.compile_text(&var)?;
+            self.module.emit_byte(Op::CallNative, NativeFunc::BufAppend as u8);
+            self.compile_assign_expr(&var)?;
+            self.buffered.insert(name.clone());
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    /// A loop or branch body, which starts a basic block
+    fn compile_body(&mut self, stmts: &[Stmt]) -> Result<(), String> {
+        self.block_start = true;
+        for s in stmts {
+            self.compile_stmt(s)?;
+        }
+        Ok(())
+    }
+
+    /// Leave out `stmts`, which can never run, keeping the line table in
+    /// step
+    fn skip(&mut self, stmts: &[Stmt]) {
+        self.next_stmt += stmts.iter().map(loader::count).sum::<usize>();
+    }
+
+    /// Whether `cond` holds, if that is known at compile time. Strings are
+    /// pointers on the VM, so any string is true
+    fn truth(&self, cond: &Expr) -> Option<bool> {
+        match self.fold(cond)? {
+            Expr::Integer(n) => Some(n as u16 != 0),
+            Expr::String(_) => Some(true),
+            _ => None,
+        }
+    }
+
+    /// Bump the next coverage counter
+    fn count(&mut self) -> Result<(), String> {
+        let idx = u8::try_from(self.counters)
+            .map_err(|_| format!("Too many basic blocks for coverage: the limit is {}", bytecode::COUNTERS))?;
+        self.module.emit_byte(Op::Count, idx);
+        self.counters += 1;
         Ok(())
     }
 
     fn compile_expr(&mut self, expr: &Expr) -> Result<(), String> {
+        if matches!(expr, Expr::BinOp(..) | Expr::UnaryOp(..) | Expr::Call(..)) {
+            if let Some(value) = self.fold(expr) {
+                return self.compile_expr(&value);
+            }
+        }
         match expr {
             Expr::Integer(n) => {
                 self.module.emit_word(Op::Push, *n as u16);
@@ -456,8 +1489,10 @@ impl Compiler {
                     self.module.emit_byte(Op::LoadLocal, idx);
                 } else if let Some(idx) = self.globals.get(name) {
                     self.module.emit_word(Op::LoadGlobal, *idx);
+                } else if name == "ARGV" {
+                    self.module.emit(Op::Argv);
                 } else {
-                    return Err(format!("Undefined variable: ${}", name));
+                    return Err(self.error_at("undefined-variable", &[&format!("${}", name)], format!("Undefined variable: ${}", name)));
                 }
             }
 
@@ -466,8 +1501,13 @@ impl Compiler {
                     self.module.emit_byte(Op::LoadLocal, idx);
                 } else if let Some(idx) = self.globals.get(name) {
                     self.module.emit_word(Op::LoadGlobal, *idx);
+                } else if name == "ARGV" {
+                    // The program's arguments, unless it declares its own
+                    self.module.emit(Op::Argv);
                 } else {
-                    return Err(format!("Undefined array: @{}", name));
+                    let tokens = [format!("@{}", name), format!("${}", name), format!("$#{}", name)];
+                    let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+                    return Err(self.error_at("undefined-variable", &tokens, format!("Undefined array: @{}", name)));
                 }
             }
 
@@ -476,26 +1516,70 @@ impl Compiler {
                     self.module.emit_byte(Op::LoadLocal, idx);
                 } else if let Some(idx) = self.globals.get(name) {
                     self.module.emit_word(Op::LoadGlobal, *idx);
+                } else if name == "ENV" {
+                    self.compile_env()?;
                 } else {
-                    return Err(format!("Undefined hash: %{}", name));
+                    return Err(self.error_at("undefined-variable", &[&format!("%{}", name), &format!("${}", name)], format!("Undefined hash: %{}", name)));
                 }
             }
 
             Expr::ArrayIndex(arr, idx) => {
                 self.compile_expr(arr)?;
                 self.compile_expr(idx)?;
+                self.check_index();
                 self.module.emit(Op::ArrGet);
             }
 
+            Expr::HashIndex(hash, key) if self.is_env(hash) => {
+                let name = match self.fold(key) {
+                    Some(Expr::String(name)) => Some(name),
+                    Some(Expr::Integer(n)) => Some(n.to_string()),
+                    _ => None,
+                };
+                match name {
+                    // A missing key reads as 0, as from any hash
+                    Some(name) => {
+                        let value = self.env.get(&name).cloned().unwrap_or(Expr::Integer(0));
+                        self.compile_expr(&value)?;
+                    }
+                    None => {
+                        self.compile_env()?;
+                        self.compile_expr(key)?;
+                        self.module.emit(Op::HashGet);
+                    }
+                }
+            }
+
             Expr::HashIndex(hash, key) => {
                 self.compile_expr(hash)?;
                 self.compile_expr(key)?;
                 self.module.emit(Op::HashGet);
             }
 
-            Expr::BinOp(left, op, right) => {
-                self.compile_expr(left)?;
+            Expr::BinOp(left, BinOp::Repeat, right) => {
+                match &**left {
+                    Expr::List(_) => {
+                        self.compile_expr(left)?;
+                        self.compile_expr(right)?;
+                        self.module.emit(Op::ArrRepeat);
+                        return Ok(());
+                    }
+                    // The Z80 runtime repeats only strings
+                    Expr::Integer(n) => self.compile_expr(&Expr::String((*n as i16).to_string()))?,
+                    _ => self.compile_expr(left)?,
+                }
                 self.compile_expr(right)?;
+                self.module.emit(Op::Repeat);
+            }
+
+            Expr::BinOp(left, op, right) => {
+                if is_text(op) {
+                    self.compile_text(left)?;
+                    self.compile_text(right)?;
+                } else {
+                    self.compile_expr(left)?;
+                    self.compile_expr(right)?;
+                }
 
                 let opcode = match op {
                     BinOp::Add => Op::Add,
@@ -504,6 +1588,7 @@ impl Compiler {
                     BinOp::Div => Op::Div,
                     BinOp::Mod => Op::Mod,
                     BinOp::Concat => Op::StrCat,
+                    BinOp::Repeat => Op::Repeat,
                     BinOp::Eq => Op::CmpEq,
                     BinOp::Ne => Op::CmpNe,
                     BinOp::Lt => Op::CmpLt,
@@ -530,7 +1615,7 @@ impl Compiler {
                         return Err("Power operator not yet implemented".to_string());
                     }
                 };
-                self.module.emit(opcode);
+                self.module.emit(self.arith(opcode));
             }
 
             Expr::UnaryOp(op, expr) => {
@@ -546,8 +1631,6 @@ impl Compiler {
             }
 
             Expr::PreIncrement(expr) => {
-                self.compile_lvalue_addr(expr)?;
-                self.module.emit(Op::Dup);
                 self.compile_load_indirect(expr)?;
                 self.module.emit(Op::Inc);
                 self.module.emit(Op::Dup);
@@ -555,8 +1638,6 @@ impl Compiler {
             }
 
             Expr::PreDecrement(expr) => {
-                self.compile_lvalue_addr(expr)?;
-                self.module.emit(Op::Dup);
                 self.compile_load_indirect(expr)?;
                 self.module.emit(Op::Dec);
                 self.module.emit(Op::Dup);
@@ -578,40 +1659,292 @@ impl Compiler {
             }
 
             Expr::Assign(target, value) => {
-                self.compile_expr(value)?;
+                self.compile_assigned(target, value)?;
                 self.module.emit(Op::Dup); // Keep value on stack as result
-                self.compile_assign_expr(target)?;
+                self.compile_assign_from(target, Some(value))?;
             }
 
             Expr::OpAssign(target, op, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-
-                let opcode = match op {
-                    BinOp::Add => Op::Add,
-                    BinOp::Sub => Op::Sub,
-                    BinOp::Mul => Op::Mul,
-                    BinOp::Div => Op::Div,
-                    BinOp::Concat => Op::StrCat,
-                    _ => return Err(format!("Unsupported op-assign: {:?}", op)),
-                };
-                self.module.emit(opcode);
+                self.compile_op_assign(target, op, value)?;
                 self.module.emit(Op::Dup);
                 self.compile_assign_expr(target)?;
             }
 
-            Expr::Call(name, args) => {
-                // Push arguments
+            // Only reached when the argument doesn't fold to a key
+            Expr::Call(name, _) if name == "ctrl" && !self.is_sub(name) => {
+                return Err("ctrl takes one constant key, as in ctrl('C')".to_string());
+            }
+
+            Expr::Call(name, args) if name == "__FILE__" && args.is_empty() => {
+                let idx = self.module.add_string(&self.file);
+                self.module.emit_word(Op::PushStr, idx);
+            }
+
+            // The calling sub's name and the line of the call, `level` calls up
+            Expr::Call(name, args) if name == "caller" && !self.is_sub(name) => {
+                match args.as_slice() {
+                    [] => self.module.emit_word(Op::Push, 0),
+                    [level] => self.compile_expr(level)?,
+                    _ => return Err("caller takes at most one argument, the number of calls to go up".to_string()),
+                }
+                self.module.emit_byte(Op::CallNative, NativeFunc::Caller as u8);
+            }
+
+            // Battery-backed boards: stop, to go on past the call after a reset
+            Expr::Call(name, args) if matches!(name.as_str(), "suspend" | "resumed") && !self.is_sub(name) => {
+                if !args.is_empty() {
+                    return Err(format!("{} takes no arguments", name));
+                }
+                self.module.emit(if name == "suspend" { Op::Suspend } else { Op::Resumed });
+            }
+
+            // A scalar is defined by its defined bit and an element or key
+            // while it exists; any other value is defined, but undef
+            Expr::Call(name, args) if name == "defined" && !self.is_sub(name) => {
+                let [value] = args.as_slice() else {
+                    return Err("defined takes one argument".to_string());
+                };
+                if let Some(bit) = self.scalar_bit(value) {
+                    self.compile_expr(&bit)?;
+                } else if self.undef_call(value) {
+                    // [result, bit] to the bit
+                    self.keep_bit = true;
+                    self.compile_expr(value)?;
+                    let undefined = self.module.pos() as usize + 1;
+                    self.module.emit_word(Op::JumpIfNot, 0);
+                    self.module.emit(Op::Pop);
+                    self.module.emit_word(Op::Push, 1);
+                    let end = self.module.pos() as usize + 1;
+                    self.module.emit_word(Op::Jump, 0);
+                    self.module.patch_addr(undefined, self.module.pos());
+                    self.module.emit(Op::Pop);
+                    self.module.emit_word(Op::Push, 0);
+                    self.module.patch_addr(end, self.module.pos());
+                } else if let Expr::ArrayIndex(..) | Expr::HashIndex(..) = value {
+                    self.compile_expr(&Expr::call("exists", vec![value.clone()]))?;
+                } else if self.is_undef(value) {
+                    self.module.emit_word(Op::Push, 0);
+                } else {
+                    self.compile_effect(value)?;
+                    self.module.emit_word(Op::Push, 1);
+                }
+            }
+            // Undef reads as 0, and `undef $x` clears $x's defined bit
+            Expr::Call(name, args) if name == "undef" && !self.is_sub(name) => match args.as_slice() {
+                [] => self.module.emit_word(Op::Push, 0),
+                [target] => self.compile_expr(&Expr::assign(target.clone(), Expr::call("undef", Vec::new())))?,
+                _ => return Err("undef takes at most one argument, the variable to clear".to_string()),
+            },
+
+            // printf's formatting into a new string, with the count of
+            // arguments pushed last
+            Expr::Call(name, args) if name == "sprintf" && !self.is_sub(name) => {
+                let Some((format, values)) = args.split_first() else {
+                    return Err("sprintf needs a format".to_string());
+                };
+                self.compile_expr(format)?;
+                for value in values {
+                    self.compile_expr(value)?;
+                }
+                self.module.emit_word(Op::Push, values.len() as u16);
+                self.module.emit_byte(Op::CallNative, NativeFunc::Sprintf as u8);
+            }
+
+            // A pack of constants is a constant, when the string table can
+            // hold it; otherwise like sprintf
+            Expr::Call(name, args) if name == "pack" && !self.is_sub(name) => {
+                let Some((template, values)) = args.split_first() else {
+                    return Err("pack needs a template".to_string());
+                };
+                if let Some(text) = self.pack_constant(template, values)? {
+                    return self.compile_expr(&Expr::String(text));
+                }
+                self.compile_expr(template)?;
+                for value in values {
+                    self.compile_expr(value)?;
+                }
+                self.module.emit_word(Op::Push, values.len() as u16);
+                self.module.emit_byte(Op::CallNative, NativeFunc::Pack as u8);
+            }
+
+            // Z80 IN and OUT, giving the byte read or written
+            Expr::Call(name, args) if matches!(name.as_str(), "port_out" | "port_in") && !self.is_sub(name) => {
+                let params = if name == "port_out" { 2 } else { 1 };
+                if args.len() != params {
+                    return Err(format!("{} takes {} but is called with {}", name, arguments(params), args.len()));
+                }
                 for arg in args {
                     self.compile_expr(arg)?;
                 }
+                self.module.emit(if name == "port_out" { Op::PortOut } else { Op::PortIn });
+            }
 
-                if let Some((addr, _)) = self.subs.get(name) {
-                    self.module.emit_word(Op::Call, *addr);
-                } else {
-                    // Forward reference
-                    self.forward_refs.push((name.clone(), self.module.pos() as usize + 1));
-                    self.module.emit_word(Op::Call, 0);
+            // Timer mode with its interrupt on: the control word, then the
+            // time constant, giving back the constant's byte
+            Expr::Call(name, args) if name == "ctc_timer" && !self.is_sub(name) => {
+                if args.len() != 3 {
+                    return Err(format!("ctc_timer takes 3 arguments but is called with {}", args.len()));
+                }
+                let control = match self.fold(&args[2]) {
+                    Some(Expr::Integer(16)) => 0x87,
+                    Some(Expr::Integer(256)) => 0xA7,
+                    _ => return Err("ctc_timer's prescaler must be 16 or 256".to_string()),
+                };
+                self.compile_expr(&args[0])?;
+                self.module.emit(Op::Dup);
+                self.module.emit_word(Op::Push, control);
+                self.module.emit(Op::PortOut);
+                self.module.emit(Op::Pop);
+                self.compile_expr(&args[1])?;
+                self.module.emit(Op::PortOut);
+            }
+
+            // The task calls the sub and ends when it returns
+            Expr::Call(name, args) if name == "spawn" && !self.is_sub(name) => {
+                let [Expr::Ref(sub)] = args.as_slice() else {
+                    return Err("spawn takes a sub as \\&name".to_string());
+                };
+                let Expr::Call(sub, sub_args) = sub.as_ref() else {
+                    return Err("spawn takes a sub as \\&name".to_string());
+                };
+                if !sub_args.is_empty() || !self.is_sub(sub) {
+                    return Err(format!("spawn: undefined sub `{}`", sub));
+                }
+                let spawn = self.module.pos() as usize + 1;
+                self.module.emit_word(Op::Spawn, 0);
+                let skip = self.module.pos() as usize + 1;
+                self.module.emit_word(Op::Jump, 0);
+                self.module.patch_addr(spawn, self.module.pos());
+                self.compile_expr(&Expr::Call(sub.clone(), Vec::new()))?;
+                self.module.emit(Op::Pop);
+                self.module.emit(Op::TaskEnd);
+                self.module.patch_addr(skip, self.module.pos());
+            }
+
+            Expr::Call(name, args) if name == "yield" && !self.is_sub(name) => {
+                if !args.is_empty() {
+                    return Err("yield takes no arguments".to_string());
+                }
+                self.module.emit(Op::Yield);
+            }
+
+            Expr::Call(name, args) if name == "ticks" && !self.is_sub(name) => {
+                if !args.is_empty() {
+                    return Err("ticks takes no arguments".to_string());
+                }
+                self.module.emit(Op::Ticks);
+            }
+
+            // Direct memory access, a byte or a little-endian word
+            Expr::Call(name, args) if matches!(name.as_str(), "peek" | "poke" | "peek16" | "poke16") && !self.is_sub(name) => {
+                let params = if name.starts_with("poke") { 2 } else { 1 };
+                if args.len() != params {
+                    return Err(format!("{} takes {} but is called with {}", name, arguments(params), args.len()));
+                }
+                let width = if name.ends_with("16") { 2 } else { 1 };
+                self.compile_expr(&args[0])?;
+                if params == 1 {
+                    self.module.emit_byte(Op::Peek, width);
+                    return Ok(());
+                }
+                if let Some((lo, hi)) = self.poke_range {
+                    // The last address written must be in the range too
+                    let last = hi.saturating_sub(width as u16 - 1);
+                    match self.fold(&args[0]) {
+                        Some(Expr::Integer(addr)) if !(lo..=last).contains(&(addr as u16)) => {
+                            return Err(format!("{} to {} is outside --poke-range {}-{}", name, addr as u16, lo, hi));
+                        }
+                        Some(Expr::Integer(_)) => {}
+                        _ => {
+                            self.module.emit_word(Op::Push, lo);
+                            self.module.emit_word(Op::Push, last);
+                            self.module.emit(Op::CheckPoke);
+                        }
+                    }
+                }
+                self.compile_expr(&args[1])?;
+                self.module.emit_byte(Op::Poke, width);
+            }
+
+            // Prints the VM stack and heap use so far
+            Expr::Call(name, args) if name == "memstats" && !self.is_sub(name) => {
+                if !args.is_empty() {
+                    return Err("memstats takes no arguments".to_string());
+                }
+                self.module.emit_byte(Op::CallNative, NativeFunc::MemStats as u8);
+            }
+
+            Expr::Call(name, args) => {
+                let keep = std::mem::take(&mut self.keep_bit);
+                // A sub of the same name wins over a native
+                if matches!(name.as_str(), "exists" | "delete" | "splice") && !self.is_sub(name) {
+                    return self.compile_array_builtin(name, args);
+                }
+                if let Some((native, params)) = NativeFunc::lookup(name).filter(|_| !self.is_sub(name)) {
+                    if args.len() != params {
+                        return Err(format!("{} takes {} but is called with {}", name, arguments(params), args.len()));
+                    }
+                    for arg in args {
+                        self.compile_expr(arg)?;
+                    }
+                    self.module.emit_byte(Op::CallNative, native as u8);
+                    return Ok(());
+                }
+
+                if let Some((params, value)) = self.inlinable.get(name) {
+                    // An argument used more than once is only copied when it's a
+                    // single push, so the code stays small
+                    let cheap = |(param, arg): (&String, &Expr)| {
+                        uses(value, param) <= 1 || matches!(arg, Expr::Integer(_) | Expr::String(_) | Expr::ScalarVar(_))
+                    };
+                    if args.len() == params.len()
+                        && args.iter().all(|arg| self.inline_arg(arg))
+                        && params.iter().zip(args).all(cheap)
+                    {
+                        let bindings: HashMap<&str, &Expr> = params.iter().map(String::as_str).zip(args).collect();
+                        let value = substitute(value, &bindings);
+                        return self.compile_expr(&value);
+                    }
+                }
+
+                // The slot for a result that can be undefined, under the
+                // arguments
+                let undef = self.undef_subs.contains(name);
+                if undef {
+                    self.module.emit_word(Op::Push, 0);
+                }
+                match self.sub_params(name) {
+                    // The arguments past the scalar parameters go in an array
+                    Some(params) if self.variadic.contains(name) => {
+                        let fixed = params as usize - 1;
+                        if args.len() < fixed {
+                            let message = format!("Sub {} takes at least {} but is called with {}", name, arguments(fixed), args.len());
+                            return Err(self.error_at("sub-arity", &[name], message));
+                        }
+                        for arg in &args[..fixed] {
+                            self.compile_expr(arg)?;
+                        }
+                        self.compile_expr(&Expr::List(args[fixed..].to_vec()))?;
+                    }
+                    Some(params) if args.len() != params as usize => {
+                        let message = format!("Sub {} takes {} but is called with {}", name, arguments(params as usize), args.len());
+                        return Err(self.error_at("sub-arity", &[name], message));
+                    }
+                    _ => {
+                        for arg in args {
+                            self.compile_expr(arg)?;
+                        }
+                    }
+                }
+
+                // What the call pushed: a variadic sub's rest are one array
+                let pushed = match self.sub_params(name) {
+                    Some(params) if self.variadic.contains(name) => params as usize,
+                    _ => args.len(),
+                };
+                self.emit_sub_addr(Op::Call, name, pushed);
+                if undef && !keep {
+                    self.module.emit(Op::Pop);
                 }
             }
 
@@ -624,8 +1957,19 @@ impl Compiler {
                 return Err(format!("Method calls not yet implemented: {}", method));
             }
 
+            // A hash in a list is its keys and values, in insertion order
+            Expr::List(items) if items.iter().any(|item| matches!(item, Expr::HashVar(_))) => {
+                let [hash] = items.as_slice() else {
+                    return Err("A hash in a list must be the only item, as in [%h]".to_string());
+                };
+                self.compile_expr(hash)?;
+                self.module.emit_byte(Op::CallNative, NativeFunc::Pairs as u8);
+            }
+
             Expr::List(items) => {
-                self.module.emit_byte(Op::NewArray, items.len() as u8);
+                let len = u8::try_from(items.len())
+                    .map_err(|_| format!("List of {} items, the limit is {}", items.len(), u8::MAX))?;
+                self.module.emit_byte(Op::NewArray, len);
                 for (i, item) in items.iter().enumerate() {
                     self.module.emit(Op::Dup);
                     self.module.emit_word(Op::Push, i as u16);
@@ -634,6 +1978,13 @@ impl Compiler {
                 }
             }
 
+            // Values for an array of keys, undef where a key is missing
+            Expr::HashSlice(hash, keys) => {
+                self.compile_expr(hash)?;
+                self.compile_expr(keys)?;
+                self.module.emit_byte(Op::CallNative, NativeFunc::Slice as u8);
+            }
+
             Expr::Hash(pairs) => {
                 self.module.emit(Op::NewHash);
                 for (key, value) in pairs {
@@ -684,11 +2035,11 @@ impl Compiler {
                 self.module.emit(Op::Not);
             }
 
-            Expr::Ref(expr) => {
+            Expr::Ref(_) => {
                 return Err("References not yet implemented".to_string());
             }
 
-            Expr::Deref(expr) => {
+            Expr::Deref(_) => {
                 return Err("Dereferences not yet implemented".to_string());
             }
         }
@@ -696,29 +2047,145 @@ impl Compiler {
         Ok(())
     }
 
+    /// `exists` and `delete` on an array element, and `splice(@a, offset,
+    /// count, LIST)`, whose count defaults to the rest of the array and
+    /// whose LIST is one array or any number of values
+    fn compile_array_builtin(&mut self, name: &str, args: &[Expr]) -> Result<(), String> {
+        if name == "splice" {
+            let [array, offset, rest @ ..] = args else {
+                return Err("splice takes an array, an offset, and optionally a count and a list".to_string());
+            };
+            self.compile_expr(array)?;
+            self.compile_expr(offset)?;
+            match rest.first() {
+                Some(count) => self.compile_expr(count)?,
+                None => self.module.emit_word(Op::Push, u16::MAX),
+            }
+            match rest.get(1..).unwrap_or_default() {
+                [] => self.module.emit_word(Op::Push, 0),
+                [list @ Expr::ArrayVar(_)] => self.compile_expr(list)?,
+                values => self.compile_expr(&Expr::List(values.to_vec()))?,
+            }
+            self.module.emit_byte(Op::CallNative, NativeFunc::Splice as u8);
+            return Ok(());
+        }
+        if let [Expr::HashIndex(hash, key)] = args {
+            if name == "exists" {
+                self.compile_expr(hash)?;
+                self.compile_expr(key)?;
+                self.module.emit_byte(Op::CallNative, NativeFunc::KeyExists as u8);
+                return Ok(());
+            }
+        }
+        let [Expr::ArrayIndex(arr, idx)] = args else {
+            return Err(format!("{} takes an array element, as in {} $a[0]", name, name));
+        };
+        self.compile_expr(arr)?;
+        self.compile_expr(idx)?;
+        let native = if name == "exists" { NativeFunc::Exists } else { NativeFunc::Delete };
+        self.module.emit_byte(Op::CallNative, native as u8);
+        Ok(())
+    }
+
+    /// `target OP value`, for `target OP= value` to store
+    fn compile_op_assign(&mut self, target: &Expr, op: &BinOp, value: &Expr) -> Result<(), String> {
+        if is_text(op) {
+            self.compile_text(target)?;
+            self.compile_text(value)?;
+        } else {
+            self.compile_expr(target)?;
+            self.compile_expr(value)?;
+        }
+
+        let opcode = match op {
+            BinOp::Add => Op::Add,
+            BinOp::Sub => Op::Sub,
+            BinOp::Mul => Op::Mul,
+            BinOp::Div => Op::Div,
+            BinOp::Concat => Op::StrCat,
+            BinOp::Repeat => Op::Repeat,
+            _ => return Err(format!("Unsupported op-assign: {:?}", op)),
+        };
+        self.module.emit(self.arith(opcode));
+        Ok(())
+    }
+
+    /// Compile `expr` for its effect alone, leaving nothing on the stack.
+    /// Assignments and increments store without keeping a copy of the
+    /// value; anything else has its value dropped.
+    fn compile_effect(&mut self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::PreIncrement(target) | Expr::PostIncrement(target) => {
+                self.compile_expr(target)?;
+                self.module.emit(Op::Inc);
+                self.compile_assign_expr(target)?;
+            }
+            Expr::PreDecrement(target) | Expr::PostDecrement(target) => {
+                self.compile_expr(target)?;
+                self.module.emit(Op::Dec);
+                self.compile_assign_expr(target)?;
+            }
+            Expr::Assign(target, value) => {
+                self.compile_assigned(target, value)?;
+                self.compile_assign_from(target, Some(value))?;
+            }
+            Expr::OpAssign(target, BinOp::Concat, value)
+                if matches!(&**target, Expr::ScalarVar(name) if self.buffered.contains(name)) =>
+            {
+                self.compile_expr(target)?;
+                self.compile_expr(value)?;
+                self.module.emit_byte(Op::CallNative, NativeFunc::BufAppend as u8);
+                self.module.emit(Op::Pop);
+            }
+            Expr::OpAssign(target, op, value) => {
+                self.compile_op_assign(target, op, value)?;
+                self.compile_assign_expr(target)?;
+            }
+            _ => {
+                self.compile_expr(expr)?;
+                self.module.emit(Op::Pop);
+            }
+        }
+        Ok(())
+    }
+
     fn compile_assign_expr(&mut self, target: &Expr) -> Result<(), String> {
+        self.compile_assign_from(target, None)
+    }
+
+    /// Store into `target` the value of `value`, when the assignment has
+    /// one, for a scalar's defined bit to follow
+    fn compile_assign_from(&mut self, target: &Expr, value: Option<&Expr>) -> Result<(), String> {
         match target {
-            Expr::ScalarVar(name) => {
+            Expr::HashVar(_) if self.is_env(target) => return Err("%ENV is read-only".to_string()),
+            // An array or hash variable holds a pointer, like a scalar
+            Expr::ScalarVar(name) | Expr::ArrayVar(name) | Expr::HashVar(name) => {
                 if let Some(idx) = self.find_local(name) {
                     self.module.emit_byte(Op::StoreLocal, idx);
                 } else if let Some(idx) = self.globals.get(name) {
                     self.module.emit_word(Op::StoreGlobal, *idx);
                 } else {
                     // Auto-vivify as local
-                    let idx = self.locals.last().unwrap().len() as u8;
-                    self.locals.last_mut().unwrap().insert(name.clone(), idx);
+                    let idx = self.new_var(name)?;
                     self.module.emit_byte(Op::StoreLocal, idx);
                 }
+                if let Expr::ScalarVar(name) = target {
+                    self.mark_defined(name, value)?;
+                }
             }
             Expr::ArrayIndex(arr, idx) => {
-                // Stack: [value, arr, idx]
+                // [value, arr, idx] to [arr, idx, value]; a store past the
+                // end extends the array, so it needs no bounds check
                 self.compile_expr(arr)?;
                 self.compile_expr(idx)?;
+                self.module.emit(Op::Rot);
                 self.module.emit(Op::ArrSet);
             }
+            Expr::HashIndex(hash, _) if self.is_env(hash) => return Err("%ENV is read-only".to_string()),
             Expr::HashIndex(hash, key) => {
                 self.compile_expr(hash)?;
                 self.compile_expr(key)?;
+                self.module.emit(Op::Rot);
                 self.module.emit(Op::HashSet);
             }
             _ => return Err("Invalid assignment target".to_string()),
@@ -726,11 +2193,6 @@ impl Compiler {
         Ok(())
     }
 
-    fn compile_lvalue_addr(&mut self, _expr: &Expr) -> Result<(), String> {
-        // For pre-increment/decrement - simplified
-        Ok(())
-    }
-
     fn compile_load_indirect(&mut self, expr: &Expr) -> Result<(), String> {
         self.compile_expr(expr)
     }
@@ -739,6 +2201,18 @@ impl Compiler {
         self.compile_assign_expr(expr)
     }
 
+    /// Declare `name` in the innermost scope, in a slot of its own in the
+    /// frame: from 0 up in the main program, and in a sub from -3 down,
+    /// under the return address and saved frame pointer
+    fn new_local(&mut self, name: &str) -> Result<u8, String> {
+        let n = self.frame_locals as i16;
+        let idx = if self.frame_params.is_some() { -3 - n } else { n };
+        let idx = i8::try_from(idx).map_err(|_| "Too many local variables: the limit is 128 in the main program and 126 in a sub".to_string())?;
+        self.frame_locals += 1;
+        self.locals.last_mut().unwrap().insert(name.to_string(), idx as u8);
+        Ok(idx as u8)
+    }
+
     fn find_local(&self, name: &str) -> Option<u8> {
         for scope in self.locals.iter().rev() {
             if let Some(idx) = scope.get(name) {
@@ -747,6 +2221,445 @@ impl Compiler {
         }
         None
     }
+
+    /// Declare `my` variable `name`, and its defined bit if it can be
+    /// undefined
+    fn new_var(&mut self, name: &str) -> Result<u8, String> {
+        let idx = self.new_local(name)?;
+        if self.undefinable.contains(name) {
+            self.new_local(&defined_bit(name))?;
+        }
+        Ok(idx)
+    }
+
+    /// The defined bit of `expr`, when it is a scalar that has one
+    fn scalar_bit(&self, expr: &Expr) -> Option<Expr> {
+        let Expr::ScalarVar(name) = expr else {
+            return None;
+        };
+        let bit = defined_bit(name);
+        (self.find_local(&bit).is_some() || self.globals.contains_key(&bit)).then_some(Expr::ScalarVar(bit))
+    }
+
+    /// `expr` is the value undef
+    fn is_undef(&self, expr: &Expr) -> bool {
+        matches!(expr, Expr::Call(name, args) if name == "undef" && args.is_empty() && !self.is_sub(name))
+    }
+
+    /// Set the defined bit of scalar `name`, if it has one, for the value
+    /// just stored: clear for undef, a copy of another scalar's bit, and
+    /// otherwise set
+    fn mark_defined(&mut self, name: &str, value: Option<&Expr>) -> Result<(), String> {
+        let Some(bit) = self.scalar_bit(&Expr::ScalarVar(name.to_string())) else {
+            return Ok(());
+        };
+        match value {
+            // Stored already, by compile_assigned
+            Some(value) if self.undef_call(value) => return Ok(()),
+            Some(value) if self.is_undef(value) => self.module.emit_word(Op::Push, 0),
+            Some(value) => self.compile_bit(value)?,
+            None => self.module.emit_word(Op::Push, 1),
+        }
+        self.compile_assign_expr(&bit)
+    }
+
+    /// Push whether `value`, just read, is defined: a scalar's defined bit,
+    /// whether an element or key exists, and otherwise 1
+    fn compile_bit(&mut self, value: &Expr) -> Result<(), String> {
+        match self.scalar_bit(value) {
+            Some(bit) => self.compile_expr(&bit),
+            None if may_not_exist(value) => self.compile_expr(&Expr::call("exists", vec![value.clone()])),
+            None => {
+                self.module.emit_word(Op::Push, 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// `expr` calls a sub whose result can be undefined
+    fn undef_call(&self, expr: &Expr) -> bool {
+        matches!(expr, Expr::Call(name, _) if self.undef_subs.contains(name) && self.is_sub(name))
+    }
+
+    /// Push `value`, to be stored in `target`. A call whose result can be
+    /// undefined stores its defined bit in the target's first, when the
+    /// target has one.
+    fn compile_assigned(&mut self, target: &Expr, value: &Expr) -> Result<(), String> {
+        if !self.undef_call(value) {
+            return self.compile_expr(value);
+        }
+        if let Expr::ScalarVar(name) = target {
+            if self.find_local(name).is_none() && !self.globals.contains_key(name) {
+                self.new_var(name)?;
+            }
+        }
+        match self.scalar_bit(target) {
+            Some(bit) => {
+                self.keep_bit = true;
+                self.compile_expr(value)?;
+                self.compile_assign_expr(&bit)
+            }
+            None => self.compile_expr(value),
+        }
+    }
+
+    /// `expr` for a string operator: an undefined scalar is the empty
+    /// string rather than 0
+    fn compile_text(&mut self, expr: &Expr) -> Result<(), String> {
+        let Some(bit) = self.scalar_bit(expr) else {
+            return self.compile_expr(expr);
+        };
+        self.compile_expr(&bit)?;
+        let undefined = self.module.pos() as usize + 1;
+        self.module.emit_word(Op::JumpIfNot, 0);
+        self.compile_expr(expr)?;
+        let end = self.module.pos() as usize + 1;
+        self.module.emit_word(Op::Jump, 0);
+        self.module.patch_addr(undefined, self.module.pos());
+        let idx = self.module.add_string("");
+        self.module.emit_word(Op::PushStr, idx);
+        self.module.patch_addr(end, self.module.pos());
+        Ok(())
+    }
+
+    /// Print `expr` for `print` or `say` to `handle`. An undefined scalar
+    /// prints nothing, with a warning on STDERR where the target has it.
+    fn compile_print(&mut self, expr: &Expr, handle: Option<Handle>, what: &str) -> Result<(), String> {
+        let Some(bit) = self.scalar_bit(expr) else {
+            self.compile_expr(expr)?;
+            self.module.emit(Op::Print);
+            return Ok(());
+        };
+        self.compile_expr(&bit)?;
+        let defined = self.module.pos() as usize + 1;
+        self.module.emit_word(Op::JumpIf, 0);
+        if self.stderr {
+            let line = self.line().map_or_else(String::new, |line| format!(" at line {}", line));
+            let warning = format!("Use of uninitialized value {} in {}{}\n", printer::expr(expr), what, line);
+            let to_errors = handle != Some(Handle::Stderr);
+            if to_errors {
+                self.module.emit_byte(Op::Select, 1);
+            }
+            let idx = self.module.add_string(&warning);
+            self.module.emit_word(Op::PushStr, idx);
+            self.module.emit(Op::Print);
+            if to_errors {
+                self.module.emit_byte(Op::Select, 0);
+            }
+        }
+        let end = self.module.pos() as usize + 1;
+        self.module.emit_word(Op::Jump, 0);
+        self.module.patch_addr(defined, self.module.pos());
+        self.compile_expr(expr)?;
+        self.module.emit(Op::Print);
+        self.module.patch_addr(end, self.module.pos());
+        Ok(())
+    }
+}
+
+/// The name of scalar `name`'s defined bit, with a space no variable has
+fn defined_bit(name: &str) -> String {
+    format!("{} defined", name)
+}
+
+/// `op` works on its operands' text
+fn is_text(op: &BinOp) -> bool {
+    matches!(op, BinOp::Concat | BinOp::StrEq | BinOp::StrNe | BinOp::StrLt | BinOp::StrGt | BinOp::StrLe | BinOp::StrGe | BinOp::StrCmp)
+}
+
+/// Scalars in `stmts` that can be undefined: those declared without a
+/// value, cleared with `undef`, assigned an element or key that may not
+/// exist or the result of one of `subs`, or assigned another of them
+fn undefinable(stmts: &[Stmt], subs: &HashSet<String>) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut copies = Vec::new();
+    let undef = |e: &Expr| matches!(e, Expr::Call(name, args) if name == "undef" && args.is_empty());
+    each_stmt(stmts, &mut |stmt| {
+        let mut assign = |var: &String, value: &Expr| match value {
+            _ if undef(value) || may_not_exist(value) => {
+                names.insert(var.clone());
+            }
+            Expr::Call(name, _) if subs.contains(name) => {
+                names.insert(var.clone());
+            }
+            Expr::ScalarVar(other) => copies.push((var.clone(), other.clone())),
+            _ => {}
+        };
+        match stmt {
+            Stmt::My(vars, None) | Stmt::Our(vars, None) => vars.iter().for_each(|var| assign(var, &Expr::call("undef", Vec::new()))),
+            Stmt::My(vars, Some(value)) | Stmt::Our(vars, Some(value)) if vars.len() == 1 => assign(&vars[0], value),
+            _ => {}
+        }
+        for expr in stmt_exprs(stmt) {
+            each_expr(expr, &mut |e| match e {
+                Expr::Call(name, args) if name == "undef" => {
+                    if let [Expr::ScalarVar(var)] = args.as_slice() {
+                        assign(var, &Expr::call("undef", Vec::new()));
+                    }
+                }
+                Expr::Assign(target, value) => {
+                    if let Expr::ScalarVar(var) = &**target {
+                        assign(var, value);
+                    }
+                }
+                _ => {}
+            });
+        }
+    });
+    // Until no copy adds a name
+    while let Some(i) = copies.iter().position(|(var, other)| names.contains(other) && !names.contains(var)) {
+        names.insert(copies.swap_remove(i).0);
+    }
+    names
+}
+
+/// Subs in `stmts` whose result can be undefined: those that return
+/// nothing, undef, an element or key that may not exist, a scalar of
+/// `names` or the result of one of `subs`. Running off the end returns 0.
+/// A `:native` or variadic sub, one that `started` names, whose calls don't
+/// know the result slot, and one whose slot is out of a local's reach, are
+/// left out.
+fn undef_results(stmts: &[Stmt], names: &HashSet<String>, subs: &HashSet<String>, started: &HashSet<String>) -> HashSet<String> {
+    let undefined = |value: &Option<Expr>| match value {
+        None => true,
+        Some(Expr::Call(name, args)) if name == "undef" && args.is_empty() => true,
+        Some(Expr::Call(name, _)) => subs.contains(name),
+        Some(Expr::ScalarVar(name)) => names.contains(name),
+        Some(value) => may_not_exist(value),
+    };
+    let mut found = subs.clone();
+    each_stmt(stmts, &mut |stmt| {
+        let Stmt::Sub { name, params, body, native: false, variadic: false } = stmt else {
+            return;
+        };
+        if started.contains(name) || params.len() >= MAX_PARAMS {
+            return;
+        }
+        let mut returns_undef = false;
+        each_stmt(body, &mut |stmt| returns_undef |= matches!(stmt, Stmt::Return(value) if undefined(value)));
+        if returns_undef {
+            found.insert(name.clone());
+        }
+    });
+    found
+}
+
+/// `value` is an element or key that may not exist, which can be read
+/// again for whether it does: it has no effects
+fn may_not_exist(value: &Expr) -> bool {
+    matches!(value, Expr::ArrayIndex(..) | Expr::HashIndex(..)) && repeatable(value)
+}
+
+/// Evaluating `expr` again gives the same value, and has no effects
+fn repeatable(expr: &Expr) -> bool {
+    match expr {
+        Expr::Integer(_) | Expr::String(_) | Expr::ScalarVar(_) | Expr::ArrayVar(_) | Expr::HashVar(_) => true,
+        Expr::ArrayIndex(a, b) | Expr::HashIndex(a, b) | Expr::BinOp(a, _, b) => repeatable(a) && repeatable(b),
+        Expr::UnaryOp(op, e) => *op != UnaryOp::Ref && repeatable(e),
+        _ => false,
+    }
+}
+
+/// `n` arguments, or 1 argument
+pub(crate) fn arguments(n: usize) -> String {
+    format!("{} argument{}", n, if n == 1 { "" } else { "s" })
+}
+
+fn sorted(names: &HashSet<String>) -> Vec<String> {
+    let mut names: Vec<String> = names.iter().cloned().collect();
+    names.sort();
+    names
+}
+
+/// The expressions of `stmt` itself, not of the statements nested in it
+pub(crate) fn stmt_exprs(stmt: &Stmt) -> Vec<&Expr> {
+    match stmt {
+        Stmt::Expr(e) | Stmt::Constant(_, e) => vec![e],
+        Stmt::My(_, value) | Stmt::Our(_, value) | Stmt::Return(value) => value.iter().collect(),
+        Stmt::If { cond, elsif_blocks, .. } => std::iter::once(cond).chain(elsif_blocks.iter().map(|(e, _)| e)).collect(),
+        Stmt::Unless { cond, .. } | Stmt::While { cond, .. } | Stmt::Until { cond, .. } => vec![cond],
+        Stmt::For { cond, step, .. } => cond.iter().chain(step).collect(),
+        Stmt::Foreach { list, .. } => vec![list],
+        Stmt::Print(_, exprs) | Stmt::Say(_, exprs) | Stmt::Printf(_, exprs) => exprs.iter().collect(),
+        Stmt::Assert(cond, message) => std::iter::once(cond).chain(message).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Call `f` on `expr` and each expression nested in it, parents first
+fn each_expr(expr: &Expr, f: &mut impl FnMut(&Expr)) {
+    f(expr);
+    for e in types::operands(expr) {
+        each_expr(e, f);
+    }
+}
+
+/// Most parameters a sub can have, each in reach of a signed local index
+const MAX_PARAMS: usize = 128;
+
+/// Number of parameters of sub `name`, which must fit the frame
+fn param_count(name: &str, params: &[String]) -> Result<u8, String> {
+    if params.len() > MAX_PARAMS {
+        return Err(format!("Too many parameters for sub {}: {}, the limit is {}", name, params.len(), MAX_PARAMS));
+    }
+    Ok(params.len() as u8)
+}
+
+/// `expr` with each parameter in `bindings` replaced by its argument
+fn substitute(expr: &Expr, bindings: &HashMap<&str, &Expr>) -> Expr {
+    let sub = |e: &Expr| Box::new(substitute(e, bindings));
+    match expr {
+        Expr::ScalarVar(name) => bindings.get(name.as_str()).map_or_else(|| expr.clone(), |&arg| arg.clone()),
+        Expr::BinOp(left, op, right) => Expr::BinOp(sub(left), op.clone(), sub(right)),
+        Expr::UnaryOp(op, e) => Expr::UnaryOp(op.clone(), sub(e)),
+        Expr::Ternary(cond, then_expr, else_expr) => Expr::Ternary(sub(cond), sub(then_expr), sub(else_expr)),
+        _ => expr.clone(),
+    }
+}
+
+/// How many times `expr` reads `$param`
+fn uses(expr: &Expr, param: &str) -> usize {
+    match expr {
+        Expr::ScalarVar(name) => (name == param) as usize,
+        Expr::BinOp(left, _, right) => uses(left, param) + uses(right, param),
+        Expr::UnaryOp(_, e) => uses(e, param),
+        Expr::Ternary(cond, then_expr, else_expr) => {
+            uses(cond, param) + uses(then_expr, param) + uses(else_expr, param)
+        }
+        _ => 0,
+    }
+}
+
+/// Scalars that `stmts` name only as the target of `$var .= value`
+/// statements, nested ones included. A sub defined among them could run
+/// later, so then none.
+fn append_only(stmts: &[Stmt]) -> Vec<String> {
+    let mut targets = Vec::new();
+    let mut subs = false;
+    each_stmt(stmts, &mut |stmt| {
+        subs |= matches!(stmt, Stmt::Sub { .. });
+        if let Stmt::Expr(Expr::OpAssign(target, BinOp::Concat, _)) = stmt {
+            if let Expr::ScalarVar(name) = &**target {
+                targets.push(name.clone());
+            }
+        }
+    });
+    if subs {
+        return Vec::new();
+    }
+    targets.sort();
+    targets.dedup();
+    targets.retain(|name| {
+        let (mut appends, mut named) = (0, 0);
+        each_stmt(stmts, &mut |stmt| {
+            if matches!(stmt, Stmt::Expr(Expr::OpAssign(target, BinOp::Concat, _)) if **target == Expr::ScalarVar(name.clone())) {
+                appends += 1;
+            }
+            named += stmt_mentions(stmt, name);
+        });
+        appends == named
+    });
+    targets
+}
+
+/// Call `f` on each of `stmts` and the statements nested in them, parents
+/// first
+pub(crate) fn each_stmt(stmts: &[Stmt], f: &mut impl FnMut(&Stmt)) {
+    for stmt in stmts {
+        f(stmt);
+        match stmt {
+            Stmt::If { then_block, elsif_blocks, else_block, .. } => {
+                each_stmt(then_block, f);
+                for (_, block) in elsif_blocks {
+                    each_stmt(block, f);
+                }
+                if let Some(block) = else_block {
+                    each_stmt(block, f);
+                }
+            }
+            Stmt::Unless { then_block, else_block, .. } => {
+                each_stmt(then_block, f);
+                if let Some(block) = else_block {
+                    each_stmt(block, f);
+                }
+            }
+            Stmt::For { init, body, .. } => {
+                if let Some(init) = init {
+                    each_stmt(std::slice::from_ref(&**init), f);
+                }
+                each_stmt(body, f);
+            }
+            Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Foreach { body, .. } | Stmt::Sub { body, .. }
+            | Stmt::Block(body) | Stmt::Begin(body) => each_stmt(body, f),
+            _ => {}
+        }
+    }
+}
+
+/// How often `stmt` itself, not the statements nested in it, names scalar
+/// `var`
+fn stmt_mentions(stmt: &Stmt, var: &str) -> usize {
+    let all = |exprs: &[Expr]| exprs.iter().map(|e| mentions(e, var)).sum::<usize>();
+    let declares = |names: &[String]| names.iter().filter(|name| *name == var).count();
+    match stmt {
+        Stmt::Expr(e) | Stmt::Constant(_, e) => mentions(e, var),
+        Stmt::My(names, init) | Stmt::Our(names, init) => declares(names) + init.as_ref().map_or(0, |e| mentions(e, var)),
+        Stmt::If { cond, elsif_blocks, .. } => mentions(cond, var) + elsif_blocks.iter().map(|(e, _)| mentions(e, var)).sum::<usize>(),
+        Stmt::Unless { cond, .. } | Stmt::While { cond, .. } | Stmt::Until { cond, .. } => mentions(cond, var),
+        Stmt::For { cond, step, .. } => cond.iter().chain(step).map(|e| mentions(e, var)).sum(),
+        Stmt::Foreach { var: name, list, .. } => (name == var) as usize + mentions(list, var),
+        Stmt::Return(value) => value.as_ref().map_or(0, |e| mentions(e, var)),
+        Stmt::Sub { params, .. } => declares(params),
+        Stmt::Print(_, exprs) | Stmt::Say(_, exprs) | Stmt::Printf(_, exprs) => all(exprs),
+        Stmt::Assert(cond, message) => mentions(cond, var) + message.as_ref().map_or(0, |e| mentions(e, var)),
+        Stmt::Last | Stmt::Next | Stmt::Block(_) | Stmt::Begin(_) | Stmt::Use(_) | Stmt::Package(_) | Stmt::Include { .. } => 0,
+    }
+}
+
+/// How often `expr` names scalar `var`
+fn mentions(expr: &Expr, var: &str) -> usize {
+    let all = |exprs: &[Expr]| exprs.iter().map(|e| mentions(e, var)).sum::<usize>();
+    match expr {
+        Expr::ScalarVar(name) => (name == var) as usize,
+        Expr::Integer(_) | Expr::Float(_) | Expr::String(_) | Expr::ArrayVar(_) | Expr::HashVar(_) => 0,
+        Expr::ArrayIndex(a, b) | Expr::HashIndex(a, b) | Expr::HashSlice(a, b) | Expr::BinOp(a, _, b)
+        | Expr::Assign(a, b) | Expr::OpAssign(a, _, b) | Expr::Range(a, b) => mentions(a, var) + mentions(b, var),
+        Expr::UnaryOp(_, e) | Expr::PreIncrement(e) | Expr::PreDecrement(e) | Expr::PostIncrement(e)
+        | Expr::PostDecrement(e) | Expr::Match(e, ..) | Expr::NotMatch(e, ..) | Expr::Ref(e) | Expr::Deref(e) => mentions(e, var),
+        Expr::Call(_, args) | Expr::List(args) => all(args),
+        Expr::MethodCall(obj, _, args) => mentions(obj, var) + all(args),
+        Expr::Hash(pairs) => pairs.iter().map(|(k, v)| mentions(k, var) + mentions(v, var)).sum(),
+        Expr::Ternary(a, b, c) => mentions(a, var) + mentions(b, var) + mentions(c, var),
+    }
+}
+
+/// `text x n` as a constant, unless it is too long for the string table
+/// (the runtime cuts it to fit a string instead)
+/// The built-in control characters, `CR`, `LF`, `BELL`, `ESC` and `NUL`,
+/// as one-character strings
+fn char_constant(name: &str) -> Option<Expr> {
+    let code = match name {
+        "NUL" => 0,
+        "BELL" => 7,
+        "LF" => 10,
+        "CR" => 13,
+        "ESC" => 27,
+        _ => return None,
+    };
+    Some(Expr::String(char::from(code).to_string()))
+}
+
+/// `ctrl('C')`: the control character typed with Ctrl and `key`
+fn ctrl(key: &str) -> Option<Expr> {
+    match key.as_bytes() {
+        &[c @ (b'@'..=b'_' | b'a'..=b'z')] => Some(Expr::String(char::from(c & 0x1F).to_string())),
+        _ => None,
+    }
+}
+
+fn repeat(text: &str, n: i32) -> Option<Expr> {
+    let n = (n as i16).max(0) as usize;
+    (text.len() * n <= u8::MAX as usize).then(|| Expr::String(text.repeat(n)))
 }
 
 #[cfg(test)]
@@ -761,8 +2674,7 @@ mod tests {
         let tokens = lexer.tokenize();
         let mut parser = Parser::new(tokens);
         let program = parser.parse()?;
-        let compiler = Compiler::new();
-        compiler.compile(&program)
+        Compiler::new().compile(&program)
     }
 
     fn get_opcodes(module: &Module) -> Vec<Op> {
@@ -836,6 +2748,27 @@ mod tests {
                 "Wildcard pattern should be preserved");
     }
 
+    #[test]
+    fn test_compile_line_table() {
+        let module = compile("my $x = 1;\n\nwhile ($x < 3) {\n    $x++;\n}\n").unwrap();
+
+        assert_eq!(module.line_at(0), Some(1));
+        // The loop condition starts right after the first statement
+        let body = module.lines.iter().find(|&&(_, line)| line == 4).unwrap().0;
+        assert_eq!(module.line_at(body), Some(4));
+        assert_eq!(module.line_at(body - 1), Some(3));
+        // The jump back to the test is the loop's too
+        assert_eq!(module.line_at(module.pos() - 4), Some(3));
+
+        // An elsif's test is on its line, and a for loop's step on the loop's
+        let module = compile(
+            "my $x = 1;\nif ($x == 1) {\n    print 1;\n} elsif ($x == 2) {\n    print 2;\n}\nfor (my $i = 0; $i < 2; $i++) {\n    print $i;\n}\n",
+        )
+        .unwrap();
+        let lines: Vec<usize> = module.lines.iter().map(|&(_, line)| line).collect();
+        assert_eq!(lines, [1, 2, 3, 4, 5, 7, 8, 7]);
+    }
+
     #[test]
     fn test_compile_match_empty_pattern() {
         let module = compile(r#"my $x = "test"; $x =~ //;"#).unwrap();
@@ -948,4 +2881,511 @@ mod tests {
         assert!(ops.contains(&Op::Match));
         assert!(ops.contains(&Op::Jump), "While loop should have Jump for looping");
     }
+
+    #[test]
+    fn test_compile_sub_arity() {
+        let err = compile("sub add($a, $b) { return $a + $b; } print add(1);").unwrap_err();
+        assert_eq!(err, "Sub add takes 2 arguments but is called with 1");
+        // Checked for calls ahead of the definition too, nested ones included
+        assert!(compile("print twice(1, 2); sub twice($n) { return $n + $n; }").is_err());
+        assert_eq!(
+            compile("sub f { g(1); }\n{ sub g { return 0; } }").unwrap_err(),
+            "Sub g takes 0 arguments but is called with 1"
+        );
+    }
+
+    #[test]
+    fn test_forward_sub_call() {
+        // Calls ahead of the definition, at the top level and from another
+        // sub, reach the sub once it has an address
+        let module = compile("print first(1);\nsub first($n) { return second($n); }\n{ sub second($n) { return $n; } }").unwrap();
+        let addr = |name: &str| module.subs.iter().find(|(sub, _, _)| sub == name).unwrap().1;
+        let mut pc = 0;
+        let mut targets = Vec::new();
+        while pc < module.code.len() {
+            let op = Op::from_byte(module.code[pc]);
+            if op == Op::Call || (op == Op::Jump && pc > 0 && Op::from_byte(module.code[pc - 2]) == Op::TailCall) {
+                targets.push(u16::from_le_bytes([module.code[pc + 1], module.code[pc + 2]]));
+            }
+            pc += op.size();
+        }
+        assert_eq!(targets, [addr("first"), addr("second")]);
+        // A sub wins over a builtin even when defined further down
+        let ops = get_opcodes(&compile("print memstats();\nsub memstats { return 1; }").unwrap());
+        assert!(ops.contains(&Op::Call) && !ops.contains(&Op::CallNative));
+    }
+
+    #[test]
+    fn test_compile_variadic_sub() {
+        // The arguments past $sep go in a new array
+        let module = compile("sub join_all($sep, @items) { return $sep; }\nprint join_all(\",\", 1, 2);").unwrap();
+        let ops = get_opcodes(&module);
+        assert_eq!(module.variadic, ["join_all"]);
+        assert!(ops.windows(2).any(|w| w == [Op::NewArray, Op::Dup]));
+        assert!(compile("sub f($sep, @items) { }\nf(\",\");").is_ok());
+
+        assert_eq!(
+            compile("sub f($sep, @items) { }\nf();").unwrap_err(),
+            "Sub f takes at least 1 argument but is called with 0"
+        );
+        // Declared ahead of the call, so it gets the array too
+        let ops = get_opcodes(&compile("sub g { f(1); }\n{ sub f(@items) { } }").unwrap());
+        assert!(ops.contains(&Op::NewArray));
+        assert_eq!(
+            compile("sub f(@items) :native { return 0; }").unwrap_err(),
+            "Sub f is :native, which can't take a list of arguments"
+        );
+    }
+
+    #[test]
+    fn test_compile_more_continues_module() {
+        let parse = |code: &str| Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        let (first, start) = compiler.compile_more(&parse("our $x = 1; sub two() { return 2; }"), false).unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(first.code.last(), Some(&(Op::Halt as u8)));
+
+        // The new code overwrites the Halt and still sees $x and two()
+        let (second, start) = compiler.compile_more(&parse("$x + two();"), true).unwrap();
+        assert_eq!(start as usize, first.code.len() - 1);
+        assert_eq!(second.globals, vec!["x".to_string()]);
+        let ops = get_opcodes(&second);
+        assert_eq!(&ops[ops.len() - 2..], &[Op::Add, Op::Halt]);
+
+        assert!(compiler.compile_more(&parse("three();"), false)
+            .unwrap_err().contains("Undefined subroutine"));
+    }
+
+    #[test]
+    fn test_compile_constants() {
+        // Folded into one Push, with 16-bit wrapping as on the VM
+        let module = compile("use constant PORT => 128;\nuse constant NEXT => (PORT + 1) * 512;\nprint NEXT;").unwrap();
+        assert_eq!(get_opcodes(&module), vec![Op::Push, Op::Print, Op::Halt]);
+        assert_eq!(&module.code[1..3], &0x0200u16.to_le_bytes());
+
+        let module = compile("use constant NAME => \"a\" . \"b\";\nprint NAME;").unwrap();
+        assert_eq!(module.strings, vec!["ab".to_string()]);
+
+        let err = compile("my $x = 1;\nuse constant X => $x;").unwrap_err();
+        assert_eq!(err, "Constant X needs a value known at compile time");
+        // Division by zero is not folded
+        assert!(get_opcodes(&compile("print 1 / 0;").unwrap()).contains(&Op::Div));
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let code = "use constant LCD => 0;\nif (LCD) {\n    print 1;\n} elsif (2) {\n    print 2;\n} else {\n    print 3;\n}\n\
+                    unless (LCD) { print 4; } else { print 6; }\nprint 5;";
+        let module = compile(code).unwrap();
+        // Only the branches taken are left, at their own lines
+        assert_eq!(get_opcodes(&module), vec![Op::Push, Op::Print, Op::Push, Op::Print, Op::Push, Op::Print, Op::Halt]);
+        assert_eq!(&module.code[1..3], &2u16.to_le_bytes());
+        assert_eq!(module.lines, vec![(0, 5), (4, 9), (8, 10)]);
+
+        // A sub in a branch left out is left out with it
+        let err = compile("if (0) { sub f() { return 1; } }\nprint f();").unwrap_err();
+        assert_eq!(err, "Undefined subroutine: f");
+        // Conditions known only at run time still branch
+        assert!(get_opcodes(&compile("my $x = 1;\nif (0) { print 1; } elsif ($x) { print 2; }").unwrap()).contains(&Op::JumpIfNot));
+    }
+
+    #[test]
+    fn test_error_lines() {
+        let line = |code: &str| {
+            let mut compiler = Compiler::new();
+            compiler.compile(&Parser::new(Lexer::new(code).tokenize()).parse().unwrap()).unwrap_err();
+            compiler.line()
+        };
+        // A call to a sub never defined is blamed on the call, not the end
+        assert_eq!(line("print 1;\nnope();\nprint 2;\nprint 3;"), Some(2));
+        assert_eq!(line("print 1;\nmy $x = 2 +\n  nope();\nprint 3;"), Some(2));
+        // The module's limits belong to no statement
+        let strings: String = (0..300).map(|i| format!("print \"s{}\";\n", i)).collect();
+        assert_eq!(line(&strings), None);
+    }
+
+    #[test]
+    fn test_char_constants() {
+        let module = compile("print ESC . \"[2J\", CR . LF, ctrl('c'), BELL;").unwrap();
+        assert_eq!(module.strings, vec!["\x1b[2J".to_string(), "\r\n".to_string(), "\x03".to_string(), "\x07".to_string()]);
+        // A constant or sub of the same name comes first
+        let module = compile("use constant CR => 1;\nsub ESC() { return 2; }\nprint CR, ESC();").unwrap();
+        assert!(module.strings.is_empty());
+
+        let err = compile("my $k = 'C';\nprint ctrl($k);").unwrap_err();
+        assert_eq!(err, "ctrl takes one constant key, as in ctrl('C')");
+        assert!(compile("print ctrl('CC');").is_err());
+    }
+
+    #[test]
+    fn test_define_overrides_constant() {
+        let program = Parser::new(Lexer::new("use constant PORT => 128;\nprint PORT, NAME;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.define("PORT", "0x81").unwrap();
+        compiler.define("NAME", "rc2014").unwrap();
+        let module = compiler.compile(&program).unwrap();
+        assert_eq!(&module.code[1..3], &0x81u16.to_le_bytes());
+        assert_eq!(module.strings, vec!["rc2014".to_string()]);
+
+        let mut compiler = Compiler::new();
+        assert_eq!(compiler.define("1X", "1").unwrap_err(), "Invalid constant name: 1X");
+        assert!(compiler.define("BIG", "70000").is_err());
+        compiler.define("NEG", "-5").unwrap();
+    }
+
+    #[test]
+    fn test_assert() {
+        let module = compile("my $x = 1;\nassert $x < 2;").unwrap();
+        assert_eq!(
+            get_opcodes(&module),
+            vec![Op::Push, Op::StoreLocal, Op::LoadLocal, Op::Push, Op::CmpLt, Op::JumpIf, Op::PushStr, Op::Die, Op::Halt]
+        );
+        assert_eq!(module.strings, vec!["Assertion failed: $x < 2".to_string()]);
+
+        // Stripped, without evaluating the condition
+        let program = Parser::new(Lexer::new("assert f(), \"f failed\";").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.define("NDEBUG", "1").unwrap();
+        let module = compiler.compile(&program).unwrap();
+        assert_eq!(get_opcodes(&module), vec![Op::Halt]);
+        assert!(module.strings.is_empty());
+        let module = compile("use constant NDEBUG => 1;\nassert 0;").unwrap();
+        assert_eq!(get_opcodes(&module), vec![Op::Halt]);
+    }
+
+    #[test]
+    #[cfg(feature = "host-vm")]
+    fn test_begin() {
+        let code = "BEGIN {\n    my $t = \"\";\n    my $i = 0;\n    while ($i < 4) {\n        $t = $t . $i * 2;\n        $i++;\n    }\n    \
+                    use constant TABLE => $t;\n    use constant COUNT => $i;\n    print \"dropped\";\n}\nprint TABLE, COUNT;";
+        let module = compile(code).unwrap();
+        // Only the results are left in the program
+        assert_eq!(get_opcodes(&module), vec![Op::PushStr, Op::Print, Op::Push, Op::Print, Op::Halt]);
+        assert_eq!(module.strings, vec!["0246".to_string()]);
+        assert_eq!(module.lines, vec![(0, 12)]);
+
+        // --define still wins
+        let program = Parser::new(Lexer::new("BEGIN { use constant N => 1 + 1; }\nprint N;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.define("N", "7").unwrap();
+        let module = compiler.compile(&program).unwrap();
+        assert_eq!(&module.code[..3], &[Op::Push as u8, 7, 0]);
+
+        // Numbers stay numbers, however big or negative
+        let code = "BEGIN {\n    my $n = 0 - 1;\n    use constant NEG => $n;\n    use constant BIG => 5000;\n    use constant SUM => f(4000);\n    \
+                    sub f($x) { return $x + 96; }\n}\nprint NEG + 2, BIG, SUM;";
+        let module = compile(code).unwrap();
+        assert!(module.strings.is_empty());
+        assert_eq!(get_opcodes(&module), vec![Op::Push, Op::Print, Op::Push, Op::Print, Op::Push, Op::Print, Op::Halt]);
+        let words: Vec<u16> = [1, 5, 9].iter().map(|&i| u16::from_le_bytes([module.code[i], module.code[i + 1]])).collect();
+        assert_eq!(words, [1, 5000, 4096]);
+
+        assert_eq!(compile("BEGIN { my $z = 0; my $x = 1 / $z; }").unwrap_err(), "BEGIN block failed: Illegal division by zero at line 1");
+        assert_eq!(compile("BEGIN { print $nope; }").unwrap_err(), "In BEGIN: Undefined variable: $nope");
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let program = Parser::new(Lexer::new("my $x = 1;\n$x += 2;\nprint $x - 1, $x * 3, $x / 2;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_checked(true);
+        let ops = get_opcodes(&compiler.compile(&program).unwrap());
+        assert!(ops.contains(&Op::AddChk) && ops.contains(&Op::SubChk) && ops.contains(&Op::MulChk));
+        assert!(!ops.contains(&Op::Add) && !ops.contains(&Op::Sub) && !ops.contains(&Op::Mul));
+        assert!(ops.contains(&Op::Div));
+
+        // Constants that overflow are left to trap at run time
+        let program = Parser::new(Lexer::new("print 30000 + 3000, 2 * 3;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_checked(true);
+        let ops = get_opcodes(&compiler.compile(&program).unwrap());
+        assert_eq!(ops, vec![Op::Push, Op::Push, Op::AddChk, Op::Print, Op::Push, Op::Print, Op::Halt]);
+    }
+
+    #[test]
+    fn test_pack_constants() {
+        // ASCII from constants is a string constant
+        let module = compile(r#"my $s = pack("a2 C n", "OK", 33, 16706);"#).unwrap();
+        assert_eq!(module.strings, ["OK!AB"]);
+        assert!(!get_opcodes(&module).contains(&Op::CallNative));
+        for code in [r#"my $s = pack("C", 200);"#, r#"my $n = 1; my $s = pack("C", $n);"#] {
+            assert!(get_opcodes(&compile(code).unwrap()).contains(&Op::CallNative), "{}", code);
+        }
+        assert!(compile(r#"my $s = pack("Q", 1);"#).unwrap_err().contains("unknown format"));
+    }
+
+    #[test]
+    fn test_bounds_check() {
+        let program = Parser::new(Lexer::new("my @a = [1, 2];\nmy $x = $a[1];\n$a[0] = $x;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_bounds_check(true);
+        let ops = get_opcodes(&compiler.compile(&program).unwrap());
+        // Not for the literal's own elements, nor stores, which extend
+        assert_eq!(ops.iter().filter(|&&op| op == Op::CheckIdx).count(), 1);
+        let at = ops.iter().position(|&op| op == Op::CheckIdx).unwrap();
+        assert_eq!(ops[at + 1], Op::ArrGet);
+
+        let mut compiler = Compiler::new();
+        compiler.set_bounds_check(true);
+        compiler.define("NDEBUG", "1").unwrap();
+        assert!(!get_opcodes(&compiler.compile(&program).unwrap()).contains(&Op::CheckIdx));
+    }
+
+    #[test]
+    fn test_poke_range() {
+        let compile_in = |source: &str| {
+            let mut compiler = Compiler::new();
+            compiler.set_poke_range(Some((100, 199)));
+            compiler.compile(&Parser::new(Lexer::new(source).tokenize()).parse().unwrap())
+        };
+        // Constant addresses are checked here, the rest at run time
+        let ops = get_opcodes(&compile_in("use constant BASE => 150;\npoke(BASE, 1);\npoke16(198, 2);").unwrap());
+        assert!(!ops.contains(&Op::CheckPoke));
+        assert_eq!(compile_in("poke16(199, 2);").unwrap_err(), "poke16 to 199 is outside --poke-range 100-199");
+        let ops = get_opcodes(&compile_in("my $a = 5;\npoke($a, 1);\nprint peek(0);").unwrap());
+        let at = ops.iter().position(|&op| op == Op::CheckPoke).unwrap();
+        assert_eq!(ops[at + 2], Op::Poke);
+    }
+
+    #[test]
+    fn test_entry() {
+        let compile_with = |source: &str, entry: Option<&str>, menu: &[&str]| {
+            let mut compiler = Compiler::new();
+            compiler.set_entry(entry.map(str::to_string));
+            compiler.set_menu(menu.iter().map(|s| s.to_string()).collect());
+            compiler.compile(&Parser::new(Lexer::new(source).tokenize()).parse().unwrap())
+        };
+        let source = "sub a { print 1; }\nsub b { print 2; }\nsub c($n) { print $n; }\nprint 0;";
+        // The stub after the main code's Halt calls the sub
+        let module = compile_with(source, Some("b"), &[]).unwrap();
+        let stub = &module.code[module.entry as usize..];
+        let b = module.subs.iter().find(|(name, ..)| name == "b").unwrap().1;
+        assert_eq!(stub, [&[Op::Call as u8][..], &b.to_le_bytes(), &[Op::Pop as u8, Op::Halt as u8]].concat());
+        assert_eq!(module.code[module.entry as usize - 1], Op::Halt as u8);
+        assert_eq!(compile_with(source, None, &[]).unwrap().entry, 0);
+
+        let module = compile_with(source, None, &["a", "b"]).unwrap();
+        assert!(module.strings.contains(&"2) b\n".to_string()));
+        assert!(module.entry > 0);
+        // The menu reads its choice
+        assert!(module.reads_input());
+        assert!(!compile_with(source, Some("b"), &[]).unwrap().reads_input());
+        assert_eq!(compile_with(source, Some("d"), &[]).unwrap_err(), "Entry sub d is not defined");
+        assert_eq!(compile_with(source, None, &["a", "c"]).unwrap_err(),
+                   "Entry sub c takes 1 argument, but must take none");
+        assert!(compile_with(source, None, &["a"; 10]).is_err());
+    }
+
+    #[test]
+    fn test_loop_appends_use_a_buffer() {
+        let native = |ops: &[u8], func: NativeFunc| ops.windows(2).filter(|w| w == &[Op::CallNative as u8, func as u8]).count();
+        let buffered = |code: &str| {
+            let mut compiler = Compiler::new();
+            compiler.set_string_buffers(true);
+            compiler.compile(&Parser::new(Lexer::new(code).tokenize()).parse().unwrap()).unwrap()
+        };
+        let source = "my $s = \"\";\nmy $i = 0;\nwhile ($i < 3) { if ($i) { $s .= \",\"; } $s .= $i; $i++; }\n$s .= \".\";\nprint $s;";
+        let module = buffered(source);
+        assert_eq!(native(&module.code, NativeFunc::BufNew), 1);
+        assert_eq!(native(&module.code, NativeFunc::BufAppend), 3);
+        // Only the append after the loop copies
+        assert_eq!(get_opcodes(&module).iter().filter(|&&op| op == Op::StrCat).count(), 1);
+        // Not for the Z80 runtime, which has no buffers
+        assert_eq!(native(&compile(source).unwrap().code, NativeFunc::BufNew), 0);
+
+        // Read in the loop, appended to itself, a global, or appended to in
+        // a sub: copied each time
+        for code in [
+            "my $s = \"\";\nforeach my $c (@ARGV) { $s .= $c; print $s; }",
+            "my $s = \"a\";\nforeach my $c (@ARGV) { $s .= $s; }",
+            "our $s = \"\";\nforeach my $c (@ARGV) { $s .= $c; }",
+            "my $s = \"\";\nforeach my $c (@ARGV) { sub f { return 1; } $s .= $c; }",
+        ] {
+            assert_eq!(native(&buffered(code).code, NativeFunc::BufNew), 0, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_set_optimize() {
+        // The else branch's Jump over nothing stays without the optimizer
+        let program = Parser::new(Lexer::new("my $x = 1;\nif ($x) { print 1; } else { }\nprint 2 + 3;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_optimize(false);
+        let raw = compiler.compile(&program).unwrap();
+        let optimized = Compiler::new().compile(&program).unwrap();
+        assert!(raw.code.len() > optimized.code.len());
+        // Constants are folded either way
+        assert!(!get_opcodes(&raw).contains(&Op::Add));
+    }
+
+    #[test]
+    fn test_statement_context() {
+        // No copy of the value to pop
+        let ops = get_opcodes(&compile("my $i = 0;\n$i++;\n--$i;\n$i += 2;\nmy @a = [1];\n$a[0] = $i;").unwrap());
+        assert!(!ops.contains(&Op::Pop));
+        // The one Dup fills in the array
+        assert_eq!(ops.iter().filter(|&&op| op == Op::Dup).count(), 1);
+        assert_eq!(&ops[2..5], [Op::LoadLocal, Op::Inc, Op::StoreLocal]);
+        // Other values are still dropped, and a value used keeps its copy
+        let ops = get_opcodes(&compile("my $i = 0;\n$i + 1;\nprint ++$i;").unwrap());
+        assert_eq!(ops, [Op::Push, Op::StoreLocal, Op::LoadLocal, Op::Push, Op::Add, Op::Pop,
+                         Op::LoadLocal, Op::Inc, Op::Dup, Op::StoreLocal, Op::Print, Op::Halt]);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let ops = get_opcodes(&compile("sub count($n) { if ($n) { return count($n - 1); } return 0; }").unwrap());
+        let at = ops.iter().position(|&op| op == Op::TailCall).unwrap();
+        assert_eq!(ops[at + 1], Op::Jump);
+
+        // Not when more arguments than the frame holds, nor for a call that
+        // isn't the whole return value, nor outside a sub
+        assert!(!get_opcodes(&compile("sub f($a, $b) { return 0; }\nsub g($a) { return f($a, 1); }").unwrap())
+            .contains(&Op::TailCall));
+        assert!(!get_opcodes(&compile("sub f($n) { return 1 + f($n - 1); }").unwrap()).contains(&Op::TailCall));
+        assert!(!get_opcodes(&compile("sub f($n) { return $n; }\nreturn f(1);").unwrap()).contains(&Op::TailCall));
+    }
+
+    #[test]
+    fn test_inline_sub() {
+        let inline = |code: &str| {
+            let program = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_inline(INLINE_LIMIT);
+            get_opcodes(&compiler.compile(&program).unwrap())
+        };
+        // Each call becomes the body, on the arguments, which folds when
+        // they are constants
+        let ops = inline("sub sq($n) { return $n * $n; }\nmy $x = 3;\nprint sq($x), sq(4);");
+        assert!(!ops.contains(&Op::Call));
+        assert_eq!(ops.iter().filter(|&&op| op == Op::Mul).count(), 2);
+
+        // Arguments with side effects keep the call, as do bodies that do
+        // more than return, and calls ahead of the definition
+        assert!(inline("sub sq($n) { return $n * $n; }\nmy $x = 3;\nprint sq($x++);").contains(&Op::Call));
+        assert!(inline("sub sq($n) { return $n * $n; }\nmy $x = 3;\nprint sq($x + 1);").contains(&Op::Call));
+        assert!(inline("sub f($n) { print $n; return $n; }\nprint f(1);").contains(&Op::Call));
+        assert!(inline("sub f($n) { return g($n); }\nsub g($n) { return $n; }\nprint f(1);").contains(&Op::Call));
+        // Off by default
+        assert!(get_opcodes(&compile("sub one { return 1; }\nprint one();").unwrap()).contains(&Op::Call));
+    }
+
+    #[test]
+    fn test_env() {
+        let program = Parser::new(Lexer::new("print $ENV{\"HOST\"}, $ENV{\"PORT\"} + 1, $ENV{\"NONE\"};").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_env("HOST", "board").unwrap();
+        compiler.set_env("PORT", "0x80").unwrap();
+        let module = compiler.compile(&program).unwrap();
+        // Constant keys read straight from the string table
+        assert_eq!(module.strings, vec!["board".to_string()]);
+        assert!(!get_opcodes(&module).contains(&Op::HashGet));
+
+        let program = Parser::new(Lexer::new("my $k = \"HOST\";\nprint $ENV{$k};").tokenize()).parse().unwrap();
+        let ops = get_opcodes(&compiler.clone().compile(&program).unwrap());
+        assert_eq!(ops.iter().filter(|&&op| op == Op::HashSet).count(), 2);
+        assert!(ops.contains(&Op::HashGet));
+
+        let program = Parser::new(Lexer::new("$ENV{\"HOST\"} = 1;").tokenize()).parse().unwrap();
+        assert_eq!(compiler.compile(&program).unwrap_err(), "%ENV is read-only");
+    }
+
+    #[test]
+    fn test_argv() {
+        let ops = get_opcodes(&compile("print $ARGV[0];\nforeach my $a (@ARGV) { print $a; }").unwrap());
+        assert_eq!(ops.iter().filter(|&&op| op == Op::Argv).count(), 2);
+        // A program's own @ARGV hides the arguments
+        assert!(!get_opcodes(&compile("my @ARGV = [1];\nprint $ARGV[0];").unwrap()).contains(&Op::Argv));
+    }
+
+    #[test]
+    fn test_file_natives() {
+        let module = compile("my $fh = open(\"log.txt\", \">\");\nwrite($fh, \"hi\");\nclose($fh);").unwrap();
+        let natives: Vec<u8> = module.code.windows(2).filter(|w| w[0] == Op::CallNative as u8).map(|w| w[1]).collect();
+        assert_eq!(natives, vec![NativeFunc::Open as u8, NativeFunc::Write as u8, NativeFunc::Close as u8]);
+
+        assert_eq!(compile("close(1, 2);").unwrap_err(), "close takes 1 argument but is called with 2");
+        // A sub of the same name is called instead
+        let ops = get_opcodes(&compile("sub eof($h) { return 1; }\nprint eof(3);").unwrap());
+        assert!(ops.contains(&Op::Call) && !ops.contains(&Op::CallNative));
+    }
+
+    #[test]
+    fn test_array_builtins() {
+        let module = compile("my @a = [1];\nprint exists $a[0], delete($a[0]);\nsplice(@a, 0);").unwrap();
+        let natives: Vec<u8> = module.code.windows(2).filter(|w| w[0] == Op::CallNative as u8).map(|w| w[1]).collect();
+        assert_eq!(natives, vec![NativeFunc::Exists as u8, NativeFunc::Delete as u8, NativeFunc::Splice as u8]);
+
+        assert_eq!(compile("my $x = 1;\nprint exists $x;").unwrap_err(), "exists takes an array element, as in exists $a[0]");
+        assert_eq!(
+            compile("my @a = [1];\nsplice(@a);").unwrap_err(),
+            "splice takes an array, an offset, and optionally a count and a list"
+        );
+    }
+
+    #[test]
+    fn test_file_and_caller() {
+        let program = Parser::new(Lexer::new("print __FILE__;\nsub f { return caller(); }").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_file("lib/log.mpl");
+        let module = compiler.compile(&program).unwrap();
+        assert_eq!(module.strings, vec!["lib/log.mpl".to_string()]);
+        assert_eq!(module.sub_at(module.subs[0].1 + 4), Some("f"));
+        assert_eq!(module.sub_at(0), None);
+
+        assert_eq!(
+            compile("caller(1, 2);").unwrap_err(),
+            "caller takes at most one argument, the number of calls to go up"
+        );
+    }
+
+    #[test]
+    fn test_suspend() {
+        let module = compile("if (suspend()) { print resumed(); }").unwrap();
+        assert_eq!(Op::from_byte(module.code[0]), Op::Suspend);
+        assert!(module.code.contains(&(Op::Resumed as u8)));
+        assert_eq!(compile("suspend(1);").unwrap_err(), "suspend takes no arguments");
+    }
+
+    #[test]
+    fn test_memstats() {
+        let module = compile("memstats();").unwrap();
+        assert_eq!(module.code[..2], [Op::CallNative as u8, NativeFunc::MemStats as u8]);
+        assert_eq!(compile("memstats(1);").unwrap_err(), "memstats takes no arguments");
+    }
+
+    #[test]
+    #[cfg(feature = "z80-backend")]
+    fn test_native_sub() {
+        let module = compile("use constant K => 3;\nsub f($n) :native { return $n * K + g($n); }\nsub g($n) :native { return 1; }").unwrap();
+        assert_eq!(module.native.iter().map(|sub| sub.name.as_str()).collect::<Vec<_>>(), ["f", "g"]);
+        assert_eq!(module.native[0].constants.get("K"), Some(&Expr::Integer(3)));
+        assert_eq!(Op::from_byte(module.code[module.native[0].addr as usize]), Op::Native);
+
+        assert_eq!(compile("sub f($s) :native { return $s . \"x\"; }").unwrap_err(), "Sub f is :native, which can't use strings");
+        assert_eq!(compile("sub f :native { print 1; }").unwrap_err(), "Sub f is :native, which can't use print");
+        assert_eq!(
+            compile("sub f :native { return h(); }\nsub h { return 1; }").unwrap_err(),
+            "Sub f is :native, which can't use h(), which is not a native sub"
+        );
+    }
+
+    #[test]
+    fn test_repeat_folds() {
+        let module = compile("print \"=\" x 3, 7 x 2, \"ab\" x 200;").unwrap();
+        // The last is too long for the string table, so it repeats at run time
+        assert_eq!(module.strings, vec!["===".to_string(), "77".to_string(), "ab".to_string()]);
+        assert_eq!(get_opcodes(&module).iter().filter(|&&op| op == Op::Repeat).count(), 1);
+    }
+
+    #[test]
+    fn test_image_limits() {
+        let long = format!("print \"{}\";", "a".repeat(300));
+        assert_eq!(compile(&long).unwrap_err(), "String constant too long: 300 bytes, the limit is 255");
+        let params: Vec<String> = (0..300).map(|i| format!("$p{}", i)).collect();
+        let sub = format!("sub f({}) {{ return 1; }}", params.join(", "));
+        assert_eq!(compile(&sub).unwrap_err(), "Too many parameters for sub f: 300, the limit is 128");
+        let locals: String = (0..300).map(|i| format!("my $v{} = 1;\n", i)).collect();
+        assert_eq!(compile(&locals).unwrap_err(), "Too many local variables: the limit is 128 in the main program and 126 in a sub");
+    }
 }
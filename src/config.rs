@@ -0,0 +1,136 @@
+//! Project settings from microperl.toml
+//!
+//! Only the `[env]` table is read: each `KEY = value` in it becomes
+//! `$ENV{KEY}` in the program, as `--env KEY=VALUE` does. Values are
+//! strings (`"..."` with `\` escapes, or `'...'`), integers or booleans
+//! (`1` and `0`). Other tables are ignored, so the file can carry settings
+//! for other tools.
+
+use std::path::Path;
+
+/// Name of the settings file, looked for in the program's directory
+pub const FILE: &str = "microperl.toml";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// %ENV entries, in file order
+    pub env: Vec<(String, String)>,
+}
+
+impl Config {
+    /// The settings in `dir`, or none if it has no microperl.toml
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(dir.join(FILE)) {
+            Ok(text) => Config::parse(&text).map_err(|e| format!("{}:{}", dir.join(FILE).display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", dir.join(FILE).display(), e)),
+        }
+    }
+
+    /// Errors start with the line number, as `3: ...`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut in_env = false;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(table) = line.strip_prefix('[') {
+                let table = strip_comment(table).trim_end();
+                let name = table.strip_suffix(']').ok_or_else(|| format!("{}: Unclosed table header", i + 1))?;
+                in_env = name.trim() == "env";
+                continue;
+            }
+            if !in_env {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("{}: Expected KEY = value", i + 1))?;
+            let key = key.trim().trim_matches('"');
+            let value = parse_value(value.trim()).map_err(|e| format!("{}: {}", i + 1, e))?;
+            config.env.push((key.to_string(), value));
+        }
+        Ok(config)
+    }
+}
+
+/// `text` up to a `#` comment
+fn strip_comment(text: &str) -> &str {
+    text.split('#').next().unwrap_or("")
+}
+
+fn parse_value(text: &str) -> Result<String, String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return end_of_value(chars.as_str(), value),
+                '\\' => value.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(c @ ('"' | '\\')) => c,
+                    other => return Err(format!("Unknown escape \\{}", other.map_or(String::new(), String::from))),
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err("Unterminated string".to_string());
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let (value, rest) = rest.split_once('\'').ok_or("Unterminated string")?;
+        return end_of_value(rest, value.to_string());
+    }
+    match strip_comment(text).trim() {
+        "true" => Ok("1".to_string()),
+        "false" => Ok("0".to_string()),
+        // Left as written for the compiler, which reads decimal and 0x hex
+        number if is_integer(number) => Ok(number.trim_start_matches('+').replace('_', "")),
+        other => Err(format!("Unsupported value: {}", other)),
+    }
+}
+
+fn is_integer(text: &str) -> bool {
+    let digits = text.trim_start_matches(['-', '+']);
+    match digits.strip_prefix("0x") {
+        Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit() || c == '_'),
+        None => !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit() || c == '_'),
+    }
+}
+
+/// `value`, if only a comment follows it
+fn end_of_value(rest: &str, value: String) -> Result<String, String> {
+    match strip_comment(rest).trim() {
+        "" => Ok(value),
+        extra => Err(format!("Unexpected {} after the value", extra)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env() {
+        let text = "name = \"demo\"\n\n[env]\n# Where the LED is\nPORT = 0x80\n\
+                    GREETING = \"hi \\\"you\\\"\\n\"  # quoted\nPATH = 'C:\\dir'\nDEBUG = true\nCOUNT = 1_000\n\n\
+                    [build]\nOTHER = \"x\"\n";
+        let config = Config::parse(text).unwrap();
+        let env: Vec<(&str, &str)> = config.env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            env,
+            vec![("PORT", "0x80"), ("GREETING", "hi \"you\"\n"), ("PATH", "C:\\dir"), ("DEBUG", "1"), ("COUNT", "1000")]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Config::parse("[env\n").unwrap_err(), "1: Unclosed table header");
+        assert_eq!(Config::parse("[env]\nA\n").unwrap_err(), "2: Expected KEY = value");
+        assert_eq!(Config::parse("[env]\nA = \"x\n").unwrap_err(), "2: Unterminated string");
+        assert_eq!(Config::parse("[env]\nA = \"x\" y\n").unwrap_err(), "2: Unexpected y after the value");
+        assert_eq!(Config::parse("[env]\nA = [1]\n").unwrap_err(), "2: Unsupported value: [1]");
+        assert_eq!(Config::load(Path::new("/nonexistent")).unwrap(), Config::default());
+    }
+}
@@ -0,0 +1,133 @@
+//! Line coverage from basic block counters
+//!
+//! A module compiled with `Compiler::set_coverage` starts each basic block
+//! with `Count idx`, which bumps 16-bit counter `idx`. The host VM keeps
+//! the counters in `Vm::counters`, and the Z80 runtime at the layout's
+//! `counters()` address, read back with `from_memory`. `lines` turns them
+//! into run counts per source line.
+
+use std::collections::BTreeMap;
+
+use crate::bytecode::{Module, Op, COUNTERS};
+
+/// Offset of each Count in `module` and the counter it bumps
+pub fn blocks(module: &Module) -> Vec<(u16, u8)> {
+    let mut blocks = Vec::new();
+    let mut pc = 0;
+    while pc < module.code.len() {
+        let op = Op::from_byte(module.code[pc]);
+        if op == Op::Count {
+            if let Some(&idx) = module.code.get(pc + 1) {
+                blocks.push((pc as u16, idx));
+            }
+        }
+        pc += op.size();
+    }
+    blocks
+}
+
+/// The counters stored at `base` in a machine's memory
+pub fn from_memory(mem: &[u8], base: u16) -> Vec<u16> {
+    (0..COUNTERS)
+        .map(|i| {
+            let at = base as usize + 2 * i;
+            u16::from_le_bytes([mem[at % mem.len()], mem[(at + 1) % mem.len()]])
+        })
+        .collect()
+}
+
+/// Source line -> times it ran, for every line with code in a basic block.
+/// A line's code runs whenever the block it is in runs; blocks are laid
+/// out in order and each begins with its Count, so that is the last Count
+/// at or before the line's code.
+pub fn lines(module: &Module, counters: &[u16]) -> BTreeMap<usize, u16> {
+    let blocks = blocks(module);
+    let mut by_line = BTreeMap::new();
+    for &(offset, line) in &module.lines {
+        let idx = blocks.partition_point(|&(at, _)| at <= offset);
+        let Some(&(_, counter)) = idx.checked_sub(1).and_then(|i| blocks.get(i)) else {
+            continue;
+        };
+        let runs = counters.get(counter as usize).copied().unwrap_or(0);
+        let entry = by_line.entry(line).or_insert(0);
+        *entry = runs.max(*entry);
+    }
+    by_line
+}
+
+/// Format the coverage of `by_line`, annotating `source` when given: each
+/// line with its run count, `#####` if it never ran, or `-` if it has no
+/// code
+pub fn render(by_line: &BTreeMap<usize, u16>, source: Option<&str>) -> String {
+    let ran = by_line.values().filter(|&&n| n > 0).count();
+    let percent = if by_line.is_empty() { 100.0 } else { ran as f64 * 100.0 / by_line.len() as f64 };
+    let mut out = format!("Coverage: {} of {} lines ({:.1}%)\n", ran, by_line.len(), percent);
+    let runs = |line: usize| match by_line.get(&line) {
+        Some(0) => "#####".to_string(),
+        Some(n) => n.to_string(),
+        None => "-".to_string(),
+    };
+    match source {
+        Some(source) => {
+            for (i, text) in source.lines().enumerate() {
+                out.push_str(&format!("  {:>6}  {:5}  {}\n", runs(i + 1), i + 1, text));
+            }
+        }
+        None => {
+            for &line in by_line.keys() {
+                out.push_str(&format!("  {:>6}  {:5}\n", runs(line), line));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(code: &str) -> Module {
+        let program = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_coverage(true);
+        compiler.compile(&program).unwrap()
+    }
+
+    #[test]
+    fn test_block_per_branch() {
+        let module = compile("my $x = 1;\nif ($x) {\n    print 1;\n} else {\n    print 2;\n}\nprint 3;\n");
+        // Entry, then, else and the join after the if
+        let counters: Vec<u8> = blocks(&module).iter().map(|&(_, idx)| idx).collect();
+        assert_eq!(counters, vec![0, 1, 2, 3]);
+
+        let mut counters = vec![0; COUNTERS];
+        counters[0] = 1;
+        counters[1] = 1;
+        counters[3] = 1;
+        let by_line = lines(&module, &counters);
+        assert_eq!(by_line.into_iter().collect::<Vec<_>>(), vec![(1, 1), (2, 1), (3, 1), (5, 0), (7, 1)]);
+    }
+
+    #[test]
+    fn test_render() {
+        let by_line = BTreeMap::from([(1, 3), (3, 0)]);
+        assert_eq!(
+            render(&by_line, Some("a;\n\nb;\n")),
+            "Coverage: 1 of 2 lines (50.0%)\n       3      1  a;\n       -      2  \n   #####      3  b;\n"
+        );
+    }
+
+    #[test]
+    fn test_from_memory() {
+        let mut mem = vec![0; 0x10000];
+        mem[0x2000] = 0x34;
+        mem[0x2001] = 0x12;
+        mem[0x2003] = 1;
+        let counters = from_memory(&mem, 0x2000);
+        assert_eq!(counters.len(), COUNTERS);
+        assert_eq!(&counters[..3], &[0x1234, 0x100, 0]);
+    }
+}
@@ -0,0 +1,303 @@
+//! Differential testing of the host VM against the Z80 runtime
+//!
+//! Runs a module on both the reference VM and the generated ROM in the
+//! embedded emulator, then compares console output, STDERR, how each run
+//! stopped and the final VM state (registers, live stack and locals, heap).
+
+use crate::bytecode::{Module, Op};
+use crate::vm::{self, Vm};
+use crate::z80::{self, RomOptions, HEAP_BASE, HEAP_PTR, VM_FP, VM_PC, VM_SP, VM_STACK};
+use crate::z80emu::{self, Console, Machine};
+
+/// Bytes above VM_STACK compared as main-program locals (256 slots)
+const LOCALS_SIZE: u16 = 512;
+
+/// Differences listed per memory region before eliding the rest
+const MAX_REPORTED: usize = 8;
+
+/// How a run ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Halted,
+    InputExhausted,
+    Limit,
+    Error(String),
+}
+
+/// Final state of one side of the comparison
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub stop: Stop,
+    /// Bytecode instructions (VM) or T-states (Z80) executed
+    pub executed: u64,
+    pub output: Vec<u8>,
+    /// STDERR, die messages included
+    pub errors: Vec<u8>,
+    pub pc: u16,
+    pub sp: u16,
+    pub fp: u16,
+    pub heap: u16,
+    pub mem: Vec<u8>,
+}
+
+/// Result of a crosscheck
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub vm: Outcome,
+    pub z80: Outcome,
+    pub divergences: Vec<String>,
+}
+
+impl Report {
+    pub fn agrees(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+/// Execution budgets for the two sides
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_steps: u64,
+    pub max_cycles: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: 10_000_000,
+            max_cycles: 500_000_000,
+        }
+    }
+}
+
+/// Run `module` on both implementations with the same input and compare
+pub fn crosscheck(module: &Module, options: &RomOptions, input: &[u8], limits: Limits) -> Report {
+    let vm = run_vm(module, input, limits.max_steps);
+    let z80 = run_z80(module, options, input, limits.max_cycles);
+    let divergences = compare(&vm, &z80);
+    Report { vm, z80, divergences }
+}
+
+fn run_vm(module: &Module, input: &[u8], max_steps: u64) -> Outcome {
+    let mut vm = Vm::new(module, Console::scripted(input));
+    let exit = vm.run(Some(max_steps));
+    let mut errors = vm.io.errors().to_vec();
+    let stop = match exit {
+        Ok(vm::Exit::Halted) => Stop::Halted,
+        Ok(vm::Exit::InputExhausted) => Stop::InputExhausted,
+        Ok(vm::Exit::StepLimit) => Stop::Limit,
+        // The VM returns a die message as its error, which the runtime
+        // prints to STDERR before it stops
+        Err(e) if module.code.get(vm.pc.wrapping_sub(1) as usize) == Some(&(Op::Die as u8)) => {
+            errors.extend(e.bytes().chain([b'\n']));
+            Stop::Error(z80::halt_error(module, vm.pc - 1).unwrap_or_default())
+        }
+        Err(e) => Stop::Error(e),
+    };
+    Outcome {
+        stop,
+        executed: vm.steps,
+        output: vm.io.output().to_vec(),
+        errors,
+        pc: vm.pc,
+        sp: vm.sp,
+        fp: vm.fp,
+        heap: vm.heap,
+        mem: vm.mem,
+    }
+}
+
+fn run_z80(module: &Module, options: &RomOptions, input: &[u8], max_cycles: u64) -> Outcome {
+    let rom = z80::generate_rom(module, options);
+    let console = Console::scripted(input).with_irq(options.irq_input);
+    let console = console.with_error_port(options.stderr_port().unwrap_or(z80::PORT_ERROR));
+    let mut machine = Machine::new(&rom, console);
+    let stop = match machine.run(Some(max_cycles)) {
+        z80emu::Exit::Halted => match z80::halt(module, &options.target.layout(), &machine) {
+            z80::Halt::Ended => Stop::Halted,
+            // It has printed why
+            z80::Halt::BootFailed => Stop::Error(String::from_utf8_lossy(machine.io.output()).trim_end().to_string()),
+            z80::Halt::Error { message, .. } => Stop::Error(message),
+        },
+        z80emu::Exit::InputExhausted => Stop::InputExhausted,
+        z80emu::Exit::CycleLimit => Stop::Limit,
+    };
+    Outcome {
+        stop,
+        executed: machine.cpu.cycles,
+        output: machine.io.output().to_vec(),
+        errors: machine.io.errors().to_vec(),
+        pc: machine.read16(VM_PC),
+        sp: machine.read16(VM_SP),
+        fp: machine.read16(VM_FP),
+        heap: machine.read16(HEAP_PTR),
+        mem: machine.mem,
+    }
+}
+
+fn compare(vm: &Outcome, z80: &Outcome) -> Vec<String> {
+    let mut out = Vec::new();
+
+    compare_stream(&mut out, "output", &vm.output, &z80.output);
+    compare_stream(&mut out, "errors", &vm.errors, &z80.errors);
+
+    // The two word their errors differently
+    if std::mem::discriminant(&vm.stop) != std::mem::discriminant(&z80.stop) {
+        out.push(format!("stopped differently: vm {:?}, z80 {:?}", vm.stop, z80.stop));
+        return out;
+    }
+
+    // A run cut short has no meaningful final state, nor does one that
+    // failed, which the two leave at different points of the instruction
+    if matches!(vm.stop, Stop::Limit | Stop::Error(_)) {
+        return out;
+    }
+
+    let registers = [
+        ("pc", vm.pc, z80.pc),
+        ("sp", vm.sp, z80.sp),
+        ("fp", vm.fp, z80.fp),
+        ("heap", vm.heap, z80.heap),
+    ];
+    for (name, a, b) in registers {
+        if a != b {
+            out.push(format!("{}: vm 0x{:04X}, z80 0x{:04X}", name, a, b));
+        }
+    }
+
+    // Live stack plus main-program locals, when the stacks line up
+    if vm.sp == z80.sp {
+        compare_memory(&mut out, "stack", vm, z80, vm.sp, VM_STACK.saturating_add(LOCALS_SIZE));
+    }
+    if vm.heap == z80.heap {
+        compare_memory(&mut out, "heap", vm, z80, HEAP_BASE, vm.heap);
+    }
+
+    out
+}
+
+fn compare_stream(out: &mut Vec<String>, stream: &str, vm: &[u8], z80: &[u8]) {
+    if vm != z80 {
+        let at = vm.iter().zip(z80).take_while(|(a, b)| a == b).count();
+        out.push(format!(
+            "{} differs at byte {}: vm {:?}, z80 {:?}",
+            stream,
+            at,
+            String::from_utf8_lossy(&vm[at..]),
+            String::from_utf8_lossy(&z80[at..])
+        ));
+    }
+}
+
+fn compare_memory(out: &mut Vec<String>, region: &str, vm: &Outcome, z80: &Outcome, start: u16, end: u16) {
+    let range = start as usize..(end as usize).max(start as usize);
+    let diffs: Vec<usize> = range.filter(|&i| vm.mem[i] != z80.mem[i]).collect();
+    for &i in diffs.iter().take(MAX_REPORTED) {
+        out.push(format!("{} byte 0x{:04X}: vm 0x{:02X}, z80 0x{:02X}", region, i, vm.mem[i], z80.mem[i]));
+    }
+    if diffs.len() > MAX_REPORTED {
+        out.push(format!("{}: {} more differing bytes", region, diffs.len() - MAX_REPORTED));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn check(code: &str) -> Report {
+        let mut lexer = Lexer::new(code);
+        let mut parser = Parser::new(lexer.tokenize());
+        let program = parser.parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        crosscheck(&module, &RomOptions::default(), b"", Limits::default())
+    }
+
+    #[test]
+    fn test_agreement_on_supported_program() {
+        let report = check(r#"
+            sub greet($who) { print "Hi ", $who, "\n"; }
+            my $i = 0;
+            while ($i < 3) {
+                if ($i % 2 == 0) { greet("even"); } else { greet("odd"); }
+                $i++;
+            }
+            if ("hello" =~ /h.l/) { print "match\n"; }
+        "#);
+        assert!(report.agrees(), "{:?}", report.divergences);
+        assert_eq!(report.vm.output, b"Hi even\nHi odd\nHi even\nmatch\n");
+    }
+
+    #[test]
+    fn test_agreement_on_repeat() {
+        let report = check(r#"
+            my $n = 3;
+            my $bar = "ab" x $n;
+            print $bar, "|", "-" x 0, "|", "=" x -2, "|", "wxyz" x 70, "|\n";
+        "#);
+        assert!(report.agrees(), "{:?}", report.divergences);
+        assert_eq!(report.vm.output, [b"ababab|||".to_vec(), b"wxyz".repeat(63), b"wxy|\n".to_vec()].concat());
+    }
+
+    #[test]
+    fn test_heap_past_the_vm_state() {
+        // 8000 bytes of strings take the heap from 0x2000 past 0x3000
+        let report = check(r#"
+            my $i = 0;
+            my $n = 100;
+            my $s;
+            while ($i < 40) { $s = "ab" x $n; $i++; }
+            print $i, " ", $s, "\n";
+        "#);
+        assert!(report.agrees(), "{:?}", report.divergences);
+        assert_eq!(report.vm.output, [b"40 ".to_vec(), b"ab".repeat(100), b"\n".to_vec()].concat());
+    }
+
+    #[test]
+    fn test_agreement_on_printf() {
+        let report = check(r#"
+            my $n = -42;
+            my $u;
+            printf "%d|%s|%c|%%|%s|%d|%s|", $n, "ab", 65, $u, 30000, 7;
+            printf "%d%s\n", 1;
+        "#);
+        assert!(report.agrees(), "{:?}", report.divergences);
+        assert_eq!(report.vm.output, b"-42|ab|A|%|0|30000|7|10\n");
+
+        // Other conversions need the host VM
+        let report = check(r#"printf "%x", 255;"#);
+        assert_eq!(report.z80.stop, Stop::Error("Runtime error: printf on the Z80 takes only %d, %s, %c and %%".to_string()));
+    }
+
+    #[test]
+    fn test_agreement_on_tail_call() {
+        let report = check(r#"
+            sub up($n) { if (5 < $n) { return $n; } return up($n + 1); }
+            sub walk($i, $acc) { if (4 < $i) { return $acc; } return walk($i + 1, $acc + $acc + $i); }
+            print up(0), " ", walk(1, 0), "\n";
+        "#);
+        assert!(report.agrees(), "{:?}", report.divergences);
+        assert_eq!(report.vm.output, b"6 26\n");
+    }
+
+    #[test]
+    fn test_failing_assert() {
+        let report = check("print \"a\";\nprint STDERR \"b\";\nassert 0, \"no\";\n");
+        assert!(report.agrees(), "{:?}", report.divergences);
+        assert_eq!(report.vm.output, b"a");
+        assert_eq!(report.vm.errors, b"bAssertion failed: no\n");
+        assert_eq!(report.z80.stop, Stop::Error("Program died".to_string()));
+    }
+
+    #[test]
+    fn test_flags_unimplemented_opcode() {
+        // The Z80 runtime has no Sub handler and halts
+        let report = check("my $a = 7; my $x = $a - 2; print $x;");
+        assert!(!report.agrees());
+        assert_eq!(report.vm.output, b"5");
+        assert_eq!(report.z80.stop, Stop::Error("Runtime error: Sub is not in the Z80 runtime".to_string()));
+        assert!(report.divergences[0].starts_with("output differs"));
+    }
+}
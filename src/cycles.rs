@@ -0,0 +1,867 @@
+//! T-state budget report
+//!
+//! Runs the ROM on the embedded emulator as a trace of `Step`s, one per Z80
+//! instruction, and charges every T-state to the bytecode instruction being
+//! interpreted (the runtime loads VM_PC at main_loop before each dispatch).
+//! The `Profiler` then sums the costs per source line, per subroutine and
+//! per opcode. A trace can be saved with `write_step` and profiled later
+//! with `read_trace`.
+//!
+//! `estimate` instead works from a table of each opcode's T-states, giving
+//! the longest path through each sub and loop without running anything.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::bytecode::{Module, NativeFunc, Op};
+use crate::z80::{self, RomOptions, VM_PC};
+use crate::z80emu::{Console, Exit, Machine};
+
+/// Clock of the target board, used to turn T-states into time
+pub const CLOCK_HZ: u64 = 4_000_000;
+
+/// Cycle budget used when none is given
+pub const DEFAULT_MAX_CYCLES: u64 = 500_000_000;
+
+/// Cost of one subroutine's own code (callees are not included)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubCost {
+    pub name: String,
+    pub calls: u64,
+    pub tstates: u64,
+}
+
+/// Cost of one opcode, dispatch included
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpCost {
+    pub op: Op,
+    pub count: u64,
+    pub tstates: u64,
+}
+
+/// Measured T-states for one run
+#[derive(Debug, Clone)]
+pub struct CycleReport {
+    pub exit: Exit,
+    pub total: u64,
+    /// T-states spent before the first bytecode instruction
+    pub startup: u64,
+    /// Source line -> T-states
+    pub by_line: BTreeMap<usize, u64>,
+    pub by_sub: Vec<SubCost>,
+    /// Most expensive first
+    pub by_op: Vec<OpCost>,
+}
+
+/// One Z80 instruction of a run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Address of the instruction
+    pub pc: u16,
+    pub tstates: u32,
+    /// The bytecode offset about to be dispatched, at main_loop only
+    pub vm_pc: Option<u16>,
+}
+
+/// Run `module` on the emulator with `input`, passing each instruction to
+/// `each`
+pub fn trace(module: &Module, options: &RomOptions, input: &[u8], max_cycles: u64, mut each: impl FnMut(Step)) -> Exit {
+    let rom = z80::generate_rom(module, options);
+    let main_loop = z80::runtime_symbol(&options.for_module(module), "main_loop").expect("runtime has a main_loop");
+    let console = Console::scripted(input).with_irq(options.irq_input);
+    let mut machine = Machine::new(&rom, console);
+    loop {
+        if let Some(exit) = machine.stopped(Some(max_cycles)) {
+            return exit;
+        }
+        let pc = machine.cpu.pc;
+        let vm_pc = (pc == main_loop).then(|| machine.read16(VM_PC));
+        let tstates = machine.step();
+        each(Step { pc, tstates, vm_pc });
+    }
+}
+
+/// Run `module` on the emulator with `input` and measure where the time goes
+pub fn measure(module: &Module, options: &RomOptions, input: &[u8], max_cycles: u64) -> CycleReport {
+    let mut profiler = Profiler::new(module);
+    let exit = trace(module, options, input, max_cycles, |step| profiler.record(step));
+    profiler.report(exit)
+}
+
+/// Write `step` as one line of a trace file: the Z80 address and T-states,
+/// then the bytecode offset at main_loop, addresses in hex
+pub fn write_step(out: &mut impl Write, step: Step) -> std::io::Result<()> {
+    match step.vm_pc {
+        Some(vm_pc) => writeln!(out, "{:04X} {} {:04X}", step.pc, step.tstates, vm_pc),
+        None => writeln!(out, "{:04X} {}", step.pc, step.tstates),
+    }
+}
+
+/// Write the line that ends a trace file
+pub fn write_exit(out: &mut impl Write, exit: Exit) -> std::io::Result<()> {
+    let exit = match exit {
+        Exit::Halted => "halted",
+        Exit::CycleLimit => "cycle-limit",
+        Exit::InputExhausted => "input-exhausted",
+    };
+    writeln!(out, "# exit {}", exit)
+}
+
+/// Profile a trace written by `write_step` and `write_exit`. A trace
+/// without an exit line is taken to have halted.
+pub fn read_trace(module: &Module, text: &str) -> Result<CycleReport, String> {
+    let mut profiler = Profiler::new(module);
+    let mut exit = Exit::Halted;
+    for (i, line) in text.lines().enumerate() {
+        let bad = || format!("trace line {}: Expected address, T-states and optional VM PC, got {:?}", i + 1, line);
+        if let Some(comment) = line.strip_prefix('#') {
+            exit = match comment.trim() {
+                "exit halted" => Exit::Halted,
+                "exit cycle-limit" => Exit::CycleLimit,
+                "exit input-exhausted" => Exit::InputExhausted,
+                _ => exit,
+            };
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let hex = |f: &str| u16::from_str_radix(f, 16).map_err(|_| bad());
+        let step = match fields[..] {
+            [] => continue,
+            [pc, t] => Step { pc: hex(pc)?, tstates: t.parse().map_err(|_| bad())?, vm_pc: None },
+            [pc, t, vm_pc] => Step { pc: hex(pc)?, tstates: t.parse().map_err(|_| bad())?, vm_pc: Some(hex(vm_pc)?) },
+            _ => return Err(bad()),
+        };
+        profiler.record(step);
+    }
+    Ok(profiler.report(exit))
+}
+
+/// Charges the T-states of a trace to bytecode instructions
+pub struct Profiler<'a> {
+    module: &'a Module,
+    total: u64,
+    startup: u64,
+    current: Option<u16>,
+    /// Bytecode offset -> (T-states, executions)
+    by_offset: BTreeMap<u16, (u64, u64)>,
+}
+
+impl<'a> Profiler<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        Profiler { module, total: 0, startup: 0, current: None, by_offset: BTreeMap::new() }
+    }
+
+    pub fn record(&mut self, step: Step) {
+        if let Some(pc) = step.vm_pc {
+            self.by_offset.entry(pc).or_default().1 += 1;
+            self.current = Some(pc);
+        }
+        let t = step.tstates as u64;
+        self.total += t;
+        match self.current {
+            Some(pc) => self.by_offset.entry(pc).or_default().0 += t,
+            None => self.startup += t,
+        }
+    }
+
+    pub fn report(self, exit: Exit) -> CycleReport {
+        let module = self.module;
+        let by_offset = self.by_offset;
+
+        let mut by_line = BTreeMap::new();
+        for (&offset, &(tstates, _)) in &by_offset {
+            if let Some(line) = module.line_at(offset) {
+                *by_line.entry(line).or_insert(0) += tstates;
+            }
+        }
+
+        let by_sub = sub_ranges(module)
+            .into_iter()
+            .map(|(name, start, end)| SubCost {
+                name,
+                calls: by_offset.get(&start).map_or(0, |&(_, n)| n),
+                tstates: by_offset.range(start..end).map(|(_, &(t, _))| t).sum(),
+            })
+            .collect();
+
+        // Opcode byte -> (T-states, executions)
+        let mut ops: BTreeMap<u8, (u64, u64)> = BTreeMap::new();
+        for (&offset, &(tstates, count)) in &by_offset {
+            if let Some(&byte) = module.code.get(offset as usize) {
+                let entry = ops.entry(byte).or_default();
+                entry.0 += tstates;
+                entry.1 += count;
+            }
+        }
+        let mut by_op: Vec<OpCost> = ops
+            .into_iter()
+            .map(|(byte, (tstates, count))| OpCost { op: Op::from_byte(byte), count, tstates })
+            .collect();
+        by_op.sort_by_key(|op| std::cmp::Reverse(op.tstates));
+
+        CycleReport {
+            exit,
+            total: self.total,
+            startup: self.startup,
+            by_line,
+            by_sub,
+            by_op,
+        }
+    }
+}
+
+/// Code range of each sub. Subs are compiled in place behind a Jump over
+/// their body, so that jump's target is where the body ends.
+fn sub_ranges(module: &Module) -> Vec<(String, u16, u16)> {
+    let mut ranges: Vec<_> = module
+        .subs
+        .iter()
+        .map(|(name, addr, _)| {
+            let start = *addr as usize;
+            let end = match start.checked_sub(3).map(|i| &module.code[i..start]) {
+                Some([op, lo, hi]) if *op == Op::Jump as u8 => u16::from_le_bytes([*lo, *hi]),
+                _ => *addr,
+            };
+            (name.clone(), *addr, end)
+        })
+        .collect();
+    ranges.sort_by_key(|(_, start, _)| *start);
+    ranges
+}
+
+/// T-states one bytecode instruction takes on the RetroShield runtime,
+/// dispatch included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// The usual path, with short strings
+    pub typical: u32,
+    /// The longest path, or None when it grows with the data (string
+    /// lengths, waiting for input)
+    pub worst: Option<u32>,
+}
+
+/// Opcode, typical and worst T-states on the default RetroShield runtime.
+/// Handlers are found down a chain of comparisons, so these move whenever
+/// one is added ahead of another: `test_timings_match_the_runtime` checks
+/// them against the emulator.
+const TIMINGS: &[(Op, u32, Option<u32>)] = &[
+    (Op::Count, 223, Some(223)),
+    (Op::CheckIdx, 281, Some(281)),
+    (Op::Push, 232, Some(232)),
+    (Op::Argv, 221, Some(221)),
+    (Op::PushByte, 278, Some(280)),
+    // The first string; each one before it adds about 57
+    (Op::PushStr, 329, Some(329)),
+    (Op::Repeat, 1069, None),
+    // Two bytes; in a program that selects STDERR each byte takes 77 more
+    (Op::Print, 450, None),
+    (Op::Select, 270, Some(270)),
+    (Op::LoadLocal, 414, Some(414)),
+    (Op::StoreLocal, 473, Some(473)),
+    (Op::Add, 499, Some(499)),
+    (Op::AddChk, 534, Some(534)),
+    (Op::SubChk, 555, Some(555)),
+    (Op::CmpLt, 588, Some(589)),
+    (Op::CmpLe, 601, Some(602)),
+    (Op::CmpEq, 618, Some(619)),
+    (Op::Div, 2277, Some(2312)),
+    (Op::Mod, 2278, Some(2313)),
+    (Op::Jump, 433, Some(433)),
+    (Op::JumpIfNot, 540, Some(579)),
+    (Op::JumpIf, 557, Some(596)),
+    (Op::Inc, 598, Some(598)),
+    (Op::Dup, 587, Some(587)),
+    (Op::Over, 628, Some(628)),
+    (Op::ArrLen, 679, Some(679)),
+    // Past the end; in range it takes 27 more to read the element
+    (Op::ArrGet, 874, Some(901)),
+    (Op::Pop, 619, Some(619)),
+    (Op::Call, 780, Some(780)),
+    (Op::EnterFrame, 739, Some(739)),
+    (Op::LeaveFrame, 668, Some(668)),
+    (Op::Return, 882, Some(882)),
+    (Op::ReturnVal, 935, Some(935)),
+    // One argument; each one more copies two bytes more, for 42
+    (Op::TailCall, 833, Some(833)),
+    // Without machine code; with it, the Z80 code's own time is unknown
+    (Op::Native, 733, Some(733)),
+    (Op::Not, 844, Some(844)),
+    (Op::And, 947, Some(963)),
+    (Op::Or, 964, Some(980)),
+    (Op::Match, 1644, None),
+    (Op::InputChar, 925, None),
+    (Op::Input, 1775, None),
+    (Op::Suspend, 1132, Some(1132)),
+    (Op::PortOut, 909, Some(909)),
+    (Op::PortIn, 891, Some(891)),
+    (Op::Peek, 965, Some(966)),
+    (Op::Poke, 1035, Some(1037)),
+    (Op::CheckPoke, 1073, Some(1073)),
+    // Up to 187 more for each free task slot passed over
+    (Op::Yield, 1515, Some(2076)),
+    (Op::TaskEnd, 1329, Some(1703)),
+    (Op::Spawn, 1272, Some(1491)),
+    (Op::Resumed, 1034, Some(1034)),
+    (Op::Ticks, 1047, Some(1047)),
+    // close() that the device fails at once: the others wait on the device
+    // for longer, and memstats() prints
+    (Op::CallNative, 1221, None),
+    (Op::Die, 1067, None),
+    // printf "%d\n" of a digit; each byte and conversion more adds to it
+    (Op::Printf, 1959, None),
+    (Op::Halt, 79, Some(79)),
+];
+
+/// T-states for each comparison in the dispatch chain a handler is behind
+const DISPATCH_STEP: u32 = 17;
+
+/// T-states memstats() takes over the CallNative row, printing its report
+const MEMSTATS: u32 = 1563;
+
+/// Timing of `op` on the RetroShield runtime built with `options`, or None
+/// when the runtime has no handler for it and stops there
+pub fn op_timing(op: Op, options: &RomOptions) -> Option<Timing> {
+    let built = match op {
+        Op::Count => options.coverage,
+        Op::CheckIdx => options.bounds_check,
+        Op::Ticks => options.timer,
+        Op::CallNative => options.mem_stats || !options.target.hosted(),
+        _ => true,
+    };
+    let &(_, typical, worst) = TIMINGS.iter().find(|(o, ..)| *o == op).filter(|_| built)?;
+    // Optional handlers ahead of this one in the chain; Halt comes first
+    let ahead = match op {
+        Op::Halt | Op::Count => 0,
+        Op::CheckIdx => options.coverage as u32,
+        _ => options.coverage as u32 + options.bounds_check as u32 + (options.timer && matches!(op, Op::CallNative | Op::Die | Op::Printf)) as u32,
+    };
+    // --mem-stats samples the stack before every dispatch
+    let (sample, sample_worst) = if options.mem_stats { (67, 89) } else { (0, 0) };
+    let extra = ahead * DISPATCH_STEP;
+    Some(Timing { typical: typical + extra + sample, worst: worst.map(|w| w + extra + sample_worst) })
+}
+
+/// Timing of the instruction at `offset`, whose operand can matter
+pub fn instruction_timing(module: &Module, offset: u16, options: &RomOptions) -> Option<Timing> {
+    let op = Op::from_byte(*module.code.get(offset as usize)?);
+    let mut timing = op_timing(op, options)?;
+    let at = offset as usize;
+    let operand = module.code.get(at + 1..at + 3).map_or(0, |w| u16::from_le_bytes([w[0], w[1]]));
+    match op {
+        Op::PushStr if operand > 0 => {
+            timing.typical += 57 * operand as u32 - 10;
+            timing.worst = Some(timing.typical);
+        }
+        Op::Native if operand != 0 => timing.worst = None,
+        Op::TailCall => {
+            timing.typical = (timing.typical + 42 * (operand & 0xFF) as u32).saturating_sub(42);
+            timing.worst = Some(timing.typical);
+        }
+        // memstats() with --mem-stats and the file natives on a board; the
+        // runtime has no other natives
+        Op::CallNative => match NativeFunc::from_byte(operand as u8) {
+            Some(NativeFunc::MemStats) if options.mem_stats => timing.typical += MEMSTATS,
+            Some(NativeFunc::Open | NativeFunc::Close | NativeFunc::Read | NativeFunc::Write | NativeFunc::Eof)
+                if !options.target.hosted() => {}
+            _ => return None,
+        },
+        _ => {}
+    }
+    Some(timing)
+}
+
+/// Offset and opcode of each instruction in `module` that the runtime
+/// built with `options` has no handler for, where a run would stop
+pub fn missing_handlers(module: &Module, options: &RomOptions) -> Vec<(u16, Op)> {
+    let mut missing = Vec::new();
+    let mut pc = 0;
+    while pc < module.code.len() {
+        let op = Op::from_byte(module.code[pc]);
+        // Banking pads the end of each page with Nops
+        let padding = op == Op::Nop && options.banking.is_some();
+        if !padding && instruction_timing(module, pc as u16, options).is_none() {
+            missing.push((pc as u16, op));
+        }
+        pc += op.size();
+    }
+    missing
+}
+
+/// Static T-state estimate for one call of a sub or one pass of a loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    /// `sub name`, or `loop`
+    pub name: String,
+    /// Source line of the sub or the loop's condition
+    pub line: Option<usize>,
+    /// Longest path at typical costs
+    pub typical: u64,
+    /// Longest path at worst costs, or None when that is unbounded
+    pub worst: Option<u64>,
+    /// Inner loops and recursive calls are counted once
+    pub nested: bool,
+    /// The first opcode on the way that the runtime has no handler for
+    pub unsupported: Option<Op>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cost {
+    typical: u64,
+    worst: Option<u64>,
+}
+
+const FREE: Cost = Cost { typical: 0, worst: Some(0) };
+
+impl Cost {
+    fn then(self, other: Cost) -> Cost {
+        Cost { typical: self.typical + other.typical, worst: self.worst.zip(other.worst).map(|(a, b)| a + b) }
+    }
+
+    fn max(self, other: Cost) -> Cost {
+        Cost { typical: self.typical.max(other.typical), worst: self.worst.zip(other.worst).map(|(a, b)| a.max(b)) }
+    }
+}
+
+/// Cost of a path through some code, with what was found on the way
+#[derive(Debug, Clone, Copy)]
+struct Path {
+    cost: Cost,
+    nested: bool,
+    unsupported: Option<Op>,
+}
+
+/// Estimate every sub and loop of `module` from the opcode timings, without
+/// running it. A loop's pass runs from its head to a jump back to it; inner
+/// loops are taken once, and a call adds the longest path through the sub.
+pub fn estimate(module: &Module, options: &RomOptions) -> Vec<Estimate> {
+    let mut analysis = Analysis { module, options, ranges: sub_ranges(module), subs: BTreeMap::new(), active: Vec::new() };
+
+    // Loop heads, and the end of the last jump back to each
+    let mut loops: BTreeMap<u16, u16> = BTreeMap::new();
+    let mut pc = 0;
+    while pc < module.code.len() {
+        let op = Op::from_byte(module.code[pc]);
+        if let Some(target) = analysis.jump_target(pc as u16) {
+            if target as usize <= pc {
+                loops.insert(target, (pc + op.size()) as u16);
+            }
+        }
+        pc += op.size();
+    }
+
+    let mut estimates: Vec<(u16, Estimate)> = Vec::new();
+    for (name, start, _) in analysis.ranges.clone() {
+        let path = analysis.sub(start);
+        estimates.push((start, Estimate::new(format!("sub {}", name), module.line_at(start), path)));
+    }
+    for (&head, &end) in &loops {
+        let path = analysis.walk(head, end, Some(head));
+        estimates.push((head, Estimate::new("loop".to_string(), module.line_at(head), path)));
+    }
+    estimates.sort_by_key(|(at, _)| *at);
+    estimates.into_iter().map(|(_, estimate)| estimate).collect()
+}
+
+impl Estimate {
+    fn new(name: String, line: Option<usize>, path: Path) -> Self {
+        Estimate { name, line, typical: path.cost.typical, worst: path.cost.worst, nested: path.nested, unsupported: path.unsupported }
+    }
+}
+
+struct Analysis<'a> {
+    module: &'a Module,
+    options: &'a RomOptions,
+    ranges: Vec<(String, u16, u16)>,
+    /// Sub address -> longest path through it
+    subs: BTreeMap<u16, Path>,
+    /// Subs being walked, to catch recursion
+    active: Vec<u16>,
+}
+
+impl Analysis<'_> {
+    fn jump_target(&self, pc: u16) -> Option<u16> {
+        let at = pc as usize;
+        match Op::from_byte(self.module.code[at]) {
+            Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef => {
+                self.module.code.get(at + 1..at + 3).map(|w| u16::from_le_bytes([w[0], w[1]]))
+            }
+            _ => None,
+        }
+    }
+
+    fn sub(&mut self, start: u16) -> Path {
+        if let Some(path) = self.subs.get(&start) {
+            return *path;
+        }
+        if self.active.contains(&start) {
+            return Path { cost: Cost { typical: 0, worst: None }, nested: true, unsupported: None };
+        }
+        let end = self.ranges.iter().find(|(_, at, _)| *at == start).map_or(self.module.code.len() as u16, |r| r.2);
+        self.active.push(start);
+        let path = self.walk(start, end, None);
+        self.active.pop();
+        self.subs.insert(start, path);
+        path
+    }
+
+    /// Longest path from `start` through the code up to `end`. Without a
+    /// loop `head` that is to a return or out of the range; with one, to a
+    /// jump back to the head.
+    fn walk(&mut self, start: u16, end: u16, head: Option<u16>) -> Path {
+        let mut reached: BTreeMap<u16, Cost> = BTreeMap::from([(start, FREE)]);
+        let mut longest: Option<Cost> = None;
+        let mut nested = false;
+        let mut unsupported = None;
+        let finish = |longest: &mut Option<Cost>, cost: Cost| {
+            *longest = Some(longest.map_or(cost, |l| l.max(cost)));
+        };
+        // Jumps only go forward once the backward ones are dealt with, so
+        // each instruction is reached by all its paths before it is taken
+        while let Some((pc, cost)) = reached.pop_first() {
+            if pc < start || pc >= end {
+                if head.is_none() {
+                    // A tail call runs the sub it jumps to
+                    let tail = match self.ranges.iter().any(|(_, at, _)| *at == pc) {
+                        true => self.sub(pc),
+                        false => Path { cost: FREE, nested: false, unsupported: None },
+                    };
+                    nested |= tail.nested;
+                    unsupported = unsupported.or(tail.unsupported);
+                    finish(&mut longest, cost.then(tail.cost));
+                }
+                continue;
+            }
+            let op = Op::from_byte(self.module.code[pc as usize]);
+            let Some(timing) = instruction_timing(self.module, pc, self.options) else {
+                unsupported = unsupported.or(Some(op));
+                continue;
+            };
+            let mut cost = cost.then(Cost { typical: timing.typical as u64, worst: timing.worst.map(u64::from) });
+            if op == Op::Call {
+                let at = pc as usize;
+                let target = u16::from_le_bytes([self.module.code[at + 1], self.module.code[at + 2]]);
+                let callee = self.sub(target);
+                nested |= callee.nested;
+                unsupported = unsupported.or(callee.unsupported);
+                cost = cost.then(callee.cost);
+            }
+            let next = pc + op.size() as u16;
+            let mut go = |to: u16| {
+                let entry = reached.entry(to).or_insert(cost);
+                *entry = entry.max(cost);
+            };
+            match op {
+                Op::Return | Op::ReturnVal | Op::Halt | Op::Die | Op::Suspend | Op::TaskEnd => {
+                    if head.is_none() {
+                        finish(&mut longest, cost);
+                    }
+                }
+                _ => match self.jump_target(pc) {
+                    Some(target) if target > pc || target < start => {
+                        go(target);
+                        if op != Op::Jump {
+                            go(next);
+                        }
+                    }
+                    Some(target) if Some(target) == head => {
+                        finish(&mut longest, cost);
+                        if op != Op::Jump {
+                            go(next);
+                        }
+                    }
+                    // An inner loop, taken once
+                    Some(_) => {
+                        nested = true;
+                        go(next);
+                    }
+                    None => go(next),
+                },
+            }
+        }
+        Path { cost: longest.unwrap_or(FREE), nested, unsupported }
+    }
+}
+
+/// Format `estimates`, quoting lines from `source` when given
+pub fn render_estimates(estimates: &[Estimate], source: Option<&str>) -> String {
+    let lines: Vec<&str> = source.map(|s| s.lines().collect()).unwrap_or_default();
+    let mut out = format!("Static estimate, T-states per call or pass ({} MHz retroshield runtime):\n", CLOCK_HZ / 1_000_000);
+    out.push_str("  where               line    typical      worst        ms\n");
+    for estimate in estimates {
+        let line = estimate.line.map_or(String::new(), |l| l.to_string());
+        let (worst, ms) = match estimate.worst {
+            Some(worst) => (worst.to_string(), format!("{:.2}", millis(worst))),
+            None => ("?".to_string(), "?".to_string()),
+        };
+        let mut notes = Vec::new();
+        if estimate.nested {
+            notes.push("*".to_string());
+        }
+        if let Some(op) = estimate.unsupported {
+            notes.push(format!("stops at {:?}", op));
+        }
+        if estimate.name == "loop" {
+            if let Some(text) = estimate.line.and_then(|l| lines.get(l.wrapping_sub(1))) {
+                notes.push(text.trim().to_string());
+            }
+        }
+        let row = format!(
+            "  {:<18}  {:>4}  {:9}  {:>9}  {:>8}  {}",
+            estimate.name,
+            line,
+            estimate.typical,
+            worst,
+            ms,
+            notes.join(" ")
+        );
+        out.push_str(row.trim_end());
+        out.push('\n');
+    }
+    if estimates.iter().any(|e| e.nested) {
+        out.push_str("* inner loops and recursive calls counted once\n");
+    }
+    if estimates.iter().any(|e| e.worst.is_none()) {
+        out.push_str("? grows with string lengths, division or waiting for input\n");
+    }
+    out
+}
+
+fn millis(tstates: u64) -> f64 {
+    tstates as f64 * 1000.0 / CLOCK_HZ as f64
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+}
+
+impl CycleReport {
+    /// Format the report, quoting lines from `source` when given
+    pub fn render(&self, source: Option<&str>) -> String {
+        let mut out = String::new();
+        out.push_str(&format!(
+            "Total: {} T-states ({:.2} ms at {} MHz), startup {}\n",
+            self.total,
+            millis(self.total),
+            CLOCK_HZ / 1_000_000,
+            self.startup
+        ));
+        match self.exit {
+            Exit::Halted => {}
+            Exit::CycleLimit => out.push_str("Stopped at the cycle limit; costs are partial\n"),
+            Exit::InputExhausted => out.push_str("Stopped waiting for input\n"),
+        }
+
+        let lines: Vec<&str> = source.map(|s| s.lines().collect()).unwrap_or_default();
+        out.push_str("\nBy line:\n");
+        out.push_str("   line     T-states      %        ms\n");
+        for (&line, &tstates) in &self.by_line {
+            let text = lines.get(line.wrapping_sub(1)).map_or("", |l| l.trim());
+            out.push_str(&format!(
+                "  {:5}  {:11}  {:5.1}  {:8.2}  {}\n",
+                line,
+                tstates,
+                percent(tstates, self.total),
+                millis(tstates),
+                text
+            ));
+        }
+
+        if !self.by_sub.is_empty() {
+            out.push_str("\nBy sub (own code only):\n");
+            out.push_str("  sub                   calls     T-states      %        ms\n");
+            for sub in &self.by_sub {
+                out.push_str(&format!(
+                    "  {:<18}  {:7}  {:11}  {:5.1}  {:8.2}\n",
+                    sub.name,
+                    sub.calls,
+                    sub.tstates,
+                    percent(sub.tstates, self.total),
+                    millis(sub.tstates)
+                ));
+            }
+        }
+
+        if !self.by_op.is_empty() {
+            out.push_str("\nBy opcode:\n");
+            out.push_str("  opcode                count     T-states      %   average\n");
+            for op in &self.by_op {
+                out.push_str(&format!(
+                    "  {:<18}  {:7}  {:11}  {:5.1}  {:8.1}\n",
+                    format!("{:?}", op.op),
+                    op.count,
+                    op.tstates,
+                    percent(op.tstates, self.total),
+                    op.tstates as f64 / op.count.max(1) as f64
+                ));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn measure_source(code: &str) -> CycleReport {
+        let mut lexer = Lexer::new(code);
+        let mut parser = Parser::new(lexer.tokenize());
+        let program = parser.parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        measure(&module, &RomOptions::default(), b"", DEFAULT_MAX_CYCLES)
+    }
+
+    #[test]
+    fn test_costs_add_up() {
+        let report = measure_source("my $i = 0;\nwhile ($i < 5) {\n    $i++;\n}\nprint \"done\";\n");
+        assert_eq!(report.exit, Exit::Halted);
+        let lines: u64 = report.by_line.values().sum();
+        assert_eq!(report.startup + lines, report.total);
+        assert_eq!(report.by_line.keys().copied().collect::<Vec<_>>(), vec![1, 2, 3, 5]);
+        // The loop body runs five times, so it outweighs the initialization
+        assert!(report.by_line[&3] > report.by_line[&1]);
+    }
+
+    #[test]
+    fn test_sub_costs() {
+        let report = measure_source("sub tick() {\n    print \".\";\n}\ntick();\ntick();\ntick();\n");
+        assert_eq!(report.by_sub.len(), 1);
+        assert_eq!(report.by_sub[0].name, "tick");
+        assert_eq!(report.by_sub[0].calls, 3);
+        assert!(report.by_sub[0].tstates > 0);
+    }
+
+    #[test]
+    fn test_op_costs() {
+        let report = measure_source("my $i = 0;\nwhile ($i < 5) {\n    $i++;\n}\n");
+        let ops: u64 = report.by_op.iter().map(|op| op.tstates).sum();
+        assert_eq!(report.startup + ops, report.total);
+        let inc = report.by_op.iter().find(|op| op.op == Op::Inc).unwrap();
+        assert_eq!(inc.count, 5);
+        assert!(report.by_op.windows(2).all(|w| w[0].tstates >= w[1].tstates));
+    }
+
+    #[test]
+    fn test_trace_round_trip() {
+        let program = Parser::new(Lexer::new("sub f() {\n    print \".\";\n}\nf();\nf();\n").tokenize()).parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        let mut text = Vec::new();
+        let exit = trace(&module, &RomOptions::default(), b"", DEFAULT_MAX_CYCLES, |step| {
+            write_step(&mut text, step).unwrap();
+        });
+        write_exit(&mut text, exit).unwrap();
+
+        let measured = measure(&module, &RomOptions::default(), b"", DEFAULT_MAX_CYCLES);
+        let profiled = read_trace(&module, &String::from_utf8(text).unwrap()).unwrap();
+        assert_eq!(profiled.render(None), measured.render(None));
+
+        let partial = read_trace(&module, "0000 4\n# exit cycle-limit\n").unwrap();
+        assert_eq!((partial.exit, partial.total, partial.startup), (Exit::CycleLimit, 4, 4));
+        assert_eq!(
+            read_trace(&module, "0000 4\nzz 4\n").unwrap_err(),
+            "trace line 2: Expected address, T-states and optional VM PC, got \"zz 4\""
+        );
+    }
+
+    #[test]
+    fn test_missing_handlers() {
+        let module = Compiler::new().compile(&Parser::new(Lexer::new("my $x = 3;\nprint $x - 1, memstats();").tokenize()).parse().unwrap()).unwrap();
+        let ops: Vec<Op> = missing_handlers(&module, &RomOptions::default()).into_iter().map(|(_, op)| op).collect();
+        assert_eq!(ops, [Op::Sub, Op::CallNative]);
+        let stats = RomOptions { mem_stats: true, ..Default::default() };
+        assert_eq!(missing_handlers(&module, &stats).len(), 1);
+    }
+
+    #[test]
+    fn test_timings_match_the_runtime() {
+        let sources = [
+            "my $a = 5;\nmy $b = 300;\nmy $u;\nprint $a + $b, $a + $u, $b / $a, $b % $a, $a < $b, $b < $a, $a <= $b, $b <= $a, $a == $b, $a == $a;\n\
+             print !$a, !0, $a && $b, 0 && $b, $a || 0, 0 || 0, defined($u), defined($a), \"abc\" =~ /b/;\n\
+             my $i = 0;\nwhile ($i < 3) { $i++; }\nunless ($i) { print 1; }",
+            "sub f($n) { print $n; }\nf(3);\nsub g($n) { my $t = $n; return $t; }\nprint g(2);\nmy $at = 28672;\npoke16($at, 300);\nprint peek($at), peek16($at);\nport_out(65, 1);\nprint port_in(65);",
+            "sub up($n) { if (2 < $n) { return $n; } return up($n + 1); }\nsub walk($i, $acc) { if (2 < $i) { return $acc; } return walk($i + 1, $acc + $i); }\nprint up(0), walk(0, 0);",
+            "foreach my $e (@ARGV) { print $e; }\nprint $ARGV[0];",
+            "sub w { yield(); yield(); }\nspawn(\\&w);\nspawn(\\&w);\nspawn(\\&w);\nspawn(\\&w);\nyield();\nyield();\nyield();\nyield();",
+        ];
+        let all = RomOptions { coverage: true, bounds_check: true, mem_stats: true, timer: true, ..Default::default() };
+        for options in [RomOptions::default(), all] {
+            let mut seen = 0;
+            for source in sources {
+                let module = Compiler::new().compile(&Parser::new(Lexer::new(source).tokenize()).parse().unwrap()).unwrap();
+                // (offset, T-states) of each instruction run
+                let mut runs: Vec<(u16, u32)> = Vec::new();
+                let exit = trace(&module, &options, b"", DEFAULT_MAX_CYCLES, |step| {
+                    if let Some(pc) = step.vm_pc {
+                        runs.push((pc, 0));
+                    }
+                    if let Some(run) = runs.last_mut() {
+                        run.1 += step.tstates;
+                    }
+                });
+                assert_eq!(exit, Exit::Halted);
+                for (pc, tstates) in runs {
+                    let timing = instruction_timing(&module, pc, &options).unwrap();
+                    if let Some(worst) = timing.worst {
+                        let op = Op::from_byte(module.code[pc as usize]);
+                        assert!((timing.typical..=worst).contains(&tstates), "{:?} took {} for {:?}", op, tstates, timing);
+                        seen += 1;
+                    }
+                }
+            }
+            assert!(seen > 100);
+        }
+    }
+
+    fn estimate_source(code: &str) -> Vec<Estimate> {
+        let module = Compiler::new().compile(&Parser::new(Lexer::new(code).tokenize()).parse().unwrap()).unwrap();
+        estimate(&module, &RomOptions::default())
+    }
+
+    #[test]
+    fn test_estimate_loop() {
+        let source = "my $i = 0;\nwhile ($i < 10) {\n    $i++;\n}\n";
+        let loops = estimate_source(source);
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].name.as_str(), loops[0].line, loops[0].nested, loops[0].unsupported), ("loop", Some(2), false, None));
+        // Ten passes and the check that ends them
+        let report = measure_source(source);
+        let measured = report.by_line[&2] + report.by_line[&3];
+        let worst = loops[0].worst.unwrap();
+        assert!(10 * loops[0].typical <= measured && measured <= 11 * worst, "{} {:?}", measured, loops[0]);
+    }
+
+    #[test]
+    fn test_estimate_subs() {
+        let estimates = estimate_source(
+            "sub pick($n) {\n    if ($n) { poke(28672, $n); } else { print \"x\"; }\n}\n\
+             sub fact($n) {\n    return $n < 2 ? 1 : $n * fact($n - 1);\n}\n\
+             sub twice($n) {\n    pick($n);\n    pick($n);\n}\n\
+             sub down($n) {\n    if ($n) { down(0); }\n}\n",
+        );
+        let names: Vec<&str> = estimates.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["sub pick", "sub fact", "sub twice", "sub down"]);
+        let [pick, fact, twice, down] = &estimates[..] else { unreachable!() };
+        // The poke is the longer branch, and the print has no bound
+        assert!(pick.typical > 2 * op_timing(Op::Print, &RomOptions::default()).unwrap().typical as u64);
+        assert_eq!(pick.worst, None);
+        assert!(twice.typical > 2 * pick.typical);
+        // The runtime has no Sub, and a recursive call has no bound
+        assert_eq!(fact.unsupported, Some(Op::Sub));
+        assert!(!pick.nested && down.nested);
+        assert_eq!((down.worst, down.unsupported), (None, None));
+    }
+
+    #[test]
+    fn test_render_estimates() {
+        let source = "sub blink() {\n    port_out(65, 1);\n}\nwhile (1) {\n    blink();\n}\n";
+        let text = render_estimates(&estimate_source(source), Some(source));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "  where               line    typical      worst        ms");
+        assert!(lines[2].starts_with("  sub blink              1  "), "{}", text);
+        assert!(lines[3].starts_with("  loop                   4  ") && lines[3].ends_with("  while (1) {"), "{}", text);
+        assert_eq!(lines.len(), 4);
+    }
+}
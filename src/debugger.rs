@@ -0,0 +1,283 @@
+//! Source-level debugger on the host VM
+//!
+//! Breakpoints and stepping work on source lines through the module's line
+//! table: a line is reached when the VM is about to execute the first
+//! instruction of one of its entries. Runtime errors are reported against
+//! the line of the instruction that failed. Watchpoints stop on any store
+//! to a global or main-program variable, whatever line it comes from.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::bytecode::Module;
+use crate::vm::{Exit, Vm};
+use crate::z80emu::Console;
+
+/// Why the debugger handed control back
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// Stepped onto the start of a line
+    Step(usize),
+    /// Reached a line with a breakpoint
+    Breakpoint(usize),
+    /// The program stopped by itself
+    Exited(Exit),
+    /// The program failed on `line`
+    Error { line: Option<usize>, message: String },
+    /// The instruction at `pc`, on `line`, stored `value` in the watched
+    /// variable `name`
+    Watch { name: String, value: String, pc: u16, line: Option<usize> },
+}
+
+/// A program loaded for debugging
+pub struct Debugger {
+    pub vm: Vm<Console>,
+    module: Module,
+    file: String,
+    source: Vec<String>,
+    breakpoints: BTreeSet<usize>,
+    /// Watched variables by name, with their addresses
+    watchpoints: BTreeMap<String, u16>,
+    /// Frame of the main program, where its `my` variables live
+    main_fp: u16,
+    /// Set once the program has exited or failed
+    finished: bool,
+}
+
+impl Debugger {
+    /// Load `module`, compiled from `source` in `file`, with `input` as the
+    /// program's console input
+    pub fn new(module: &Module, file: &str, source: &str, input: &[u8]) -> Self {
+        let vm = Vm::new(module, Console::scripted(input));
+        Debugger {
+            main_fp: vm.fp,
+            vm,
+            module: module.clone(),
+            file: file.to_string(),
+            source: source.lines().map(str::to_string).collect(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            finished: false,
+        }
+    }
+
+    /// Source line the VM is stopped on
+    pub fn line(&self) -> Option<usize> {
+        self.module.line_at(self.vm.pc)
+    }
+
+    /// `file:line: text` for a source line
+    pub fn location(&self, line: usize) -> String {
+        let text = self.source.get(line.wrapping_sub(1)).map_or("", |l| l.trim());
+        format!("{}:{}: {}", self.file, line, text)
+    }
+
+    /// Set a breakpoint at `spec`, either `line` or `file:line`, returning
+    /// the line
+    pub fn set_breakpoint(&mut self, spec: &str) -> Result<usize, String> {
+        let line = self.parse_location(spec)?;
+        if !self.module.lines.iter().any(|&(_, l)| l == line) {
+            return Err(format!("No code at {}:{}", self.file, line));
+        }
+        self.breakpoints.insert(line);
+        Ok(line)
+    }
+
+    /// Remove the breakpoint at `spec`
+    pub fn clear_breakpoint(&mut self, spec: &str) -> Result<usize, String> {
+        let line = self.parse_location(spec)?;
+        if !self.breakpoints.remove(&line) {
+            return Err(format!("No breakpoint at {}:{}", self.file, line));
+        }
+        Ok(line)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stop whenever `var`, a global or a main-program `my` variable with
+    /// or without its sigil, is stored to
+    pub fn watch(&mut self, var: &str) -> Result<(), String> {
+        let name = var.trim_start_matches(['$', '@', '%']);
+        let addr = match self.module.globals.iter().position(|g| g == name) {
+            Some(idx) => Vm::<Console>::global_addr(idx as u16),
+            None => match self.module.locals.iter().position(|l| l == name) {
+                Some(slot) => self.main_fp.wrapping_add(2 * slot as u16),
+                None => return Err(format!("No global or main-program variable {}", var)),
+            },
+        };
+        self.watchpoints.insert(var.to_string(), addr);
+        Ok(())
+    }
+
+    /// Stop watching `var`
+    pub fn unwatch(&mut self, var: &str) -> Result<(), String> {
+        match self.watchpoints.remove(var) {
+            Some(_) => Ok(()),
+            None => Err(format!("Not watching {}", var)),
+        }
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &str> + '_ {
+        self.watchpoints.keys().map(String::as_str)
+    }
+
+    /// Run to the start of the next line
+    pub fn step(&mut self) -> Stop {
+        self.run_until(|_, _| true)
+    }
+
+    /// Run to the next breakpoint
+    pub fn resume(&mut self) -> Stop {
+        self.run_until(|breakpoints, line| breakpoints.contains(&line))
+    }
+
+    /// Execute at least one instruction, then stop at the first line start
+    /// that `stop_at` accepts
+    fn run_until(&mut self, stop_at: impl Fn(&BTreeSet<usize>, usize) -> bool) -> Stop {
+        if self.finished {
+            return Stop::Exited(Exit::Halted);
+        }
+        loop {
+            let at = self.vm.pc;
+            let stored = self.vm.store_target();
+            match self.vm.step() {
+                Ok(None) => {}
+                Ok(Some(exit)) => {
+                    self.finished = true;
+                    return Stop::Exited(exit);
+                }
+                Err(message) => {
+                    self.finished = true;
+                    return Stop::Error { line: self.module.line_at(at), message };
+                }
+            }
+            if let Some(target) = stored {
+                if let Some((name, _)) = self.watchpoints.iter().find(|&(_, &addr)| addr == target) {
+                    let value = String::from_utf8_lossy(&self.vm.text(self.vm.read16(target))).into_owned();
+                    return Stop::Watch { name: name.clone(), value, pc: at, line: self.module.line_at(at) };
+                }
+            }
+            let pc = self.vm.pc;
+            if let Some(&(_, line)) = self.module.lines.iter().find(|&&(pos, _)| pos == pc) {
+                if stop_at(&self.breakpoints, line) {
+                    return if self.breakpoints.contains(&line) {
+                        Stop::Breakpoint(line)
+                    } else {
+                        Stop::Step(line)
+                    };
+                }
+            }
+        }
+    }
+
+    /// Line number of `line` or `file:line`; the file has to be the one
+    /// being debugged
+    fn parse_location(&self, spec: &str) -> Result<usize, String> {
+        let (file, line) = match spec.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, spec),
+        };
+        if let Some(file) = file {
+            let same = file == self.file || Path::new(&self.file).file_name() == Some(file.as_ref());
+            if !same {
+                return Err(format!("Unknown file: {}", file));
+            }
+        }
+        line.trim().parse().map_err(|_| format!("Invalid line number: {}", line))
+    }
+}
+
+/// `file:line: ` prefix for an error in `module` at bytecode offset `pc`,
+/// or nothing when the line is unknown
+pub fn error_location(module: &Module, file: &str, pc: u16) -> String {
+    module.line_at(pc).map_or_else(String::new, |line| format!("{}:{}: ", file, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    const SOURCE: &str = "my $i = 0;\nwhile ($i < 2) {\n    print $i;\n    $i++;\n}\nprint 10 / $i - 2;\n";
+
+    fn debugger(source: &str) -> Debugger {
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        Debugger::new(&module, "demo/loop.mpl", source, b"")
+    }
+
+    #[test]
+    fn test_step_through_lines() {
+        let mut dbg = debugger(SOURCE);
+        assert_eq!(dbg.line(), Some(1));
+        assert_eq!(dbg.step(), Stop::Step(2));
+        assert_eq!(dbg.step(), Stop::Step(3));
+        assert_eq!(dbg.location(3), "demo/loop.mpl:3: print $i;");
+        assert_eq!(dbg.step(), Stop::Step(4));
+        // The loop goes back to its condition
+        assert_eq!(dbg.step(), Stop::Step(2));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = debugger(SOURCE);
+        assert_eq!(dbg.set_breakpoint("loop.mpl:4"), Ok(4));
+        assert_eq!(dbg.set_breakpoint("demo/loop.mpl:3"), Ok(3));
+        assert!(dbg.set_breakpoint("5").unwrap_err().starts_with("No code at"));
+        assert!(dbg.set_breakpoint("other.mpl:3").unwrap_err().starts_with("Unknown file"));
+
+        assert_eq!(dbg.resume(), Stop::Breakpoint(3));
+        assert_eq!(dbg.resume(), Stop::Breakpoint(4));
+        assert_eq!(dbg.vm.io.output(), b"0");
+        assert_eq!(dbg.clear_breakpoint("3"), Ok(3));
+        assert_eq!(dbg.resume(), Stop::Breakpoint(4));
+        assert_eq!(dbg.clear_breakpoint("4"), Ok(4));
+        assert_eq!(dbg.resume(), Stop::Exited(Exit::Halted));
+        assert_eq!(dbg.vm.io.output(), b"013");
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut dbg = debugger("our $total = 0;\nmy $i = 1;\nsub add($n) { $total = $total + $n; }\nadd(5);\n$i = 2;\nadd($i);\n");
+        assert_eq!(dbg.watch("$total"), Ok(()));
+        assert_eq!(dbg.watch("i"), Ok(()));
+        assert_eq!(dbg.watch("$n").unwrap_err(), "No global or main-program variable $n");
+
+        let watch = |name: &str, value: &str, line| (name.to_string(), value.to_string(), Some(line));
+        let mut stops = Vec::new();
+        loop {
+            match dbg.resume() {
+                Stop::Watch { name, value, line, .. } => stops.push((name, value, line)),
+                stop => {
+                    assert_eq!(stop, Stop::Exited(Exit::Halted));
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            stops,
+            [watch("$total", "0", 1), watch("i", "1", 2), watch("$total", "5", 3), watch("i", "2", 5), watch("$total", "7", 3)]
+        );
+        assert_eq!(dbg.watchpoints().collect::<Vec<_>>(), ["$total", "i"]);
+        assert_eq!(dbg.unwatch("i"), Ok(()));
+        assert!(dbg.unwatch("i").is_err());
+    }
+
+    #[test]
+    fn test_error_line() {
+        let mut dbg = debugger("my $x = 0;\nprint 1;\nprint 1 / $x;\nprint 2;\n");
+        match dbg.resume() {
+            Stop::Error { line, message } => {
+                assert_eq!(line, Some(3));
+                assert_eq!(message, "Illegal division by zero at line 3");
+            }
+            stop => panic!("expected an error, got {:?}", stop),
+        }
+        let module = dbg.module.clone();
+        assert_eq!(error_location(&module, "x.mpl", dbg.vm.pc.wrapping_sub(1)), "x.mpl:3: ");
+    }
+}
@@ -0,0 +1,279 @@
+//! Minimal JSON values
+//!
+//! Enough JSON for machine-readable diagnostics and the language server:
+//! a value type, a parser and compact output through `Display`.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Members in the order written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Object from `(key, value)` pairs
+    pub fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+        Json::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Json::Null, Into::into)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Parse one JSON value
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut parser = JsonParser { chars: text.chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(format!("Trailing characters at {}", parser.pos));
+    }
+    Ok(value)
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonParser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.chars.get(self.pos).copied().ok_or("Unexpected end of JSON")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return Err(format!("Expected {} at {}", word, self.pos));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.pos) == Some(&']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => {}
+                        ']' => return Ok(Json::Array(items)),
+                        c => return Err(format!("Unexpected {:?} in array", c)),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.pos) == Some(&'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.next()? != ':' {
+                        return Err(format!("Expected : at {}", self.pos));
+                    }
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => {}
+                        '}' => return Ok(Json::Object(members)),
+                        c => return Err(format!("Unexpected {:?} in object", c)),
+                    }
+                }
+            }
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| "+-.eE".contains(*c) || c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                text.parse().map(Json::Number).map_err(|_| format!("Invalid number {}", text))
+            }
+            _ => Err(format!("Unexpected character at {}", self.pos)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.next()? != '"' {
+            return Err(format!("Expected string at {}", self.pos));
+        }
+        let mut out = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(out),
+                '\\' => match self.next()? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        // A surrogate pair spells one character
+                        if (0xD800..0xDC00).contains(&code) {
+                            self.expect("\\u")?;
+                            code = 0x10000 + ((code - 0xD800) << 10) + (self.hex4()? - 0xDC00);
+                        }
+                        out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next()?.to_digit(16).ok_or("Invalid \\u escape")?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = r#"{"id":1,"params":{"text":"a\"b\né","list":[true,false,null,-2.5]},"empty":{}}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("id").and_then(Json::as_usize), Some(1));
+        let params = value.get("params").unwrap();
+        assert_eq!(params.get("text").and_then(Json::as_str), Some("a\"b\né"));
+        assert_eq!(params.get("list").and_then(Json::as_array).map(|l| l.len()), Some(4));
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+        assert_eq!(value.to_string(), r#"{"id":1,"params":{"text":"a\"b\né","list":[true,false,null,-2.5]},"empty":{}}"#);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("[1,").is_err());
+        assert!(parse("1 2").is_err());
+    }
+}
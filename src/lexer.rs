@@ -8,6 +8,8 @@ pub struct Lexer {
     line: usize,
     column: usize,
     last_token: Option<Token>,
+    /// First character not recognised, with its line and column
+    unknown: Option<(char, usize, usize)>,
 }
 
 impl Lexer {
@@ -18,9 +20,16 @@ impl Lexer {
             line: 1,
             column: 1,
             last_token: None,
+            unknown: None,
         }
     }
 
+    /// The character that ended tokenizing early, if it was not the end of
+    /// the input: `(character, line, column)`
+    pub fn unknown(&self) -> Option<(char, usize, usize)> {
+        self.unknown
+    }
+
     fn current(&self) -> Option<char> {
         self.input.get(self.pos).copied()
     }
@@ -65,6 +74,21 @@ impl Lexer {
         let mut num_str = String::new();
         let mut is_float = false;
 
+        // 0x1F, not 0 x 1F
+        if self.current() == Some('0') && matches!(self.peek(), Some('x' | 'X')) {
+            self.advance();
+            self.advance();
+            while let Some(c) = self.current() {
+                if c.is_ascii_hexdigit() {
+                    num_str.push(c);
+                } else if c != '_' {
+                    break;
+                }
+                self.advance();
+            }
+            return Token::Integer(i32::from_str_radix(&num_str, 16).unwrap_or(0));
+        }
+
         while let Some(c) = self.current() {
             if c.is_ascii_digit() {
                 num_str.push(c);
@@ -99,7 +123,9 @@ impl Lexer {
     fn read_string(&mut self, quote: char) -> Token {
         self.advance(); // consume opening quote
         let mut s = String::new();
-        let interpolate = quote == '"';
+        // \U and \L change the case of what follows, up to \E; with no
+        // interpolation yet, all of it is known here
+        let mut case: Option<char> = None;
 
         while let Some(c) = self.current() {
             if c == quote {
@@ -107,7 +133,10 @@ impl Lexer {
                 break;
             } else if c == '\\' {
                 self.advance();
-                if let Some(escaped) = self.current() {
+                if let Some(escaped @ ('U' | 'L' | 'E')) = self.current().filter(|_| quote == '"') {
+                    case = Some(escaped).filter(|&c| c != 'E');
+                    self.advance();
+                } else if let Some(escaped) = self.current() {
                     let ch = match escaped {
                         'n' => '\n',
                         't' => '\t',
@@ -120,11 +149,11 @@ impl Lexer {
                         '0' => '\0',
                         _ => escaped,
                     };
-                    s.push(ch);
+                    s.push(fold(ch, case));
                     self.advance();
                 }
             } else {
-                s.push(c);
+                s.push(fold(c, case));
                 self.advance();
             }
         }
@@ -391,9 +420,10 @@ impl Lexer {
                 '\\' => { self.advance(); Token::Backslash }
                 '?' => { self.advance(); Token::Question }
 
-                _ => {
+                c => {
+                    self.unknown.get_or_insert((c, self.line, self.column));
                     self.advance();
-                    Token::Eof // Unknown character, skip
+                    Token::Eof // Unknown character, stop here
                 }
             }
         };
@@ -416,6 +446,15 @@ impl Lexer {
     }
 }
 
+/// `c` in the case a \U (upper) or \L (lower) escape asks for
+fn fold(c: char, case: Option<char>) -> char {
+    match case {
+        Some('U') => c.to_ascii_uppercase(),
+        Some('L') => c.to_ascii_lowercase(),
+        _ => c,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -428,12 +467,37 @@ mod tests {
         assert!(matches!(lexer.next_token().token, Token::HashVar(s) if s == "hash"));
     }
 
+    #[test]
+    fn test_unknown_character() {
+        let mut lexer = Lexer::new("my $x = 1;\nprint $x ` 2;");
+        let tokens = lexer.tokenize();
+        assert_eq!(tokens.last().unwrap().token, Token::Eof);
+        assert_eq!(lexer.unknown(), Some(('`', 2, 10)));
+        let mut clean = Lexer::new("print 1;");
+        clean.tokenize();
+        assert_eq!(clean.unknown(), None);
+    }
+
+    #[test]
+    fn test_hex_numbers() {
+        let tokens: Vec<Token> = Lexer::new("0x10 0XfF 0x70_00 0 x 3").tokenize().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[..4], [Token::Integer(16), Token::Integer(255), Token::Integer(0x7000), Token::Integer(0)]);
+        assert_eq!(tokens[4], Token::Ident("x".to_string()));
+    }
+
     #[test]
     fn test_string() {
         let mut lexer = Lexer::new("\"hello world\"");
         assert!(matches!(lexer.next_token().token, Token::String(s) if s == "hello world"));
     }
 
+    #[test]
+    fn test_case_escapes() {
+        let mut lexer = Lexer::new(r#""\Uhi\n there\E, \LYOU\Uand\E me" '\Uraw'"#);
+        assert!(matches!(lexer.next_token().token, Token::String(s) if s == "HI\n THERE, youAND me"));
+        assert!(matches!(lexer.next_token().token, Token::String(s) if s == "Uraw"));
+    }
+
     #[test]
     fn test_keywords() {
         let mut lexer = Lexer::new("my if while sub");
@@ -0,0 +1,578 @@
+//! MicroPerl - A minimal Perl compiler for Z80
+//!
+//! Each stage of the compiler is a public module: `lexer`, `parser` (to the
+//! `ast`), `compiler` (to a bytecode `Module`, which `optimizer` tidies)
+//! and `z80` (runtime and target images), plus the host-side `vm` and
+//! `z80emu` for running the results. `compile_source` runs the whole
+//! pipeline on a source string.
+//!
+//! The front end and bytecode are always built. Cargo features add the
+//! rest: `z80-backend` for runtimes and ROM images, `emulator`, `host-vm`
+//! and a `target-*` feature per hosted machine, all on by default, and
+//! `testing` for running snippets in tests.
+
+pub mod token;
+pub mod lexer;
+pub mod ast;
+pub mod astdump;
+pub mod astgen;
+pub mod parser;
+pub mod bytecode;
+pub mod compiler;
+pub mod optimizer;
+pub mod stack;
+pub mod types;
+pub mod unused;
+pub mod pack;
+pub mod config;
+pub mod coverage;
+pub mod json;
+pub mod linker;
+pub mod loader;
+pub mod printer;
+pub mod render;
+pub mod lsp;
+pub mod native;
+
+#[cfg(feature = "z80-backend")]
+pub mod asm;
+#[cfg(feature = "z80-backend")]
+pub mod backend;
+#[cfg(feature = "z80-backend")]
+pub mod z80;
+#[cfg(feature = "z80-backend")]
+pub mod z80dis;
+#[cfg(feature = "z80-backend")]
+pub mod banking;
+#[cfg(feature = "z80-backend")]
+pub mod budget;
+#[cfg(feature = "z80-backend")]
+pub mod carray;
+#[cfg(feature = "z80-backend")]
+pub mod patch;
+#[cfg(feature = "target-spectrum")]
+pub mod tap;
+#[cfg(feature = "target-cpc")]
+pub mod amsdos;
+#[cfg(feature = "target-trs80")]
+pub mod trs80;
+#[cfg(feature = "target-ti83")]
+pub mod ti8xp;
+
+#[cfg(feature = "emulator")]
+pub mod z80emu;
+#[cfg(any(feature = "z80-backend", feature = "emulator"))]
+pub mod storage;
+#[cfg(all(feature = "z80-backend", feature = "emulator"))]
+pub mod cycles;
+
+#[cfg(feature = "host-vm")]
+pub mod vm;
+#[cfg(feature = "host-vm")]
+pub mod crosscheck;
+#[cfg(feature = "host-vm")]
+pub mod debugger;
+#[cfg(feature = "host-vm")]
+pub mod repl;
+#[cfg(feature = "host-vm")]
+pub mod playground;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+use std::fmt;
+use std::path::PathBuf;
+
+use json::Json;
+use loader::Loader;
+
+pub use ast::Program;
+pub use bytecode::{Module, Op};
+pub use compiler::Compiler;
+pub use lexer::Lexer;
+pub use parser::Parser;
+#[cfg(feature = "z80-backend")]
+pub use z80::{RomOptions, Target};
+
+/// Options for `compile_source`
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Target and runtime options for the image
+    #[cfg(feature = "z80-backend")]
+    pub rom: RomOptions,
+    /// Program name stored in tape, disk and calculator files, and shown in
+    /// the self-test banner
+    pub name: String,
+    /// Directories searched for the libraries named by `use`
+    pub include: Vec<PathBuf>,
+    /// Size limits the image must fit
+    #[cfg(feature = "z80-backend")]
+    pub limits: budget::Limits,
+    /// Constants as `(name, value)`, overriding `use constant`
+    pub defines: Vec<(String, String)>,
+    /// The program's read-only %ENV as `(name, value)`, such as
+    /// `config::Config::env`
+    pub env: Vec<(String, String)>,
+    /// Count basic blocks for `coverage`, in the image's runtime too
+    pub coverage: bool,
+    /// Make + - * a runtime error on 16-bit overflow
+    pub checked: bool,
+    /// Check array indexes, in the image's runtime too
+    pub bounds_check: bool,
+    /// The addresses poke and poke16 may write, lo..=hi
+    pub poke_range: Option<(u16, u16)>,
+    /// Inline calls to small subs that just return an expression
+    pub inline: bool,
+    /// Start at this sub instead of the main code
+    pub entry: Option<String>,
+    /// Start at a numbered menu of these subs, for several programs in one
+    /// image
+    pub menu: Vec<String>,
+    /// Name `__FILE__` gives, rather than `-`
+    pub file: Option<String>,
+    /// Turn loop appends into string buffers, for a module the host VM runs:
+    /// the Z80 runtime has none
+    pub string_buffers: bool,
+    /// Warn where a string is used as a number or a number as a string
+    pub strict_types: bool,
+    /// Warn about `my` variables that nothing names
+    pub unused: bool,
+    /// Fail with the warnings as the diagnostics, if there are any
+    pub warnings_as_errors: bool,
+}
+
+/// Everything `compile_source` produces
+#[derive(Debug, Clone)]
+pub struct Artifacts {
+    pub program: Program,
+    pub module: Module,
+    /// Bytecode image (header, code, strings), as written by `-o`
+    pub bytecode: Vec<u8>,
+    /// Runtime and bytecode in the target's file format, as written by `--rom`
+    #[cfg(feature = "z80-backend")]
+    pub image: Vec<u8>,
+    /// Warnings from the optional checks, which did not stop the build
+    pub warnings: Diagnostics,
+}
+
+/// Class of failure, for wrappers to branch on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The program failed or hit a step or cycle limit while running
+    Runtime,
+    /// Bad command line or option combination
+    Usage,
+    /// Reading or writing a file
+    Io,
+    Lex,
+    Parse,
+    /// Finding or reading a library named by `use`
+    Library,
+    Compile,
+    /// A crosscheck divergence, or `fmt --check` finding unformatted files
+    Verify,
+    /// The image is over a size limit or does not fit the address space
+    Size,
+}
+
+impl ErrorKind {
+    /// Exit status of the command line tool
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Runtime => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::Io => 3,
+            ErrorKind::Lex => 4,
+            ErrorKind::Parse => 5,
+            ErrorKind::Library => 6,
+            ErrorKind::Compile => 7,
+            ErrorKind::Verify => 8,
+            ErrorKind::Size => 9,
+        }
+    }
+}
+
+/// Compiler stage that reported a diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Options,
+    Lex,
+    Parse,
+    /// Finding and parsing libraries for `use`
+    Load,
+    Compile,
+    /// The image is over a size limit
+    Size,
+    /// A warning from an optional check, such as `--strict-types`; it does
+    /// not stop the build
+    Lint,
+}
+
+/// An error found while compiling
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub stage: Stage,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Byte range of the offending source text
+    pub span: Option<(usize, usize)>,
+    /// Stable name for the kind of error, for tools to match on, given
+    /// where it was raised
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Error from an options check
+    pub fn options(message: String) -> Self {
+        Diagnostic { stage: Stage::Options, line: None, column: None, span: None, code: "invalid-options", message }
+    }
+
+    /// Error from the library loader
+    pub fn load(message: String) -> Self {
+        Diagnostic { stage: Stage::Load, line: None, column: None, span: None, code: "use-error", message }
+    }
+
+    /// Image over a size limit, with the breakdown
+    pub fn size(message: String) -> Self {
+        Diagnostic { stage: Stage::Size, line: None, column: None, span: None, code: "rom-size", message }
+    }
+
+    /// Unknown `character` at `line` and `column` of `source`, where the
+    /// lexer stopped
+    pub fn lex(source: &str, (character, line, column): (char, usize, usize)) -> Self {
+        let start = offset(source, line, column);
+        Diagnostic {
+            stage: Stage::Lex,
+            line: Some(line),
+            column: Some(column),
+            span: Some((start, start + character.len_utf8())),
+            code: "unexpected-character",
+            message: format!("Unexpected character {:?}", character),
+        }
+    }
+
+    /// Source that is not UTF-8, at the first byte that isn't
+    pub fn utf8(source: &[u8], valid_up_to: usize) -> Self {
+        let before = String::from_utf8_lossy(&source[..valid_up_to]);
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().map_or(0, |text| text.chars().count()) + 1;
+        Diagnostic {
+            stage: Stage::Lex,
+            line: Some(line),
+            column: Some(column),
+            span: Some((valid_up_to, valid_up_to + 1)),
+            code: "unexpected-character",
+            message: format!("Invalid UTF-8 byte 0x{:02X}", source[valid_up_to]),
+        }
+    }
+
+    /// Error from `parser`, which failed on `source`, covering the token it
+    /// stopped at
+    pub fn parse(source: &str, parser: &Parser, message: String) -> Self {
+        let (line, column) = parser.location();
+        let start = offset(source, line, column);
+        // The token ends where the next one starts, less any space between
+        let rest = &source[start..];
+        let next = Lexer::new(rest).tokenize().get(1).map_or(rest.len(), |t| offset(rest, t.line, t.column));
+        let end = start + rest[..next].trim_end().len();
+        Diagnostic {
+            stage: Stage::Parse,
+            line: Some(line),
+            column: Some(column),
+            span: Some((start, end)),
+            code: parser.error_code(),
+            message,
+        }
+    }
+
+    /// Error from `compiler`, covering the token it is about, or else the
+    /// statement it stopped at
+    pub fn compile(source: &str, compiler: &Compiler, message: String) -> Self {
+        let diagnostic = Self::statement(source, compiler.line(), compiler.error_code(), message);
+        let token = diagnostic.line.and_then(|line| token_span(source, line, compiler.error_tokens()));
+        match token {
+            Some((column, span)) => Diagnostic { column: Some(column), span: Some(span), ..diagnostic },
+            None => diagnostic,
+        }
+    }
+
+    /// Error from compiling the program to machine code with `--native`
+    pub fn native(source: &str, line: Option<usize>, message: String) -> Self {
+        Self::statement(source, line, "native-program", message)
+    }
+
+    /// Warning of kind `code` about the statement on `line`
+    pub fn lint(source: &str, line: usize, code: &'static str, message: String) -> Self {
+        Diagnostic { stage: Stage::Lint, ..Self::statement(source, Some(line), code, message) }
+    }
+
+    /// Warning that the image's runtime has no handler for `op`, used on
+    /// `line`
+    pub fn missing_handler(source: &str, line: Option<usize>, op: Op) -> Self {
+        let message = format!("{:?} is not in the Z80 runtime", op);
+        Diagnostic { stage: Stage::Lint, ..Self::statement(source, line, "not-in-runtime", message) }
+    }
+
+    /// Error that the program uses, on `line`, something only the host VM
+    /// has, so it can't become an image
+    pub fn host_only(source: &str, line: Option<usize>, message: String) -> Self {
+        Self::statement(source, line, "host-vm-only", message)
+    }
+
+    /// Whether this is a warning rather than an error
+    pub fn is_warning(&self) -> bool {
+        self.stage == Stage::Lint
+    }
+
+    /// Compile error covering the statement on `line`
+    fn statement(source: &str, line: Option<usize>, code: &'static str, message: String) -> Self {
+        let Some(line) = line else {
+            return Diagnostic { stage: Stage::Compile, line: None, column: None, span: None, code, message };
+        };
+        let text = source.lines().nth(line - 1).unwrap_or("");
+        let indent = text.len() - text.trim_start().len();
+        let start = offset(source, line, 1) + indent;
+        let column = text[..indent].chars().count() + 1;
+        Diagnostic {
+            stage: Stage::Compile,
+            line: Some(line),
+            column: Some(column),
+            span: Some((start, start + text.trim().len())),
+            code,
+            message,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self.stage {
+            Stage::Options => ErrorKind::Usage,
+            Stage::Lex => ErrorKind::Lex,
+            Stage::Parse => ErrorKind::Parse,
+            Stage::Load => ErrorKind::Library,
+            Stage::Compile => ErrorKind::Compile,
+            Stage::Size => ErrorKind::Size,
+            // Only reached if a wrapper treats warnings as errors
+            Stage::Lint => ErrorKind::Compile,
+        }
+    }
+
+    /// Hints on fixing the error, shown under the rendered source
+    pub fn notes(&self) -> Vec<&'static str> {
+        match self.code {
+            "undefined-variable" => vec!["declare it with `my` or `our` before using it"],
+            "undefined-sub" => vec!["define it with `sub`, or `use` the library that does"],
+            "unexpected-character" => vec!["MicroPerl stops reading the program here"],
+            "chained-comparison" => vec!["comparisons don't chain; in parentheses, `(0 < $x) < 10` compares the 1 or 0"],
+            "native-sub" => vec!["without :native the sub runs as bytecode"],
+            "native-program" => vec!["without --native the program runs as bytecode"],
+            "not-in-runtime" => vec!["the program stops here on the target; `run` has it on the host VM"],
+            "host-vm-only" => vec!["`run` runs the program on the host VM"],
+            "use-error" if self.message.starts_with("Can't locate") => {
+                vec!["add the library's directory with -I or MPLLIB"]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// JSON object with the file, position, span, code and message
+    pub fn to_json(&self, file: &str) -> String {
+        self.json(file).to_string()
+    }
+
+    /// `to_json` as a value, to embed in a larger document
+    pub fn json(&self, file: &str) -> Json {
+        let span = self.span.map_or(Json::Null, |(start, end)| {
+            Json::object([("start", start.into()), ("end", end.into())])
+        });
+        Json::object([
+            ("file", file.into()),
+            ("severity", if self.is_warning() { "warning" } else { "error" }.into()),
+            ("code", self.code.into()),
+            ("line", self.line.into()),
+            ("column", self.column.into()),
+            ("span", span),
+            ("message", self.message.as_str().into()),
+        ])
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.stage {
+            Stage::Options => write!(f, "Invalid options")?,
+            Stage::Lex => write!(f, "Lex error")?,
+            Stage::Parse => write!(f, "Parse error")?,
+            Stage::Load => write!(f, "Load error")?,
+            Stage::Compile => write!(f, "Compile error")?,
+            Stage::Size => write!(f, "Size error")?,
+            Stage::Lint => write!(f, "Warning")?,
+        }
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, " at line {}, column {}", line, column)?,
+            (Some(line), None) => write!(f, " at line {}", line)?,
+            _ => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+pub type Diagnostics = Vec<Diagnostic>;
+
+/// `diagnostics` as a JSON array
+pub fn diagnostics_json(file: &str, diagnostics: &[Diagnostic]) -> String {
+    let items: Vec<String> = diagnostics.iter().map(|d| d.to_json(file)).collect();
+    format!("[{}]", items.join(","))
+}
+
+/// Byte offset of 1-based `line` and `column` (in characters) in `source`
+/// Column and byte range of the first token on `line` of `source` written
+/// as one of `tokens`
+fn token_span(source: &str, line: usize, tokens: &[String]) -> Option<(usize, (usize, usize))> {
+    let ident = |c: char| c.is_alphanumeric() || c == '_';
+    Lexer::new(source).tokenize().iter().filter(|t| t.line == line).find_map(|t| {
+        let start = offset(source, line, t.column);
+        let rest = &source[start..];
+        let token = tokens.iter().find(|token| rest.starts_with(token.as_str()) && !rest[token.len()..].starts_with(ident))?;
+        Some((t.column, (start, start + token.len())))
+    })
+}
+
+fn offset(source: &str, line: usize, column: usize) -> usize {
+    let mut start = 0;
+    for _ in 1..line {
+        match source[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return source.len(),
+        }
+    }
+    let text = &source[start..];
+    start + text.char_indices().nth(column - 1).map_or(text.len(), |(i, _)| i)
+}
+
+impl Options {
+    /// A compiler set up with these options, after `libraries`, the ones
+    /// `Loader::libraries` found
+    pub fn compiler(&self, libraries: &[Module]) -> Result<Compiler, Diagnostic> {
+        let mut compiler = Compiler::new();
+        compiler.set_coverage(self.coverage);
+        compiler.set_checked(self.checked);
+        compiler.set_bounds_check(self.bounds_check);
+        compiler.set_poke_range(self.poke_range);
+        compiler.set_entry(self.entry.clone());
+        compiler.set_menu(self.menu.clone());
+        compiler.set_string_buffers(self.string_buffers);
+        #[cfg(feature = "z80-backend")]
+        compiler.set_stderr(self.rom.stderr_port().is_some());
+        if self.inline {
+            compiler.set_inline(compiler::INLINE_LIMIT);
+        }
+        if let Some(file) = &self.file {
+            compiler.set_file(file);
+        }
+        for (name, value) in &self.defines {
+            compiler.define(name, value).map_err(Diagnostic::options)?;
+        }
+        for (name, value) in &self.env {
+            compiler.set_env(name, value).map_err(Diagnostic::options)?;
+        }
+        for library in libraries {
+            compiler.link(library).map_err(Diagnostic::load)?;
+        }
+        Ok(compiler)
+    }
+}
+
+/// The `strict_types` warnings for `program`, read from `source`
+pub fn type_warnings(source: &str, program: &Program) -> Diagnostics {
+    types::check(program).into_iter().map(|(line, message)| Diagnostic::lint(source, line, "type-mix", message)).collect()
+}
+
+/// The `unused` warnings for `program`, read from `source`
+pub fn unused_warnings(source: &str, program: &Program) -> Diagnostics {
+    unused::check(program).into_iter().map(|(line, message)| Diagnostic::lint(source, line, "unused", message)).collect()
+}
+
+/// Compile MicroPerl source to bytecode and a target image
+pub fn compile_source(source: &str, options: Options) -> Result<Artifacts, Diagnostics> {
+    #[cfg(feature = "z80-backend")]
+    options.rom.check().map_err(|e| vec![Diagnostic::options(e)])?;
+
+    let mut lexer = Lexer::new(source);
+    let tokens = lexer.tokenize();
+    if let Some(unknown) = lexer.unknown() {
+        return Err(vec![Diagnostic::lex(source, unknown)]);
+    }
+    let mut parser = Parser::new(tokens);
+    let program = parser.parse().map_err(|e| vec![Diagnostic::parse(source, &parser, e)])?;
+    let mut loader = Loader::new(options.include.clone());
+    let program = loader.resolve(program).map_err(|e| vec![Diagnostic::load(e)])?;
+    let mut warnings = match options.strict_types {
+        true => type_warnings(source, &program),
+        false => Vec::new(),
+    };
+    if options.unused {
+        warnings.extend(unused_warnings(source, &program));
+    }
+
+    let mut compiler = options.compiler(loader.libraries()).map_err(|e| vec![e])?;
+    let module = compiler
+        .compile(&program)
+        .map_err(|e| vec![Diagnostic::compile(source, &compiler, e)])?;
+    #[cfg(feature = "z80-backend")]
+    let (module, image) = target_image(module, &options)?;
+
+    if options.warnings_as_errors && !warnings.is_empty() {
+        return Err(warnings);
+    }
+
+    let bytecode = module.image();
+    Ok(Artifacts {
+        program,
+        module,
+        bytecode,
+        #[cfg(feature = "z80-backend")]
+        image,
+        warnings,
+    })
+}
+
+/// `compile_source` on raw bytes, such as a fuzzer's input. No input makes
+/// it panic: whatever can't be compiled comes back as diagnostics.
+pub fn compile_bytes(source: &[u8], options: Options) -> Result<Artifacts, Diagnostics> {
+    let source = std::str::from_utf8(source).map_err(|e| vec![Diagnostic::utf8(source, e.valid_up_to())])?;
+    compile_source(source, options)
+}
+
+/// `module` laid out in ROM pages when `rom` is banked, so that no
+/// instruction straddles two
+#[cfg(feature = "z80-backend")]
+pub fn paginate(module: Module, rom: &RomOptions) -> Result<Module, Diagnostic> {
+    match rom.banking {
+        Some(_) => {
+            let fixed_space = banking::PAGE_SIZE - rom.target.layout().bytecode_org as usize;
+            banking::paginate(&module, fixed_space).map_err(Diagnostic::size)
+        }
+        None => Ok(module),
+    }
+}
+
+/// `module`, paginated when banked, and its image in the target's file
+/// format, once it is known to fit the size limits
+#[cfg(feature = "z80-backend")]
+fn target_image(module: Module, options: &Options) -> Result<(Module, Vec<u8>), Diagnostics> {
+    let rom = RomOptions {
+        coverage: options.rom.coverage || options.coverage,
+        bounds_check: options.rom.bounds_check || options.bounds_check,
+        program_name: if options.rom.program_name.is_empty() { options.name.clone() } else { options.rom.program_name.clone() },
+        ..options.rom.clone()
+    };
+    let module = paginate(module, &rom).map_err(|e| vec![e])?;
+
+    options
+        .limits
+        .check(&budget::measure(&module, &rom))
+        .map_err(|e| vec![Diagnostic::size(e)])?;
+
+    let image = z80::generate_output(&module, &rom, &options.name);
+    Ok((module, image))
+}
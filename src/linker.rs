@@ -0,0 +1,347 @@
+//! Precompiled library modules
+//!
+//! A library compiled with `--mpb` is saved as its bytecode module: the
+//! string pool, globals, sub table and code. Linking puts the code ahead of
+//! the program's and relocates it. Jump and call targets move by the code's
+//! new offset, and string and global indices are mapped into the program's
+//! pools. Everything to relocate is found by walking the instructions, so
+//! the file needs no relocation table.
+//!
+//! A library's top-level code runs before the program. It may only declare
+//! subs and `our` globals, since top-level `my` variables live in the
+//! program's frame and would collide with its own.
+//!
+//! `bundle` uses the same relocation to put several whole programs in one
+//! image, behind a boot menu. They share the runtime and string pool, but
+//! each keeps its own globals.
+
+use crate::ast::{Expr, Program, Stmt};
+use crate::bytecode::{Module, Op};
+use crate::loader;
+use crate::native::NativeSub;
+
+/// Extension of precompiled libraries
+pub const EXTENSION: &str = "mpb";
+
+/// Version 2 adds a flags byte to each sub: bit 0 set when it is variadic,
+/// bit 1 when its result can be undefined
+const MAGIC: &[u8; 4] = b"MPB\x02";
+
+/// Libraries written before subs had flags, which still load
+const MAGIC_V1: &[u8; 4] = b"MPB\x01";
+
+/// Check that `program` can be compiled as a library. It may end in the
+/// `1;` that Perl modules end with.
+pub fn check_library(program: &Program) -> Result<(), String> {
+    let mut next = 0;
+    let last = program.statements.len().saturating_sub(1);
+    for (i, stmt) in program.statements.iter().enumerate() {
+        match stmt {
+            Stmt::Sub { .. } | Stmt::Our(..) | Stmt::Use(_) | Stmt::Package(_) | Stmt::Constant(..) => next += loader::count(stmt),
+            Stmt::Expr(Expr::Integer(_)) if i == last => {}
+            _ => {
+                let line = program.lines.get(next).copied().unwrap_or(0);
+                return Err(format!("Line {}: a library may only declare subs and our variables", line));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `module` as the bytes of a .mpb file
+pub fn write(module: &Module) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    let name = |out: &mut Vec<u8>, s: &str| {
+        out.extend((s.len() as u16).to_le_bytes());
+        out.extend(s.as_bytes());
+    };
+    out.extend((module.strings.len() as u16).to_le_bytes());
+    for s in &module.strings {
+        name(&mut out, s);
+    }
+    out.extend((module.globals.len() as u16).to_le_bytes());
+    for g in &module.globals {
+        name(&mut out, g);
+    }
+    let mut subs = module.subs.clone();
+    subs.sort();
+    out.extend((subs.len() as u16).to_le_bytes());
+    for (sub, addr, params) in &subs {
+        name(&mut out, sub);
+        out.extend(addr.to_le_bytes());
+        out.push(*params);
+        out.push(module.variadic.contains(sub) as u8 | (module.undef_subs.contains(sub) as u8) << 1);
+    }
+    out.extend((module.code.len() as u16).to_le_bytes());
+    out.extend(&module.code);
+    out
+}
+
+/// Read a .mpb file written by `write`
+pub fn read(bytes: &[u8]) -> Result<Module, String> {
+    let flags = bytes.starts_with(MAGIC);
+    if !flags && !bytes.starts_with(MAGIC_V1) {
+        return Err("Not a MicroPerl library module".to_string());
+    }
+    let mut reader = Reader { bytes, pos: MAGIC.len() };
+    let mut module = Module::new();
+    for _ in 0..reader.word()? {
+        module.strings.push(reader.name()?);
+    }
+    for _ in 0..reader.word()? {
+        module.globals.push(reader.name()?);
+    }
+    for _ in 0..reader.word()? {
+        let name = reader.name()?;
+        let addr = reader.word()?;
+        let params = reader.take(1)?[0];
+        let bits = if flags { reader.take(1)?[0] } else { 0 };
+        if bits & 1 != 0 {
+            module.variadic.push(name.clone());
+        }
+        if bits & 2 != 0 {
+            module.undef_subs.push(name.clone());
+        }
+        module.subs.push((name, addr, params));
+    }
+    let len = reader.word()? as usize;
+    module.code = reader.take(len)?.to_vec();
+    if reader.pos != bytes.len() {
+        return Err("Trailing bytes after library code".to_string());
+    }
+    Ok(module)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or("Library module is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.word()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "Invalid name in library module".to_string())
+    }
+}
+
+/// Library `code` moved to offset `base`, with string and global indices
+/// mapped through `strings` and `globals`. The final Halt is dropped so
+/// execution falls through into the code that follows.
+pub fn relocate(code: &[u8], base: u16, strings: &[u16], globals: &[u16]) -> Result<Vec<u8>, String> {
+    let mut out = code.to_vec();
+    if out.last() == Some(&(Op::Halt as u8)) {
+        out.pop();
+    }
+    let mut pc = 0;
+    while pc < out.len() {
+        let op = Op::from_byte(out[pc]);
+        if op == Op::Invalid || pc + op.size() > out.len() {
+            return Err(format!("Invalid library code at offset {}", pc));
+        }
+        if op.size() == 3 {
+            let operand = u16::from_le_bytes([out[pc + 1], out[pc + 2]]);
+            let mapped = match op {
+                Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef | Op::Call | Op::Spawn => operand.checked_add(base),
+                Op::PushStr => strings.get(operand as usize).copied(),
+                Op::LoadGlobal | Op::StoreGlobal => globals.get(operand as usize).copied(),
+                _ => Some(operand),
+            }
+            .ok_or_else(|| format!("Invalid operand in library code at offset {}", pc))?;
+            out[pc + 1..pc + 3].copy_from_slice(&mapped.to_le_bytes());
+        }
+        pc += op.size();
+    }
+    Ok(out)
+}
+
+/// One module holding each of `programs`, (name, module), chosen at boot.
+/// With `select_port`, the byte read from that port picks program 1, 2 and
+/// so on, as jumpers would; any other value, or no port, shows a menu on
+/// the console. The chosen program runs until it halts.
+pub fn bundle(programs: &[(String, Module)], select_port: Option<u8>) -> Result<Module, String> {
+    if programs.is_empty() || programs.len() > 9 {
+        return Err(format!("A bundle holds 1 to 9 programs, not {}", programs.len()));
+    }
+    let mut linked = Module::new();
+    let mut starts = Vec::new();
+    let mut parts = Vec::new();
+    // The boot code's size doesn't depend on where the programs go, so
+    // lay it out once to find the first program's offset
+    let boot = |module: &mut Module, starts: &[u16]| {
+        let items: Vec<(String, u16)> = programs.iter().map(|(name, _)| name.clone()).zip(starts.iter().copied()).collect();
+        if let Some(port) = select_port {
+            module.emit_word(Op::Push, port as u16);
+            module.emit(Op::PortIn);
+            for (i, &(_, start)) in items.iter().enumerate() {
+                module.emit(Op::Dup);
+                module.emit_byte(Op::PushByte, i as u8 + 1);
+                module.emit(Op::CmpEq);
+                let skip = module.pos() as usize + 1;
+                module.emit_word(Op::JumpIfNot, 0);
+                module.emit(Op::Pop);
+                module.emit_word(Op::Jump, start);
+                let next = module.pos();
+                module.patch_addr(skip, next);
+            }
+            module.emit(Op::Pop);
+        }
+        module.emit_menu(&items, false);
+    };
+    boot(&mut linked, &vec![0; programs.len()]);
+    let mut base = linked.pos();
+    for (name, program) in programs {
+        let strings: Vec<u16> = program.strings.iter().map(|s| linked.add_string(s)).collect();
+        let globals: Vec<u16> = (linked.globals.len()..).take(program.globals.len()).map(|idx| idx as u16).collect();
+        linked.globals.extend(program.globals.iter().map(|global| format!("{}::{}", name, global)));
+        let mut code = relocate(&program.code, base, &strings, &globals).map_err(|e| format!("{}: {}", name, e))?;
+        code.push(Op::Halt as u8);
+        starts.push(base + program.entry);
+        linked.subs.extend(program.subs.iter().map(|(sub, addr, params)| (format!("{}::{}", name, sub), base + addr, *params)));
+        linked.variadic.extend(program.variadic.iter().map(|sub| format!("{}::{}", name, sub)));
+        linked.undef_subs.extend(program.undef_subs.iter().map(|sub| format!("{}::{}", name, sub)));
+        linked.lines.extend(program.lines.iter().map(|&(pos, line)| (base + pos, line)));
+        for native in &program.native {
+            linked.native.push(NativeSub { addr: base + native.addr, ..native.clone() });
+        }
+        let size = u16::try_from(code.len()).ok().filter(|&size| base.checked_add(size).is_some());
+        base = base.wrapping_add(size.ok_or("Bundle too large for 16-bit offsets")?);
+        parts.push(code);
+    }
+
+    linked.code.clear();
+    boot(&mut linked, &starts);
+    for code in parts {
+        linked.code.extend(code);
+    }
+    linked.check_limits()?;
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    #[cfg(feature = "host-vm")]
+    use crate::vm::{Exit, Vm};
+    #[cfg(feature = "host-vm")]
+    use crate::z80emu::Console;
+
+    fn parse(source: &str) -> Program {
+        Parser::new(Lexer::new(source).tokenize()).parse().unwrap()
+    }
+
+    fn library(source: &str) -> Module {
+        let program = parse(source);
+        check_library(&program).unwrap();
+        read(&write(&Compiler::new().compile(&program).unwrap())).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "host-vm")]
+    fn test_link_library() {
+        let lib = library("our $greeting = \"hi \";\nsub greet($n) { print $greeting, $n, \"\\n\"; return $n + 1; }\n");
+        let mut compiler = Compiler::new();
+        compiler.link(&lib).unwrap();
+        let program = parse("our $x = \"x\";\nprint \"start\\n\";\nprint greet(41), \"\\n\";\n");
+        let module = compiler.compile(&program).unwrap();
+
+        let mut vm = Vm::new(&module, Console::scripted(b""));
+        assert_eq!(vm.run(Some(100_000)), Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"start\nhi 41\n42\n");
+    }
+
+    #[test]
+    fn test_strings_merged_across_modules() {
+        let lib = library("sub greet() { print \"hi \"; print \"lib\"; }");
+        let mut compiler = Compiler::new();
+        compiler.link(&lib).unwrap();
+        let module = compiler.compile(&parse("print \"hi \";\ngreet();\nprint \"main\";")).unwrap();
+        assert_eq!(module.strings, ["hi ", "lib", "main"]);
+    }
+
+    #[test]
+    fn test_library_errors() {
+        check_library(&parse("sub f() { return 1; }\n1;\n")).unwrap();
+        assert!(check_library(&parse("1;\nsub f() { return 1; }\n")).is_err());
+        let err = check_library(&parse("sub f() { my $a = 1; }\nmy $x = 1;\n")).unwrap_err();
+        assert_eq!(err, "Line 2: a library may only declare subs and our variables");
+        assert!(read(b"MPL\x01").is_err());
+        let bytes = write(&library("sub f() { return 1; }"));
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut compiler = Compiler::new();
+        compiler.link(&library("sub f($a) { return $a; }")).unwrap();
+        let err = compiler.compile(&parse("f(1, 2);")).unwrap_err();
+        assert!(err.starts_with("Sub f takes 1 argument but"));
+    }
+
+    #[test]
+    #[cfg(feature = "host-vm")]
+    fn test_variadic_library() {
+        let lib = library("sub first($sep, @rest) { return $rest[0]; }\nsub one($a) { return $a; }");
+        assert_eq!(lib.variadic, ["first"]);
+        let mut compiler = Compiler::new();
+        compiler.link(&lib).unwrap();
+        let module = compiler.compile(&parse("print first(\",\", 7, 8), one(9);")).unwrap();
+        let mut vm = Vm::new(&module, Console::scripted(b""));
+        assert_eq!(vm.run(Some(100_000)), Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"79");
+
+        // Flags came in with version 2
+        let mut v1 = write(&library("sub one($a) { return $a; }"));
+        v1[3] = 1;
+        // Magic, no strings or globals, one sub: its name, address, params
+        v1.remove(4 + 2 + 2 + 2 + (2 + 3) + 2 + 1);
+        assert_eq!(read(&v1).unwrap().subs, [("one".to_string(), 3, 1)]);
+
+        // A caller pushes the slot for a result that can be undefined
+        let lib = read(&write(&library("sub none($a) { if ($a) { return; } return $a; }"))).unwrap();
+        assert_eq!(lib.undef_subs, ["none"]);
+        let mut compiler = Compiler::new();
+        compiler.link(&lib).unwrap();
+        let module = compiler.compile(&parse("my $x = none(1);
+print defined($x) ? \"d\" : \"u\", defined(none(0)) ? \"d\" : \"u\";")).unwrap();
+        let mut vm = Vm::new(&module, Console::scripted(b""));
+        assert_eq!(vm.run(Some(100_000)), Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"ud");
+    }
+
+    #[test]
+    #[cfg(feature = "host-vm")]
+    fn test_bundle() {
+        let compile = |source: &str| Compiler::new().compile(&parse(source)).unwrap();
+        let programs = [
+            ("hello".to_string(), compile("our $n = \"hi\";\nsub show { print $n; }\nshow();")),
+            ("count".to_string(), compile("our $n = 3;\nsub show { print $n, \"hi\"; }\nwhile ($n) { show(); $n--; }")),
+        ];
+        let module = bundle(&programs, None).unwrap();
+        assert_eq!(module.strings.iter().filter(|s| *s == "hi").count(), 1);
+        assert_eq!(module.globals, ["hello::n", "count::n"]);
+        assert!(module.subs.iter().any(|(name, ..)| name == "count::show"));
+
+        let run = |module: &Module, console: Console| {
+            let mut vm = Vm::new(module, console);
+            assert_eq!(vm.run(Some(10_000)), Ok(Exit::Halted));
+            String::from_utf8_lossy(vm.io.output()).into_owned()
+        };
+        assert_eq!(run(&module, Console::scripted(b"x2")), "1) hello\n2) count\n? 2\n3hi2hi1hi");
+        // A jumper port picks without the menu, unless it reads 0
+        let module = bundle(&programs, Some(0x40)).unwrap();
+        assert_eq!(run(&module, Console::scripted(b"").with_port(0x40, 1)), "hi");
+        assert!(run(&module, Console::scripted(b"1").with_port(0x40, 0)).ends_with("? 1\nhi"));
+        assert!(bundle(&[], None).is_err());
+    }
+}
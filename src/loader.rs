@@ -0,0 +1,303 @@
+//! `use` statements and library files
+//!
+//! `use Name;` at the top level of a program is replaced by the statements
+//! of `Name.mpl`, found by searching the include path in order. Each
+//! library is read once, however often it is used, and libraries can use
+//! other libraries. Names starting with a lower case letter are pragmas
+//! such as `use strict;`, which are accepted and ignored as before.
+//!
+//! The line table has no room for file names, so code from a library is
+//! recorded at the line of the `use` that pulled it in.
+//!
+//! A precompiled `Name.mpb` (see `linker`) is taken over `Name.mpl` in the
+//! same directory. It is not spliced in but collected in `libraries`, for
+//! the compiler to link ahead of the program.
+//!
+//! `do "file.mpl";` splices in a source file by name, relative names being
+//! searched for on the include path, every time it appears; `require
+//! "file.mpl";` does so only the first time. A file that includes itself,
+//! directly or through others, is an error.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Program, Stmt};
+use crate::bytecode::Module;
+use crate::lexer::Lexer;
+use crate::linker;
+use crate::parser::Parser;
+
+/// Extension of library files
+pub const EXTENSION: &str = "mpl";
+
+/// Resolves `use` against an include path
+pub struct Loader {
+    include: Vec<PathBuf>,
+    /// Libraries already pulled in
+    loaded: HashSet<String>,
+    /// Precompiled libraries, in the order they were used
+    libraries: Vec<Module>,
+    /// Files being included, outermost first
+    including: Vec<PathBuf>,
+}
+
+/// A library found on the include path
+enum Library {
+    Source(Program),
+    Precompiled(Module),
+}
+
+impl Loader {
+    pub fn new(include: Vec<PathBuf>) -> Self {
+        Loader { include, loaded: HashSet::new(), libraries: Vec::new(), including: Vec::new() }
+    }
+
+    /// Precompiled libraries to link, once `resolve` has run
+    pub fn libraries(&self) -> &[Module] {
+        &self.libraries
+    }
+
+    /// `program` with its `use` statements replaced by the libraries they
+    /// name
+    pub fn resolve(&mut self, program: Program) -> Result<Program, String> {
+        let mut out = Program::new();
+        let mut lines = program.lines.into_iter();
+        for stmt in program.statements {
+            let own: Vec<usize> = lines.by_ref().take(count(&stmt)).collect();
+            match &stmt {
+                Stmt::Use(name) if !is_pragma(name) => {
+                    if !self.loaded.insert(name.clone()) {
+                        continue;
+                    }
+                    match self.load(name)? {
+                        Library::Source(library) => {
+                            let library = self.resolve(library)?;
+                            out.lines.extend(std::iter::repeat_n(own[0], library.lines.len()));
+                            out.statements.extend(library.statements);
+                        }
+                        Library::Precompiled(module) => self.libraries.push(module),
+                    }
+                }
+                Stmt::Include { file, once } => {
+                    let path = self.find(file)?;
+                    if *once && !self.loaded.insert(path.display().to_string()) {
+                        continue;
+                    }
+                    if let Some(start) = self.including.iter().position(|p| *p == path) {
+                        let chain: Vec<String> =
+                            self.including[start..].iter().chain([&path]).map(|p| p.display().to_string()).collect();
+                        return Err(format!("{} includes itself: {}", file, chain.join(" -> ")));
+                    }
+                    let included = parse(&path)?;
+                    self.including.push(path);
+                    let included = self.resolve(included);
+                    self.including.pop();
+                    let included = included?;
+                    out.lines.extend(std::iter::repeat_n(own[0], included.lines.len()));
+                    out.statements.extend(included.statements);
+                }
+                _ => {
+                    if let Some(nested) = nested_use(std::slice::from_ref(&stmt), true) {
+                        return Err(format!("{} must be at the top level", nested));
+                    }
+                    out.lines.extend(own);
+                    out.statements.push(stmt);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Read the library file for `name`
+    fn load(&self, name: &str) -> Result<Library, String> {
+        let file = format!("{}.{}", name, EXTENSION);
+        let module = format!("{}.{}", name, linker::EXTENSION);
+        let path = self
+            .include
+            .iter()
+            .flat_map(|dir| [dir.join(&module), dir.join(&file)])
+            .find(|p| p.is_file())
+            .ok_or_else(|| {
+                let dirs: Vec<String> = self.include.iter().map(|d| d.display().to_string()).collect();
+                format!("Can't locate {} in the include path ({})", file, dirs.join(", "))
+            })?;
+        if path.extension().is_some_and(|ext| ext == linker::EXTENSION) {
+            let bytes = fs::read(&path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+            return linker::read(&bytes)
+                .map(Library::Precompiled)
+                .map_err(|e| format!("{}: {}", path.display(), e));
+        }
+        parse(&path).map(Library::Source)
+    }
+
+    /// The file `do` or `require` names: as it is when absolute, otherwise
+    /// the first match on the include path
+    fn find(&self, file: &str) -> Result<PathBuf, String> {
+        let path = PathBuf::from(file);
+        let found = match path.is_absolute() {
+            true => path.is_file().then_some(path),
+            false => self.include.iter().map(|dir| dir.join(file)).find(|p| p.is_file()),
+        };
+        let found = found.ok_or_else(|| {
+            let dirs: Vec<String> = self.include.iter().map(|d| d.display().to_string()).collect();
+            format!("Can't locate {} in the include path ({})", file, dirs.join(", "))
+        })?;
+        // The same file by any name is the same file, for cycles and require
+        Ok(fs::canonicalize(&found).unwrap_or(found))
+    }
+}
+
+/// Lex and parse the source file at `path`
+fn parse(path: &Path) -> Result<Program, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    let mut lexer = Lexer::new(&source);
+    let tokens = lexer.tokenize();
+    if let Some((c, line, _)) = lexer.unknown() {
+        return Err(format!("Unexpected character {:?} in {} at line {}", c, path.display(), line));
+    }
+    let mut parser = Parser::new(tokens);
+    parser.parse().map_err(|e| format!("Parse error in {} at line {}: {}", path.display(), parser.location().0, e))
+}
+
+/// Pragmas are lower case, libraries are capitalised
+fn is_pragma(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+}
+
+/// Number of statements in `stmt`, itself included, and of its elsifs, as
+/// the parser counts them for the line table
+pub(crate) fn count(stmt: &Stmt) -> usize {
+    let all = |stmts: &[Stmt]| stmts.iter().map(count).sum::<usize>();
+    1 + match stmt {
+        Stmt::If { then_block, elsif_blocks, else_block, .. } => {
+            all(then_block)
+                + elsif_blocks.iter().map(|(_, b)| 1 + all(b)).sum::<usize>()
+                + else_block.as_deref().map_or(0, all)
+        }
+        Stmt::Unless { then_block, else_block, .. } => all(then_block) + else_block.as_deref().map_or(0, all),
+        Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Foreach { body, .. } => all(body),
+        Stmt::For { init, body, .. } => init.as_deref().map_or(0, count) + all(body),
+        Stmt::Sub { body, .. } | Stmt::Block(body) | Stmt::Begin(body) => all(body),
+        _ => 0,
+    }
+}
+
+/// A library `use`, `do` or `require` inside `stmts`, as written; `top`
+/// skips the statements themselves
+fn nested_use(stmts: &[Stmt], top: bool) -> Option<String> {
+    stmts.iter().find_map(|stmt| match stmt {
+        Stmt::Use(name) if !top && !is_pragma(name) => Some(format!("use {}", name)),
+        Stmt::Include { file, once } if !top => Some(format!("{} \"{}\"", if *once { "require" } else { "do" }, file)),
+        Stmt::If { then_block, elsif_blocks, else_block, .. } => nested_use(then_block, false)
+            .or_else(|| elsif_blocks.iter().find_map(|(_, b)| nested_use(b, false)))
+            .or_else(|| else_block.as_deref().and_then(|b| nested_use(b, false))),
+        Stmt::Unless { then_block, else_block, .. } => nested_use(then_block, false)
+            .or_else(|| else_block.as_deref().and_then(|b| nested_use(b, false))),
+        Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Foreach { body, .. }
+        | Stmt::For { body, .. } | Stmt::Sub { body, .. } | Stmt::Block(body) | Stmt::Begin(body) => {
+            nested_use(body, false)
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Program {
+        Parser::new(Lexer::new(source).tokenize()).parse().unwrap()
+    }
+
+    /// A fresh directory holding `files`
+    fn library_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("microperl_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_use_splices_library() {
+        let dir = library_dir("use", &[
+            ("Greet.mpl", "use Util;\nsub greet($n) { print \"hi \", $n; }\n"),
+            ("Util.mpl", "our $count = 0;\n"),
+        ]);
+        let program = parse("use strict;\nuse Greet;\nuse Util;\nif (1) { greet(1); }\n");
+        let resolved = Loader::new(vec![PathBuf::from("/nonexistent"), dir]).resolve(program).unwrap();
+
+        // Util comes in once, through Greet
+        assert!(matches!(&resolved.statements[0], Stmt::Use(n) if n == "strict"));
+        assert!(matches!(&resolved.statements[1], Stmt::Our(v, _) if v == &["count"]));
+        assert!(matches!(&resolved.statements[2], Stmt::Sub { name, .. } if name == "greet"));
+        assert!(matches!(&resolved.statements[3], Stmt::If { .. }));
+        // Library code sits at the line of the use
+        assert_eq!(resolved.lines, vec![1, 2, 2, 2, 4, 4]);
+    }
+
+    #[test]
+    fn test_use_precompiled() {
+        let library = crate::compiler::Compiler::new().compile(&parse("sub one() { return 1; }")).unwrap();
+        let dir = library_dir("use_precompiled", &[("One.mpl", "sub one() { return 2; }")]);
+        fs::write(dir.join("One.mpb"), linker::write(&library)).unwrap();
+        let mut loader = Loader::new(vec![dir]);
+        let resolved = loader.resolve(parse("use One;\nprint one();\n")).unwrap();
+
+        // Nothing spliced in; the module is left for the compiler to link
+        assert_eq!(resolved.statements.len(), 1);
+        assert_eq!(resolved.lines, vec![2]);
+        assert_eq!(loader.libraries().len(), 1);
+        assert_eq!(loader.libraries()[0].subs, vec![("one".to_string(), 3, 0)]);
+    }
+
+    #[test]
+    fn test_do_and_require() {
+        let dir = library_dir("include", &[
+            ("lib/greet.mpl", "require \"lib/util.mpl\";\nsub greet($n) { print \"hi \", $n; }\n"),
+            ("lib/util.mpl", "our $count = 0;\n"),
+            ("twice.mpl", "print 1;\n"),
+        ]);
+        let program = parse("require \"lib/greet.mpl\";\nrequire \"lib/util.mpl\";\ndo \"twice.mpl\";\ndo \"twice.mpl\";\n");
+        let resolved = Loader::new(vec![dir.clone()]).resolve(program).unwrap();
+
+        // util comes in once, through greet; do repeats
+        assert!(matches!(&resolved.statements[0], Stmt::Our(v, _) if v == &["count"]));
+        assert!(matches!(&resolved.statements[1], Stmt::Sub { name, .. } if name == "greet"));
+        assert!(matches!(&resolved.statements[2], Stmt::Print(..)));
+        assert!(matches!(&resolved.statements[3], Stmt::Print(..)));
+        assert_eq!(resolved.statements.len(), 4);
+        assert_eq!(resolved.lines, vec![1, 1, 1, 3, 4]);
+
+        let absolute = format!("do \"{}\";", dir.join("twice.mpl").display());
+        assert_eq!(Loader::new(Vec::new()).resolve(parse(&absolute)).unwrap().statements.len(), 1);
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = library_dir("include_errors", &[("a.mpl", "do \"b.mpl\";\n"), ("b.mpl", "do \"a.mpl\";\n")]);
+        let mut loader = Loader::new(vec![dir.clone()]);
+        let cycle = loader.resolve(parse("do \"a.mpl\";")).unwrap_err();
+        let (a, b) = (fs::canonicalize(dir.join("a.mpl")).unwrap(), fs::canonicalize(dir.join("b.mpl")).unwrap());
+        assert_eq!(cycle, format!("a.mpl includes itself: {} -> {} -> {}", a.display(), b.display(), a.display()));
+        let missing = loader.resolve(parse("require \"c.mpl\";")).unwrap_err();
+        assert!(missing.starts_with("Can't locate c.mpl in the include path"));
+        let nested = loader.resolve(parse("sub f() { do \"a.mpl\"; }")).unwrap_err();
+        assert_eq!(nested, "do \"a.mpl\" must be at the top level");
+    }
+
+    #[test]
+    fn test_use_errors() {
+        let dir = library_dir("use_errors", &[("Broken.mpl", "sub f( {")]);
+        let mut loader = Loader::new(vec![dir]);
+        let missing = loader.resolve(parse("use Missing;")).unwrap_err();
+        assert!(missing.starts_with("Can't locate Missing.mpl in the include path"));
+        let broken = loader.resolve(parse("use Broken;")).unwrap_err();
+        assert!(broken.starts_with("Parse error in ") && broken.contains("Broken.mpl at line 1"));
+        let nested = loader.resolve(parse("if (1) { use Other; }")).unwrap_err();
+        assert_eq!(nested, "use Other must be at the top level");
+    }
+}
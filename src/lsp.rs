@@ -0,0 +1,380 @@
+//! Language server for MicroPerl sources
+//!
+//! Speaks the Language Server Protocol over stdin and stdout. Open documents
+//! are compiled with `compile_source` on every change and the errors are
+//! published as diagnostics. Go-to-definition and document symbols come from
+//! the declarations in the token stream: `sub` names and parameters, and the
+//! variables of `my` and `our`.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use crate::json::{self, Json};
+use crate::lexer::Lexer;
+use crate::token::Token;
+use crate::{compile_source, Options};
+
+/// LSP SymbolKind values
+const SYMBOL_FUNCTION: usize = 12;
+const SYMBOL_VARIABLE: usize = 13;
+
+/// Serve requests from `input` until the client sends `exit`
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> Result<(), String> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read one `Content-Length` framed message, or None at the end of input
+fn read_message(input: &mut impl BufRead) -> Result<Option<Json>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or("Message without Content-Length")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let text = String::from_utf8(body).map_err(|e| e.to_string())?;
+    json::parse(&text).map(Some)
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body).map_err(|e| e.to_string())?;
+    output.flush().map_err(|e| e.to_string())
+}
+
+#[derive(Default)]
+struct Server {
+    /// Open documents by URI
+    documents: HashMap<String, String>,
+    exited: bool,
+}
+
+impl Server {
+    /// Messages to send in answer to `message`
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str);
+
+        let result = match method {
+            "initialize" => Json::object([
+                ("capabilities", Json::object([
+                    ("textDocumentSync", 1.into()),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true)),
+                ])),
+                ("serverInfo", Json::object([("name", "microperl".into())])),
+            ]),
+            "shutdown" => Json::Null,
+            "exit" => {
+                self.exited = true;
+                return vec![];
+            }
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = match method {
+                    "textDocument/didOpen" => params.get("textDocument").and_then(|d| d.get("text")),
+                    // Full sync: the last change holds the whole text
+                    _ => params.get("contentChanges").and_then(Json::as_array)
+                        .and_then(|c| c.last()).and_then(|c| c.get("text")),
+                };
+                let (Some(uri), Some(text)) = (uri, text.and_then(Json::as_str)) else {
+                    return vec![];
+                };
+                self.documents.insert(uri.to_string(), text.to_string());
+                return vec![publish(uri, diagnostics(text))];
+            }
+            "textDocument/didClose" => {
+                let Some(uri) = uri else {
+                    return vec![];
+                };
+                self.documents.remove(uri);
+                return vec![publish(uri, Json::Array(vec![]))];
+            }
+            "textDocument/definition" => {
+                let position = params.get("position");
+                let line = position.and_then(|p| p.get("line")).and_then(Json::as_usize);
+                let character = position.and_then(|p| p.get("character")).and_then(Json::as_usize);
+                match (uri, line, character) {
+                    (Some(uri), Some(line), Some(character)) => self.documents.get(uri)
+                        .and_then(|text| definition(text, line, character))
+                        .map_or(Json::Null, |decl| location(uri, &decl)),
+                    _ => Json::Null,
+                }
+            }
+            "textDocument/documentSymbol" => match uri.and_then(|uri| self.documents.get(uri).map(|t| (uri, t))) {
+                Some((uri, text)) => symbols(uri, text),
+                None => Json::Array(vec![]),
+            },
+            _ => {
+                // Requests need an answer; notifications can be ignored
+                return match message.get("id") {
+                    Some(id) => vec![Json::object([
+                        ("jsonrpc", "2.0".into()),
+                        ("id", id.clone()),
+                        ("error", Json::object([
+                            ("code", Json::Number(-32601.0)),
+                            ("message", format!("Method not found: {}", method).as_str().into()),
+                        ])),
+                    ])],
+                    None => vec![],
+                };
+            }
+        };
+        match message.get("id") {
+            Some(id) => vec![Json::object([("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)])],
+            None => vec![],
+        }
+    }
+}
+
+fn publish(uri: &str, diagnostics: Json) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        ("params", Json::object([("uri", uri.into()), ("diagnostics", diagnostics)])),
+    ])
+}
+
+/// LSP diagnostics for the errors in `text`
+fn diagnostics(text: &str) -> Json {
+    let errors = match compile_source(text, Options::default()) {
+        Ok(_) => vec![],
+        Err(errors) => errors,
+    };
+    let items = errors.iter().map(|error| {
+        let (start, end) = match (error.span, error.line) {
+            (Some((start, end)), _) => (position(text, start), position(text, end)),
+            (None, Some(line)) => ((line - 1, 0), (line - 1, 0)),
+            (None, None) => ((0, 0), (0, 0)),
+        };
+        Json::object([
+            ("range", range(start, end)),
+            ("severity", 1.into()),
+            ("code", error.code.into()),
+            ("source", "microperl".into()),
+            ("message", error.message.as_str().into()),
+        ])
+    });
+    Json::Array(items.collect())
+}
+
+/// 0-based line and character of byte `offset` in `text`
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count(), before[line_start..].chars().count())
+}
+
+fn range(start: (usize, usize), end: (usize, usize)) -> Json {
+    let point = |(line, character): (usize, usize)| {
+        Json::object([("line", line.into()), ("character", character.into())])
+    };
+    Json::object([("start", point(start)), ("end", point(end))])
+}
+
+fn location(uri: &str, decl: &Name) -> Json {
+    Json::object([("uri", uri.into()), ("range", decl.range())])
+}
+
+/// A sub or variable name in the source
+#[derive(Debug, Clone, PartialEq)]
+struct Name {
+    /// Variables keep their sigil
+    name: String,
+    /// 0-based
+    line: usize,
+    character: usize,
+    len: usize,
+}
+
+impl Name {
+    fn range(&self) -> Json {
+        range((self.line, self.character), (self.line, self.character + self.len))
+    }
+
+    fn contains(&self, line: usize, character: usize) -> bool {
+        self.line == line && (self.character..self.character + self.len).contains(&character)
+    }
+}
+
+/// Names in `text`, each with whether it declares something and its brace
+/// depth
+fn names(text: &str) -> Vec<(Name, bool, usize)> {
+    let tokens = Lexer::new(text).tokenize();
+    let mut out = Vec::new();
+    let mut depth = 0;
+    // Set while inside `my (...)`, `our (...)` or a sub's parameter list
+    let mut declaring_list = false;
+    // Parameters belong to the sub's body
+    let mut in_params = false;
+
+    for (i, tok) in tokens.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|p| &tokens[p].token);
+        let next = tokens.get(i + 1).map(|t| &t.token);
+        let (name, len) = match &tok.token {
+            Token::LBrace => {
+                depth += 1;
+                continue;
+            }
+            Token::RBrace => {
+                depth -= 1;
+                continue;
+            }
+            Token::LParen => {
+                let sub_params = i >= 2 && tokens[i - 2].token == Token::Sub;
+                declaring_list = matches!(prev, Some(Token::My | Token::Our)) || sub_params;
+                in_params = sub_params;
+                continue;
+            }
+            Token::RParen => {
+                declaring_list = false;
+                in_params = false;
+                continue;
+            }
+            // Elements name their array or hash
+            Token::ScalarVar(n) if next == Some(&Token::LBracket) => (format!("@{}", n), n.len() + 1),
+            Token::ScalarVar(n) if next == Some(&Token::LBrace) => (format!("%{}", n), n.len() + 1),
+            Token::ScalarVar(n) => (format!("${}", n), n.len() + 1),
+            Token::ArrayVar(n) => (format!("@{}", n), n.len() + 1),
+            Token::HashVar(n) => (format!("%{}", n), n.len() + 1),
+            Token::Ident(n) => (n.clone(), n.len()),
+            _ => continue,
+        };
+        let declares = match &tok.token {
+            Token::Ident(_) => prev == Some(&Token::Sub),
+            _ => declaring_list || matches!(prev, Some(Token::My | Token::Our)),
+        };
+        let name = Name { name, line: tok.line - 1, character: tok.column - 1, len };
+        out.push((name, declares, depth + in_params as usize));
+    }
+    out
+}
+
+/// Declaration of the name at `line` and `character`: the closest one
+/// before it, or the first one after it for subs defined further down
+fn definition(text: &str, line: usize, character: usize) -> Option<Name> {
+    let names = names(text);
+    let (target, _, _) = names.iter().find(|(n, _, _)| n.contains(line, character))?;
+    let decls = || names.iter().filter(|(n, declares, _)| *declares && n.name == target.name);
+    let before = |n: &Name| (n.line, n.character) <= (target.line, target.character);
+    decls()
+        .rfind(|(n, _, _)| before(n))
+        .or_else(|| decls().next())
+        .map(|(n, _, _)| n.clone())
+}
+
+/// Subs and top-level variables of `text`
+fn symbols(uri: &str, text: &str) -> Json {
+    let items = names(text).into_iter().filter(|(n, declares, depth)| {
+        *declares && (*depth == 0 || !n.name.starts_with(['$', '@', '%']))
+    });
+    Json::Array(items.map(|(n, _, _)| {
+        let kind = if n.name.starts_with(['$', '@', '%']) { SYMBOL_VARIABLE } else { SYMBOL_FUNCTION };
+        Json::object([("name", n.name.as_str().into()), ("kind", kind.into()), ("location", location(uri, &n))])
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "our $total = 0;\nsub add($n) {\n    my $t = $total;\n    $total = $t + $n;\n}\nadd(2);\n";
+
+    fn frame(message: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+    }
+
+    /// Send `messages` to a server and return its replies
+    fn session(messages: &[String]) -> Vec<Json> {
+        let input: String = messages.iter().map(|m| frame(m)).collect();
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output).unwrap();
+        let mut replies = Vec::new();
+        let mut rest = output.as_slice();
+        while let Some(reply) = read_message(&mut rest).unwrap() {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    fn open(uri: &str, text: &str) -> String {
+        let doc = Json::object([("uri", uri.into()), ("text", text.into())]);
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/didOpen".into()),
+            ("params", Json::object([("textDocument", doc)])),
+        ])
+        .to_string()
+    }
+
+    #[test]
+    fn test_diagnostics_on_open() {
+        let replies = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#.to_string(),
+            open("file:///a.mpl", "my $x = 1;\nprint $y;\n"),
+            r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#.to_string(),
+            r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string(),
+        ]);
+        assert_eq!(replies.len(), 3);
+        let capabilities = replies[0].get("result").and_then(|r| r.get("capabilities")).unwrap();
+        assert_eq!(capabilities.get("definitionProvider"), Some(&Json::Bool(true)));
+
+        let params = replies[1].get("params").unwrap();
+        assert_eq!(params.get("uri").and_then(Json::as_str), Some("file:///a.mpl"));
+        let diags = params.get("diagnostics").and_then(Json::as_array).unwrap();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(
+            diags[0].get("range").unwrap().to_string(),
+            r#"{"start":{"line":1,"character":6},"end":{"line":1,"character":8}}"#
+        );
+        assert_eq!(diags[0].get("code").and_then(Json::as_str), Some("undefined-variable"));
+        assert_eq!(replies[2].get("id").and_then(Json::as_usize), Some(2));
+    }
+
+    #[test]
+    fn test_definition() {
+        // $t in `$t + $n` goes to `my $t`
+        assert_eq!(definition(SOURCE, 3, 14).map(|n| (n.line, n.character)), Some((2, 7)));
+        // $n goes to the parameter, $total to the `our`
+        assert_eq!(definition(SOURCE, 3, 19).map(|n| (n.line, n.character)), Some((1, 8)));
+        assert_eq!(definition(SOURCE, 3, 5).map(|n| (n.line, n.character)), Some((0, 4)));
+        // The call goes to the sub
+        assert_eq!(definition(SOURCE, 5, 1).map(|n| (n.line, n.character, n.len)), Some((1, 4, 3)));
+        assert_eq!(definition(SOURCE, 5, 5), None);
+    }
+
+    #[test]
+    fn test_document_symbols() {
+        let replies = session(&[
+            open("file:///b.mpl", SOURCE),
+            r#"{"jsonrpc":"2.0","id":7,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///b.mpl"}}}"#.to_string(),
+            r#"{"jsonrpc":"2.0","id":8,"method":"textDocument/hover","params":{}}"#.to_string(),
+        ]);
+        let symbols = replies[1].get("result").and_then(Json::as_array).unwrap();
+        let names: Vec<_> = symbols.iter().map(|s| s.get("name").and_then(Json::as_str).unwrap()).collect();
+        assert_eq!(names, ["$total", "add"]);
+        assert_eq!(symbols[1].get("kind").and_then(Json::as_usize), Some(SYMBOL_FUNCTION));
+        assert!(replies[2].get("error").is_some());
+    }
+}
@@ -1,224 +1,2134 @@
 //! MicroPerl - A minimal Perl interpreter and compiler for Z80
 
-mod token;
-mod lexer;
-mod ast;
-mod parser;
-mod bytecode;
-mod compiler;
-mod z80;
-
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{BufRead, Read, Write};
 use std::process;
 
-use lexer::Lexer;
-use parser::Parser;
-use compiler::Compiler;
-use bytecode::Op;
+use kz80_microperl::{astdump, banking, budget, bytecode, carray, config, coverage, crosscheck, cycles, debugger, lsp, native, patch, printer, render, repl, storage, vm, z80, z80emu};
+use kz80_microperl::json::Json;
+use kz80_microperl::{linker, loader, type_warnings, unused_warnings, Diagnostic, ErrorKind, Lexer, Options, Parser};
+
+/// A subcommand: what follows its name on the usage line, what it does,
+/// the options only it takes, and the general options it refuses
+struct Command {
+    name: &'static str,
+    args: &'static str,
+    about: &'static str,
+    options: &'static str,
+    /// Groups of OPTIONS it has no use for; None when it takes none of them
+    refuses: Option<&'static [&'static [&'static str]]>,
+    /// Read the arguments after the name and carry the command out
+    main: fn(&'static Command, &[String]),
+}
+
+/// Options that write a file
+const OUTPUTS: &[&str] = &["-o", "--mpb", "--rom", "--ino", "--c-header", "--asm", "--lst", "--map", "--out-dir"];
+/// Options that shape the Z80 image
+const IMAGE: &[&str] = &[
+    "--target", "--rom-shell", "--native", "--reproducible", "--self-test", "--trace-rom", "--trace-port",
+    "--trace-stack", "--error-port", "--mem-stats", "--timer", "--banked", "--bank-port", "--first-page", "--max-rom-size",
+    "--max-bytecode-size", "--max-strings-size", "--irq-input", "--dump-runtime",
+];
+/// Options that run the image on the emulator
+const EMULATOR: &[&str] = &["--run", "--max-cycles", "--crosscheck", "--cycles", "--trace", "--profile"];
+/// Options that print a stage of compilation instead
+const LISTINGS: &[&str] = &["-c", "--tokens", "--ast", "--ast-format", "--bytecode", "--wcet", "--where", "--dump-after"];
+/// Options for the host VM
+const HOST: &[&str] = &["--max-steps", "--files", "--args"];
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "build",
+        args: "[options] <file.mpl>...",
+        about: "Compile to bytecode, a ROM or another image (what runs without a command)",
+        options: "",
+        refuses: Some(&[]),
+        main: build,
+    },
+    Command {
+        name: "run",
+        args: "[options] <file.mpl>",
+        about: "Run the program on the host VM",
+        options: "",
+        refuses: Some(&[OUTPUTS, IMAGE, EMULATOR, LISTINGS]),
+        main: run,
+    },
+    Command {
+        name: "check",
+        args: "[options] <file.mpl>...",
+        about: "Check syntax, variables and sub calls without generating code",
+        options: "",
+        refuses: Some(&[OUTPUTS, EMULATOR, HOST, &["--bytecode", "--wcet", "--where", "--dump-after", "--dump-runtime"]]),
+        main: build,
+    },
+    Command {
+        name: "debug",
+        args: "[options] <file.mpl>",
+        about: "Run the program on the host VM under the debugger",
+        options: "  -b, --break <file:line> Stop before the line runs
+  --watch <var> Print the variable each time it changes
+  --input <file> Feed the file to the program's input",
+        refuses: Some(&[OUTPUTS, IMAGE, EMULATOR, LISTINGS, HOST, &["--bundle", "--select-port"]]),
+        main: debug,
+    },
+    Command {
+        name: "upload",
+        args: "[options] --port <device> <file.mpl>",
+        about: "Send the ROM as Intel HEX to a monitor on a serial port",
+        options: "  --port <device> Serial device (or file) to write to",
+        refuses: Some(&[OUTPUTS, EMULATOR, LISTINGS, HOST, &["--native", "--banked", "--bank-port", "--first-page", "--dump-runtime", "--bundle", "--select-port"]]),
+        main: upload,
+    },
+    Command {
+        name: "disasm",
+        args: "<file.mpb|file.bin|file.rom>...",
+        about: "Disassemble a library, bytecode image or the bytecode in a ROM",
+        options: "",
+        refuses: None,
+        main: |_, args| disasm_files(args),
+    },
+    Command {
+        name: "patch",
+        args: "<old.rom> <new.rom> -o <file>",
+        about: "Write the patch that turns one image into another",
+        options: "  -o <file>   Write the patch to file
+  --applier <file> Also write the Z80 routine that applies it
+  --target <name> Target the applier runs on (default retroshield)
+  --org <n>   Address the applier runs at (default the heap base)",
+        refuses: None,
+        main: |_, args| patch_files(args),
+    },
+    Command {
+        name: "fmt",
+        args: "[-w | --check] <file.mpl>...",
+        about: "Print the program in the standard layout",
+        options: "  -w          Rewrite the files in place
+  --check     Fail if a file is not already formatted",
+        refuses: None,
+        main: |_, args| format_files(args),
+    },
+    Command {
+        name: "repl",
+        args: "[--max-steps <n>]",
+        about: "Read and run lines interactively on the host VM",
+        options: "  --max-steps <n> Stop a line after n bytecode instructions",
+        refuses: None,
+        main: repl,
+    },
+    Command {
+        name: "lsp",
+        args: "",
+        about: "Serve the language server protocol on stdin and stdout",
+        options: "",
+        refuses: None,
+        main: serve_lsp,
+    },
+];
+
+/// Options for building, and for the commands they apply to
+const OPTIONS: &str = "  -e <program> Compile the program given on the command line
+  -           Read the program from stdin
+  -I <dir>    Search dir for libraries named by `use` (also MPLLIB)
+  -D, --define <NAME=VALUE> Define a constant, overriding `use constant`
+  --checked   Make + - * a runtime error on 16-bit overflow instead of wrapping
+  --bounds-check Stop on an array index outside the array
+  --poke-range <lo-hi> Only let poke and poke16 write addresses from lo to hi
+  --inline    Inline calls to small subs that just return an expression
+  --entry <sub> Start at sub instead of the main code
+  --menu <sub,sub,...> Start at a numbered menu that runs the chosen sub
+  --bundle    Put each input file in the image as its own program, chosen
+              from a menu at boot
+  --select-port <n> With --bundle, run program k when port n reads k
+  --strict-types Warn where a string is used as a number or a number as a string
+  -W <setting> error: warnings fail the build; type-mix: same as --strict-types;
+              unused: warn about variables nothing names; not-in-runtime
+              (on by default): warn about code the image's runtime lacks;
+              no-<setting>: turn one off again
+  --release   Compile out asserts and bounds checks (same as -D NDEBUG=1)
+  --env <NAME=VALUE> Set $ENV{NAME}, over the [env] table of microperl.toml
+  -c          Check syntax, variables and sub calls without generating code
+  --diagnostics <text|json> Format of error messages (default text)
+  --color <auto|always|never> Colour error messages (default auto)
+  --tokens    Print tokens only
+  --ast       Print AST only
+  --ast-format <json|sexp> Print the AST for tools, with statement lines
+  --bytecode  Print bytecode disassembly
+  --dump-after <lexer|parser|fold|peephole|link> Print the program as that
+              stage leaves it, and stop there; give it again for more stages
+  --wcet      Estimate T-states per sub and loop pass without running
+  --where <line:n|pc:offset|address> Print the ROM address, bytecode offset
+              and source line of code, found by any of them
+  -o <file>   Output bytecode binary file
+  --mpb <file> Output the program as a precompiled library for `use`
+  --rom <file> Output runtime + bytecode for the target (ROM, .TAP, .BIN, /CMD or .8xp)
+  --ino <file> Output the ROM as an Arduino sketch array (retroshield)
+  --c-header <file> Output runtime + bytecode as a C header
+  --asm <file> Output runtime + bytecode as assembler source
+  --lst <file> Output a listing of the runtime and bytecode
+  --map <file> Output a symbol map (runtime labels, subs, RAM variables)
+  --out-dir <dir> Write the bytecode, ROM, Intel HEX, listing, map (and .mpb for
+              a library) there, and print a JSON summary of them
+  --target <name> retroshield (default), rc2014-acia, rc2014-sio, spectrum,
+              cpc, trs80 or ti83
+  --rom-shell Read and evaluate lines on the program's variables when it ends
+  --native    Compile the whole program to Z80 code, with no bytecode VM
+  --reproducible Zero-fill gaps and drop directories from names in the output
+  --self-test Check the program's CRC at startup and print a banner (ROM targets)
+  --trace-rom Write each opcode to port 3 before running it (--run prints them)
+  --trace-port <n> Port for --trace-rom (implies it)
+  --trace-stack With --trace-rom, also write the word on top of the VM stack
+  --error-port <n> Port print STDERR writes to (retroshield: 2)
+  --mem-stats Track VM stack and heap use for memstats(); --run reports them
+  --timer     Count CTC timer interrupts for ticks() (retroshield, rc2014)
+  --banked    Fetch code from 16K ROM pages switched in at 0x4000 (rc2014)
+  --bank-port <n> Page register port for --banked (default 0x79)
+  --first-page <n> ROM page of the first 16K of code (default 1)
+  --max-rom-size <n> Fail if the image is over n bytes (also
+              --max-bytecode-size and --max-strings-size)
+  --irq-input Buffer console input from an IM1 interrupt handler
+  --dump-runtime Print Z80 disassembly of the runtime
+  --run       Run the program on the built-in Z80 emulator
+  --max-cycles <n> Stop --run after n T-states
+  --max-steps <n> Stop `run` after n bytecode instructions
+  --files <dir> Let `run` and --run open files in dir with open/readline/write/eof/close
+  --args ...  Pass the rest of the command line to `run` or --run as @ARGV
+  --crosscheck Run on the host VM and the Z80 emulator and compare
+  --cycles    Report T-states per source line, sub and opcode (runs on the emulator)
+  --trace <file> With --cycles, also write the per-instruction trace
+  --coverage  Count basic blocks; with --run or `run`, report lines run to stderr
+  --profile <file> Report T-states from a trace written by --trace";
+
+impl Command {
+    fn find(name: &str) -> Option<&'static Command> {
+        COMMANDS.iter().find(|command| command.name == name)
+    }
+
+    fn refuses(&self, flag: &str) -> bool {
+        let flag = flag.split('=').next().unwrap_or(flag);
+        self.refuses.is_some_and(|groups| groups.iter().any(|group| group.contains(&flag)))
+    }
+
+    /// `--help` for the command: its usage and the options it takes
+    fn help(&self) -> String {
+        let mut out = format!("Usage: microperl {} {}\n{}\n", self.name, self.args, self.about);
+        let mut options: Vec<&str> = self.options.lines().collect();
+        if self.refuses.is_some() {
+            // A continuation line goes with the option above it
+            let mut keep = true;
+            for line in OPTIONS.lines() {
+                if let Some(flag) = line.strip_prefix("  ").filter(|l| l.starts_with('-')) {
+                    let flag = flag.split_whitespace().next().unwrap_or("").trim_end_matches(',');
+                    keep = !self.refuses(flag);
+                }
+                if keep {
+                    options.push(line);
+                }
+            }
+        }
+        if !options.is_empty() {
+            out.push_str("Options:\n");
+            for line in options {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+fn usage() -> String {
+    let mut out = String::from("Usage: microperl [build] [options] <file.mpl>...\n");
+    out.push_str("       microperl [build] [options] -e <program>\n");
+    out.push_str("       microperl <command> [options] ...\n");
+    out.push_str("Commands:\n");
+    for command in COMMANDS {
+        out.push_str(&format!("  {:<8} {}\n", command.name, command.about));
+    }
+    out.push_str("Options (see `microperl <command> --help` for those each command takes):\n");
+    out.push_str(OPTIONS);
+    out.push('\n');
+    out
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        eprintln!("Usage: microperl [options] <file.mpl>");
-        eprintln!("Options:");
-        eprintln!("  --tokens    Print tokens only");
-        eprintln!("  --ast       Print AST only");
-        eprintln!("  --bytecode  Print bytecode disassembly");
-        eprintln!("  -o <file>   Output bytecode binary file");
-        eprintln!("  --rom <file> Output complete Z80 ROM (runtime + bytecode)");
-        process::exit(1);
-    }
-
-    let mut input_file = None;
-    let mut output_file = None;
-    let mut rom_file = None;
-    let mut print_tokens = false;
-    let mut print_ast = false;
-    let mut print_bytecode = false;
-
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--tokens" => print_tokens = true,
-            "--ast" => print_ast = true,
-            "--bytecode" => print_bytecode = true,
-            "-o" => {
-                i += 1;
-                if i < args.len() {
-                    output_file = Some(args[i].clone());
+        eprint!("{}", usage());
+        exit_with(ErrorKind::Usage);
+    }
+
+    if matches!(args[1].as_str(), "help" | "--help" | "-h") {
+        match args.get(2) {
+            None => print!("{}", usage()),
+            Some(name) => match Command::find(name) {
+                Some(command) => print!("{}", command.help()),
+                None => {
+                    eprintln!("Unknown command: {}", name);
+                    exit_with(ErrorKind::Usage);
                 }
+            },
+        }
+        return;
+    }
+
+    // Without a command the arguments are build's
+    let (command, rest) = match Command::find(&args[1]) {
+        // A command's --help comes before its options are checked; past
+        // --args it belongs to the program
+        Some(command) if args[2..].iter().take_while(|a| *a != "--args").any(|a| a == "--help" || a == "-h") => {
+            print!("{}", command.help());
+            return;
+        }
+        Some(command) => (command, &args[2..]),
+        // The flags that came before the commands
+        None => match args[1].strip_prefix("--").and_then(Command::find) {
+            Some(command) if matches!(command.name, "disasm" | "patch") => (command, &args[2..]),
+            _ => (&COMMANDS[0], &args[1..]),
+        },
+    };
+    (command.main)(command, rest);
+}
+
+/// The arguments after a command's name, read in order
+struct Args<'a> {
+    args: &'a [String],
+    at: usize,
+    command: &'static Command,
+}
+
+impl<'a> Args<'a> {
+    fn new(command: &'static Command, args: &'a [String]) -> Self {
+        Args { args, at: 0, command }
+    }
+
+    /// The next option or file, which the command must have a use for
+    fn next(&mut self) -> Option<&'a str> {
+        let arg = self.value()?;
+        if self.command.refuses(arg) {
+            eprintln!("{} does not apply to `microperl {}`", arg, self.command.name);
+            exit_with(ErrorKind::Usage);
+        }
+        Some(arg)
+    }
+
+    /// The value after an option
+    fn value(&mut self) -> Option<&'a str> {
+        let value = self.args.get(self.at)?;
+        self.at += 1;
+        Some(value)
+    }
+
+    /// The value after an option as `parse` reads it, or `message` and
+    /// exit if there is none or `parse` refuses it
+    fn parsed<T>(&mut self, message: &str, parse: impl FnOnce(&'a str) -> Option<T>) -> T {
+        self.value().and_then(parse).unwrap_or_else(|| {
+            eprintln!("{}", message);
+            exit_with(ErrorKind::Usage);
+        })
+    }
+
+    fn string(&mut self, message: &str) -> String {
+        self.parsed(message, |value| Some(value.to_string()))
+    }
+
+    /// The arguments left, which belong to the program
+    fn rest(&mut self) -> Vec<String> {
+        let rest = self.args[self.at..].to_vec();
+        self.at = self.args.len();
+        rest
+    }
+}
+
+/// The options the commands that compile a program share, as given on the
+/// command line
+#[derive(Default)]
+struct Settings {
+    input_files: Vec<String>,
+    include: Vec<std::path::PathBuf>,
+    defines: Vec<(String, String)>,
+    env_vars: Vec<(String, String)>,
+    inline_source: Option<String>,
+    output_file: Option<String>,
+    library_file: Option<String>,
+    rom_file: Option<String>,
+    ino_file: Option<String>,
+    header_file: Option<String>,
+    asm_file: Option<String>,
+    lst_file: Option<String>,
+    map_file: Option<String>,
+    out_dir: Option<String>,
+    check_only: bool,
+    json_diagnostics: bool,
+    color: render::Color,
+    print_tokens: bool,
+    print_ast: bool,
+    ast_format: Option<String>,
+    print_bytecode: bool,
+    dump_after: Vec<Stage>,
+    print_wcet: bool,
+    locate: Option<Locate>,
+    dump_runtime: bool,
+    run: bool,
+    native: bool,
+    crosscheck: bool,
+    report_cycles: bool,
+    coverage: bool,
+    checked: bool,
+    bounds_check: bool,
+    poke_range: Option<(u16, u16)>,
+    inline: bool,
+    entry: Option<String>,
+    menu: Vec<String>,
+    bundle: bool,
+    select_port: Option<u8>,
+    strict_types: bool,
+    unused: bool,
+    /// Build an image whose runtime lacks handlers without warning
+    allow_missing: bool,
+    warnings_as_errors: bool,
+    trace_file: Option<String>,
+    profile_file: Option<String>,
+    max_cycles: Option<u64>,
+    max_steps: Option<u64>,
+    files: Option<std::path::PathBuf>,
+    program_args: Vec<String>,
+    rom_options: z80::RomOptions,
+    limits: budget::Limits,
+}
+
+impl Settings {
+    /// Apply `-W setting`: `error` makes warnings fail the build, a
+    /// warning's code turns on the check that gives it, and `no-` before
+    /// either turns it off
+    fn set_warning(&mut self, setting: &str) -> Result<(), String> {
+        let (name, on) = match setting.strip_prefix("no-") {
+            Some(name) => (name, false),
+            None => (setting, true),
+        };
+        match name {
+            "error" => self.warnings_as_errors = on,
+            "type-mix" => self.strict_types = on,
+            "unused" => self.unused = on,
+            "not-in-runtime" => self.allow_missing = !on,
+            _ => {
+                return Err(format!(
+                    "Unknown warning setting {}: -W takes error, type-mix, unused or not-in-runtime, with or without no-",
+                    setting
+                ))
             }
-            "--rom" => {
-                i += 1;
-                if i < args.len() {
-                    rom_file = Some(args[i].clone());
+        }
+        Ok(())
+    }
+
+    /// Read `args` for `command`, offering each to `extra` first for the
+    /// options only that command takes, and refuse options that can't go
+    /// together
+    fn parse(command: &'static Command, args: &[String], mut extra: impl FnMut(&str, &mut Args) -> bool) -> Settings {
+        let mut settings = Settings { check_only: command.name == "check", ..Default::default() };
+        let mut args = Args::new(command, args);
+        while let Some(arg) = args.next() {
+            if !extra(arg, &mut args) {
+                settings.option(arg, &mut args);
+            }
+        }
+        settings.check();
+        settings
+    }
+
+    /// Take `arg`, with any value after it in `args`
+    fn option<'a>(&mut self, arg: &'a str, args: &mut Args<'a>) {
+        let byte = |n: &str| parse_number(n).and_then(|n| u8::try_from(n).ok());
+        let assignment = |d: &str| d.split_once('=').map(|(name, value)| (name.to_string(), value.to_string()));
+        match arg {
+            "-c" => self.check_only = true,
+            "--diagnostics" => {
+                self.json_diagnostics = args.parsed("--diagnostics requires text or json", |format| match format {
+                    "text" => Some(false),
+                    "json" => Some(true),
+                    _ => None,
+                });
+            }
+            "--color" => self.color = args.parsed("--color requires auto, always or never", render::Color::from_name),
+            "--tokens" => self.print_tokens = true,
+            "--ast" => self.print_ast = true,
+            "--ast-format" => {
+                let format = args.parsed("--ast-format requires json or sexp", |format| {
+                    matches!(format, "json" | "sexp").then(|| format.to_string())
+                });
+                self.print_ast = true;
+                self.ast_format = Some(format);
+            }
+            "--bytecode" => self.print_bytecode = true,
+            "--dump-after" => {
+                self.dump_after.push(args.parsed("--dump-after requires lexer, parser, fold, peephole or link", Stage::parse));
+            }
+            "--wcet" => self.print_wcet = true,
+            "--where" => {
+                let number = |s: &str| parse_number(s).and_then(|n| u16::try_from(n).ok());
+                let locate = args.parsed("--where requires line:<n>, pc:<offset> or a ROM address", |spec| {
+                    match spec.split_once(':') {
+                        Some(("line", n)) => n.parse().ok().map(Locate::Line),
+                        Some(("pc", n)) => number(n).map(Locate::Offset),
+                        _ => number(spec).map(Locate::Address),
+                    }
+                });
+                self.locate = Some(locate);
+            }
+            "--irq-input" => self.rom_options.irq_input = true,
+            "--banked" => {
+                self.rom_options.banking.get_or_insert_with(banking::Banking::default);
+            }
+            "--bank-port" | "--first-page" => {
+                let n = args.parsed(&format!("{} requires a number from 0 to 255", arg), byte);
+                let banking = self.rom_options.banking.get_or_insert_with(banking::Banking::default);
+                if arg == "--bank-port" {
+                    banking.port = n;
+                } else {
+                    banking.first_page = n;
                 }
             }
-            _ => {
-                if args[i].starts_with('-') {
-                    eprintln!("Unknown option: {}", args[i]);
-                    process::exit(1);
+            "--max-rom-size" | "--max-bytecode-size" | "--max-strings-size" => {
+                let n = args.parsed(&format!("{} requires a number of bytes", arg), parse_number);
+                let limit = match arg {
+                    "--max-rom-size" => &mut self.limits.rom,
+                    "--max-bytecode-size" => &mut self.limits.bytecode,
+                    _ => &mut self.limits.strings,
+                };
+                *limit = Some(n as usize);
+            }
+            "--dump-runtime" => self.dump_runtime = true,
+            "--run" => self.run = true,
+            "--crosscheck" => self.crosscheck = true,
+            "--cycles" => self.report_cycles = true,
+            "--checked" => self.checked = true,
+            "--bounds-check" => {
+                self.bounds_check = true;
+                self.rom_options.bounds_check = true;
+            }
+            "--poke-range" => {
+                let range = args.parsed("--poke-range requires addresses as LO-HI, such as 0x8000-0x80FF", |range| {
+                    let (lo, hi) = range.split_once('-')?;
+                    let lo = u16::try_from(parse_number(lo)?).ok()?;
+                    let hi = u16::try_from(parse_number(hi)?).ok()?;
+                    (lo <= hi).then_some((lo, hi))
+                });
+                self.poke_range = Some(range);
+            }
+            "--inline" => self.inline = true,
+            "--entry" => self.entry = Some(args.string("--entry requires a sub name")),
+            "--menu" => {
+                let subs = args.string("--menu requires sub names, separated by commas");
+                self.menu = subs.split(',').map(str::to_string).collect();
+            }
+            "--strict-types" => self.strict_types = true,
+            arg if arg.starts_with("-W") => {
+                let setting = match &arg[2..] {
+                    "" => args.parsed("-W requires a warning setting", Some),
+                    setting => setting,
+                };
+                self.set_warning(setting).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    exit_with(ErrorKind::Usage);
+                });
+            }
+            "--rom-shell" => self.rom_options.shell = true,
+            "--native" => self.native = true,
+            "--reproducible" => self.rom_options.reproducible = true,
+            "--self-test" => self.rom_options.self_test = true,
+            "--trace-rom" => {
+                self.rom_options.trace_port.get_or_insert(z80::PORT_TRACE);
+            }
+            "--trace-stack" => self.rom_options.trace_stack = true,
+            "--mem-stats" => self.rom_options.mem_stats = true,
+            "--timer" => self.rom_options.timer = true,
+            "--bundle" => self.bundle = true,
+            "--select-port" => self.select_port = Some(args.parsed("--select-port requires a number from 0 to 255", byte)),
+            "--error-port" => {
+                self.rom_options.error_port = Some(args.parsed("--error-port requires a number from 0 to 255", byte));
+            }
+            "--trace-port" => {
+                self.rom_options.trace_port = Some(args.parsed("--trace-port requires a number from 0 to 255", byte));
+            }
+            "--coverage" => {
+                self.coverage = true;
+                self.rom_options.coverage = true;
+            }
+            "--trace" => self.trace_file = Some(args.string("--trace requires a file")),
+            "--profile" => self.profile_file = Some(args.string("--profile requires a file")),
+            "--args" => self.program_args = args.rest(),
+            "--files" => self.files = Some(args.string("--files requires a directory").into()),
+            "--max-cycles" => self.max_cycles = Some(args.parsed("--max-cycles requires a number", |n| n.parse().ok())),
+            "--max-steps" => self.max_steps = Some(args.parsed("--max-steps requires a number", |n| n.parse().ok())),
+            "-o" | "--mpb" | "--rom" | "--ino" | "--c-header" | "--asm" | "--lst" | "--map" => {
+                let file = match arg {
+                    "-o" => &mut self.output_file,
+                    "--mpb" => &mut self.library_file,
+                    "--rom" => &mut self.rom_file,
+                    "--ino" => &mut self.ino_file,
+                    "--c-header" => &mut self.header_file,
+                    "--asm" => &mut self.asm_file,
+                    "--lst" => &mut self.lst_file,
+                    _ => &mut self.map_file,
+                };
+                if let Some(path) = args.value() {
+                    *file = Some(path.to_string());
                 }
-                input_file = Some(args[i].clone());
             }
+            "--out-dir" => self.out_dir = Some(args.string("--out-dir requires a directory")),
+            "--target" => {
+                self.rom_options.target = args.parsed(
+                    "--target requires retroshield, rc2014-acia, rc2014-sio, spectrum, cpc, trs80 or ti83",
+                    z80::Target::from_name,
+                );
+            }
+            "-e" => self.inline_source = Some(args.string("-e requires a program")),
+            "-I" => self.include.push(args.string("-I requires a directory").into()),
+            arg if arg.starts_with("-I") => self.include.push(arg[2..].into()),
+            "--define" | "-D" => self.defines.push(args.parsed(&format!("{} requires NAME=VALUE", arg), assignment)),
+            "--env" => self.env_vars.push(args.parsed("--env requires NAME=VALUE", assignment)),
+            "--release" => self.defines.push(("NDEBUG".to_string(), "1".to_string())),
+            arg if arg.starts_with("-D") && arg.contains('=') => self.defines.extend(assignment(&arg[2..])),
+            arg if arg.starts_with('-') && arg != "-" => {
+                eprintln!("Unknown option: {}", arg);
+                exit_with(ErrorKind::Usage);
+            }
+            file => self.input_files.push(file.to_string()),
         }
-        i += 1;
     }
 
-    let input_file = input_file.unwrap_or_else(|| {
-        eprintln!("No input file specified");
-        process::exit(1);
-    });
+    /// Refuse options that can't be used together, or on the target
+    fn check(&self) {
+        if let Err(e) = self.rom_options.check() {
+            let file = self.input_files.first().map_or("-e", String::as_str);
+            fail(Diagnostic::options(e), file, "", self.report());
+        }
+        let rom_options = &self.rom_options;
+        // The Z80 heap starts above the counters, so the two heaps would differ
+        if self.coverage && self.crosscheck {
+            eprintln!("--coverage does not support --crosscheck");
+            exit_with(ErrorKind::Usage);
+        }
+        if self.trace_file.is_some() && !self.report_cycles {
+            eprintln!("--trace needs --cycles");
+            exit_with(ErrorKind::Usage);
+        }
+        // The emulator models the RetroShield only
+        if (self.run || self.crosscheck || self.report_cycles) && rom_options.target != z80::Target::RetroShield {
+            eprintln!("--run, --crosscheck and --cycles need the retroshield target");
+            exit_with(ErrorKind::Usage);
+        }
+        // The cost model is the RetroShield runtime's, without tracing
+        if self.print_wcet && (rom_options.target != z80::Target::RetroShield || rom_options.trace_port.is_some()) {
+            eprintln!("--wcet needs the retroshield target, without --trace-rom");
+            exit_with(ErrorKind::Usage);
+        }
+        let listings = [&self.asm_file, &self.lst_file, &self.map_file, &self.out_dir];
+        if rom_options.banking.is_some() && (listings.iter().any(|file| file.is_some()) || self.locate.is_some()) {
+            eprintln!("--asm, --lst, --map, --out-dir and --where do not support --banked yet");
+            exit_with(ErrorKind::Usage);
+        }
+        let outputs = [
+            &self.output_file, &self.library_file, &self.rom_file, &self.ino_file, &self.header_file, &self.asm_file,
+            &self.lst_file, &self.map_file,
+        ];
+        if self.out_dir.is_some() && outputs.iter().any(|file| file.is_some()) {
+            eprintln!("--out-dir cannot be combined with -o, --mpb, --rom, --ino, --c-header, --asm, --lst or --map");
+            exit_with(ErrorKind::Usage);
+        }
+        if self.check_only && (self.out_dir.is_some() || outputs.iter().any(|file| file.is_some())) {
+            eprintln!("-c writes nothing, so it cannot be combined with -o, --mpb, --rom, --ino, --c-header, --asm, --lst, --map or --out-dir");
+            exit_with(ErrorKind::Usage);
+        }
+        if self.ino_file.is_some() && rom_options.target != z80::Target::RetroShield {
+            eprintln!("--ino needs the retroshield target");
+            exit_with(ErrorKind::Usage);
+        }
+        if self.native && rom_options.target.hosted() {
+            eprintln!("--native needs the retroshield, rc2014-acia or rc2014-sio target");
+            exit_with(ErrorKind::Usage);
+        }
+        if self.entry.is_some() && !self.menu.is_empty() {
+            eprintln!("--entry and --menu can't be used together");
+            exit_with(ErrorKind::Usage);
+        }
+        if (self.entry.is_some() || !self.menu.is_empty()) && (self.native || self.library_file.is_some()) {
+            eprintln!("--entry and --menu don't apply to --native or --mpb");
+            exit_with(ErrorKind::Usage);
+        }
+        let images = [
+            &self.output_file, &self.library_file, &self.header_file, &self.asm_file, &self.lst_file, &self.map_file,
+            &self.out_dir,
+        ];
+        if self.native && (images.iter().any(|file| file.is_some()) || rom_options.banking.is_some() || rom_options.shell
+            || rom_options.irq_input || rom_options.self_test || rom_options.trace_port.is_some() || rom_options.mem_stats
+            || self.coverage || self.crosscheck || self.report_cycles || !self.program_args.is_empty())
+        {
+            eprintln!("--native only writes --rom and --ino, or runs with --run");
+            exit_with(ErrorKind::Usage);
+        }
 
-    let source = fs::read_to_string(&input_file).unwrap_or_else(|e| {
-        eprintln!("Error reading {}: {}", input_file, e);
-        process::exit(1);
-    });
+        if self.select_port.is_some() && !self.bundle {
+            eprintln!("--select-port needs --bundle");
+            exit_with(ErrorKind::Usage);
+        }
+        if self.select_port.is_some() && rom_options.target.hosted() {
+            eprintln!("--select-port needs the retroshield, rc2014-acia or rc2014-sio target");
+            exit_with(ErrorKind::Usage);
+        }
+        let others = [
+            &self.library_file, &self.ino_file, &self.header_file, &self.asm_file, &self.lst_file, &self.out_dir,
+            &self.trace_file, &self.profile_file,
+        ];
+        if self.bundle && (others.iter().any(|file| file.is_some()) || self.inline_source.is_some()
+            || self.input_files.iter().any(|file| file == "-") || self.native || rom_options.banking.is_some()
+            || self.coverage || self.crosscheck || self.report_cycles || self.check_only || self.print_tokens
+            || self.print_ast || self.print_wcet || !self.dump_after.is_empty() || self.locate.is_some()
+            || self.entry.is_some() || !self.menu.is_empty())
+        {
+            eprintln!("--bundle takes program files, and only writes -o, --rom and --map, prints --bytecode, or runs with --run or run");
+            exit_with(ErrorKind::Usage);
+        }
+    }
+
+    fn report(&self) -> Report {
+        Report { json: self.json_diagnostics, check_only: self.check_only, color: self.color.enabled() }
+    }
 
-    // Tokenize
-    let mut lexer = Lexer::new(&source);
-    let tokens = lexer.tokenize();
+    /// Print what `stage` left for --dump-after, and whether to stop there
+    fn dump(&self, stage: Stage, print: impl Fn()) -> bool {
+        if self.dump_after.contains(&stage) {
+            println!("--- after {} ---", stage.name());
+            print();
+        }
+        self.dump_after.iter().max() == Some(&stage)
+    }
 
-    if print_tokens {
-        println!("Tokens:");
-        for tok in &tokens {
-            println!("  {:?} at {}:{}", tok.token, tok.line, tok.column);
+    /// Compile each input file as its own program, and bundle them
+    fn bundle(&mut self) -> bytecode::Module {
+        let mut include = self.include.clone();
+        if let Some(path) = env::var_os("MPLLIB") {
+            include.extend(env::split_paths(&path));
         }
-        return;
+        if let Some(stem) = self.input_files.first().and_then(|file| std::path::Path::new(file).file_stem()) {
+            self.rom_options.program_name = stem.to_string_lossy().into_owned();
+        }
+        let options = Options {
+            rom: self.rom_options.clone(),
+            include,
+            defines: self.defines.clone(),
+            env: self.env_vars.clone(),
+            checked: self.checked,
+            bounds_check: self.bounds_check,
+            poke_range: self.poke_range,
+            inline: self.inline,
+            strict_types: self.strict_types,
+            unused: self.unused,
+            warnings_as_errors: self.warnings_as_errors,
+            ..Default::default()
+        };
+        bundle_programs(&self.input_files, options, self.select_port, self.report())
     }
 
-    // Parse
-    let mut parser = Parser::new(tokens);
-    let program = match parser.parse() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Parse error: {}", e);
-            process::exit(1);
+    /// `-e` gives the program on the command line, and `-` reads it from
+    /// stdin. Several files are compiled as one program, in order, so line
+    /// numbers after the first file count on through the files.
+    fn read_source(&self) -> (String, String) {
+        match (&self.inline_source, self.input_files.first()) {
+            (Some(_), Some(file)) => {
+                eprintln!("-e cannot be combined with an input file ({})", file);
+                exit_with(ErrorKind::Usage);
+            }
+            (Some(code), None) => ("-e".to_string(), code.clone()),
+            (None, Some(first)) => {
+                let mut source = String::new();
+                for file in &self.input_files {
+                    let text = if file == "-" {
+                        String::from_utf8(read_stdin()).unwrap_or_else(|_| {
+                            eprintln!("Program on stdin is not valid UTF-8");
+                            exit_with(ErrorKind::Io);
+                        })
+                    } else {
+                        fs::read_to_string(file).unwrap_or_else(|e| {
+                            eprintln!("Error reading {}: {}", file, e);
+                            exit_with(ErrorKind::Io);
+                        })
+                    };
+                    source.push_str(&text);
+                    if !source.is_empty() && !source.ends_with('\n') {
+                        source.push('\n');
+                    }
+                }
+                (first.clone(), source)
+            }
+            (None, None) => {
+                eprintln!("No input file specified");
+                exit_with(ErrorKind::Usage);
+            }
         }
-    };
+    }
+
+    /// Read, parse and compile the program, with string buffers for the
+    /// `host` VM. None once a listing that stops there has been printed.
+    fn compile(&mut self, host: bool) -> Option<(Compiled, bytecode::Module)> {
+        let report = self.report();
+        let (file, source) = self.read_source();
+        let name = std::path::Path::new(&file)
+            .file_stem()
+            .filter(|_| !file.starts_with('-'))
+            .map_or_else(|| "microperl".into(), |s| s.to_string_lossy().into_owned());
+        self.rom_options.program_name = name.clone();
+        let last_dump = self.dump_after.iter().copied().max();
 
-    if print_ast {
-        println!("AST:");
-        for stmt in &program.statements {
-            println!("  {:?}", stmt);
+        // Tokenize
+        let mut lexer = Lexer::new(&source);
+        let tokens = lexer.tokenize();
+        if let (Some(unknown), false) = (lexer.unknown(), self.print_tokens || last_dump == Some(Stage::Lexer)) {
+            fail(Diagnostic::lex(&source, unknown), &file, &source, report);
         }
+
+        if self.print_tokens {
+            println!("Tokens:");
+            print_tokens_of(&tokens);
+            return None;
+        }
+        if self.dump(Stage::Lexer, || print_tokens_of(&tokens)) {
+            return None;
+        }
+
+        // Parse
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse().unwrap_or_else(|e| {
+            fail(Diagnostic::parse(&source, &parser, e), &file, &source, report)
+        });
+
+        // Libraries come from -I, then MPLLIB, then the program's directory
+        let mut include = self.include.clone();
+        if let Some(path) = env::var_os("MPLLIB") {
+            include.extend(env::split_paths(&path).filter(|dir| !dir.as_os_str().is_empty()));
+        }
+        // A bare file name's directory is the empty path, the current one
+        let program_dir = std::path::Path::new(&file).parent().filter(|d| !file.starts_with('-') && !d.as_os_str().is_empty());
+        let program_dir = program_dir.map_or_else(|| ".".into(), |d| d.to_path_buf());
+        // The project's microperl.toml sits there too; --env wins over it
+        let config = config::Config::load(&program_dir).unwrap_or_else(|e| {
+            fail(Diagnostic::options(e), &file, &source, report)
+        });
+        let mut env_vars = config.env;
+        env_vars.extend(self.env_vars.iter().cloned());
+        include.push(program_dir);
+        let mut loader = loader::Loader::new(include);
+        let program = loader.resolve(program).unwrap_or_else(|e| {
+            fail(Diagnostic::load(e), &file, &source, report)
+        });
+
+        let mut warnings = match self.strict_types {
+            true => type_warnings(&source, &program),
+            false => Vec::new(),
+        };
+        if self.unused {
+            warnings.extend(unused_warnings(&source, &program));
+        }
+        warn(&warnings, &file, &source, report);
+        if self.warnings_as_errors {
+            fail_on_warnings(&warnings, &file, report);
+        }
+
+        if self.dump(Stage::Parser, || print_statements(&program)) {
+            return None;
+        }
+
+        if self.print_ast {
+            match self.ast_format.as_deref() {
+                Some("json") => println!("{}", astdump::json(&program)),
+                Some(_) => print!("{}", astdump::sexp(&program)),
+                None => {
+                    println!("AST:");
+                    print_statements(&program);
+                }
+            }
+            return None;
+        }
+
+        if self.library_file.is_some() {
+            if let Err(e) = linker::check_library(&program) {
+                eprintln!("{}: {}", file, e);
+                exit_with(ErrorKind::Compile);
+            }
+        }
+
+        // Where the build ran stays out of a reproducible image
+        let file_name = std::path::Path::new(&file).file_name().map(|n| n.to_string_lossy().into_owned());
+        let embedded_file = match file_name {
+            Some(name) if self.rom_options.reproducible => name,
+            _ => file.clone(),
+        };
+        // Compile, after any precompiled libraries
+        let options = Options {
+            coverage: self.coverage,
+            checked: self.checked,
+            bounds_check: self.bounds_check,
+            poke_range: self.poke_range,
+            inline: self.inline,
+            entry: self.entry.clone(),
+            menu: self.menu.clone(),
+            defines: self.defines.clone(),
+            env: env_vars,
+            file: Some(embedded_file.clone()),
+            string_buffers: host,
+            rom: self.rom_options.clone(),
+            ..Default::default()
+        };
+        let mut compiler = options.compiler(loader.libraries()).unwrap_or_else(|e| fail(e, &file, &source, report));
+        if self.dump_after.contains(&Stage::Fold) {
+            let mut unoptimized = compiler.clone();
+            unoptimized.set_optimize(false);
+            let module = unoptimized.compile(&program).unwrap_or_else(|e| {
+                fail(Diagnostic::compile(&source, &unoptimized, e), &file, &source, report)
+            });
+            if self.dump(Stage::Fold, || print_module(&module)) {
+                return None;
+            }
+        }
+        let module = compiler.compile(&program).unwrap_or_else(|e| {
+            fail(Diagnostic::compile(&source, &compiler, e), &file, &source, report)
+        });
+        if self.dump(Stage::Peephole, || print_module(&module)) {
+            return None;
+        }
+        Some((Compiled { file, source, name, embedded_file, program, compiler, report }, module))
+    }
+
+    /// Keep instructions from straddling ROM pages
+    fn paginate(&self, compiled: &Compiled, module: bytecode::Module) -> bytecode::Module {
+        kz80_microperl::paginate(module, &self.rom_options).unwrap_or_else(|e| compiled.fail(e))
+    }
+
+    /// Stop before writing an image whose runtime has no handler for some
+    /// of the code, or no hashes at all, whose order the program may count on
+    fn check_handlers(&self, compiled: &Compiled, module: &bytecode::Module) {
+        let missing = cycles::missing_handlers(module, &self.rom_options);
+        if let Some(&(pc, _)) = missing.iter().find(|&&(pc, _)| z80::uses_hash(module, pc)) {
+            let message = format!("Hashes need the host VM (run): the {} runtime has none", self.rom_options.target.name());
+            compiled.fail(Diagnostic::host_only(&compiled.source, module.line_at(pc), message));
+        }
+        if self.allow_missing {
+            return;
+        }
+        let mut ops = Vec::new();
+        let warnings: Vec<Diagnostic> = missing
+            .into_iter()
+            .filter(|&(_, op)| !ops.contains(&op) && { ops.push(op); true })
+            .map(|(pc, op)| Diagnostic::missing_handler(&compiled.source, module.line_at(pc), op))
+            .collect();
+        warn(&warnings, &compiled.file, &compiled.source, compiled.report);
+        if self.warnings_as_errors {
+            fail_on_warnings(&warnings, &compiled.file, compiled.report);
+        }
+    }
+
+    /// Refuse to build an image that won't fit the EPROM
+    fn check_size(&self, compiled: &Compiled, module: &bytecode::Module) {
+        if !self.limits.is_empty() {
+            if let Err(e) = self.limits.check(&budget::measure(module, &self.rom_options)) {
+                compiled.fail(Diagnostic::size(e));
+            }
+        }
+    }
+}
+
+/// The program a command compiled, for what it does next
+struct Compiled {
+    /// The first input file, `-e` or `-`, as messages name it
+    file: String,
+    source: String,
+    /// The program's name in the image
+    name: String,
+    /// The file name compiled into the image
+    embedded_file: String,
+    program: kz80_microperl::Program,
+    compiler: kz80_microperl::Compiler,
+    report: Report,
+}
+
+impl Compiled {
+    fn fail(&self, diagnostic: Diagnostic) -> ! {
+        fail(diagnostic, &self.file, &self.source, self.report)
+    }
+}
+
+/// `microperl build`, which runs without a command, and `microperl check`,
+/// which is `build -c`
+fn build(command: &'static Command, args: &[String]) {
+    let settings = Settings::parse(command, args, |_, _| false);
+    if settings.files.is_some() && !settings.run {
+        eprintln!("--files needs `run` or --run");
+        exit_with(ErrorKind::Usage);
+    }
+    build_program(settings);
+}
+
+fn build_program(mut settings: Settings) {
+    // The runtime does not depend on the program, so no input is needed
+    if settings.dump_runtime {
+        print!("{}", z80::dump_runtime(&settings.rom_options));
         return;
     }
 
-    // Compile
-    let compiler = Compiler::new();
-    let module = match compiler.compile(&program) {
-        Ok(m) => m,
-        Err(e) => {
-            eprintln!("Compile error: {}", e);
-            process::exit(1);
+    if settings.bundle {
+        let module = settings.bundle();
+        if settings.print_bytecode {
+            print_module(&module);
+        } else if settings.run {
+            let files = settings.files.as_deref();
+            run_rom(&module, &settings.input_files[0], &settings.rom_options, settings.max_cycles, files, &settings.program_args, None);
+        } else {
+            write_bundle(&module, &settings.rom_options, settings.output_file, settings.rom_file, settings.map_file);
         }
+        return;
+    }
+
+    let Some((compiled, module)) = settings.compile(false) else {
+        return;
     };
 
-    if print_bytecode {
-        println!("String constants:");
-        for (i, s) in module.strings.iter().enumerate() {
-            println!("  [{}] {:?}", i, s);
+    // The bytecode compiler has checked the program; compile it again to
+    // machine code instead
+    if settings.native {
+        let constants = compiled.compiler.constants();
+        let image = native::program(&compiled.program, &constants, &settings.rom_options).unwrap_or_else(|e| {
+            compiled.fail(Diagnostic::native(&compiled.source, e.line, e.message))
+        });
+        if settings.check_only {
+            println!("{} syntax OK", compiled.file);
+        } else if settings.run {
+            run_native(&image, &compiled.file, settings.max_cycles);
+        } else {
+            write_native(&image, &compiled.embedded_file, settings.rom_file, settings.ino_file);
         }
-        println!("\nSubroutines:");
-        for (name, addr, params) in &module.subs {
-            println!("  {} @ 0x{:04X} ({} params)", name, addr, params);
+        return;
+    }
+
+    if settings.check_only {
+        println!("{} syntax OK", compiled.file);
+        return;
+    }
+
+    let module = settings.paginate(&compiled, module);
+    if settings.dump(Stage::Link, || print_linked(&module, &settings.rom_options)) {
+        return;
+    }
+
+    if settings.print_bytecode {
+        print_module(&module);
+        return;
+    }
+
+    if settings.print_wcet {
+        print!("{}", cycles::render_estimates(&cycles::estimate(&module, &settings.rom_options), Some(&compiled.source)));
+        return;
+    }
+
+    if let Some(locate) = settings.locate {
+        print_location(&module, &settings.rom_options, locate);
+        return;
+    }
+
+    if let Some(path) = &settings.profile_file {
+        let trace = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", path, e);
+            exit_with(ErrorKind::Io);
+        });
+        match cycles::read_trace(&module, &trace) {
+            Ok(report) => print!("{}", report.render(Some(&compiled.source))),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                exit_with(ErrorKind::Io);
+            }
         }
-        println!("\nBytecode ({} bytes):", module.code.len());
-        disassemble(&module.code);
         return;
     }
 
+    if settings.report_cycles {
+        let input = read_stdin();
+        let max_cycles = settings.max_cycles.unwrap_or(cycles::DEFAULT_MAX_CYCLES);
+        let report = match &settings.trace_file {
+            Some(path) => trace_to_file(&module, &settings.rom_options, &input, max_cycles, path),
+            None => cycles::measure(&module, &settings.rom_options, &input, max_cycles),
+        };
+        print!("{}", report.render(Some(&compiled.source)));
+        return;
+    }
+
+    if settings.crosscheck {
+        crosscheck_module(&module, &settings.rom_options, settings.max_steps, settings.max_cycles);
+        return;
+    }
+
+    if settings.run {
+        let coverage = settings.coverage.then_some(compiled.source.as_str());
+        let files = settings.files.as_deref();
+        run_rom(&module, &compiled.file, &settings.rom_options, settings.max_cycles, files, &settings.program_args, coverage);
+        return;
+    }
+
+    let images = [&settings.rom_file, &settings.ino_file, &settings.header_file, &settings.out_dir];
+    if images.iter().any(|file| file.is_some()) {
+        settings.check_handlers(&compiled, &module);
+    }
+    settings.check_size(&compiled, &module);
+
+    // Every artifact at once, for build systems
+    if let Some(dir) = &settings.out_dir {
+        let library = linker::check_library(&compiled.program).is_ok();
+        write_out_dir(&module, &settings.rom_options, &compiled.name, dir, library);
+        return;
+    }
+
+    write_outputs(&settings, &compiled, &module);
+}
+
+/// Write the files build's options name
+fn write_outputs(settings: &Settings, compiled: &Compiled, module: &bytecode::Module) {
+    let (options, name) = (&settings.rom_options, &compiled.name);
     println!("Compiled: {} bytes of bytecode, {} strings, {} subs",
              module.code.len(), module.strings.len(), module.subs.len());
 
     // Write bytecode output
-    if let Some(out) = output_file {
-        let binary = generate_binary(&module);
-        let mut file = fs::File::create(&out).unwrap_or_else(|e| {
-            eprintln!("Error creating {}: {}", out, e);
-            process::exit(1);
-        });
-        file.write_all(&binary).unwrap_or_else(|e| {
-            eprintln!("Error writing {}: {}", out, e);
-            process::exit(1);
-        });
+    if let Some(out) = &settings.output_file {
+        let binary = z80::generate_bytecode_image(module);
+        write_output(out, &binary);
         println!("Wrote {} bytes to {}", binary.len(), out);
     }
 
+    // Write a precompiled library for `use`
+    if let Some(out) = &settings.library_file {
+        let library = linker::write(module);
+        write_output(out, &library);
+        println!("Wrote {} byte library to {}", library.len(), out);
+    }
+
     // Write ROM output (runtime + bytecode)
+    if let Some(out) = &settings.rom_file {
+        let rom = z80::generate_output(module, options, name);
+        write_output(out, &rom);
+        println!("Wrote {} bytes {} to {} (bytecode at 0x{:04X})",
+                 rom.len(), options.target.output_kind(), out,
+                 options.target.layout().bytecode_org);
+    }
+
+    // Write the ROM as C source for the RetroShield sketch
+    if let Some(out) = &settings.ino_file {
+        let rom = z80::generate_rom(module, options);
+        let sketch = carray::arduino_sketch(&compiled.embedded_file, &rom, options.target.layout().runtime_org);
+        write_output(out, sketch.as_bytes());
+        println!("Wrote {} byte ROM array to {}", rom.len(), out);
+    }
+
+    // Write the image as a C header for other firmware
+    if let Some(out) = &settings.header_file {
+        let rom = z80::generate_rom(module, options);
+        let layout = options.target.layout();
+        let header = carray::c_header(name, &rom, layout.runtime_org, layout.bytecode_org);
+        write_output(out, header.as_bytes());
+        println!("Wrote {} byte image to {}", rom.len(), out);
+    }
+
+    // Write assembler source for the same image
+    if let Some(out) = &settings.asm_file {
+        let source = z80::generate_asm(module, options);
+        write_output(out, source.as_bytes());
+        println!("Wrote {} lines of assembler source to {}", source.lines().count(), out);
+    }
+
+    // Write the listing and symbol map for debugging on the target
+    if let Some(out) = &settings.lst_file {
+        let listing = z80::generate_listing(module, options);
+        write_output(out, listing.as_bytes());
+        println!("Wrote listing to {}", out);
+    }
+    if let Some(out) = &settings.map_file {
+        let map = z80::generate_map(module, options);
+        write_output(out, map.as_bytes());
+        println!("Wrote symbol map to {}", out);
+    }
+}
+
+/// `microperl run`: run the program on the host VM instead of building
+fn run(command: &'static Command, args: &[String]) {
+    run_program(Settings::parse(command, args, |_, _| false));
+}
+
+fn run_program(mut settings: Settings) {
+    if settings.bundle {
+        let module = settings.bundle();
+        run_vm_module(&module, &settings.input_files[0], settings.max_steps, settings.files.as_deref(), &settings.program_args, None);
+        return;
+    }
+    let Some((compiled, module)) = settings.compile(true) else {
+        return;
+    };
+    let module = settings.paginate(&compiled, module);
+    let coverage = settings.coverage.then_some(compiled.source.as_str());
+    run_vm_module(&module, &compiled.file, settings.max_steps, settings.files.as_deref(), &settings.program_args, coverage);
+}
+
+/// Where `microperl debug` stops, what it watches and what it feeds the
+/// program
+#[derive(Default)]
+struct Debugging {
+    breakpoints: Vec<String>,
+    watches: Vec<String>,
+    input: Option<String>,
+}
+
+/// `microperl debug`: run the program on the host VM under the debugger
+fn debug(command: &'static Command, args: &[String]) {
+    let mut debugging = Debugging::default();
+    let settings = Settings::parse(command, args, |arg, args| {
+        match arg {
+            "-b" | "--break" => debugging.breakpoints.extend(args.value().map(str::to_string)),
+            "--watch" => debugging.watches.extend(args.value().map(str::to_string)),
+            "--input" => {
+                if let Some(path) = args.value() {
+                    debugging.input = Some(path.to_string());
+                }
+            }
+            _ => return false,
+        }
+        true
+    });
+    debug_program(settings, debugging);
+}
+
+fn debug_program(mut settings: Settings, debugging: Debugging) {
+    let Some((compiled, module)) = settings.compile(true) else {
+        return;
+    };
+    let module = settings.paginate(&compiled, module);
+    let input = match &debugging.input {
+        Some(path) => fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", path, e);
+            exit_with(ErrorKind::Io);
+        }),
+        None => Vec::new(),
+    };
+    let mut session = debugger::Debugger::new(&module, &compiled.file, &compiled.source, &input);
+    for spec in &debugging.breakpoints {
+        if let Err(e) = session.set_breakpoint(spec) {
+            eprintln!("{}", e);
+            exit_with(ErrorKind::Usage);
+        }
+    }
+    for var in &debugging.watches {
+        if let Err(e) = session.watch(var) {
+            eprintln!("{}", e);
+            exit_with(ErrorKind::Usage);
+        }
+    }
+    debug_module(session);
+}
+
+/// `microperl upload`: send the ROM to a monitor on a serial port
+fn upload(command: &'static Command, args: &[String]) {
+    let mut port = None;
+    let settings = Settings::parse(command, args, |arg, args| {
+        if arg != "--port" {
+            return false;
+        }
+        port = Some(args.string("--port requires a device"));
+        true
+    });
+    let Some(port) = port else {
+        eprintln!("upload needs --port <device>");
+        exit_with(ErrorKind::Usage);
+    };
+    // A hosted target loads its image from tape or disk, not a monitor
+    if settings.rom_options.target.hosted() {
+        eprintln!("upload needs the retroshield, rc2014-acia or rc2014-sio target");
+        exit_with(ErrorKind::Usage);
+    }
+    upload_program(settings, &port);
+}
+
+fn upload_program(mut settings: Settings, port: &str) {
+    let Some((compiled, module)) = settings.compile(false) else {
+        return;
+    };
+    let module = settings.paginate(&compiled, module);
+    settings.check_handlers(&compiled, &module);
+    settings.check_size(&compiled, &module);
+
+    let options = &settings.rom_options;
+    let rom = z80::generate_rom(&module, options);
+    let hex = carray::intel_hex(&rom, options.target.layout().runtime_org);
+    let mut device = fs::OpenOptions::new().write(true).create(true).truncate(true).open(port).unwrap_or_else(|e| {
+        eprintln!("Error opening {}: {}", port, e);
+        exit_with(ErrorKind::Io);
+    });
+    if let Err(e) = device.write_all(hex.as_bytes()).and_then(|_| device.flush()) {
+        eprintln!("Error writing {}: {}", port, e);
+        exit_with(ErrorKind::Io);
+    }
+    println!("Sent {} byte ROM to {} as {} Intel HEX records", rom.len(), port, hex.lines().count());
+}
+
+/// `microperl repl`: read and run lines on the host VM
+fn repl(_: &'static Command, args: &[String]) {
+    let mut session = repl::Repl::new();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("--max-steps") => match args.get(1).and_then(|n| n.parse::<u64>().ok()) {
+            Some(n) => session.max_steps = n,
+            None => {
+                eprintln!("--max-steps requires a number");
+                exit_with(ErrorKind::Usage);
+            }
+        },
+        Some(arg) => {
+            eprintln!("Unknown repl option: {}", arg);
+            exit_with(ErrorKind::Usage);
+        }
+    }
+    run_repl(session);
+}
+
+/// `microperl lsp`: serve the language server protocol on stdin and stdout
+fn serve_lsp(_: &'static Command, _: &[String]) {
+    let stdin = std::io::stdin();
+    if let Err(e) = lsp::serve(stdin.lock(), std::io::stdout()) {
+        eprintln!("lsp: {}", e);
+        exit_with(ErrorKind::Io);
+    }
+}
+
+/// Compile each of `files` on its own with `options`, and bundle them
+/// behind a boot menu, or `select_port`
+fn bundle_programs(files: &[String], options: kz80_microperl::Options, select_port: Option<u8>, report: Report) -> bytecode::Module {
+    if files.is_empty() {
+        eprintln!("No input file specified");
+        exit_with(ErrorKind::Usage);
+    }
+    let mut programs = Vec::new();
+    for file in files {
+        let source = fs::read_to_string(file).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", file, e);
+            exit_with(ErrorKind::Io);
+        });
+        let path = std::path::Path::new(file);
+        let name = path.file_stem().map_or_else(|| file.clone(), |s| s.to_string_lossy().into_owned());
+        let mut options = options.clone();
+        options.name = name.clone();
+        options.include.push(path.parent().map_or_else(|| ".".into(), |d| d.to_path_buf()));
+        match kz80_microperl::compile_source(&source, options) {
+            Ok(artifacts) => {
+                warn(&artifacts.warnings, file, &source, report);
+                programs.push((name, artifacts.module));
+            }
+            Err(diagnostics) => match diagnostics.iter().find(|d| !d.is_warning()) {
+                Some(error) => fail(error.clone(), file, &source, report),
+                // Only warnings, with -W error
+                None => {
+                    warn(&diagnostics, file, &source, report);
+                    fail_on_warnings(&diagnostics, file, report);
+                }
+            },
+        }
+    }
+    linker::bundle(&programs, select_port).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit_with(ErrorKind::Compile);
+    })
+}
+
+/// Write the outputs `--bundle` supports
+fn write_bundle(module: &bytecode::Module, options: &z80::RomOptions, output_file: Option<String>, rom_file: Option<String>, map_file: Option<String>) {
+    println!("Bundled: {} bytes of bytecode, {} strings, {} subs", module.code.len(), module.strings.len(), module.subs.len());
+    if let Some(out) = output_file {
+        let binary = z80::generate_bytecode_image(module);
+        write_output(&out, &binary);
+        println!("Wrote {} bytes to {}", binary.len(), out);
+    }
+    if let Some(out) = rom_file {
+        let rom = z80::generate_output(module, options, &options.program_name);
+        write_output(&out, &rom);
+        println!("Wrote {} bytes {} to {} (bytecode at 0x{:04X})",
+                 rom.len(), options.target.output_kind(), out, options.target.layout().bytecode_org);
+    }
+    if let Some(out) = map_file {
+        let map = z80::generate_map(module, options);
+        write_output(&out, map.as_bytes());
+        println!("Wrote symbol map to {}", out);
+    }
+}
+
+/// The `--trace-rom` port log as one opcode a line, with the top of the
+/// stack after it for `--trace-stack`
+fn rom_trace(log: &[u8], stack: bool) -> String {
+    let mut out = String::new();
+    for record in log.chunks(if stack { 3 } else { 1 }) {
+        out.push_str(&format!("trace: {:?}", bytecode::Op::from_byte(record[0])));
+        if let [_, lo, hi] = *record {
+            out.push_str(&format!(" 0x{:04X}", u16::from_le_bytes([lo, hi])));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Compiler stages, in order, for --dump-after
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    Lexer,
+    /// The parser, and the loader's `use`
+    Parser,
+    /// Code generation, which folds constants
+    Fold,
+    /// The jump optimizer
+    Peephole,
+    /// Libraries linked ahead and the code placed in the image
+    Link,
+}
+
+impl Stage {
+    fn parse(name: &str) -> Option<Stage> {
+        Some(match name {
+            "lexer" => Stage::Lexer,
+            "parser" => Stage::Parser,
+            "fold" => Stage::Fold,
+            "peephole" => Stage::Peephole,
+            "link" => Stage::Link,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Lexer => "lexer",
+            Stage::Parser => "parser",
+            Stage::Fold => "fold",
+            Stage::Peephole => "peephole",
+            Stage::Link => "link",
+        }
+    }
+}
+
+fn print_tokens_of(tokens: &[kz80_microperl::token::TokenWithSpan]) {
+    for tok in tokens {
+        println!("  {:?} at {}:{}", tok.token, tok.line, tok.column);
+    }
+}
+
+fn print_statements(program: &kz80_microperl::Program) {
+    for stmt in &program.statements {
+        println!("  {:?}", stmt);
+    }
+}
+
+/// Each instruction at its ROM address, with the sub it starts and its
+/// source line; banked code as the pages hold it
+fn print_linked(module: &bytecode::Module, options: &z80::RomOptions) {
+    if options.banking.is_some() {
+        print_module(module);
+        return;
+    }
+    let map = z80::AddressMap::new(module, options);
+    for pc in map.instructions() {
+        if let Some((name, ..)) = module.subs.iter().find(|(_, addr, _)| *addr == pc) {
+            println!("{}:", name);
+        }
+        let line = map.line(pc).map_or(String::new(), |n| format!("  line {}", n));
+        println!("  {:04X}  {}{}", map.address(pc), map.describe(pc), line);
+    }
+}
+
+/// Code to find with --where
+#[derive(Clone, Copy)]
+enum Locate {
+    Line(usize),
+    /// A bytecode offset, as VM_PC and jump operands hold
+    Offset(u16),
+    Address(u16),
+}
+
+/// Print the ROM address, instruction and source line of the code `locate`
+/// names, one line per instruction found
+fn print_location(module: &bytecode::Module, options: &z80::RomOptions, locate: Locate) {
+    let map = z80::AddressMap::new(module, options);
+    let offsets = match locate {
+        Locate::Line(line) => map.line_offsets(line),
+        Locate::Offset(pc) => map.instructions().filter(|&at| at == pc).collect(),
+        Locate::Address(addr) => map.offset(addr).into_iter().collect(),
+    };
+    if offsets.is_empty() {
+        match locate {
+            Locate::Line(line) => eprintln!("Line {} has no code", line),
+            Locate::Offset(pc) => eprintln!("No instruction starts at offset 0x{:04X}", pc),
+            Locate::Address(addr) => eprintln!(
+                "0x{:04X} is not in the code, which runs from 0x{:04X} to 0x{:04X}",
+                addr,
+                map.address(0),
+                map.address(module.code.len() as u16).wrapping_sub(1)
+            ),
+        }
+        exit_with(ErrorKind::Usage);
+    }
+    for pc in offsets {
+        let line = map.line(pc).map_or(String::new(), |n| format!("  line {}", n));
+        println!("{:04X}  {}{}", map.address(pc), map.describe(pc), line);
+    }
+}
+
+/// A decimal or 0x-prefixed hex number
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn write_output(path: &str, bytes: &[u8]) {
+    let mut file = fs::File::create(path).unwrap_or_else(|e| {
+        eprintln!("Error creating {}: {}", path, e);
+        exit_with(ErrorKind::Io);
+    });
+    file.write_all(bytes).unwrap_or_else(|e| {
+        eprintln!("Error writing {}: {}", path, e);
+        exit_with(ErrorKind::Io);
+    });
+}
+
+/// Write the `--out-dir` artifacts, then print the build summary as JSON:
+/// the target, entry points and each file's size and CRC-16
+fn write_out_dir(module: &bytecode::Module, options: &z80::RomOptions, name: &str, dir: &str, library: bool) {
+    fs::create_dir_all(dir).unwrap_or_else(|e| {
+        eprintln!("Error creating {}: {}", dir, e);
+        exit_with(ErrorKind::Io);
+    });
+    let layout = options.target.layout();
+    let rom = z80::generate_rom(module, options);
+    let mut artifacts = vec![
+        ("bytecode", "bc".to_string(), z80::generate_bytecode_image(module)),
+        ("rom", options.target.output_kind().to_ascii_lowercase(), z80::generate_output(module, options, name)),
+        ("hex", "hex".to_string(), carray::intel_hex(&rom, layout.runtime_org).into_bytes()),
+        ("lst", "lst".to_string(), z80::generate_listing(module, options).into_bytes()),
+        ("map", "map".to_string(), z80::generate_map(module, options).into_bytes()),
+    ];
+    if library {
+        artifacts.push(("mpb", "mpb".to_string(), linker::write(module)));
+    }
+
+    let mut files = Vec::new();
+    for (kind, extension, bytes) in artifacts {
+        let path = std::path::Path::new(dir).join(format!("{}.{}", name, extension));
+        let path = path.to_string_lossy();
+        write_output(&path, &bytes);
+        files.push(Json::object([
+            ("kind", kind.into()),
+            ("path", path.as_ref().into()),
+            ("size", bytes.len().into()),
+            ("crc16", (z80::crc16(&bytes) as usize).into()),
+        ]));
+    }
+    let code = layout.bytecode_org as usize + bytecode::HEADER;
+    let subs = module.subs.iter().map(|(sub, address, _)| {
+        Json::object([("name", sub.as_str().into()), ("address", (code + *address as usize).into())])
+    });
+    let summary = Json::object([
+        ("program", name.into()),
+        ("target", options.target.name().into()),
+        ("runtime_version", (z80::RUNTIME_VERSION as usize).into()),
+        ("load_address", (layout.runtime_org as usize).into()),
+        ("bytecode_address", (layout.bytecode_org as usize).into()),
+        ("entry", (code + module.entry as usize).into()),
+        ("subs", Json::Array(subs.collect())),
+        ("artifacts", Json::Array(files)),
+    ]);
+    println!("{}", summary);
+}
+
+/// Write the `--native` ROM as an image and as a sketch
+fn write_native(image: &native::Image, file: &str, rom_file: Option<String>, ino_file: Option<String>) {
+    println!("Compiled: {} bytes of machine code", image.rom.len());
     if let Some(out) = rom_file {
-        let rom = z80::generate_rom(&module);
-        let mut file = fs::File::create(&out).unwrap_or_else(|e| {
-            eprintln!("Error creating {}: {}", out, e);
-            process::exit(1);
+        write_output(&out, &image.rom);
+        println!("Wrote {} bytes ROM to {}", image.rom.len(), out);
+    }
+    if let Some(out) = ino_file {
+        let sketch = carray::arduino_sketch(file, &image.rom, 0);
+        write_output(&out, sketch.as_bytes());
+        println!("Wrote {} byte ROM array to {}", image.rom.len(), out);
+    }
+}
+
+/// Run a `--native` ROM on the emulator
+fn run_native(image: &native::Image, file: &str, max_cycles: Option<u64>) {
+    let mut machine = z80emu::Machine::new(&image.rom, z80emu::Console::stdio());
+    let exit = machine.run(max_cycles);
+    machine.io.flush();
+    match exit {
+        z80emu::Exit::Halted if machine.cpu.pc == image.error_pc => {
+            // The division's call left its return address on top
+            let at = image.error_line(machine.read16(machine.cpu.sp)).map_or_else(String::new, |line| format!(" at line {}", line));
+            eprintln!("{}: Runtime error: Illegal division by zero{}", file, at);
+            exit_with(ErrorKind::Runtime);
+        }
+        // Native programs don't read the console
+        z80emu::Exit::Halted | z80emu::Exit::InputExhausted => {}
+        z80emu::Exit::CycleLimit => {
+            eprintln!("Stopped after {} T-states (cycle limit)", machine.cpu.cycles);
+            exit_with(ErrorKind::Runtime);
+        }
+    }
+}
+
+/// Measure the program like `cycles::measure`, writing its trace to `path`
+fn trace_to_file(
+    module: &bytecode::Module,
+    options: &z80::RomOptions,
+    input: &[u8],
+    max_cycles: u64,
+    path: &str,
+) -> cycles::CycleReport {
+    let file = fs::File::create(path).unwrap_or_else(|e| {
+        eprintln!("Error creating {}: {}", path, e);
+        exit_with(ErrorKind::Io);
+    });
+    let mut out = std::io::BufWriter::new(file);
+    let mut profiler = cycles::Profiler::new(module);
+    let mut written = Ok(());
+    let exit = cycles::trace(module, options, input, max_cycles, |step| {
+        profiler.record(step);
+        if written.is_ok() {
+            written = cycles::write_step(&mut out, step);
+        }
+    });
+    written
+        .and_then(|_| cycles::write_exit(&mut out, exit))
+        .and_then(|_| out.flush())
+        .unwrap_or_else(|e| {
+            eprintln!("Error writing {}: {}", path, e);
+            exit_with(ErrorKind::Io);
         });
-        file.write_all(&rom).unwrap_or_else(|e| {
-            eprintln!("Error writing {}: {}", out, e);
-            process::exit(1);
+    profiler.report(exit)
+}
+
+/// Run `module` as a ROM on the emulator with `args` in its parameter
+/// block, then with `coverage`, its source, report the lines that ran
+fn run_rom(
+    module: &bytecode::Module,
+    file: &str,
+    options: &z80::RomOptions,
+    max_cycles: Option<u64>,
+    files: Option<&std::path::Path>,
+    args: &[String],
+    coverage: Option<&str>,
+) {
+    let console = z80emu::Console::stdio().with_irq(options.irq_input);
+    let console = console.with_error_port(options.stderr_port().unwrap_or(z80::PORT_ERROR));
+    let mut console = console.with_trace_port(options.trace_port.unwrap_or(z80::PORT_TRACE));
+    if let Some(dir) = files {
+        console = console.with_files(storage::HostDir::new(dir));
+    }
+    let mut machine = z80emu::Machine::new(&z80::generate_rom(module, options), console);
+    let layout = options.target.layout();
+    let block = z80::args_block(layout.args(), args).unwrap_or_else(|e| {
+        eprintln!("--args: {}", e);
+        exit_with(ErrorKind::Usage);
+    });
+    for (i, &b) in block.iter().enumerate() {
+        machine.write(layout.args() + i as u16, b);
+    }
+    let exit = machine.run(max_cycles);
+    machine.io.flush();
+    if let Some(source) = coverage {
+        let counters = coverage::from_memory(&machine.mem, layout.counters());
+        eprint!("{}", coverage::render(&coverage::lines(module, &counters), Some(source)));
+    }
+    if options.trace_port.is_some() {
+        eprint!("{}", rom_trace(machine.io.trace(), options.trace_stack));
+    }
+    if options.mem_stats {
+        let stack_low = machine.read16(layout.stack_low());
+        let heap = machine.read16(layout.heap_ptr());
+        eprintln!(
+            "Memory: VM stack {} bytes (down to 0x{:04X}), heap {} bytes (up to 0x{:04X})",
+            layout.vm_stack.wrapping_sub(stack_low),
+            stack_low,
+            heap.wrapping_sub(layout.heap_base),
+            heap
+        );
+    }
+
+    match exit {
+        z80emu::Exit::Halted => match z80::halt(module, &layout, &machine) {
+            z80::Halt::Ended => {}
+            // The runtime has printed why it refused to start
+            z80::Halt::BootFailed => exit_with(ErrorKind::Runtime),
+            z80::Halt::Error { pc, message } => {
+                eprintln!("{}{}", debugger::error_location(module, file, pc), message);
+                exit_with(ErrorKind::Runtime);
+            }
+        },
+        // The shell reads lines until stdin ends
+        z80emu::Exit::InputExhausted if options.shell => println!(),
+        z80emu::Exit::InputExhausted => {
+            eprintln!("Program is waiting for input after end of stdin");
+        }
+        z80emu::Exit::CycleLimit => {
+            eprintln!("Stopped after {} T-states (cycle limit)", machine.cpu.cycles);
+            exit_with(ErrorKind::Runtime);
+        }
+    }
+}
+
+/// The strings, subs and disassembled code of `module`, as `--bytecode`
+/// prints them
+fn print_module(module: &bytecode::Module) {
+    println!("String constants:");
+    for (i, s) in module.strings.iter().enumerate() {
+        println!("  [{}] {:?}", i, s);
+    }
+    println!("\nSubroutines:");
+    for (name, addr, params) in &module.subs {
+        println!("  {} @ 0x{:04X} ({} params)", name, addr, params);
+    }
+    println!("\nBytecode ({} bytes):", module.code.len());
+    print!("{}", bytecode::disassemble(&module.code));
+}
+
+/// `microperl disasm`: print the bytecode of files built earlier, a
+/// library from --mpb, an image from -o, or a ROM with the image in it
+fn disasm_files(files: &[String]) {
+    if files.is_empty() {
+        eprintln!("disasm needs a .mpb, bytecode or ROM file");
+        exit_with(ErrorKind::Usage);
+    }
+    for (i, file) in files.iter().enumerate() {
+        let bytes = fs::read(file).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", file, e);
+            exit_with(ErrorKind::Io);
         });
-        println!("Wrote {} bytes ROM to {} (runtime: {}B, bytecode at 0x1000)",
-                 rom.len(), out, 0x1000);
+        if files.len() > 1 {
+            println!("{}{}:", if i > 0 { "\n" } else { "" }, file);
+        }
+        let module = if bytes.starts_with(b"MPB") {
+            linker::read(&bytes).unwrap_or_else(|e| {
+                eprintln!("{}: {}", file, e);
+                exit_with(ErrorKind::Io);
+            })
+        } else {
+            match bytecode::Module::find_image(&bytes) {
+                Some((0, module)) => module,
+                Some((at, module)) => {
+                    println!("Bytecode image at offset 0x{:04X}\n", at);
+                    module
+                }
+                None => {
+                    eprintln!("{}: no MicroPerl library or bytecode image in it", file);
+                    exit_with(ErrorKind::Io);
+                }
+            }
+        };
+        print_module(&module);
     }
 }
 
-fn disassemble(code: &[u8]) {
-    let mut pc = 0;
-    while pc < code.len() {
-        let op = Op::from_byte(code[pc]);
-        let size = op.size();
+/// `microperl patch`: write the patch from one ROM image to another, and
+/// with `--applier` the Z80 routine that applies it on the target
+fn patch_files(args: &[String]) {
+    let mut files = Vec::new();
+    let (mut output, mut applier) = (None, None);
+    let mut options = z80::RomOptions::default();
+    let mut org = None;
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match args[i].as_str() {
+            "-o" => output = value.cloned(),
+            "--applier" => applier = value.cloned(),
+            "--target" => match value.and_then(|name| z80::Target::from_name(name)) {
+                Some(target) => options.target = target,
+                None => {
+                    eprintln!("--target requires retroshield, rc2014-acia or rc2014-sio");
+                    exit_with(ErrorKind::Usage);
+                }
+            },
+            "--org" => match value.and_then(|n| parse_number(n)).and_then(|n| u16::try_from(n).ok()) {
+                Some(n) => org = Some(n),
+                None => {
+                    eprintln!("--org requires an address");
+                    exit_with(ErrorKind::Usage);
+                }
+            },
+            arg if arg.starts_with('-') => {
+                eprintln!("Unknown patch option: {}", arg);
+                exit_with(ErrorKind::Usage);
+            }
+            file => {
+                files.push(file.to_string());
+                i += 1;
+                continue;
+            }
+        }
+        i += 2;
+    }
+    let ([old, new], Some(output)) = (files.as_slice(), output) else {
+        eprintln!("patch needs the old and new images and -o <file>");
+        exit_with(ErrorKind::Usage);
+    };
+
+    let read = |path: &String| fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", path, e);
+        exit_with(ErrorKind::Io);
+    });
+    let (old, new) = (read(old), read(new));
+    let diff = patch::diff(&old, &new).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit_with(ErrorKind::Usage);
+    });
+    write_output(&output, &diff);
+    println!("Wrote {} byte patch for the {} byte image to {}", diff.len(), new.len(), output);
 
-        print!("  {:04X}: {:?}", pc, op);
+    // The heap is free while the program isn't running
+    if let Some(path) = applier {
+        let l = options.target.layout();
+        let org = org.unwrap_or(l.heap_base);
+        let code = patch::applier(&options, org, l.runtime_org).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit_with(ErrorKind::Usage);
+        });
+        write_output(&path, &code);
+        println!("Wrote {} byte applier to {} (call 0x{:04X})", code.len(), path, org);
+    }
+}
+
+/// `microperl fmt`: print each file reformatted, rewrite it in place with
+/// `-w`, or with `--check` list the files that would change
+fn format_files(args: &[String]) {
+    let write = args.iter().any(|a| a == "-w");
+    let check = args.iter().any(|a| a == "--check");
+    let files: Vec<&String> = args.iter().filter(|a| !a.starts_with('-') || *a == "-").collect();
+    if let Some(flag) = args.iter().find(|a| a.starts_with('-') && *a != "-" && *a != "-w" && *a != "--check") {
+        eprintln!("Unknown fmt option: {}", flag);
+        exit_with(ErrorKind::Usage);
+    }
+    if files.is_empty() {
+        eprintln!("fmt needs a file (- for stdin)");
+        exit_with(ErrorKind::Usage);
+    }
 
-        match size {
-            2 if pc + 1 < code.len() => {
-                print!(" 0x{:02X}", code[pc + 1]);
+    let mut failed = None;
+    for file in files {
+        let source = if file == "-" {
+            String::from_utf8_lossy(&read_stdin()).to_string()
+        } else {
+            fs::read_to_string(file).unwrap_or_else(|e| {
+                eprintln!("Error reading {}: {}", file, e);
+                exit_with(ErrorKind::Io);
+            })
+        };
+        let formatted = match printer::format(&source) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failed = Some(ErrorKind::Parse);
+                continue;
             }
-            3 if pc + 2 < code.len() => {
-                let addr = code[pc + 1] as u16 | ((code[pc + 2] as u16) << 8);
-                print!(" 0x{:04X}", addr);
+        };
+        if check {
+            if formatted != source {
+                println!("{}", file);
+                failed = failed.or(Some(ErrorKind::Verify));
             }
-            _ => {}
+        } else if write && file != "-" {
+            if formatted != source {
+                write_output(file, formatted.as_bytes());
+            }
+        } else {
+            print!("{}", formatted);
         }
-        println!();
+    }
+    if let Some(kind) = failed {
+        exit_with(kind);
+    }
+}
 
-        pc += size;
+/// How `fail` reports a diagnostic
+#[derive(Clone, Copy)]
+struct Report {
+    json: bool,
+    check_only: bool,
+    color: bool,
+}
+
+/// Report `diagnostic` for `file`, whose text is `source`, and exit. Text
+/// goes out as `file:line: message` for `-c`, and rendered with the source
+/// line otherwise.
+fn fail(diagnostic: Diagnostic, file: &str, source: &str, report: Report) -> ! {
+    if report.json {
+        eprintln!("{}", kz80_microperl::diagnostics_json(file, std::slice::from_ref(&diagnostic)));
+    } else if report.check_only {
+        match diagnostic.line {
+            Some(line) => eprintln!("{}:{}: {}", file, line, diagnostic.message),
+            None => eprintln!("{}: {}", file, diagnostic.message),
+        }
+    } else {
+        eprint!("{}", render::render(&diagnostic, file, source, report.color));
     }
+    exit_with(diagnostic.kind());
 }
 
-fn generate_binary(module: &bytecode::Module) -> Vec<u8> {
-    let mut binary = Vec::new();
+/// Report the warnings in `diagnostics` as `fail` would, and carry on
+fn warn(diagnostics: &[Diagnostic], file: &str, source: &str, report: Report) {
+    if diagnostics.is_empty() {
+        return;
+    }
+    if report.json {
+        eprintln!("{}", kz80_microperl::diagnostics_json(file, diagnostics));
+        return;
+    }
+    for diagnostic in diagnostics {
+        match (report.check_only, diagnostic.line) {
+            (true, Some(line)) => eprintln!("{}:{}: warning: {}", file, line, diagnostic.message),
+            (true, None) => eprintln!("{}: warning: {}", file, diagnostic.message),
+            (false, _) => eprint!("{}", render::render(diagnostic, file, source, report.color)),
+        }
+    }
+}
 
-    // Header: "MPL\x01" (MicroPerl v1)
-    binary.extend_from_slice(b"MPL\x01");
+/// Stop, as `-W error` asks, if there were `warnings`, which have been
+/// reported already
+fn fail_on_warnings(warnings: &[Diagnostic], file: &str, report: Report) {
+    if warnings.is_empty() {
+        return;
+    }
+    if !report.json {
+        eprintln!("{}: warnings are errors with -W error", file);
+    }
+    exit_with(ErrorKind::Compile);
+}
+
+/// Exit with the status for a failure of `kind`
+fn exit_with(kind: ErrorKind) -> ! {
+    process::exit(kind.exit_code())
+}
+
+/// Run `module` on the host VM, with the file natives served from `files`
+/// and `args` as @ARGV, then with `coverage`, its source, report the lines
+/// that ran
+fn run_vm_module(
+    module: &bytecode::Module,
+    file: &str,
+    max_steps: Option<u64>,
+    files: Option<&std::path::Path>,
+    args: &[String],
+    coverage: Option<&str>,
+) {
+    let mut console = z80emu::Console::stdio();
+    if let Some(dir) = files {
+        console = console.with_files(storage::HostDir::new(dir));
+    }
+    let mut vm = vm::Vm::new(module, console);
+    if let Err(e) = vm.set_args(args) {
+        eprintln!("--args: {}", e);
+        exit_with(ErrorKind::Usage);
+    }
+    let exit = vm.run(max_steps);
+    vm.io.flush();
+    if let Some(source) = coverage {
+        eprint!("{}", coverage::render(&coverage::lines(module, &vm.counters), Some(source)));
+    }
+
+    match exit {
+        Ok(vm::Exit::Halted) => {}
+        Ok(vm::Exit::InputExhausted) => {
+            eprintln!("Program is waiting for input after end of stdin");
+        }
+        Ok(vm::Exit::StepLimit) => {
+            eprintln!("Stopped after {} instructions (step limit)", vm.steps);
+            exit_with(ErrorKind::Runtime);
+        }
+        Err(e) => {
+            // The failed instruction ends just before PC
+            let at = debugger::error_location(module, file, vm.pc.wrapping_sub(1));
+            eprintln!("{}Runtime error: {}", at, e);
+            exit_with(ErrorKind::Runtime);
+        }
+    }
+}
+
+fn debug_module(mut session: debugger::Debugger) {
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut shown = 0;
+
+    if let Some(line) = session.line() {
+        println!("Stopped at {}", session.location(line));
+    }
+    loop {
+        print!("(mdb) ");
+        let _ = std::io::stdout().flush();
+        let Some(Ok(command)) = lines.next() else {
+            println!();
+            return;
+        };
+        let mut words = command.split_whitespace();
+        let stop = match (words.next(), words.next()) {
+            (Some("s" | "step"), None) => session.step(),
+            (Some("c" | "continue"), None) => session.resume(),
+            (Some("b" | "break"), Some(spec)) => {
+                match session.set_breakpoint(spec) {
+                    Ok(line) => println!("Breakpoint at {}", session.location(line)),
+                    Err(e) => println!("{}", e),
+                }
+                continue;
+            }
+            (Some("d" | "delete"), Some(spec)) => {
+                if let Err(e) = session.clear_breakpoint(spec) {
+                    println!("{}", e);
+                }
+                continue;
+            }
+            (Some("watch"), Some(var)) => {
+                match session.watch(var) {
+                    Ok(()) => println!("Watching {}", var),
+                    Err(e) => println!("{}", e),
+                }
+                continue;
+            }
+            (Some("unwatch"), Some(var)) => {
+                if let Err(e) = session.unwatch(var) {
+                    println!("{}", e);
+                }
+                continue;
+            }
+            (Some("i" | "info"), None) => {
+                for line in session.breakpoints() {
+                    println!("  {}", session.location(line));
+                }
+                for var in session.watchpoints() {
+                    println!("  watch {}", var);
+                }
+                continue;
+            }
+            (Some("w" | "where"), None) => {
+                match session.line() {
+                    Some(line) => println!("At {}", session.location(line)),
+                    None => println!("No source line"),
+                }
+                continue;
+            }
+            (Some("q" | "quit"), None) => return,
+            (None, _) => continue,
+            _ => {
+                println!("Commands: step, continue, break <file:line>, delete <file:line>, watch <var>, unwatch <var>, info, where, quit");
+                continue;
+            }
+        };
+
+        // Show what the program printed since the last stop
+        let output = &session.vm.io.output()[shown..];
+        if !output.is_empty() {
+            let _ = std::io::stdout().write_all(output);
+            if !output.ends_with(b"\n") {
+                println!();
+            }
+        }
+        shown = session.vm.io.output().len();
+
+        match stop {
+            debugger::Stop::Step(line) => println!("Stopped at {}", session.location(line)),
+            debugger::Stop::Breakpoint(line) => println!("Breakpoint at {}", session.location(line)),
+            debugger::Stop::Exited(vm::Exit::InputExhausted) => {
+                println!("Program is waiting for input after the end of --input");
+            }
+            debugger::Stop::Exited(_) => println!("Program exited"),
+            debugger::Stop::Watch { name, value, pc, line } => match line {
+                Some(line) => println!("{} = {} at 0x{:04X}, {}", name, value, pc, session.location(line)),
+                None => println!("{} = {} at 0x{:04X}", name, value, pc),
+            },
+            debugger::Stop::Error { line, message } => match line {
+                Some(line) => println!("Runtime error at {}\n  {}", session.location(line), message),
+                None => println!("Runtime error: {}", message),
+            },
+        }
+    }
+}
+
+fn run_repl(mut session: repl::Repl) {
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut entry = String::new();
 
-    // String table offset (2 bytes)
-    // Header: magic(4) + strtab_offset(2) + code_len(2) + entry(2) = 10 bytes
-    let code_start = 10u16; // Header size
-    let string_table_offset = code_start + module.code.len() as u16;
-    binary.push(string_table_offset as u8);
-    binary.push((string_table_offset >> 8) as u8);
+    loop {
+        print!("{}", if entry.is_empty() { "mpl> " } else { "...> " });
+        let _ = std::io::stdout().flush();
+
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            return;
+        };
+        // A blank line ends an entry even if brackets are still open
+        let blank = line.trim().is_empty();
+        entry.push_str(&line);
+        entry.push('\n');
+        if !blank && repl::needs_more(&entry) {
+            continue;
+        }
 
-    // Code length (2 bytes)
-    binary.push(module.code.len() as u8);
-    binary.push((module.code.len() >> 8) as u8);
+        match session.eval(&entry) {
+            Ok(out) => {
+                print!("{}", out);
+                if !out.ends_with('\n') && !out.is_empty() {
+                    println!();
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+        entry.clear();
+    }
+}
 
-    // Entry point (2 bytes)
-    binary.push(module.entry as u8);
-    binary.push((module.entry >> 8) as u8);
+fn crosscheck_module(
+    module: &bytecode::Module,
+    options: &z80::RomOptions,
+    max_steps: Option<u64>,
+    max_cycles: Option<u64>,
+) {
+    // Both sides get the same input, so read all of it up front, and only
+    // when the program reads any, so stdin is left alone otherwise
+    let input = if module.reads_input() { read_stdin() } else { Vec::new() };
 
-    // Bytecode
-    binary.extend_from_slice(&module.code);
+    let mut limits = crosscheck::Limits::default();
+    if let Some(n) = max_steps {
+        limits.max_steps = n;
+    }
+    if let Some(n) = max_cycles {
+        limits.max_cycles = n;
+    }
 
-    // String table
-    binary.push(module.strings.len() as u8);
-    for s in &module.strings {
-        binary.push(s.len() as u8);
-        binary.extend_from_slice(s.as_bytes());
+    let report = crosscheck::crosscheck(module, options, &input, limits);
+    if report.agrees() {
+        println!("crosscheck: VM and Z80 agree ({} bytes of output, {:?})",
+                 report.vm.output.len(), report.vm.stop);
+        println!("  VM: {} instructions, Z80: {} T-states",
+                 report.vm.executed, report.z80.executed);
+    } else {
+        println!("crosscheck: VM and Z80 diverge");
+        for d in &report.divergences {
+            println!("  {}", d);
+        }
+        exit_with(ErrorKind::Verify);
     }
+}
 
-    binary
+fn read_stdin() -> Vec<u8> {
+    let mut input = Vec::new();
+    if let Err(e) = std::io::stdin().read_to_end(&mut input) {
+        eprintln!("Error reading stdin: {}", e);
+        exit_with(ErrorKind::Io);
+    }
+    input
 }
@@ -0,0 +1,1304 @@
+//! Z80 code for subs marked `:native`, and for whole programs
+//!
+//! A native sub compiles to bytecode as usual, which the host VM runs, and
+//! again to machine code when a ROM image is built. Its bytecode starts
+//! with a Native instruction whose operand the image fills in with the
+//! address of the machine code, and the runtime calls that instead.
+//!
+//! Native code works on integers: parameters, `my` variables and integer
+//! constants, arithmetic (wrapping, even in checked mode), comparisons, the
+//! control statements, and calls to other native subs. Values are kept in
+//! HL. Each call has a frame on the Z80 stack addressed from IX.
+//!
+//! `--native` compiles a whole program this way, with no bytecode or VM at
+//! all. It adds strings and printing from a small support library, and
+//! knows at compile time whether each value is a number or a string.
+//! Top-level variables live at fixed addresses.
+
+use std::collections::HashMap;
+
+use crate::ast::{Expr, Stmt};
+#[cfg(feature = "z80-backend")]
+use crate::ast::{BinOp, Handle, Program, UnaryOp};
+#[cfg(feature = "z80-backend")]
+use crate::asm::{Asm, Cond, Label, Reg16, Reg8, Rot, StackReg};
+#[cfg(feature = "z80-backend")]
+use crate::z80::RomOptions;
+
+/// A sub to compile to native code
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeSub {
+    pub name: String,
+    /// Bytecode address of its Native instruction
+    pub addr: u16,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+    /// Integer and string constants in scope where the sub was declared
+    pub constants: HashMap<String, Expr>,
+}
+
+/// Most parameters, and most `my` variables, a native sub can have: each
+/// must be in reach of an IX displacement
+pub const MAX_VARS: usize = 60;
+
+/// Most top-level variables a `--native` program can have
+pub const MAX_STATICS: usize = 256;
+
+/// Check that `sub` keeps to what native code can do, given the other
+/// native subs by name and parameter count
+#[cfg(feature = "z80-backend")]
+pub fn check(sub: &NativeSub, natives: &HashMap<String, usize>) -> Result<(), String> {
+    let mut subs = vec![sub.clone()];
+    for (name, &params) in natives {
+        if *name != sub.name {
+            let params = (0..params).map(|i| format!("_{}", i)).collect();
+            subs.push(NativeSub { name: name.clone(), addr: 0, params, body: Vec::new(), constants: HashMap::new() });
+        }
+    }
+    generate(&subs, 0, 0, 0).map(|_| ())
+}
+
+/// Machine code for `subs` to run at `org`, and the address of each sub's
+/// entry point for the Native instruction. An entry point takes the
+/// arguments from the VM stack, whose pointer is at `vm_sp`, and returns
+/// the result in HL and the arguments' size in bytes in BC. Division by
+/// zero jumps to `error`.
+#[cfg(feature = "z80-backend")]
+pub fn generate(subs: &[NativeSub], org: u16, vm_sp: u16, error: u16) -> Result<(Vec<u8>, Vec<u16>), String> {
+    let mut a = Asm::new(org);
+    let helpers = Helpers::new(&mut a);
+    let labels: HashMap<&str, (Label, usize)> =
+        subs.iter().map(|sub| (sub.name.as_str(), (a.label(&sub.name), sub.params.len()))).collect();
+
+    let mut entries = Vec::new();
+    let no_lines = HashMap::new();
+    let mut no_literals = Vec::new();
+    for sub in subs {
+        // Entry from the bytecode: the arguments are under the return
+        // address and frame pointer that Call pushed, the last on top
+        let n = sub.params.len();
+        entries.push(a.here_label("native_entry"));
+        for i in 0..n {
+            a.ld_from(Reg16::HL, vm_sp);
+            a.ld_nn(Reg16::DE, (4 + 2 * (n - 1 - i)) as u16);
+            a.add_hl(Reg16::DE);
+            a.ld(Reg8::E, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::D, Reg8::HLInd);
+            a.push(StackReg::DE);
+        }
+        a.call(labels[sub.name.as_str()].0);
+        for _ in 0..n {
+            a.pop(StackReg::BC);
+        }
+        // The bytes of arguments for the Native instruction to drop
+        a.ld_nn(Reg16::BC, 2 * n as u16);
+        a.ret();
+
+        let mut gen = Gen::new(&mut a, &labels, &helpers, None, &sub.name, &sub.constants, &no_lines, &mut no_literals);
+        gen.sub(&sub.params, &sub.body, HashMap::new())?;
+    }
+    emit_helpers(&mut a, &helpers, |a| a.jp_addr(error));
+
+    let entries = entries.into_iter().map(|label| a.addr(label).unwrap()).collect();
+    Ok((a.finish(), entries))
+}
+
+/// A whole program compiled to machine code
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub rom: Vec<u8>,
+    /// Where the PC stops when a run-time error has halted the program
+    pub error_pc: u16,
+    /// The return address of each division's call and the line of its
+    /// statement
+    pub divisions: Vec<(u16, usize)>,
+}
+
+impl Image {
+    /// The line of the division that halted the program, from the return
+    /// address left on top of the Z80 stack
+    pub fn error_line(&self, return_address: u16) -> Option<usize> {
+        self.divisions.iter().find(|&&(address, _)| address == return_address).map(|&(_, line)| line)
+    }
+}
+
+/// Why a program can't be compiled to machine code
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramError {
+    /// Source line of the statement, when there is one
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// Compile `program` to a ROM for a bare-metal target, with `constants`
+/// from the bytecode compiler. It runs from reset, prints through the
+/// target's console and halts at the end.
+#[cfg(feature = "z80-backend")]
+pub fn program(program: &Program, constants: &HashMap<String, Expr>, options: &RomOptions) -> Result<Image, ProgramError> {
+    let fail = |message: String| ProgramError { line: None, message };
+    if options.target.hosted() {
+        return Err(fail("--native needs a bare-metal target (retroshield, rc2014-acia or rc2014-sio)".into()));
+    }
+    let l = options.target.layout();
+    let console = options.target.console();
+    let mut a = Asm::new(l.runtime_org);
+    let helpers = Helpers::new(&mut a);
+    let support = Support {
+        print_str: a.label("print_str"),
+        print_num: a.label("print_num"),
+        newline: a.label("newline"),
+        num_str: a.label("num_str"),
+        concat: a.label("concat"),
+        strcmp: a.label("strcmp"),
+    };
+    let putc = a.label("putc");
+    let error = a.label("native_error");
+
+    let mut subs = Vec::new();
+    collect_subs(&program.statements, &mut subs);
+    let labels: HashMap<&str, (Label, usize)> =
+        subs.iter().map(|&(name, params, _)| (name, (a.label(name), params.len()))).collect();
+    let mut lines = HashMap::new();
+    index_lines(&program.statements, &program.lines, &mut 0, &mut lines);
+    let mut literals = Vec::new();
+    let mut divisions = Vec::new();
+
+    a.ld_nn(Reg16::SP, l.stack_top);
+    a.di();
+    console.emit_init(&mut a, false);
+    // With no VM, its stack holds the heap pointer and the variables, out
+    // of the heap's way
+    let heap_ptr = l.vm_stack;
+    a.ld_nn(Reg16::HL, l.heap_base);
+    a.ld_to(heap_ptr, Reg16::HL);
+
+    let mut gen = Gen::new(&mut a, &labels, &helpers, Some(&support), "main", constants, &lines, &mut literals);
+    gen.statics = Some((heap_ptr + 2, heap_ptr + 2 + 2 * MAX_STATICS as u16));
+    let globals = gen.main(&program.statements).map_err(|message| ProgramError { line: gen.line, message })?;
+    divisions.append(&mut gen.divisions);
+    a.di();
+    a.halt();
+
+    for &(name, params, body) in &subs {
+        let mut gen = Gen::new(&mut a, &labels, &helpers, Some(&support), name, constants, &lines, &mut literals);
+        gen.sub(params, body, globals.clone()).map_err(|message| ProgramError { line: gen.line, message })?;
+        divisions.append(&mut gen.divisions);
+    }
+
+    emit_helpers(&mut a, &helpers, |a| a.jp(error));
+    emit_support(&mut a, &support, &helpers, heap_ptr, |a| console.emit_write(a, putc));
+    console.emit_putc(&mut a, putc);
+    // Division by zero, the only run-time error, halts as the runtime does
+    a.bind(error);
+    a.di();
+    a.halt();
+    let error_pc = a.here();
+    for (label, text) in &literals {
+        a.bind(*label);
+        a.defb(&string_bytes(text));
+    }
+
+    let rom = a.finish();
+    let room = l.heap_base.min(l.vars) - l.runtime_org;
+    if rom.len() > room as usize {
+        return Err(fail(format!("The program is {} bytes of machine code, over the {} bytes of ROM", rom.len(), room)));
+    }
+    Ok(Image { rom, error_pc, divisions })
+}
+
+/// A string as the support library stores it: length, then the bytes, cut
+/// to 255 of them
+#[cfg(feature = "z80-backend")]
+fn string_bytes(text: &str) -> Vec<u8> {
+    let bytes = &text.as_bytes()[..text.len().min(u8::MAX as usize)];
+    let mut out = vec![bytes.len() as u8];
+    out.extend_from_slice(bytes);
+    out
+}
+
+/// Every sub in `stmts`, by name, parameters and body
+#[cfg(feature = "z80-backend")]
+fn collect_subs<'a>(stmts: &'a [Stmt], subs: &mut Vec<(&'a str, &'a [String], &'a [Stmt])>) {
+    for stmt in stmts {
+        if let Stmt::Sub { name, params, body, .. } = stmt {
+            subs.push((name, params, body));
+        }
+    }
+}
+
+/// The source line of each statement, walking them in the parser's order
+#[cfg(feature = "z80-backend")]
+fn index_lines(stmts: &[Stmt], lines: &[usize], next: &mut usize, map: &mut HashMap<*const Stmt, usize>) {
+    for stmt in stmts {
+        if let Some(&line) = lines.get(*next) {
+            map.insert(stmt as *const Stmt, line);
+        }
+        *next += 1;
+        let mut nested = |stmts: &[Stmt]| index_lines(stmts, lines, next, map);
+        match stmt {
+            Stmt::If { then_block, elsif_blocks, else_block, .. } => {
+                nested(then_block);
+                for (_, block) in elsif_blocks {
+                    *next += 1;
+                    index_lines(block, lines, next, map);
+                }
+                if let Some(block) = else_block {
+                    index_lines(block, lines, next, map);
+                }
+            }
+            Stmt::Unless { then_block, else_block, .. } => {
+                nested(then_block);
+                if let Some(block) = else_block {
+                    nested(block);
+                }
+            }
+            Stmt::For { init, body, .. } => {
+                if let Some(init) = init {
+                    nested(std::slice::from_ref(init));
+                }
+                nested(body);
+            }
+            Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Foreach { body, .. } => nested(body),
+            Stmt::Sub { body, .. } | Stmt::Block(body) | Stmt::Begin(body) => nested(body),
+            _ => {}
+        }
+    }
+}
+
+/// Routines shared by all native code
+#[cfg(feature = "z80-backend")]
+struct Helpers {
+    /// HL = HL * DE
+    mul: Label,
+    /// HL = HL / DE, DE = HL % DE, unsigned
+    divmod: Label,
+    /// HL = HL << DE
+    shl: Label,
+    /// HL = HL >> DE
+    shr: Label,
+}
+
+#[cfg(feature = "z80-backend")]
+impl Helpers {
+    fn new(a: &mut Asm) -> Self {
+        Helpers {
+            mul: a.label("native_mul"),
+            divmod: a.label("native_divmod"),
+            shl: a.label("native_shl"),
+            shr: a.label("native_shr"),
+        }
+    }
+}
+
+/// The support library of `--native` programs. Strings are a length byte
+/// and the bytes; new ones are bump-allocated from the heap.
+#[cfg(feature = "z80-backend")]
+struct Support {
+    /// Print the string at HL
+    print_str: Label,
+    /// Print HL as a signed number
+    print_num: Label,
+    newline: Label,
+    /// HL = a new string of the signed number HL
+    num_str: Label,
+    /// HL = a new string of the strings HL and DE joined, cut to 255 bytes
+    concat: Label,
+    /// HL = -1, 0 or 1 as the string HL sorts before, with or after DE
+    strcmp: Label,
+}
+
+/// What a value is, known when compiling
+#[cfg(feature = "z80-backend")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Str,
+}
+
+/// Where a variable lives
+#[cfg(feature = "z80-backend")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slot {
+    /// In the frame, by IX displacement
+    Frame(i8),
+    /// At a fixed address
+    Static(u16),
+}
+
+#[cfg(feature = "z80-backend")]
+type Scope = HashMap<String, (Slot, Ty)>;
+
+#[cfg(feature = "z80-backend")]
+struct Gen<'a> {
+    a: &'a mut Asm,
+    /// Every native sub's label and parameter count
+    subs: &'a HashMap<&'a str, (Label, usize)>,
+    helpers: &'a Helpers,
+    /// Strings and printing, for whole programs
+    support: Option<&'a Support>,
+    name: &'a str,
+    constants: &'a HashMap<String, Expr>,
+    /// Variables in scope, innermost last
+    scopes: Vec<Scope>,
+    /// `my` variables declared so far in the frame
+    locals: usize,
+    /// Next and end address for variables with fixed addresses, in the
+    /// main program
+    statics: Option<(u16, u16)>,
+    /// (next, last) targets of the enclosing loops
+    loops: Vec<(Label, Label)>,
+    /// The epilogue, with the result in HL
+    exit: Label,
+    /// Source lines of the statements, for errors
+    lines: &'a HashMap<*const Stmt, usize>,
+    line: Option<usize>,
+    /// String literals, placed after the code
+    literals: &'a mut Vec<(Label, String)>,
+    /// The return address and line of each division's call
+    divisions: Vec<(u16, usize)>,
+}
+
+#[cfg(feature = "z80-backend")]
+impl<'a> Gen<'a> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        a: &'a mut Asm,
+        subs: &'a HashMap<&'a str, (Label, usize)>,
+        helpers: &'a Helpers,
+        support: Option<&'a Support>,
+        name: &'a str,
+        constants: &'a HashMap<String, Expr>,
+        lines: &'a HashMap<*const Stmt, usize>,
+        literals: &'a mut Vec<(Label, String)>,
+    ) -> Self {
+        let exit = a.label("native_exit");
+        Gen {
+            a,
+            subs,
+            helpers,
+            support,
+            name,
+            constants,
+            scopes: Vec::new(),
+            locals: 0,
+            statics: None,
+            loops: Vec::new(),
+            exit,
+            lines,
+            line: None,
+            literals,
+            divisions: Vec::new(),
+        }
+    }
+
+    fn unsupported(&self, what: &str) -> String {
+        match self.support {
+            Some(_) => format!("--native can't compile {}", what),
+            None => format!("Sub {} is :native, which can't use {}", self.name, what),
+        }
+    }
+
+    /// The main program, returning its top-level variables
+    fn main(&mut self, body: &[Stmt]) -> Result<Scope, String> {
+        self.scopes.push(HashMap::new());
+        for stmt in body {
+            self.stmt(stmt)?;
+        }
+        Ok(self.scopes.pop().unwrap())
+    }
+
+    /// A sub that sees `globals`, returning a number in HL
+    fn sub(&mut self, params: &[String], body: &[Stmt], globals: Scope) -> Result<(), String> {
+        let locals = count_locals(body);
+        if params.len() > MAX_VARS || locals > MAX_VARS {
+            return Err(match self.support {
+                Some(_) => format!("Sub {} has more than {} parameters or my variables for --native", self.name, MAX_VARS),
+                None => format!("Sub {} is :native, which allows at most {} parameters and {} my variables",
+                                self.name, MAX_VARS, MAX_VARS),
+            });
+        }
+        // A native sub's frame mirrors the bytecode's, where FP+0, here
+        // IX+4, is the last argument, and whole programs push them the same
+        let n = params.len();
+        let params = params.iter().enumerate().map(|(i, p)| (p.clone(), (Slot::Frame((4 + 2 * (n - 1 - i)) as i8), Ty::Int)));
+        self.scopes.push(globals);
+        self.scopes.push(params.collect());
+
+        self.a.bind(self.subs[self.name].0);
+        self.a.push_ix();
+        self.a.ld_ix_nn(0);
+        self.a.add_ix_sp();
+        if locals > 0 {
+            self.a.ld_nn(Reg16::HL, (-2 * locals as i16) as u16);
+            self.a.add_hl(Reg16::SP);
+            self.a.ld_sp_hl();
+        }
+        self.block(body)?;
+        self.a.ld_nn(Reg16::HL, 0);
+        self.a.bind(self.exit);
+        self.a.ld_sp_ix();
+        self.a.pop_ix();
+        self.a.ret();
+        Ok(())
+    }
+
+    fn block(&mut self, body: &[Stmt]) -> Result<(), String> {
+        self.scopes.push(HashMap::new());
+        for stmt in body {
+            self.stmt(stmt)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<(), String> {
+        if let Some(&line) = self.lines.get(&(stmt as *const Stmt)) {
+            self.line = Some(line);
+        }
+        match stmt {
+            Stmt::Expr(expr) => {
+                self.expr(expr)?;
+            }
+            Stmt::My(vars, init) => self.declare(vars, init.as_ref())?,
+            Stmt::Our(vars, init) if self.statics.is_some() => self.declare(vars, init.as_ref())?,
+            Stmt::If { cond, then_block, elsif_blocks, else_block } => {
+                let end = self.a.label("native_endif");
+                for (cond, block) in std::iter::once((cond, then_block)).chain(elsif_blocks.iter().map(|(c, b)| (c, b))) {
+                    let next = self.a.label("native_else");
+                    self.condition(cond)?;
+                    self.a.jp_cc(Cond::Z, next);
+                    self.block(block)?;
+                    self.a.jp(end);
+                    self.a.bind(next);
+                }
+                if let Some(block) = else_block {
+                    self.block(block)?;
+                }
+                self.a.bind(end);
+            }
+            Stmt::Unless { cond, then_block, else_block } => {
+                let (other, end) = (self.a.label("native_else"), self.a.label("native_endif"));
+                self.condition(cond)?;
+                self.a.jp_cc(Cond::NZ, other);
+                self.block(then_block)?;
+                self.a.jp(end);
+                self.a.bind(other);
+                if let Some(block) = else_block {
+                    self.block(block)?;
+                }
+                self.a.bind(end);
+            }
+            Stmt::While { cond, body } => self.looped(None, Some((cond, Cond::Z)), None, body)?,
+            Stmt::Until { cond, body } => self.looped(None, Some((cond, Cond::NZ)), None, body)?,
+            Stmt::For { init, cond, step, body } => {
+                self.looped(init.as_deref(), cond.as_ref().map(|c| (c, Cond::Z)), step.as_ref(), body)?
+            }
+            Stmt::Block(body) => self.block(body)?,
+            Stmt::Return(_) if self.statics.is_some() => return Err(self.unsupported("return outside a sub")),
+            Stmt::Return(value) => {
+                match value {
+                    Some(value) => self.number(value, "returning a string")?,
+                    None => self.a.ld_nn(Reg16::HL, 0),
+                }
+                self.a.jp(self.exit);
+            }
+            Stmt::Last | Stmt::Next => {
+                let &(next, last) = self.loops.last().ok_or_else(|| self.unsupported("last or next outside a loop"))?;
+                self.a.jp(if matches!(stmt, Stmt::Last) { last } else { next });
+            }
+            Stmt::Print(handle, args) | Stmt::Say(handle, args) if self.support.is_some() => {
+                let support = self.support.unwrap();
+                if *handle == Some(Handle::Stderr) {
+                    return Err(self.unsupported("printing to STDERR"));
+                }
+                if args.is_empty() {
+                    return Err(self.unsupported("print without arguments"));
+                }
+                for arg in args {
+                    let ty = self.expr(arg)?;
+                    self.a.call(if ty == Ty::Str { support.print_str } else { support.print_num });
+                }
+                if matches!(stmt, Stmt::Say(..)) {
+                    self.a.call(support.newline);
+                }
+            }
+            // Compiled on their own, or already taken into account
+            Stmt::Sub { .. } | Stmt::Constant(..) | Stmt::Use(_) | Stmt::Package(_) if self.statics.is_some() => {}
+            Stmt::Print(..) | Stmt::Say(..) => return Err(self.unsupported("print")),
+            Stmt::Printf(..) => return Err(self.unsupported("printf")),
+            Stmt::Our(..) => return Err(self.unsupported("our variables")),
+            Stmt::Foreach { .. } => return Err(self.unsupported("foreach")),
+            Stmt::Begin(_) => return Err(self.unsupported("BEGIN blocks")),
+            Stmt::Assert(..) => return Err(self.unsupported("assert")),
+            _ => return Err(self.unsupported("this statement")),
+        }
+        Ok(())
+    }
+
+    /// `my $x = init`, in the frame or, in the main program, at a fixed
+    /// address
+    fn declare(&mut self, vars: &[String], init: Option<&Expr>) -> Result<(), String> {
+        let [var] = vars else {
+            return Err(self.unsupported("a list of variables"));
+        };
+        let ty = match init {
+            Some(init) => self.expr(init)?,
+            None => {
+                self.a.ld_nn(Reg16::HL, 0);
+                Ty::Int
+            }
+        };
+        let slot = match &mut self.statics {
+            Some((next, end)) => {
+                if *next >= *end {
+                    return Err(format!("More than {} top-level variables for --native", MAX_STATICS));
+                }
+                *next += 2;
+                Slot::Static(*next - 2)
+            }
+            None => {
+                self.locals += 1;
+                Slot::Frame(-2 * self.locals as i8)
+            }
+        };
+        self.store(slot);
+        self.scopes.last_mut().unwrap().insert(var.clone(), (slot, ty));
+        Ok(())
+    }
+
+    /// A loop that leaves when `cond` sets `exit_if`, with the variables
+    /// of `init` scoped to it
+    fn looped(&mut self, init: Option<&Stmt>, cond: Option<(&Expr, Cond)>, step: Option<&Expr>, body: &[Stmt]) -> Result<(), String> {
+        self.scopes.push(HashMap::new());
+        if let Some(init) = init {
+            self.stmt(init)?;
+        }
+        let top = self.a.here_label("native_loop");
+        let (next, last) = (self.a.label("native_next"), self.a.label("native_last"));
+        if let Some((cond, exit_if)) = cond {
+            self.condition(cond)?;
+            self.a.jp_cc(exit_if, last);
+        }
+        self.loops.push((next, last));
+        self.block(body)?;
+        self.loops.pop();
+        self.a.bind(next);
+        if let Some(step) = step {
+            self.expr(step)?;
+        }
+        self.a.jp(top);
+        self.a.bind(last);
+        self.scopes.pop();
+        Ok(())
+    }
+
+    /// Set Z if `cond` is false
+    fn condition(&mut self, cond: &Expr) -> Result<(), String> {
+        self.number(cond, "a string as a condition")?;
+        self.test();
+        Ok(())
+    }
+
+    /// HL = `expr`, which must be a number, or else `what` can't compile
+    fn number(&mut self, expr: &Expr, what: &str) -> Result<(), String> {
+        match self.expr(expr)? {
+            Ty::Int => Ok(()),
+            Ty::Str => Err(self.unsupported(what)),
+        }
+    }
+
+    /// HL = `expr` as a string, converting a number
+    fn string(&mut self, expr: &Expr) -> Result<(), String> {
+        if self.expr(expr)? == Ty::Int {
+            self.a.call(self.support.unwrap().num_str);
+        }
+        Ok(())
+    }
+
+    /// HL = the value of `expr`
+    fn expr(&mut self, expr: &Expr) -> Result<Ty, String> {
+        match expr {
+            Expr::Integer(n) => self.a.ld_nn(Reg16::HL, *n as u16),
+            Expr::String(text) if self.support.is_some() => {
+                let label = self.a.label("native_string");
+                self.literals.push((label, text.clone()));
+                self.a.ld_label(Reg16::HL, label);
+                return Ok(Ty::Str);
+            }
+            Expr::ScalarVar(name) => {
+                let (slot, ty) = self.var(name)?;
+                self.load(slot);
+                return Ok(ty);
+            }
+            Expr::BinOp(left, op, right) if self.support.is_some() && is_string_op(op) => {
+                let support = self.support.unwrap();
+                self.string(left)?;
+                self.a.push(StackReg::HL);
+                self.string(right)?;
+                self.a.ex_de_hl();
+                self.a.pop(StackReg::HL);
+                if *op == BinOp::Concat {
+                    self.a.call(support.concat);
+                    return Ok(Ty::Str);
+                }
+                self.a.call(support.strcmp);
+                match op {
+                    BinOp::StrEq | BinOp::StrNe => {
+                        self.test();
+                        self.flag(if *op == BinOp::StrEq { Cond::Z } else { Cond::NZ });
+                    }
+                    BinOp::StrLt | BinOp::StrGe => {
+                        self.a.ld(Reg8::A, Reg8::H);
+                        self.a.rlca();
+                        self.flag(if *op == BinOp::StrLt { Cond::C } else { Cond::NC });
+                    }
+                    BinOp::StrGt | BinOp::StrLe => {
+                        self.a.dec16(Reg16::HL);
+                        self.test();
+                        self.flag(if *op == BinOp::StrGt { Cond::Z } else { Cond::NZ });
+                    }
+                    _ => {}
+                }
+            }
+            Expr::BinOp(left, op, right) => {
+                self.number(left, "a string in arithmetic")?;
+                self.a.push(StackReg::HL);
+                self.number(right, "a string in arithmetic")?;
+                self.a.ex_de_hl();
+                self.a.pop(StackReg::HL);
+                self.binary(op)?;
+            }
+            Expr::UnaryOp(op, operand) => {
+                self.number(operand, "a string in arithmetic")?;
+                match op {
+                    UnaryOp::Neg => {
+                        self.a.ex_de_hl();
+                        self.a.ld_nn(Reg16::HL, 0);
+                        self.a.or(Reg8::A);
+                        self.a.sbc_hl(Reg16::DE);
+                    }
+                    UnaryOp::Not => {
+                        self.test();
+                        self.flag(Cond::Z);
+                    }
+                    UnaryOp::BitNot => {
+                        for r in [Reg8::H, Reg8::L] {
+                            self.a.ld(Reg8::A, r);
+                            self.a.cpl();
+                            self.a.ld(r, Reg8::A);
+                        }
+                    }
+                    UnaryOp::Ref => return Err(self.unsupported("references")),
+                }
+            }
+            Expr::PreIncrement(target) | Expr::PreDecrement(target) | Expr::PostIncrement(target) | Expr::PostDecrement(target) => {
+                let slot = self.target(target, Ty::Int)?;
+                let post = matches!(expr, Expr::PostIncrement(_) | Expr::PostDecrement(_));
+                self.load(slot);
+                if post {
+                    self.a.push(StackReg::HL);
+                }
+                if matches!(expr, Expr::PreIncrement(_) | Expr::PostIncrement(_)) {
+                    self.a.inc16(Reg16::HL);
+                } else {
+                    self.a.dec16(Reg16::HL);
+                }
+                self.store(slot);
+                if post {
+                    self.a.pop(StackReg::HL);
+                }
+            }
+            Expr::Assign(target, value) => {
+                let ty = self.expr(value)?;
+                let slot = self.target(target, ty)?;
+                self.store(slot);
+                return Ok(ty);
+            }
+            Expr::OpAssign(target, BinOp::Concat, value) if self.support.is_some() => {
+                let slot = self.target(target, Ty::Str)?;
+                self.load(slot);
+                self.a.push(StackReg::HL);
+                self.string(value)?;
+                self.a.ex_de_hl();
+                self.a.pop(StackReg::HL);
+                self.a.call(self.support.unwrap().concat);
+                self.store(slot);
+                return Ok(Ty::Str);
+            }
+            Expr::OpAssign(target, op, value) => {
+                let slot = self.target(target, Ty::Int)?;
+                self.load(slot);
+                self.a.push(StackReg::HL);
+                self.number(value, "a string in arithmetic")?;
+                self.a.ex_de_hl();
+                self.a.pop(StackReg::HL);
+                self.binary(op)?;
+                self.store(slot);
+            }
+            Expr::Ternary(cond, then, other) => {
+                let (else_label, end) = (self.a.label("native_else"), self.a.label("native_endif"));
+                self.condition(cond)?;
+                self.a.jp_cc(Cond::Z, else_label);
+                let ty = self.expr(then)?;
+                self.a.jp(end);
+                self.a.bind(else_label);
+                if self.expr(other)? != ty {
+                    return Err(self.unsupported("?: giving a string one way and a number the other"));
+                }
+                self.a.bind(end);
+                return Ok(ty);
+            }
+            Expr::Call(name, args) if args.is_empty() && self.constants.contains_key(name) => {
+                let constants = self.constants;
+                return self.expr(&constants[name]);
+            }
+            Expr::Call(name, args) => {
+                let Some(&(label, params)) = self.subs.get(name.as_str()) else {
+                    return Err(match self.support {
+                        Some(_) => self.unsupported(&format!("{}()", name)),
+                        None => self.unsupported(&format!("{}(), which is not a native sub", name)),
+                    });
+                };
+                if args.len() != params {
+                    return Err(format!("Sub {} takes {} but is called with {}", name, crate::compiler::arguments(params), args.len()));
+                }
+                for arg in args {
+                    self.number(arg, "passing a string to a sub")?;
+                    self.a.push(StackReg::HL);
+                }
+                self.a.call(label);
+                for _ in args {
+                    self.a.pop(StackReg::BC);
+                }
+            }
+            Expr::String(_) => return Err(self.unsupported("strings")),
+            Expr::Float(_) => return Err(self.unsupported("floating point numbers")),
+            Expr::ArrayVar(_) | Expr::ArrayIndex(..) | Expr::List(_) | Expr::Range(..) => {
+                return Err(self.unsupported("arrays"))
+            }
+            Expr::HashVar(_) | Expr::HashIndex(..) | Expr::HashSlice(..) | Expr::Hash(_) => return Err(self.unsupported("hashes")),
+            Expr::Match(..) | Expr::NotMatch(..) => return Err(self.unsupported("regular expressions")),
+            Expr::Ref(_) | Expr::Deref(_) => return Err(self.unsupported("references")),
+            _ => return Err(self.unsupported("this expression")),
+        }
+        Ok(Ty::Int)
+    }
+
+    /// HL = HL `op` DE
+    fn binary(&mut self, op: &BinOp) -> Result<(), String> {
+        let a = &mut *self.a;
+        match op {
+            BinOp::Add => a.add_hl(Reg16::DE),
+            BinOp::Sub => {
+                a.or(Reg8::A);
+                a.sbc_hl(Reg16::DE);
+            }
+            BinOp::Mul => a.call(self.helpers.mul),
+            BinOp::Div | BinOp::Mod => {
+                a.call(self.helpers.divmod);
+                if let Some(line) = self.line {
+                    self.divisions.push((a.here(), line));
+                }
+                if *op == BinOp::Mod {
+                    a.ex_de_hl();
+                }
+            }
+            BinOp::ShiftLeft => a.call(self.helpers.shl),
+            BinOp::ShiftRight => a.call(self.helpers.shr),
+            BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor => {
+                let alu = match op {
+                    BinOp::BitAnd => crate::asm::Alu::And,
+                    BinOp::BitOr => crate::asm::Alu::Or,
+                    _ => crate::asm::Alu::Xor,
+                };
+                for (r, other) in [(Reg8::H, Reg8::D), (Reg8::L, Reg8::E)] {
+                    a.ld(Reg8::A, r);
+                    a.alu(alu, other);
+                    a.ld(r, Reg8::A);
+                }
+            }
+            BinOp::Eq | BinOp::Ne => {
+                a.or(Reg8::A);
+                a.sbc_hl(Reg16::DE);
+                self.flag(if *op == BinOp::Eq { Cond::Z } else { Cond::NZ });
+            }
+            // Ordered by the sign of the 16-bit difference, as in the runtime
+            BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                if matches!(op, BinOp::Gt | BinOp::Le) {
+                    a.ex_de_hl();
+                }
+                a.or(Reg8::A);
+                a.sbc_hl(Reg16::DE);
+                a.ld(Reg8::A, Reg8::H);
+                a.rlca();
+                self.flag(if matches!(op, BinOp::Lt | BinOp::Gt) { Cond::C } else { Cond::NC });
+            }
+            BinOp::Cmp => {
+                let done = a.label("native_cmp");
+                a.or(Reg8::A);
+                a.sbc_hl(Reg16::DE);
+                a.jr_cc(Cond::Z, done);
+                a.ld(Reg8::A, Reg8::H);
+                a.rlca();
+                a.ld_nn(Reg16::HL, 1);
+                a.jr_cc(Cond::NC, done);
+                a.dec16(Reg16::HL);
+                a.dec16(Reg16::HL);
+                a.bind(done);
+            }
+            BinOp::And => {
+                let done = a.label("native_and");
+                a.ld(Reg8::A, Reg8::H);
+                a.or(Reg8::L);
+                a.ld_nn(Reg16::HL, 0);
+                a.jr_cc(Cond::Z, done);
+                a.ld(Reg8::A, Reg8::D);
+                a.or(Reg8::E);
+                a.jr_cc(Cond::Z, done);
+                a.inc(Reg8::L);
+                a.bind(done);
+            }
+            BinOp::Or => {
+                a.ld(Reg8::A, Reg8::H);
+                for r in [Reg8::L, Reg8::D, Reg8::E] {
+                    a.or(r);
+                }
+                self.flag(Cond::NZ);
+            }
+            BinOp::Pow => return Err(self.unsupported("**")),
+            BinOp::Repeat => return Err(self.unsupported("x")),
+            _ => return Err(self.unsupported("strings")),
+        }
+        Ok(())
+    }
+
+    /// HL = 1 if `cond` holds, else 0
+    fn flag(&mut self, cond: Cond) {
+        let skip = self.a.label("native_flag");
+        let unless = match cond {
+            Cond::Z => Cond::NZ,
+            Cond::NZ => Cond::Z,
+            Cond::C => Cond::NC,
+            _ => Cond::C,
+        };
+        self.a.ld_nn(Reg16::HL, 0);
+        self.a.jr_cc(unless, skip);
+        self.a.inc(Reg8::L);
+        self.a.bind(skip);
+    }
+
+    /// Set Z if HL is zero (false)
+    fn test(&mut self) {
+        self.a.ld(Reg8::A, Reg8::H);
+        self.a.or(Reg8::L);
+    }
+
+    /// Where variable `name` lives, and what it holds
+    fn var(&self, name: &str) -> Result<(Slot, Ty), String> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied()).ok_or_else(|| match self.support {
+            Some(_) => self.unsupported(&format!("${}, which isn't a my variable in scope", name)),
+            None => self.unsupported(&format!("${}: only its parameters and my variables", name)),
+        })
+    }
+
+    /// The variable assigned by `expr`, which must hold `ty`
+    fn target(&self, expr: &Expr, ty: Ty) -> Result<Slot, String> {
+        let Expr::ScalarVar(name) = expr else {
+            return Err(self.unsupported("this assignment"));
+        };
+        let (slot, held) = self.var(name)?;
+        if held != ty {
+            return Err(self.unsupported(&format!("${} holding both strings and numbers", name)));
+        }
+        Ok(slot)
+    }
+
+    fn load(&mut self, slot: Slot) {
+        match slot {
+            Slot::Frame(d) => {
+                self.a.ld_from_ix(Reg8::L, d);
+                self.a.ld_from_ix(Reg8::H, d + 1);
+            }
+            Slot::Static(addr) => self.a.ld_from(Reg16::HL, addr),
+        }
+    }
+
+    fn store(&mut self, slot: Slot) {
+        match slot {
+            Slot::Frame(d) => {
+                self.a.ld_to_ix(d, Reg8::L);
+                self.a.ld_to_ix(d + 1, Reg8::H);
+            }
+            Slot::Static(addr) => self.a.ld_to(addr, Reg16::HL),
+        }
+    }
+}
+
+#[cfg(feature = "z80-backend")]
+fn is_string_op(op: &BinOp) -> bool {
+    matches!(op, BinOp::Concat | BinOp::StrEq | BinOp::StrNe | BinOp::StrLt | BinOp::StrGt | BinOp::StrLe | BinOp::StrGe | BinOp::StrCmp)
+}
+
+/// `my` variables declared in `body`, nested blocks included
+#[cfg(feature = "z80-backend")]
+fn count_locals(body: &[Stmt]) -> usize {
+    body.iter()
+        .map(|stmt| match stmt {
+            Stmt::My(vars, _) => vars.len(),
+            Stmt::If { then_block, elsif_blocks, else_block, .. } => {
+                count_locals(then_block)
+                    + elsif_blocks.iter().map(|(_, b)| count_locals(b)).sum::<usize>()
+                    + else_block.as_deref().map_or(0, count_locals)
+            }
+            Stmt::Unless { then_block, else_block, .. } => {
+                count_locals(then_block) + else_block.as_deref().map_or(0, count_locals)
+            }
+            Stmt::For { init, body, .. } => init.as_deref().map_or(0, |s| count_locals(std::slice::from_ref(s))) + count_locals(body),
+            Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Block(body) => count_locals(body),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Emit the arithmetic helpers; `error` emits the jump taken on division
+/// by zero
+#[cfg(feature = "z80-backend")]
+fn emit_helpers(a: &mut Asm, helpers: &Helpers, error: impl FnOnce(&mut Asm)) {
+    // Shift and add, from the top bit of DE down
+    a.bind(helpers.mul);
+    a.ld(Reg8::B, Reg8::H);
+    a.ld(Reg8::C, Reg8::L);
+    a.ld_nn(Reg16::HL, 0);
+    a.ld_n(Reg8::A, 16);
+    let mul_loop = a.here_label("native_mul_loop");
+    let mul_skip = a.label("native_mul_skip");
+    a.add_hl(Reg16::HL);
+    a.ex_de_hl();
+    a.add_hl(Reg16::HL);
+    a.ex_de_hl();
+    a.jr_cc(Cond::NC, mul_skip);
+    a.add_hl(Reg16::BC);
+    a.bind(mul_skip);
+    a.dec(Reg8::A);
+    a.jr_cc(Cond::NZ, mul_loop);
+    a.ret();
+
+    emit_divmod(a, helpers.divmod, "native", error);
+
+    // Shifts by 16 or more give 0, as in the VM
+    for (label, name) in [(helpers.shl, "native_shl"), (helpers.shr, "native_shr")] {
+        a.bind(label);
+        let (zero, shift) = (a.label(&format!("{}_zero", name)), a.label(&format!("{}_loop", name)));
+        a.ld(Reg8::A, Reg8::D);
+        a.or(Reg8::A);
+        a.jr_cc(Cond::NZ, zero);
+        a.ld(Reg8::A, Reg8::E);
+        a.cp_n(16);
+        a.jr_cc(Cond::NC, zero);
+        a.or(Reg8::A);
+        a.ret_cc(Cond::Z);
+        a.ld(Reg8::B, Reg8::A);
+        a.bind(shift);
+        if label == helpers.shl {
+            a.add_hl(Reg16::HL);
+        } else {
+            a.rot(Rot::Srl, Reg8::H);
+            a.rot(Rot::Rr, Reg8::L);
+        }
+        a.djnz(shift);
+        a.ret();
+        a.bind(zero);
+        a.ld_nn(Reg16::HL, 0);
+        a.ret();
+    }
+}
+
+/// Emit the routine at `label` setting HL = HL / DE and DE = HL % DE,
+/// unsigned; `error` emits the jump taken on division by zero. The Z80
+/// runtime's `/` and `%` share it.
+#[cfg(feature = "z80-backend")]
+pub(crate) fn emit_divmod(a: &mut Asm, label: Label, prefix: &str, error: impl FnOnce(&mut Asm)) {
+    // Long division: BC shifts the dividend out and the quotient in, HL
+    // holds the remainder
+    a.bind(label);
+    a.ld(Reg8::A, Reg8::D);
+    a.or(Reg8::E);
+    let nonzero = a.label(&format!("{}_div_nonzero", prefix));
+    a.jr_cc(Cond::NZ, nonzero);
+    error(a);
+    a.bind(nonzero);
+    a.ld(Reg8::B, Reg8::H);
+    a.ld(Reg8::C, Reg8::L);
+    a.ld_nn(Reg16::HL, 0);
+    a.ld_n(Reg8::A, 16);
+    let div_loop = a.here_label(&format!("{}_div_loop", prefix));
+    let carried = a.label(&format!("{}_div_carried", prefix));
+    let fits = a.label(&format!("{}_div_fits", prefix));
+    let next = a.label(&format!("{}_div_next", prefix));
+    a.rot(Rot::Sla, Reg8::C);
+    a.rot(Rot::Rl, Reg8::B);
+    a.adc_hl(Reg16::HL);
+    a.jr_cc(Cond::C, carried);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NC, fits);
+    a.add_hl(Reg16::DE);
+    a.jr(next);
+    // The remainder went past 16 bits, so it is over the divisor
+    a.bind(carried);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.bind(fits);
+    a.inc(Reg8::C);
+    a.bind(next);
+    a.dec(Reg8::A);
+    a.jr_cc(Cond::NZ, div_loop);
+    a.ex_de_hl();
+    a.ld(Reg8::H, Reg8::B);
+    a.ld(Reg8::L, Reg8::C);
+    a.ret();
+}
+
+/// Emit the support library; `write` emits code printing A, keeping the
+/// other registers, and `heap_ptr` holds the next free heap byte
+#[cfg(feature = "z80-backend")]
+fn emit_support(a: &mut Asm, support: &Support, helpers: &Helpers, heap_ptr: u16, write: impl Fn(&mut Asm)) {
+    a.bind(support.print_str);
+    a.ld(Reg8::B, Reg8::HLInd);
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::A);
+    a.ret_cc(Cond::Z);
+    let print_loop = a.here_label("print_str_loop");
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    write(a);
+    a.djnz(print_loop);
+    a.ret();
+
+    a.bind(support.newline);
+    a.ld_n(Reg8::A, b'\n');
+    write(a);
+    a.ret();
+
+    // The text of a number goes in free heap, so printing it allocates
+    // nothing
+    let num_text = a.label("num_text");
+    a.bind(support.print_num);
+    a.call(num_text);
+    a.jp(support.print_str);
+
+    a.bind(support.num_str);
+    a.call(num_text);
+    a.ld_to(heap_ptr, Reg16::DE);
+    a.ret();
+
+    // HL = the string at the heap pointer, DE = its end. The digits come
+    // out of the division last first, so they wait on the stack, counted
+    // in B; C is 1 for a minus sign.
+    a.bind(num_text);
+    a.ld_n(Reg8::C, 0);
+    let positive = a.label("num_text_positive");
+    a.bit(7, Reg8::H);
+    a.jr_cc(Cond::Z, positive);
+    a.inc(Reg8::C);
+    a.ex_de_hl();
+    a.ld_nn(Reg16::HL, 0);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.bind(positive);
+    a.ld_n(Reg8::B, 0);
+    let digits = a.here_label("num_text_digits");
+    a.push(StackReg::BC);
+    a.ld_nn(Reg16::DE, 10);
+    a.call(helpers.divmod);
+    a.pop(StackReg::BC);
+    a.push(StackReg::DE);
+    a.inc(Reg8::B);
+    a.ld(Reg8::A, Reg8::H);
+    a.or(Reg8::L);
+    a.jr_cc(Cond::NZ, digits);
+    a.ld_from(Reg16::HL, heap_ptr);
+    a.inc16(Reg16::HL);
+    let unsigned = a.label("num_text_unsigned");
+    a.ld(Reg8::A, Reg8::C);
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, unsigned);
+    a.ld_n(Reg8::HLInd, b'-');
+    a.inc16(Reg16::HL);
+    a.bind(unsigned);
+    let out = a.here_label("num_text_out");
+    a.pop(StackReg::DE);
+    a.ld(Reg8::A, Reg8::E);
+    a.alu_n(crate::asm::Alu::Add, b'0');
+    a.ld(Reg8::HLInd, Reg8::A);
+    a.inc16(Reg16::HL);
+    a.djnz(out);
+    a.ex_de_hl();
+    a.ld_from(Reg16::HL, heap_ptr);
+    a.ld(Reg8::A, Reg8::E);
+    a.alu(crate::asm::Alu::Sub, Reg8::L);
+    a.dec(Reg8::A);
+    a.ld(Reg8::HLInd, Reg8::A);
+    a.ret();
+
+    // Copy the first string, then as much of the second as fits in 255
+    a.bind(support.concat);
+    a.push(StackReg::DE);
+    a.ld_from(Reg16::DE, heap_ptr);
+    a.push(StackReg::DE);
+    a.inc16(Reg16::DE);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.ld(Reg8::C, Reg8::A);
+    a.ld_n(Reg8::B, 0);
+    a.inc16(Reg16::HL);
+    let (first_done, fits, second_done) =
+        (a.label("concat_first_done"), a.label("concat_fits"), a.label("concat_second_done"));
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, first_done);
+    a.ldir();
+    a.bind(first_done);
+    a.pop(StackReg::HL);
+    a.ex_sp_hl();
+    a.ld(Reg8::C, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.alu(crate::asm::Alu::Add, Reg8::C);
+    a.jr_cc(Cond::NC, fits);
+    // Over by A + 1: keep 255 - first length bytes of the second
+    a.ld(Reg8::B, Reg8::A);
+    a.ld(Reg8::A, Reg8::C);
+    a.alu(crate::asm::Alu::Sub, Reg8::B);
+    a.dec(Reg8::A);
+    a.ld(Reg8::C, Reg8::A);
+    a.ld_n(Reg8::A, 0xFF);
+    a.bind(fits);
+    a.ld_n(Reg8::B, 0);
+    a.ex_sp_hl();
+    a.ld(Reg8::HLInd, Reg8::A);
+    a.ex_sp_hl();
+    a.ld(Reg8::A, Reg8::C);
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, second_done);
+    a.ldir();
+    a.bind(second_done);
+    a.ld_to(heap_ptr, Reg16::DE);
+    a.pop(StackReg::HL);
+    a.ret();
+
+    // B and C count down the bytes left of each string
+    a.bind(support.strcmp);
+    a.ld(Reg8::B, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.ld_a_ind(Reg16::DE);
+    a.ld(Reg8::C, Reg8::A);
+    a.inc16(Reg16::DE);
+    let (cmp_loop, first_ended, differ, less, greater) = (
+        a.here_label("strcmp_loop"),
+        a.label("strcmp_first_ended"),
+        a.label("strcmp_differ"),
+        a.label("strcmp_less"),
+        a.label("strcmp_greater"),
+    );
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, first_ended);
+    a.ld(Reg8::A, Reg8::C);
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, greater);
+    a.ld_a_ind(Reg16::DE);
+    a.alu(crate::asm::Alu::Cp, Reg8::HLInd);
+    a.jr_cc(Cond::NZ, differ);
+    a.inc16(Reg16::HL);
+    a.inc16(Reg16::DE);
+    a.dec(Reg8::B);
+    a.dec(Reg8::C);
+    a.jr(cmp_loop);
+    a.bind(first_ended);
+    a.ld_nn(Reg16::HL, 0);
+    a.ld(Reg8::A, Reg8::C);
+    a.or(Reg8::A);
+    a.ret_cc(Cond::Z);
+    a.jr(less);
+    // The second string's byte minus the first's
+    a.bind(differ);
+    a.jr_cc(Cond::C, greater);
+    a.bind(less);
+    a.ld_nn(Reg16::HL, 0xFFFF);
+    a.ret();
+    a.bind(greater);
+    a.ld_nn(Reg16::HL, 1);
+    a.ret();
+}
+
+#[cfg(all(test, feature = "z80-backend", feature = "emulator"))]
+mod tests {
+    use super::*;
+    use crate::z80emu::{Console, Exit, Machine};
+    use crate::{Compiler, Lexer, Parser};
+
+    fn image(source: &str) -> Result<Image, ProgramError> {
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&program).unwrap();
+        super::program(&program, &compiler.constants(), &RomOptions::default())
+    }
+
+    fn run(source: &str) -> (Exit, String, bool) {
+        let (exit, output, error_line) = run_to_error(source);
+        (exit, output, error_line.is_some())
+    }
+
+    /// The exit, the output and, if a division by zero halted it, the
+    /// division's line
+    fn run_to_error(source: &str) -> (Exit, String, Option<Option<usize>>) {
+        let image = image(source).unwrap();
+        let mut machine = Machine::new(&image.rom, Console::scripted(b""));
+        let exit = machine.run(Some(20_000_000));
+        let error = (machine.cpu.pc == image.error_pc).then(|| image.error_line(machine.read16(machine.cpu.sp)));
+        (exit, String::from_utf8_lossy(machine.io.output()).into_owned(), error)
+    }
+
+    #[test]
+    fn test_program() {
+        let (exit, output, _) = run(
+            "use constant NAME => \"Z80\";
+             sub fib($n) { return $n < 2 ? $n : fib($n - 1) + fib($n - 2); }
+             sub diff($a, $b) { return $a - $b; }
+             my $s = \"Hello, \" . NAME;
+             $s .= \"!\";
+             say $s;
+             print fib(20), \" \", diff(10, 3), \" \", -42, \" \", 100 % 7, \"\\n\";
+             my $t = \"\";
+             for (my $i = 0; $i < 3; $i++) { $t .= $i; }
+             say $t . 4, \" \", \"abc\" cmp \"abd\", \" \", \"b\" gt \"a\", \" \", \"ab\" eq \"abc\";",
+        );
+        assert_eq!(exit, Exit::Halted);
+        assert_eq!(output, "Hello, Z80!\n6765 7 -42 2\n0124 -1 1 0\n");
+    }
+
+    #[test]
+    fn test_program_strings_stop_at_255() {
+        let (_, output, _) = run("my $s = \"ab\"; for (my $i = 0; $i < 8; $i++) { $s .= $s; } say $s eq $s . \"c\", \" \", $s lt \"b\";");
+        assert_eq!(output, "1 1\n");
+    }
+
+    #[test]
+    fn test_program_division_by_zero() {
+        let (exit, output, at_error) = run("my $z = 0; print 1; print 2 / $z; print 3;");
+        assert_eq!((exit, output.as_str(), at_error), (Exit::Halted, "1", true));
+        let (_, _, error) = run_to_error("my $z = 0;\nprint 7 / 7;\nsub f($n) {\n    return 5 % $n;\n}\nprint f($z);");
+        assert_eq!(error, Some(Some(4)));
+    }
+
+    #[test]
+    fn test_program_errors() {
+        let error = |source| image(source).unwrap_err();
+        assert_eq!(error("my $x = 1;\nmy @a;\n$a[0] = 1;"),
+                   ProgramError { line: Some(3), message: "--native can't compile this assignment".into() });
+        assert_eq!(error("my $x = \"a\";\n$x = 3;").message, "--native can't compile $x holding both strings and numbers");
+        assert_eq!(error("sub f($s) { return 1; }\nf(\"x\");").message, "--native can't compile passing a string to a sub");
+        assert_eq!(error("print STDERR 1;").message, "--native can't compile printing to STDERR");
+        assert_eq!(error("return 1;").message, "--native can't compile return outside a sub");
+    }
+}
@@ -0,0 +1,224 @@
+//! Jump optimizer
+//!
+//! The compiler emits control flow a statement at a time, so nested ifs
+//! jump to jumps and `last if COND` branches over a Jump. This pass tidies
+//! the finished module: a jump to a Jump goes straight to where that one
+//! leads, a conditional jump over a Jump becomes the opposite condition to
+//! the Jump's target, and a Jump to the next instruction goes. Removing
+//! code moves what follows, so jumps, calls, subs and the line table are
+//! relocated to match.
+
+use std::collections::HashSet;
+
+use crate::bytecode::{Module, Op};
+
+/// Jumps followed when threading, so a loop of Jumps can't hang the pass
+const MAX_HOPS: usize = 16;
+
+/// Thread jumps and drop the Jumps that become unneeded, until nothing
+/// changes
+pub fn optimize(module: &mut Module) {
+    loop {
+        // Inverting first, as threading would take the branch past the
+        // Jump it skips
+        let removed = invert_branches(module);
+        if !removed.is_empty() {
+            remove(module, &removed);
+        }
+        if !thread_jumps(module) && removed.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Start and opcode of each instruction
+fn instructions(code: &[u8]) -> Vec<(usize, Op)> {
+    let mut out = Vec::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = Op::from_byte(code[pc]);
+        out.push((pc, op));
+        pc += op.size();
+    }
+    out
+}
+
+/// Whether `op` is followed by a code address
+fn has_target(op: Op) -> bool {
+    matches!(op, Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef | Op::Call | Op::Spawn)
+}
+
+fn target(code: &[u8], pc: usize) -> Option<usize> {
+    code.get(pc + 1..pc + 3).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+}
+
+fn set_target(code: &mut [u8], pc: usize, addr: usize) {
+    code[pc + 1..pc + 3].copy_from_slice(&(addr as u16).to_le_bytes());
+}
+
+/// Point each jump that lands on a Jump at that Jump's destination.
+/// Returns whether any changed.
+fn thread_jumps(module: &mut Module) -> bool {
+    let mut changed = false;
+    for (pc, op) in instructions(&module.code) {
+        if !has_target(op) || op == Op::Call {
+            continue;
+        }
+        let Some(mut to) = target(&module.code, pc) else {
+            continue;
+        };
+        for _ in 0..MAX_HOPS {
+            match (module.code.get(to), target(&module.code, to)) {
+                (Some(&byte), Some(next)) if byte == Op::Jump as u8 && next != to => to = next,
+                _ => break,
+            }
+        }
+        if Some(to) != target(&module.code, pc) {
+            set_target(&mut module.code, pc, to);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Turn `JumpIfNot L; Jump M; L:` into `JumpIf M` (and the reverse), and
+/// find Jumps to the next instruction. Returns the Jumps no longer needed;
+/// none of them is the target of another jump.
+fn invert_branches(module: &mut Module) -> Vec<usize> {
+    let ops = instructions(&module.code);
+    let targets: HashSet<usize> = ops
+        .iter()
+        .filter(|&&(_, op)| has_target(op))
+        .filter_map(|&(pc, _)| target(&module.code, pc))
+        .collect();
+
+    let mut removed = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let (pc, op) = ops[i];
+        let next = ops.get(i + 1).copied();
+        match (op, next) {
+            (Op::JumpIf | Op::JumpIfNot, Some((jump, Op::Jump)))
+                if target(&module.code, pc) == Some(jump + 3) && !targets.contains(&jump) =>
+            {
+                let to = target(&module.code, jump).unwrap_or(jump + 3);
+                module.code[pc] = if op == Op::JumpIf { Op::JumpIfNot } else { Op::JumpIf } as u8;
+                set_target(&mut module.code, pc, to);
+                removed.push(jump);
+                i += 2;
+                continue;
+            }
+            (Op::Jump, _) if target(&module.code, pc) == Some(pc + 3) => removed.push(pc),
+            _ => {}
+        }
+        i += 1;
+    }
+    removed
+}
+
+/// Delete the Jumps at `removed`, moving everything after them down
+fn remove(module: &mut Module, removed: &[usize]) {
+    let removed: HashSet<usize> = removed.iter().copied().collect();
+
+    // New offset of each instruction; a removed one maps to the next kept
+    let mut moved = vec![0u16; module.code.len() + 1];
+    let mut targets = Vec::new();
+    let mut code = Vec::new();
+    for (pc, op) in instructions(&module.code) {
+        moved[pc] = code.len() as u16;
+        if removed.contains(&pc) {
+            continue;
+        }
+        let end = (pc + op.size()).min(module.code.len());
+        if has_target(op) && end - pc == 3 {
+            targets.push(code.len() + 1);
+        }
+        code.extend_from_slice(&module.code[pc..end]);
+    }
+    moved[module.code.len()] = code.len() as u16;
+
+    let relocate = |addr: u16| moved.get(addr as usize).copied().unwrap_or(addr);
+    for pos in targets {
+        let addr = relocate(u16::from_le_bytes([code[pos], code[pos + 1]]));
+        code[pos..pos + 2].copy_from_slice(&addr.to_le_bytes());
+    }
+
+    module.code = code;
+    module.entry = relocate(module.entry);
+    for sub in &mut module.subs {
+        sub.1 = relocate(sub.1);
+    }
+    for sub in &mut module.native {
+        sub.addr = relocate(sub.addr);
+    }
+    // A statement left with no code yields to the next, as in mark_line
+    let mut lines: Vec<(u16, usize)> = Vec::new();
+    for &(pos, line) in &module.lines {
+        let pos = relocate(pos);
+        match lines.last_mut() {
+            Some(last) if last.0 == pos => last.1 = line,
+            _ => lines.push((pos, line)),
+        }
+    }
+    module.lines = lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(code: &str) -> Module {
+        let program = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        Compiler::new().compile(&program).unwrap()
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        let mut m = Module::new();
+        m.emit_word(Op::Jump, 6);
+        m.emit_word(Op::Push, 1);
+        m.emit_word(Op::Jump, 9);
+        m.emit_word(Op::Jump, 3);
+        m.emit(Op::Halt);
+        thread_jumps(&mut m);
+        assert_eq!(target(&m.code, 0), Some(3));
+        // A Jump to itself stays put
+        let mut m = Module::new();
+        m.emit_word(Op::Jump, 0);
+        optimize(&mut m);
+        assert_eq!(m.code, [Op::Jump as u8, 0, 0]);
+    }
+
+    #[test]
+    fn test_invert_branch() {
+        // The JumpIfNot over `last` becomes a JumpIf out of the loop, which
+        // then skips the Jump back to the top: the loop ends in one branch
+        let m = compile("my $i = 0;\nwhile (1) {\n$i++;\nif ($i > 3) { last; }\n}\nprint $i;");
+        let ops = instructions(&m.code);
+        let at = ops.iter().position(|&(_, op)| op == Op::CmpGt).unwrap();
+        let (pc, op) = ops[at + 1];
+        assert_eq!(op, Op::JumpIfNot);
+        assert_eq!(target(&m.code, pc), Some(5));
+        let (end, op) = ops[at + 2];
+        assert_eq!(op, Op::LoadLocal);
+        assert_eq!(m.line_at(end as u16), Some(6));
+    }
+
+    #[test]
+    fn test_remove_relocates() {
+        let m = compile("sub f { if (1) { return 1; } }\nif (f()) { print 1; } else { print 2; }\nprint f();");
+        for (pc, op) in instructions(&m.code) {
+            if has_target(op) {
+                let to = target(&m.code, pc).unwrap();
+                assert!(instructions(&m.code).iter().any(|&(start, _)| start == to), "{:?} at {} to {}", op, pc, to);
+                assert_ne!(m.code[to], Op::Jump as u8);
+            }
+        }
+        let (_, addr, _) = m.subs[0];
+        assert_eq!(m.code[addr as usize], Op::EnterFrame as u8);
+        assert_eq!(m.line_at(m.lines.last().unwrap().0), Some(3));
+    }
+}
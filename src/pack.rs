@@ -0,0 +1,150 @@
+//! pack() and unpack()
+//!
+//! A subset of Perl's templates for binary data: `C` an unsigned byte, `n`
+//! a 16-bit big-endian word, `v` a 16-bit little-endian word and `a` a
+//! string padded with NULs. Each letter takes a count, or `*` for all the
+//! rest. The compiler folds a pack of constants into the string table, and
+//! the host VM runs the rest.
+
+/// One item unpack() gives back
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unpacked {
+    Num(u16),
+    Bytes(Vec<u8>),
+}
+
+/// How many of a letter: one when it has no count, a number, or `*` for all
+enum Count {
+    One,
+    Exactly(usize),
+    All,
+}
+
+/// The letters of `template` with their counts
+fn parse(template: &str, function: &str) -> Result<Vec<(char, Count)>, String> {
+    let mut items = Vec::new();
+    let mut chars = template.chars().filter(|c| !c.is_whitespace()).peekable();
+    while let Some(letter) = chars.next() {
+        if !matches!(letter, 'C' | 'n' | 'v' | 'a') {
+            return Err(format!("{}: unknown format `{}`, the formats are C, n, v and a", function, letter));
+        }
+        let count = if chars.next_if_eq(&'*').is_some() {
+            Count::All
+        } else {
+            let mut n = None;
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                n = Some(n.unwrap_or(0) * 10 + digit.to_digit(10).unwrap() as usize);
+            }
+            n.map_or(Count::One, Count::Exactly)
+        };
+        items.push((letter, count));
+    }
+    Ok(items)
+}
+
+/// The number a string reads as: its leading decimal digits, or 0
+pub fn number(text: &[u8]) -> u16 {
+    let (negative, digits) = match text.strip_prefix(b"-") {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let n = digits.iter().take_while(|b| b.is_ascii_digit()).fold(0u16, |n, &b| n.wrapping_mul(10).wrapping_add((b - b'0') as u16));
+    if negative {
+        n.wrapping_neg()
+    } else {
+        n
+    }
+}
+
+/// `args` packed by `template`, each argument as its number and its text.
+/// Missing arguments are 0 or empty.
+pub fn pack(template: &str, args: &[(u16, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut args = args.iter();
+    for (letter, count) in parse(template, "pack")? {
+        if letter == 'a' {
+            let text = args.next().map_or(&[][..], |(_, text)| text);
+            let len = match count {
+                Count::One => 1,
+                Count::Exactly(n) => n,
+                Count::All => text.len(),
+            };
+            out.extend(text.iter().copied().chain(std::iter::repeat(0)).take(len));
+            continue;
+        }
+        let n = match count {
+            Count::One => 1,
+            Count::Exactly(n) => n,
+            Count::All => args.len(),
+        };
+        for _ in 0..n {
+            let v = args.next().map_or(0, |&(v, _)| v);
+            match letter {
+                'C' => out.push(v as u8),
+                'n' => out.extend(v.to_be_bytes()),
+                _ => out.extend(v.to_le_bytes()),
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The items `template` reads from `data`, stopping where it runs out
+pub fn unpack(template: &str, data: &[u8]) -> Result<Vec<Unpacked>, String> {
+    let mut out = Vec::new();
+    let mut rest = data;
+    for (letter, count) in parse(template, "unpack")? {
+        let size = if letter == 'C' || letter == 'a' { 1 } else { 2 };
+        let n = match count {
+            Count::One => 1,
+            Count::Exactly(n) => n,
+            Count::All => rest.len() / size,
+        };
+        if letter == 'a' {
+            let (text, tail) = rest.split_at(n.min(rest.len()));
+            out.push(Unpacked::Bytes(text.to_vec()));
+            rest = tail;
+            continue;
+        }
+        for _ in 0..n {
+            let Some((item, tail)) = rest.split_at_checked(size) else { break };
+            out.push(Unpacked::Num(match letter {
+                'C' => item[0] as u16,
+                'n' => u16::from_be_bytes([item[0], item[1]]),
+                _ => u16::from_le_bytes([item[0], item[1]]),
+            }));
+            rest = tail;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(v: u16) -> (u16, Vec<u8>) {
+        (v, v.to_string().into_bytes())
+    }
+
+    #[test]
+    fn test_pack() {
+        assert_eq!(pack("C n v", &[num(0x41), num(0x1234), num(0x1234)]).unwrap(), [0x41, 0x12, 0x34, 0x34, 0x12]);
+        assert_eq!(pack("C*", &[num(1), num(2), num(300)]).unwrap(), [1, 2, 44]);
+        assert_eq!(pack("a4 a a*", &[(0, b"hi".to_vec()), (0, b"xyz".to_vec()), (0, b"end".to_vec())]).unwrap(), b"hi\0\0xend");
+        // Missing arguments are 0
+        assert_eq!(pack("C2", &[num(7)]).unwrap(), [7, 0]);
+        assert!(pack("N", &[]).unwrap_err().contains("unknown format `N`"));
+    }
+
+    #[test]
+    fn test_unpack() {
+        let data = [0x41, 0x12, 0x34, 0x34, 0x12, b'o', b'k', 9];
+        assert_eq!(
+            unpack("C n v a2 C*", &data).unwrap(),
+            [Unpacked::Num(0x41), Unpacked::Num(0x1234), Unpacked::Num(0x1234), Unpacked::Bytes(b"ok".to_vec()), Unpacked::Num(9)]
+        );
+        // Short data gives fewer items
+        assert_eq!(unpack("n3", &[1, 2, 3]).unwrap(), [Unpacked::Num(0x0102)]);
+    }
+}
@@ -1,16 +1,73 @@
 //! Parser for MicroPerl
 
-use crate::ast::{BinOp, Expr, Program, Stmt, UnaryOp};
+use crate::ast::{BinOp, Expr, Handle, Program, Stmt, UnaryOp};
+use crate::printer;
 use crate::token::{Token, TokenWithSpan};
 
+/// Nesting budget. A program nested deeper is rejected, so that no input
+/// can overflow the stack here or in the passes that walk the tree. Each
+/// operator costs 1; expressions in parentheses or argument lists and
+/// nested statements cost more, as they take more stack.
+pub const MAX_DEPTH: usize = 256;
+
+/// Cost of an expression inside another one
+const EXPR_COST: usize = 8;
+
+/// Cost of a statement inside a block
+const STMT_COST: usize = 4;
+
 pub struct Parser {
     tokens: Vec<TokenWithSpan>,
     pos: usize,
+
+    /// Statement lines in pre-order, see `Program::lines`
+    lines: Vec<usize>,
+
+    /// Current nesting, see `MAX_DEPTH`
+    depth: usize,
+
+    /// Code of the error `parse` stopped with, see `Diagnostic::code`
+    error_code: &'static str,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<TokenWithSpan>) -> Self {
-        Parser { tokens, pos: 0 }
+        Parser { tokens, pos: 0, lines: Vec::new(), depth: 0, error_code: "syntax-error" }
+    }
+
+    /// Go `cost` deeper, see `MAX_DEPTH`
+    fn deeper(&mut self, cost: usize) -> Result<(), String> {
+        if self.depth + cost > MAX_DEPTH {
+            return Err("Program is nested too deeply".to_string());
+        }
+        self.depth += cost;
+        Ok(())
+    }
+
+    /// Run `parse` `cost` deeper
+    fn nested<T>(&mut self, cost: usize, parse: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        self.deeper(cost)?;
+        let result = parse(self);
+        self.depth -= cost;
+        result
+    }
+
+    /// Line of the current token
+    fn line(&self) -> usize {
+        self.location().0
+    }
+
+    /// Line and column of the current token, where a parse error was found
+    pub fn location(&self) -> (usize, usize) {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or((1, 1), |t| (t.line, t.column))
+    }
+
+    /// Code of the error `parse` stopped with
+    pub fn error_code(&self) -> &'static str {
+        self.error_code
     }
 
     fn current(&self) -> &Token {
@@ -46,50 +103,64 @@ impl Parser {
             let stmt = self.parse_statement()?;
             program.statements.push(stmt);
         }
+        program.lines = std::mem::take(&mut self.lines);
         Ok(program)
     }
 
     fn parse_statement(&mut self) -> Result<Stmt, String> {
-        match self.current().clone() {
-            Token::My => self.parse_my(),
-            Token::Our => self.parse_our(),
-            Token::Sub => self.parse_sub(),
-            Token::If => self.parse_if(),
-            Token::Unless => self.parse_unless(),
-            Token::While => self.parse_while(),
-            Token::Until => self.parse_until(),
-            Token::For => self.parse_for(),
-            Token::Foreach => self.parse_foreach(),
+        // Recorded before any nested statement is parsed
+        self.lines.push(self.line());
+        self.nested(STMT_COST, |p| match p.current().clone() {
+            Token::My => p.parse_my(),
+            Token::Our => p.parse_our(),
+            Token::Sub => p.parse_sub(),
+            Token::If => p.parse_if(),
+            Token::Unless => p.parse_unless(),
+            Token::While => p.parse_while(),
+            Token::Until => p.parse_until(),
+            Token::For => p.parse_for(),
+            Token::Foreach => p.parse_foreach(),
             Token::Last => {
-                self.advance();
-                self.expect(Token::Semicolon)?;
+                p.advance();
+                p.expect(Token::Semicolon)?;
                 Ok(Stmt::Last)
             }
             Token::Next => {
-                self.advance();
-                self.expect(Token::Semicolon)?;
+                p.advance();
+                p.expect(Token::Semicolon)?;
                 Ok(Stmt::Next)
             }
-            Token::Return => self.parse_return(),
-            Token::Print => self.parse_print(),
-            Token::Say => self.parse_say(),
-            Token::Use => self.parse_use(),
-            Token::Package => self.parse_package(),
-            Token::LBrace => self.parse_block(),
+            Token::Return => p.parse_return(),
+            Token::Print => p.parse_print(),
+            Token::Say => p.parse_say(),
+            Token::Printf => p.parse_printf(),
+            Token::Assert => p.parse_assert(),
+            Token::Use => p.parse_use(),
+            Token::Ident(name) if matches!(name.as_str(), "do" | "require") && matches!(p.peek(), Token::String(_)) => {
+                p.parse_include()
+            }
+            Token::Package => p.parse_package(),
+            Token::LBrace => p.parse_block(),
+            Token::Ident(name) if name == "BEGIN" && p.peek() == &Token::LBrace => p.parse_begin(),
             _ => {
-                let expr = self.parse_expr()?;
-                self.expect(Token::Semicolon)?;
+                let expr = p.parse_expr()?;
+                p.expect(Token::Semicolon)?;
                 Ok(Stmt::Expr(expr))
             }
-        }
+        })
     }
 
     fn parse_my(&mut self) -> Result<Stmt, String> {
         self.advance(); // consume 'my'
+        let list = matches!(self.current(), Token::LParen | Token::ArrayVar(_));
         let vars = self.parse_var_list()?;
         let init = if self.at(&Token::Assign) {
             self.advance();
-            Some(self.parse_expr()?)
+            Some(match self.parse_expr()? {
+                // In list context a hash flattens to its keys and values
+                hash @ Expr::HashVar(_) if list => Expr::List(vec![hash]),
+                init => init,
+            })
         } else {
             None
         };
@@ -153,33 +224,48 @@ impl Parser {
             _ => return Err(format!("Expected subroutine name, got {:?}", self.current())),
         };
 
-        // Optional parameter list
-        let params = if self.at(&Token::LParen) {
+        // Optional parameter list, which may end in an array for the rest
+        // of the arguments: `sub log($level, @values)`
+        let mut params = Vec::new();
+        let mut variadic = false;
+        if self.at(&Token::LParen) {
             self.advance();
-            let mut params = Vec::new();
             while !self.at(&Token::RParen) {
+                if variadic {
+                    return Err(format!("Array parameter @{} must be the last parameter", params.last().unwrap()));
+                }
                 match self.current().clone() {
-                    Token::ScalarVar(name) => {
+                    Token::ScalarVar(name) => params.push(name),
+                    Token::ArrayVar(name) => {
                         params.push(name);
-                        self.advance();
+                        variadic = true;
                     }
                     _ => return Err(format!("Expected parameter, got {:?}", self.current())),
                 }
+                self.advance();
                 if self.at(&Token::Comma) {
                     self.advance();
                 }
             }
             self.expect(Token::RParen)?;
-            params
-        } else {
-            Vec::new()
-        };
+        }
+
+        // Attributes: `sub fib($n) :native { ... }`
+        let mut native = false;
+        while self.at(&Token::Colon) {
+            self.advance();
+            match self.current().clone() {
+                Token::Ident(attr) if attr == "native" => native = true,
+                other => return Err(format!("Unknown sub attribute {:?}, expected :native", other)),
+            }
+            self.advance();
+        }
 
         self.expect(Token::LBrace)?;
         let body = self.parse_stmt_list()?;
         self.expect(Token::RBrace)?;
 
-        Ok(Stmt::Sub { name, params, body })
+        Ok(Stmt::Sub { name, params, body, native, variadic })
     }
 
     fn parse_if(&mut self) -> Result<Stmt, String> {
@@ -193,6 +279,7 @@ impl Parser {
 
         let mut elsif_blocks = Vec::new();
         while self.at(&Token::Elsif) {
+            self.lines.push(self.line());
             self.advance();
             self.expect(Token::LParen)?;
             let elsif_cond = self.parse_expr()?;
@@ -353,16 +440,57 @@ impl Parser {
 
     fn parse_print(&mut self) -> Result<Stmt, String> {
         self.advance(); // consume 'print'
+        let handle = self.parse_handle();
         let args = self.parse_expr_list()?;
         self.expect(Token::Semicolon)?;
-        Ok(Stmt::Print(args))
+        Ok(Stmt::Print(handle, args))
     }
 
     fn parse_say(&mut self) -> Result<Stmt, String> {
         self.advance(); // consume 'say'
+        let handle = self.parse_handle();
         let args = self.parse_expr_list()?;
         self.expect(Token::Semicolon)?;
-        Ok(Stmt::Say(args))
+        Ok(Stmt::Say(handle, args))
+    }
+
+    fn parse_printf(&mut self) -> Result<Stmt, String> {
+        self.advance(); // consume 'printf'
+        let handle = self.parse_handle();
+        let args = self.parse_expr_list()?;
+        if args.is_empty() {
+            return Err("printf needs a format".to_string());
+        }
+        self.expect(Token::Semicolon)?;
+        Ok(Stmt::Printf(handle, args))
+    }
+
+    /// `STDOUT` or `STDERR` after print, say or printf: a filehandle unless
+    /// a comma makes it an expression
+    fn parse_handle(&mut self) -> Option<Handle> {
+        let handle = match self.current() {
+            Token::Ident(name) if name == "STDOUT" => Handle::Stdout,
+            Token::Ident(name) if name == "STDERR" => Handle::Stderr,
+            _ => return None,
+        };
+        if self.peek() == &Token::Comma {
+            return None;
+        }
+        self.advance();
+        Some(handle)
+    }
+
+    fn parse_assert(&mut self) -> Result<Stmt, String> {
+        self.advance(); // consume 'assert'
+        let cond = self.parse_expr()?;
+        let message = if self.at(&Token::Comma) {
+            self.advance();
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        self.expect(Token::Semicolon)?;
+        Ok(Stmt::Assert(cond, message))
     }
 
     fn parse_use(&mut self) -> Result<Stmt, String> {
@@ -374,10 +502,31 @@ impl Parser {
             }
             _ => return Err(format!("Expected module name, got {:?}", self.current())),
         };
+        if name == "constant" && !self.at(&Token::Semicolon) {
+            let Token::Ident(constant) = self.current().clone() else {
+                return Err(format!("Expected constant name, got {:?}", self.current()));
+            };
+            self.advance();
+            self.expect(Token::FatArrow)?;
+            let value = self.parse_expr()?;
+            self.expect(Token::Semicolon)?;
+            return Ok(Stmt::Constant(constant, value));
+        }
         self.expect(Token::Semicolon)?;
         Ok(Stmt::Use(name))
     }
 
+    fn parse_include(&mut self) -> Result<Stmt, String> {
+        let once = self.current() == &Token::Ident("require".to_string());
+        self.advance(); // consume 'do' or 'require'
+        let Token::String(file) = self.current().clone() else {
+            return Err(format!("Expected file name, got {:?}", self.current()));
+        };
+        self.advance();
+        self.expect(Token::Semicolon)?;
+        Ok(Stmt::Include { file, once })
+    }
+
     fn parse_package(&mut self) -> Result<Stmt, String> {
         self.advance(); // consume 'package'
         let name = match self.current().clone() {
@@ -398,6 +547,14 @@ impl Parser {
         Ok(Stmt::Block(stmts))
     }
 
+    fn parse_begin(&mut self) -> Result<Stmt, String> {
+        self.advance(); // consume 'BEGIN'
+        self.expect(Token::LBrace)?;
+        let body = self.parse_stmt_list()?;
+        self.expect(Token::RBrace)?;
+        Ok(Stmt::Begin(body))
+    }
+
     fn parse_stmt_list(&mut self) -> Result<Vec<Stmt>, String> {
         let mut stmts = Vec::new();
         while !self.at(&Token::RBrace) && !self.at(&Token::Eof) {
@@ -408,11 +565,12 @@ impl Parser {
 
     fn parse_expr_list(&mut self) -> Result<Vec<Expr>, String> {
         let mut exprs = Vec::new();
-        if !self.at(&Token::Semicolon) && !self.at(&Token::RParen) {
+        let end = |p: &Self| matches!(p.current(), Token::Semicolon | Token::RParen | Token::RBracket);
+        if !end(self) {
             exprs.push(self.parse_expr()?);
             while self.at(&Token::Comma) {
                 self.advance();
-                if !self.at(&Token::Semicolon) && !self.at(&Token::RParen) {
+                if !end(self) {
                     exprs.push(self.parse_expr()?);
                 }
             }
@@ -421,7 +579,7 @@ impl Parser {
     }
 
     fn parse_expr(&mut self) -> Result<Expr, String> {
-        self.parse_assignment()
+        self.nested(EXPR_COST, Self::parse_assignment)
     }
 
     fn parse_assignment(&mut self) -> Result<Expr, String> {
@@ -430,34 +588,40 @@ impl Parser {
         match self.current() {
             Token::Assign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
                 Ok(Expr::Assign(Box::new(left), Box::new(right)))
             }
             Token::PlusEquals => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
                 Ok(Expr::OpAssign(Box::new(left), BinOp::Add, Box::new(right)))
             }
             Token::MinusEquals => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
                 Ok(Expr::OpAssign(Box::new(left), BinOp::Sub, Box::new(right)))
             }
             Token::StarEquals => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
                 Ok(Expr::OpAssign(Box::new(left), BinOp::Mul, Box::new(right)))
             }
             Token::SlashEquals => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
                 Ok(Expr::OpAssign(Box::new(left), BinOp::Div, Box::new(right)))
             }
             Token::DotEquals => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
                 Ok(Expr::OpAssign(Box::new(left), BinOp::Concat, Box::new(right)))
             }
+            Token::Ident(x) if x == "x" && self.peek() == &Token::Assign => {
+                self.advance();
+                self.advance();
+                let right = self.nested(EXPR_COST, Self::parse_assignment)?;
+                Ok(Expr::OpAssign(Box::new(left), BinOp::Repeat, Box::new(right)))
+            }
             _ => Ok(left),
         }
     }
@@ -469,7 +633,7 @@ impl Parser {
             self.advance();
             let then_expr = self.parse_expr()?;
             self.expect(Token::Colon)?;
-            let else_expr = self.parse_ternary()?;
+            let else_expr = self.nested(EXPR_COST, Self::parse_ternary)?;
             Ok(Expr::Ternary(
                 Box::new(cond),
                 Box::new(then_expr),
@@ -481,37 +645,48 @@ impl Parser {
     }
 
     fn parse_or(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
         let mut left = self.parse_and()?;
 
         while matches!(self.current(), Token::Or | Token::OrWord) {
             self.advance();
+            self.deeper(1)?;
             let right = self.parse_and()?;
             left = Expr::BinOp(Box::new(left), BinOp::Or, Box::new(right));
         }
 
+        self.depth = depth;
         Ok(left)
     }
 
     fn parse_and(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
         let mut left = self.parse_comparison()?;
 
         while matches!(self.current(), Token::And | Token::AndWord) {
             self.advance();
+            self.deeper(1)?;
             let right = self.parse_comparison()?;
             left = Expr::BinOp(Box::new(left), BinOp::And, Box::new(right));
         }
 
+        self.depth = depth;
         Ok(left)
     }
 
     fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
         let mut left = self.parse_additive()?;
+        // Whether `left` is a comparison made in this loop, not one in
+        // parentheses, and if so whether it was relational
+        let mut compared = None;
 
         loop {
             // Check for regex match operators first
             if matches!(self.current(), Token::Match | Token::NotMatch) {
                 let is_negated = matches!(self.current(), Token::NotMatch);
                 self.advance();
+                self.deeper(1)?;
 
                 // Expect a regex pattern
                 if let Token::Regex(pattern, flags) = self.current().clone() {
@@ -544,15 +719,28 @@ impl Parser {
                 Token::StrCmp => BinOp::StrCmp,
                 _ => break,
             };
+            let at = self.pos;
             self.advance();
+            self.deeper(1)?;
             let right = self.parse_additive()?;
+            // Only a chain within one level, as in `0 < $x < 10`: `$a < $b
+            // == $c` compares the result on purpose, as Perl allows
+            if compared == Some(relational(&op)) {
+                // Point the error at the second operator
+                self.pos = at;
+                self.error_code = "chained-comparison";
+                return Err(chained(&left, &op, &right));
+            }
+            compared = Some(relational(&op));
             left = Expr::BinOp(Box::new(left), op, Box::new(right));
         }
 
+        self.depth = depth;
         Ok(left)
     }
 
     fn parse_additive(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
         let mut left = self.parse_multiplicative()?;
 
         loop {
@@ -563,14 +751,17 @@ impl Parser {
                 _ => break,
             };
             self.advance();
+            self.deeper(1)?;
             let right = self.parse_multiplicative()?;
             left = Expr::BinOp(Box::new(left), op, Box::new(right));
         }
 
+        self.depth = depth;
         Ok(left)
     }
 
     fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
         let mut left = self.parse_unary()?;
 
         loop {
@@ -578,36 +769,65 @@ impl Parser {
                 Token::Star => BinOp::Mul,
                 Token::Slash => BinOp::Div,
                 Token::Percent => BinOp::Mod,
+                _ if self.at_repeat() => BinOp::Repeat,
                 _ => break,
             };
-            self.advance();
-            let right = self.parse_unary()?;
+            self.deeper(1)?;
+            let right = match self.current().clone() {
+                // `"-" x40` lexes as one word
+                Token::Ident(word) if op == BinOp::Repeat && word.len() > 1 => {
+                    self.advance();
+                    Expr::Integer(word[1..].parse().map_err(|_| format!("Bad repeat count: {}", word))?)
+                }
+                _ => {
+                    self.advance();
+                    self.parse_unary()?
+                }
+            };
             left = Expr::BinOp(Box::new(left), op, Box::new(right));
         }
 
+        self.depth = depth;
         Ok(left)
     }
 
+    /// At the `x` operator, or `x` run together with its count as in `x40`
+    /// (but not `x=`)
+    fn at_repeat(&self) -> bool {
+        match self.current() {
+            Token::Ident(word) if word == "x" => self.peek() != &Token::Assign,
+            Token::Ident(word) => word.len() > 1 && word.starts_with('x') && word[1..].bytes().all(|b| b.is_ascii_digit()),
+            _ => false,
+        }
+    }
+
     fn parse_unary(&mut self) -> Result<Expr, String> {
         match self.current() {
             Token::Not | Token::NotWord => {
                 self.advance();
-                let expr = self.parse_unary()?;
+                let expr = self.nested(1, Self::parse_unary)?;
                 Ok(Expr::UnaryOp(UnaryOp::Not, Box::new(expr)))
             }
             Token::Minus => {
                 self.advance();
-                let expr = self.parse_unary()?;
+                let expr = self.nested(1, Self::parse_unary)?;
                 Ok(Expr::UnaryOp(UnaryOp::Neg, Box::new(expr)))
             }
             Token::BitNot => {
                 self.advance();
-                let expr = self.parse_unary()?;
+                let expr = self.nested(1, Self::parse_unary)?;
                 Ok(Expr::UnaryOp(UnaryOp::BitNot, Box::new(expr)))
             }
             Token::Backslash => {
                 self.advance();
-                let expr = self.parse_unary()?;
+                // \&name, a reference to a sub
+                if let (Token::BitAnd, Token::Ident(name)) = (self.current(), self.peek()) {
+                    let name = name.clone();
+                    self.advance();
+                    self.advance();
+                    return Ok(Expr::Ref(Box::new(Expr::Call(name, Vec::new()))));
+                }
+                let expr = self.nested(1, Self::parse_unary)?;
                 Ok(Expr::Ref(Box::new(expr)))
             }
             Token::Increment => {
@@ -625,9 +845,13 @@ impl Parser {
     }
 
     fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
         let mut expr = self.parse_primary()?;
 
         loop {
+            if matches!(self.current(), Token::Increment | Token::Decrement | Token::LBracket | Token::LBrace | Token::Arrow) {
+                self.deeper(1)?;
+            }
             match self.current() {
                 Token::Increment => {
                     self.advance();
@@ -643,6 +867,18 @@ impl Parser {
                     self.expect(Token::RBracket)?;
                     expr = Expr::ArrayIndex(Box::new(expr), Box::new(index));
                 }
+                Token::LBrace if matches!(expr, Expr::ArrayVar(_)) => {
+                    // A slice, `@h{"a", "b"}` or `@h{@keys}`
+                    self.advance();
+                    let mut keys = self.parse_expr_list()?;
+                    self.expect(Token::RBrace)?;
+                    let keys = match keys.as_slice() {
+                        [Expr::ArrayVar(_) | Expr::List(_)] => keys.pop().unwrap(),
+                        _ => Expr::List(keys),
+                    };
+                    let Expr::ArrayVar(name) = expr else { unreachable!() };
+                    expr = Expr::HashSlice(Box::new(Expr::HashVar(name)), Box::new(keys));
+                }
                 Token::LBrace => {
                     self.advance();
                     let key = self.parse_expr()?;
@@ -684,6 +920,7 @@ impl Parser {
             }
         }
 
+        self.depth = depth;
         Ok(expr)
     }
 
@@ -713,6 +950,11 @@ impl Parser {
                 self.advance();
                 Ok(Expr::HashVar(name))
             }
+            Token::Ident(name) if name == "__LINE__" => {
+                let line = self.line();
+                self.advance();
+                Ok(Expr::Integer(line as i32))
+            }
             Token::Ident(name) => {
                 self.advance();
                 if self.at(&Token::LParen) {
@@ -720,15 +962,33 @@ impl Parser {
                     let args = self.parse_expr_list()?;
                     self.expect(Token::RParen)?;
                     Ok(Expr::Call(name, args))
+                } else if matches!(name.as_str(), "exists" | "delete" | "defined" | "undef" | "uc" | "lc") && matches!(self.current(), Token::ScalarVar(_)) {
+                    // Named unary operators: `exists $a[5]`, `defined $x`
+                    let arg = self.nested(1, Self::parse_unary)?;
+                    Ok(Expr::Call(name, vec![arg]))
                 } else {
                     Ok(Expr::Call(name, Vec::new()))
                 }
             }
             Token::LParen => {
                 self.advance();
-                let expr = self.parse_expr()?;
+                let mut items = vec![self.parse_expr()?];
+                while self.at(&Token::Comma) {
+                    self.advance();
+                    if self.at(&Token::RParen) {
+                        break;
+                    }
+                    items.push(self.parse_expr()?);
+                }
                 self.expect(Token::RParen)?;
-                Ok(expr)
+                // Only repetition takes a list in parentheses: `(0) x 8`
+                if self.at_repeat() {
+                    Ok(Expr::List(items))
+                } else if items.len() == 1 {
+                    Ok(items.pop().unwrap())
+                } else {
+                    Err("A list in parentheses needs x after it, as in (0, 1) x 4".to_string())
+                }
             }
             Token::LBracket => {
                 self.advance();
@@ -756,6 +1016,45 @@ impl Parser {
     }
 }
 
+/// Whether `op` is `<`, `>`, `<=`, `>=` or a string one of those, rather
+/// than an equality or `<=>`
+fn relational(op: &BinOp) -> bool {
+    matches!(op, BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::StrLt | BinOp::StrGt | BinOp::StrLe | BinOp::StrGe)
+}
+
+/// Error for `first op right`, where `first` is itself a comparison: Perl
+/// users expect `0 < $x < 10` to test both sides of `$x`
+fn chained(first: &Expr, op: &BinOp, right: &Expr) -> String {
+    let Expr::BinOp(_, first_op, middle) = first else { unreachable!("only a comparison is chained") };
+    let (first, right) = (printer::expr(first), printer::expr(right));
+    let symbol = printer::binop(op).0;
+    // `<=>` and `cmp` give -1, 0 or 1, so no `&&` means the same
+    if let Some(three_way) = [first_op, op].into_iter().find(|op| matches!(op, BinOp::Cmp | BinOp::StrCmp)) {
+        return format!(
+            "Chained comparison `{} {} {}` mixes `{}` with another comparison; put parentheses around the one to do first, as in `({}) {} {}`",
+            first,
+            symbol,
+            right,
+            printer::binop(three_way).0,
+            first,
+            symbol,
+            right
+        );
+    }
+    format!(
+        "Chained comparison `{} {} {}` compares the 1 or 0 of `{}` with {}; write `{} && {} {} {}`",
+        first,
+        symbol,
+        right,
+        first,
+        right,
+        first,
+        printer::expr(middle),
+        symbol,
+        right
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -973,4 +1272,126 @@ mod tests {
             _ => panic!("Expected While statement"),
         }
     }
+
+    #[test]
+    fn test_parse_printf() {
+        let program = parse_program("printf \"%d\\n\", $x;").unwrap();
+        assert_eq!(program.statements[0], Stmt::Printf(None, vec![Expr::String("%d\n".into()), Expr::scalar("x")]));
+        assert_eq!(parse_program("printf;").unwrap_err(), "printf needs a format");
+    }
+
+    #[test]
+    fn test_parse_filehandle() {
+        let program = parse_program("print STDERR \"x\";\nsay STDOUT;\nprintf STDERR \"%d\", 1;\nprint STDERR, 1;").unwrap();
+        assert_eq!(program.statements[0], Stmt::Print(Some(Handle::Stderr), vec![Expr::String("x".into())]));
+        assert_eq!(program.statements[1], Stmt::Say(Some(Handle::Stdout), vec![]));
+        assert!(matches!(&program.statements[2], Stmt::Printf(Some(Handle::Stderr), args) if args.len() == 2));
+        // Followed by a comma it is a constant
+        assert_eq!(program.statements[3], Stmt::Print(None, vec![Expr::call("STDERR", vec![]), Expr::Integer(1)]));
+    }
+
+    #[test]
+    fn test_parse_assert() {
+        let program = parse_program("assert $x;\nassert $x > 1, \"too small\";").unwrap();
+        assert_eq!(program.statements[0], Stmt::Assert(Expr::scalar("x"), None));
+        assert_eq!(
+            program.statements[1],
+            Stmt::Assert(Expr::bin(Expr::scalar("x"), BinOp::Gt, Expr::Integer(1)), Some(Expr::String("too small".into())))
+        );
+        assert!(parse_program("assert;").is_err());
+        assert!(parse_program("assert $x, ;").is_err());
+    }
+
+    #[test]
+    fn test_parse_begin() {
+        let program = parse_program("BEGIN { my $x = 1; }\nprint BEGIN;").unwrap();
+        assert_eq!(program.statements[0], Stmt::Begin(vec![Stmt::My(vec!["x".into()], Some(Expr::Integer(1)))]));
+        // Only a block makes it one
+        assert_eq!(program.statements[1], Stmt::Print(None, vec![Expr::call("BEGIN", Vec::new())]));
+        assert_eq!(program.lines, vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn test_parse_exists_without_parens() {
+        let element = Expr::ArrayIndex(Box::new(Expr::ScalarVar("a".into())), Box::new(Expr::Integer(5)));
+        assert_eq!(
+            parse_expr("exists $a[5] && 1").unwrap(),
+            Expr::BinOp(Box::new(Expr::call("exists", vec![element])), BinOp::And, Box::new(Expr::Integer(1)))
+        );
+    }
+
+    #[test]
+    fn test_parse_line_constant() {
+        let program = parse_program("my $a = 1;\n\nprint __LINE__, __FILE__;").unwrap();
+        assert_eq!(program.statements[1], Stmt::Print(None, vec![Expr::Integer(3), Expr::call("__FILE__", vec![])]));
+    }
+
+    #[test]
+    fn test_parse_native_sub() {
+        let program = parse_program("sub sq($x) :native { return $x * $x; }").unwrap();
+        assert!(matches!(&program.statements[0], Stmt::Sub { name, native: true, .. } if name == "sq"));
+        assert!(parse_program("sub sq($x) :fast { return $x; }").unwrap_err().contains("expected :native"));
+    }
+
+    #[test]
+    fn test_parse_variadic_sub() {
+        let program = parse_program("sub log($level, @values) { }").unwrap();
+        assert!(matches!(&program.statements[0], Stmt::Sub { params, variadic: true, .. } if params == &["level", "values"]));
+        assert_eq!(parse_program("sub f(@a, $b) { }").unwrap_err(), "Array parameter @a must be the last parameter");
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        let dash = Expr::String("-".into());
+        assert_eq!(parse_expr("\"-\" x 4 . 1").unwrap(), Expr::bin(Expr::bin(dash.clone(), BinOp::Repeat, Expr::Integer(4)), BinOp::Concat, Expr::Integer(1)));
+        assert_eq!(parse_expr("\"-\" x40").unwrap(), Expr::bin(dash, BinOp::Repeat, Expr::Integer(40)));
+        assert_eq!(
+            parse_expr("(0, 1) x $n").unwrap(),
+            Expr::bin(Expr::List(vec![Expr::Integer(0), Expr::Integer(1)]), BinOp::Repeat, Expr::scalar("n"))
+        );
+        assert_eq!(parse_expr("(1) + 2").unwrap(), Expr::bin(Expr::Integer(1), BinOp::Add, Expr::Integer(2)));
+        assert_eq!(parse_expr("(1, 2)").unwrap_err(), "A list in parentheses needs x after it, as in (0, 1) x 4");
+        let program = parse_program("$s x= 2;").unwrap();
+        assert_eq!(
+            program.statements[0],
+            Stmt::Expr(Expr::OpAssign(Box::new(Expr::scalar("s")), BinOp::Repeat, Box::new(Expr::Integer(2))))
+        );
+    }
+
+    #[test]
+    fn test_chained_comparison() {
+        assert_eq!(
+            parse_expr("0 < $x < 10").unwrap_err(),
+            "Chained comparison `0 < $x < 10` compares the 1 or 0 of `0 < $x` with 10; write `0 < $x && $x < 10`"
+        );
+        assert!(parse_expr("$a eq $b ne \"\"").unwrap_err().ends_with("write `$a eq $b && $b ne \"\"`"));
+        assert_eq!(
+            parse_expr("$a <=> $b == 0").unwrap_err(),
+            "Chained comparison `$a <=> $b == 0` mixes `<=>` with another comparison; put parentheses around the one to do first, as in `($a <=> $b) == 0`"
+        );
+        assert!(!parse_expr("$a eq $b cmp $c").unwrap_err().contains("&&"));
+        // Across levels the result is compared, as in Perl
+        let lt = Expr::bin(Expr::scalar("a"), BinOp::Lt, Expr::scalar("b"));
+        assert_eq!(parse_expr("$a < $b == $c").unwrap(), Expr::bin(lt, BinOp::Eq, Expr::scalar("c")));
+        assert!(parse_expr("$a lt $b != 0").is_ok());
+        // In parentheses the result is compared on purpose
+        let lt = Expr::bin(Expr::Integer(0), BinOp::Lt, Expr::scalar("x"));
+        assert_eq!(parse_expr("(0 < $x) < 10").unwrap(), Expr::bin(lt.clone(), BinOp::Lt, Expr::Integer(10)));
+        assert_eq!(parse_expr("0 < $x && $x < 10").unwrap(), Expr::bin(lt, BinOp::And, Expr::bin(Expr::scalar("x"), BinOp::Lt, Expr::Integer(10))));
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let deep = format!("print {}1{};", "(".repeat(40), ")".repeat(40));
+        assert_eq!(parse_program(&deep).unwrap_err(), "Program is nested too deeply");
+        let deep = format!("{}print 1;{}", "if (1) { ".repeat(80), " }".repeat(80));
+        assert_eq!(parse_program(&deep).unwrap_err(), "Program is nested too deeply");
+
+        let nested = format!("print {}1{};", "(".repeat(10), ")".repeat(10));
+        assert!(parse_program(&nested).is_ok());
+        let chain = format!("print 1{};", " + 1".repeat(200));
+        assert!(parse_program(&chain).is_ok());
+        let blocks = format!("{}print 1;{}", "if (1) { ".repeat(20), " }".repeat(20));
+        assert!(parse_program(&blocks).is_ok());
+    }
 }
@@ -0,0 +1,262 @@
+//! Binary patches between two images, to update a program kept in
+//! battery-backed RAM without sending the whole image over a slow link
+//!
+//! A patch is the magic `MPP\x01`, the length and CRC-16 of the old image
+//! (little-endian words), then the length and CRC-16 of the new one, then
+//! records of a byte count (1 to 255), an offset word and that many bytes,
+//! ending with a zero count. Offsets are from the start of the image.
+//!
+//! The applier on the target reads the patch from the console in two
+//! steps. After the old image's length and CRC it answers `G` if the image
+//! in memory matches, so the host sends the rest, or `O` if it doesn't.
+//! After the last record it answers `K` if the patched image has the new
+//! CRC, else `C`. A bad magic is answered with `M`.
+
+use crate::asm::{Asm, Cond, Reg16, Reg8, StackReg};
+use crate::z80::{self, RomOptions};
+
+/// First bytes of every patch
+pub const MAGIC: &[u8; 4] = b"MPP\x01";
+
+/// Bytes of patch header: magic, then length and CRC of each image
+pub const HEADER: usize = 12;
+
+/// Unchanged bytes a record runs through rather than start a new record,
+/// which costs three bytes
+const MERGE_GAP: usize = 3;
+
+/// The patch turning `old` into `new`
+pub fn diff(old: &[u8], new: &[u8]) -> Result<Vec<u8>, String> {
+    if old.len() > u16::MAX as usize || new.len() > u16::MAX as usize {
+        return Err("Patches need images of at most 65535 bytes".to_string());
+    }
+    let mut patch = MAGIC.to_vec();
+    for image in [old, new] {
+        patch.extend((image.len() as u16).to_le_bytes());
+        patch.extend(z80::crc16(image).to_le_bytes());
+    }
+
+    // Bytes past the end of the old image always count as changed
+    let changed = |i: usize| old.get(i) != Some(&new[i]);
+    let mut i = 0;
+    while i < new.len() {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i + 1;
+        while end < new.len() && end - start < u8::MAX as usize {
+            if changed(end) {
+                end += 1;
+            } else if (end..new.len().min(end + MERGE_GAP)).any(changed) {
+                let next = (end..new.len()).find(|&j| changed(j)).unwrap();
+                end = next.min(start + u8::MAX as usize);
+            } else {
+                break;
+            }
+        }
+        patch.push((end - start) as u8);
+        patch.extend((start as u16).to_le_bytes());
+        patch.extend_from_slice(&new[start..end]);
+        i = end;
+    }
+    patch.push(0);
+    Ok(patch)
+}
+
+/// Apply `patch` to `old` as the applier does, checking both CRCs
+pub fn apply(old: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < HEADER + 1 || &patch[..4] != MAGIC {
+        return Err("Not a MicroPerl patch".to_string());
+    }
+    let word = |at: usize| u16::from_le_bytes([patch[at], patch[at + 1]]);
+    let (old_len, new_len) = (word(4) as usize, word(8) as usize);
+    if old.len() < old_len || z80::crc16(&old[..old_len]) != word(6) {
+        return Err("The patch is for a different image".to_string());
+    }
+    let mut image = old.to_vec();
+    image.resize(image.len().max(new_len), 0);
+    let mut at = HEADER;
+    loop {
+        let count = *patch.get(at).ok_or("The patch ends early")? as usize;
+        if count == 0 {
+            break;
+        }
+        let record = patch.get(at + 3..at + 3 + count).ok_or("The patch ends early")?;
+        let offset = word(at + 1) as usize;
+        if offset + count > image.len() {
+            image.resize(offset + count, 0);
+        }
+        image[offset..offset + count].copy_from_slice(record);
+        at += 3 + count;
+    }
+    image.truncate(new_len);
+    if z80::crc16(&image) != word(10) {
+        return Err("The patched image has the wrong CRC".to_string());
+    }
+    Ok(image)
+}
+
+/// The applier for `options`' console, to run at `org` and patch the image
+/// loaded at `base`. Call it from a monitor; it returns with A = 0 when the
+/// image was patched, else 1 for a bad magic, 2 for a different old image
+/// and 3 for a bad CRC after patching, having answered the host as well.
+pub fn applier(options: &RomOptions, org: u16, base: u16) -> Result<Vec<u8>, String> {
+    if options.target.hosted() {
+        return Err("The patch applier needs the retroshield, rc2014-acia or rc2014-sio target".to_string());
+    }
+    let console = options.target.console();
+    let mut a = Asm::new(org);
+    let (getc, putc, get_word, crc) = (a.label("getc"), a.label("putc"), a.label("get_word"), a.label("crc16"));
+    let (magic, bad_magic, bad_old, bad_new, reply) =
+        (a.label("magic"), a.label("bad_magic"), a.label("bad_old"), a.label("bad_new"), a.label("reply"));
+
+    // Console input is polled
+    a.di();
+    a.ld_label(Reg16::HL, magic);
+    a.ld_n(Reg8::B, MAGIC.len() as u8);
+    let magic_loop = a.here_label("magic_loop");
+    a.call(getc);
+    a.alu(crate::asm::Alu::Cp, Reg8::HLInd);
+    a.jr_cc(Cond::NZ, bad_magic);
+    a.inc16(Reg16::HL);
+    a.djnz(magic_loop);
+
+    a.call(get_word);
+    a.ld(Reg8::B, Reg8::H);
+    a.ld(Reg8::C, Reg8::L);
+    a.call(get_word);
+    a.push(StackReg::HL);
+    a.ld_nn(Reg16::HL, base);
+    a.call(crc);
+    a.pop(StackReg::HL);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NZ, bad_old);
+    a.ld_n(Reg8::A, b'G');
+    console.emit_write(&mut a, putc);
+
+    // New length, then CRC, stay on the stack through the records
+    a.call(get_word);
+    a.push(StackReg::HL);
+    a.call(get_word);
+    a.push(StackReg::HL);
+    let record = a.here_label("record");
+    let records_done = a.label("records_done");
+    a.call(getc);
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, records_done);
+    a.ld(Reg8::B, Reg8::A);
+    a.call(get_word);
+    a.ld_nn(Reg16::DE, base);
+    a.add_hl(Reg16::DE);
+    let data = a.here_label("data");
+    a.call(getc);
+    a.ld(Reg8::HLInd, Reg8::A);
+    a.inc16(Reg16::HL);
+    a.djnz(data);
+    a.jr(record);
+
+    a.bind(records_done);
+    a.pop(StackReg::DE);
+    a.pop(StackReg::BC);
+    a.push(StackReg::DE);
+    a.ld_nn(Reg16::HL, base);
+    a.call(crc);
+    a.pop(StackReg::HL);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NZ, bad_new);
+    a.ld_nn(Reg16::BC, u16::from_le_bytes([b'K', 0]));
+    a.jr(reply);
+
+    a.bind(bad_magic);
+    a.ld_nn(Reg16::BC, u16::from_le_bytes([b'M', 1]));
+    a.jr(reply);
+    a.bind(bad_old);
+    a.ld_nn(Reg16::BC, u16::from_le_bytes([b'O', 2]));
+    a.jr(reply);
+    a.bind(bad_new);
+    a.ld_nn(Reg16::BC, u16::from_le_bytes([b'C', 3]));
+    // Answer with C, return B
+    a.bind(reply);
+    a.ld(Reg8::A, Reg8::C);
+    console.emit_write(&mut a, putc);
+    a.ld(Reg8::A, Reg8::B);
+    a.ret();
+
+    // HL = the next two bytes, low first
+    a.bind(get_word);
+    a.call(getc);
+    a.ld(Reg8::L, Reg8::A);
+    a.call(getc);
+    a.ld(Reg8::H, Reg8::A);
+    a.ret();
+
+    a.bind(crc);
+    z80::emit_crc16(&mut a);
+    a.ret();
+
+    a.bind(getc);
+    console.emit_getc(&mut a, getc, putc);
+    console.emit_putc(&mut a, putc);
+    a.bind(magic);
+    a.defb(MAGIC);
+    Ok(a.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_and_apply() {
+        let old: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut new = old.clone();
+        new[10] = 0;
+        new[12] = 0;
+        new[500..800].fill(7);
+        new.extend([1, 2, 3]);
+        let patch = diff(&old, &new).unwrap();
+        // Records for 10..13, 500..755, 755..800 and 1000..1003
+        assert_eq!(patch.len(), HEADER + (3 + 3) + (3 + 255) + (3 + 45) + (3 + 3) + 1);
+        assert_eq!(apply(&old, &patch).unwrap(), new);
+
+        assert_eq!(diff(&old, &old).unwrap().len(), HEADER + 1);
+        assert_eq!(apply(&old, &diff(&old, &old[..10]).unwrap()).unwrap(), &old[..10]);
+        assert_eq!(apply(&new, &patch).unwrap_err(), "The patch is for a different image");
+        assert_eq!(apply(&old, b"MPQ\x01").unwrap_err(), "Not a MicroPerl patch");
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn test_applier() {
+        use crate::z80emu::{Console, Exit, Machine};
+
+        let old = b"MPL\x01 the old program".to_vec();
+        let new = b"MPL\x01 the new program, longer".to_vec();
+        let patch = diff(&old, &new).unwrap();
+        let options = RomOptions::default();
+        // A monitor at 0 calls the applier at 0x100, which patches 0x2000
+        let run = |memory: &[u8], patch: &[u8]| {
+            let mut rom = vec![0x31, 0xFE, 0xFF, 0xCD, 0x00, 0x01, 0x76];
+            rom.resize(0x100, 0);
+            rom.extend(applier(&options, 0x100, 0x2000).unwrap());
+            let mut machine = Machine::new(&rom, Console::scripted(patch));
+            for (i, &b) in memory.iter().enumerate() {
+                machine.write(0x2000 + i as u16, b);
+            }
+            assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+            let image: Vec<u8> = (0..new.len() as u16).map(|i| machine.read(0x2000 + i)).collect();
+            (String::from_utf8_lossy(machine.io.output()).into_owned(), machine.cpu.a, image)
+        };
+        assert_eq!(run(&old, &patch), ("GK".to_string(), 0, new.clone()));
+        assert_eq!(run(&new, &patch).0, "O");
+        assert_eq!(run(&old, b"MPX").0, "M");
+
+        let mut corrupt = patch.clone();
+        corrupt[HEADER + 3] ^= 1;
+        assert_eq!(run(&old, &corrupt).0, "GC");
+    }
+}
@@ -0,0 +1,239 @@
+//! Browser playground API
+//!
+//! Compile, disassemble and run a program on the host VM, with every
+//! result a JSON document or text, so a page can drive the compiler
+//! without a server. On wasm32 the functions are exported to JavaScript
+//! (see `web/microperl.js`): strings go in through memory from
+//! `mpl_alloc`, and each call leaves its result in a buffer read through
+//! `mpl_result`.
+
+use crate::budget;
+use crate::bytecode;
+use crate::json::Json;
+use crate::render;
+use crate::vm::{Exit, Vm};
+use crate::z80emu::Console;
+use crate::{compile_source, debugger, Artifacts, Diagnostics, Options};
+
+/// Instructions run before giving up, so a loop can't hang the page
+pub const DEFAULT_STEPS: u64 = 10_000_000;
+
+/// File name used in diagnostics
+const FILE: &str = "playground.mpl";
+
+/// `source` compiled: whether it worked, the diagnostics and the ROM size
+/// breakdown
+pub fn compile(source: &str) -> String {
+    match compile_source(source, Options::default()) {
+        Ok(artifacts) => {
+            let size = budget::measure(&artifacts.module, &Options::default().rom);
+            let size = Json::object([
+                ("runtime", size.runtime.into()),
+                ("padding", size.padding.into()),
+                ("header", size.header.into()),
+                ("bytecode", size.bytecode.into()),
+                ("strings", size.strings.into()),
+                ("total", size.total().into()),
+            ]);
+            Json::object([("ok", Json::Bool(true)), ("diagnostics", Json::Array(Vec::new())), ("size", size)])
+        }
+        Err(diagnostics) => failed(source, &diagnostics),
+    }
+    .to_string()
+}
+
+/// Bytecode listing of `source`, as `--bytecode` prints it, or the
+/// rendered diagnostics
+pub fn disassemble(source: &str) -> String {
+    match compile_source(source, Options::default()) {
+        Ok(Artifacts { module, .. }) => {
+            let mut out = String::from("String constants:\n");
+            for (i, s) in module.strings.iter().enumerate() {
+                out.push_str(&format!("  [{}] {:?}\n", i, s));
+            }
+            out.push_str("\nSubroutines:\n");
+            for (name, addr, params) in &module.subs {
+                out.push_str(&format!("  {} @ 0x{:04X} ({} params)\n", name, addr, params));
+            }
+            out.push_str(&format!("\nBytecode ({} bytes):\n", module.code.len()));
+            out.push_str(&bytecode::disassemble(&module.code));
+            out
+        }
+        Err(diagnostics) => rendered(source, &diagnostics),
+    }
+}
+
+/// `source` run on the host VM with `input` as the console input, for at
+/// most `max_steps` instructions: the output and STDERR output, how it
+/// stopped and any runtime error
+pub fn run(source: &str, input: &str, max_steps: u64) -> String {
+    let artifacts = match compile_source(source, Options::default()) {
+        Ok(artifacts) => artifacts,
+        Err(diagnostics) => return failed(source, &diagnostics).to_string(),
+    };
+    let mut vm = Vm::new(&artifacts.module, Console::scripted(input.as_bytes()));
+    let result = vm.run(Some(max_steps));
+    let (exit, error) = match result {
+        Ok(Exit::Halted) => ("halted", Json::Null),
+        Ok(Exit::StepLimit) => ("step-limit", Json::Null),
+        Ok(Exit::InputExhausted) => ("input-exhausted", Json::Null),
+        Err(e) => {
+            let at = debugger::error_location(&artifacts.module, FILE, vm.pc.wrapping_sub(1));
+            ("error", format!("{}Runtime error: {}", at, e).as_str().into())
+        }
+    };
+    Json::object([
+        ("ok", Json::Bool(exit == "halted")),
+        ("diagnostics", Json::Array(Vec::new())),
+        ("output", String::from_utf8_lossy(vm.io.output()).as_ref().into()),
+        ("errors", String::from_utf8_lossy(vm.io.errors()).as_ref().into()),
+        ("exit", exit.into()),
+        ("error", error),
+        ("steps", Json::Number(vm.steps as f64)),
+    ])
+    .to_string()
+}
+
+fn failed(source: &str, diagnostics: &Diagnostics) -> Json {
+    Json::object([
+        ("ok", Json::Bool(false)),
+        ("diagnostics", Json::Array(diagnostics.iter().map(|d| d.json(FILE)).collect())),
+        ("rendered", rendered(source, diagnostics).as_str().into()),
+    ])
+}
+
+fn rendered(source: &str, diagnostics: &Diagnostics) -> String {
+    diagnostics.iter().map(|d| render::render(d, FILE, source, false)).collect()
+}
+
+#[cfg(target_arch = "wasm32")]
+mod exports {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static RESULT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Keep `text` for `mpl_result`, returning its length
+    fn keep(text: String) -> usize {
+        RESULT.with(|result| {
+            *result.borrow_mut() = text.into_bytes();
+            result.borrow().len()
+        })
+    }
+
+    /// # Safety
+    /// `ptr` must point to `len` readable bytes.
+    unsafe fn text(ptr: *const u8, len: usize) -> String {
+        match len {
+            0 => String::new(),
+            _ => String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).into_owned(),
+        }
+    }
+
+    /// Space for `len` bytes of input, released with `mpl_free`
+    #[no_mangle]
+    pub extern "C" fn mpl_alloc(len: usize) -> *mut u8 {
+        let mut buffer = Vec::<u8>::with_capacity(len);
+        let ptr = buffer.as_mut_ptr();
+        std::mem::forget(buffer);
+        ptr
+    }
+
+    /// # Safety
+    /// `ptr` and `len` must come from one call of `mpl_alloc`.
+    #[no_mangle]
+    pub unsafe extern "C" fn mpl_free(ptr: *mut u8, len: usize) {
+        drop(Vec::from_raw_parts(ptr, 0, len));
+    }
+
+    /// The result of the last call, `len` bytes of UTF-8
+    #[no_mangle]
+    pub extern "C" fn mpl_result() -> *const u8 {
+        RESULT.with(|result| result.borrow().as_ptr())
+    }
+
+    /// # Safety
+    /// `source` must point to `len` readable bytes.
+    #[no_mangle]
+    pub unsafe extern "C" fn mpl_compile(source: *const u8, len: usize) -> usize {
+        keep(super::compile(&text(source, len)))
+    }
+
+    /// # Safety
+    /// `source` must point to `len` readable bytes.
+    #[no_mangle]
+    pub unsafe extern "C" fn mpl_disassemble(source: *const u8, len: usize) -> usize {
+        keep(super::disassemble(&text(source, len)))
+    }
+
+    /// Run the program; `max_steps` of 0 means `DEFAULT_STEPS`
+    ///
+    /// # Safety
+    /// `source` and `input` must point to `source_len` and `input_len`
+    /// readable bytes.
+    #[no_mangle]
+    pub unsafe extern "C" fn mpl_run(
+        source: *const u8,
+        source_len: usize,
+        input: *const u8,
+        input_len: usize,
+        max_steps: u32,
+    ) -> usize {
+        let steps = match max_steps {
+            0 => super::DEFAULT_STEPS,
+            n => n as u64,
+        };
+        keep(super::run(&text(source, source_len), &text(input, input_len), steps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn test_compile() {
+        let result = json::parse(&compile("print \"hi\", 1 + 2;")).unwrap();
+        assert_eq!(result.get("ok"), Some(&Json::Bool(true)));
+        let size = result.get("size").unwrap();
+        assert_eq!(size.get("strings").and_then(Json::as_usize), Some(1 + 1 + 2));
+
+        let result = json::parse(&compile("print $x;")).unwrap();
+        assert_eq!(result.get("ok"), Some(&Json::Bool(false)));
+        let diagnostic = &result.get("diagnostics").and_then(Json::as_array).unwrap()[0];
+        assert_eq!(diagnostic.get("code").and_then(Json::as_str), Some("undefined-variable"));
+        let rendered = result.get("rendered").and_then(Json::as_str).unwrap();
+        assert!(rendered.starts_with("error[undefined-variable]: Undefined variable: $x\n --> playground.mpl:1:"));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let listing = disassemble("print \"a\";");
+        assert!(listing.starts_with("String constants:\n  [0] \"a\"\n"));
+        assert!(listing.contains("\nBytecode ("));
+        assert!(listing.contains(": PushStr 0x0000\n"));
+        assert!(disassemble("print 1 ` 2;").starts_with("error[unexpected-character]"));
+    }
+
+    #[test]
+    fn test_run() {
+        let result = json::parse(&run("my $s = \"abc\";\nprint \"got \", $s, \"\\n\";", "", DEFAULT_STEPS)).unwrap();
+        assert_eq!(result.get("exit").and_then(Json::as_str), Some("halted"));
+        assert_eq!(result.get("output").and_then(Json::as_str), Some("got abc\n"));
+
+        let result = json::parse(&run("print STDERR \"oops\";\nprint \"fine\";", "", DEFAULT_STEPS)).unwrap();
+        assert_eq!(result.get("output").and_then(Json::as_str), Some("fine"));
+        assert_eq!(result.get("errors").and_then(Json::as_str), Some("oops"));
+
+        let result = json::parse(&run("while (1) { }", "", 1000)).unwrap();
+        assert_eq!(result.get("ok"), Some(&Json::Bool(false)));
+        assert_eq!(result.get("exit").and_then(Json::as_str), Some("step-limit"));
+        assert_eq!(result.get("steps").and_then(Json::as_usize), Some(1000));
+
+        let result = json::parse(&run("my $z = 0;\nprint 1 / $z;", "", DEFAULT_STEPS)).unwrap();
+        assert_eq!(result.get("exit").and_then(Json::as_str), Some("error"));
+        assert!(result.get("error").and_then(Json::as_str).unwrap().starts_with("playground.mpl:2: Runtime error: "));
+    }
+}
@@ -0,0 +1,532 @@
+//! AST to source printer
+//!
+//! `program` prints a parsed program back as canonical MicroPerl: four
+//! space indentation, one statement per line, spaces around binary
+//! operators and parentheses only where precedence needs them. Parsing the
+//! output gives the same AST. `format` does the same for source text and
+//! keeps its comments and single blank lines, which is what `microperl fmt`
+//! runs.
+//!
+//! The AST does not record the sigils of `my` and `our` lists or whether
+//! `and`/`or`/`not` were spelled as words, so those come out as `$name`,
+//! `&&`, `||` and `!`.
+
+use std::collections::BTreeMap;
+
+use crate::ast::{BinOp, Expr, Handle, Program, Stmt, UnaryOp};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+
+const INDENT: &str = "    ";
+
+/// Binding strength of each level of the parser's expression grammar
+const ASSIGN: u8 = 1;
+const TERNARY: u8 = 2;
+const OR: u8 = 3;
+const AND: u8 = 4;
+const COMPARE: u8 = 5;
+const ADD: u8 = 6;
+const MUL: u8 = 7;
+const UNARY: u8 = 8;
+const POSTFIX: u8 = 9;
+const PRIMARY: u8 = 10;
+
+/// Source for `program`, without comments
+pub fn program(program: &Program) -> String {
+    let mut printer = Printer::new(program, "");
+    printer.block(&program.statements);
+    printer.out
+}
+
+/// Source for one expression
+pub fn expr(e: &Expr) -> String {
+    expr_at(e, ASSIGN)
+}
+
+/// Reformat `source`, keeping its comments
+pub fn format(source: &str) -> Result<String, String> {
+    let mut lexer = Lexer::new(source);
+    let tokens = lexer.tokenize();
+    // Formatting what was read so far would drop the rest of the file
+    if let Some((c, line, _)) = lexer.unknown() {
+        return Err(format!("line {}: Unexpected character {:?}", line, c));
+    }
+    let mut parser = Parser::new(tokens);
+    let parsed = parser.parse().map_err(|e| format!("line {}: {}", parser.location().0, e))?;
+    let mut printer = Printer::new(&parsed, source);
+    printer.block(&parsed.statements);
+    printer.flush_comments(usize::MAX, None);
+    Ok(printer.out)
+}
+
+/// A comment on a line of its own, or after code
+#[derive(Debug, Clone, PartialEq)]
+enum Comment {
+    Line(String),
+    Trailing(String),
+}
+
+struct Printer<'a> {
+    out: String,
+    /// Source lines, empty when printing without comments
+    source: Vec<&'a str>,
+    /// Statement lines in pre-order, from the parser
+    lines: &'a [usize],
+    next: usize,
+    /// Comments not printed yet, by 1-based line
+    comments: BTreeMap<usize, Comment>,
+    depth: usize,
+    /// Nothing printed yet in the current block
+    block_start: bool,
+}
+
+impl<'a> Printer<'a> {
+    fn new(program: &'a Program, source: &'a str) -> Self {
+        let source: Vec<&str> = source.lines().collect();
+        let comments = source.iter().enumerate()
+            .filter_map(|(i, text)| comment(text).map(|c| (i + 1, c)))
+            .collect();
+        Printer {
+            out: String::new(),
+            source,
+            lines: &program.lines,
+            next: 0,
+            comments,
+            depth: 0,
+            block_start: true,
+        }
+    }
+
+    /// Write one line at the current indentation
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+        self.block_start = false;
+    }
+
+    /// Write a statement's first line, followed by any comment after it in
+    /// the source
+    fn header(&mut self, text: &str, line: Option<usize>) {
+        match line.and_then(|l| self.comments.get(&l)) {
+            Some(Comment::Trailing(c)) => {
+                let text = format!("{}  {}", text, c);
+                self.comments.remove(&line.unwrap());
+                self.line(&text);
+            }
+            _ => self.line(text),
+        }
+    }
+
+    /// Keep a blank line above `line` if the source has one
+    fn spacing(&mut self, line: usize) {
+        let blank_above = line >= 2 && self.source.get(line - 2).is_some_and(|l| l.trim().is_empty());
+        if blank_above && !self.block_start {
+            self.out.push('\n');
+        }
+    }
+
+    /// Print the comments above source line `before`. With `indent`, only
+    /// those indented deeper than that: the ones still inside a block.
+    fn flush_comments(&mut self, before: usize, indent: Option<usize>) {
+        let pending: Vec<usize> = self.comments.range(..before).map(|(&l, _)| l).collect();
+        for l in pending {
+            let inside = indent.is_none_or(|i| indentation(self.source[l - 1]) > i);
+            if !inside {
+                break;
+            }
+            let text = match self.comments.remove(&l) {
+                Some(Comment::Line(text) | Comment::Trailing(text)) => text,
+                None => continue,
+            };
+            self.spacing(l);
+            self.line(&text);
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        for s in stmts {
+            self.stmt(s);
+        }
+    }
+
+    /// `{`, the statements, then `}` with `close` after it
+    fn body(&mut self, header: &str, line: Option<usize>, stmts: &[Stmt]) {
+        self.header(&format!("{} {{", header), line);
+        self.depth += 1;
+        self.block_start = true;
+        self.block(stmts);
+        // Comments before the closing brace stay in the block
+        let next = self.lines.get(self.next).copied().unwrap_or(usize::MAX);
+        let indent = line.map_or(0, |l| indentation(self.source.get(l - 1).unwrap_or(&"")));
+        self.flush_comments(next, Some(indent));
+        self.depth -= 1;
+    }
+
+    fn stmt(&mut self, s: &Stmt) {
+        let line = self.lines.get(self.next).copied();
+        self.next += 1;
+        if let Some(l) = line {
+            self.flush_comments(l, None);
+            self.spacing(l);
+        }
+
+        match s {
+            Stmt::If { cond, then_block, elsif_blocks, else_block } => {
+                self.body(&format!("if ({})", expr(cond)), line, then_block);
+                for (cond, body) in elsif_blocks {
+                    let line = self.lines.get(self.next).copied();
+                    self.next += 1;
+                    self.body(&format!("}} elsif ({})", expr(cond)), line, body);
+                }
+                if let Some(body) = else_block {
+                    self.body("} else", None, body);
+                }
+                self.line("}");
+            }
+            Stmt::Unless { cond, then_block, else_block } => {
+                self.body(&format!("unless ({})", expr(cond)), line, then_block);
+                if let Some(body) = else_block {
+                    self.body("} else", None, body);
+                }
+                self.line("}");
+            }
+            Stmt::While { cond, body } => {
+                self.body(&format!("while ({})", expr(cond)), line, body);
+                self.line("}");
+            }
+            Stmt::Until { cond, body } => {
+                self.body(&format!("until ({})", expr(cond)), line, body);
+                self.line("}");
+            }
+            Stmt::For { init, cond, step, body } => {
+                // The initialiser is a statement of its own
+                let init = match init {
+                    Some(init) => {
+                        self.next += 1;
+                        simple_stmt(init).unwrap_or_default()
+                    }
+                    None => ";".to_string(),
+                };
+                let cond = cond.as_ref().map_or_else(String::new, |c| format!(" {}", expr(c)));
+                let step = step.as_ref().map_or_else(String::new, |s| format!(" {}", expr(s)));
+                self.body(&format!("for ({}{};{})", init, cond, step), line, body);
+                self.line("}");
+            }
+            Stmt::Foreach { var, list, body } => {
+                self.body(&format!("foreach my ${} ({})", var, expr(list)), line, body);
+                self.line("}");
+            }
+            Stmt::Sub { name, params, body, native, variadic } => {
+                let mut header = if params.is_empty() {
+                    format!("sub {}", name)
+                } else {
+                    let last = params.len() - 1;
+                    let params: Vec<String> = params
+                        .iter()
+                        .enumerate()
+                        .map(|(i, p)| format!("{}{}", if *variadic && i == last { '@' } else { '$' }, p))
+                        .collect();
+                    format!("sub {}({})", name, params.join(", "))
+                };
+                if *native {
+                    header.push_str(" :native");
+                }
+                self.body(&header, line, body);
+                self.line("}");
+            }
+            Stmt::Begin(body) => {
+                self.body("BEGIN", line, body);
+                self.line("}");
+            }
+            Stmt::Block(body) => {
+                self.header("{", line);
+                self.depth += 1;
+                self.block_start = true;
+                self.block(body);
+                self.depth -= 1;
+                self.line("}");
+            }
+            _ => {
+                let text = simple_stmt(s).unwrap_or_default();
+                self.header(&text, line);
+            }
+        }
+    }
+}
+
+/// One-line statements, ending in `;`
+fn simple_stmt(s: &Stmt) -> Option<String> {
+    let text = match s {
+        Stmt::Expr(e) => {
+            // A leading brace would start a block
+            let text = expr(e);
+            if text.starts_with('{') {
+                format!("({});", text)
+            } else {
+                format!("{};", text)
+            }
+        }
+        Stmt::My(vars, init) => declaration("my", vars, init),
+        Stmt::Our(vars, init) => declaration("our", vars, init),
+        Stmt::Last => "last;".to_string(),
+        Stmt::Next => "next;".to_string(),
+        Stmt::Return(None) => "return;".to_string(),
+        Stmt::Return(Some(e)) => format!("return {};", expr(e)),
+        Stmt::Print(handle, args) => list_stmt("print", *handle, args),
+        Stmt::Say(handle, args) => list_stmt("say", *handle, args),
+        Stmt::Printf(handle, args) => list_stmt("printf", *handle, args),
+        Stmt::Assert(cond, None) => format!("assert {};", expr(cond)),
+        Stmt::Assert(cond, Some(message)) => format!("assert {}, {};", expr(cond), expr(message)),
+        Stmt::Use(name) => format!("use {};", name),
+        Stmt::Constant(name, value) => format!("use constant {} => {};", name, expr(value)),
+        Stmt::Package(name) => format!("package {};", name),
+        Stmt::Include { file, once } => format!("{} {};", if *once { "require" } else { "do" }, expr(&Expr::String(file.clone()))),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn declaration(keyword: &str, vars: &[String], init: &Option<Expr>) -> String {
+    let vars: Vec<String> = vars.iter().map(|v| format!("${}", v)).collect();
+    let vars = if vars.len() == 1 { vars[0].clone() } else { format!("({})", vars.join(", ")) };
+    match init {
+        Some(e) => format!("{} {} = {};", keyword, vars, expr(e)),
+        None => format!("{} {};", keyword, vars),
+    }
+}
+
+fn list_stmt(keyword: &str, handle: Option<Handle>, args: &[Expr]) -> String {
+    let keyword = match handle {
+        Some(handle) => format!("{} {}", keyword, handle.name()),
+        None => keyword.to_string(),
+    };
+    if args.is_empty() {
+        format!("{};", keyword)
+    } else {
+        format!("{} {};", keyword, list(args))
+    }
+}
+
+fn list(items: &[Expr]) -> String {
+    items.iter().map(expr).collect::<Vec<_>>().join(", ")
+}
+
+/// `e`, in parentheses if it binds less tightly than `min`
+fn expr_at(e: &Expr, min: u8) -> String {
+    let (text, level) = match e {
+        Expr::Integer(n) => (n.to_string(), PRIMARY),
+        Expr::Float(f) => (format!("{:?}", f), PRIMARY),
+        Expr::String(s) => (string(s), PRIMARY),
+        Expr::ScalarVar(name) => (format!("${}", name), PRIMARY),
+        Expr::ArrayVar(name) => (format!("@{}", name), PRIMARY),
+        Expr::HashVar(name) => (format!("%{}", name), PRIMARY),
+        Expr::Call(name, args) => (format!("{}({})", name, list(args)), PRIMARY),
+        Expr::List(items) => (format!("[{}]", list(items)), PRIMARY),
+        Expr::Hash(pairs) => {
+            let pairs: Vec<String> = pairs.iter().map(|(k, v)| format!("{} => {}", expr(k), expr(v))).collect();
+            (format!("{{{}}}", pairs.join(", ")), PRIMARY)
+        }
+
+        Expr::ArrayIndex(base, index) => match base.as_ref() {
+            Expr::Deref(inner) => (format!("{}->[{}]", expr_at(inner, POSTFIX), expr(index)), POSTFIX),
+            _ => (format!("{}[{}]", expr_at(base, POSTFIX), expr(index)), POSTFIX),
+        },
+        Expr::HashIndex(base, key) => match base.as_ref() {
+            Expr::Deref(inner) => (format!("{}->{{{}}}", expr_at(inner, POSTFIX), expr(key)), POSTFIX),
+            _ => (format!("{}{{{}}}", expr_at(base, POSTFIX), expr(key)), POSTFIX),
+        },
+        Expr::HashSlice(hash, keys) => {
+            let name = match hash.as_ref() {
+                Expr::HashVar(name) => name.clone(),
+                other => format!("{{{}}}", expr(other)),
+            };
+            let keys = match keys.as_ref() {
+                Expr::List(items) => list(items),
+                other => expr(other),
+            };
+            (format!("@{}{{{}}}", name, keys), POSTFIX)
+        }
+        Expr::MethodCall(obj, name, args) => {
+            (format!("{}->{}({})", expr_at(obj, POSTFIX), name, list(args)), POSTFIX)
+        }
+        Expr::PostIncrement(e) => (format!("{}++", expr_at(e, POSTFIX)), POSTFIX),
+        Expr::PostDecrement(e) => (format!("{}--", expr_at(e, POSTFIX)), POSTFIX),
+        Expr::Deref(e) => (format!("${{{}}}", expr(e)), POSTFIX),
+
+        Expr::PreIncrement(e) => (format!("++{}", expr_at(e, POSTFIX)), UNARY),
+        Expr::PreDecrement(e) => (format!("--{}", expr_at(e, POSTFIX)), UNARY),
+        Expr::UnaryOp(op, e) => {
+            let op = match op {
+                UnaryOp::Neg => "-",
+                UnaryOp::Not => "!",
+                UnaryOp::BitNot => "~",
+                UnaryOp::Ref => "\\",
+            };
+            let operand = expr_at(e, UNARY);
+            // Keep `- -$x` and `! ~$x` from reading as `--$x` and `!~`
+            let space = if operand.starts_with(op) || (op == "!" && operand.starts_with('~')) { " " } else { "" };
+            (format!("{}{}{}", op, space, operand), UNARY)
+        }
+        Expr::Ref(e) => match e.as_ref() {
+            Expr::Call(name, args) if args.is_empty() => (format!("\\&{}", name), UNARY),
+            _ => (format!("\\{}", expr_at(e, UNARY)), UNARY),
+        },
+
+        Expr::BinOp(left, op, right) => {
+            let (symbol, level) = binop(op);
+            // Comparisons don't chain, so one compared again needs parentheses
+            let left_level = if level == COMPARE { level + 1 } else { level };
+            (format!("{} {} {}", expr_at(left, left_level), symbol, expr_at(right, level + 1)), level)
+        }
+        Expr::Match(subject, pattern, flags) => {
+            (format!("{} =~ /{}/{}", expr_at(subject, COMPARE), pattern, flags), COMPARE)
+        }
+        Expr::NotMatch(subject, pattern, flags) => {
+            (format!("{} !~ /{}/{}", expr_at(subject, COMPARE), pattern, flags), COMPARE)
+        }
+        Expr::Range(from, to) => (format!("{} .. {}", expr_at(from, ADD), expr_at(to, ADD)), COMPARE),
+
+        Expr::Ternary(cond, then, other) => {
+            (format!("{} ? {} : {}", expr_at(cond, OR), expr(then), expr_at(other, TERNARY)), TERNARY)
+        }
+        Expr::Assign(target, value) => {
+            (format!("{} = {}", expr_at(target, TERNARY), expr_at(value, ASSIGN)), ASSIGN)
+        }
+        Expr::OpAssign(target, op, value) => {
+            let symbol = binop(op).0;
+            (format!("{} {}= {}", expr_at(target, TERNARY), symbol, expr_at(value, ASSIGN)), ASSIGN)
+        }
+    };
+    if level < min {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+/// Operator symbol and level. Operators the parser has no syntax for yet
+/// get the level of their closest relative.
+pub(crate) fn binop(op: &BinOp) -> (&'static str, u8) {
+    match op {
+        BinOp::Or => ("||", OR),
+        BinOp::And => ("&&", AND),
+        BinOp::Eq => ("==", COMPARE),
+        BinOp::Ne => ("!=", COMPARE),
+        BinOp::Lt => ("<", COMPARE),
+        BinOp::Gt => (">", COMPARE),
+        BinOp::Le => ("<=", COMPARE),
+        BinOp::Ge => (">=", COMPARE),
+        BinOp::Cmp => ("<=>", COMPARE),
+        BinOp::StrEq => ("eq", COMPARE),
+        BinOp::StrNe => ("ne", COMPARE),
+        BinOp::StrLt => ("lt", COMPARE),
+        BinOp::StrGt => ("gt", COMPARE),
+        BinOp::StrLe => ("le", COMPARE),
+        BinOp::StrGe => ("ge", COMPARE),
+        BinOp::StrCmp => ("cmp", COMPARE),
+        BinOp::Add => ("+", ADD),
+        BinOp::Sub => ("-", ADD),
+        BinOp::Concat => (".", ADD),
+        BinOp::Mul => ("*", MUL),
+        BinOp::Div => ("/", MUL),
+        BinOp::Mod => ("%", MUL),
+        BinOp::Repeat => ("x", MUL),
+        BinOp::Pow => ("**", MUL),
+        BinOp::BitAnd => ("&", AND),
+        BinOp::BitOr => ("|", OR),
+        BinOp::BitXor => ("^", OR),
+        BinOp::ShiftLeft => ("<<", ADD),
+        BinOp::ShiftRight => (">>", ADD),
+    }
+}
+
+/// Double-quoted string literal for `s`
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The comment on a source line, if any. A `#` starts a comment when the
+/// code before it lexes to the same tokens as the whole line, so a `#` in
+/// a string or regex doesn't count.
+fn comment(text: &str) -> Option<Comment> {
+    let trimmed = text.trim_start();
+    if trimmed.starts_with('#') {
+        return Some(Comment::Line(trimmed.trim_end().to_string()));
+    }
+    let tokens = |s: &str| Lexer::new(s).tokenize().into_iter().map(|t| t.token).collect::<Vec<_>>();
+    let whole = tokens(text);
+    text.match_indices('#')
+        .map(|(i, _)| i)
+        .find(|&i| tokens(&text[..i]) == whole)
+        .map(|i| Comment::Trailing(text[i..].trim_end().to_string()))
+}
+
+fn indentation(text: &str) -> usize {
+    text.len() - text.trim_start().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Program {
+        Parser::new(Lexer::new(source).tokenize()).parse().unwrap()
+    }
+
+    #[test]
+    fn test_canonical_layout() {
+        let source = "my $i=0;while($i<3){print $i,\"\\n\";$i++;}\nsub f($a,$b){return $a*($b+1);}\n";
+        assert_eq!(
+            format(source).unwrap(),
+            "my $i = 0;\nwhile ($i < 3) {\n    print $i, \"\\n\";\n    $i++;\n}\n\
+             sub f($a, $b) {\n    return $a * ($b + 1);\n}\n"
+        );
+        assert_eq!(format("sub g($n):native{return $n;}").unwrap(), "sub g($n) :native {\n    return $n;\n}\n");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = r#"
+            our ($a, $b) = [1, 2];
+            my $h = {"k" => -(-1), "j" => [1, 2]};
+            for (my $i = 0; $i < 10; $i++) { next; }
+            foreach my $x ([1, 2, 3]) { say $x; }
+            if ($a =~ /a#b/i && !($b || $a)) { print; } elsif ($b) { last; } else { return; }
+            unless ($a) { $a .= "x" . "\t"; } else { { $h->{k} = $a ? $b : $h->[1]; } }
+            $a = $b = 3 - (2 - 1);
+            ({} );
+            print f(1), --$a, $a--, [], !(~$a);
+            my @v = @h{"a", $b}; my @w = @h{@v}; my @p = %h;
+        "#;
+        let printed = program(&parse(source));
+        assert_eq!(parse(&printed).statements, parse(source).statements);
+        assert_eq!(program(&parse(&printed)), printed);
+    }
+
+    #[test]
+    fn test_comments_kept() {
+        let source = "#!/usr/bin/microperl\n# Count\n\nmy $n = 3;  # start\nwhile ($n) {\n  # tick\n  $n--;\n  # last\n}\n\nprint \"#\";   # done\n# end\n";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "#!/usr/bin/microperl\n# Count\n\nmy $n = 3;  # start\nwhile ($n) {\n    # tick\n    $n--;\n    # last\n}\n\nprint \"#\";  # done\n# end\n"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+}
@@ -0,0 +1,148 @@
+//! Diagnostics for people
+//!
+//! Renders a diagnostic with its code, location, the source line with the
+//! offending text underlined, and any notes, coloured with ANSI escapes
+//! when the output is a terminal.
+
+use std::io::IsTerminal;
+
+use crate::Diagnostic;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// When to colour diagnostics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    pub fn from_name(name: &str) -> Option<Color> {
+        match name {
+            "auto" => Some(Color::Auto),
+            "always" => Some(Color::Always),
+            "never" => Some(Color::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether to colour what goes to stderr. `auto` colours a terminal,
+    /// unless NO_COLOR is set.
+    pub fn enabled(self) -> bool {
+        match self {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal(),
+        }
+    }
+}
+
+/// `diagnostic` for `file`, whose text is `source`, ready to print
+pub fn render(diagnostic: &Diagnostic, file: &str, source: &str, color: bool) -> String {
+    let paint = |style: &str, text: &str| match color {
+        true => format!("{}{}{}", style, text, RESET),
+        false => text.to_string(),
+    };
+    let (label, style) = if diagnostic.is_warning() { ("warning", YELLOW) } else { ("error", RED) };
+    let mut message = diagnostic.message.lines();
+    let mut out = format!(
+        "{}{}\n",
+        paint(style, &format!("{}[{}]", label, diagnostic.code)),
+        paint(BOLD, &format!(": {}", message.next().unwrap_or("")))
+    );
+    // Further lines, such as the size breakdown, go out as they are
+    for line in message {
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    let text = diagnostic.line.and_then(|line| source.lines().nth(line.checked_sub(1)?));
+    let gutter = " ".repeat(diagnostic.line.filter(|_| text.is_some()).map_or(1, |l| l.to_string().len()));
+    let location = match (diagnostic.line, diagnostic.column) {
+        (Some(line), Some(column)) => format!("{}:{}:{}", file, line, column),
+        (Some(line), None) => format!("{}:{}", file, line),
+        _ => file.to_string(),
+    };
+    out.push_str(&format!("{}{} {}\n", gutter, paint(BLUE, "-->"), location));
+
+    if let (Some(line), Some(text)) = (diagnostic.line, text) {
+        let bar = paint(BLUE, "|");
+        let (pad, width) = underline(diagnostic, source, line, text);
+        out.push_str(&format!("{} {}\n", gutter, bar));
+        out.push_str(&format!("{} {} {}\n", paint(BLUE, &line.to_string()), bar, text));
+        out.push_str(&format!("{} {} {}{}\n", gutter, bar, pad, paint(style, &"^".repeat(width))));
+    }
+    for note in diagnostic.notes() {
+        out.push_str(&format!("{} {} note: {}\n", gutter, paint(BLUE, "="), note));
+    }
+    out
+}
+
+/// Indent (keeping tabs, so it lines up) and width of the underline on
+/// `text`, the source of `line`. Without a span the column gets one caret.
+fn underline(diagnostic: &Diagnostic, source: &str, line: usize, text: &str) -> (String, usize) {
+    let line_start = source.split_inclusive('\n').take(line - 1).map(str::len).sum::<usize>();
+    let (start, end) = match diagnostic.span {
+        Some((start, end)) if start >= line_start && start <= line_start + text.len() => {
+            (start - line_start, end.clamp(start, line_start + text.len()) - line_start)
+        }
+        _ => {
+            let column = diagnostic.column.unwrap_or(1) - 1;
+            let start = text.char_indices().nth(column).map_or(text.len(), |(i, _)| i);
+            (start, start)
+        }
+    };
+    let pad = text[..start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    (pad, text[start..end].chars().count().max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_source, Options};
+
+    fn first_error(source: &str) -> Diagnostic {
+        compile_source(source, Options::default()).unwrap_err().remove(0)
+    }
+
+    #[test]
+    fn test_render_span() {
+        let source = "my $x = 1;\n  print \"a\", $y;\n";
+        let text = render(&first_error(source), "a.mpl", source, false);
+        assert_eq!(
+            text,
+            "error[undefined-variable]: Undefined variable: $y\n \
+             --> a.mpl:2:14\n  \
+             |\n\
+             2 |   print \"a\", $y;\n  \
+             |              ^^\n  \
+             = note: declare it with `my` or `our` before using it\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "z80-backend")]
+    fn test_render_without_source() {
+        let options = Options { limits: crate::budget::Limits { rom: Some(16), ..Default::default() }, ..Options::default() };
+        let error = compile_source("print 1;", options).unwrap_err().remove(0);
+        let text = render(&error, "a.mpl", "print 1;", false);
+        assert!(text.starts_with("error[rom-size]: ROM image is "));
+        assert!(text.contains("\n  runtime "));
+        assert!(text.ends_with(" --> a.mpl\n"));
+    }
+
+    #[test]
+    fn test_render_color() {
+        let source = "print 1 ` 2;";
+        let text = render(&first_error(source), "-", source, true);
+        assert!(text.starts_with("\x1b[1;31merror[unexpected-character]\x1b[0m\x1b[1m: Unexpected character '`'\x1b[0m\n"));
+        assert!(text.contains("        \x1b[1;31m^\x1b[0m\n"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Interactive sessions on the host VM
+//!
+//! Each entry is compiled onto the end of the program so far and run from
+//! where the new code starts, so variables, subs and the heap carry over
+//! from one entry to the next. An entry that fails to compile is rolled
+//! back; one that fails at run time keeps its definitions but resets the
+//! stack.
+
+use crate::ast::Stmt;
+use crate::bytecode::Module;
+use crate::compiler::Compiler;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::token::Token;
+use crate::vm::{Exit, Vm};
+use crate::z80::VM_STACK;
+use crate::z80emu::Console;
+
+/// Instructions each entry may execute before it is stopped
+pub const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// REPL session state
+pub struct Repl {
+    compiler: Compiler,
+    vm: Vm<Console>,
+    /// Console output already returned
+    shown: usize,
+    /// Instruction budget per entry
+    pub max_steps: u64,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let mut compiler = Compiler::new();
+        compiler.set_string_buffers(true);
+        Repl {
+            compiler,
+            vm: Vm::new(&Module::new(), Console::scripted(b"")),
+            shown: 0,
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+
+    /// Compile and run one entry, returning what it printed followed by
+    /// `= value` when it ends in an expression
+    pub fn eval(&mut self, source: &str) -> Result<String, String> {
+        let mut parser = Parser::new(Lexer::new(source).tokenize());
+        let program = parser.parse().map_err(|e| format!("Parse error: {}", e))?;
+        let keep_value = matches!(program.statements.last(), Some(Stmt::Expr(_)));
+
+        let saved = self.compiler.clone();
+        let loaded = self.compiler
+            .compile_more(&program, keep_value)
+            .map_err(|e| format!("Compile error: {}", e))
+            .and_then(|(module, start)| self.vm.reload(&module).map(|_| start));
+        let start = match loaded {
+            Ok(start) => start,
+            Err(e) => {
+                self.compiler = saved;
+                return Err(e);
+            }
+        };
+
+        self.vm.pc = start;
+        let limit = self.vm.steps + self.max_steps;
+        let exit = self.vm.run(Some(limit));
+
+        let mut out = String::from_utf8_lossy(&self.vm.io.output()[self.shown..]).to_string();
+        self.shown = self.vm.io.output().len();
+
+        let stopped = match exit {
+            Ok(Exit::Halted) => None,
+            Ok(Exit::StepLimit) => Some(format!("Stopped after {} instructions (step limit)", self.max_steps)),
+            Ok(Exit::InputExhausted) => Some("Input is not available in the repl".to_string()),
+            Err(e) => Some(format!("Runtime error: {}", e)),
+        };
+        if let Some(e) = stopped {
+            // Unwind whatever the entry left on the stack
+            self.vm.sp = VM_STACK;
+            self.vm.fp = VM_STACK;
+            return Err(format!("{}{}", out, e));
+        }
+
+        if keep_value {
+            let value = self.vm.read16(self.vm.sp);
+            self.vm.sp = self.vm.sp.wrapping_add(2);
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&format!("= {}\n", describe(&self.vm, value)));
+        }
+        Ok(out)
+    }
+}
+
+/// Whether `source` leaves a bracket open, so the entry continues on the
+/// next line
+pub fn needs_more(source: &str) -> bool {
+    let mut depth = 0i32;
+    for tok in Lexer::new(source).tokenize() {
+        match tok.token {
+            Token::LParen | Token::LBrace | Token::LBracket => depth += 1,
+            Token::RParen | Token::RBrace | Token::RBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// A value as the REPL shows it: strings quoted, numbers in decimal
+fn describe(vm: &Vm<Console>, value: u16) -> String {
+    let text = String::from_utf8_lossy(&vm.text(value)).to_string();
+    if value >= 0x1000 {
+        format!("{:?}", text)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_carries_over() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("my $n = 40;"), Ok(String::new()));
+        assert_eq!(repl.eval("sub add2($x) { return $x + 2; }"), Ok(String::new()));
+        assert_eq!(repl.eval("add2($n);"), Ok("= 42\n".to_string()));
+        assert_eq!(repl.eval(r#"my $s = "hi"; print $s; $s . "!";"#), Ok("hi\n= \"hi!\"\n".to_string()));
+    }
+
+    #[test]
+    fn test_errors_do_not_end_the_session() {
+        let mut repl = Repl::new();
+        repl.eval("our $x = 5;").unwrap();
+        assert!(repl.eval("print (;").unwrap_err().starts_with("Parse error"));
+        assert!(repl.eval("nope();").unwrap_err().contains("Undefined subroutine: nope"));
+        assert!(repl.eval("print 1 / 0;").unwrap_err().contains("Illegal division by zero at line 1"));
+        assert_eq!(repl.eval("$x;"), Ok("= 5\n".to_string()));
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut repl = Repl::new();
+        repl.max_steps = 1000;
+        assert!(repl.eval("while (1) { }").unwrap_err().contains("step limit"));
+        assert_eq!(repl.eval("1 + 1;"), Ok("= 2\n".to_string()));
+    }
+
+    #[test]
+    fn test_needs_more() {
+        assert!(needs_more("sub f($x) {"));
+        assert!(needs_more("if ($x) { print (1,"));
+        assert!(!needs_more("if ($x) { print 1; }"));
+        assert!(!needs_more(r#"print "{";"#));
+    }
+}
@@ -0,0 +1,212 @@
+//! VM stack depth
+//!
+//! Follows every path from the entry point with each instruction's
+//! `Op::stack_effect`, taking the deepest a join is reached at, and adds a
+//! sub's own depth, over the return address and frame pointer `Call`
+//! pushes, at each call. A call leaves the value the sub returns in place
+//! of its arguments.
+//!
+//! Recursion, a loop that leaves the stack deeper every pass, and
+//! instructions whose effect isn't known make the depth unbounded.
+
+use std::collections::BTreeMap;
+
+use crate::bytecode::{Module, NativeFunc, Op};
+
+const MAX_WORDS: i32 = 0x7FFF;
+
+/// Most bytes the VM stack holds while `module` runs from its entry point,
+/// or None when there is no bound
+pub fn max_depth(module: &Module) -> Option<u16> {
+    let mut starts = Vec::new();
+    let mut pc = 0;
+    while pc < module.code.len() {
+        starts.push(pc as u16);
+        pc += Op::from_byte(module.code[pc]).size();
+    }
+    let mut analysis = Analysis { module, starts, subs: BTreeMap::new(), active: Vec::new() };
+    let walk = analysis.walk(module.entry, None)?;
+    u16::try_from(walk.max * 2).ok()
+}
+
+/// Depths in words through some code: the deepest, and the deepest it is
+/// left at
+#[derive(Debug, Clone, Copy)]
+struct Walk {
+    max: i32,
+    exit: i32,
+}
+
+struct Analysis<'a> {
+    module: &'a Module,
+    /// Offset of every instruction, in order
+    starts: Vec<u16>,
+    /// Sub address -> its walk, from just after the call; None when it has
+    /// no bound
+    subs: BTreeMap<u16, Option<Walk>>,
+    /// Subs being walked, to catch recursion
+    active: Vec<u16>,
+}
+
+impl Analysis<'_> {
+    fn word(&self, at: u16) -> Option<u16> {
+        let at = at as usize;
+        self.module.code.get(at + 1..at + 3).map(|w| u16::from_le_bytes([w[0], w[1]]))
+    }
+
+    fn is_sub(&self, addr: u16) -> bool {
+        self.module.subs.iter().any(|&(_, at, _)| at == addr)
+    }
+
+    fn sub(&mut self, addr: u16) -> Option<Walk> {
+        if let Some(walk) = self.subs.get(&addr) {
+            return *walk;
+        }
+        if self.active.contains(&addr) {
+            return None;
+        }
+        self.active.push(addr);
+        let walk = self.walk(addr, Some(addr));
+        self.active.pop();
+        self.subs.insert(addr, walk);
+        walk
+    }
+
+    /// Values a `CallNative` at `pc` pops: its arguments, or for those with
+    /// a count on top, the count, the values and the format or template,
+    /// when the instruction before pushed the count as a constant
+    fn native_args(&self, pc: u16) -> Option<i32> {
+        let id = self.module.code.get(pc as usize + 1)?;
+        if let Some(args) = NativeFunc::from_byte(*id)?.args() {
+            return Some(args as i32);
+        }
+        let i = self.starts.binary_search(&pc).ok()?;
+        let before = *self.starts.get(i.checked_sub(1)?)?;
+        let count = match Op::from_byte(self.module.code[before as usize]) {
+            Op::Push => self.word(before)? as i32,
+            Op::PushByte => *self.module.code.get(before as usize + 1)? as i8 as i32,
+            _ => return None,
+        };
+        Some(count + 2)
+    }
+
+    /// Every path from `start`: the main program when `sub` is None, which
+    /// only ends, or the sub at that address, which returns. Depths in a sub
+    /// count from before the call pushed its two words, so the sub starts
+    /// at 2.
+    fn walk(&mut self, start: u16, sub: Option<u16>) -> Option<Walk> {
+        let base = if sub.is_some() { 2 } else { 0 };
+        let mut depth: BTreeMap<u16, i32> = BTreeMap::new();
+        let mut raised: BTreeMap<u16, usize> = BTreeMap::new();
+        let mut work = vec![(start, base)];
+        let mut max = base;
+        let mut exit: Option<i32> = None;
+        while let Some((pc, d)) = work.pop() {
+            if depth.get(&pc).is_some_and(|&seen| seen >= d) {
+                continue;
+            }
+            depth.insert(pc, d);
+            // A depth raised more often than there are instructions, or past
+            // what 16 bits of bytes can hold, is a loop that grows the stack
+            // each pass
+            let times = raised.entry(pc).or_insert(0);
+            *times += 1;
+            if *times > self.starts.len() || d > MAX_WORDS {
+                return None;
+            }
+            max = max.max(d);
+            // Jumping into another sub is a tail call
+            if sub.is_some_and(|own| own != pc) && self.is_sub(pc) {
+                let callee = self.sub(pc)?;
+                max = max.max(d - base + callee.max);
+                exit = exit.max(Some(d - base + callee.exit));
+                continue;
+            }
+            let op = Op::from_byte(*self.module.code.get(pc as usize)?);
+            let next = pc.checked_add(op.size() as u16)?;
+            let mut go = |to: u16, d: i32| work.push((to, d));
+            match op {
+                Op::Halt | Op::Die | Op::TaskEnd => {}
+                Op::Suspend => max = max.max(d + 1),
+                Op::Return | Op::ReturnVal => {
+                    sub?;
+                    exit = exit.max(Some(1 - *self.module.code.get(pc as usize + 1)? as i32));
+                }
+                Op::Call => {
+                    let callee = self.sub(self.word(pc)?)?;
+                    max = max.max(d + callee.max);
+                    go(next, d + callee.exit);
+                }
+                Op::CallNative => {
+                    let d = d - self.native_args(pc)? + 1;
+                    max = max.max(d);
+                    go(next, d);
+                }
+                Op::Printf => go(next, d - *self.module.code.get(pc as usize + 1)? as i32 - 1),
+                Op::EnterFrame => {
+                    let d = d + *self.module.code.get(pc as usize + 1)? as i32;
+                    max = max.max(d);
+                    go(next, d);
+                }
+                Op::LeaveFrame | Op::TailCall => go(next, base),
+                Op::Native if self.word(pc)? == 0 => go(next, d),
+                Op::Jump => go(self.word(pc)?, d),
+                Op::JumpIf | Op::JumpIfNot => {
+                    go(self.word(pc)?, d - 1);
+                    go(next, d - 1);
+                }
+                _ => {
+                    let (pops, pushes) = op.stack_effect()?;
+                    let d = d - pops as i32 + pushes as i32;
+                    max = max.max(d);
+                    go(next, d);
+                }
+            }
+        }
+        // A sub that never returns leaves nothing to count after its calls
+        Some(Walk { max, exit: exit.unwrap_or(0) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn depth(code: &str) -> Option<u16> {
+        let program = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        max_depth(&Compiler::new().compile(&program).unwrap())
+    }
+
+    #[test]
+    fn test_stack_effect() {
+        assert_eq!(Op::Push.stack_effect(), Some((0, 1)));
+        assert_eq!(Op::Add.stack_effect(), Some((2, 1)));
+        assert_eq!(Op::Call.stack_effect(), None);
+        let mut m = Module::new();
+        m.emit_word(Op::Push, 1);
+        m.emit_word(Op::Push, 2);
+        m.emit(Op::Add);
+        m.emit(Op::Print);
+        m.emit(Op::Halt);
+        assert_eq!(max_depth(&m), Some(4));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(depth("my $a = 1;\nprint $a + 2 * $a;"), Some(6));
+        // The argument, the two words the call pushes, then $n and 1
+        assert_eq!(depth("sub f($n) { return $n + 1; }\nprint f(1);"), Some(10));
+        // ...and the sub's `my` slots under them
+        assert_eq!(depth("sub f($n) { my $t = $n; return $t + 1; }\nprint f(1);"), Some(12));
+        // A tail call reuses the frame, other recursion has no bound
+        assert_eq!(depth("sub f($n) { return f($n - 1); }\nf(3);"), Some(10));
+        assert_eq!(depth("sub f($n) { return 1 + f($n - 1); }\nf(3);"), None);
+        let mut m = Module::new();
+        m.emit_word(Op::Push, 1);
+        m.emit_word(Op::Jump, 0);
+        assert_eq!(max_depth(&m), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! File storage behind a port protocol
+//!
+//! The file natives (`open`, `close`, `readline`, `write` and `eof`) talk
+//! to a storage device over two I/O ports, so one program works against a
+//! block device on real hardware, served by whatever answers the ports,
+//! and against host files in the emulator and host VM, where a `Bridge`
+//! answers them from a `Storage`.
+//!
+//! A command is its argument bytes written to `PORT_FILE_DATA`, then the
+//! command byte written to `PORT_FILE_CMD`, which runs it. Reading
+//! `PORT_FILE_CMD` gives the status: bit 1 if the command failed, else
+//! bit 0 while result bytes are waiting to be read from `PORT_FILE_DATA`.
+//!
+//! | Command | Arguments                                  | Result             |
+//! |---------|--------------------------------------------|--------------------|
+//! | `OPEN`  | mode (`r`, `w` or `a`), then the file name | handle             |
+//! | `CLOSE` | handle                                     |                    |
+//! | `READ`  | handle                                     | next line, fails at end of file |
+//! | `WRITE` | handle, then the bytes                     |                    |
+//! | `EOF`   | handle                                     | 1 at end of file, else 0 |
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Argument and result bytes
+pub const PORT_FILE_DATA: u8 = 0x10;
+/// Command (write) and status (read)
+pub const PORT_FILE_CMD: u8 = 0x11;
+
+pub const OPEN: u8 = 1;
+pub const CLOSE: u8 = 2;
+pub const READ: u8 = 3;
+pub const WRITE: u8 = 4;
+pub const EOF: u8 = 5;
+
+/// Status bit: a result byte is waiting
+pub const STATUS_READY: u8 = 0x01;
+/// Status bit: the last command failed
+pub const STATUS_FAILED: u8 = 0x02;
+
+/// Longest line `READ` returns; the rest comes with the next one
+pub const MAX_LINE: usize = 255;
+
+/// Files served to a program, by handle (1 and up)
+pub trait Storage {
+    /// Open `name` for reading (`r`), writing (`w`) or appending (`a`)
+    fn open(&mut self, name: &str, mode: u8) -> Result<u8, String>;
+    fn close(&mut self, handle: u8) -> Result<(), String>;
+    /// The next line with its newline, or None at end of file
+    fn read_line(&mut self, handle: u8) -> Result<Option<Vec<u8>>, String>;
+    fn write(&mut self, handle: u8, bytes: &[u8]) -> Result<(), String>;
+    fn eof(&mut self, handle: u8) -> Result<bool, String>;
+}
+
+enum HostFile {
+    Read(BufReader<File>),
+    Write(File),
+}
+
+/// Files in a host directory. Names are relative to it, and may not leave
+/// it.
+pub struct HostDir {
+    root: PathBuf,
+    files: Vec<Option<HostFile>>,
+}
+
+impl HostDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        HostDir { root: root.into(), files: Vec::new() }
+    }
+
+    fn file(&mut self, handle: u8) -> Result<&mut HostFile, String> {
+        self.files
+            .get_mut((handle as usize).wrapping_sub(1))
+            .and_then(Option::as_mut)
+            .ok_or_else(|| format!("Bad file handle {}", handle))
+    }
+
+    fn reader(&mut self, handle: u8) -> Result<&mut BufReader<File>, String> {
+        match self.file(handle)? {
+            HostFile::Read(reader) => Ok(reader),
+            HostFile::Write(_) => Err(format!("File handle {} is open for writing", handle)),
+        }
+    }
+}
+
+impl Storage for HostDir {
+    fn open(&mut self, name: &str, mode: u8) -> Result<u8, String> {
+        let relative = Path::new(name);
+        if name.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("File name outside the storage directory: {}", name));
+        }
+        let path = self.root.join(relative);
+        let file = match mode {
+            b'r' => File::open(&path).map(|f| HostFile::Read(BufReader::new(f))),
+            b'w' => File::create(&path).map(HostFile::Write),
+            b'a' => OpenOptions::new().append(true).create(true).open(&path).map(HostFile::Write),
+            _ => return Err(format!("Bad file mode {:?}", mode as char)),
+        }
+        .map_err(|e| format!("{}: {}", name, e))?;
+        let slot = match self.files.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        let handle = u8::try_from(slot + 1).map_err(|_| "Too many open files".to_string())?;
+        self.files[slot] = Some(file);
+        Ok(handle)
+    }
+
+    fn close(&mut self, handle: u8) -> Result<(), String> {
+        self.file(handle)?;
+        self.files[handle as usize - 1] = None;
+        Ok(())
+    }
+
+    fn read_line(&mut self, handle: u8) -> Result<Option<Vec<u8>>, String> {
+        let reader = self.reader(handle)?;
+        let mut line = Vec::new();
+        while line.len() < MAX_LINE {
+            let buf = reader.fill_buf().map_err(|e| e.to_string())?;
+            if buf.is_empty() {
+                break;
+            }
+            let take = buf.len().min(MAX_LINE - line.len());
+            let (take, done) = match buf[..take].iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (take, false),
+            };
+            line.extend_from_slice(&buf[..take]);
+            reader.consume(take);
+            if done {
+                break;
+            }
+        }
+        Ok((!line.is_empty()).then_some(line))
+    }
+
+    fn write(&mut self, handle: u8, bytes: &[u8]) -> Result<(), String> {
+        match self.file(handle)? {
+            HostFile::Write(file) => file.write_all(bytes).map_err(|e| e.to_string()),
+            HostFile::Read(_) => Err(format!("File handle {} is open for reading", handle)),
+        }
+    }
+
+    fn eof(&mut self, handle: u8) -> Result<bool, String> {
+        let reader = self.reader(handle)?;
+        Ok(reader.fill_buf().map_err(|e| e.to_string())?.is_empty())
+    }
+}
+
+/// The device end of the protocol, answering commands from a `Storage`
+pub struct Bridge {
+    storage: Box<dyn Storage>,
+    args: Vec<u8>,
+    result: VecDeque<u8>,
+    failed: bool,
+}
+
+impl Bridge {
+    pub fn new(storage: impl Storage + 'static) -> Self {
+        Bridge { storage: Box::new(storage), args: Vec::new(), result: VecDeque::new(), failed: false }
+    }
+
+    pub fn input(&mut self, port: u8) -> u8 {
+        match port {
+            PORT_FILE_CMD if self.failed => STATUS_FAILED,
+            PORT_FILE_CMD => (!self.result.is_empty()) as u8 * STATUS_READY,
+            _ => self.result.pop_front().unwrap_or(0),
+        }
+    }
+
+    pub fn output(&mut self, port: u8, value: u8) {
+        if port == PORT_FILE_DATA {
+            self.args.push(value);
+            return;
+        }
+        let args = std::mem::take(&mut self.args);
+        let result = self.run(value, &args);
+        self.failed = result.is_err();
+        self.result = result.unwrap_or_default().into();
+    }
+
+    fn run(&mut self, command: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        let (&first, rest) = args.split_first().ok_or("Missing arguments")?;
+        match command {
+            OPEN => Ok(vec![self.storage.open(&String::from_utf8_lossy(rest), first)?]),
+            CLOSE => self.storage.close(first).map(|_| Vec::new()),
+            READ => self.storage.read_line(first)?.ok_or_else(|| "End of file".to_string()),
+            WRITE => self.storage.write(first, rest).map(|_| Vec::new()),
+            EOF => Ok(vec![self.storage.eof(first)? as u8]),
+            _ => Err(format!("Unknown file command {}", command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `command` with `args` through `bridge` as a program would
+    fn command(bridge: &mut Bridge, command: u8, args: &[u8]) -> Option<Vec<u8>> {
+        for &b in args {
+            bridge.output(PORT_FILE_DATA, b);
+        }
+        bridge.output(PORT_FILE_CMD, command);
+        let mut result = Vec::new();
+        loop {
+            match bridge.input(PORT_FILE_CMD) {
+                STATUS_FAILED => return None,
+                STATUS_READY => result.push(bridge.input(PORT_FILE_DATA)),
+                _ => return Some(result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_host_dir() {
+        let dir = std::env::temp_dir().join(format!("microperl_storage_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut bridge = Bridge::new(HostDir::new(&dir));
+
+        let out = command(&mut bridge, OPEN, b"wlog.txt").unwrap();
+        assert_eq!(out, vec![1]);
+        assert_eq!(command(&mut bridge, WRITE, b"\x01one\ntwo"), Some(Vec::new()));
+        assert_eq!(command(&mut bridge, CLOSE, &[1]), Some(Vec::new()));
+        assert_eq!(std::fs::read(dir.join("log.txt")).unwrap(), b"one\ntwo");
+
+        assert_eq!(command(&mut bridge, OPEN, b"rlog.txt"), Some(vec![1]));
+        assert_eq!(command(&mut bridge, EOF, &[1]), Some(vec![0]));
+        assert_eq!(command(&mut bridge, READ, &[1]).unwrap(), b"one\n");
+        assert_eq!(command(&mut bridge, READ, &[1]).unwrap(), b"two");
+        assert_eq!(command(&mut bridge, EOF, &[1]), Some(vec![1]));
+        assert_eq!(command(&mut bridge, READ, &[1]), None);
+        // Writing to a file open for reading
+        assert_eq!(command(&mut bridge, WRITE, b"\x01x"), None);
+
+        assert_eq!(command(&mut bridge, OPEN, b"rmissing.txt"), None);
+        assert_eq!(command(&mut bridge, OPEN, b"r../log.txt"), None);
+        assert_eq!(command(&mut bridge, OPEN, b"r/etc/passwd"), None);
+        assert_eq!(command(&mut bridge, CLOSE, &[7]), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_lines_split() {
+        let dir = std::env::temp_dir().join(format!("microperl_storage_long_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("long.txt"), [b"a".repeat(300), b"\n".to_vec()].concat()).unwrap();
+        let mut storage = HostDir::new(&dir);
+        let handle = storage.open("long.txt", b'r').unwrap();
+        assert_eq!(storage.read_line(handle).unwrap().unwrap().len(), MAX_LINE);
+        assert_eq!(storage.read_line(handle).unwrap().unwrap(), [b"a".repeat(45), b"\n".to_vec()].concat());
+        assert_eq!(storage.read_line(handle).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! ZX Spectrum tape (.TAP) images
+//!
+//! A TAP file is the sequence of blocks the ROM saves to tape, each stored as
+//! a 2-byte length, a flag byte (0x00 header, 0xFF data), the data and an XOR
+//! checksum. The tape built here starts with an auto-running BASIC loader
+//! followed by one CODE file per block of machine code.
+
+/// BASIC tokens used by the loader
+const TOK_CODE: u8 = 0xAF;
+const TOK_USR: u8 = 0xC0;
+const TOK_LOAD: u8 = 0xEF;
+const TOK_RANDOMIZE: u8 = 0xF9;
+const TOK_CLEAR: u8 = 0xFD;
+
+/// Header types
+const TYPE_PROGRAM: u8 = 0;
+const TYPE_CODE: u8 = 3;
+
+/// Line number of the loader, also its autostart line
+const LOADER_LINE: u16 = 10;
+
+/// Build a tape that loads each `(address, bytes)` block and then calls the
+/// first one. RAMTOP is set just below the lowest block.
+pub fn spectrum_tape(name: &str, blocks: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let lowest = blocks.iter().map(|(addr, _)| *addr).min().unwrap_or(0x8000);
+    let entry = blocks.first().map_or(lowest, |(addr, _)| *addr);
+    let program = loader(lowest - 1, blocks.len(), entry);
+
+    let mut tape = Vec::new();
+    let len = program.len() as u16;
+    tape.extend(block(0x00, &header(TYPE_PROGRAM, name, len, LOADER_LINE, len)));
+    tape.extend(block(0xFF, &program));
+    for (addr, bytes) in blocks {
+        tape.extend(block(0x00, &header(TYPE_CODE, name, bytes.len() as u16, *addr, 0x8000)));
+        tape.extend(block(0xFF, bytes));
+    }
+    tape
+}
+
+/// `10 CLEAR clear: LOAD "" CODE: ...: RANDOMIZE USR entry`
+fn loader(clear: u16, loads: usize, entry: u16) -> Vec<u8> {
+    let mut line = vec![TOK_CLEAR];
+    line.extend(basic_number(clear));
+    for _ in 0..loads {
+        line.extend([b':', TOK_LOAD, b'"', b'"', TOK_CODE]);
+    }
+    line.extend([b':', TOK_RANDOMIZE, TOK_USR]);
+    line.extend(basic_number(entry));
+    line.push(0x0D);
+
+    // Line numbers are big-endian, line lengths little-endian
+    let mut program = LOADER_LINE.to_be_bytes().to_vec();
+    program.extend((line.len() as u16).to_le_bytes());
+    program.extend(line);
+    program
+}
+
+/// A number as BASIC stores it: the digits as typed, then 0x0E and the value
+/// in the 5-byte small integer form
+fn basic_number(n: u16) -> Vec<u8> {
+    let mut bytes = n.to_string().into_bytes();
+    bytes.push(0x0E);
+    bytes.extend([0x00, 0x00]);
+    bytes.extend(n.to_le_bytes());
+    bytes.push(0x00);
+    bytes
+}
+
+/// 17-byte tape header; the name is padded or cut to 10 characters
+fn header(kind: u8, name: &str, len: u16, param1: u16, param2: u16) -> Vec<u8> {
+    let mut h = vec![kind];
+    let mut padded: Vec<u8> = name.bytes().filter(|b| b.is_ascii_graphic() || *b == b' ').take(10).collect();
+    padded.resize(10, b' ');
+    h.extend(padded);
+    h.extend(len.to_le_bytes());
+    h.extend(param1.to_le_bytes());
+    h.extend(param2.to_le_bytes());
+    h
+}
+
+/// One TAP block: length, flag, data, checksum
+fn block(flag: u8, data: &[u8]) -> Vec<u8> {
+    let checksum = data.iter().fold(flag, |acc, b| acc ^ b);
+    let mut out = ((data.len() + 2) as u16).to_le_bytes().to_vec();
+    out.push(flag);
+    out.extend_from_slice(data);
+    out.push(checksum);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split a tape into (flag, data) blocks, checking lengths and checksums
+    fn blocks(tape: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < tape.len() {
+            let len = u16::from_le_bytes([tape[pos], tape[pos + 1]]) as usize;
+            let body = &tape[pos + 2..pos + 2 + len];
+            assert_eq!(body.iter().fold(0, |acc, b| acc ^ b), 0, "bad checksum");
+            out.push((body[0], body[1..len - 1].to_vec()));
+            pos += 2 + len;
+        }
+        out
+    }
+
+    #[test]
+    fn test_tape_layout() {
+        let tape = spectrum_tape("hello", &[(0x8000, vec![0xC9; 5]), (0x9000, vec![1, 2, 3])]);
+        let blocks = blocks(&tape);
+        assert_eq!(blocks.len(), 6);
+
+        let (flag, program_header) = &blocks[0];
+        assert_eq!(*flag, 0x00);
+        assert_eq!(program_header[0], TYPE_PROGRAM);
+        assert_eq!(&program_header[1..11], b"hello     ");
+        assert_eq!(u16::from_le_bytes([program_header[13], program_header[14]]), LOADER_LINE);
+
+        let (_, code_header) = &blocks[4];
+        assert_eq!(code_header[0], TYPE_CODE);
+        assert_eq!(u16::from_le_bytes([code_header[11], code_header[12]]), 3);
+        assert_eq!(u16::from_le_bytes([code_header[13], code_header[14]]), 0x9000);
+        assert_eq!(blocks[5], (0xFF, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_loader_program() {
+        let program = loader(32767, 1, 32768);
+        assert_eq!(&program[..2], &[0, 10]);
+        assert_eq!(u16::from_le_bytes([program[2], program[3]]) as usize, program.len() - 4);
+
+        let mut expected = vec![TOK_CLEAR];
+        expected.extend(b"32767\x0E\x00\x00\xFF\x7F\x00");
+        expected.extend([b':', TOK_LOAD, b'"', b'"', TOK_CODE, b':', TOK_RANDOMIZE, TOK_USR]);
+        expected.extend(b"32768\x0E\x00\x00\x00\x80\x00\x0D");
+        assert_eq!(&program[4..], &expected[..]);
+    }
+}
@@ -0,0 +1,159 @@
+//! Test support
+//!
+//! Compile a snippet and run it on the host VM or the Z80 emulator inside
+//! the test's own process, and get back what it printed, how it stopped and
+//! how long it took, so golden-output tests need no temporary files or the
+//! `microperl` binary. Behind the `testing` feature.
+
+use crate::debugger;
+use crate::render;
+use crate::vm::{self, Vm};
+use crate::z80;
+use crate::z80emu::{self, Console, Machine};
+use crate::{compile_source, Artifacts, Options};
+
+/// Instructions the host VM runs before giving up
+pub const MAX_STEPS: u64 = 10_000_000;
+
+/// T-states the emulator runs before giving up, about 2.5 seconds of a
+/// 4 MHz Z80
+pub const MAX_CYCLES: u64 = 10_000_000;
+
+/// File name used in diagnostics and runtime error locations
+const FILE: &str = "test.mpl";
+
+/// How a run stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// The program ended
+    Halted,
+    /// The program waited for input after the input ended
+    InputExhausted,
+    /// MAX_STEPS or MAX_CYCLES ran out
+    Limit,
+    /// A runtime error, with its location and message
+    Error(String),
+}
+
+/// A finished run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub stdout: String,
+    /// Output to STDERR, and the message of a runtime error
+    pub stderr: String,
+    pub status: Status,
+    /// Instructions on the host VM, T-states on the emulator
+    pub cycles: u64,
+}
+
+impl Outcome {
+    /// Whether the program ran to its end
+    pub fn success(&self) -> bool {
+        self.status == Status::Halted
+    }
+}
+
+/// `source` compiled with `options`, or its diagnostics rendered as
+/// `microperl` prints them
+pub fn compile(source: &str, options: &Options) -> Result<Artifacts, String> {
+    compile_source(source, options.clone()).map_err(|diagnostics| {
+        diagnostics.iter().map(|d| render::render(d, FILE, source, false)).collect()
+    })
+}
+
+/// `source` run on the host VM with `input` as the console input
+pub fn run_vm(source: &str, options: &Options, input: &[u8]) -> Result<Outcome, String> {
+    let artifacts = compile(source, &Options { string_buffers: true, ..options.clone() })?;
+    let module = &artifacts.module;
+    let mut vm = Vm::new(module, Console::scripted(input));
+    let exit = vm.run(Some(MAX_STEPS));
+    let mut stderr = String::from_utf8_lossy(vm.io.errors()).into_owned();
+    let status = match exit {
+        Ok(vm::Exit::Halted) => Status::Halted,
+        Ok(vm::Exit::InputExhausted) => Status::InputExhausted,
+        Ok(vm::Exit::StepLimit) => Status::Limit,
+        // The failed instruction ends just before PC
+        Err(e) => error(&mut stderr, &debugger::error_location(module, FILE, vm.pc.wrapping_sub(1)), &format!("Runtime error: {}", e)),
+    };
+    let stdout = String::from_utf8_lossy(vm.io.output()).into_owned();
+    Ok(Outcome { stdout, stderr, status, cycles: vm.steps })
+}
+
+/// `source` run on the Z80 emulator, in the RetroShield ROM built with
+/// `options`, with `input` as the console input
+pub fn run_z80(source: &str, options: &Options, input: &[u8]) -> Result<Outcome, String> {
+    if options.rom.target != z80::Target::RetroShield {
+        return Err("The emulator models the RetroShield only".to_string());
+    }
+    let artifacts = compile(source, options)?;
+    let module = &artifacts.module;
+    let rom = z80::generate_rom(module, &options.rom);
+    let console = Console::scripted(input).with_irq(options.rom.irq_input);
+    let console = console.with_error_port(options.rom.stderr_port().unwrap_or(z80::PORT_ERROR));
+    let mut machine = Machine::new(&rom, console);
+    let exit = machine.run(Some(MAX_CYCLES));
+    let layout = options.rom.target.layout();
+    let mut stderr = String::from_utf8_lossy(machine.io.errors()).into_owned();
+    let status = match exit {
+        z80emu::Exit::Halted => match z80::halt(module, &layout, &machine) {
+            z80::Halt::Ended => Status::Halted,
+            z80::Halt::BootFailed => Status::Error(stderr.clone()),
+            z80::Halt::Error { pc, message } => error(&mut stderr, &debugger::error_location(module, FILE, pc), &message),
+        },
+        z80emu::Exit::InputExhausted => Status::InputExhausted,
+        z80emu::Exit::CycleLimit => Status::Limit,
+    };
+    let stdout = String::from_utf8_lossy(machine.io.output()).into_owned();
+    Ok(Outcome { stdout, stderr, status, cycles: machine.cpu.cycles })
+}
+
+/// A runtime error at `at`, reported on `stderr` as `microperl` does
+fn error(stderr: &mut String, at: &str, message: &str) -> Status {
+    let message = format!("{}{}", at, message);
+    stderr.push_str(&message);
+    stderr.push('\n');
+    Status::Error(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_and_z80_agree() {
+        let source = "my $n = 6;\nprint $n + 36, \"\\n\";\n";
+        let vm = run_vm(source, &Options::default(), b"").unwrap();
+        let z80 = run_z80(source, &Options::default(), b"").unwrap();
+        assert!(vm.success() && z80.success());
+        assert_eq!(vm.stdout, "42\n");
+        assert_eq!(z80.stdout, vm.stdout);
+        assert!(z80.cycles > vm.cycles);
+    }
+
+    #[test]
+    fn test_undef_agrees() {
+        let source = "my $n;\nprint defined($n), \"|\", $n, \"|\", $n + 1, \"|\";\nif (!$n) { $n++; }\nprint $n;\n\
+                      my $k = 64 * 64;\nif ($k) { print \"|\", defined($k), $k / 64; }\n";
+        let vm = run_vm(source, &Options::default(), b"").unwrap();
+        let z80 = run_z80(source, &Options::default(), b"").unwrap();
+        assert_eq!(vm.stdout, "0||1|1|164");
+        assert_eq!(vm.stderr, "Use of uninitialized value $n in print at line 2\n");
+        assert_eq!((z80.stdout, z80.stderr), (vm.stdout, vm.stderr));
+    }
+
+    #[test]
+    fn test_failures() {
+        let died = run_z80("print \"a\";\nassert 0, \"no\";\n", &Options::default(), b"").unwrap();
+        assert_eq!(died.stdout, "a");
+        assert_eq!(died.stderr, "Assertion failed: no\ntest.mpl:2: Program died\n");
+        assert_eq!(died.status, Status::Error("test.mpl:2: Program died".to_string()));
+        // An instruction the runtime lacks stops it, which is no success
+        let missing = run_z80("my $x = 3;\nprint $x - 1;\n", &Options::default(), b"").unwrap();
+        assert_eq!(missing.status, Status::Error("test.mpl:2: Runtime error: Sub is not in the Z80 runtime".to_string()));
+        assert!(!missing.success());
+        let looped = run_vm("while (1) { }", &Options::default(), b"").unwrap();
+        assert_eq!(looped.status, Status::Limit);
+        let error = compile("print $x;", &Options::default()).unwrap_err();
+        assert!(error.contains("Undefined variable"), "{}", error);
+    }
+}
@@ -0,0 +1,80 @@
+//! TI-83+/84+ program files (.8xp)
+//!
+//! A .8xp file holds one variable: an 11-byte signature, a 42-byte comment,
+//! the length of the data section, the data section (a variable entry header
+//! followed by the program) and a 16-bit sum of the data section. Assembly
+//! programs start with the AsmPrgm tokens and run with `Asm(prgmNAME)`.
+
+const SIGNATURE: &[u8] = b"**TI83F*\x1A\x0A\x00";
+const COMMENT: &[u8] = b"MicroPerl program";
+
+/// Variable type of a protected program (not editable as BASIC)
+const TYPE_PROTECTED_PROGRAM: u8 = 0x06;
+
+/// t2ByteTok, tAsmCmp: marks the program as compiled assembly
+const ASM_PRGM: [u8; 2] = [0xBB, 0x6D];
+
+/// Build a protected program named after `name` whose machine code loads at
+/// userMem
+pub fn program(name: &str, code: &[u8]) -> Vec<u8> {
+    let mut body = ASM_PRGM.to_vec();
+    body.extend_from_slice(code);
+
+    // Variable data is the program size followed by the program
+    let mut var = (body.len() as u16).to_le_bytes().to_vec();
+    var.extend(body);
+    let var_len = (var.len() as u16).to_le_bytes();
+
+    // Names are up to 8 upper case letters and digits, starting with a letter
+    let mut var_name: Vec<u8> = name
+        .bytes()
+        .filter(u8::is_ascii_alphanumeric)
+        .map(|b| b.to_ascii_uppercase())
+        .skip_while(u8::is_ascii_digit)
+        .take(8)
+        .collect();
+    if var_name.is_empty() {
+        var_name = b"MPERL".to_vec();
+    }
+    var_name.resize(8, 0);
+
+    let mut data = vec![0x0D, 0x00];
+    data.extend(var_len);
+    data.push(TYPE_PROTECTED_PROGRAM);
+    data.extend(var_name);
+    data.extend([0x00, 0x00]); // Version, not archived
+    data.extend(var_len);
+    data.extend(var);
+
+    let mut file = SIGNATURE.to_vec();
+    let mut comment = COMMENT.to_vec();
+    comment.resize(42, 0);
+    file.extend(comment);
+    file.extend((data.len() as u16).to_le_bytes());
+    let checksum = data.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+    file.extend(data);
+    file.extend(checksum.to_le_bytes());
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program_file() {
+        let file = program("2hello.mpl", &[0xC9]);
+        assert_eq!(&file[..11], SIGNATURE);
+        let data_len = u16::from_le_bytes([file[53], file[54]]) as usize;
+        assert_eq!(file.len(), 55 + data_len + 2);
+
+        let data = &file[55..55 + data_len];
+        assert_eq!(data[4], TYPE_PROTECTED_PROGRAM);
+        assert_eq!(&data[5..13], b"HELLOMPL");
+        // Size word, then the AsmPrgm tokens and the code
+        assert_eq!(&data[17..], &[3, 0, 0xBB, 0x6D, 0xC9]);
+
+        let sum = data.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        assert_eq!(&file[55 + data_len..], &sum.to_le_bytes());
+    }
+}
@@ -31,6 +31,8 @@ pub enum Token {
     Return,
     Print,
     Say,
+    Printf,
+    Assert,
     Use,
     Package,
 
@@ -137,6 +139,8 @@ impl Token {
             "return" => Some(Token::Return),
             "print" => Some(Token::Print),
             "say" => Some(Token::Say),
+            "printf" => Some(Token::Printf),
+            "assert" => Some(Token::Assert),
             "use" => Some(Token::Use),
             "package" => Some(Token::Package),
             "eq" => Some(Token::StrEq),
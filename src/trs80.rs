@@ -0,0 +1,81 @@
+//! TRS-80 /CMD files
+//!
+//! A /CMD file is a sequence of records, each a type byte, a length byte and
+//! the payload. Load records (type 1) carry a load address and up to 256
+//! bytes; the transfer record (type 2) ends the file with the entry address.
+
+const RECORD_LOAD: u8 = 0x01;
+const RECORD_TRANSFER: u8 = 0x02;
+const RECORD_HEADER: u8 = 0x05;
+
+/// Bytes of data per load record
+const CHUNK: usize = 256;
+
+/// Build a /CMD file loading each `(address, bytes)` block and jumping to
+/// `entry`
+pub fn cmd_file(name: &str, entry: u16, blocks: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    // Module name header, up to 8 characters
+    let name: Vec<u8> = name
+        .bytes()
+        .filter(u8::is_ascii_alphanumeric)
+        .map(|b| b.to_ascii_uppercase())
+        .take(8)
+        .collect();
+    if !name.is_empty() {
+        out.push(RECORD_HEADER);
+        out.push(name.len() as u8);
+        out.extend(&name);
+    }
+
+    for (addr, bytes) in blocks {
+        for (i, chunk) in bytes.chunks(CHUNK).enumerate() {
+            // The length counts the address; 256 data bytes wrap to 0x02
+            out.push(RECORD_LOAD);
+            out.push((chunk.len() + 2) as u8);
+            out.extend((addr + (i * CHUNK) as u16).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+    }
+
+    out.push(RECORD_TRANSFER);
+    out.push(2);
+    out.extend(entry.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a /CMD file into memory, returning the entry address
+    fn load(file: &[u8], mem: &mut [u8]) -> u16 {
+        let mut pos = 0;
+        loop {
+            let (kind, len) = (file[pos], file[pos + 1]);
+            match kind {
+                RECORD_LOAD => {
+                    let len = if len < 3 { len as usize + 256 } else { len as usize };
+                    let addr = u16::from_le_bytes([file[pos + 2], file[pos + 3]]) as usize;
+                    mem[addr..addr + len - 2].copy_from_slice(&file[pos + 4..pos + 2 + len]);
+                    pos += 2 + len;
+                }
+                RECORD_TRANSFER => return u16::from_le_bytes([file[pos + 2], file[pos + 3]]),
+                _ => pos += 2 + len as usize,
+            }
+        }
+    }
+
+    #[test]
+    fn test_cmd_round_trip() {
+        let code: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let file = cmd_file("demo", 0x6000, &[(0x6000, code.clone()), (0x7000, vec![9, 8, 7])]);
+        assert_eq!(&file[..6], &[RECORD_HEADER, 4, b'D', b'E', b'M', b'O']);
+
+        let mut mem = vec![0; 0x10000];
+        assert_eq!(load(&file, &mut mem), 0x6000);
+        assert_eq!(&mem[0x6000..0x6000 + 600], &code[..]);
+        assert_eq!(&mem[0x7000..0x7003], &[9, 8, 7]);
+    }
+}
@@ -0,0 +1,406 @@
+//! Number and string type warnings
+//!
+//! A value is a 16-bit word, and the VM takes one of 4096 or more for the
+//! address of a string. Nothing stops a program comparing strings with `<`
+//! or adding to one; it compares or adds addresses, and the bug only shows
+//! as wrong output on the hardware. `--strict-types` infers which
+//! expressions are numbers and which strings, and warns where one is used
+//! as the other.
+//!
+//! The inference is deliberately loose: a scalar has a type only when every
+//! assignment to it, in any scope, gives the same one. Sub parameters and
+//! results, array and hash elements and anything unclear count as either.
+
+use std::collections::HashMap;
+
+use crate::ast::{BinOp, Expr, Program, Stmt, UnaryOp};
+
+/// Lowest value the runtime takes for a string address
+const STRING_MIN: i32 = 0x1000;
+
+/// Rounds over the program before the variable types settle
+const MAX_ROUNDS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Num,
+    Str,
+    /// Either, or not known
+    Any,
+}
+
+/// What two assignments to a variable, or two branches, make it. `None` is
+/// not known yet.
+fn join(a: Option<Ty>, b: Option<Ty>) -> Option<Ty> {
+    match (a, b) {
+        (None, t) | (t, None) => t,
+        (Some(a), Some(b)) if a == b => Some(a),
+        _ => Some(Ty::Any),
+    }
+}
+
+/// Warnings for `program` as `(line, message)`, in source order
+pub fn check(program: &Program) -> Vec<(usize, String)> {
+    let mut checker = infer(program);
+    checker.block(&program.statements);
+    checker.warnings
+}
+
+/// Whether scalar `name` in `program` always holds a string (`Some(true)`)
+/// or always a number (`Some(false)`), or `None` if it is unclear
+pub fn holds_string(program: &Program, name: &str) -> Option<bool> {
+    match infer(program).vars.get(name).copied().flatten()? {
+        Ty::Str => Some(true),
+        Ty::Num => Some(false),
+        Ty::Any => None,
+    }
+}
+
+/// A checker with the types of `program`'s variables settled
+fn infer(program: &Program) -> Checker<'_> {
+    let mut checker = Checker { vars: HashMap::new(), lines: &program.lines, next: 0, warnings: Vec::new() };
+    for _ in 0..MAX_ROUNDS {
+        let mut vars = HashMap::new();
+        checker.assignments(&program.statements, &mut vars);
+        if vars == checker.vars {
+            break;
+        }
+        checker.vars = vars;
+    }
+    checker
+}
+
+/// Type of each scalar, from all its assignments
+type Vars = HashMap<String, Option<Ty>>;
+
+fn assign(vars: &mut Vars, name: &str, ty: Option<Ty>) {
+    let slot = vars.entry(name.to_string()).or_insert(None);
+    *slot = join(*slot, ty);
+}
+
+struct Checker<'a> {
+    vars: Vars,
+    /// The parser's statement lines, walked in its pre-order
+    lines: &'a [usize],
+    next: usize,
+    warnings: Vec<(usize, String)>,
+}
+
+impl Checker<'_> {
+    /// Record in `vars` what each assignment in `stmts` gives its scalar,
+    /// with expression types from the last round
+    fn assignments(&self, stmts: &[Stmt], vars: &mut Vars) {
+        for stmt in stmts {
+            let mut exprs = Vec::new();
+            match stmt {
+                Stmt::My(names, init) | Stmt::Our(names, init) => {
+                    match (names.as_slice(), init) {
+                        ([name], Some(value)) => assign(vars, name, self.ty(value)),
+                        (_, Some(_)) => names.iter().for_each(|name| assign(vars, name, Some(Ty::Any))),
+                        // Undefined reads as 0
+                        (_, None) => names.iter().for_each(|name| assign(vars, name, Some(Ty::Num))),
+                    }
+                    exprs.extend(init);
+                }
+                Stmt::Foreach { var, list, .. } => {
+                    assign(vars, var, Some(Ty::Any));
+                    exprs.push(list);
+                }
+                Stmt::Sub { params, .. } => params.iter().for_each(|name| assign(vars, name, Some(Ty::Any))),
+                Stmt::Expr(e) | Stmt::Return(Some(e)) | Stmt::Constant(_, e) => exprs.push(e),
+                Stmt::If { cond, elsif_blocks, .. } => {
+                    exprs.push(cond);
+                    exprs.extend(elsif_blocks.iter().map(|(c, _)| c));
+                }
+                Stmt::Unless { cond, .. } | Stmt::While { cond, .. } | Stmt::Until { cond, .. } => exprs.push(cond),
+                Stmt::For { cond, step, .. } => exprs.extend(cond.iter().chain(step)),
+                Stmt::Print(_, args) | Stmt::Say(_, args) | Stmt::Printf(_, args) => exprs.extend(args),
+                Stmt::Assert(cond, message) => exprs.extend(std::iter::once(cond).chain(message)),
+                _ => {}
+            }
+            for e in exprs {
+                self.expr_assignments(e, vars);
+            }
+            for body in children(stmt) {
+                self.assignments(body, vars);
+            }
+        }
+    }
+
+    fn expr_assignments(&self, e: &Expr, vars: &mut Vars) {
+        match e {
+            Expr::Assign(target, value) => {
+                if let Expr::ScalarVar(name) = target.as_ref() {
+                    assign(vars, name, self.ty(value));
+                }
+            }
+            Expr::OpAssign(target, op, _) => {
+                if let Expr::ScalarVar(name) = target.as_ref() {
+                    assign(vars, name, Some(op_result(op)));
+                }
+            }
+            // ++ and -- keep the type, so a string's is the bug to find
+            _ => {}
+        }
+        for sub in operands(e) {
+            self.expr_assignments(sub, vars);
+        }
+    }
+
+    /// What `e` produces, `None` for a variable with no known assignment yet
+    fn ty(&self, e: &Expr) -> Option<Ty> {
+        Some(match e {
+            Expr::Integer(_) | Expr::Float(_) => Ty::Num,
+            Expr::String(_) => Ty::Str,
+            Expr::ScalarVar(name) => return self.vars.get(name).copied().flatten(),
+            Expr::BinOp(left, BinOp::And | BinOp::Or, right) => return join(self.ty(left), self.ty(right)),
+            Expr::BinOp(_, op, _) | Expr::OpAssign(_, op, _) => op_result(op),
+            Expr::UnaryOp(UnaryOp::Ref, _) => Ty::Any,
+            Expr::UnaryOp(..) => Ty::Num,
+            Expr::PreIncrement(_) | Expr::PreDecrement(_) | Expr::PostIncrement(_) | Expr::PostDecrement(_) => Ty::Num,
+            Expr::Match(..) | Expr::NotMatch(..) => Ty::Num,
+            Expr::Assign(_, value) => return self.ty(value),
+            Expr::Ternary(_, a, b) => return join(self.ty(a), self.ty(b)),
+            Expr::Call(name, _) if matches!(name.as_str(), "readline" | "uc" | "lc" | "sprintf" | "pack" | "buf_new" | "buf_append" | "buf_str") => Ty::Str,
+            Expr::Call(name, _) if name == "defined" => Ty::Num,
+            _ => Ty::Any,
+        })
+    }
+
+    fn is(&self, e: &Expr, ty: Ty) -> bool {
+        self.ty(e) == Some(ty)
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        let line = self.lines.get(self.next).copied().unwrap_or(0);
+        self.next += 1;
+        let mut exprs: Vec<&Expr> = Vec::new();
+        match stmt {
+            Stmt::Expr(e) | Stmt::Return(Some(e)) | Stmt::Constant(_, e) => exprs.push(e),
+            Stmt::My(_, init) | Stmt::Our(_, init) => exprs.extend(init),
+            Stmt::If { cond, then_block, elsif_blocks, else_block } => {
+                self.expr(cond, line);
+                self.block(then_block);
+                for (cond, body) in elsif_blocks {
+                    let line = self.lines.get(self.next).copied().unwrap_or(0);
+                    self.next += 1;
+                    self.expr(cond, line);
+                    self.block(body);
+                }
+                if let Some(body) = else_block {
+                    self.block(body);
+                }
+                return;
+            }
+            Stmt::For { init, cond, step, body } => {
+                if let Some(init) = init {
+                    self.stmt(init);
+                }
+                exprs.extend(cond.iter().chain(step));
+                self.block(body);
+            }
+            Stmt::Unless { cond, .. } | Stmt::While { cond, .. } | Stmt::Until { cond, .. } => exprs.push(cond),
+            Stmt::Foreach { list, .. } => exprs.push(list),
+            Stmt::Print(_, args) | Stmt::Say(_, args) | Stmt::Printf(_, args) => exprs.extend(args),
+            Stmt::Assert(cond, message) => exprs.extend(std::iter::once(cond).chain(message)),
+            _ => {}
+        }
+        for e in exprs {
+            self.expr(e, line);
+        }
+        if !matches!(stmt, Stmt::For { .. }) {
+            for body in children(stmt) {
+                self.block(body);
+            }
+        }
+    }
+
+    fn expr(&mut self, e: &Expr, line: usize) {
+        let (left, op, right) = match e {
+            Expr::BinOp(left, op, right) => (left.as_ref(), op, right.as_ref()),
+            Expr::OpAssign(target, op, value) => (target.as_ref(), op, value.as_ref()),
+            Expr::UnaryOp(UnaryOp::Neg | UnaryOp::BitNot, operand) if self.is(operand, Ty::Str) => {
+                self.warnings.push((line, "Arithmetic on a string, which works on its address".to_string()));
+                return self.expr(operand, line);
+            }
+            Expr::PreIncrement(target) | Expr::PreDecrement(target) | Expr::PostIncrement(target) | Expr::PostDecrement(target)
+                if self.is(target, Ty::Str) =>
+            {
+                self.warnings.push((line, "Incrementing a string, which moves its address".to_string()));
+                return self.expr(target, line);
+            }
+            _ => {
+                for sub in operands(e) {
+                    self.expr(sub, line);
+                }
+                return;
+            }
+        };
+        let strings = self.is(left, Ty::Str) || self.is(right, Ty::Str);
+        let message = match op {
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Cmp if strings => {
+                Some(format!("Numeric `{}` on a string compares its address; use `{}`", symbol(op), counterpart(op)))
+            }
+            BinOp::StrEq | BinOp::StrNe | BinOp::StrLt | BinOp::StrGt | BinOp::StrLe | BinOp::StrGe | BinOp::StrCmp
+                if self.is(left, Ty::Num) || self.is(right, Ty::Num) =>
+            {
+                Some(format!("String `{}` on a number, which from 4096 up reads as an address; use `{}`", symbol(op), counterpart(op)))
+            }
+            BinOp::Concat if [left, right].iter().any(|side| self.is(side, Ty::Num) && !small(side)) => {
+                Some("`.` on a number, which from 4096 up reads as an address; convert it with sprintf(\"%d\", ...)".to_string())
+            }
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod | BinOp::Pow
+            | BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::ShiftLeft | BinOp::ShiftRight if strings => {
+                Some(format!("Arithmetic `{}` on a string, which works on its address", symbol(op)))
+            }
+            _ => None,
+        };
+        if let Some(message) = message {
+            self.warnings.push((line, message));
+        }
+        self.expr(left, line);
+        self.expr(right, line);
+    }
+}
+
+/// Number literal the runtime can't take for an address
+fn small(e: &Expr) -> bool {
+    matches!(e, Expr::Integer(n) if (0..STRING_MIN).contains(n))
+}
+
+/// Type of `op`'s result
+fn op_result(op: &BinOp) -> Ty {
+    match op {
+        BinOp::Concat | BinOp::Repeat => Ty::Str,
+        // `&&` and `||` give an operand; the caller joins them
+        BinOp::And | BinOp::Or => Ty::Any,
+        _ => Ty::Num,
+    }
+}
+
+fn symbol(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Eq => "==",
+        BinOp::Ne => "!=",
+        BinOp::Lt => "<",
+        BinOp::Gt => ">",
+        BinOp::Le => "<=",
+        BinOp::Ge => ">=",
+        BinOp::Cmp => "<=>",
+        BinOp::StrEq => "eq",
+        BinOp::StrNe => "ne",
+        BinOp::StrLt => "lt",
+        BinOp::StrGt => "gt",
+        BinOp::StrLe => "le",
+        BinOp::StrGe => "ge",
+        BinOp::StrCmp => "cmp",
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Mod => "%",
+        BinOp::Pow => "**",
+        BinOp::BitAnd => "&",
+        BinOp::BitOr => "|",
+        BinOp::BitXor => "^",
+        BinOp::ShiftLeft => "<<",
+        BinOp::ShiftRight => ">>",
+        BinOp::Concat => ".",
+        BinOp::Repeat => "x",
+        BinOp::And => "&&",
+        BinOp::Or => "||",
+    }
+}
+
+/// The comparison of the other kind
+fn counterpart(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Eq => "eq",
+        BinOp::Ne => "ne",
+        BinOp::Lt => "lt",
+        BinOp::Gt => "gt",
+        BinOp::Le => "le",
+        BinOp::Ge => "ge",
+        BinOp::Cmp => "cmp",
+        BinOp::StrEq => "==",
+        BinOp::StrNe => "!=",
+        BinOp::StrLt => "<",
+        BinOp::StrGt => ">",
+        BinOp::StrLe => "<=",
+        BinOp::StrGe => ">=",
+        BinOp::StrCmp => "<=>",
+        _ => symbol(op),
+    }
+}
+
+/// Statement lists nested in `stmt`, in the parser's order
+fn children(stmt: &Stmt) -> Vec<&[Stmt]> {
+    match stmt {
+        Stmt::If { then_block, elsif_blocks, else_block, .. } => std::iter::once(then_block.as_slice())
+            .chain(elsif_blocks.iter().map(|(_, b)| b.as_slice()))
+            .chain(else_block.as_deref())
+            .collect(),
+        Stmt::Unless { then_block, else_block, .. } => std::iter::once(then_block.as_slice()).chain(else_block.as_deref()).collect(),
+        Stmt::For { init, body, .. } => init.as_deref().map(std::slice::from_ref).into_iter().chain([body.as_slice()]).collect(),
+        Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Foreach { body, .. } | Stmt::Sub { body, .. }
+        | Stmt::Block(body) | Stmt::Begin(body) => vec![body],
+        _ => Vec::new(),
+    }
+}
+
+/// Subexpressions of `e`
+pub(crate) fn operands(e: &Expr) -> Vec<&Expr> {
+    match e {
+        Expr::ArrayIndex(a, b) | Expr::HashIndex(a, b) | Expr::HashSlice(a, b) | Expr::BinOp(a, _, b) | Expr::Assign(a, b)
+        | Expr::OpAssign(a, _, b) | Expr::Range(a, b) => vec![a, b],
+        Expr::UnaryOp(_, a) | Expr::PreIncrement(a) | Expr::PreDecrement(a) | Expr::PostIncrement(a)
+        | Expr::PostDecrement(a) | Expr::Match(a, ..) | Expr::NotMatch(a, ..) | Expr::Ref(a) | Expr::Deref(a) => vec![a],
+        Expr::Call(_, args) | Expr::List(args) => args.iter().collect(),
+        Expr::MethodCall(target, _, args) => std::iter::once(target.as_ref()).chain(args).collect(),
+        Expr::Hash(pairs) => pairs.iter().flat_map(|(k, v)| [k, v]).collect(),
+        Expr::Ternary(a, b, c) => vec![a, b, c],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn warnings(code: &str) -> Vec<(usize, String)> {
+        check(&Parser::new(Lexer::new(code).tokenize()).parse().unwrap())
+    }
+
+    #[test]
+    fn test_mixed_types() {
+        let found = warnings(
+            "my $name = \"bob\";\nmy $n = 5000;\nif ($name < \"carol\") { print 1; }\n\
+             my $s = $n . \"!\";\nmy $t = $name + 1;\nwhile ($n eq 3) { $n--; }\nmy $alias = $name;\n$alias++;\n",
+        );
+        let lines: Vec<usize> = found.iter().map(|&(line, _)| line).collect();
+        assert_eq!(lines, [3, 4, 5, 6, 8]);
+        assert_eq!(found[0].1, "Numeric `<` on a string compares its address; use `lt`");
+        assert!(found[1].1.starts_with("`.` on a number"));
+        assert_eq!(found[2].1, "Arithmetic `+` on a string, which works on its address");
+        assert!(found[3].1.starts_with("String `eq` on a number"));
+    }
+
+    #[test]
+    fn test_clean_and_unknown() {
+        // Matching types, small literals, and a variable that holds both
+        // kinds or comes from a sub
+        let found = warnings(
+            "my $s = \"a\" . 1;\nmy $n = ($s eq \"b\") + 1;\nif ($s eq \"a\" && $n == 2) { print \"x\" . $n; }\n\
+             my $v = 1;\n$v = \"one\";\nprint $v + 1;\nsub f($x) { return $x < 3; }\nprint f(\"z\") . 1;\n",
+        );
+        assert_eq!(found, [(3, "`.` on a number, which from 4096 up reads as an address; convert it with sprintf(\"%d\", ...)".to_string())]);
+    }
+}
@@ -0,0 +1,114 @@
+//! Unused variable warnings
+//!
+//! A `my` variable that nothing in its scope names is most often a typo for
+//! another one, or left over from an edit. `-W unused` warns at its
+//! declaration. Any mention counts as a use, an assignment included, and a
+//! sub's parameters and a `foreach` loop's variable are left alone.
+
+use crate::ast::{Expr, Program, Stmt};
+use crate::compiler::{each_stmt, stmt_exprs};
+use crate::types::operands;
+
+/// Warnings for `program` as `(line, message)`, in source order
+pub fn check(program: &Program) -> Vec<(usize, String)> {
+    let mut checker = Checker { lines: &program.lines, next: 0, warnings: Vec::new() };
+    checker.block(&program.statements, &program.statements);
+    checker.warnings
+}
+
+struct Checker<'a> {
+    /// The parser's statement lines, walked in its pre-order
+    lines: &'a [usize],
+    next: usize,
+    warnings: Vec<(usize, String)>,
+}
+
+impl Checker<'_> {
+    /// Check the declarations in `stmts`, which `scope` can name
+    fn block(&mut self, stmts: &[Stmt], scope: &[Stmt]) {
+        for stmt in stmts {
+            let line = self.lines.get(self.next).copied().unwrap_or(0);
+            self.next += 1;
+            if let Stmt::My(names, _) = stmt {
+                for name in names.iter().filter(|name| !named(scope, name)) {
+                    self.warnings.push((line, format!("Variable {} is declared but never used", name)));
+                }
+            }
+            match stmt {
+                Stmt::If { then_block, elsif_blocks, else_block, .. } => {
+                    self.block(then_block, then_block);
+                    for (_, body) in elsif_blocks {
+                        // The elsif has a line of its own
+                        self.next += 1;
+                        self.block(body, body);
+                    }
+                    if let Some(body) = else_block {
+                        self.block(body, body);
+                    }
+                }
+                Stmt::Unless { then_block, else_block, .. } => {
+                    self.block(then_block, then_block);
+                    if let Some(body) = else_block {
+                        self.block(body, body);
+                    }
+                }
+                Stmt::For { init, body, .. } => {
+                    // The initialiser's variable is the test's and step's too
+                    if let Some(init) = init {
+                        self.block(std::slice::from_ref(init), std::slice::from_ref(stmt));
+                    }
+                    self.block(body, body);
+                }
+                Stmt::While { body, .. } | Stmt::Until { body, .. } | Stmt::Foreach { body, .. } | Stmt::Sub { body, .. }
+                | Stmt::Block(body) | Stmt::Begin(body) => self.block(body, body),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Whether any expression in `stmts` names variable `name`, with any sigil
+fn named(stmts: &[Stmt], name: &str) -> bool {
+    let mut found = false;
+    each_stmt(stmts, &mut |stmt| {
+        found |= stmt_exprs(stmt).into_iter().any(|e| mentions(e, name));
+    });
+    found
+}
+
+fn mentions(e: &Expr, name: &str) -> bool {
+    match e {
+        Expr::ScalarVar(var) | Expr::ArrayVar(var) | Expr::HashVar(var) => var == name,
+        _ => operands(e).into_iter().any(|e| mentions(e, name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn warnings(code: &str) -> Vec<(usize, String)> {
+        check(&Parser::new(Lexer::new(code).tokenize()).parse().unwrap())
+    }
+
+    #[test]
+    fn test_unused() {
+        let found = warnings(
+            "my $a = 1;\nmy @b;\nmy ($c, $d);\nif ($a) {\n    print 1;\n} elsif (1) {\n    my $e = f();\n}\n\
+             for (my $i = 0; $i < 3; $i++) { my %h; }\nsub f { return $b[0] + $c; }\n",
+        );
+        assert_eq!(
+            found,
+            [
+                (3, "Variable d is declared but never used".to_string()),
+                (7, "Variable e is declared but never used".to_string()),
+                (9, "Variable h is declared but never used".to_string()),
+            ]
+        );
+        // Assigned is used, and a name in an inner scope counts for the
+        // outer one
+        assert!(warnings("my $x;\n$x = 2;\nmy $y = 1;\nwhile (1) { my $y = 2; print $y; }\n").is_empty());
+    }
+}
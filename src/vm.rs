@@ -0,0 +1,1601 @@
+//! Host-side bytecode VM
+//!
+//! A pure-Rust interpreter for compiled modules. It uses the same 64K memory
+//! layout as the Z80 runtime: 16-bit little-endian values on a stack growing
+//! down from VM_STACK, length-prefixed strings addressed by pointer (any
+//! value from 0x1000 up is a string), and a bump-allocated heap. It is the reference
+//! semantics for differential testing, and also implements the opcodes the
+//! compiler emits that the Z80 runtime does not handle yet.
+
+use crate::bytecode::{binary, checked, Module, NativeFunc, Op, COUNTERS, HEADER, MAX_TASKS, TASK_STACK, UNBOUNDED_STACK};
+use crate::pack::{self, Unpacked};
+use crate::storage;
+use crate::z80::{self, ARGS, ARGV, BYTECODE_ORG, HEAP_BASE, PORT_CONSOLE, PORT_ERROR, PORT_STATUS, SNAPSHOT, SNAPSHOT_MAGIC, VM_STACK};
+use crate::z80emu::Io;
+
+/// Global variable slots (the Z80 runtime has no globals yet)
+const VM_GLOBALS: u16 = 0x3200;
+
+/// Values at or above this address are string pointers
+pub(crate) const STRING_MIN: u16 = 0x1000;
+
+/// Most elements an array can grow to
+const MAX_ELEMENTS: u16 = 0x2000;
+
+/// Longest string the length byte can describe
+const MAX_STRING: usize = 255;
+
+/// String table offset in images loaded by `reload`
+const RELOAD_STRTAB: u16 = 0x0800;
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// Halt executed
+    Halted,
+    /// The step budget ran out
+    StepLimit,
+    /// The program asked for input after the input ended
+    InputExhausted,
+}
+
+/// A task's saved registers
+#[derive(Debug, Clone, Copy)]
+struct Task {
+    pc: u16,
+    sp: u16,
+    fp: u16,
+}
+
+/// Bytecode interpreter state
+pub struct Vm<T: Io> {
+    pub mem: Vec<u8>,
+    /// Bytecode offset of the next instruction
+    pub pc: u16,
+    pub sp: u16,
+    pub fp: u16,
+    /// Next free heap byte
+    pub heap: u16,
+    /// Top of the heap, below the VM stack the image's header asks for
+    heap_limit: u16,
+    /// Lowest the stack pointer has been, for memstats()
+    pub stack_low: u16,
+    /// Instructions executed
+    pub steps: u64,
+    /// Coverage counters, bumped by Count
+    pub counters: Vec<u16>,
+    /// Whether `resume` found a snapshot, for resumed()
+    pub resumed: bool,
+    /// Timer interrupts taken, for ticks()
+    pub ticks: u16,
+    /// The saved registers of each task but the running one, the main
+    /// program's in the first slot
+    tasks: [Option<Task>; MAX_TASKS],
+    /// Slot of the running task
+    task: usize,
+    pub io: T,
+    /// Port output goes to, switched by Select
+    out_port: u8,
+    code: u16,
+    strings: u16,
+    rom_end: usize,
+    /// The loaded module, whose sub and line tables caller() reads
+    debug: Module,
+}
+
+impl<T: Io> Vm<T> {
+    /// Load `module` at BYTECODE_ORG, as in the ROM
+    pub fn new(module: &Module, io: T) -> Self {
+        let image = module.image();
+        let mut mem = vec![0; 0x10000];
+        let start = BYTECODE_ORG as usize;
+        let rom_end = (start + image.len()).min(mem.len());
+        mem[start..rom_end].copy_from_slice(&image[..rom_end - start]);
+        let strtab = u16::from_le_bytes([image[4], image[5]]);
+
+        Vm {
+            mem,
+            pc: module.entry,
+            sp: VM_STACK,
+            fp: VM_STACK,
+            heap: HEAP_BASE,
+            heap_limit: heap_limit(&image),
+            stack_low: VM_STACK,
+            steps: 0,
+            counters: vec![0; COUNTERS],
+            resumed: false,
+            ticks: 0,
+            tasks: [None; MAX_TASKS],
+            task: 0,
+            out_port: PORT_CONSOLE,
+            io,
+            code: BYTECODE_ORG + HEADER as u16,
+            strings: BYTECODE_ORG + strtab,
+            rom_end,
+            debug: module.clone(),
+        }
+    }
+
+    /// Replace the loaded program with `module`, a longer version of it,
+    /// keeping the stack, heap and variables. The string table goes at a
+    /// fixed offset so that strings already in use keep their addresses as
+    /// code and strings are added.
+    pub fn reload(&mut self, module: &Module) -> Result<(), String> {
+        let image = module.image();
+        let strtab = HEADER + module.code.len();
+        if strtab > RELOAD_STRTAB as usize {
+            return Err(format!("Program too large: {} bytes of bytecode", module.code.len()));
+        }
+        let mut fixed = image[..strtab].to_vec();
+        fixed[4..6].copy_from_slice(&RELOAD_STRTAB.to_le_bytes());
+        fixed.resize(RELOAD_STRTAB as usize, 0);
+        fixed.extend_from_slice(&image[strtab..]);
+
+        let start = BYTECODE_ORG as usize;
+        let end = start + fixed.len();
+        if end > HEAP_BASE as usize {
+            return Err(format!("String table too large: {} strings", module.strings.len()));
+        }
+        self.mem[start..end].copy_from_slice(&fixed);
+        self.strings = BYTECODE_ORG + RELOAD_STRTAB;
+        self.heap_limit = heap_limit(&image);
+        self.rom_end = end;
+        self.debug = module.clone();
+        Ok(())
+    }
+
+    /// Go on from the snapshot suspend() left in memory, as the runtime does
+    /// at reset, if there is one for this program. Memory stands for the
+    /// battery-backed RAM, so call this on the same VM after it stopped.
+    pub fn resume(&mut self) -> bool {
+        let word = |i: u16| self.read16(SNAPSHOT + 2 * i);
+        let words = [word(0), word(1), word(2), word(3), word(4)];
+        let code_len = self.read16(BYTECODE_ORG + 6);
+        self.resumed = words[0] == SNAPSHOT_MAGIC && word(5) == z80::snapshot_check(words, code_len);
+        if self.resumed {
+            [self.pc, self.sp, self.fp, self.heap] = [words[1], words[2], words[3], words[4]];
+            self.write16(SNAPSHOT, 0);
+        }
+        self.resumed
+    }
+
+    /// Fill the parameter block with `args` for @ARGV, as a loader would
+    pub fn set_args(&mut self, args: &[String]) -> Result<(), String> {
+        let block = z80::args_block(ARGS, args)?;
+        for (i, &b) in block.iter().enumerate() {
+            self.write(ARGS + i as u16, b);
+        }
+        Ok(())
+    }
+
+    /// Run until Halt, the input runs dry or `max_steps` instructions have
+    /// executed
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<Exit, String> {
+        loop {
+            if max_steps.is_some_and(|max| self.steps >= max) {
+                return Ok(Exit::StepLimit);
+            }
+            if let Some(exit) = self.step()? {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Execute one instruction, returning Some when the program stops
+    pub fn step(&mut self) -> Result<Option<Exit>, String> {
+        let at = self.pc;
+        let addr = self.code.wrapping_add(at);
+        let op = Op::from_byte(self.read(addr));
+        let byte = self.read(addr.wrapping_add(1));
+        let word = self.read16(addr.wrapping_add(1));
+        self.pc = at.wrapping_add(op.size() as u16);
+        self.steps += 1;
+
+        match op {
+            // The host VM runs a native sub's bytecode
+            Op::Nop | Op::Native => {}
+            Op::Push => self.push(word),
+            Op::PushByte => self.push(byte as i8 as u16),
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Dup => {
+                let v = self.peek(0);
+                self.push(v);
+            }
+            Op::Swap => {
+                let b = self.pop();
+                let a = self.pop();
+                self.push(b);
+                self.push(a);
+            }
+            Op::Over => {
+                let v = self.peek(1);
+                self.push(v);
+            }
+            Op::Rot => {
+                let c = self.pop();
+                let b = self.pop();
+                let a = self.pop();
+                self.push(b);
+                self.push(c);
+                self.push(a);
+            }
+
+            Op::LoadLocal => {
+                let v = self.read16(self.local_addr(byte));
+                self.push(v);
+            }
+            Op::StoreLocal => {
+                let v = self.pop();
+                self.write16(self.local_addr(byte), v);
+            }
+            Op::LoadGlobal => {
+                let v = self.read16(Self::global_addr(word));
+                self.push(v);
+            }
+            Op::StoreGlobal => {
+                let v = self.pop();
+                self.write16(Self::global_addr(word), v);
+            }
+
+            Op::PushStr => {
+                // Walk the length-prefixed table like the runtime does
+                let mut p = self.strings.wrapping_add(1);
+                for _ in 0..word {
+                    p = p.wrapping_add(self.read(p) as u16 + 1);
+                }
+                self.push(p);
+            }
+            Op::StrLen => {
+                let s = self.pop();
+                let len = self.text(s).len() as u16;
+                self.push(len);
+            }
+            Op::StrCat => {
+                let b = self.pop();
+                let a = self.pop();
+                let mut s = self.text(a);
+                s.extend(self.text(b));
+                let p = self.alloc_string(&s);
+                self.push(p);
+            }
+            // Cut to the longest string, like the runtime's copy loop
+            Op::Repeat => {
+                let n = (self.pop() as i16).max(0) as usize;
+                let s = self.pop();
+                let text = self.text(s);
+                let s: Vec<u8> = text.iter().copied().cycle().take((text.len() * n).min(MAX_STRING)).collect();
+                let p = self.alloc_string(&s);
+                self.push(p);
+            }
+            Op::StrCmp | Op::StrEq | Op::StrNe | Op::StrLt | Op::StrGt | Op::StrLe | Op::StrGe => {
+                let b = self.pop();
+                let a = self.pop();
+                let ord = self.text(a).cmp(&self.text(b));
+                let v = match op {
+                    Op::StrCmp => ord as i8 as u16,
+                    Op::StrEq => ord.is_eq() as u16,
+                    Op::StrNe => ord.is_ne() as u16,
+                    Op::StrLt => ord.is_lt() as u16,
+                    Op::StrGt => ord.is_gt() as u16,
+                    Op::StrLe => ord.is_le() as u16,
+                    _ => ord.is_ge() as u16,
+                };
+                self.push(v);
+            }
+
+            // Arrays are a [len][capacity][data] header on the heap, with the
+            // elements at data, which moves when a store past the end grows them
+            Op::NewArray => {
+                let arr = self.alloc(6 + 2 * byte as u16);
+                self.write16(arr, byte as u16);
+                self.write16(arr.wrapping_add(2), byte as u16);
+                self.write16(arr.wrapping_add(4), arr.wrapping_add(6));
+                self.push(arr);
+            }
+            Op::Argv => self.push(ARGV),
+            Op::ArrLen => {
+                let arr = self.pop();
+                let len = self.read16(arr);
+                self.push(len);
+            }
+            Op::ArrGet => {
+                // Past the end reads as 0, and is only an error with CheckIdx
+                let idx = self.pop();
+                let arr = self.pop();
+                let v = match self.element_addr(arr, idx, at) {
+                    Ok(addr) => self.read16(addr),
+                    Err(_) => 0,
+                };
+                self.push(v);
+            }
+            Op::ArrSet => {
+                let v = self.pop();
+                let idx = self.pop();
+                let arr = self.pop();
+                let addr = self.store_addr(arr, idx, at)?;
+                self.write16(addr, v);
+            }
+            Op::ArrRepeat => {
+                let n = (self.pop() as i16).max(0) as usize;
+                let arr = self.pop();
+                let values = self.elements(arr);
+                if values.len() * n > MAX_ELEMENTS as usize {
+                    return Err(format!("Array of {} elements, the limit is {}", values.len() * n, MAX_ELEMENTS));
+                }
+                let values: Vec<u16> = values.iter().copied().cycle().take(values.len() * n).collect();
+                let arr = self.new_array(&values)?;
+                self.push(arr);
+            }
+            Op::CheckIdx => {
+                self.element_addr(self.peek(1), self.peek(0), at)?;
+            }
+
+            // Hashes are a [first][last][cursor] header over a chain of
+            // [next][key][value] entries in the order the keys were added,
+            // with the cursor at the entry each() gave last
+            Op::NewHash => {
+                let hash = self.alloc(6);
+                for i in 0..3 {
+                    self.write16(hash.wrapping_add(2 * i), 0);
+                }
+                self.push(hash);
+            }
+            Op::HashGet => {
+                let key = self.pop();
+                let hash = self.pop();
+                let v = match self.find_entry(hash, key) {
+                    Some(entry) => self.read16(entry.wrapping_add(4)),
+                    None => 0,
+                };
+                self.push(v);
+            }
+            Op::HashSet => {
+                let v = self.pop();
+                let key = self.pop();
+                let hash = self.pop();
+                let entry = match self.find_entry(hash, key) {
+                    Some(entry) => entry,
+                    None => {
+                        let entry = self.alloc(6);
+                        self.write16(entry, 0);
+                        self.write16(entry.wrapping_add(2), key);
+                        match self.read16(hash.wrapping_add(2)) {
+                            0 => self.write16(hash, entry),
+                            last => self.write16(last, entry),
+                        }
+                        self.write16(hash.wrapping_add(2), entry);
+                        entry
+                    }
+                };
+                self.write16(entry.wrapping_add(4), v);
+            }
+
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::BitAnd | Op::BitOr |
+            Op::BitXor | Op::Shl | Op::Shr | Op::CmpEq | Op::CmpNe | Op::CmpLt | Op::CmpGt |
+            Op::CmpLe | Op::CmpGe | Op::Cmp | Op::And | Op::Or => {
+                let b = self.pop();
+                let a = self.pop();
+                let v = binary(op, a, b).ok_or_else(|| format!("Illegal division by zero{}", self.at_line(at)))?;
+                self.push(v);
+            }
+            Op::AddChk | Op::SubChk | Op::MulChk => {
+                let b = self.pop();
+                let a = self.pop();
+                let v = checked(op, a, b).ok_or_else(|| {
+                    let sign = ["+", "-", "*"][op as usize - Op::AddChk as usize];
+                    format!("Integer overflow: {} {} {}", a as i16, sign, b as i16)
+                })?;
+                self.push(v);
+            }
+            Op::Neg => {
+                let v = self.pop();
+                self.push(v.wrapping_neg());
+            }
+            Op::Inc => {
+                let v = self.pop();
+                self.push(v.wrapping_add(1));
+            }
+            Op::Dec => {
+                let v = self.pop();
+                self.push(v.wrapping_sub(1));
+            }
+            Op::BitNot => {
+                let v = self.pop();
+                self.push(!v);
+            }
+            Op::Not => {
+                let v = self.pop();
+                self.push((v == 0) as u16);
+            }
+
+            Op::Jump => self.pc = word,
+            Op::JumpIf => {
+                if self.pop() != 0 {
+                    self.pc = word;
+                }
+            }
+            Op::JumpIfNot => {
+                if self.pop() == 0 {
+                    self.pc = word;
+                }
+            }
+
+            Op::Call => {
+                self.push(self.pc);
+                self.push(self.fp);
+                self.pc = word;
+            }
+            Op::TailCall => {
+                // The new arguments take the old ones' places, the last at
+                // fp, under the same return address and saved fp
+                for i in 0..byte as u16 {
+                    let v = self.read16(self.sp.wrapping_add(i * 2));
+                    self.write16(self.fp.wrapping_add(i * 2), v);
+                }
+                self.sp = self.fp.wrapping_sub(4);
+            }
+            Op::EnterFrame => {
+                self.fp = self.sp.wrapping_add(4);
+                self.sp = self.sp.wrapping_sub(byte as u16 * 2);
+            }
+            Op::LeaveFrame => self.sp = self.fp.wrapping_sub(4),
+            Op::Return | Op::ReturnVal => {
+                // Every call leaves one value in place of its arguments
+                let v = if op == Op::ReturnVal { self.pop() } else { 0 };
+                self.sp = self.fp.wrapping_sub(4);
+                self.fp = self.pop();
+                self.pc = self.pop();
+                self.sp = self.sp.wrapping_add(byte as u16 * 2);
+                self.push(v);
+            }
+
+            Op::Print => {
+                let v = self.pop();
+                for b in self.text(v) {
+                    self.io.output(self.out_port, b);
+                }
+            }
+            Op::PrintLn => self.io.output(self.out_port, b'\n'),
+            Op::PortOut => {
+                let v = self.pop() as u8;
+                let port = self.pop() as u8;
+                // A timer started here counts from this instruction
+                self.io.tick(self.steps);
+                self.io.output(port, v);
+                self.push(v as u16);
+            }
+            Op::PortIn => {
+                let port = self.pop() as u8;
+                let v = self.io.input(port);
+                self.push(v as u16);
+            }
+            Op::Peek => {
+                let addr = self.pop();
+                let v = if byte == 2 { self.read16(addr) } else { self.mem[addr as usize] as u16 };
+                self.push(v);
+            }
+            Op::Poke => {
+                let v = self.pop();
+                let addr = self.pop();
+                let v = if byte == 2 {
+                    self.write16(addr, v);
+                    v
+                } else {
+                    self.mem[addr as usize] = v as u8;
+                    v & 0xFF
+                };
+                self.push(v);
+            }
+            Op::CheckPoke => {
+                let hi = self.pop();
+                let lo = self.pop();
+                let addr = self.peek(0);
+                if !(lo..=hi).contains(&addr) {
+                    return Err(format!("Poke at {:04X} outside --poke-range at {:04X}", addr, at));
+                }
+            }
+            Op::Select => self.out_port = if byte == 1 { PORT_ERROR } else { PORT_CONSOLE },
+            Op::Printf => {
+                let mut args = vec![0; byte as usize];
+                for arg in args.iter_mut().rev() {
+                    *arg = self.pop();
+                }
+                let format = self.pop();
+                for b in self.format(&self.text(format), &args) {
+                    self.io.output(self.out_port, b);
+                }
+            }
+            // Let the other tasks run until a byte arrives
+            Op::InputChar | Op::Input if self.tasks.iter().flatten().next().is_some() && self.io.input_pending() => {
+                self.pc = at;
+                self.io.tick(self.steps);
+                self.switch_task(true);
+            }
+            Op::InputChar => match self.getc() {
+                Some(c) => self.push(c as u16),
+                None => return self.stop_for_input(at),
+            },
+            Op::Input => {
+                let mut line = Vec::new();
+                loop {
+                    match self.getc() {
+                        Some(b'\r') | Some(b'\n') => break,
+                        Some(c) => line.push(c),
+                        None => return self.stop_for_input(at),
+                    }
+                    if line.len() == MAX_STRING {
+                        break;
+                    }
+                }
+                let p = self.alloc_string(&line);
+                self.push(p);
+            }
+
+            Op::CallNative => {
+                let result = self.native(byte)?;
+                self.push(result);
+            }
+
+            Op::Match => {
+                let pattern = self.pop();
+                let subject = self.pop();
+                let found = regex_match(&self.text(subject), &self.text(pattern));
+                self.push(found as u16);
+            }
+
+            Op::Die => {
+                let v = self.pop();
+                return Err(String::from_utf8_lossy(&self.text(v)).into_owned());
+            }
+
+            Op::Suspend => {
+                self.push(1);
+                let words = [SNAPSHOT_MAGIC, self.pc, self.sp, self.fp, self.heap];
+                let check = z80::snapshot_check(words, self.read16(BYTECODE_ORG + 6));
+                for (i, w) in words.into_iter().chain([check]).enumerate() {
+                    self.write16(SNAPSHOT + 2 * i as u16, w);
+                }
+                return Ok(Some(Exit::Halted));
+            }
+            Op::Resumed => self.push(self.resumed as u16),
+            Op::Spawn => {
+                let free = (1..MAX_TASKS).find(|&i| i != self.task && self.tasks[i].is_none());
+                if let Some(i) = free {
+                    let top = self.alloc(TASK_STACK).wrapping_add(TASK_STACK);
+                    self.tasks[i] = Some(Task { pc: word, sp: top, fp: top });
+                }
+                self.push(free.unwrap_or(0) as u16);
+            }
+            Op::Yield => {
+                self.push(0);
+                self.switch_task(true);
+            }
+            Op::TaskEnd => self.switch_task(false),
+            Op::Ticks => {
+                // Take the timer's interrupts as the ISR would have, with
+                // each instruction standing for a T-state
+                self.io.tick(self.steps);
+                while self.io.interrupt() {
+                    self.ticks = self.ticks.wrapping_add(1);
+                }
+                self.push(self.ticks);
+            }
+
+            Op::Count => self.counters[byte as usize] = self.counters[byte as usize].wrapping_add(1),
+
+            Op::Halt => {
+                // The runtime leaves PC on the Halt instruction
+                self.pc = at;
+                return Ok(Some(Exit::Halted));
+            }
+
+            _ => return Err(format!("Unsupported opcode {:?} at {:04X}", op, at)),
+        }
+        if self.heap > self.heap_limit {
+            return Err(format!("Out of memory: the heap ran into the {} bytes kept for the VM stack", VM_STACK - self.heap_limit));
+        }
+        Ok(None)
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    pub fn read16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    /// Writes into the loaded image are ignored, as ROM would ignore them
+    fn write(&mut self, addr: u16, value: u8) {
+        if (addr as usize) < BYTECODE_ORG as usize || addr as usize >= self.rom_end {
+            self.mem[addr as usize] = value;
+        }
+    }
+
+    fn write16(&mut self, addr: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
+    fn push(&mut self, v: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.stack_low = self.stack_low.min(self.sp);
+        self.write16(self.sp, v);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let v = self.read16(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        v
+    }
+
+    /// Value `depth` entries below the top of the stack
+    fn peek(&self, depth: u16) -> u16 {
+        self.read16(self.sp.wrapping_add(depth * 2))
+    }
+
+    fn local_addr(&self, idx: u8) -> u16 {
+        self.fp.wrapping_add((idx as i8 as i16 * 2) as u16)
+    }
+
+    fn alloc(&mut self, size: u16) -> u16 {
+        let p = self.heap;
+        self.heap = self.heap.wrapping_add(size);
+        for i in 0..size {
+            self.write(p.wrapping_add(i), 0);
+        }
+        p
+    }
+
+    fn alloc_string(&mut self, bytes: &[u8]) -> u16 {
+        let bytes = &bytes[..bytes.len().min(MAX_STRING)];
+        let p = self.alloc(bytes.len() as u16 + 1);
+        self.write(p, bytes.len() as u8);
+        for (i, &b) in bytes.iter().enumerate() {
+            self.write(p.wrapping_add(1 + i as u16), b);
+        }
+        p
+    }
+
+    /// Whether `v` points at a string: one of the module's, or something on
+    /// the heap, where every string made at run time is
+    pub fn is_string(&self, v: u16) -> bool {
+        let mut p = self.strings.wrapping_add(1);
+        for _ in 0..self.debug.strings.len() {
+            if p == v {
+                return true;
+            }
+            p = p.wrapping_add(self.read(p) as u16 + 1);
+        }
+        (HEAP_BASE..self.heap).contains(&v)
+    }
+
+    /// Address of global `idx`
+    pub fn global_addr(idx: u16) -> u16 {
+        VM_GLOBALS.wrapping_add(idx.wrapping_mul(2))
+    }
+
+    /// Address of the variable the next instruction stores to, if it is a
+    /// StoreLocal or StoreGlobal
+    pub fn store_target(&self) -> Option<u16> {
+        let addr = self.code.wrapping_add(self.pc);
+        match Op::from_byte(self.read(addr)) {
+            Op::StoreLocal => Some(self.local_addr(self.read(addr.wrapping_add(1)))),
+            Op::StoreGlobal => Some(Self::global_addr(self.read16(addr.wrapping_add(1)))),
+            _ => None,
+        }
+    }
+
+    /// Local `idx` of the current frame, or of the main program once it
+    /// has halted
+    pub fn local(&self, idx: u8) -> u16 {
+        self.read16(self.local_addr(idx))
+    }
+
+    /// " at line N" for the instruction at `at`, when the line is known
+    fn at_line(&self, at: u16) -> String {
+        self.debug.line_at(at).map_or_else(String::new, |line| format!(" at line {}", line))
+    }
+
+    /// The text of a value: a string's bytes, or a number in decimal
+    pub fn text(&self, v: u16) -> Vec<u8> {
+        if v >= STRING_MIN {
+            let len = self.read(v) as u16;
+            (1..=len).map(|i| self.read(v.wrapping_add(i))).collect()
+        } else {
+            v.to_string().into_bytes()
+        }
+    }
+
+    /// `format` with its conversions replaced by `args`: `%d`/`%i` signed,
+    /// `%u` unsigned, `%x`/`%X` hex, `%c` a character, `%s` a value's text
+    /// and `%%` a percent sign, each with optional `-` (left align) and `0`
+    /// (zero fill) flags, a width and a precision (the most bytes of a
+    /// string, the fewest digits of a number). Missing arguments are 0.
+    fn format(&self, format: &[u8], args: &[u16]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut args = args.iter().copied();
+        let mut i = 0;
+        while i < format.len() {
+            if format[i] != b'%' {
+                out.push(format[i]);
+                i += 1;
+                continue;
+            }
+            let start = i;
+            i += 1;
+            let (mut left, mut zero) = (false, false);
+            while let Some(&flag @ (b'-' | b'0')) = format.get(i) {
+                left |= flag == b'-';
+                zero |= flag == b'0';
+                i += 1;
+            }
+            let mut width = 0;
+            while let Some(&digit @ b'0'..=b'9') = format.get(i) {
+                width = width * 10 + (digit - b'0') as usize;
+                i += 1;
+            }
+            let mut precision = None;
+            if format.get(i) == Some(&b'.') {
+                i += 1;
+                let mut p = 0;
+                while let Some(&digit @ b'0'..=b'9') = format.get(i) {
+                    p = p * 10 + (digit - b'0') as usize;
+                    i += 1;
+                }
+                precision = Some(p);
+            }
+            let Some(&conversion) = format.get(i) else {
+                out.extend_from_slice(&format[start..]);
+                break;
+            };
+            i += 1;
+            let mut arg = || args.next().unwrap_or(0);
+            let mut text = match conversion {
+                b'%' => b"%".to_vec(),
+                b'd' | b'i' => (arg() as i16).to_string().into_bytes(),
+                b'u' => arg().to_string().into_bytes(),
+                b'x' => format!("{:x}", arg()).into_bytes(),
+                b'X' => format!("{:X}", arg()).into_bytes(),
+                b'c' => vec![arg() as u8],
+                b's' => self.text(arg()),
+                // Not a conversion: print it as written
+                _ => format[start..i].to_vec(),
+            };
+            match (precision, conversion) {
+                (Some(p), b's') => text.truncate(p),
+                (Some(p), b'd' | b'i' | b'u' | b'x' | b'X') => {
+                    // At least p digits, and the width is then filled with spaces
+                    let sign = usize::from(text.first() == Some(&b'-'));
+                    let zeros = p.saturating_sub(text.len() - sign);
+                    text.splice(sign..sign, std::iter::repeat_n(b'0', zeros));
+                    zero = false;
+                }
+                _ => {}
+            }
+            let fill = width.saturating_sub(text.len());
+            if left {
+                out.extend_from_slice(&text);
+                out.extend(std::iter::repeat_n(b' ', fill));
+            } else if zero {
+                // Zeros go after the sign
+                let sign = usize::from(text.first() == Some(&b'-'));
+                out.extend_from_slice(&text[..sign]);
+                out.extend(std::iter::repeat_n(b'0', fill));
+                out.extend_from_slice(&text[sign..]);
+            } else {
+                out.extend(std::iter::repeat_n(b' ', fill));
+                out.extend_from_slice(&text);
+            }
+        }
+        out
+    }
+
+    fn element_addr(&self, arr: u16, idx: u16, at: u16) -> Result<u16, String> {
+        let len = self.read16(arr);
+        if idx >= len {
+            return Err(format!("Array index {} out of range (length {}) at {:04X}", idx, len, at));
+        }
+        Ok(self.read16(arr.wrapping_add(4)).wrapping_add(idx * 2))
+    }
+
+    /// Address of element `idx` to store to, extending the array with
+    /// zeros to reach it
+    fn store_addr(&mut self, arr: u16, idx: u16, at: u16) -> Result<u16, String> {
+        let len = self.read16(arr);
+        if idx >= len {
+            if idx >= MAX_ELEMENTS {
+                return Err(format!("Array index {} out of range (length {}) at {:04X}", idx, len, at));
+            }
+            self.reserve(arr, idx + 1);
+            let data = self.read16(arr.wrapping_add(4));
+            for i in len..idx {
+                self.write16(data.wrapping_add(i * 2), 0);
+            }
+            self.write16(arr, idx + 1);
+        }
+        Ok(self.read16(arr.wrapping_add(4)).wrapping_add(idx * 2))
+    }
+
+    /// Make room for `n` elements, moving them to a new block twice the
+    /// size (or `n`) if they don't fit
+    fn reserve(&mut self, arr: u16, n: u16) {
+        let cap = self.read16(arr.wrapping_add(2));
+        if n <= cap {
+            return;
+        }
+        let cap = n.max(cap.saturating_mul(2).min(MAX_ELEMENTS));
+        let data = self.alloc(cap * 2);
+        let old = self.read16(arr.wrapping_add(4));
+        for i in 0..self.read16(arr) {
+            let v = self.read16(old.wrapping_add(i * 2));
+            self.write16(data.wrapping_add(i * 2), v);
+        }
+        self.write16(arr.wrapping_add(2), cap);
+        self.write16(arr.wrapping_add(4), data);
+    }
+
+    fn elements(&self, arr: u16) -> Vec<u16> {
+        let data = self.read16(arr.wrapping_add(4));
+        (0..self.read16(arr)).map(|i| self.read16(data.wrapping_add(i * 2))).collect()
+    }
+
+    /// Replace the elements of `arr` with `values`
+    fn set_elements(&mut self, arr: u16, values: &[u16]) -> Result<(), String> {
+        let len = u16::try_from(values.len()).ok().filter(|&n| n <= MAX_ELEMENTS).ok_or_else(|| {
+            format!("Array of {} elements, the limit is {}", values.len(), MAX_ELEMENTS)
+        })?;
+        self.reserve(arr, len);
+        let data = self.read16(arr.wrapping_add(4));
+        for (i, &v) in values.iter().enumerate() {
+            self.write16(data.wrapping_add(i as u16 * 2), v);
+        }
+        self.write16(arr, len);
+        Ok(())
+    }
+
+    /// A new array holding `values`
+    fn new_array(&mut self, values: &[u16]) -> Result<u16, String> {
+        let arr = self.alloc(6);
+        self.write16(arr.wrapping_add(4), arr.wrapping_add(6));
+        self.set_elements(arr, values)?;
+        Ok(arr)
+    }
+
+    /// Find the entry for `key`; string keys compare by content
+    fn find_entry(&self, hash: u16, key: u16) -> Option<u16> {
+        let key_text = self.text(key);
+        let mut entry = self.read16(hash);
+        while entry != 0 {
+            if self.text(self.read16(entry.wrapping_add(2))) == key_text {
+                return Some(entry);
+            }
+            entry = self.read16(entry);
+        }
+        None
+    }
+
+    /// Run native `id`. The file natives go over the storage ports, and
+    /// their failures are values for the program to test: 0 from open and
+    /// readline, false from the rest, and eof is true when it can't tell.
+    fn native(&mut self, id: u8) -> Result<u16, String> {
+        Ok(match NativeFunc::from_byte(id) {
+            // splice(arr, offset, count, replacement array or 0), giving the
+            // removed elements; the offset and count are cut to fit
+            Some(NativeFunc::Splice) => {
+                let with = self.pop();
+                let count = self.pop();
+                let offset = self.pop();
+                let arr = self.pop();
+                let mut values = self.elements(arr);
+                let offset = (offset as usize).min(values.len());
+                let end = offset + (count as usize).min(values.len() - offset);
+                let with = if with == 0 { Vec::new() } else { self.elements(with) };
+                let removed: Vec<u16> = values.splice(offset..end, with).collect();
+                self.set_elements(arr, &values)?;
+                self.new_array(&removed)?
+            }
+            // reserve(arr, n): room for n elements without moving them again,
+            // giving the capacity
+            Some(NativeFunc::Reserve) => {
+                let n = self.pop();
+                let arr = self.pop();
+                if n > MAX_ELEMENTS {
+                    return Err(format!("Array of {} elements, the limit is {}", n, MAX_ELEMENTS));
+                }
+                self.reserve(arr, n);
+                self.read16(arr.wrapping_add(2))
+            }
+            // pack(template, args..., argc)
+            Some(NativeFunc::Pack) => {
+                let mut args = vec![(0, Vec::new()); self.pop() as usize];
+                for arg in args.iter_mut().rev() {
+                    let v = self.pop();
+                    let text = self.text(v);
+                    *arg = (if v >= STRING_MIN { pack::number(&text) } else { v }, text);
+                }
+                let template = self.pop();
+                let bytes = pack::pack(&String::from_utf8_lossy(&self.text(template)), &args)?;
+                self.alloc_string(&bytes)
+            }
+            Some(NativeFunc::Unpack) => {
+                let data = self.pop();
+                let template = self.pop();
+                let data = self.text(data);
+                let mut values = Vec::new();
+                for item in pack::unpack(&String::from_utf8_lossy(&self.text(template)), &data)? {
+                    values.push(match item {
+                        Unpacked::Num(v) => v,
+                        Unpacked::Bytes(bytes) => self.alloc_string(&bytes),
+                    });
+                }
+                self.new_array(&values)?
+            }
+            // sprintf(format, args..., argc)
+            Some(NativeFunc::Sprintf) => {
+                let mut args = vec![0; self.pop() as usize];
+                for arg in args.iter_mut().rev() {
+                    *arg = self.pop();
+                }
+                let format = self.pop();
+                let text = self.format(&self.text(format), &args);
+                self.alloc_string(&text)
+            }
+            Some(case @ (NativeFunc::Uc | NativeFunc::Lc)) => {
+                let v = self.pop();
+                let mut text = self.text(v);
+                if case == NativeFunc::Uc {
+                    text.make_ascii_uppercase();
+                } else {
+                    text.make_ascii_lowercase();
+                }
+                self.alloc_string(&text)
+            }
+            // A string with room for the longest, which buf_append fills in
+            // place rather than copying
+            Some(NativeFunc::BufNew) => {
+                let buf = self.alloc(MAX_STRING as u16 + 1);
+                self.write(buf, 0);
+                buf
+            }
+            Some(NativeFunc::BufAppend) => {
+                let v = self.pop();
+                let buf = self.pop();
+                if !(HEAP_BASE..self.heap).contains(&buf) {
+                    return Err("buf_append needs a buffer from buf_new".to_string());
+                }
+                let len = self.read(buf) as usize;
+                let text = self.text(v);
+                let text = &text[..text.len().min(MAX_STRING - len)];
+                for (i, &b) in text.iter().enumerate() {
+                    self.write(buf.wrapping_add((1 + len + i) as u16), b);
+                }
+                self.write(buf, (len + text.len()) as u8);
+                buf
+            }
+            Some(NativeFunc::BufStr) => {
+                let buf = self.pop();
+                let text = self.text(buf);
+                self.alloc_string(&text)
+            }
+            // The next [key, value] in the order they were added, or 0 after
+            // the last, when the next call starts over
+            Some(NativeFunc::Each) => {
+                let hash = self.pop();
+                let cursor = hash.wrapping_add(4);
+                let entry = match self.read16(cursor) {
+                    0 => self.read16(hash),
+                    last => self.read16(last),
+                };
+                self.write16(cursor, entry);
+                if entry == 0 {
+                    0
+                } else {
+                    let pair = [self.read16(entry.wrapping_add(2)), self.read16(entry.wrapping_add(4))];
+                    self.new_array(&pair)?
+                }
+            }
+            Some(NativeFunc::Pairs) => {
+                let hash = self.pop();
+                let mut pairs = Vec::new();
+                let mut entry = self.read16(hash);
+                while entry != 0 {
+                    pairs.extend([self.read16(entry.wrapping_add(2)), self.read16(entry.wrapping_add(4))]);
+                    entry = self.read16(entry);
+                }
+                self.new_array(&pairs)?
+            }
+            Some(NativeFunc::Slice) => {
+                let keys = self.pop();
+                let hash = self.pop();
+                let values: Vec<u16> = self.elements(keys).into_iter().map(|key| match self.find_entry(hash, key) {
+                    Some(entry) => self.read16(entry.wrapping_add(4)),
+                    None => 0,
+                }).collect();
+                self.new_array(&values)?
+            }
+            Some(NativeFunc::Exists) => {
+                let idx = self.pop();
+                let arr = self.pop();
+                (idx < self.read16(arr)) as u16
+            }
+            Some(NativeFunc::KeyExists) => {
+                let key = self.pop();
+                let hash = self.pop();
+                self.find_entry(hash, key).is_some() as u16
+            }
+            // The old value, or 0; deleting the last element shortens the array
+            Some(NativeFunc::Delete) => {
+                let idx = self.pop();
+                let arr = self.pop();
+                let len = self.read16(arr);
+                if idx >= len {
+                    0
+                } else {
+                    let addr = self.element_addr(arr, idx, 0)?;
+                    let old = self.read16(addr);
+                    self.write16(addr, 0);
+                    if idx + 1 == len {
+                        self.write16(arr, idx);
+                    }
+                    old
+                }
+            }
+            Some(NativeFunc::Open) => {
+                let mode = self.pop();
+                let name = self.pop();
+                let mode = match self.text(mode).as_slice() {
+                    b"<" | b"r" => b'r',
+                    b">" | b"w" => b'w',
+                    b">>" | b"a" => b'a',
+                    other => return Err(format!("Bad open mode {:?}", String::from_utf8_lossy(other))),
+                };
+                let args = [&[mode], self.text(name).as_slice()].concat();
+                self.file_command(storage::OPEN, &args).and_then(|r| r.first().copied()).unwrap_or(0) as u16
+            }
+            Some(NativeFunc::Close) => {
+                let handle = self.pop() as u8;
+                self.file_command(storage::CLOSE, &[handle]).is_some() as u16
+            }
+            Some(NativeFunc::Read) => {
+                let handle = self.pop() as u8;
+                match self.file_command(storage::READ, &[handle]) {
+                    Some(line) => self.alloc_string(&line),
+                    None => 0,
+                }
+            }
+            Some(NativeFunc::Write) => {
+                let text = self.pop();
+                let handle = self.pop() as u8;
+                let args = [&[handle], self.text(text).as_slice()].concat();
+                self.file_command(storage::WRITE, &args).is_some() as u16
+            }
+            Some(NativeFunc::Eof) => {
+                let handle = self.pop() as u8;
+                self.file_command(storage::EOF, &[handle]).is_none_or(|r| r.first() != Some(&0)) as u16
+            }
+            // [sub, line] for the call `level` frames up, or an empty
+            // array outside any sub
+            Some(NativeFunc::Caller) => {
+                let level = self.pop();
+                let (mut site, mut fp) = (self.pc, self.fp);
+                for _ in 0..=level {
+                    if self.debug.sub_at(site).is_none() {
+                        return self.new_array(&[]);
+                    }
+                    // The return address is just past the 3-byte Call
+                    site = self.read16(fp.wrapping_sub(2)).wrapping_sub(3);
+                    fp = self.read16(fp.wrapping_sub(4));
+                }
+                let name = self.debug.sub_at(site).unwrap_or("main").to_string();
+                let line = self.debug.line_at(site).unwrap_or(0) as u16;
+                let name = self.alloc_string(name.as_bytes());
+                self.new_array(&[name, line])?
+            }
+            // The same report as the runtime's, on the selected channel
+            Some(NativeFunc::MemStats) => {
+                let report = format!("stack: {} heap: {}\n", VM_STACK - self.stack_low, self.heap - HEAP_BASE);
+                for b in report.bytes() {
+                    self.io.output(self.out_port, b);
+                }
+                0
+            }
+            _ => return Err(format!("Unsupported native function {}", id)),
+        })
+    }
+
+    /// Send a storage command; its result bytes, or None if it failed
+    fn file_command(&mut self, command: u8, args: &[u8]) -> Option<Vec<u8>> {
+        for &b in args {
+            self.io.output(storage::PORT_FILE_DATA, b);
+        }
+        self.io.output(storage::PORT_FILE_CMD, command);
+        let mut result = Vec::new();
+        loop {
+            let status = self.io.input(storage::PORT_FILE_CMD);
+            if status & storage::STATUS_FAILED != 0 {
+                return None;
+            }
+            if status & storage::STATUS_READY == 0 {
+                return Some(result);
+            }
+            result.push(self.io.input(storage::PORT_FILE_DATA));
+        }
+    }
+
+    /// Read a console byte, waiting like the runtime's polling loop.
+    /// Returns None if the input has ended.
+    fn getc(&mut self) -> Option<u8> {
+        loop {
+            if self.io.input(PORT_STATUS) & 0x01 != 0 {
+                return Some(self.io.input(PORT_CONSOLE));
+            }
+            if self.io.input_exhausted() {
+                return None;
+            }
+        }
+    }
+
+    /// Run the next task after the running one, which is kept to go on
+    /// with later if `save`, or ended
+    fn switch_task(&mut self, save: bool) {
+        if save {
+            self.tasks[self.task] = Some(Task { pc: self.pc, sp: self.sp, fp: self.fp });
+        }
+        let next = (1..=MAX_TASKS).map(|k| (self.task + k) % MAX_TASKS).find(|&i| self.tasks[i].is_some());
+        if let Some(task) = next.and_then(|i| self.tasks[i].take()) {
+            [self.pc, self.sp, self.fp] = [task.pc, task.sp, task.fp];
+            self.task = next.unwrap();
+        }
+    }
+
+    /// Leave PC on the input instruction so the state matches a program
+    /// still waiting for input
+    fn stop_for_input(&mut self, at: u16) -> Result<Option<Exit>, String> {
+        self.pc = at;
+        Ok(Some(Exit::InputExhausted))
+    }
+}
+
+/// Highest the heap may reach under the VM stack of `image`, the whole
+/// space when its depth has no bound
+fn heap_limit(image: &[u8]) -> u16 {
+    match u16::from_le_bytes([image[10], image[11]]) {
+        UNBOUNDED_STACK => VM_STACK,
+        stack => VM_STACK.saturating_sub(stack),
+    }
+}
+
+/// Substring search where '.' in the pattern matches any byte
+fn regex_match(subject: &[u8], pattern: &[u8]) -> bool {
+    pattern.is_empty()
+        || subject
+            .windows(pattern.len())
+            .any(|window| window.iter().zip(pattern).all(|(&s, &p)| p == b'.' || p == s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::z80emu::Console;
+
+    fn run_with_input(code: &str, input: &[u8]) -> (Vm<Console>, Result<Exit, String>) {
+        let mut lexer = Lexer::new(code);
+        let mut parser = Parser::new(lexer.tokenize());
+        let program = parser.parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        let mut vm = Vm::new(&module, Console::scripted(input));
+        let exit = vm.run(Some(100_000));
+        (vm, exit)
+    }
+
+    fn output(code: &str) -> String {
+        let (vm, exit) = run_with_input(code, b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        String::from_utf8_lossy(vm.io.output()).to_string()
+    }
+
+    #[test]
+    fn test_print_strings_and_numbers() {
+        assert_eq!(output(r#"print "Hi ", 42, " ", 1234, "\n";"#), "Hi 42 1234\n");
+    }
+
+    #[test]
+    fn test_printf() {
+        assert_eq!(
+            output(r#"my $n = 0 - 42; printf "[%d] [%5d] [%-5d] [%05d] [%u]\n", $n, 42, 42, $n, $n;"#),
+            "[-42] [   42] [42   ] [-0042] [65494]\n"
+        );
+        assert_eq!(output(r#"printf "%x %04X %c%s 100%% %q", 255, 255, 65, "bc";"#), "ff 00FF Abc 100% %q");
+        // Missing arguments are 0
+        assert_eq!(output(r#"printf "%d|%3s|%", 7;"#), "7|  0|%");
+    }
+
+    #[test]
+    fn test_sprintf() {
+        let code = r#"my $row = sprintf("%-8s|%8.3s|%.3d|%6.4x|%-05d", "name", "abcdef", 7, 255, 0 - 12);
+print $row, "|", sprintf("%%"), "|", sprintf("%s", 1);"#;
+        assert_eq!(output(code), "name    |     abc|007|  00ff|-12  |%|1");
+    }
+
+    #[test]
+    fn test_file_natives() {
+        let dir = std::env::temp_dir().join(format!("microperl_vm_files_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let code = r#"
+            my $out = open("notes.txt", ">");
+            write($out, "one\ntwo\n");
+            close($out);
+            my $in = open("notes.txt", "<");
+            while (!eof($in)) { print "> ", readline($in); }
+            print close($in), close($in), open("missing.txt", "<"), readline(9), eof(9);
+        "#;
+        let program = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        let mut vm = Vm::new(&module, Console::scripted(b"").with_files(storage::HostDir::new(&dir)));
+        assert_eq!(vm.run(Some(100_000)), Ok(Exit::Halted));
+        assert_eq!(String::from_utf8_lossy(vm.io.output()), "> one\n> two\n10001");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // With no storage attached every call fails
+        assert_eq!(output(r#"print open("a", "<"), eof(1);"#), "01");
+    }
+
+    #[test]
+    fn test_array_growth() {
+        let code = r#"
+            my @a = [1, 2];
+            $a[5] = 6;
+            $a[1] += 10;
+            print $a[1], ",", $a[4], ",", $a[5], ",", exists $a[5], exists $a[6], ",";
+            print delete $a[5], delete $a[9], exists $a[5], exists $a[4];
+            my %h = {};
+            $h{"k"} = 3;
+            print ",", $h{"k"};
+        "#;
+        assert_eq!(output(code), "12,0,6,10,6001,3");
+        // Each growth at least doubles the capacity
+        let code = "my @a = [0];
+my $i = 0;
+while ($i < 300) { $a[$i] = $i; $i++; }
+print $a[299], $a[0];";
+        assert_eq!(output(code), "2990");
+        let program = Parser::new(Lexer::new("my @a = [1];
+$a[9000] = 1;").tokenize()).parse().unwrap();
+        let module = Compiler::new().compile(&program).unwrap();
+        let mut vm = Vm::new(&module, Console::scripted(b""));
+        assert!(vm.run(Some(1000)).unwrap_err().starts_with("Array index 9000 out of range (length 1)"));
+    }
+
+    #[test]
+    fn test_undef() {
+        let code = r#"my $x;
+my %h = {};
+my @a = [1];
+print defined($x), defined $h{"k"}, defined($a[5]), defined(0), "|";
+print $x + 2, $h{"k"} + 3, $a[5] ? "t" : "f", "|";
+$x = 1;
+undef $x;
+print !$x, $x == 0, $x eq "", $x . "s", "|", $x, "\n";
+my $y = $x;
+print defined($y), "|";
+$y = 4096;
+print defined($y), defined(undef), defined(f()), "\n";
+sub f { return; }"#;
+        let (vm, exit) = run_with_input(code, b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        assert_eq!(String::from_utf8_lossy(vm.io.output()), "0001|23f|111s|\n0|100\n");
+        assert_eq!(String::from_utf8_lossy(vm.io.errors()), "Use of uninitialized value $x in print at line 8\n");
+
+        // A scalar assigned an element or key keeps whether it exists,
+        // and one assigned a sub's result whether it returned undef
+        let code = r#"our %h;
+$h{"a"} = 1;
+my @a = [1];
+my $v = $h{"nope"};
+my $w = $h{"a"};
+my $e = $a[5];
+print defined($v) ? "d" : "u", defined($w) ? "d" : "u", defined($e) ? "d" : "u", "|";
+sub f($n) { if ($n) { return; } return $n + 5; }
+sub g($n) { return f($n); }
+sub h($k) { return $h{$k}; }
+sub k() { my $z = 3; }
+my $r = f(1);
+my $s = f(0);
+print defined($r) ? "d" : "u", defined($s) ? "d" : "u", $s, defined(f(1)) ? "d" : "u", "|";
+$r = g(1);
+$s = h("a");
+my $t = k();
+print defined($r) ? "d" : "u", defined($s) ? "d" : "u", defined($t) ? "d" : "u", defined(h("b")) ? "d" : "u", f(1) + 2;"#;
+        assert_eq!(output(code), "udu|ud5u|uddu2");
+
+        // Arithmetic and conditions see only numbers, so none of them
+        // makes undef
+        let code = "my $x = 4095;\n$x++;\n$x++;\nprint $x - 4096, 4096 * 2 / 1024, \"|\";\nif (64 * 64) { print \"t|\"; }\n\
+                    my $n = 4200;\nwhile ($n) { $n--; }\nprint $n;";
+        assert_eq!(output(code), "18|t|0");
+    }
+
+    #[test]
+    fn test_case() {
+        let code = r#"my $s = "MiXed 1";
+print uc($s), "|", lc $s, "|", "\Uup\E \Ldown\E";"#;
+        assert_eq!(output(code), "MIXED 1|mixed 1|UP down");
+    }
+
+    #[test]
+    fn test_each() {
+        // Insertion order, with a new value keeping its key's place, then
+        // 0 once and around again
+        let code = r#"my %h = {"zebra" => 1, "apple" => 2};
+$h{"mango"} = 3;
+$h{"zebra"} = 4;
+my @p;
+while (@p = each(%h)) { print $p[0], "=", $p[1], " "; }
+@p = each(%h);
+print $p[0], ",", each(%h)[1];"#;
+        assert_eq!(output(code), "zebra=4 apple=2 mango=3 zebra,2");
+    }
+
+    #[test]
+    fn test_hash_lists() {
+        let code = r#"my %h = {"a" => 1, "b" => 2};
+$h{"c"} = 3;
+my @pairs = %h;
+my ($k, $n) = %h;
+my @keys = ["c", "a", "z"];
+my @v = @h{@keys};
+my ($x, $y) = @h{"b", "a"};
+print $pairs[0], $pairs[1], $pairs[4], $pairs[5], "|", $k, $n, "|";
+print $v[0], $v[1], $v[2], "|", $x, $y;"#;
+        assert_eq!(output(code), "a1c3|a1|310|21");
+    }
+
+    #[test]
+    fn test_pack() {
+        let code = r#"my $n = 258;
+my $packet = pack("C n a3", 200, $n, "ab");
+my @fields = unpack("C n a*", $packet);
+print $fields[0], ",", $fields[1], ",", $fields[2], ",", unpack("C*", pack("v", $n))[1];"#;
+        assert_eq!(output(code), "200,258,ab\0,1");
+    }
+
+    #[test]
+    fn test_splice() {
+        let code = r#"
+            my @a = [1, 2, 3, 4, 5];
+            my @r = splice(@a, 1, 2, 7, 8, 9);
+            print $r[0], $r[1], exists $r[2], ",", $a[0], $a[1], $a[2], $a[3], $a[4], $a[5], exists $a[6], ",";
+            my @b = [6, 6];
+            splice(@a, 0, 1, @b);
+            my @t = splice(@a, 4);
+            print $a[0], $a[1], $a[2], $a[3], exists $a[4], ",", $t[0], $t[1], $t[2], ",";
+            my @none = splice(@a, 9, 1);
+            print exists $none[0], $a[3];
+        "#;
+        assert_eq!(output(code), "230,1789450,66780,945,08");
+    }
+
+    #[test]
+    fn test_repeat() {
+        let code = r#"
+            my $n = 3;
+            my @a = (7, 8) x $n;
+            my @none = [1] x -1;
+            my $s = $n x $n;
+            $s x= 2;
+            print $a[0], $a[5], exists $a[6], exists $none[0], ",", $s, ",", "ab" x 200;
+        "#;
+        assert_eq!(output(code), format!("7800,333333,{}a", "ab".repeat(127)));
+    }
+
+    #[test]
+    fn test_caller() {
+        let code = r#"
+            sub here { our @c = caller(); our @up = caller(1); print $c[0], ":", $c[1], ":", exists $up[0] ? $up[1] : "-", ","; }
+            sub outer { here(); }
+            here();
+            outer();
+            my @top = caller(); print exists $top[0], __LINE__;
+        "#;
+        assert_eq!(output(code), "main:4:-,outer:3:5,06");
+    }
+
+    #[test]
+    fn test_sub_parameters() {
+        assert_eq!(output("sub pair($a, $b) { print $a, \"-\", $b; }\npair(1, 2);"), "1-2");
+        let code = "sub show($label, @values) { print $label, \":\", $values[0], $values[1], exists $values[2], \" \"; }\n\
+                    show(\"a\", 1, 2);\nshow(\"b\", 3, 4, 5);";
+        assert_eq!(output(code), "a:120 b:341 ");
+    }
+
+    #[test]
+    fn test_tail_call() {
+        // Each call would take 6 bytes of stack without reusing the frame
+        let code = "sub down($n, $acc) { if ($n == 0) { return $acc; } return down($n - 1, $acc + 2); }\n\
+                    sub start($n) { return down($n, 0); }\nprint start(2000) / 4, \" \", start(3);";
+        let (vm, exit) = run_with_input(code, b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"1000 6");
+        assert!(vm.stack_low > VM_STACK - 64);
+    }
+
+    #[test]
+    fn test_string_buffers() {
+        let code = "my $b = buf_new();\nbuf_append($b, \"ab\");\nmy $s = buf_str(buf_append($b, 12));\n\
+                    buf_append($b, \"!\");\nprint $b, \" \", $s;";
+        assert_eq!(output(code), "ab12! ab12");
+        let (_, exit) = run_with_input("buf_append(\"lit\", 1);", b"");
+        assert_eq!(exit, Err("buf_append needs a buffer from buf_new".to_string()));
+
+        // `.=` in a loop fills one buffer, where copying each pass would use
+        // 2 + 3 + ... + 101 bytes of heap
+        let program = Parser::new(Lexer::new("my $s = \"\";\nmy $i = 0;\nwhile ($i < 100) { $s .= \"x\"; $i++; }\nprint $s;").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_string_buffers(true);
+        let mut vm = Vm::new(&compiler.compile(&program).unwrap(), Console::scripted(b""));
+        assert_eq!(vm.run(Some(100_000)), Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), [b'x'; 100]);
+        assert_eq!(vm.heap - HEAP_BASE, 256);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let fill = "my $i = 0;\nwhile ($i < 100) { $a[$i] = $i; $i++; }\nprint $a[99];";
+        let (grown, _) = run_with_input(&format!("my @a = [];\n{}", fill), b"");
+        let (reserved, exit) = run_with_input(&format!("my @a = [];\nprint reserve(@a, 100), \" \";\n{}", fill), b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        assert_eq!(reserved.io.output(), b"100 99");
+        // The header and one block, where growing copied to blocks of 1, 2,
+        // 4 ... 128 elements
+        assert_eq!(reserved.heap - HEAP_BASE, 6 + 200);
+        assert_eq!(grown.heap - HEAP_BASE, 6 + 2 * 255);
+        // A smaller reserve keeps the room there is
+        assert_eq!(output("my @a = [1, 2, 3];\nprint reserve(@a, 2);"), "3");
+        let (_, exit) = run_with_input("my @a = [];\nreserve(@a, 9000);", b"");
+        assert!(exit.is_err());
+    }
+
+    #[test]
+    fn test_memstats() {
+        // A call's argument, return address and frame, then "abab" on the heap
+        let (vm, exit) = run_with_input("sub f($x) { memstats(); }\nf(1);\nmy $n = 2;\nmy $s = \"ab\" x $n;\nmemstats();", b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        let report = String::from_utf8_lossy(vm.io.output()).into_owned();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].split(" heap: ").nth(1), Some("0"));
+        assert_eq!(lines[1], format!("stack: {} heap: 5", VM_STACK - vm.stack_low));
+        assert!(vm.stack_low <= VM_STACK - 6);
+    }
+
+    #[test]
+    fn test_suspend_and_resume() {
+        let code = r#"my $n = 5; print resumed(); if (suspend()) { print "back", $n, resumed(); } print "end";"#;
+        let (mut vm, exit) = run_with_input(code, b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"0");
+        assert!(vm.resume());
+        assert_eq!(vm.run(Some(100_000)), Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"0back51end");
+        // The snapshot is used up
+        assert!(!vm.resume());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(output("print 7 - 2, 6 * 7, 100 / 7, 100 % 7;"), "542142");
+    }
+
+    #[test]
+    fn test_loop_and_subs() {
+        let code = r#"
+            sub show($n) { print $n, ","; }
+            my $i = 0;
+            while ($i < 3) { show($i); $i++; }
+        "#;
+        assert_eq!(output(code), "0,1,2,");
+    }
+
+    #[test]
+    fn test_regex_and_concat() {
+        let code = r#"
+            my $s = "hello" . " world";
+            if ($s =~ /w.rld/) { print $s; }
+            if ($s !~ /xyz/) { print "!"; }
+        "#;
+        assert_eq!(output(code), "hello world!");
+    }
+
+    #[test]
+    fn test_final_state_is_balanced() {
+        let (vm, exit) = run_with_input("my $x = 1; $x = $x + 1;", b"");
+        assert_eq!(exit, Ok(Exit::Halted));
+        assert_eq!(vm.sp, VM_STACK);
+        assert_eq!(vm.read16(VM_STACK), 2);
+    }
+
+    #[test]
+    fn test_reload_keeps_state() {
+        let mut compiler = Compiler::new();
+        let parse = |code: &str| Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let mut vm = Vm::new(&Module::new(), Console::scripted(b""));
+
+        let (module, start) = compiler.compile_more(&parse(r#"our $s = "kept";"#), false).unwrap();
+        vm.reload(&module).unwrap();
+        vm.pc = start;
+        assert_eq!(vm.run(None), Ok(Exit::Halted));
+
+        // More code and strings leave $s pointing at the same text
+        let (module, start) = compiler.compile_more(&parse(r#"print "and ", $s;"#), false).unwrap();
+        vm.reload(&module).unwrap();
+        vm.pc = start;
+        assert_eq!(vm.run(None), Ok(Exit::Halted));
+        assert_eq!(vm.io.output(), b"and kept");
+    }
+
+    #[test]
+    fn test_stack_stays_in_bound() {
+        for code in [
+            "sub f($n) { return $n * 2; }\nprint f(3) + 1;",
+            "my $i = 0;\nwhile ($i < 5) { printf \"%d %d\\n\", $i, $i * $i; $i++; }",
+            "sub g($s) { return $s . \"!\"; }\nsub f($s) { return g($s) . g($s); }\nprint f(\"a\");",
+        ] {
+            let (vm, exit) = run_with_input(code, b"");
+            assert_eq!(exit, Ok(Exit::Halted), "{}", code);
+            let program = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+            let bound = Compiler::new().compile(&program).unwrap().max_stack().unwrap();
+            assert!(VM_STACK - vm.stack_low <= bound, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_heap_stops_at_the_stack() {
+        let (vm, exit) = run_with_input("my $s = \"x\";\nwhile (1) { $s = $s . $s; }", b"");
+        assert!(exit.unwrap_err().starts_with("Out of memory: the heap ran into the"));
+        assert!(vm.heap > VM_STACK - 32);
+    }
+
+    #[test]
+    fn test_calls_in_expressions() {
+        // A call leaves just its value, its arguments gone
+        assert_eq!(output("sub f($n) { return $n * 2; }\nprint 20 + f(3);"), "26");
+        assert_eq!(output("sub f($a, $b) { return $a - $b; }\nprint f(9, 2) * f(5, 1), f(1, 1) + 1;"), "281");
+        // Falling off the end, or a bare return, gives undef
+        assert_eq!(output("sub f { print \"f\"; }\nmy $x = 1;\nf();\nf();\nprint f() + 0, $x;"), "fff01");
+        assert_eq!(output("my $n = 0;\nsub f { return; }\nwhile ($n < 500) { f(); $n++; }\nprint $n;"), "500");
+    }
+
+    #[test]
+    fn test_sub_locals() {
+        // A sub's `my` variables are its own, under its frame
+        let code = "sub g($n) { my $t = $n + 1; return $t; }\nmy $a = 5;\nprint g(1), \" \", $a;";
+        assert_eq!(output(code), "2 5");
+        let code = "sub g($n) { if ($n == 0) { return 0; } my $r = g($n - 1); my $s = $r + $n; return $s; }\nprint g(10);";
+        assert_eq!(output(code), "55");
+        // Nested blocks take slots of their own
+        let code = "my $a = 1;\nif ($a) { my $b = 2; my $c = 3; print $a, $b, $c; }\nprint $a;";
+        assert_eq!(output(code), "1231");
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let (_, exit) = run_with_input("my $x = 0; print 1 / $x;", b"");
+        assert_eq!(exit.unwrap_err(), "Illegal division by zero at line 1");
+    }
+
+    #[test]
+    fn test_regex_match_rules() {
+        assert!(regex_match(b"hello", b""));
+        assert!(regex_match(b"hello", b"h.l"));
+        assert!(regex_match(b"hello", b"hello"));
+        assert!(!regex_match(b"hi", b"hello"));
+        assert!(!regex_match(b"Hello", b"hello"));
+    }
+}
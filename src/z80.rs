@@ -1,1473 +1,4125 @@
 //! Z80 machine code generation
 //!
-//! This module contains the bytecode interpreter runtime as raw Z80 machine code,
-//! and utilities to generate complete ROM images.
-
-use crate::bytecode::Module;
-
-/// Z80 opcode constants
-pub mod opcodes {
-    pub const NOP: u8 = 0x00;
-    pub const LD_BC_NN: u8 = 0x01;
-    pub const LD_DE_NN: u8 = 0x11;
-    pub const LD_HL_NN: u8 = 0x21;
-    pub const LD_SP_NN: u8 = 0x31;
-    pub const LD_A_N: u8 = 0x3E;
-    pub const LD_B_N: u8 = 0x06;
-    pub const LD_C_N: u8 = 0x0E;
-    pub const LD_D_N: u8 = 0x16;
-    pub const LD_E_N: u8 = 0x1E;
-    pub const LD_H_N: u8 = 0x26;
-    pub const LD_L_N: u8 = 0x2E;
-    pub const LD_A_HL: u8 = 0x7E;
-    pub const LD_HL_A: u8 = 0x77;
-    pub const LD_A_DE: u8 = 0x1A;
-    pub const LD_DE_A: u8 = 0x12;
-    pub const LD_A_BC: u8 = 0x0A;
-    pub const INC_HL: u8 = 0x23;
-    pub const INC_DE: u8 = 0x13;
-    pub const INC_BC: u8 = 0x03;
-    pub const DEC_HL: u8 = 0x2B;
-    pub const DEC_DE: u8 = 0x1B;
-    pub const DEC_BC: u8 = 0x0B;
-    pub const INC_A: u8 = 0x3C;
-    pub const DEC_A: u8 = 0x3D;
-    pub const INC_B: u8 = 0x04;
-    pub const DEC_B: u8 = 0x05;
-    pub const INC_C: u8 = 0x0C;
-    pub const DEC_C: u8 = 0x0D;
-    pub const ADD_HL_BC: u8 = 0x09;
-    pub const ADD_HL_DE: u8 = 0x19;
-    pub const ADD_HL_HL: u8 = 0x29;
-    pub const ADD_A_N: u8 = 0xC6;
-    pub const SUB_N: u8 = 0xD6;
-    pub const AND_N: u8 = 0xE6;
-    pub const OR_N: u8 = 0xF6;
-    pub const XOR_N: u8 = 0xEE;
-    pub const CP_N: u8 = 0xFE;
-    pub const CP_A: u8 = 0xBF;
-    pub const CP_B: u8 = 0xB8;
-    pub const CP_HL: u8 = 0xBE;
-    pub const ADD_A_B: u8 = 0x80;
-    pub const ADD_A_C: u8 = 0x81;
-    pub const ADD_A_L: u8 = 0x85;
-    pub const SUB_B: u8 = 0x90;
-    pub const SUB_L: u8 = 0x95;
-    pub const AND_A: u8 = 0xA7;
-    pub const AND_B: u8 = 0xA0;
-    pub const OR_A: u8 = 0xB7;
-    pub const OR_B: u8 = 0xB0;
-    pub const OR_C: u8 = 0xB1;
-    pub const OR_D: u8 = 0xB2;
-    pub const OR_E: u8 = 0xB3;
-    pub const OR_H: u8 = 0xB4;
-    pub const OR_L: u8 = 0xB5;
-    pub const XOR_A: u8 = 0xAF;
-    pub const PUSH_AF: u8 = 0xF5;
-    pub const PUSH_BC: u8 = 0xC5;
-    pub const PUSH_DE: u8 = 0xD5;
-    pub const PUSH_HL: u8 = 0xE5;
-    pub const POP_AF: u8 = 0xF1;
-    pub const POP_BC: u8 = 0xC1;
-    pub const POP_DE: u8 = 0xD1;
-    pub const POP_HL: u8 = 0xE1;
-    pub const JP_NN: u8 = 0xC3;
-    pub const JP_Z_NN: u8 = 0xCA;
-    pub const JP_NZ_NN: u8 = 0xC2;
-    pub const JP_C_NN: u8 = 0xDA;
-    pub const JP_NC_NN: u8 = 0xD2;
-    pub const JP_HL: u8 = 0xE9;
-    pub const JR_N: u8 = 0x18;
-    pub const JR_Z_N: u8 = 0x28;
-    pub const JR_NZ_N: u8 = 0x20;
-    pub const JR_C_N: u8 = 0x38;
-    pub const JR_NC_N: u8 = 0x30;
-    pub const CALL_NN: u8 = 0xCD;
-    pub const RET: u8 = 0xC9;
-    pub const RST_00: u8 = 0xC7;
-    pub const RST_08: u8 = 0xCF;
-    pub const RST_10: u8 = 0xD7;
-    pub const RST_38: u8 = 0xFF;
-    pub const HALT: u8 = 0x76;
-    pub const DI: u8 = 0xF3;
-    pub const EI: u8 = 0xFB;
-    pub const OUT_N_A: u8 = 0xD3;
-    pub const IN_A_N: u8 = 0xDB;
-    pub const EX_DE_HL: u8 = 0xEB;
-    pub const EX_AF_AF: u8 = 0x08;
-    pub const EXX: u8 = 0xD9;
-    pub const LD_NN_A: u8 = 0x32;
-    pub const LD_A_NN: u8 = 0x3A;
-    pub const LD_NN_HL: u8 = 0x22;
-    pub const LD_HL_NN_IND: u8 = 0x2A;
-    pub const SCF: u8 = 0x37;
-    pub const CCF: u8 = 0x3F;
-    pub const CPL: u8 = 0x2F;
-    pub const NEG: u8 = 0x44; // ED prefix needed
-    pub const DJNZ: u8 = 0x10;
-    pub const LDIR: u8 = 0xB0; // ED prefix needed
-    pub const SBC_HL_DE: u8 = 0x52; // ED prefix needed
-    pub const ADC_HL_DE: u8 = 0x5A; // ED prefix needed
-    pub const LD_A_I: u8 = 0x57; // ED prefix
-    pub const LD_DE_NN_IND: u8 = 0x5B; // ED prefix - LD DE,(nn)
-    pub const LD_NN_DE: u8 = 0x53; // ED prefix - LD (nn),DE
-    pub const ED: u8 = 0xED;
-    pub const CB: u8 = 0xCB;
-    pub const BIT_7_A: u8 = 0x7F; // CB prefix
-    pub const BIT_7_H: u8 = 0x7C; // CB prefix
-    pub const SRL_H: u8 = 0x3C; // CB prefix
-    pub const RR_L: u8 = 0x1D; // CB prefix
-    pub const SLA_L: u8 = 0x25; // CB prefix
-    pub const RL_H: u8 = 0x14; // CB prefix
-
-    // LD r,(HL) and LD (HL),r
-    pub const LD_B_HL: u8 = 0x46;
-    pub const LD_C_HL: u8 = 0x4E;
-    pub const LD_D_HL: u8 = 0x56;
-    pub const LD_E_HL: u8 = 0x5E;
-    pub const LD_H_HL: u8 = 0x66;
-    pub const LD_L_HL: u8 = 0x6E;
-    pub const LD_HL_B: u8 = 0x70;
-    pub const LD_HL_C: u8 = 0x71;
-    pub const LD_HL_D: u8 = 0x72;
-    pub const LD_HL_E: u8 = 0x73;
-
-    // Register moves
-    pub const LD_A_B: u8 = 0x78;
-    pub const LD_A_C: u8 = 0x79;
-    pub const LD_A_D: u8 = 0x7A;
-    pub const LD_A_E: u8 = 0x7B;
-    pub const LD_A_H: u8 = 0x7C;
-    pub const LD_A_L: u8 = 0x7D;
-    pub const LD_B_A: u8 = 0x47;
-    pub const LD_C_A: u8 = 0x4F;
-    pub const LD_D_A: u8 = 0x57;
-    pub const LD_E_A: u8 = 0x5F;
-    pub const LD_H_A: u8 = 0x67;
-    pub const LD_L_A: u8 = 0x6F;
-    pub const LD_B_C: u8 = 0x41;
-    pub const LD_C_B: u8 = 0x48;
-    pub const LD_D_E: u8 = 0x53;
-    pub const LD_E_D: u8 = 0x5B;
-    pub const LD_H_L: u8 = 0x65;
-    pub const LD_L_H: u8 = 0x6C;
-    pub const LD_B_D: u8 = 0x42;
-    pub const LD_C_E: u8 = 0x4B;
-    pub const LD_D_H: u8 = 0x54;
-    pub const LD_E_L: u8 = 0x5D;
-    pub const LD_H_B: u8 = 0x60;
-    pub const LD_L_C: u8 = 0x69;
-    pub const LD_H_D: u8 = 0x62;
-    pub const LD_L_E: u8 = 0x6B;
-    pub const LD_D_B: u8 = 0x50;
-    pub const LD_E_C: u8 = 0x59;
-    pub const LD_B_H: u8 = 0x44;
-    pub const LD_C_L: u8 = 0x4D;
-    pub const LD_B_L: u8 = 0x45;
-    pub const LD_C_H: u8 = 0x4C;
-    pub const LD_SP_HL: u8 = 0xF9;
-}
+//! This module contains the bytecode interpreter runtime, assembled with the
+//! symbolic assembler in `asm`, and utilities to generate complete ROM images.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::asm::{Alu, Asm, Cond, Label, Reg16, Reg8, StackReg};
+use crate::backend::{Acia, Channels, ConsoleBackend, RetroShieldPort, Sio};
+use crate::bytecode::{Module, NativeFunc, Op, COUNTERS, HEADER, MAGIC, MAX_TASKS, TASK_STACK};
+use crate::banking::{self, Banking};
+use crate::native;
+use crate::storage;
+use crate::z80dis;
+#[cfg(feature = "emulator")]
+use crate::z80emu::{Io, Machine};
+#[cfg(feature = "target-cpc")]
+use crate::{amsdos, backend::CpcFirmware};
+#[cfg(feature = "target-spectrum")]
+use crate::{backend::SpectrumRom, tap};
+#[cfg(feature = "target-ti83")]
+use crate::{backend::TiOs, ti8xp};
+#[cfg(feature = "target-trs80")]
+use crate::{backend::Trs80Rom, trs80};
 
-use opcodes::*;
 
 /// Console I/O port for RetroShield
-const PORT_CONSOLE: u8 = 0x00;
+pub(crate) const PORT_CONSOLE: u8 = 0x00;
+
+/// Console status port (bit 0 set when a received byte is waiting)
+pub(crate) const PORT_STATUS: u8 = 0x01;
+
+/// Port `--trace-rom` writes each opcode to, before running it
+pub const PORT_TRACE: u8 = 0x03;
+
+/// RetroShield error output port, for `print STDERR` (see
+/// `RomOptions::stderr_port`)
+pub const PORT_ERROR: u8 = 0x02;
+
+/// Memory map of a target machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    /// Where the runtime is assembled
+    pub runtime_org: u16,
+    /// Where the bytecode image is loaded
+    pub bytecode_org: u16,
+    /// Start of the heap (grows up)
+    pub heap_base: u16,
+    /// VM stack (grows down, main-program locals above it)
+    pub vm_stack: u16,
+    /// Initial Z80 stack pointer
+    pub stack_top: u16,
+    /// VM state variables (see the accessors below)
+    pub vars: u16,
+    /// Serial receive ring buffer (page aligned so the index wraps in 8 bits)
+    pub rx_buf: u16,
+}
+
+impl Layout {
+    /// VM stack pointer
+    pub const fn vm_sp(&self) -> u16 { self.vars }
+    /// VM frame pointer
+    pub const fn vm_fp(&self) -> u16 { self.vars + 2 }
+    /// Next free heap byte
+    pub const fn heap_ptr(&self) -> u16 { self.vars + 4 }
+    /// Start of bytecode
+    pub const fn vm_code(&self) -> u16 { self.vars + 6 }
+    /// Start of string table
+    pub const fn vm_strings(&self) -> u16 { self.vars + 8 }
+    /// Bytecode offset of next instruction
+    pub const fn vm_pc(&self) -> u16 { self.vars + 10 }
+    /// Receive buffer head, written by the ISR
+    pub const fn rx_head(&self) -> u16 { self.vars + 12 }
+    /// Receive buffer tail, written by the reader
+    pub const fn rx_tail(&self) -> u16 { self.vars + 13 }
+    /// Caller's stack pointer, for targets that return to a host OS
+    pub const fn saved_sp(&self) -> u16 { self.vars + 14 }
+    /// ROM shell: address of the variable table (see `shell_table`)
+    pub const fn shell_vars(&self) -> u16 { self.vars + 16 }
+    /// ROM shell: nonzero when the last line printed a value
+    pub const fn shell_newline(&self) -> u16 { self.vars + 18 }
+    /// Nonzero when the program was resumed from a snapshot, for resumed()
+    pub const fn resumed(&self) -> u16 { self.vars + 19 }
+    /// Snapshot that suspend() leaves for the next reset, `SNAPSHOT_SIZE`
+    /// bytes (see `snapshot_check`)
+    pub const fn snapshot(&self) -> u16 { self.vars + 20 }
+    /// Coverage counters, 16 bits each, taking the bottom of the heap
+    pub const fn counters(&self) -> u16 { self.heap_base }
+    /// Parameter block a loader fills with the program's arguments,
+    /// `ARGS_SIZE` bytes (see `args_block`)
+    pub const fn args(&self) -> u16 { self.vars + 0x20 }
+    /// The array @ARGV reads, after the parameter block's `ARGS_MAGIC`
+    pub const fn argv(&self) -> u16 { self.args() + 2 }
+    /// Lowest VM_SP reached, kept with `RomOptions::mem_stats`, after the
+    /// parameter block
+    pub const fn stack_low(&self) -> u16 { self.vars + 0xC0 }
+    /// Timer interrupts taken, counted by the `RomOptions::timer` ISR
+    pub const fn ticks(&self) -> u16 { self.vars + 0xC2 }
+    /// Slot of the running task
+    pub const fn task(&self) -> u16 { self.vars + 0xC4 }
+    /// VM_PC, VM_SP and VM_FP of each of `MAX_TASKS` tasks, saved while
+    /// another runs; a free slot's VM_SP is below 0x100
+    pub const fn tasks(&self) -> u16 { self.vars + 0xC5 }
+    /// Highest HEAP_PTR may go, under the VM stack the header asks for,
+    /// after the task slots
+    pub const fn heap_limit(&self) -> u16 { self.vars + 0xDD }
+    /// Channel Select last chose, 0 the console or 1 the error port, on a
+    /// runtime with one
+    pub const fn channel(&self) -> u16 { self.vars + 0xDF }
+
+    /// Named addresses of the memory map and VM state, for assembler source
+    pub fn symbols(&self) -> Vec<(&'static str, u16)> {
+        vec![
+            ("BYTECODE_ORG", self.bytecode_org),
+            ("HEAP_BASE", self.heap_base),
+            ("VM_STACK", self.vm_stack),
+            ("STACK_TOP", self.stack_top),
+            ("VM_SP", self.vm_sp()),
+            ("VM_FP", self.vm_fp()),
+            ("HEAP_PTR", self.heap_ptr()),
+            ("VM_CODE", self.vm_code()),
+            ("VM_STRINGS", self.vm_strings()),
+            ("VM_PC", self.vm_pc()),
+            ("RX_HEAD", self.rx_head()),
+            ("RX_TAIL", self.rx_tail()),
+            ("SAVED_SP", self.saved_sp()),
+            ("SHELL_VARS", self.shell_vars()),
+            ("SHELL_NEWLINE", self.shell_newline()),
+            ("RESUMED", self.resumed()),
+            ("SNAPSHOT", self.snapshot()),
+            ("ARGS", self.args()),
+            ("STACK_LOW", self.stack_low()),
+            ("TICKS", self.ticks()),
+            ("TASK", self.task()),
+            ("TASKS", self.tasks()),
+            ("HEAP_LIMIT", self.heap_limit()),
+            ("CHANNEL", self.channel()),
+            ("RX_BUF", self.rx_buf),
+        ]
+    }
+}
+
+/// Size of the snapshot: the magic, VM_PC, VM_SP, VM_FP, HEAP_PTR and the
+/// check word, which ends where the parameter block starts
+pub const SNAPSHOT_SIZE: usize = 12;
+
+/// First word of a snapshot a reset may resume from
+pub const SNAPSHOT_MAGIC: u16 = 0x534D;
+
+/// Check word of a snapshot holding `words` (the magic and the four VM
+/// registers) for a program of `code_len` bytes, so that a snapshot of
+/// another program, or half written, is not resumed. Globals, the VM stack
+/// and the heap stay where they are in battery-backed RAM.
+pub fn snapshot_check(words: [u16; 5], code_len: u16) -> u16 {
+    words.iter().fold(code_len, |sum, w| sum.wrapping_add(*w))
+}
+
+/// Size of the parameter block, which fits below `rx_buf` on every target
+pub const ARGS_SIZE: usize = 160;
+
+/// First word of a parameter block a loader filled; without it the runtime
+/// empties @ARGV at reset, as RAM holds anything at power on
+pub const ARGS_MAGIC: u16 = 0x4741;
+
+/// The parameter block holding `args` when written at `base`: `ARGS_MAGIC`,
+/// then an array header (the count twice, as length and capacity, and the
+/// address of the pointers), a pointer to each argument, then the arguments
+/// as length-prefixed strings, so the block after the magic is itself the
+/// array @ARGV reads
+pub fn args_block(base: u16, args: &[String]) -> Result<Vec<u8>, String> {
+    let count = (args.len() as u16).to_le_bytes();
+    let pointers = if args.is_empty() { 0 } else { base + 8 };
+    let mut block = [ARGS_MAGIC.to_le_bytes(), count, count, pointers.to_le_bytes()].concat();
+    let mut text = Vec::new();
+    let strings = base as usize + 8 + 2 * args.len();
+    for arg in args {
+        let len = u8::try_from(arg.len()).map_err(|_| format!("Argument too long: {} bytes, the limit is 255", arg.len()))?;
+        block.extend_from_slice(&((strings + text.len()) as u16).to_le_bytes());
+        text.push(len);
+        text.extend_from_slice(arg.as_bytes());
+    }
+    block.extend_from_slice(&text);
+    if block.len() > ARGS_SIZE {
+        return Err(format!("Arguments take {} bytes, the limit is {}", block.len(), ARGS_SIZE));
+    }
+    Ok(block)
+}
+
+/// Why the runtime halted with VM_PC at `pc` in `module`, if that was an
+/// error: Die, a failed check and an opcode the runtime lacks all stop on
+/// the instruction, and only Halt ends the program. Die has printed its
+/// own message.
+pub fn halt_error(module: &Module, pc: u16) -> Option<String> {
+    let op = module.code.get(pc as usize).map(|&b| Op::from_byte(b))?;
+    Some(match op {
+        Op::Halt => return None,
+        Op::Die => "Program died",
+        Op::AddChk | Op::SubChk => "Runtime error: Integer overflow",
+        Op::CheckIdx => "Runtime error: Array index out of range",
+        // The only way a native sub halts
+        Op::Div | Op::Mod | Op::Native => {
+            return Some(match module.line_at(pc) {
+                Some(line) => format!("Runtime error: Illegal division by zero at line {}", line),
+                None => "Runtime error: Illegal division by zero".to_string(),
+            })
+        }
+        Op::NewHash | Op::HashGet | Op::HashSet | Op::HashDel | Op::HashKeys => "Runtime error: Hashes need the host VM (run)",
+        Op::Repeat => "Runtime error: Repeating a number needs the host VM (run)",
+        Op::Printf => "Runtime error: printf on the Z80 takes only %d, %s, %c and %%",
+        Op::PortOut | Op::PortIn => "Runtime error: Port I/O needs a board target",
+        Op::Peek | Op::Poke => "Runtime error: peek() and poke() need a board target",
+        Op::CheckPoke => "Runtime error: Poke outside --poke-range",
+        Op::Ticks => "Runtime error: ticks() needs --timer or the host VM (run)",
+        Op::CallNative => match module.code.get(pc as usize + 1).and_then(|&b| NativeFunc::from_byte(b)) {
+            Some(NativeFunc::Caller) => "Runtime error: caller() needs the host VM (run)",
+            Some(NativeFunc::MemStats) => "Runtime error: memstats() needs --mem-stats or the host VM (run)",
+            Some(NativeFunc::Uc | NativeFunc::Lc) => "Runtime error: uc() and lc() need the host VM (run)",
+            Some(NativeFunc::Sprintf) => "Runtime error: sprintf() needs the host VM (run)",
+            Some(NativeFunc::BufNew | NativeFunc::BufAppend | NativeFunc::BufStr) => "Runtime error: String buffers need the host VM (run)",
+            Some(NativeFunc::Reserve) => "Runtime error: reserve() needs the host VM (run)",
+            Some(NativeFunc::Pack | NativeFunc::Unpack) => "Runtime error: pack() of variables and unpack() need the host VM (run)",
+            Some(NativeFunc::Each | NativeFunc::Pairs | NativeFunc::Slice | NativeFunc::KeyExists) => {
+                "Runtime error: Hashes need the host VM (run)"
+            }
+            // On a board target, where the file natives are
+            Some(NativeFunc::Open) => "Runtime error: Bad open mode",
+            _ => "Runtime error: File functions need a board target or the host VM (run)",
+        },
+        _ => return Some(format!("Runtime error: {:?} is not in the Z80 runtime", op)),
+    }.to_string())
+}
+
+/// Whether the instruction at `pc` in `module` works on a hash, which only
+/// the host VM has
+pub fn uses_hash(module: &Module, pc: u16) -> bool {
+    match module.code.get(pc as usize).map(|&b| Op::from_byte(b)) {
+        Some(Op::NewHash | Op::HashGet | Op::HashSet | Op::HashDel | Op::HashKeys) => true,
+        Some(Op::CallNative) => matches!(
+            module.code.get(pc as usize + 1).and_then(|&b| NativeFunc::from_byte(b)),
+            Some(NativeFunc::Each | NativeFunc::Pairs | NativeFunc::Slice | NativeFunc::KeyExists)
+        ),
+        _ => false,
+    }
+}
+
+/// Whether `module` selects an output channel, as `print STDERR` and
+/// warnings do
+fn uses_select(module: &Module) -> bool {
+    let mut pc = 0;
+    while pc < module.code.len() {
+        let op = Op::from_byte(module.code[pc]);
+        if op == Op::Select {
+            return true;
+        }
+        pc += op.size();
+    }
+    false
+}
+
+/// Why the runtime halted, if HEAP_PTR (`heap`) had passed HEAP_LIMIT
+/// (`heap_limit`): the check stops on the instruction that grew the heap.
+pub fn heap_error(layout: &Layout, heap: u16, heap_limit: u16) -> Option<String> {
+    (layout.heap_base < layout.vm_stack && heap > heap_limit).then(|| {
+        format!(
+            "Runtime error: Out of memory: the heap ran into the {} bytes kept for the VM stack",
+            layout.vm_stack - heap_limit
+        )
+    })
+}
+
+/// How the runtime stopped, once the emulator has halted
+#[cfg(feature = "emulator")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Halt {
+    /// The program ended, or suspend() saved it to resume
+    Ended,
+    /// The runtime refused to start the program, and printed why
+    BootFailed,
+    /// A runtime error on the instruction at `pc`
+    Error { pc: u16, message: String },
+}
+
+/// How the runtime on `machine`, with the memory map `layout`, stopped
+/// running `module`: `halt_error` and `heap_error` read from its VM state
+#[cfg(feature = "emulator")]
+pub fn halt<T: Io>(module: &Module, layout: &Layout, machine: &Machine<T>) -> Halt {
+    let pc = machine.read16(layout.vm_pc());
+    if pc == BOOT_FAILED {
+        return Halt::BootFailed;
+    }
+    // suspend() stops with VM_PC past it
+    if machine.read16(layout.snapshot()) == SNAPSHOT_MAGIC {
+        return Halt::Ended;
+    }
+    let heap = machine.read16(layout.heap_ptr());
+    match heap_error(layout, heap, machine.read16(layout.heap_limit())).or_else(|| halt_error(module, pc)) {
+        Some(message) => Halt::Error { pc, message },
+        None => Halt::Ended,
+    }
+}
+
+/// RetroShield: runtime in ROM at 0, everything else in RAM above it
+const RETROSHIELD: Layout = Layout {
+    runtime_org: 0x0000,    // Runtime starts at 0
+    bytecode_org: 0x1000,   // Bytecode loaded at 4K
+    heap_base: 0x2000,      // Heap starts here
+    vm_stack: 0x8000,       // VM stack area
+    stack_top: 0xFFFE,      // Stack at top of RAM
+    vars: 0x8200,           // VM state, above the main program's locals
+    rx_buf: 0x8300,
+};
+
+/// RC2014: 32K ROM at 0, 32K RAM above it
+const RC2014: Layout = Layout {
+    runtime_org: 0x0000,
+    bytecode_org: 0x1000,
+    heap_base: 0x8200,
+    vm_stack: 0xE000,
+    stack_top: 0xFFFE,
+    vars: 0x8000,
+    rx_buf: 0x8100,
+};
+
+#[cfg(feature = "target-spectrum")]
+/// ZX Spectrum 48K: loaded above RAMTOP at 0x8000, Z80 stack below the UDGs
+const SPECTRUM: Layout = Layout {
+    runtime_org: 0x8000,
+    bytecode_org: 0x9000,
+    heap_base: 0xC000,
+    vm_stack: 0xF000,
+    stack_top: 0xFF50,
+    vars: 0xF200,
+    rx_buf: 0xF300,
+};
+
+#[cfg(feature = "target-cpc")]
+/// Amstrad CPC 464/6128: loaded at 0x4000, everything below AMSDOS's HIMEM
+const CPC: Layout = Layout {
+    runtime_org: 0x4000,
+    bytecode_org: 0x5000,
+    heap_base: 0x8000,
+    vm_stack: 0x9E00,
+    stack_top: 0xA600,
+    vars: 0xA000,
+    rx_buf: 0xA100,
+};
+
+#[cfg(feature = "target-trs80")]
+/// TRS-80 Model I/III 48K: loaded at 0x6000, clear of DOS
+const TRS80: Layout = Layout {
+    runtime_org: 0x6000,
+    bytecode_org: 0x7000,
+    heap_base: 0xA000,
+    vm_stack: 0xE000,
+    stack_top: 0xFF00,
+    vars: 0xE200,
+    rx_buf: 0xE300,
+};
+
+#[cfg(feature = "target-ti83")]
+/// TI-83+/84+: program at userMem, RAM in the OS's scratch areas (the heap in
+/// appBackUpScreen, the VM stack in plotSScreen, state and the Z80 stack in
+/// statVars)
+const TI83: Layout = Layout {
+    runtime_org: 0x9D95,
+    bytecode_org: 0xA995,
+    heap_base: 0x9872,
+    vm_stack: 0x9440,
+    stack_top: 0x8C4C,
+    vars: 0x8A3A,
+    rx_buf: 0x8B00,
+};
+
+/// RetroShield addresses used by the host-side VM and emulator tools
+#[cfg(feature = "host-vm")]
+pub(crate) const BYTECODE_ORG: u16 = RETROSHIELD.bytecode_org;
+#[cfg(feature = "host-vm")]
+pub(crate) const VM_STACK: u16 = RETROSHIELD.vm_stack;
+#[cfg(feature = "host-vm")]
+pub(crate) const HEAP_BASE: u16 = RETROSHIELD.heap_base;
+#[cfg(feature = "host-vm")]
+pub(crate) const VM_SP: u16 = RETROSHIELD.vm_sp();
+#[cfg(feature = "host-vm")]
+pub(crate) const VM_FP: u16 = RETROSHIELD.vm_fp();
+#[cfg(feature = "host-vm")]
+pub(crate) const HEAP_PTR: u16 = RETROSHIELD.heap_ptr();
+#[cfg(feature = "emulator")]
+pub(crate) const VM_PC: u16 = RETROSHIELD.vm_pc();
+#[cfg(feature = "host-vm")]
+pub(crate) const ARGS: u16 = RETROSHIELD.args();
+#[cfg(feature = "host-vm")]
+pub(crate) const ARGV: u16 = RETROSHIELD.argv();
+#[cfg(feature = "host-vm")]
+pub(crate) const SNAPSHOT: u16 = RETROSHIELD.snapshot();
+
+/// TRS-80 DOS exit
+#[cfg(feature = "target-trs80")]
+const TRS80_EXIT: u16 = 0x402D;         // Return to DOS Ready
+
+/// Machine the generated code runs on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// RetroShield Z80: ROM image, console on I/O ports
+    #[default]
+    RetroShield,
+    /// RC2014 with the 68B50 ACIA serial module
+    Rc2014Acia,
+    /// RC2014 with the Z80 SIO/2 serial module (channel A)
+    Rc2014Sio,
+    /// ZX Spectrum 48K: tape image, console through the Spectrum ROM
+    #[cfg(feature = "target-spectrum")]
+    Spectrum,
+    /// Amstrad CPC: AMSDOS binary, console through the firmware
+    #[cfg(feature = "target-cpc")]
+    Cpc,
+    /// TRS-80 Model I/III: /CMD file, console through the Level II ROM
+    #[cfg(feature = "target-trs80")]
+    Trs80,
+    /// TI-83+/84+: .8xp assembly program, console through TI-OS bcalls
+    #[cfg(feature = "target-ti83")]
+    Ti83,
+}
+
+impl Target {
+    /// The `--target` name, which `from_name` reads back
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::RetroShield => "retroshield",
+            Target::Rc2014Acia => "rc2014-acia",
+            Target::Rc2014Sio => "rc2014-sio",
+            #[cfg(feature = "target-spectrum")]
+            Target::Spectrum => "spectrum",
+            #[cfg(feature = "target-cpc")]
+            Target::Cpc => "cpc",
+            #[cfg(feature = "target-trs80")]
+            Target::Trs80 => "trs80",
+            #[cfg(feature = "target-ti83")]
+            Target::Ti83 => "ti83",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "retroshield" => Some(Target::RetroShield),
+            "rc2014-acia" => Some(Target::Rc2014Acia),
+            "rc2014-sio" => Some(Target::Rc2014Sio),
+            #[cfg(feature = "target-spectrum")]
+            "spectrum" => Some(Target::Spectrum),
+            #[cfg(feature = "target-cpc")]
+            "cpc" => Some(Target::Cpc),
+            #[cfg(feature = "target-trs80")]
+            "trs80" => Some(Target::Trs80),
+            #[cfg(feature = "target-ti83")]
+            "ti83" => Some(Target::Ti83),
+            _ => None,
+        }
+    }
+
+    pub fn layout(self) -> Layout {
+        match self {
+            Target::RetroShield => RETROSHIELD,
+            Target::Rc2014Acia | Target::Rc2014Sio => RC2014,
+            #[cfg(feature = "target-spectrum")]
+            Target::Spectrum => SPECTRUM,
+            #[cfg(feature = "target-cpc")]
+            Target::Cpc => CPC,
+            #[cfg(feature = "target-trs80")]
+            Target::Trs80 => TRS80,
+            #[cfg(feature = "target-ti83")]
+            Target::Ti83 => TI83,
+        }
+    }
+
+    /// Kind of file `generate_output` writes
+    pub fn output_kind(self) -> &'static str {
+        match self {
+            Target::RetroShield | Target::Rc2014Acia | Target::Rc2014Sio => "ROM",
+            #[cfg(feature = "target-spectrum")]
+            Target::Spectrum => "TAP",
+            #[cfg(feature = "target-cpc")]
+            Target::Cpc => "BIN",
+            #[cfg(feature = "target-trs80")]
+            Target::Trs80 => "CMD",
+            #[cfg(feature = "target-ti83")]
+            Target::Ti83 => "8XP",
+        }
+    }
+
+    /// Whether the program runs under a ROM or OS it returns to, with that
+    /// system's interrupts and console routines rather than the bare metal
+    pub fn hosted(self) -> bool {
+        !matches!(self, Target::RetroShield | Target::Rc2014Acia | Target::Rc2014Sio)
+    }
+
+    /// How the runtime talks to the console
+    pub fn console(self) -> Box<dyn ConsoleBackend> {
+        match self {
+            Target::RetroShield => Box::new(RetroShieldPort),
+            Target::Rc2014Acia => Box::new(Acia { control: 0x80, data: 0x81 }),
+            Target::Rc2014Sio => Box::new(Sio { control: 0x80, data: 0x81 }),
+            #[cfg(feature = "target-spectrum")]
+            Target::Spectrum => Box::new(SpectrumRom),
+            #[cfg(feature = "target-cpc")]
+            Target::Cpc => Box::new(CpcFirmware),
+            #[cfg(feature = "target-trs80")]
+            Target::Trs80 => Box::new(Trs80Rom),
+            #[cfg(feature = "target-ti83")]
+            Target::Ti83 => Box::new(TiOs),
+        }
+    }
+}
 
-/// Memory layout
-const RUNTIME_ORG: u16 = 0x0000;    // Runtime starts at 0
-const BYTECODE_ORG: u16 = 0x1000;   // Bytecode loaded at 4K
-const STACK_TOP: u16 = 0xFFFE;      // Stack at top of RAM
-const VM_STACK: u16 = 0x8000;       // VM stack area
-const HEAP_BASE: u16 = 0x2000;      // Heap starts here
+/// IM1 interrupt vector
+const IM1_VECTOR: u16 = 0x0038;
+
+/// Options controlling runtime generation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RomOptions {
+    /// Buffer console input from an IM1 interrupt handler instead of polling
+    pub irq_input: bool,
+    pub target: Target,
+    /// Fetch the code from ROM pages switched into a window at 0x4000
+    pub banking: Option<Banking>,
+    /// Handle Count, keeping its counters below the heap
+    pub coverage: bool,
+    /// Handle CheckIdx, halting on an index outside its array
+    pub bounds_check: bool,
+    /// Start a line interpreter on the program's variables when it halts
+    pub shell: bool,
+    /// Fill every gap in the image with zeros, as in the gap before the
+    /// bytecode, so the same program gives the same bytes
+    pub reproducible: bool,
+    /// Check the program image at startup and print a banner, or an error
+    /// instead of running a bad image
+    pub self_test: bool,
+    /// Name the banner gives the program
+    pub program_name: String,
+    /// Write each opcode to this port before running it
+    pub trace_port: Option<u8>,
+    /// Follow each traced opcode with the word on top of the VM stack, low
+    /// byte first
+    pub trace_stack: bool,
+    /// Keep the VM stack's high-water mark in STACK_LOW and handle the
+    /// memstats() native
+    pub mem_stats: bool,
+    /// Count timer interrupts in TICKS from an IM1 handler and handle
+    /// ticks()
+    pub timer: bool,
+    /// Port `print STDERR` writes to, in place of the target's own
+    pub error_port: Option<u8>,
+    /// Send the console's writes through the channel Select chooses, which
+    /// only a program that selects STDERR needs (see `for_module`)
+    pub select: bool,
+}
 
-/// Generate complete ROM with runtime + bytecode
-pub fn generate_rom(module: &Module) -> Vec<u8> {
+impl RomOptions {
+    /// Port `print STDERR` writes to: `error_port`, or port 2 on the
+    /// RetroShield. Other targets have only the console.
+    pub fn stderr_port(&self) -> Option<u8> {
+        self.error_port.or((self.target == Target::RetroShield).then_some(PORT_ERROR))
+    }
+
+    /// These options for the runtime that runs `module`
+    pub fn for_module(&self, module: &Module) -> RomOptions {
+        RomOptions { select: uses_select(module), ..self.clone() }
+    }
+
+    /// The error port when the runtime switches its writes between it and
+    /// the console
+    fn channels(&self) -> Option<u8> {
+        self.stderr_port().filter(|_| self.select)
+    }
+
+    /// Reject option combinations the target cannot support
+    pub fn check(&self) -> Result<(), String> {
+        if self.irq_input && self.target.console().rx_port().is_none() {
+            return Err("--irq-input needs a console that interrupts on receive".to_string());
+        }
+        // The window must be ROM, with RAM clear of it
+        if self.banking.is_some() && !matches!(self.target, Target::Rc2014Acia | Target::Rc2014Sio) {
+            return Err("--banked needs an rc2014 target".to_string());
+        }
+        if self.banking.is_some_and(|b| b.first_page == 0) {
+            return Err("--first-page must leave page 0 for the runtime".to_string());
+        }
+        // Shell lines run from RAM, which the banked fetch can't reach
+        if self.shell && self.banking.is_some() {
+            return Err("--rom-shell can't be combined with --banked".to_string());
+        }
+        // The self-test block sits in the ROM's gap before the bytecode
+        if self.self_test && (self.target.hosted() || self.banking.is_some()) {
+            return Err("--self-test needs an unbanked retroshield, rc2014-acia or rc2014-sio ROM".to_string());
+        }
+        // A hosted machine's ports belong to its own hardware
+        if self.trace_port.is_some() && self.target.hosted() {
+            return Err("--trace-rom needs the retroshield, rc2014-acia or rc2014-sio target".to_string());
+        }
+        if self.error_port.is_some() && self.target.hosted() {
+            return Err("--error-port needs the retroshield, rc2014-acia or rc2014-sio target".to_string());
+        }
+        if self.trace_stack && self.trace_port.is_none() {
+            return Err("--trace-stack needs --trace-rom".to_string());
+        }
+        // A hosted machine's interrupts belong to its OS, and the ISR has
+        // no way to tell a timer from a received byte
+        if self.timer && self.target.hosted() {
+            return Err("--timer needs the retroshield, rc2014-acia or rc2014-sio target".to_string());
+        }
+        if self.timer && self.irq_input {
+            return Err("--timer can't be combined with --irq-input".to_string());
+        }
+        self.check_runtime_fits()
+    }
+
+    /// Refuse options whose runtime runs into the bytecode, or into the
+    /// self-test block below it
+    fn check_runtime_fits(&self) -> Result<(), String> {
+        let l = self.target.layout();
+        let room = self.runtime_end() - l.runtime_org;
+        // Whatever the program, so a program that selects STDERR fits too
+        let size = runtime_size(&RomOptions { select: true, ..self.clone() });
+        if size > room as usize {
+            return Err(format!(
+                "these options need a {} byte runtime, and there are {} bytes before the bytecode at 0x{:04X}: leave some out",
+                size, room, l.bytecode_org
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where the runtime's room ends: the bytecode, or the self-test block
+    /// below it
+    fn runtime_end(&self) -> u16 {
+        let l = self.target.layout();
+        if self.self_test { l.bytecode_org - SELF_TEST_BLOCK } else { l.bytecode_org }
+    }
+}
+
+/// Generate complete ROM with runtime + bytecode, for options that pass
+/// `RomOptions::check`; panics on a runtime too big for its room, which
+/// `check` refuses
+pub fn generate_rom(module: &Module, options: &RomOptions) -> Vec<u8> {
+    let options = &options.for_module(module);
     let mut rom = Vec::new();
 
     // Generate runtime (interpreter)
-    let runtime = generate_runtime();
+    let runtime = generate_runtime(options);
     rom.extend_from_slice(&runtime);
 
-    // Pad to BYTECODE_ORG
-    while rom.len() < BYTECODE_ORG as usize {
-        rom.push(0x00);
+    // Pad to the bytecode origin, or to the self-test block below it
+    let layout = options.target.layout();
+    if let Err(e) = options.check_runtime_fits() {
+        panic!("{}", e);
     }
-
-    // Append bytecode module
-    let bytecode = generate_bytecode_image(module);
-    rom.extend_from_slice(&bytecode);
+    let end = options.runtime_end();
+    rom.resize((end - layout.runtime_org) as usize, 0x00);
+
+    // Append bytecode module, or with banking just its header and strings
+    if let Some(b) = options.banking {
+        let module = banking::paginate(module, banking::PAGE_SIZE - layout.bytecode_org as usize)
+            .unwrap_or_else(|e| panic!("{}", e));
+        rom.extend_from_slice(&banking::fixed_image(&module));
+        // Unused pages are left blank, as erased EPROM reads
+        let fill = if options.reproducible { 0x00 } else { 0xFF };
+        return banking::rom_image(&rom, &module.code, b, fill);
+    }
+    let image = program_image(module, options);
+    if options.self_test {
+        rom.extend_from_slice(&self_test_block(module, &image, options));
+    }
+    rom.extend_from_slice(&image);
 
     rom
 }
 
-/// Generate the bytecode image (header + code + strings)
-fn generate_bytecode_image(module: &Module) -> Vec<u8> {
-    let mut img = Vec::new();
+/// Bytes below the bytecode origin that the self-test reads: the length and
+/// CRC of the program image, then the banner, length-prefixed
+pub const SELF_TEST_BLOCK: u16 = 64;
+
+/// VM_PC once the runtime has refused to start the program and printed
+/// why: the self-test failed, or the VM stack the header asks for doesn't
+/// fit above the heap
+pub const BOOT_FAILED: u16 = 0xFFFF;
+
+/// CRC-16/CCITT-FALSE of `bytes`, as the self-test computes it
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &b| {
+        (0..8).fold(crc ^ (b as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 })
+    })
+}
+
+/// The self-test block for the program `image` of `module`
+fn self_test_block(module: &Module, image: &[u8], options: &RomOptions) -> Vec<u8> {
+    let entry = options.target.layout().bytecode_org + HEADER as u16 + module.entry;
+    let name: String = options.program_name.chars().take(24).collect();
+    let banner = format!("MicroPerl {} {} at 0x{:04X}\n", env!("CARGO_PKG_VERSION"), name, entry);
+    let mut block = (image.len() as u16).to_le_bytes().to_vec();
+    block.extend(crc16(image).to_le_bytes());
+    block.push(banner.len() as u8);
+    block.extend(banner.bytes());
+    block.resize(SELF_TEST_BLOCK as usize, 0);
+    block
+}
+
+/// The bytecode image, followed by the shell's variable table in a shell
+/// build and the machine code of `:native` subs
+fn program_image(module: &Module, options: &RomOptions) -> Vec<u8> {
+    let mut image = generate_bytecode_image(module);
+    if options.shell {
+        image.extend_from_slice(&shell_table(module));
+    }
+    if !module.native.is_empty() && options.banking.is_none() {
+        let (linked, code) = link_native(module, options, image.len());
+        image[HEADER..HEADER + linked.code.len()].copy_from_slice(&linked.code);
+        image.extend_from_slice(&code);
+    }
+    image
+}
+
+/// The machine code of `module`'s native subs, to go `offset` bytes into
+/// the program image, and the module with its Native instructions pointing
+/// at it
+fn link_native(module: &Module, options: &RomOptions, offset: usize) -> (Module, Vec<u8>) {
+    let l = options.target.layout();
+    let halt = runtime_symbol(options, "halt").unwrap_or(0);
+    let (code, entries) = native::generate(&module.native, l.bytecode_org + offset as u16, l.vm_sp(), halt)
+        .expect("native subs are checked when compiled");
+    let mut linked = module.clone();
+    for (sub, entry) in module.native.iter().zip(entries) {
+        let at = sub.addr as usize + 1;
+        linked.code[at..at + 2].copy_from_slice(&entry.to_le_bytes());
+    }
+    (linked, code)
+}
+
+/// Maximum length of a ROM shell line
+pub const SHELL_LINE: u8 = 63;
+
+/// The ROM shell's table of the program's variables, placed after the
+/// string table: each name, length-prefixed and without its sigil, with its
+/// local slot, then a zero
+pub fn shell_table(module: &Module) -> Vec<u8> {
+    let mut table = Vec::new();
+    for (slot, name) in module.locals.iter().enumerate().filter(|(_, name)| !name.is_empty()) {
+        table.push(name.len() as u8);
+        table.extend_from_slice(name.as_bytes());
+        table.push(slot as u8);
+    }
+    table.push(0);
+    table
+}
+
+/// Generate the file to load on the target: a ROM image for bare-metal
+/// boards, otherwise whatever the machine loads programs from
+#[cfg_attr(
+    not(any(feature = "target-spectrum", feature = "target-cpc", feature = "target-trs80")),
+    allow(unused_variables)
+)]
+pub fn generate_output(module: &Module, options: &RomOptions, name: &str) -> Vec<u8> {
+    let options = &options.for_module(module);
+    let l = options.target.layout();
+    match options.target {
+        Target::RetroShield | Target::Rc2014Acia | Target::Rc2014Sio => generate_rom(module, options),
+        #[cfg(feature = "target-spectrum")]
+        Target::Spectrum => tap::spectrum_tape(name, &[
+            (l.runtime_org, generate_runtime(options)),
+            (l.bytecode_org, program_image(module, options)),
+        ]),
+        #[cfg(feature = "target-cpc")]
+        Target::Cpc => amsdos::binary_file(name, l.runtime_org, l.runtime_org, &generate_rom(module, options)),
+        #[cfg(feature = "target-trs80")]
+        Target::Trs80 => trs80::cmd_file(name, l.runtime_org, &[
+            (l.runtime_org, generate_runtime(options)),
+            (l.bytecode_org, program_image(module, options)),
+        ]),
+        #[cfg(feature = "target-ti83")]
+        Target::Ti83 => ti8xp::program(name, &generate_rom(module, options)),
+    }
+}
+
+/// Assembler source (sjasmplus or zasm) for the same image as
+/// `generate_rom`: the runtime with its labels, then the bytecode image as
+/// data annotated with opcodes, source lines and strings
+pub fn generate_asm(module: &Module, options: &RomOptions) -> String {
+    let options = &options.for_module(module);
+    let l = options.target.layout();
+    let a = assemble_runtime(options);
+    let symbols = a.symbols();
+    let data = a.data().to_vec();
+    let runtime = a.finish();
+
+    let mut out = format!("; MicroPerl runtime and bytecode for the {:?} target\n\n", options.target);
+    for (name, addr) in l.symbols() {
+        out.push_str(&format!("{:<16}EQU 0x{:04X}\n", name, addr));
+    }
+    out.push_str(&format!("\n        ORG 0x{:04X}\n\n", l.runtime_org));
+    out.push_str(&z80dis::render_source(&runtime, l.runtime_org, &symbols, &l.symbols(), &data));
+    if options.self_test {
+        out.push_str(&format!("\n        DS BYTECODE_ORG-{}-$,0x00\n\nself_test:\n", SELF_TEST_BLOCK));
+        for row in self_test_block(module, &program_image(module, options), options).chunks(16) {
+            let list: Vec<String> = row.iter().map(|b| format!("0x{:02X}", b)).collect();
+            out.push_str(&format!("        DB {}\n", list.join(",")));
+        }
+        out.push('\n');
+    } else {
+        out.push_str("\n        DS BYTECODE_ORG-$,0x00\n\n");
+    }
+    let mut size = module.image().len();
+    if options.shell {
+        size += shell_table(module).len();
+    }
+    let (module, native) = if module.native.is_empty() || options.banking.is_some() {
+        (module.clone(), Vec::new())
+    } else {
+        link_native(module, options, size)
+    };
+    out.push_str(&bytecode_source(&module));
+    if options.shell {
+        let list: Vec<String> = shell_table(&module).iter().map(|b| format!("0x{:02X}", b)).collect();
+        out.push_str(&format!("shell_table:\n        DB {}\n", list.join(",")));
+    }
+    if !native.is_empty() {
+        out.push_str("native:\n");
+        for row in native.chunks(16) {
+            let list: Vec<String> = row.iter().map(|b| format!("0x{:02X}", b)).collect();
+            out.push_str(&format!("        DB {}\n", list.join(",")));
+        }
+    }
+    out
+}
+
+/// The bytecode image as DB lines, one per header field, instruction and
+/// string
+fn bytecode_source(module: &Module) -> String {
+    let mut out = String::from("bytecode:\n");
+    for line in image_lines(module) {
+        for note in &line.notes {
+            out.push_str(&format!("; {}\n", note));
+        }
+        let list: Vec<String> = line.bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+        out.push_str(&format!("        {:<24}; {}\n", format!("DB {}", list.join(",")), line.text));
+    }
+    out
+}
+
+/// A listing of the whole image: the runtime disassembly, then the bytecode
+/// image decoded into header fields, instructions and strings
+pub fn generate_listing(module: &Module, options: &RomOptions) -> String {
+    let options = &options.for_module(module);
+    let l = options.target.layout();
+    let a = assemble_runtime(options);
+    let symbols = a.symbols();
+    let data = a.data().to_vec();
+    let runtime = a.finish();
+
+    let mut out = format!("; MicroPerl listing for the {:?} target\n\n", options.target);
+    out.push_str(&z80dis::render(&runtime, l.runtime_org, &symbols, &data));
+    let end = l.runtime_org as usize + runtime.len();
+    let block = if options.self_test { SELF_TEST_BLOCK } else { 0 };
+    out.push_str(&format!("; {} bytes of padding from 0x{:04X}\n", (l.bytecode_org - block) as usize - end, end));
+    if options.self_test {
+        out.push_str(&format!("; {} byte self-test block from 0x{:04X}\n", block, l.bytecode_org - block));
+    }
+
+    out.push_str("bytecode:\n");
+    for line in image_lines(module) {
+        for note in &line.notes {
+            out.push_str(&format!("; {}\n", note));
+        }
+        // Long strings continue over several rows
+        for (i, row) in line.bytes.chunks(4).enumerate() {
+            let addr = l.bytecode_org as usize + line.offset + i * 4;
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            let text = if i == 0 { line.text.as_str() } else { "" };
+            out.push_str(format!("  {:04X}  {:<12}  {}", addr, hex.join(" "), text).trim_end());
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Symbol map for debuggers: runtime labels, the bytecode image with its
+/// subs and source lines, and the memory map with the VM variables, each by
+/// address
+pub fn generate_map(module: &Module, options: &RomOptions) -> String {
+    let options = &options.for_module(module);
+    let l = options.target.layout();
+    let code = l.bytecode_org + HEADER as u16;
+    let section = |out: &mut String, title: &str, mut entries: Vec<(u16, String)>| {
+        entries.sort_by_key(|(addr, _)| *addr);
+        out.push_str(&format!("\n; {}\n", title));
+        for (addr, name) in entries {
+            out.push_str(&format!("{:04X}  {}\n", addr, name));
+        }
+    };
+
+    let mut out = format!("; MicroPerl map for the {:?} target, runtime version {}\n", options.target, RUNTIME_VERSION);
+    let runtime = z80dis::unique_names(&assemble_runtime(options).symbols());
+    section(&mut out, "Runtime", runtime.into_iter().map(|(name, addr)| (addr, name)).collect());
+    section(&mut out, "Bytecode", vec![
+        (l.bytecode_org, "bytecode".to_string()),
+        (code, "code".to_string()),
+        (code + module.code.len() as u16, "strings".to_string()),
+    ]);
+    section(&mut out, "Subs", module.subs.iter().map(|(name, addr, _)| (code + addr, name.clone())).collect());
+    section(&mut out, "Source lines", module.lines.iter().map(|(pc, n)| (code + pc, format!("line {}", n))).collect());
+    let map = AddressMap::new(module, options);
+    section(&mut out, "Instructions", map.instructions().map(|pc| (map.address(pc), map.describe(pc))).collect());
+    section(&mut out, "Memory", l.symbols().into_iter().map(|(name, addr)| (addr, name.to_string())).collect());
+    out
+}
+
+/// Converts between bytecode offsets, as jump operands and VM_PC hold them,
+/// the ROM addresses the code is loaded at and source lines, for an image
+/// built with `options` (not banked, where an offset's page decides)
+pub struct AddressMap<'a> {
+    module: &'a Module,
+    /// ROM address of offset 0
+    code: u16,
+}
+
+impl<'a> AddressMap<'a> {
+    pub fn new(module: &'a Module, options: &RomOptions) -> Self {
+        AddressMap { module, code: options.target.layout().bytecode_org + HEADER as u16 }
+    }
+
+    /// ROM address of the code at `offset`
+    pub fn address(&self, offset: u16) -> u16 {
+        self.code.wrapping_add(offset)
+    }
+
+    /// Offset of the instruction holding the byte at ROM address `addr`
+    pub fn offset(&self, addr: u16) -> Option<u16> {
+        let at = addr.checked_sub(self.code).filter(|&at| (at as usize) < self.module.code.len())?;
+        self.instructions().take_while(|&pc| pc <= at).last()
+    }
 
-    // Header: "MPL\x01"
-    img.extend_from_slice(b"MPL\x01");
+    /// Source line of the instruction at `offset`
+    pub fn line(&self, offset: u16) -> Option<usize> {
+        self.module.line_at(offset)
+    }
 
-    // String table offset (after header + code)
-    // Header: magic(4) + strtab_offset(2) + code_len(2) + entry(2) = 10 bytes
-    let code_start = 10u16;
-    let string_table_offset = code_start + module.code.len() as u16;
-    img.push(string_table_offset as u8);
-    img.push((string_table_offset >> 8) as u8);
+    /// Offsets where code for source `line` starts
+    pub fn line_offsets(&self, line: usize) -> Vec<u16> {
+        self.module.lines.iter().filter(|(_, n)| *n == line).map(|(pc, _)| *pc).collect()
+    }
 
-    // Code length
-    img.push(module.code.len() as u8);
-    img.push((module.code.len() >> 8) as u8);
+    /// Offset of each instruction, in order
+    pub fn instructions(&self) -> impl Iterator<Item = u16> + '_ {
+        let code = &self.module.code;
+        std::iter::successors(Some(0usize), move |&pc| code.get(pc).map(|&b| pc + Op::from_byte(b).size()))
+            .take_while(move |&pc| pc < code.len())
+            .map(|pc| pc as u16)
+    }
 
-    // Entry point
-    img.push(module.entry as u8);
-    img.push((module.entry >> 8) as u8);
+    /// The instruction at `offset` with its operand, and the ROM address of
+    /// a code operand
+    pub fn describe(&self, offset: u16) -> String {
+        let code = &self.module.code;
+        let pc = offset as usize;
+        let op = Op::from_byte(code[pc]);
+        let mut text = format!("{:04X} {:?}", offset, op);
+        match code.get(pc + 1..(pc + op.size()).min(code.len())) {
+            Some([b]) => text.push_str(&format!(" 0x{:02X}", b)),
+            Some([lo, hi]) => {
+                let operand = u16::from_le_bytes([*lo, *hi]);
+                text.push_str(&format!(" 0x{:04X}", operand));
+                if matches!(op, Op::Jump | Op::JumpIf | Op::JumpIfNot | Op::JumpIfDef | Op::Call | Op::Spawn) {
+                    text.push_str(&format!(" -> {:04X}", self.address(operand)));
+                }
+            }
+            _ => {}
+        }
+        text
+    }
+}
+
+/// Part of the bytecode image: its offset in the image, bytes, description,
+/// and notes (subs, source lines) that start there
+struct ImageLine {
+    offset: usize,
+    bytes: Vec<u8>,
+    text: String,
+    notes: Vec<String>,
+}
 
-    // Bytecode
-    img.extend_from_slice(&module.code);
+/// The bytecode image split into header fields, instructions and strings
+fn image_lines(module: &Module) -> Vec<ImageLine> {
+    let image = generate_bytecode_image(module);
+    let word = |at: usize| u16::from_le_bytes([image[at], image[at + 1]]);
+    let line = |offset: usize, len: usize, text: String| ImageLine {
+        offset,
+        bytes: image[offset..offset + len].to_vec(),
+        text,
+        notes: Vec::new(),
+    };
+
+    let mut lines = vec![
+        line(0, 4, "magic".to_string()),
+        line(4, 2, format!("string table offset 0x{:04X}", word(4))),
+        line(6, 2, format!("code length 0x{:04X}", word(6))),
+        line(8, 2, format!("entry point 0x{:04X}", word(8))),
+        line(10, 2, format!("VM stack 0x{:04X}", word(10))),
+    ];
+
+    let mut pc = 0;
+    while pc < module.code.len() {
+        let op = Op::from_byte(module.code[pc]);
+        let end = (pc + op.size()).min(module.code.len());
+        let operand = match &module.code[pc + 1..end] {
+            [b] => format!(" 0x{:02X}", b),
+            [lo, hi] => format!(" 0x{:04X}", u16::from_le_bytes([*lo, *hi])),
+            _ => String::new(),
+        };
+        let mut l = line(HEADER + pc, end - pc, format!("{:04X}  {:?}{}", pc, op, operand));
+        for (name, _, params) in module.subs.iter().filter(|(_, addr, _)| *addr as usize == pc) {
+            l.notes.push(format!("sub {} ({} params)", name, params));
+        }
+        if let Some((_, n)) = module.lines.iter().find(|(offset, _)| *offset as usize == pc) {
+            l.notes.push(format!("line {}", n));
+        }
+        lines.push(l);
+        pc = end;
+    }
 
-    // String table
-    img.push(module.strings.len() as u8);
-    for s in &module.strings {
-        img.push(s.len() as u8);
-        img.extend_from_slice(s.as_bytes());
+    let mut offset = HEADER + module.code.len();
+    lines.push(line(offset, 1, "string count".to_string()));
+    offset += 1;
+    for (i, s) in module.strings.iter().enumerate() {
+        lines.push(line(offset, 1 + s.len(), format!("[{}] {:?}", i, s)));
+        offset += 1 + s.len();
     }
+    lines
+}
 
-    img
+/// Generate the bytecode image (header + code + strings)
+pub fn generate_bytecode_image(module: &Module) -> Vec<u8> {
+    module.image()
+}
+
+/// Version of the runtime's code, bumped whenever the bytes `runtime`
+/// gives change, so a golden ROM can tell a new runtime from a new compiler
+pub const RUNTIME_VERSION: u16 = 21;
+
+/// The runtime interpreter for `options`, assembled once per set of options
+/// and the same bytes every time. The program's name goes in the self-test
+/// block, not the runtime, so programs that differ only in name share one.
+pub fn runtime(options: &RomOptions) -> &'static [u8] {
+    static RUNTIMES: OnceLock<Mutex<HashMap<RomOptions, &'static [u8]>>> = OnceLock::new();
+    let key = RomOptions { program_name: String::new(), ..options.clone() };
+    let mut runtimes = RUNTIMES.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    runtimes.entry(key).or_insert_with(|| assemble_runtime(options).finish().leak())
 }
 
 /// Generate the Z80 runtime interpreter
-fn generate_runtime() -> Vec<u8> {
-    let mut code = Vec::new();
+fn generate_runtime(options: &RomOptions) -> Vec<u8> {
+    runtime(options).to_vec()
+}
 
-    // Entry point at 0x0000
-    // LD SP, STACK_TOP
-    code.push(LD_SP_NN);
-    code.push(STACK_TOP as u8);
-    code.push((STACK_TOP >> 8) as u8);
+/// Bytes of runtime in front of the bytecode
+pub fn runtime_size(options: &RomOptions) -> usize {
+    generate_runtime(options).len()
+}
+
+/// Disassemble the runtime, annotated with its label names
+pub fn dump_runtime(options: &RomOptions) -> String {
+    let a = assemble_runtime(options);
+    let symbols = a.symbols();
+    let data = a.data().to_vec();
+    z80dis::render(&a.finish(), options.target.layout().runtime_org, &symbols, &data)
+}
 
-    // DI - disable interrupts
-    code.push(DI);
+/// Address of a runtime label such as "main_loop"
+pub fn runtime_symbol(options: &RomOptions, name: &str) -> Option<u16> {
+    assemble_runtime(options)
+        .symbols()
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, addr)| addr)
+}
+
+/// Assemble the runtime, leaving labels available for inspection
+fn assemble_runtime(options: &RomOptions) -> Asm {
+    let l = &options.target.layout();
+    let console: Box<dyn ConsoleBackend> = match options.channels() {
+        Some(port) => Box::new(Channels { console: options.target.console(), port, channel: l.channel() }),
+        None => options.target.console(),
+    };
+    let mut a = Asm::new(l.runtime_org);
+    let init = a.label("init");
+
+    // With interrupt-driven input the IM1 vector at 0x0038 must hold the
+    // ISR, so jump over it to the init code
+    if options.irq_input {
+        a.jp(init);
+        a.pad_to(IM1_VECTOR, 0x00);
+        let port = console.rx_port().expect("interrupt input needs a console that can interrupt");
+        emit_rx_isr(&mut a, l, port);
+    } else if options.timer {
+        a.jp(init);
+        a.pad_to(IM1_VECTOR, 0x00);
+        a.push(StackReg::HL);
+        a.ld_from(Reg16::HL, l.ticks());
+        a.inc16(Reg16::HL);
+        a.ld_to(l.ticks(), Reg16::HL);
+        a.pop(StackReg::HL);
+        a.ei();
+        a.reti();
+    }
+
+    a.bind(init);
+    if options.target.hosted() {
+        // Keep the caller's stack to return to, and leave interrupts on for
+        // the system's keyboard scanning and clocks
+        a.ld_to(l.saved_sp(), Reg16::SP);
+        a.ld_nn(Reg16::SP, l.stack_top);
+    } else {
+        a.ld_nn(Reg16::SP, l.stack_top);
+        a.di();
+    }
+    console.emit_init(&mut a, options.irq_input);
 
     // Initialize VM state
-    // LD HL, VM_STACK
-    code.push(LD_HL_NN);
-    code.push(VM_STACK as u8);
-    code.push((VM_STACK >> 8) as u8);
-
-    // LD (vm_sp), HL
-    let vm_sp_addr = 0x3000u16; // VM state in RAM (above protected ROM)
-    code.push(LD_NN_HL);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
-
-    // LD (vm_fp), HL
-    let vm_fp_addr = vm_sp_addr + 2;
-    code.push(LD_NN_HL);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-
-    // Initialize heap pointer
-    code.push(LD_HL_NN);
-    code.push(HEAP_BASE as u8);
-    code.push((HEAP_BASE >> 8) as u8);
-    let heap_ptr_addr = vm_fp_addr + 2;
-    code.push(LD_NN_HL);
-    code.push(heap_ptr_addr as u8);
-    code.push((heap_ptr_addr >> 8) as u8);
-
-    // Set bytecode pointer (BYTECODE_ORG + 10 for header)
-    let bc_code_start = BYTECODE_ORG + 10;
-    code.push(LD_HL_NN);
-    code.push(bc_code_start as u8);
-    code.push((bc_code_start >> 8) as u8);
-    let vm_code_addr = heap_ptr_addr + 2;
-    code.push(LD_NN_HL);
-    code.push(vm_code_addr as u8);
-    code.push((vm_code_addr >> 8) as u8);
-
-    // Load string table pointer from header
-    code.push(LD_HL_NN);
-    code.push((BYTECODE_ORG + 4) as u8);
-    code.push(((BYTECODE_ORG + 4) >> 8) as u8);
-    // LD HL,(HL) - need to do this manually
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
-    // DE = string table offset, add BYTECODE_ORG
-    code.push(LD_HL_NN);
-    code.push(BYTECODE_ORG as u8);
-    code.push((BYTECODE_ORG >> 8) as u8);
-    code.push(ADD_HL_DE);
-    let vm_strings_addr = vm_code_addr + 2;
-    code.push(LD_NN_HL);
-    code.push(vm_strings_addr as u8);
-    code.push((vm_strings_addr >> 8) as u8);
-
-    // Initialize PC to entry point (read from header at BYTECODE_ORG+8)
-    code.push(LD_HL_NN);
-    code.push((BYTECODE_ORG + 8) as u8);
-    code.push(((BYTECODE_ORG + 8) >> 8) as u8);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
-    code.push(EX_DE_HL);
-    let vm_pc_addr = vm_strings_addr + 2;
-    code.push(LD_NN_HL);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-
-    // Jump to main interpreter loop
-    let main_loop_addr = code.len() as u16 + 3; // After this JP
-    code.push(JP_NN);
-    code.push(main_loop_addr as u8);
-    code.push((main_loop_addr >> 8) as u8);
+    a.ld_nn(Reg16::HL, l.vm_stack);
+    a.ld_to(l.vm_sp(), Reg16::HL);
+    a.ld_to(l.vm_fp(), Reg16::HL);
+    if options.mem_stats {
+        a.ld_to(l.stack_low(), Reg16::HL);
+    }
+    let mut heap = l.heap_base;
+    if options.coverage {
+        // Zero the counters and start the heap above them
+        let bytes = 2 * COUNTERS as u16;
+        a.ld_nn(Reg16::HL, l.counters());
+        a.ld_nn(Reg16::DE, l.counters() + 1);
+        a.ld_nn(Reg16::BC, bytes - 1);
+        a.ld_n(Reg8::HLInd, 0);
+        a.ldir();
+        heap += bytes;
+    }
+    a.ld_nn(Reg16::HL, heap);
+    a.ld_to(l.heap_ptr(), Reg16::HL);
+
+    // Bytecode starts after the header
+    a.ld_nn(Reg16::HL, l.bytecode_org + HEADER as u16);
+    a.ld_to(l.vm_code(), Reg16::HL);
+
+    // String table = bytecode origin + offset from header
+    a.ld_from(Reg16::DE, l.bytecode_org + 4);
+    a.ld_nn(Reg16::HL, l.bytecode_org);
+    a.add_hl(Reg16::DE);
+    a.ld_to(l.vm_strings(), Reg16::HL);
+
+    // PC = entry point from header
+    a.ld_from(Reg16::HL, l.bytecode_org + 8);
+    a.ld_to(l.vm_pc(), Reg16::HL);
+
+    // No arguments unless a loader filled the parameter block
+    a.ld_from(Reg16::HL, l.args());
+    a.ld_nn(Reg16::DE, ARGS_MAGIC);
+    a.xor(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    let args_ok = a.label("args_ok");
+    a.jr_cc(Cond::Z, args_ok);
+    a.ld(Reg8::H, Reg8::A);
+    a.ld(Reg8::L, Reg8::A);
+    a.ld_to(l.argv(), Reg16::HL);
+    a.bind(args_ok);
+
+    if options.shell {
+        // The shell's variable table follows the string table
+        a.ld_from(Reg16::HL, l.vm_strings());
+        a.ld(Reg8::B, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::A, Reg8::B);
+        a.or(Reg8::A);
+        let found = a.label("shell_vars_found");
+        a.jr_cc(Cond::Z, found);
+        let skip = a.here_label("shell_vars_skip");
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.ld_n(Reg8::D, 0);
+        a.inc16(Reg16::DE);
+        a.add_hl(Reg16::DE);
+        a.djnz(skip);
+        a.bind(found);
+        a.ld_to(l.shell_vars(), Reg16::HL);
+        a.xor(Reg8::A);
+        a.ld_a_to(l.shell_newline());
+    }
+
+    let putc = a.label("putc");
+    let exit = a.label("exit");
+    let print_text = a.label("print_text");
+    if options.self_test {
+        emit_self_test(&mut a, l, exit, print_text);
+    }
+    // Where the heap grows up towards the VM stack, keep it under the depth
+    // the header asks for, and refuse a program that has no room for that
+    let heap_check = (l.heap_base < l.vm_stack).then(|| a.label("heap_check"));
+    if heap_check.is_some() {
+        emit_heap_limit(&mut a, l, heap, exit, print_text);
+    }
+
+    // A hosted program goes back to the OS, so only boards suspend, and
+    // only boards have the room for tasks
+    if !options.target.hosted() {
+        // Just the main program, in slot 0, whose VM_SP is saved later
+        a.ld_nn(Reg16::HL, l.task());
+        a.ld_n(Reg8::B, 1 + 6 * MAX_TASKS as u8);
+        a.xor(Reg8::A);
+        let clear = a.here_label("tasks_clear");
+        a.ld(Reg8::HLInd, Reg8::A);
+        a.inc16(Reg16::HL);
+        a.djnz(clear);
+        a.dec(Reg8::A);
+        a.ld_a_to(l.tasks() + 3);
+        emit_resume(&mut a, l);
+    }
+
+    if options.irq_input {
+        // Empty the ring buffer and enable IM1 interrupts
+        a.xor(Reg8::A);
+        a.ld_a_to(l.rx_head());
+        a.ld_a_to(l.rx_tail());
+        a.im(1);
+        a.ei();
+    }
+
+    if options.timer {
+        // Nothing interrupts until the program starts a timer
+        a.ld_nn(Reg16::HL, 0);
+        a.ld_to(l.ticks(), Reg16::HL);
+        a.im(1);
+        a.ei();
+    }
 
     // === Main interpreter loop ===
-    let loop_start = code.len() as u16;
-
-    // Load PC and get opcode
-    // LD HL,(vm_pc)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    // LD DE,(vm_code)
-    code.push(ED);
-    code.push(LD_DE_NN_IND);
-    code.push(vm_code_addr as u8);
-    code.push((vm_code_addr >> 8) as u8);
-    // ADD HL,DE
-    code.push(ADD_HL_DE);
-    // LD A,(HL) - get opcode
-    code.push(LD_A_HL);
-
-    // Check for HALT (0xF0)
-    code.push(CP_N);
-    code.push(0xF0);
-    let halt_addr = code.len() as u16 + 3; // Will patch
-    code.push(JP_Z_NN);
-    code.push(0); // placeholder
-    code.push(0);
-
-    // Dispatch based on opcode
-    // Use a jump table approach - multiply opcode by 2 and index into table
-    // For now, use a series of comparisons for key opcodes
-
-    // Save HL (instruction pointer) for operand fetching
-    code.push(PUSH_HL);
-
-    // === Opcode handlers ===
-
-    // Check for PUSH (0x01) - push 16-bit immediate
-    code.push(CP_N);
-    code.push(0x01);
-    let not_push = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // PUSH handler
-    code.push(INC_HL);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
-    // Push DE onto VM stack
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    // Advance PC by 3
-    emit_advance_pc(&mut code, vm_pc_addr, 3);
-    // Jump back to loop
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_push jump
-    let here = code.len() as u16;
-    code[not_push as usize - 2] = here as u8;
-    code[not_push as usize - 1] = (here >> 8) as u8;
-
-    // Check for PUSHBYTE (0x02)
-    code.push(CP_N);
-    code.push(0x02);
-    let not_pushbyte = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // PUSHBYTE handler - push sign-extended byte
-    code.push(INC_HL);
-    code.push(LD_A_HL);
-    code.push(LD_E_A);
-    // Sign extend: if bit 7 set, D=0xFF else D=0
-    code.push(LD_D_N);
-    code.push(0);
-    code.push(CB);
-    code.push(BIT_7_A);
-    let no_sign_ext = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    code.push(LD_D_N);
-    code.push(0xFF);
-    let sign_ext_done = code.len() as u16;
-    code[no_sign_ext as usize - 2] = sign_ext_done as u8;
-    code[no_sign_ext as usize - 1] = (sign_ext_done >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 2);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_pushbyte
-    let here = code.len() as u16;
-    code[not_pushbyte as usize - 2] = here as u8;
-    code[not_pushbyte as usize - 1] = (here >> 8) as u8;
-
-    // Check for PUSHSTR (0x18)
-    code.push(CP_N);
-    code.push(0x18);
-    let not_pushstr = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // PUSHSTR handler - push string pointer
-    code.push(INC_HL);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
-    // DE = string index, find string in table
-    code.push(PUSH_DE);
-    // LD HL,(vm_strings)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_strings_addr as u8);
-    code.push((vm_strings_addr >> 8) as u8);
-    code.push(INC_HL); // Skip count byte
-    code.push(POP_DE);
-    // Skip DE strings to find the right one
-    code.push(LD_A_E);
-    code.push(OR_A);
-    let found_str = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    // Skip loop
-    let skip_str_loop = code.len() as u16;
-    code.push(LD_A_HL); // Length byte
-    code.push(LD_C_A);
-    code.push(LD_B_N);
-    code.push(0);
-    code.push(INC_BC); // +1 for length byte
-    code.push(ADD_HL_BC);
-    code.push(DEC_DE);
-    code.push(LD_A_E);
-    code.push(OR_D);
-    code.push(JP_NZ_NN);
-    code.push(skip_str_loop as u8);
-    code.push((skip_str_loop >> 8) as u8);
-    // Patch found_str
-    let here = code.len() as u16;
-    code[found_str as usize - 2] = here as u8;
-    code[found_str as usize - 1] = (here >> 8) as u8;
-    // HL now points to string, push it
-    code.push(EX_DE_HL);
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 3);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_pushstr
-    let here = code.len() as u16;
-    code[not_pushstr as usize - 2] = here as u8;
-    code[not_pushstr as usize - 1] = (here >> 8) as u8;
-
-    // Check for PRINT (0x78)
-    code.push(CP_N);
-    code.push(0x78);
-    let not_print = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // PRINT handler - print value from stack
-    emit_vm_pop_de(&mut code, vm_sp_addr);
-    // Check if it looks like a string pointer (>= 0x1000) or a number
-    code.push(LD_A_D);
-    code.push(CP_N);
-    code.push(0x10); // High byte >= 0x10 means pointer >= 0x1000
-    let is_number = code.len() as u16 + 3;
-    code.push(JP_C_NN);
-    code.push(0);
-    code.push(0);
-
-    // It's a string pointer - print the string
-    code.push(EX_DE_HL);
-    code.push(LD_B_HL); // B = length
-    code.push(INC_HL);
-    code.push(LD_A_B);
-    code.push(OR_A);
-    let print_done = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    let print_loop = code.len() as u16;
-    code.push(LD_A_HL);
-    code.push(OUT_N_A);
-    code.push(PORT_CONSOLE);
-    code.push(INC_HL);
-    code.push(DJNZ);
-    // DJNZ offset is relative to address after the offset byte
-    let offset = (print_loop as i16 - code.len() as i16 - 1) as i8;
-    code.push(offset as u8);
-    // Patch print_done and jump to end
-    let here = code.len() as u16;
-    code[print_done as usize - 2] = here as u8;
-    code[print_done as usize - 1] = (here >> 8) as u8;
-    let print_end = code.len() as u16 + 3;
-    code.push(JP_NN);
-    code.push(0);
-    code.push(0);
-
-    // Patch is_number jump target - print as number
-    let here = code.len() as u16;
-    code[is_number as usize - 2] = here as u8;
-    code[is_number as usize - 1] = (here >> 8) as u8;
-    // DE = number, print it as decimal
-    // Simple: just print low byte as decimal for now
-    code.push(LD_A_E);
-    // Convert to decimal and print (simple version for 0-99)
-    // Divide by 10 for tens digit
-    code.push(LD_B_N);
-    code.push(0x30 - 1); // '0' - 1
-    let tens_loop = code.len() as u16;
-    code.push(INC_B);
-    code.push(SUB_N);
-    code.push(10);
-    code.push(JR_NC_N);
-    let offset = (tens_loop as i16 - code.len() as i16 - 1) as i8;
-    code.push(offset as u8);
-    // Restore remainder
-    code.push(ADD_A_N);
-    code.push(10);
-    // B = tens digit + '0', A = remainder
-    code.push(PUSH_AF);
-    // Only print tens if > 0
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(0x30); // '0'
-    let skip_tens = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    code.push(OUT_N_A);
-    code.push(PORT_CONSOLE);
-    // Patch skip_tens
-    let here = code.len() as u16;
-    code[skip_tens as usize - 2] = here as u8;
-    code[skip_tens as usize - 1] = (here >> 8) as u8;
-    // Print ones digit
-    code.push(POP_AF);
-    code.push(ADD_A_N);
-    code.push(0x30); // '0'
-    code.push(OUT_N_A);
-    code.push(PORT_CONSOLE);
-
-    // Patch print_end
-    let here = code.len() as u16;
-    code[print_end as usize - 2] = here as u8;
-    code[print_end as usize - 1] = (here >> 8) as u8;
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_print
-    let here = code.len() as u16;
-    code[not_print as usize - 2] = here as u8;
-    code[not_print as usize - 1] = (here >> 8) as u8;
-
-    // Check for LDLOC (0x10)
-    code.push(CP_N);
-    code.push(0x10);
-    let not_ldloc = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // LDLOC handler
-    code.push(INC_HL);
-    code.push(LD_A_HL); // A = local index
-    // Calculate address: fp + index * 2
-    code.push(LD_E_A);
-    code.push(LD_D_N);
-    code.push(0);
-    code.push(EX_DE_HL);
-    code.push(ADD_HL_HL); // * 2
-    code.push(EX_DE_HL); // DE = offset
-    code.push(LD_HL_NN_IND);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-    code.push(ADD_HL_DE); // HL = fp + index*2
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL); // DE = value
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 2);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_ldloc
-    let here = code.len() as u16;
-    code[not_ldloc as usize - 2] = here as u8;
-    code[not_ldloc as usize - 1] = (here >> 8) as u8;
-
-    // Check for STLOC (0x11)
-    code.push(CP_N);
-    code.push(0x11);
-    let not_stloc = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // STLOC handler
-    code.push(INC_HL);
-    code.push(LD_A_HL); // A = local index
-    code.push(PUSH_AF);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = value
-    code.push(POP_AF);
-    // Calculate address: fp + index * 2
-    code.push(LD_L_A);
-    code.push(LD_H_N);
-    code.push(0);
-    code.push(ADD_HL_HL); // * 2
-    code.push(PUSH_DE);
-    code.push(EX_DE_HL); // DE = offset
-    code.push(LD_HL_NN_IND);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-    code.push(ADD_HL_DE); // HL = fp + index*2
-    code.push(POP_DE);
-    code.push(LD_HL_E);
-    code.push(INC_HL);
-    code.push(LD_HL_D);
-    emit_advance_pc(&mut code, vm_pc_addr, 2);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_stloc
-    let here = code.len() as u16;
-    code[not_stloc as usize - 2] = here as u8;
-    code[not_stloc as usize - 1] = (here >> 8) as u8;
-
-    // Check for ADD (0x30)
-    code.push(CP_N);
-    code.push(0x30);
-    let not_add = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // ADD handler
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = b
-    code.push(PUSH_DE);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = a
-    code.push(POP_HL); // HL = b
-    code.push(ADD_HL_DE); // HL = a + b
-    code.push(EX_DE_HL);
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_add
-    let here = code.len() as u16;
-    code[not_add as usize - 2] = here as u8;
-    code[not_add as usize - 1] = (here >> 8) as u8;
-
-    // Check for CmpLt (0x42)
-    code.push(CP_N);
-    code.push(0x42);
-    let not_cmplt = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // CmpLt handler: a < b
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = b
-    code.push(PUSH_DE);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = a
-    code.push(POP_HL); // HL = b
-    // Compare: a < b means a - b < 0
-    code.push(EX_DE_HL); // HL = a, DE = b
-    code.push(OR_A); // Clear carry
-    code.push(ED);
-    code.push(SBC_HL_DE); // HL = a - b
-    // If negative (bit 15 set), result is true
-    code.push(LD_DE_NN);
-    code.push(0);
-    code.push(0);
-    code.push(CB);
-    code.push(BIT_7_H);
-    let cmplt_false = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    code.push(INC_DE); // DE = 1 (true)
-    let cmplt_done = code.len() as u16;
-    code[cmplt_false as usize - 2] = cmplt_done as u8;
-    code[cmplt_false as usize - 1] = (cmplt_done >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_cmplt
-    let here = code.len() as u16;
-    code[not_cmplt as usize - 2] = here as u8;
-    code[not_cmplt as usize - 1] = (here >> 8) as u8;
-
-    // Check for CmpLe (0x44) - a <= b
-    code.push(CP_N);
-    code.push(0x44);
-    let not_cmple = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // CmpLe handler: a <= b is same as !(b < a)
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = b
-    code.push(PUSH_DE);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = a
-    code.push(POP_HL); // HL = b
-    // Compare: b < a - if true, a <= b is false
-    code.push(OR_A);
-    code.push(ED);
-    code.push(SBC_HL_DE); // HL = b - a
-    code.push(LD_DE_NN);
-    code.push(1);
-    code.push(0); // Assume true
-    code.push(CB);
-    code.push(BIT_7_H);
-    let cmple_true = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    code.push(DEC_DE); // DE = 0 (false, because b < a)
-    let here = code.len() as u16;
-    code[cmple_true as usize - 2] = here as u8;
-    code[cmple_true as usize - 1] = (here >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_cmple
-    let here = code.len() as u16;
-    code[not_cmple as usize - 2] = here as u8;
-    code[not_cmple as usize - 1] = (here >> 8) as u8;
-
-    // Check for CmpEq (0x40) - a == b
-    code.push(CP_N);
-    code.push(0x40);
-    let not_cmpeq = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // CmpEq handler
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = b
-    code.push(PUSH_DE);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = a
-    code.push(POP_HL); // HL = b
-    code.push(OR_A);
-    code.push(ED);
-    code.push(SBC_HL_DE); // HL = b - a
-    code.push(LD_DE_NN);
-    code.push(0);
-    code.push(0);
-    code.push(LD_A_H);
-    code.push(OR_L);
-    let cmpeq_false = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-    code.push(INC_DE); // DE = 1 (equal)
-    let here = code.len() as u16;
-    code[cmpeq_false as usize - 2] = here as u8;
-    code[cmpeq_false as usize - 1] = (here >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_cmpeq
-    let here = code.len() as u16;
-    code[not_cmpeq as usize - 2] = here as u8;
-    code[not_cmpeq as usize - 1] = (here >> 8) as u8;
-
-    // Check for Mod (0x34) - a % b
-    code.push(CP_N);
-    code.push(0x34);
-    let not_mod = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // Mod handler - simple repeated subtraction
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = b (divisor)
-    code.push(PUSH_DE);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = a (dividend)
-    code.push(EX_DE_HL); // HL = dividend
-    code.push(POP_DE); // DE = divisor
-    // Repeated subtraction: while HL >= DE, HL -= DE
-    let mod_loop = code.len() as u16;
-    code.push(OR_A);
-    code.push(ED);
-    code.push(SBC_HL_DE);
-    code.push(JR_NC_N);
-    let offset = (mod_loop as i16 - code.len() as i16 - 1) as i8;
-    code.push(offset as u8);
-    // Went negative, add back
-    code.push(ADD_HL_DE);
-    code.push(EX_DE_HL); // DE = remainder
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_mod
-    let here = code.len() as u16;
-    code[not_mod as usize - 2] = here as u8;
-    code[not_mod as usize - 1] = (here >> 8) as u8;
-
-    // Check for JUMP (0x60)
-    code.push(CP_N);
-    code.push(0x60);
-    let not_jump = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // JUMP handler
-    code.push(INC_HL);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL); // DE = target
-    code.push(EX_DE_HL);
-    code.push(LD_NN_HL);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_jump
-    let here = code.len() as u16;
-    code[not_jump as usize - 2] = here as u8;
-    code[not_jump as usize - 1] = (here >> 8) as u8;
-
-    // Check for JUMPIFNOT (0x62)
-    code.push(CP_N);
-    code.push(0x62);
-    let not_jifnot = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // JUMPIFNOT handler
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = condition
-    code.push(LD_A_E);
-    code.push(OR_D);
-    let jifnot_take = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    // Condition true, don't jump
-    emit_advance_pc(&mut code, vm_pc_addr, 3);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-    // Take the jump
-    let here = code.len() as u16;
-    code[jifnot_take as usize - 2] = here as u8;
-    code[jifnot_take as usize - 1] = (here >> 8) as u8;
-    code.push(POP_HL); // Get instruction pointer back
-    code.push(INC_HL);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
-    code.push(EX_DE_HL);
-    code.push(LD_NN_HL);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_jifnot
-    let here = code.len() as u16;
-    code[not_jifnot as usize - 2] = here as u8;
-    code[not_jifnot as usize - 1] = (here >> 8) as u8;
-
-    // Check for INC (0x36)
-    code.push(CP_N);
-    code.push(0x36);
-    let not_inc = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // INC handler
-    emit_vm_pop_de(&mut code, vm_sp_addr);
-    code.push(INC_DE);
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_inc
-    let here = code.len() as u16;
-    code[not_inc as usize - 2] = here as u8;
-    code[not_inc as usize - 1] = (here >> 8) as u8;
-
-    // Check for DUP (0x04)
-    code.push(CP_N);
-    code.push(0x04);
-    let not_dup = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // DUP handler - peek and push
-    code.push(LD_HL_NN_IND);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_dup
-    let here = code.len() as u16;
-    code[not_dup as usize - 2] = here as u8;
-    code[not_dup as usize - 1] = (here >> 8) as u8;
-
-    // Check for POP (0x03)
-    code.push(CP_N);
-    code.push(0x03);
-    let not_pop = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // POP handler
-    emit_vm_pop_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_pop
-    let here = code.len() as u16;
-    code[not_pop as usize - 2] = here as u8;
-    code[not_pop as usize - 1] = (here >> 8) as u8;
-
-    // Check for CALL (0x68)
-    code.push(CP_N);
-    code.push(0x68);
-    let not_call = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // CALL handler
-    // Get target address
-    code.push(INC_HL);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL); // DE = target
-    code.push(PUSH_DE);
-    // Push return address (PC + 3) onto VM stack
-    code.push(LD_HL_NN_IND);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    code.push(LD_DE_NN);
-    code.push(3);
-    code.push(0);
-    code.push(ADD_HL_DE);
-    code.push(EX_DE_HL); // DE = return address
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    // Push current frame pointer
-    code.push(LD_HL_NN_IND);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-    code.push(EX_DE_HL);
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    // Set PC to target
-    code.push(POP_HL); // HL = target
-    code.push(LD_NN_HL);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_call
-    let here = code.len() as u16;
-    code[not_call as usize - 2] = here as u8;
-    code[not_call as usize - 1] = (here >> 8) as u8;
-
-    // Check for ENTER (0x70)
-    code.push(CP_N);
-    code.push(0x70);
-    let not_enter = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // ENTER handler - set up stack frame
-    // Stack before ENTER: [...args...] [ret_addr] [old_fp] <- SP
-    // We set FP = SP + 4 so that FP + 0 = first arg, FP + 2 = second arg, etc.
-    // The old_fp is at FP - 4, ret_addr is at FP - 2 (accessible by RETURN)
-    code.push(INC_HL); // Skip past opcode to operand (num_params, unused for now)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
-    // HL = SP, add 4 to get FP pointing at first arg
-    code.push(LD_DE_NN);
-    code.push(4);
-    code.push(0);
-    code.push(ADD_HL_DE); // HL = SP + 4
-    code.push(LD_NN_HL);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-
-    emit_advance_pc(&mut code, vm_pc_addr, 2);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_enter
-    let here = code.len() as u16;
-    code[not_enter as usize - 2] = here as u8;
-    code[not_enter as usize - 1] = (here >> 8) as u8;
-
-    // Check for LEAVE (0x71)
-    code.push(CP_N);
-    code.push(0x71);
-    let not_leave = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // LEAVE handler - restore SP to FP - 4 (where old_fp and ret_addr are)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-    code.push(LD_DE_NN);
-    code.push(4);
-    code.push(0);
-    code.push(OR_A);
-    code.push(ED);
-    code.push(SBC_HL_DE); // HL = FP - 4
-    code.push(LD_NN_HL);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_leave
-    let here = code.len() as u16;
-    code[not_leave as usize - 2] = here as u8;
-    code[not_leave as usize - 1] = (here >> 8) as u8;
-
-    // Check for RETURN (0x6A)
-    code.push(CP_N);
-    code.push(0x6A);
-    let not_return = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // RETURN handler
-    // Restore FP from stack
-    emit_vm_pop_de(&mut code, vm_sp_addr);
-    code.push(EX_DE_HL);
-    code.push(LD_NN_HL);
-    code.push(vm_fp_addr as u8);
-    code.push((vm_fp_addr >> 8) as u8);
-    // Pop return address
-    emit_vm_pop_de(&mut code, vm_sp_addr);
-    code.push(EX_DE_HL);
-    code.push(LD_NN_HL);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_return
-    let here = code.len() as u16;
-    code[not_return as usize - 2] = here as u8;
-    code[not_return as usize - 1] = (here >> 8) as u8;
-
-    // Check for NOT (0x50) - logical not
-    code.push(CP_N);
-    code.push(0x50);
-    let not_not = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // NOT handler - if value == 0, push 1, else push 0
-    emit_vm_pop_de(&mut code, vm_sp_addr);
-    code.push(LD_A_E);
-    code.push(OR_D);
-    code.push(LD_DE_NN);
-    code.push(1);
-    code.push(0); // Assume value was 0, result is 1
-    let not_done = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-    code.push(DEC_DE); // Value wasn't 0, so result is 0
-    let here = code.len() as u16;
-    code[not_done as usize - 2] = here as u8;
-    code[not_done as usize - 1] = (here >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_not
-    let here = code.len() as u16;
-    code[not_not as usize - 2] = here as u8;
-    code[not_not as usize - 1] = (here >> 8) as u8;
-
-    // Check for AND (0x51) - logical and
-    code.push(CP_N);
-    code.push(0x51);
-    let not_and = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // AND handler - pop two values, if both non-zero push 1, else push 0
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = second operand
-    code.push(PUSH_DE);                     // Save second operand on Z80 stack
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = first operand
-    // Check if first (DE) is zero
-    code.push(LD_A_D);
-    code.push(OR_E);
-    code.push(POP_BC);                      // Restore second operand to BC
-    code.push(LD_DE_NN);
-    code.push(0);
-    code.push(0); // Assume result is 0 (false)
-    let and_done = code.len() as u16 + 3;
-    code.push(JP_Z_NN); // First is zero, result is 0
-    code.push(0);
-    code.push(0);
-    // First is non-zero, now check second (BC)
-    code.push(LD_A_B);
-    code.push(OR_C);
-    let and_done2 = code.len() as u16 + 3;
-    code.push(JP_Z_NN); // Second is zero, result is 0
-    code.push(0);
-    code.push(0);
-    code.push(INC_DE); // Both non-zero, result is 1
-    let and_done3 = code.len() as u16;
-    code[and_done as usize - 2] = and_done3 as u8;
-    code[and_done as usize - 1] = (and_done3 >> 8) as u8;
-    let here = code.len() as u16;
-    code[and_done2 as usize - 2] = here as u8;
-    code[and_done2 as usize - 1] = (here >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_and
-    let here = code.len() as u16;
-    code[not_and as usize - 2] = here as u8;
-    code[not_and as usize - 1] = (here >> 8) as u8;
-
-    // Check for OR (0x52) - logical or
-    code.push(CP_N);
-    code.push(0x52);
-    let not_or = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // OR handler - pop two values, if either non-zero push 1, else push 0
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = second operand
-    code.push(PUSH_DE);                     // Save second operand on Z80 stack
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = first operand
-    // Check if first (DE) is non-zero
-    code.push(LD_A_D);
-    code.push(OR_E);
-    code.push(POP_BC);                      // Restore second operand to BC
-    code.push(LD_DE_NN);
-    code.push(1);
-    code.push(0); // Assume result is 1 (true)
-    let or_done = code.len() as u16 + 3;
-    code.push(JP_NZ_NN); // First is non-zero, result is 1
-    code.push(0);
-    code.push(0);
-    // First is zero, check second (BC)
-    code.push(LD_A_B);
-    code.push(OR_C);
-    let or_done2 = code.len() as u16 + 3;
-    code.push(JP_NZ_NN); // Second is non-zero, result is 1
-    code.push(0);
-    code.push(0);
-    code.push(DEC_DE); // Both zero, result is 0
-    let or_done3 = code.len() as u16;
-    code[or_done as usize - 2] = or_done3 as u8;
-    code[or_done as usize - 1] = (or_done3 >> 8) as u8;
-    let here = code.len() as u16;
-    code[or_done2 as usize - 2] = here as u8;
-    code[or_done2 as usize - 1] = (here >> 8) as u8;
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_or
-    let here = code.len() as u16;
-    code[not_or as usize - 2] = here as u8;
-    code[not_or as usize - 1] = (here >> 8) as u8;
-
-    // Check for MATCH (0x88) - regex match
-    code.push(CP_N);
-    code.push(0x88);
-    let not_match = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-
-    // MATCH handler - simple pattern match
-    // Stack: [pattern_ptr] [subject_ptr] (pattern on top)
-    // Pattern is length-prefixed string from string table
-    // Subject is also length-prefixed string
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = pattern pointer
-    code.push(PUSH_DE);
-    emit_vm_pop_de(&mut code, vm_sp_addr); // DE = subject pointer
-    code.push(POP_HL); // HL = pattern pointer
-    // Save subject pointer, HL = pattern, DE = subject
-    code.push(PUSH_DE); // Save subject start for later
-
-    // Get pattern length and skip length byte
-    code.push(LD_B_HL); // B = pattern length
-    code.push(INC_HL);  // HL = pattern start
-
-    // Get subject length in C
-    code.push(LD_A_DE); // A = subject length
-    code.push(LD_C_A);
-    code.push(INC_DE);  // DE = subject start
-
-    // Now we need to find pattern in subject (substring search)
-    // Use a simple sliding window approach
-    // For each position in subject, try to match pattern
-
-    // Outer loop: try matching at each position
-    let match_outer_loop = code.len() as u16;
-    code.push(PUSH_BC); // Save lengths
-    code.push(PUSH_HL); // Save pattern start
-    code.push(PUSH_DE); // Save current subject position
-
-    // Check if enough characters left: C >= B
-    code.push(LD_A_C);
-    code.push(CP_B);
-    let match_fail_outer = code.len() as u16 + 3;
-    code.push(JP_C_NN); // Not enough chars, fail
-    code.push(0);
-    code.push(0);
-
-    // Inner loop: compare characters
-    let match_inner_loop = code.len() as u16;
-    code.push(LD_A_B);
-    code.push(OR_A);
-    let match_success = code.len() as u16 + 3;
-    code.push(JP_Z_NN); // Pattern exhausted, match!
-    code.push(0);
-    code.push(0);
-
-    // Check if pattern char is '.' (wildcard)
-    code.push(LD_A_HL);
-    code.push(CP_N);
-    code.push(b'.');
-    let not_wildcard = code.len() as u16 + 3;
-    code.push(JP_NZ_NN);
-    code.push(0);
-    code.push(0);
-    // Wildcard matches any char, just skip both
-    code.push(INC_HL);
-    code.push(INC_DE);
-    code.push(DEC_B);
-    code.push(JP_NN);
-    code.push(match_inner_loop as u8);
-    code.push((match_inner_loop >> 8) as u8);
-
-    // Patch not_wildcard
-    let here = code.len() as u16;
-    code[not_wildcard as usize - 2] = here as u8;
-    code[not_wildcard as usize - 1] = (here >> 8) as u8;
-
-    // Compare pattern char with subject char
-    code.push(LD_A_HL); // A = pattern char
-    code.push(PUSH_HL);
-    code.push(EX_DE_HL);
-    code.push(CP_HL); // Compare with subject char
-    code.push(EX_DE_HL);
-    code.push(POP_HL);
-    let match_char_ok = code.len() as u16 + 3;
-    code.push(JP_Z_NN);
-    code.push(0);
-    code.push(0);
-
-    // Mismatch - try next position in subject
-    code.push(POP_DE);  // Restore subject position
-    code.push(POP_HL);  // Restore pattern start
-    code.push(POP_BC);  // Restore lengths
-    code.push(INC_DE);  // Move to next position in subject
-    code.push(DEC_C);   // One less char available
-    code.push(JP_NN);
-    code.push(match_outer_loop as u8);
-    code.push((match_outer_loop >> 8) as u8);
-
-    // Patch match_char_ok - character matched, continue
-    let here = code.len() as u16;
-    code[match_char_ok as usize - 2] = here as u8;
-    code[match_char_ok as usize - 1] = (here >> 8) as u8;
-    code.push(INC_HL);
-    code.push(INC_DE);
-    code.push(DEC_B);
-    code.push(JP_NN);
-    code.push(match_inner_loop as u8);
-    code.push((match_inner_loop >> 8) as u8);
-
-    // Patch match_success
-    let here = code.len() as u16;
-    code[match_success as usize - 2] = here as u8;
-    code[match_success as usize - 1] = (here >> 8) as u8;
-    code.push(POP_DE);  // Clean up stack
-    code.push(POP_HL);
-    code.push(POP_BC);
-    code.push(POP_DE);  // Subject start (discard)
-    code.push(LD_DE_NN);
-    code.push(1);
-    code.push(0); // Result = 1 (match)
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch match_fail_outer
-    let here = code.len() as u16;
-    code[match_fail_outer as usize - 2] = here as u8;
-    code[match_fail_outer as usize - 1] = (here >> 8) as u8;
-    code.push(POP_DE);  // Clean up stack
-    code.push(POP_HL);
-    code.push(POP_BC);
-    code.push(POP_DE);  // Subject start (discard)
-    code.push(LD_DE_NN);
-    code.push(0);
-    code.push(0); // Result = 0 (no match)
-    emit_vm_push_de(&mut code, vm_sp_addr);
-    emit_advance_pc(&mut code, vm_pc_addr, 1);
-    code.push(JP_NN);
-    code.push(loop_start as u8);
-    code.push((loop_start >> 8) as u8);
-
-    // Patch not_match
-    let here = code.len() as u16;
-    code[not_match as usize - 2] = here as u8;
-    code[not_match as usize - 1] = (here >> 8) as u8;
+    let main_loop = a.here_label("main_loop");
+    let halt = a.label("halt");
+    let getc = a.label("getc");
+    let divmod = a.label("divmod");
+    let task_save = a.label("task_save");
+    let task_next = a.label("task_next");
+    let task_slot = a.label("task_slot");
+    let print_decimal = a.label("print_decimal");
+
+    if options.mem_stats {
+        // Sampled between instructions, where the stack is deepest
+        let higher = a.label("stack_higher");
+        a.ld_from(Reg16::HL, l.vm_sp());
+        a.ld_from(Reg16::DE, l.stack_low());
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE);
+        a.jr_cc(Cond::NC, higher);
+        a.add_hl(Reg16::DE);
+        a.ld_to(l.stack_low(), Reg16::HL);
+        a.bind(higher);
+    }
+
+    // HL = address of the current instruction, A = opcode
+    a.ld_from(Reg16::HL, l.vm_pc());
+    if let Some(b) = options.banking {
+        // The top two bits of the PC pick the page, the rest the address in
+        // the window
+        a.ld(Reg8::A, Reg8::H);
+        a.rlca();
+        a.rlca();
+        a.alu_n(Alu::And, 0x03);
+        a.alu_n(Alu::Add, b.first_page);
+        a.out_n(b.port);
+        a.ld(Reg8::A, Reg8::H);
+        a.alu_n(Alu::And, 0x3F);
+        a.alu_n(Alu::Or, (banking::WINDOW >> 8) as u8);
+        a.ld(Reg8::H, Reg8::A);
+    } else {
+        a.ld_from(Reg16::DE, l.vm_code());
+        a.add_hl(Reg16::DE);
+    }
+    a.ld(Reg8::A, Reg8::HLInd);
+    if let Some(port) = options.trace_port {
+        a.out_n(port);
+        if options.trace_stack {
+            a.push(StackReg::AF);
+            a.push(StackReg::HL);
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.out_n(port);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.out_n(port);
+            a.pop(StackReg::HL);
+            a.pop(StackReg::AF);
+        }
+    }
+    a.cp_n(Op::Halt as u8);
+    a.jp_cc(Cond::Z, halt);
+
+    // Padding at the end of a page
+    if options.banking.is_some() {
+        handler(&mut a, Op::Nop, |a| emit_next(a, l, 1, main_loop));
+    }
+
+    // Dispatch through a chain of comparisons. Each handler is entered with
+    // HL pointing at its opcode and must leave the Z80 stack balanced.
+
+    // First, as every basic block starts with one
+    if options.coverage {
+        handler(&mut a, Op::Count, |a| {
+            // HL = counters + 2 * index
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::L, Reg8::HLInd);
+            a.ld_n(Reg8::H, 0);
+            a.add_hl(Reg16::HL);
+            a.ld_nn(Reg16::DE, l.counters());
+            a.add_hl(Reg16::DE);
+            a.inc(Reg8::HLInd);
+            let done = a.label("count_done");
+            a.jr_cc(Cond::NZ, done);
+            a.inc16(Reg16::HL);
+            a.inc(Reg8::HLInd);
+            a.bind(done);
+            emit_next(a, l, 2, main_loop);
+        });
+    }
+
+    if options.bounds_check {
+        handler(&mut a, Op::CheckIdx, |a| {
+            // DE = idx (top), BC = arr, left on the stack
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::E, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::D, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::C, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::B, Reg8::HLInd);
+            // HL = length, the array's first word
+            a.ld(Reg8::H, Reg8::B);
+            a.ld(Reg8::L, Reg8::C);
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::H, Reg8::HLInd);
+            a.ld(Reg8::L, Reg8::A);
+            // In range if idx < length, unsigned; halt with VM_PC on the
+            // CheckIdx otherwise
+            a.or(Reg8::A);
+            a.sbc_hl(Reg16::DE);
+            a.jp_cc(Cond::C, halt);
+            a.jp_cc(Cond::Z, halt);
+            emit_next(a, l, 1, main_loop);
+        });
+    }
+
+    handler(&mut a, Op::Push, |a| {
+        emit_operand_word(a);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 3, main_loop);
+    });
+
+    handler(&mut a, Op::Argv, |a| {
+        a.ld_nn(Reg16::DE, l.argv());
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::PushByte, |a| {
+        // Push sign-extended byte
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.ld(Reg8::E, Reg8::A);
+        a.ld_n(Reg8::D, 0);
+        a.bit(7, Reg8::A);
+        let positive = a.label("pushbyte_positive");
+        a.jr_cc(Cond::Z, positive);
+        a.ld_n(Reg8::D, 0xFF);
+        a.bind(positive);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 2, main_loop);
+    });
+
+    handler(&mut a, Op::PushStr, |a| {
+        // DE = string index; walk the length-prefixed string table to it
+        emit_operand_word(a);
+        a.ld_from(Reg16::HL, l.vm_strings());
+        a.inc16(Reg16::HL); // Skip count byte
+        a.ld(Reg8::A, Reg8::D);
+        a.or(Reg8::E);
+        let found = a.label("pushstr_found");
+        a.jr_cc(Cond::Z, found);
+        let skip = a.here_label("pushstr_skip");
+        a.ld(Reg8::C, Reg8::HLInd); // Length byte
+        a.ld_n(Reg8::B, 0);
+        a.inc16(Reg16::BC); // +1 for length byte
+        a.add_hl(Reg16::BC);
+        a.dec16(Reg16::DE);
+        a.ld(Reg8::A, Reg8::D);
+        a.or(Reg8::E);
+        a.jr_cc(Cond::NZ, skip);
+        a.bind(found);
+        a.ex_de_hl();
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 3, main_loop);
+    });
+
+    handler(&mut a, Op::Repeat, |a| {
+        // HL = count, DE = string; a number has no text here, so halt
+        emit_vm_pop_operands(a, l);
+        a.ld(Reg8::A, Reg8::D);
+        a.cp_n(0x10);
+        a.jp_cc(Cond::C, halt);
+        // B = count, 0 if negative and at most 255, which fills a string
+        let positive = a.label("repeat_positive");
+        a.bit(7, Reg8::H);
+        a.jr_cc(Cond::Z, positive);
+        a.ld_nn(Reg16::HL, 0);
+        a.bind(positive);
+        let small = a.label("repeat_small");
+        a.ld(Reg8::A, Reg8::H);
+        a.or(Reg8::A);
+        a.jr_cc(Cond::Z, small);
+        a.ld_n(Reg8::L, 0xFF);
+        a.bind(small);
+        a.ld(Reg8::B, Reg8::L);
+        // Build the result at the heap pointer, its length byte the total
+        // so far; the Z80 stack holds its address
+        a.ld_from(Reg16::HL, l.heap_ptr());
+        a.ld_n(Reg8::HLInd, 0);
+        a.push(StackReg::HL);
+        let repeat_loop = a.here_label("repeat_loop");
+        let done = a.label("repeat_done");
+        a.ld(Reg8::A, Reg8::B);
+        a.or(Reg8::A);
+        a.jr_cc(Cond::Z, done);
+        a.pop(StackReg::HL);
+        a.push(StackReg::HL);
+        // C = bytes to copy: the whole string, or what is left of 255
+        a.ld_a_ind(Reg16::DE);
+        a.ld(Reg8::C, Reg8::A);
+        a.alu(Alu::Add, Reg8::HLInd);
+        let fits = a.label("repeat_fits");
+        a.jr_cc(Cond::NC, fits);
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.cpl();
+        a.ld(Reg8::C, Reg8::A);
+        a.ld_n(Reg8::B, 1); // The last copy
+        a.ld_n(Reg8::A, 0xFF);
+        a.bind(fits);
+        // HL = the end of the result, and A its new length
+        a.push(StackReg::BC);
+        a.ld(Reg8::C, Reg8::HLInd);
+        a.ld_n(Reg8::B, 0);
+        a.ld(Reg8::HLInd, Reg8::A);
+        a.inc16(Reg16::HL);
+        a.add_hl(Reg16::BC);
+        a.pop(StackReg::BC);
+        a.push(StackReg::BC);
+        a.push(StackReg::DE);
+        a.ex_de_hl(); // DE = end of the result, HL = string
+        a.inc16(Reg16::HL);
+        a.ld_n(Reg8::B, 0);
+        let copied = a.label("repeat_copied");
+        a.ld(Reg8::A, Reg8::C);
+        a.or(Reg8::A);
+        a.jr_cc(Cond::Z, copied);
+        a.ldir();
+        a.bind(copied);
+        a.pop(StackReg::DE);
+        a.pop(StackReg::BC);
+        a.dec(Reg8::B);
+        a.jr(repeat_loop);
+        a.bind(done);
+        // Move the heap pointer past the result and push it
+        a.pop(StackReg::HL);
+        a.push(StackReg::HL);
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.ld_n(Reg8::D, 0);
+        a.inc16(Reg16::DE);
+        a.add_hl(Reg16::DE);
+        a.ld_to(l.heap_ptr(), Reg16::HL);
+        emit_heap_check(a, heap_check);
+        a.pop(StackReg::DE);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Print, |a| {
+        let done = a.label("print_done");
+        emit_vm_pop_de(a, l);
+        // Values >= 0x1000 are treated as string pointers
+        a.ld(Reg8::A, Reg8::D);
+        a.cp_n(0x10);
+        let number = a.label("print_number");
+        a.jr_cc(Cond::C, number);
+
+        // Print length-prefixed string
+        a.ex_de_hl();
+        a.call(print_text);
+        a.jr(done);
+
+        // Print as decimal, without leading zeros (numbers are < 0x1000)
+        a.bind(number);
+        a.ex_de_hl();
+        a.call(print_decimal);
+
+        a.bind(done);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Select, |a| {
+        // Without channels, output stays on the console whichever is
+        // selected
+        if options.channels().is_some() {
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.ld_a_to(l.channel());
+        }
+        emit_next(a, l, 2, main_loop);
+    });
+
+    handler(&mut a, Op::LoadLocal, |a| {
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::A, Reg8::HLInd); // A = local index
+        emit_local_addr(a, l);
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::D, Reg8::HLInd); // DE = value
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 2, main_loop);
+    });
+
+    handler(&mut a, Op::StoreLocal, |a| {
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::A, Reg8::HLInd); // A = local index
+        a.push(StackReg::AF);
+        emit_vm_pop_de(a, l); // DE = value
+        a.pop(StackReg::AF);
+        a.push(StackReg::DE);
+        emit_local_addr(a, l);
+        a.pop(StackReg::DE);
+        a.ld(Reg8::HLInd, Reg8::E);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::HLInd, Reg8::D);
+        emit_next(a, l, 2, main_loop);
+    });
+
+    handler(&mut a, Op::Add, |a| {
+        emit_vm_pop_operands(a, l);
+        a.add_hl(Reg16::DE); // HL = a + b
+        a.ex_de_hl();
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::AddChk, |a| {
+        emit_vm_pop_operands(a, l);
+        a.or(Reg8::A); // Clear carry
+        a.adc_hl(Reg16::DE); // HL = a + b, P/V set on signed overflow
+        a.jp_cc(Cond::PE, halt);
+        a.ex_de_hl();
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::SubChk, |a| {
+        emit_vm_pop_operands(a, l);
+        a.ex_de_hl(); // HL = a, DE = b
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE);
+        a.jp_cc(Cond::PE, halt);
+        a.ex_de_hl();
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::CmpLt, |a| {
+        // a < b means a - b < 0
+        emit_vm_pop_operands(a, l);
+        a.ex_de_hl(); // HL = a, DE = b
+        a.or(Reg8::A); // Clear carry
+        a.sbc_hl(Reg16::DE);
+        a.ld_nn(Reg16::DE, 0);
+        a.bit(7, Reg8::H);
+        let done = a.label("cmplt_done");
+        a.jr_cc(Cond::Z, done);
+        a.inc16(Reg16::DE); // DE = 1 (true)
+        a.bind(done);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::CmpLe, |a| {
+        // a <= b is the same as !(b < a)
+        emit_vm_pop_operands(a, l);
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE); // HL = b - a
+        a.ld_nn(Reg16::DE, 1); // Assume true
+        a.bit(7, Reg8::H);
+        let done = a.label("cmple_done");
+        a.jr_cc(Cond::Z, done);
+        a.dec16(Reg16::DE); // DE = 0 (false, because b < a)
+        a.bind(done);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::CmpEq, |a| {
+        emit_vm_pop_operands(a, l);
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE); // HL = b - a
+        a.ld_nn(Reg16::DE, 0);
+        a.ld(Reg8::A, Reg8::H);
+        a.or(Reg8::L);
+        let done = a.label("cmpeq_done");
+        a.jr_cc(Cond::NZ, done);
+        a.inc16(Reg16::DE); // DE = 1 (equal)
+        a.bind(done);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    // Long division, the quotient for / and the remainder for %
+    let push_quotient = a.label("push_quotient");
+    for op in [Op::Div, Op::Mod] {
+        handler(&mut a, op, |a| {
+            emit_vm_pop_operands(a, l);
+            a.ex_de_hl(); // HL = dividend, DE = divisor
+            a.call(divmod);
+            if op == Op::Div {
+                a.ex_de_hl();
+                a.jr(push_quotient);
+            } else {
+                a.bind(push_quotient);
+                emit_vm_push_de(a, l);
+                emit_next(a, l, 1, main_loop);
+            }
+        });
+    }
+
+    handler(&mut a, Op::Jump, |a| {
+        emit_operand_word(a);
+        a.ex_de_hl();
+        a.ld_to(l.vm_pc(), Reg16::HL);
+        a.jp(main_loop);
+    });
+
+    handler(&mut a, Op::JumpIfNot, |a| {
+        emit_operand_word(a);
+        a.push(StackReg::DE); // Save target
+        emit_vm_pop_de(a, l); // DE = condition
+        a.pop(StackReg::HL); // HL = target
+        a.ld(Reg8::A, Reg8::E);
+        a.or(Reg8::D);
+        let fall_through = a.label("jifnot_fall_through");
+        a.jr_cc(Cond::NZ, fall_through);
+        a.ld_to(l.vm_pc(), Reg16::HL);
+        a.jp(main_loop);
+        a.bind(fall_through);
+        emit_next(a, l, 3, main_loop);
+    });
+
+    handler(&mut a, Op::JumpIf, |a| {
+        emit_operand_word(a);
+        a.push(StackReg::DE); // Save target
+        emit_vm_pop_de(a, l); // DE = condition
+        a.pop(StackReg::HL); // HL = target
+        a.ld(Reg8::A, Reg8::E);
+        a.or(Reg8::D);
+        let fall_through = a.label("jif_fall_through");
+        a.jr_cc(Cond::Z, fall_through);
+        a.ld_to(l.vm_pc(), Reg16::HL);
+        a.jp(main_loop);
+        a.bind(fall_through);
+        emit_next(a, l, 3, main_loop);
+    });
+
+    handler(&mut a, Op::Inc, |a| {
+        emit_vm_pop_de(a, l);
+        a.inc16(Reg16::DE);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    // Dup pushes the word at HL, which Over and the array ops share
+    let (push_word, push_de) = (a.label("push_word"), a.label("push_de"));
+    handler(&mut a, Op::Dup, |a| {
+        // Peek and push
+        a.ld_from(Reg16::HL, l.vm_sp());
+        a.bind(push_word);
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::D, Reg8::HLInd);
+        a.bind(push_de);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Over, |a| {
+        // Push a copy of the word under the top
+        a.ld_from(Reg16::HL, l.vm_sp());
+        a.inc16(Reg16::HL);
+        a.inc16(Reg16::HL);
+        a.jr(push_word);
+    });
+
+    handler(&mut a, Op::ArrLen, |a| {
+        emit_vm_pop_de(a, l);
+        a.ex_de_hl();
+        a.jr(push_word);
+    });
+
+    handler(&mut a, Op::ArrGet, |a| {
+        // Arrays are [length][capacity][elements]; past the end reads as 0
+        emit_vm_pop_operands(a, l);
+        a.ld(Reg8::B, Reg8::H);
+        a.ld(Reg8::C, Reg8::L); // BC = idx
+        a.ex_de_hl();
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::D, Reg8::HLInd); // DE = length
+        for _ in 0..3 {
+            a.inc16(Reg16::HL);
+        }
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::H, Reg8::HLInd);
+        a.ld(Reg8::L, Reg8::A);
+        a.ex_de_hl(); // DE = elements
+        a.scf();
+        a.sbc_hl(Reg16::BC);
+        a.ex_de_hl();
+        let past = a.label("arrget_past");
+        a.jr_cc(Cond::C, past);
+        a.add_hl(Reg16::BC);
+        a.add_hl(Reg16::BC);
+        a.jr(push_word);
+        a.bind(past);
+        a.ld_nn(Reg16::DE, 0);
+        a.jr(push_de);
+    });
+
+    handler(&mut a, Op::Pop, |a| {
+        emit_vm_pop_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Call, |a| {
+        emit_operand_word(a);
+        a.push(StackReg::DE); // Save target
+        // Push return address (PC + 3) onto VM stack
+        a.ld_from(Reg16::HL, l.vm_pc());
+        a.ld_nn(Reg16::DE, 3);
+        a.add_hl(Reg16::DE);
+        a.ex_de_hl();
+        emit_vm_push_de(a, l);
+        // Push current frame pointer
+        a.ld_from(Reg16::DE, l.vm_fp());
+        emit_vm_push_de(a, l);
+        // Set PC to target
+        a.pop(StackReg::HL);
+        a.ld_to(l.vm_pc(), Reg16::HL);
+        a.jp(main_loop);
+    });
+
+    handler(&mut a, Op::EnterFrame, |a| {
+        // Stack before ENTER: [...args...] [ret_addr] [old_fp] <- SP
+        // We set FP = SP + 4 so that FP + 0 = last arg, FP + 2 = the one before, etc.
+        // The old_fp is at FP - 4, ret_addr is at FP - 2, and the operand's
+        // `my` slots go under them
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::C, Reg8::HLInd);
+        a.ld_n(Reg8::B, 0);
+        a.ld_from(Reg16::HL, l.vm_sp());
+        a.ld_nn(Reg16::DE, 4);
+        a.add_hl(Reg16::DE);
+        a.ld_to(l.vm_fp(), Reg16::HL);
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE);
+        a.sbc_hl(Reg16::BC);
+        a.sbc_hl(Reg16::BC);
+        a.ld_to(l.vm_sp(), Reg16::HL);
+        emit_next(a, l, 2, main_loop);
+    });
+
+    handler(&mut a, Op::LeaveFrame, |a| {
+        // Restore SP to FP - 4 (where old_fp and ret_addr are)
+        a.ld_from(Reg16::HL, l.vm_fp());
+        for _ in 0..4 {
+            a.dec16(Reg16::HL);
+        }
+        a.ld_to(l.vm_sp(), Reg16::HL);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    let ret = a.label("return");
+    handler(&mut a, Op::Return, |a| {
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::C, Reg8::HLInd); // C = arguments
+        a.ld_nn(Reg16::DE, 0);
+        a.jr(ret);
+    });
+
+    handler(&mut a, Op::ReturnVal, |a| {
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::C, Reg8::HLInd); // C = arguments
+        emit_vm_pop_de(a, l);
+        // Restore FP, then PC, from under the frame, drop the arguments
+        // and leave DE in their place
+        a.bind(ret);
+        a.push(StackReg::DE);
+        a.ld_from(Reg16::HL, l.vm_fp());
+        for _ in 0..4 {
+            a.dec16(Reg16::HL);
+        }
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::D, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld_to(l.vm_fp(), Reg16::DE);
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::D, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld_to(l.vm_pc(), Reg16::DE);
+        a.ld_n(Reg8::B, 0);
+        a.add_hl(Reg16::BC);
+        a.add_hl(Reg16::BC);
+        a.ld_to(l.vm_sp(), Reg16::HL);
+        a.pop(StackReg::DE);
+        emit_vm_push_de(a, l);
+        a.jp(main_loop);
+    });
+
+    handler(&mut a, Op::TailCall, |a| {
+        // Copy the new arguments over the frame's own, the last at FP, and
+        // drop the frame down to the return address and saved FP under them
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.ld_from(Reg16::HL, l.vm_fp());
+        a.ex_de_hl();
+        a.ld_from(Reg16::HL, l.vm_sp());
+        a.alu(Alu::Add, Reg8::A); // A = bytes of arguments
+        let copied = a.label("tailcall_copied");
+        a.jr_cc(Cond::Z, copied);
+        a.ld(Reg8::C, Reg8::A);
+        a.ld_n(Reg8::B, 0);
+        a.ldir();
+        a.bind(copied);
+        a.ld_from(Reg16::HL, l.vm_fp());
+        for _ in 0..4 {
+            a.dec16(Reg16::HL);
+        }
+        a.ld_to(l.vm_sp(), Reg16::HL);
+        emit_next(a, l, 2, main_loop);
+    });
+
+    handler(&mut a, Op::Native, |a| {
+        // Without machine code (operand 0) run the bytecode that follows
+        emit_operand_word(a);
+        a.ld(Reg8::A, Reg8::D);
+        a.or(Reg8::E);
+        let native = a.label("native_call");
+        a.jr_cc(Cond::NZ, native);
+        emit_next(a, l, 3, main_loop);
+        a.bind(native);
+        // Call it with the frame Call left, then return like ReturnVal,
+        // dropping the BC bytes of arguments it hands back
+        let back = a.label("native_back");
+        a.ld_label(Reg16::HL, back);
+        a.push(StackReg::HL);
+        a.ex_de_hl();
+        a.jp_hl();
+        a.bind(back);
+        a.push(StackReg::HL);
+        emit_vm_pop_de(a, l);
+        a.ld_to(l.vm_fp(), Reg16::DE);
+        emit_vm_pop_de(a, l);
+        a.ld_to(l.vm_pc(), Reg16::DE);
+        a.add_hl(Reg16::BC);
+        a.ld_to(l.vm_sp(), Reg16::HL);
+        a.pop(StackReg::DE);
+        emit_vm_push_de(a, l);
+        a.jp(main_loop);
+    });
+
+    handler(&mut a, Op::Not, |a| {
+        // If value == 0, push 1, else push 0
+        emit_vm_pop_de(a, l);
+        a.ld(Reg8::A, Reg8::E);
+        a.or(Reg8::D);
+        a.ld_nn(Reg16::DE, 1);
+        let done = a.label("not_done");
+        a.jr_cc(Cond::Z, done);
+        a.dec16(Reg16::DE);
+        a.bind(done);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::And, |a| {
+        // Pop two values, if both non-zero push 1, else push 0
+        emit_vm_pop_operands(a, l);
+        a.ld(Reg8::B, Reg8::H);
+        a.ld(Reg8::C, Reg8::L); // BC = second operand
+        a.ld(Reg8::A, Reg8::D);
+        a.or(Reg8::E);
+        a.ld_nn(Reg16::DE, 0); // Assume result is 0 (false)
+        let done = a.label("and_done");
+        a.jr_cc(Cond::Z, done);
+        a.ld(Reg8::A, Reg8::B);
+        a.or(Reg8::C);
+        a.jr_cc(Cond::Z, done);
+        a.inc16(Reg16::DE); // Both non-zero, result is 1
+        a.bind(done);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Or, |a| {
+        // Pop two values, if either non-zero push 1, else push 0
+        emit_vm_pop_operands(a, l);
+        a.ld(Reg8::B, Reg8::H);
+        a.ld(Reg8::C, Reg8::L); // BC = second operand
+        a.ld(Reg8::A, Reg8::D);
+        a.or(Reg8::E);
+        a.ld_nn(Reg16::DE, 1); // Assume result is 1 (true)
+        let done = a.label("or_done");
+        a.jr_cc(Cond::NZ, done);
+        a.ld(Reg8::A, Reg8::B);
+        a.or(Reg8::C);
+        a.jr_cc(Cond::NZ, done);
+        a.dec16(Reg16::DE); // Both zero, result is 0
+        a.bind(done);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Match, |a| {
+        // Stack: [subject_ptr] [pattern_ptr] (pattern on top), both
+        // length-prefixed. Substring search where '.' matches any char.
+        emit_vm_pop_operands(a, l); // DE = subject, HL = pattern
+        a.ld(Reg8::B, Reg8::HLInd); // B = pattern length
+        a.inc16(Reg16::HL);
+        a.ld_a_ind(Reg16::DE); // C = subject length
+        a.ld(Reg8::C, Reg8::A);
+        a.inc16(Reg16::DE);
+
+        let success = a.label("match_success");
+        let fail = a.label("match_fail");
+
+        // Outer loop: try matching at each position
+        let outer = a.here_label("match_outer");
+        a.push(StackReg::BC); // Save lengths
+        a.push(StackReg::HL); // Save pattern start
+        a.push(StackReg::DE); // Save current subject position
+        // Not enough characters left when C < B
+        a.ld(Reg8::A, Reg8::C);
+        a.alu(Alu::Cp, Reg8::B);
+        a.jr_cc(Cond::C, fail);
+
+        // Inner loop: compare characters
+        let inner = a.here_label("match_inner");
+        a.ld(Reg8::A, Reg8::B);
+        a.or(Reg8::A);
+        a.jr_cc(Cond::Z, success); // Pattern exhausted, match!
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.cp_n(b'.');
+        let next_char = a.label("match_next_char");
+        a.jr_cc(Cond::Z, next_char); // Wildcard matches any char
+        a.ex_de_hl();
+        a.alu(Alu::Cp, Reg8::HLInd); // Compare with subject char
+        a.ex_de_hl();
+        a.jr_cc(Cond::Z, next_char);
+
+        // Mismatch - try next position in subject
+        a.pop(StackReg::DE);
+        a.pop(StackReg::HL);
+        a.pop(StackReg::BC);
+        a.inc16(Reg16::DE);
+        a.dec(Reg8::C);
+        a.jr(outer);
+
+        a.bind(next_char);
+        a.inc16(Reg16::HL);
+        a.inc16(Reg16::DE);
+        a.dec(Reg8::B);
+        a.jr(inner);
+
+        let push_result = a.label("match_push");
+        a.bind(success);
+        a.ld_nn(Reg16::DE, 1);
+        a.jr(push_result);
+        a.bind(fail);
+        a.ld_nn(Reg16::DE, 0);
+        a.bind(push_result);
+        a.pop(StackReg::HL); // Clean up saved state
+        a.pop(StackReg::HL);
+        a.pop(StackReg::HL);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::InputChar, |a| {
+        emit_input_wait(a, options, console.as_ref(), task_save);
+        // Read one byte from the console and push it as a number
+        a.call(getc);
+        a.ld(Reg8::E, Reg8::A);
+        a.ld_n(Reg8::D, 0);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    handler(&mut a, Op::Input, |a| {
+        emit_input_wait(a, options, console.as_ref(), task_save);
+        // Build a length-prefixed string on the heap from the bytes up to
+        // CR/LF (the terminator is not stored)
+        a.ld_from(Reg16::HL, l.heap_ptr());
+        a.ld(Reg8::D, Reg8::H);
+        a.ld(Reg8::E, Reg8::L); // DE = string (length byte)
+        a.inc16(Reg16::HL);
+        a.ld_n(Reg8::B, 0); // B = length
+        let input_loop = a.here_label("input_loop");
+        let input_done = a.label("input_done");
+        a.call(getc);
+        a.cp_n(b'\r');
+        a.jr_cc(Cond::Z, input_done);
+        a.cp_n(b'\n');
+        a.jr_cc(Cond::Z, input_done);
+        a.ld(Reg8::HLInd, Reg8::A);
+        a.inc16(Reg16::HL);
+        a.inc(Reg8::B);
+        a.ld(Reg8::A, Reg8::B);
+        a.cp_n(0xFF); // Length byte limit
+        a.jr_cc(Cond::NZ, input_loop);
+        a.bind(input_done);
+        a.ld(Reg8::A, Reg8::B);
+        a.ld_ind_a(Reg16::DE); // Store length
+        a.ld_to(l.heap_ptr(), Reg16::HL); // Bump heap pointer past the string
+        emit_heap_check(a, heap_check);
+        emit_vm_push_de(a, l);
+        emit_next(a, l, 1, main_loop);
+    });
+
+    if !options.target.hosted() {
+        handler(&mut a, Op::Suspend, |a| {
+            // Resume past the Suspend with 1 on the stack, and stop without the
+            // shell
+            a.ld_nn(Reg16::DE, 1);
+            emit_vm_push_de(a, l);
+            a.ld_from(Reg16::HL, l.vm_pc());
+            a.inc16(Reg16::HL);
+            a.ld_to(l.vm_pc(), Reg16::HL);
+            let s = l.snapshot();
+            a.ld_to(s + 2, Reg16::HL);
+            for (at, reg) in [(s + 4, l.vm_sp()), (s + 6, l.vm_fp()), (s + 8, l.heap_ptr())] {
+                a.ld_from(Reg16::DE, reg);
+                a.ld_to(at, Reg16::DE);
+                a.add_hl(Reg16::DE);
+            }
+            a.ld_from(Reg16::DE, l.bytecode_org + 6);
+            a.add_hl(Reg16::DE);
+            a.ld_nn(Reg16::DE, SNAPSHOT_MAGIC);
+            a.add_hl(Reg16::DE);
+            a.ld_to(s + 10, Reg16::HL);
+            a.ld_to(s, Reg16::DE);
+            a.jp(exit);
+        });
+
+        // Ports belong to a hosted machine's OS
+        handler(&mut a, Op::PortOut, |a| {
+            // [port, value] to [value], where the port was
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::C, Reg8::HLInd);
+            a.out_c(Reg8::A);
+            a.ld(Reg8::HLInd, Reg8::A);
+            a.ld_to(l.vm_sp(), Reg16::HL);
+            a.inc16(Reg16::HL);
+            a.ld_n(Reg8::HLInd, 0);
+            emit_next(a, l, 1, main_loop);
+        });
+
+        handler(&mut a, Op::PortIn, |a| {
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::C, Reg8::HLInd);
+            a.in_c(Reg8::A);
+            a.ld(Reg8::HLInd, Reg8::A);
+            a.inc16(Reg16::HL);
+            a.ld_n(Reg8::HLInd, 0);
+            emit_next(a, l, 1, main_loop);
+        });
+
+        // So does its memory
+        handler(&mut a, Op::Peek, |a| {
+            // B = width, DE = address, HL = its slot's high byte
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::B, Reg8::HLInd);
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::E, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::D, Reg8::HLInd);
+            a.ex_de_hl();
+            a.ld(Reg8::C, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::A, Reg8::HLInd);
+            let word = a.label("peek_word");
+            a.djnz(word);
+            a.xor(Reg8::A);
+            a.bind(word);
+            a.ex_de_hl();
+            a.ld(Reg8::HLInd, Reg8::A);
+            a.dec16(Reg16::HL);
+            a.ld(Reg8::HLInd, Reg8::C);
+            emit_next(a, l, 2, main_loop);
+        });
+
+        handler(&mut a, Op::Poke, |a| {
+            // [addr, value] to [value], cut to a byte unless the width is 2
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::B, Reg8::HLInd);
+            emit_vm_pop_de(a, l);
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::H, Reg8::HLInd);
+            a.ld(Reg8::L, Reg8::A);
+            a.ld(Reg8::HLInd, Reg8::E);
+            let word = a.label("poke_word");
+            let done = a.label("poke_done");
+            a.djnz(word);
+            a.ld(Reg8::D, Reg8::B);
+            a.jr(done);
+            a.bind(word);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::HLInd, Reg8::D);
+            a.bind(done);
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::HLInd, Reg8::E);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::HLInd, Reg8::D);
+            emit_next(a, l, 2, main_loop);
+        });
+
+        handler(&mut a, Op::CheckPoke, |a| {
+            // BC = hi, DE = lo, HL = the address left on top
+            a.ld_from(Reg16::HL, l.vm_sp());
+            a.ld(Reg8::C, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::B, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::E, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::D, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld_to(l.vm_sp(), Reg16::HL);
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::H, Reg8::HLInd);
+            a.ld(Reg8::L, Reg8::A);
+            // Halt with VM_PC on the CheckPoke unless lo <= addr <= hi,
+            // unsigned
+            a.or(Reg8::A);
+            a.sbc_hl(Reg16::DE);
+            a.jp_cc(Cond::C, halt);
+            a.add_hl(Reg16::DE);
+            a.ex_de_hl();
+            a.ld(Reg8::H, Reg8::B);
+            a.ld(Reg8::L, Reg8::C);
+            a.or(Reg8::A);
+            a.sbc_hl(Reg16::DE);
+            a.jp_cc(Cond::C, halt);
+            emit_next(a, l, 1, main_loop);
+        });
+
+        // Tasks take turns at Yield, TaskEnd and input that hasn't arrived
+        handler(&mut a, Op::Yield, |a| {
+            a.ld_nn(Reg16::DE, 0);
+            emit_vm_push_de(a, l);
+            a.ld_from(Reg16::HL, l.vm_pc());
+            a.inc16(Reg16::HL);
+            a.ld_to(l.vm_pc(), Reg16::HL);
+            // Save VM_PC, VM_SP and VM_FP in the running task's slot
+            a.bind(task_save);
+            a.call(task_slot);
+            for reg in [l.vm_pc(), l.vm_sp(), l.vm_fp()] {
+                a.ld_from(Reg16::DE, reg);
+                a.ld(Reg8::HLInd, Reg8::E);
+                a.inc16(Reg16::HL);
+                a.ld(Reg8::HLInd, Reg8::D);
+                a.inc16(Reg16::HL);
+            }
+            // Take the registers of the next task with a VM_SP, which is
+            // the main program's at worst
+            a.bind(task_next);
+            a.ld_a_from(l.task());
+            a.inc(Reg8::A);
+            a.alu_n(Alu::And, MAX_TASKS as u8 - 1);
+            a.ld_a_to(l.task());
+            a.call(task_slot);
+            a.push(StackReg::HL);
+            a.inc16(Reg16::HL);
+            a.inc16(Reg16::HL);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.pop(StackReg::HL);
+            a.or(Reg8::A);
+            a.jr_cc(Cond::Z, task_next);
+            for reg in [l.vm_pc(), l.vm_sp(), l.vm_fp()] {
+                a.ld(Reg8::E, Reg8::HLInd);
+                a.inc16(Reg16::HL);
+                a.ld(Reg8::D, Reg8::HLInd);
+                a.inc16(Reg16::HL);
+                a.ld_to(reg, Reg16::DE);
+            }
+            a.jp(main_loop);
+
+            // HL = the running task's slot
+            a.bind(task_slot);
+            a.ld_a_from(l.task());
+            a.ld(Reg8::E, Reg8::A);
+            a.alu(Alu::Add, Reg8::A);
+            a.alu(Alu::Add, Reg8::E);
+            a.alu(Alu::Add, Reg8::A);
+            a.ld(Reg8::E, Reg8::A);
+            a.ld_n(Reg8::D, 0);
+            a.ld_nn(Reg16::HL, l.tasks());
+            a.add_hl(Reg16::DE);
+            a.ret();
+        });
+
+        handler(&mut a, Op::TaskEnd, |a| {
+            // Free the slot, whose VM_SP is its fourth byte
+            a.call(task_slot);
+            a.ld_nn(Reg16::DE, 3);
+            a.add_hl(Reg16::DE);
+            a.ld_n(Reg8::HLInd, 0);
+            a.jp(task_next);
+        });
+
+        handler(&mut a, Op::Spawn, |a| {
+            // C = the first free slot from 1, or 0 when none is
+            emit_operand_word(a);
+            a.push(StackReg::DE);
+            a.ld_nn(Reg16::HL, l.tasks() + 6 + 3);
+            a.ld_nn(Reg16::DE, 6);
+            a.ld_n(Reg8::C, 1);
+            let find = a.here_label("spawn_find");
+            let found = a.label("spawn_found");
+            let done = a.label("spawn_done");
+            a.ld(Reg8::A, Reg8::HLInd);
+            a.or(Reg8::A);
+            a.jr_cc(Cond::Z, found);
+            a.add_hl(Reg16::DE);
+            a.inc(Reg8::C);
+            a.ld(Reg8::A, Reg8::C);
+            a.cp_n(MAX_TASKS as u8);
+            a.jr_cc(Cond::NZ, find);
+            a.pop(StackReg::DE);
+            a.ld_n(Reg8::C, 0);
+            a.jr(done);
+            // Its VM_PC is the operand, and its stack TASK_STACK bytes
+            // taken from the heap
+            a.bind(found);
+            a.pop(StackReg::DE);
+            a.dec16(Reg16::HL);
+            a.dec16(Reg16::HL);
+            a.dec16(Reg16::HL);
+            a.ld(Reg8::HLInd, Reg8::E);
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::HLInd, Reg8::D);
+            a.inc16(Reg16::HL);
+            a.push(StackReg::HL);
+            a.ld_from(Reg16::HL, l.heap_ptr());
+            a.ld_nn(Reg16::DE, TASK_STACK);
+            a.add_hl(Reg16::DE);
+            a.ld_to(l.heap_ptr(), Reg16::HL);
+            emit_heap_check(a, heap_check);
+            a.ex_de_hl();
+            a.pop(StackReg::HL);
+            for _ in 0..2 {
+                a.ld(Reg8::HLInd, Reg8::E);
+                a.inc16(Reg16::HL);
+                a.ld(Reg8::HLInd, Reg8::D);
+                a.inc16(Reg16::HL);
+            }
+            a.bind(done);
+            a.ld(Reg8::E, Reg8::C);
+            a.ld_n(Reg8::D, 0);
+            emit_vm_push_de(a, l);
+            emit_next(a, l, 3, main_loop);
+        });
+
+        handler(&mut a, Op::Resumed, |a| {
+            a.ld_a_from(l.resumed());
+            a.ld(Reg8::E, Reg8::A);
+            a.ld_n(Reg8::D, 0);
+            emit_vm_push_de(a, l);
+            emit_next(a, l, 1, main_loop);
+        });
+    }
+
+    if options.timer {
+        handler(&mut a, Op::Ticks, |a| {
+            a.ld_from(Reg16::DE, l.ticks());
+            emit_vm_push_de(a, l);
+            emit_next(a, l, 1, main_loop);
+        });
+    }
+
+    // memstats() and, on a board target, the file natives: other natives
+    // halt with VM_PC on them
+    let files = !options.target.hosted();
+    if options.mem_stats || files {
+        let heap_base = heap;
+        handler(&mut a, Op::CallNative, |a| {
+            a.inc16(Reg16::HL);
+            a.ld(Reg8::A, Reg8::HLInd);
+            let file_natives = FILE_NATIVES.map(|native| a.label(&format!("file_{:?}", native).to_lowercase()));
+            if files {
+                for (native, label) in FILE_NATIVES.iter().zip(file_natives) {
+                    a.cp_n(*native as u8);
+                    a.jp_cc(Cond::Z, label);
+                }
+            }
+            if !options.mem_stats {
+                a.jp(halt);
+                emit_file_natives(a, l, file_natives, main_loop, halt, heap_check);
+                return;
+            }
+            a.cp_n(NativeFunc::MemStats as u8);
+            a.jp_cc(Cond::NZ, halt);
+            let (stack_text, heap_text) = (a.label("memstats_stack"), a.label("memstats_heap"));
+            // "stack: N heap: N", the bytes below VM_STACK and above the
+            // heap base that have been used
+            a.ld_label(Reg16::HL, stack_text);
+            a.call(print_text);
+            a.ld_from(Reg16::DE, l.stack_low());
+            a.ld_nn(Reg16::HL, l.vm_stack);
+            a.or(Reg8::A);
+            a.sbc_hl(Reg16::DE);
+            a.call(print_decimal);
+            a.ld_label(Reg16::HL, heap_text);
+            a.call(print_text);
+            a.ld_from(Reg16::HL, l.heap_ptr());
+            a.ld_nn(Reg16::DE, heap_base);
+            a.or(Reg8::A);
+            a.sbc_hl(Reg16::DE);
+            a.call(print_decimal);
+            a.ld_n(Reg8::A, b'\n');
+            console.emit_write(a, putc);
+            a.ld_nn(Reg16::DE, 0);
+            emit_vm_push_de(a, l);
+            emit_next(a, l, 2, main_loop);
+            for (label, text) in [(stack_text, "stack: "), (heap_text, " heap: ")] {
+                a.bind(label);
+                a.defb(&[text.len() as u8]);
+                a.defb(text.as_bytes());
+            }
+
+
+            if files {
+                emit_file_natives(a, l, file_natives, main_loop, halt, heap_check);
+            }
+        });
+    }
+
+    handler(&mut a, Op::Die, |a| {
+        // Print the message and a newline, straight to the error port when
+        // there is one, then halt with the VM PC left on the Die so the
+        // host can tell it from a normal exit
+        emit_vm_pop_de(a, l);
+        a.ex_de_hl();
+        match options.stderr_port() {
+            Some(port) => {
+                a.ld(Reg8::B, Reg8::HLInd);
+                a.inc(Reg8::B);
+                let next = a.label("die_next");
+                a.jr(next);
+                let byte = a.here_label("die_byte");
+                a.inc16(Reg16::HL);
+                a.ld(Reg8::A, Reg8::HLInd);
+                a.out_n(port);
+                a.bind(next);
+                a.djnz(byte);
+                a.ld_n(Reg8::A, b'\n');
+                a.out_n(port);
+            }
+            None => {
+                a.call(print_text);
+                a.ld_n(Reg8::A, b'\n');
+                console.emit_write(a, putc);
+            }
+        }
+        a.jp(halt);
+    });
+
+    handler(&mut a, Op::Printf, |a| {
+        // printf FORMAT, ARGS with %d, %s, %c and %%; any other conversion
+        // halts on the Printf
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::C, Reg8::HLInd); // C = arguments left
+        a.ld_from(Reg16::HL, l.vm_sp());
+        a.ld(Reg8::E, Reg8::C);
+        a.ld_n(Reg8::D, 0);
+        a.add_hl(Reg16::DE);
+        a.add_hl(Reg16::DE); // HL = the format's slot
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::D, Reg8::HLInd); // DE = format
+        a.inc16(Reg16::HL);
+        a.ld_to(l.vm_sp(), Reg16::HL); // Pop it all; the slots stay readable
+        a.dec16(Reg16::HL);
+        a.dec16(Reg16::HL);
+        a.dec16(Reg16::HL); // HL = the first argument's high byte
+        a.push(StackReg::HL);
+        a.ex_de_hl();
+        a.ld(Reg8::B, Reg8::HLInd);
+        a.inc16(Reg16::HL);
+        let done = a.label("printf_done");
+        let next = a.label("printf_next");
+        let write = a.label("printf_write");
+        let conversion = a.label("printf_conversion");
+        a.ld(Reg8::A, Reg8::B);
+        a.or(Reg8::A);
+        a.jp_cc(Cond::Z, done);
+        let format_loop = a.here_label("printf_loop");
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.cp_n(b'%');
+        a.jr_cc(Cond::Z, conversion);
+        a.bind(write);
+        console.emit_write(a, putc);
+        a.bind(next);
+        a.inc16(Reg16::HL);
+        a.djnz(format_loop);
+        a.jp(done);
+
+        a.bind(conversion);
+        // A % ending the format prints as written
+        let more = a.label("printf_more");
+        a.dec(Reg8::B);
+        a.jr_cc(Cond::NZ, more);
+        console.emit_write(a, putc);
+        a.jp(done);
+        a.bind(more);
+        a.inc16(Reg16::HL);
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.cp_n(b'%');
+        a.jr_cc(Cond::Z, write);
+        // DE = the next argument, or 0 once they run out
+        let none = a.label("printf_none");
+        a.ld_nn(Reg16::DE, 0);
+        a.inc(Reg8::C);
+        a.dec(Reg8::C);
+        a.jr_cc(Cond::Z, none);
+        a.dec(Reg8::C);
+        a.ex_sp_hl(); // HL = its high byte
+        a.ld(Reg8::D, Reg8::HLInd);
+        a.dec16(Reg16::HL);
+        a.ld(Reg8::E, Reg8::HLInd);
+        a.dec16(Reg16::HL);
+        a.ex_sp_hl();
+        a.bind(none);
+        let decimal = a.label("printf_decimal");
+        let string = a.label("printf_string");
+        a.cp_n(b'd');
+        a.jr_cc(Cond::Z, decimal);
+        a.cp_n(b's');
+        a.jr_cc(Cond::Z, string);
+        a.cp_n(b'c');
+        a.ld(Reg8::A, Reg8::E);
+        a.jr_cc(Cond::Z, write);
+        a.pop(StackReg::HL);
+        a.jp(halt);
+
+        // A number prints as %d
+        a.bind(string);
+        a.ld(Reg8::A, Reg8::D);
+        a.cp_n(0x10);
+        a.jr_cc(Cond::C, decimal);
+        a.push(StackReg::HL);
+        a.push(StackReg::BC);
+        a.ex_de_hl();
+        a.call(print_text);
+        a.pop(StackReg::BC);
+        a.pop(StackReg::HL);
+        a.jr(next);
+
+        a.bind(decimal);
+        a.push(StackReg::HL);
+        a.push(StackReg::BC);
+        a.ex_de_hl();
+        let positive = a.label("printf_positive");
+        a.bit(7, Reg8::H);
+        a.jr_cc(Cond::Z, positive);
+        a.ld_n(Reg8::A, b'-');
+        console.emit_write(a, putc);
+        a.xor(Reg8::A);
+        a.alu(Alu::Sub, Reg8::L);
+        a.ld(Reg8::L, Reg8::A);
+        a.ld_n(Reg8::A, 0);
+        a.alu(Alu::Sbc, Reg8::H);
+        a.ld(Reg8::H, Reg8::A);
+        a.bind(positive);
+        a.call(print_decimal);
+        a.pop(StackReg::BC);
+        a.pop(StackReg::HL);
+        a.jp(next);
+
+        a.bind(done);
+        a.pop(StackReg::HL);
+        emit_next(a, l, 2, main_loop);
+    });
 
     // Default: unknown opcode, just halt
-    code.push(POP_HL);
-    // Fall through to halt
+    a.bind(halt);
+    if options.shell {
+        emit_shell(&mut a, l, main_loop, getc, putc, console.as_ref());
+    }
+    a.bind(exit);
+    match options.target {
+        #[cfg(feature = "target-trs80")]
+        Target::Trs80 => a.jp_addr(TRS80_EXIT),
+        target if target.hosted() => {
+            // Back to BASIC
+            a.ld_from(Reg16::SP, l.saved_sp());
+            a.ret();
+        }
+        _ => {
+            a.di();
+            a.halt();
+        }
+    }
+
+    emit_getc(&mut a, getc, putc, options, console.as_ref());
+    console.emit_putc(&mut a, putc);
+
+    // Print the length-prefixed string at HL, using B
+    a.bind(print_text);
+    a.ld(Reg8::B, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::A);
+    a.ret_cc(Cond::Z);
+    let text_loop = a.here_label("print_text_loop");
+    a.ld(Reg8::A, Reg8::HLInd);
+    console.emit_write(&mut a, putc);
+    a.inc16(Reg16::HL);
+    a.djnz(text_loop);
+    a.ret();
+
+    // Print HL in decimal, without leading zeros, using BC and DE
+    a.bind(print_decimal);
+    emit_decimal(&mut a, "decimal", &[10000, 1000, 100, 10], |a| console.emit_write(a, putc));
+    a.ret();
+    if let Some(check) = heap_check {
+        // Called with HL = HEAP_PTR after it moved; past HEAP_LIMIT, halt on
+        // the instruction, keeping DE and HL
+        a.bind(check);
+        a.push(StackReg::DE);
+        a.ex_de_hl();
+        a.ld_from(Reg16::HL, l.heap_limit());
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE);
+        a.ex_de_hl();
+        a.pop(StackReg::DE);
+        a.ret_cc(Cond::NC);
+        a.jp(halt);
+    }
+    // A zero divisor stops on the / or % itself
+    native::emit_divmod(&mut a, divmod, "vm", |a| {
+        a.pop(StackReg::HL);
+        a.jp(halt);
+    });
+
+    a
+}
+
+/// Emit the check for a snapshot left by suspend(): if it is whole and for
+/// this program, restore the VM registers from it, use it up and set the
+/// resumed() flag
+fn emit_resume(a: &mut Asm, l: &Layout) {
+    let s = l.snapshot();
+    let fresh = a.label("resume_fresh");
+    a.xor(Reg8::A);
+    a.ld_a_to(l.resumed());
+    a.ld_from(Reg16::HL, l.bytecode_org + 6);
+    for at in [s, s + 2, s + 4, s + 6, s + 8] {
+        a.ld_from(Reg16::DE, at);
+        a.add_hl(Reg16::DE);
+    }
+    a.ld_from(Reg16::DE, s + 10);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NZ, fresh);
+    a.ld_from(Reg16::HL, s);
+    a.ld_nn(Reg16::DE, SNAPSHOT_MAGIC);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NZ, fresh);
+    // HL is zero here
+    a.ld_to(s, Reg16::HL);
+    for (at, reg) in [(s + 2, l.vm_pc()), (s + 4, l.vm_sp()), (s + 6, l.vm_fp()), (s + 8, l.heap_ptr())] {
+        a.ld_from(Reg16::HL, at);
+        a.ld_to(reg, Reg16::HL);
+    }
+    a.inc(Reg8::A);
+    a.ld_a_to(l.resumed());
+    a.bind(fresh);
+}
+
+/// Emit code setting DE to the CRC-16 (as `crc16`) of the BC bytes from HL,
+/// a bit at a time. It leaves HL past them and BC zero, and uses A.
+pub(crate) fn emit_crc16(a: &mut Asm) {
+    a.ld_nn(Reg16::DE, 0xFFFF);
+    let crc_loop = a.here_label("crc16_loop");
+    let crc_done = a.label("crc16_done");
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::C);
+    a.jr_cc(Cond::Z, crc_done);
+    a.ld(Reg8::A, Reg8::D);
+    a.alu(Alu::Xor, Reg8::HLInd);
+    a.ld(Reg8::D, Reg8::A);
+    a.push(StackReg::BC);
+    a.ld_n(Reg8::B, 8);
+    let bit_loop = a.here_label("crc16_bit");
+    let no_poly = a.label("crc16_no_poly");
+    a.rot(crate::asm::Rot::Sla, Reg8::E);
+    a.rot(crate::asm::Rot::Rl, Reg8::D);
+    a.jr_cc(Cond::NC, no_poly);
+    a.ld(Reg8::A, Reg8::D);
+    a.alu_n(Alu::Xor, 0x10);
+    a.ld(Reg8::D, Reg8::A);
+    a.ld(Reg8::A, Reg8::E);
+    a.alu_n(Alu::Xor, 0x21);
+    a.ld(Reg8::E, Reg8::A);
+    a.bind(no_poly);
+    a.djnz(bit_loop);
+    a.pop(StackReg::BC);
+    a.inc16(Reg16::HL);
+    a.dec16(Reg16::BC);
+    a.jr(crc_loop);
+    a.bind(crc_done);
+}
+
+/// Emit the self-test: check the header magic and the image's CRC against
+/// the self-test block and print its banner, or print what is wrong, set
+/// VM_PC to BOOT_FAILED and leave through `exit`
+fn emit_self_test(a: &mut Asm, l: &Layout, exit: Label, print: Label) {
+    let block = l.bytecode_org - SELF_TEST_BLOCK;
+    let (bad_magic, bad_crc, fail, done) = (
+        a.label("self_test_bad_magic"),
+        a.label("self_test_bad_crc"),
+        a.label("self_test_fail"),
+        a.label("self_test_done"),
+    );
+    let (magic, failed_message, magic_message, crc_message) = (
+        a.label("self_test_magic"),
+        a.label("self_test_failed_message"),
+        a.label("self_test_magic_message"),
+        a.label("self_test_crc_message"),
+    );
+
+    a.ld_nn(Reg16::HL, l.bytecode_org);
+    a.ld_label(Reg16::DE, magic);
+    a.ld_n(Reg8::B, 4);
+    let magic_loop = a.here_label("self_test_magic_loop");
+    a.ld_a_ind(Reg16::DE);
+    a.alu(Alu::Cp, Reg8::HLInd);
+    a.jr_cc(Cond::NZ, bad_magic);
+    a.inc16(Reg16::HL);
+    a.inc16(Reg16::DE);
+    a.djnz(magic_loop);
+
+    a.ld_nn(Reg16::HL, l.bytecode_org);
+    a.ld_from(Reg16::BC, block);
+    emit_crc16(a);
+    a.ld_from(Reg16::HL, block + 2);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NZ, bad_crc);
+
+    a.ld_nn(Reg16::HL, block + 4);
+    a.call(print);
+    a.jp(done);
+
+    a.bind(bad_magic);
+    a.ld_label(Reg16::HL, magic_message);
+    a.jr(fail);
+    a.bind(bad_crc);
+    a.ld_label(Reg16::HL, crc_message);
+    a.bind(fail);
+    a.push(StackReg::HL);
+    a.ld_label(Reg16::HL, failed_message);
+    a.call(print);
+    a.pop(StackReg::HL);
+    a.call(print);
+    a.ld_nn(Reg16::HL, BOOT_FAILED);
+    a.ld_to(l.vm_pc(), Reg16::HL);
+    a.jp(exit);
+
+    a.bind(magic);
+    a.defb(MAGIC);
+    let messages = [
+        (failed_message, "Self-test failed: "),
+        (magic_message, "no MicroPerl program\n"),
+        (crc_message, "bad program CRC\n"),
+    ];
+    for (label, text) in messages {
+        a.bind(label);
+        a.defb(&[text.len() as u8]);
+        a.defb(text.as_bytes());
+    }
+    a.bind(done);
+}
+
+/// Emit the boot check of the VM stack depth in the header: HEAP_LIMIT is
+/// that far under VM_STACK, and a program whose heap would start above it
+/// gets a message, VM_PC = BOOT_FAILED and `exit`
+fn emit_heap_limit(a: &mut Asm, l: &Layout, heap: u16, exit: Label, print: Label) {
+    let (limit, no_room, message, room) =
+        (a.label("heap_limit"), a.label("heap_no_room"), a.label("heap_no_room_message"), a.label("heap_room"));
+    const TEXT: &[u8] = b"Out of memory: no room for the VM stack\n";
+
+    // An unbounded program (0xFFFF) may use everything up to VM_STACK
+    a.ld_from(Reg16::DE, l.bytecode_org + 10);
+    a.ld(Reg8::A, Reg8::D);
+    a.alu(Alu::And, Reg8::E);
+    a.inc(Reg8::A);
+    a.ld_nn(Reg16::HL, l.vm_stack);
+    a.jr_cc(Cond::Z, limit);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::C, no_room);
+    a.bind(limit);
+    a.ld_to(l.heap_limit(), Reg16::HL);
+    a.ld_nn(Reg16::DE, heap);
+    a.or(Reg8::A);
+    a.sbc_hl(Reg16::DE);
+    a.jr_cc(Cond::NC, room);
+
+    a.bind(no_room);
+    a.ld_label(Reg16::HL, message);
+    a.call(print);
+    a.ld_nn(Reg16::HL, BOOT_FAILED);
+    a.ld_to(l.vm_pc(), Reg16::HL);
+    a.jp(exit);
+    a.bind(message);
+    a.defb(&[TEXT.len() as u8]);
+    a.defb(TEXT);
+    a.bind(room);
+}
 
-    // Patch halt address
-    let here = code.len() as u16;
-    code[halt_addr as usize - 2] = here as u8;
-    code[halt_addr as usize - 1] = (here >> 8) as u8;
+/// Emit the ROM shell, entered when the program halts: it reads a line,
+/// compiles it to bytecode on the heap and runs that, coming back here at
+/// its Halt. A line is `$name = EXPR` or an `EXPR` to print, where EXPR is
+/// decimal numbers and the program's variables joined by `+ - % < <= ==`,
+/// applied left to right. Anything else prints `?`.
+fn emit_shell(a: &mut Asm, l: &Layout, main_loop: Label, getc: Label, putc: Label, console: &dyn ConsoleBackend) {
+    let prompt = a.label("shell_prompt");
+    let error = a.label("shell_error");
+    let expr = a.label("shell_expr");
+    let term = a.label("shell_term");
+    let var = a.label("shell_var");
+    let skip = a.label("shell_skip");
+    let emit = a.label("shell_emit");
+    let is_name = a.label("shell_is_name");
+    let write = |a: &mut Asm, c: u8| {
+        a.ld_n(Reg8::A, c);
+        console.emit_write(a, putc);
+    };
+
+    // End the value the last line printed, and report a stop on anything
+    // but its Halt
+    a.ld_a_from(l.shell_newline());
+    a.or(Reg8::A);
+    let printed = a.label("shell_printed");
+    a.jr_cc(Cond::Z, printed);
+    write(a, b'\n');
+    a.bind(printed);
+    a.ld_from(Reg16::HL, l.vm_pc());
+    a.ld_from(Reg16::DE, l.vm_code());
+    a.add_hl(Reg16::DE);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(Op::Halt as u8);
+    a.jr_cc(Cond::Z, prompt);
+    a.bind(error);
+    write(a, b'?');
+    write(a, b'\n');
+
+    a.bind(prompt);
+    a.ld_nn(Reg16::SP, l.stack_top);
+    a.xor(Reg8::A);
+    a.ld_a_to(l.shell_newline());
+    a.ld_nn(Reg16::HL, l.vm_stack);
+    a.ld_to(l.vm_sp(), Reg16::HL);
+    a.ld_to(l.vm_fp(), Reg16::HL);
+    write(a, b'>');
+    write(a, b' ');
+
+    // Read a line onto the heap, echoing it; B = length
+    a.ld_from(Reg16::HL, l.heap_ptr());
+    a.ld_n(Reg8::B, 0);
+    let read = a.here_label("shell_read");
+    let got = a.label("shell_got");
+    let back = a.label("shell_back");
+    a.call(getc);
+    a.cp_n(b'\r');
+    a.jr_cc(Cond::Z, got);
+    a.cp_n(b'\n');
+    a.jr_cc(Cond::Z, got);
+    a.cp_n(0x08);
+    a.jr_cc(Cond::Z, back);
+    a.cp_n(0x7F);
+    a.jr_cc(Cond::Z, back);
+    a.ld(Reg8::C, Reg8::A);
+    a.ld(Reg8::A, Reg8::B);
+    a.cp_n(SHELL_LINE);
+    a.jr_cc(Cond::NC, read); // Full: drop it
+    a.ld(Reg8::A, Reg8::C);
+    a.ld(Reg8::HLInd, Reg8::A);
+    a.inc16(Reg16::HL);
+    a.inc(Reg8::B);
+    console.emit_write(a, putc);
+    a.jr(read);
+    a.bind(back);
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::A);
+    a.jr_cc(Cond::Z, read);
+    a.dec16(Reg16::HL);
+    a.dec(Reg8::B);
+    write(a, 0x08);
+    write(a, b' ');
+    write(a, 0x08);
+    a.jr(read);
+    a.bind(got);
+    a.ld_n(Reg8::HLInd, 0);
+    write(a, b'\n');
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::A);
+    a.jp_cc(Cond::Z, prompt);
+
+    // Compile it after the line: HL = source, DE = code, saved for the run
+    a.inc16(Reg16::HL);
+    a.ex_de_hl();
+    a.push(StackReg::DE);
+    a.ld_from(Reg16::HL, l.heap_ptr());
+    a.call(skip);
+    let print = a.label("shell_print");
+    let not_assign = a.label("shell_not_assign");
+    let run = a.label("shell_run");
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'$');
+    a.jr_cc(Cond::NZ, print);
+    a.push(StackReg::HL);
+    a.call(var);
+    a.ld(Reg8::C, Reg8::A);
+    a.call(skip);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'=');
+    a.jr_cc(Cond::NZ, not_assign);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'=');
+    a.jr_cc(Cond::Z, not_assign);
+    a.pop(StackReg::AF); // Drop the line start
+    a.push(StackReg::BC);
+    a.call(expr);
+    a.pop(StackReg::BC);
+    a.ld_n(Reg8::A, Op::StoreLocal as u8);
+    a.call(emit);
+    a.ld(Reg8::A, Reg8::C);
+    a.call(emit);
+    a.jr(run);
+    a.bind(not_assign);
+    a.pop(StackReg::HL);
+    a.bind(print);
+    a.call(expr);
+    a.ld_n(Reg8::A, Op::Print as u8);
+    a.call(emit);
+    a.ld_n(Reg8::A, 1);
+    a.ld_a_to(l.shell_newline());
+    a.bind(run);
+    a.ld_n(Reg8::A, Op::Halt as u8);
+    a.call(emit);
+    a.pop(StackReg::HL);
+    a.ld_to(l.vm_code(), Reg16::HL);
+    a.ld_nn(Reg16::HL, 0);
+    a.ld_to(l.vm_pc(), Reg16::HL);
+    a.jp(main_loop);
+
+    // Terms joined by operators up to the end of the line
+    a.bind(expr);
+    a.call(term);
+    let ops = a.here_label("shell_ops");
+    let op = a.label("shell_op");
+    let operand = a.label("shell_operand");
+    let not_lt = a.label("shell_not_lt");
+    a.call(skip);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.or(Reg8::A);
+    a.ret_cc(Cond::Z);
+    for (c, opcode) in [(b'+', Op::Add), (b'-', Op::SubChk), (b'%', Op::Mod)] {
+        a.ld_n(Reg8::C, opcode as u8);
+        a.cp_n(c);
+        a.jr_cc(Cond::Z, op);
+    }
+    a.cp_n(b'<');
+    a.jr_cc(Cond::NZ, not_lt);
+    a.ld_n(Reg8::C, Op::CmpLt as u8);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'=');
+    a.jr_cc(Cond::NZ, operand);
+    a.ld_n(Reg8::C, Op::CmpLe as u8);
+    a.jr(op);
+    a.bind(not_lt);
+    a.ld_n(Reg8::C, Op::CmpEq as u8);
+    a.cp_n(b'=');
+    a.jp_cc(Cond::NZ, error);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'=');
+    a.jp_cc(Cond::NZ, error);
+    a.bind(op);
+    a.inc16(Reg16::HL);
+    a.bind(operand);
+    a.push(StackReg::BC);
+    a.call(term);
+    a.pop(StackReg::BC);
+    a.ld(Reg8::A, Reg8::C);
+    a.call(emit);
+    a.jr(ops);
+
+    // A variable or a decimal number
+    a.bind(term);
+    a.call(skip);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'$');
+    let number = a.label("shell_number");
+    a.jr_cc(Cond::NZ, number);
+    a.call(var);
+    a.ld(Reg8::C, Reg8::A);
+    a.ld_n(Reg8::A, Op::LoadLocal as u8);
+    a.call(emit);
+    a.ld(Reg8::A, Reg8::C);
+    a.jr(emit);
+    a.bind(number);
+    a.alu_n(Alu::Sub, b'0');
+    a.cp_n(10);
+    a.jp_cc(Cond::NC, error);
+    a.push(StackReg::DE);
+    a.ld_nn(Reg16::BC, 0);
+    let digits = a.here_label("shell_digits");
+    let digits_done = a.label("shell_digits_done");
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.alu_n(Alu::Sub, b'0');
+    a.cp_n(10);
+    a.jr_cc(Cond::NC, digits_done);
+    a.inc16(Reg16::HL);
+    a.push(StackReg::HL);
+    // BC = BC * 10 + A
+    a.ld(Reg8::H, Reg8::B);
+    a.ld(Reg8::L, Reg8::C);
+    a.add_hl(Reg16::HL);
+    a.ld(Reg8::B, Reg8::H);
+    a.ld(Reg8::C, Reg8::L);
+    a.add_hl(Reg16::HL);
+    a.add_hl(Reg16::HL);
+    a.add_hl(Reg16::BC);
+    a.ld(Reg8::C, Reg8::A);
+    a.ld_n(Reg8::B, 0);
+    a.add_hl(Reg16::BC);
+    a.ld(Reg8::B, Reg8::H);
+    a.ld(Reg8::C, Reg8::L);
+    a.pop(StackReg::HL);
+    a.jr(digits);
+    a.bind(digits_done);
+    a.pop(StackReg::DE);
+    a.ld_n(Reg8::A, Op::Push as u8);
+    a.call(emit);
+    a.ld(Reg8::A, Reg8::C);
+    a.call(emit);
+    a.ld(Reg8::A, Reg8::B);
+    a.jr(emit);
+
+    // HL at `$name`: A = the variable's slot, HL past the name
+    a.bind(var);
+    a.inc16(Reg16::HL);
+    a.push(StackReg::DE);
+    a.ld_from(Reg16::DE, l.shell_vars());
+    let entry = a.here_label("shell_var_entry");
+    let mismatch = a.label("shell_var_mismatch");
+    a.ld_a_ind(Reg16::DE);
+    a.or(Reg8::A);
+    a.jp_cc(Cond::Z, error);
+    a.ld(Reg8::B, Reg8::A);
+    a.inc16(Reg16::DE);
+    a.push(StackReg::HL);
+    let compare = a.here_label("shell_var_compare");
+    a.ld_a_ind(Reg16::DE);
+    a.alu(Alu::Cp, Reg8::HLInd);
+    a.jr_cc(Cond::NZ, mismatch);
+    a.inc16(Reg16::DE);
+    a.inc16(Reg16::HL);
+    a.djnz(compare);
+    // The name in the line must end here too
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.call(is_name);
+    a.jr_cc(Cond::C, mismatch);
+    a.ld_a_ind(Reg16::DE);
+    a.pop(StackReg::BC);
+    a.pop(StackReg::DE);
+    a.ret();
+    // Skip the rest of the name (B bytes) and the slot
+    a.bind(mismatch);
+    a.ex_de_hl();
+    a.ld(Reg8::C, Reg8::B);
+    a.ld_n(Reg8::B, 0);
+    a.add_hl(Reg16::BC);
+    a.inc16(Reg16::HL);
+    a.ex_de_hl();
+    a.pop(StackReg::HL);
+    a.jr(entry);
+
+    // Carry set if A can be part of a name
+    a.bind(is_name);
+    a.cp_n(b'_');
+    a.scf();
+    a.ret_cc(Cond::Z);
+    let not_name = a.label("shell_not_name");
+    for (low, high) in [(b'0', b'9'), (b'A', b'Z'), (b'a', b'z')] {
+        a.cp_n(low);
+        a.jr_cc(Cond::C, not_name);
+        a.cp_n(high + 1);
+        a.ret_cc(Cond::C);
+    }
+    a.bind(not_name);
+    a.or(Reg8::A);
+    a.ret();
+
+    a.bind(skip);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b' ');
+    a.ret_cc(Cond::NZ);
+    a.inc16(Reg16::HL);
+    a.jr(skip);
+
+    a.bind(emit);
+    a.ld_ind_a(Reg16::DE);
+    a.inc16(Reg16::DE);
+    a.ret();
+}
+
+/// Emit one dispatch entry: compare A against the opcode and run `body` on a
+/// match, otherwise fall through to the next entry
+fn handler(a: &mut Asm, op: Op, body: impl FnOnce(&mut Asm)) {
+    let next = a.label(&format!("not_{:?}", op).to_lowercase());
+    a.cp_n(op as u8);
+    a.jp_cc(Cond::NZ, next);
+    body(a);
+    a.bind(next);
+}
+
+/// Emit the IM1 interrupt handler: read the received byte and append it to
+/// the ring buffer, dropping it if the buffer is full
+fn emit_rx_isr(a: &mut Asm, l: &Layout, port: u8) {
+    a.push(StackReg::AF);
+    a.push(StackReg::HL);
+    a.push(StackReg::BC);
+    a.in_n(port);
+    a.ld(Reg8::C, Reg8::A); // C = received byte
+    a.ld_a_from(l.rx_head());
+    a.ld(Reg8::L, Reg8::A);
+    a.inc(Reg8::A);
+    a.ld(Reg8::B, Reg8::A); // B = next head
+    a.ld_a_from(l.rx_tail());
+    a.alu(Alu::Cp, Reg8::B);
+    let full = a.label("isr_full");
+    a.jr_cc(Cond::Z, full);
+    a.ld_n(Reg8::H, (l.rx_buf >> 8) as u8);
+    a.ld(Reg8::HLInd, Reg8::C);
+    a.ld(Reg8::A, Reg8::B);
+    a.ld_a_to(l.rx_head());
+    a.bind(full);
+    a.pop(StackReg::BC);
+    a.pop(StackReg::HL);
+    a.pop(StackReg::AF);
+    a.ei();
+    a.reti();
+}
+
+/// Emit the getc routine: wait for a console byte and return it in A
+fn emit_getc(a: &mut Asm, getc: Label, putc: Label, options: &RomOptions, console: &dyn ConsoleBackend) {
+    let l = &options.target.layout();
+    a.bind(getc);
+    if options.irq_input {
+        // Read from the ring buffer filled by the ISR (preserves HL, BC)
+        a.push(StackReg::HL);
+        a.push(StackReg::BC);
+        let wait = a.here_label("getc_wait");
+        a.ld_a_from(l.rx_head());
+        a.ld(Reg8::B, Reg8::A);
+        a.ld_a_from(l.rx_tail());
+        a.alu(Alu::Cp, Reg8::B);
+        a.jr_cc(Cond::Z, wait);
+        a.ld(Reg8::L, Reg8::A);
+        a.ld_n(Reg8::H, (l.rx_buf >> 8) as u8);
+        a.inc(Reg8::A);
+        a.ld_a_to(l.rx_tail());
+        a.ld(Reg8::A, Reg8::HLInd);
+        a.pop(StackReg::BC);
+        a.pop(StackReg::HL);
+        a.ret();
+    } else {
+        console.emit_getc(a, getc, putc);
+    }
+}
 
-    // HALT handler
-    code.push(POP_HL); // Clean up stack
-    code.push(HALT);
+/// Emit code that runs the other tasks until a console byte is waiting,
+/// leaving VM_PC on the input instruction to try again. A hosted machine's
+/// OS waits for its keyboard itself.
+fn emit_input_wait(a: &mut Asm, options: &RomOptions, console: &dyn ConsoleBackend, task_save: Label) {
+    let l = &options.target.layout();
+    if options.target.hosted() {
+        return;
+    }
+    if options.irq_input {
+        a.ld_a_from(l.rx_head());
+        a.ld(Reg8::B, Reg8::A);
+        a.ld_a_from(l.rx_tail());
+        a.alu(Alu::Cp, Reg8::B);
+        a.jp_cc(Cond::Z, task_save);
+    } else if let Some((status, ready)) = console.rx_ready() {
+        a.in_n(status);
+        a.alu_n(Alu::And, ready);
+        a.jp_cc(Cond::Z, task_save);
+    }
+}
 
-    code
+/// Emit code to load the 16-bit operand following the opcode at HL into DE
+fn emit_operand_word(a: &mut Asm) {
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::E, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::D, Reg8::HLInd);
+}
+
+/// Emit code to compute HL = FP + DE * 2 (address of local DE)
+fn emit_local_addr(a: &mut Asm, l: &Layout) {
+    a.ld(Reg8::E, Reg8::A);
+    a.rlca();
+    a.alu(Alu::Sbc, Reg8::A);
+    a.ld(Reg8::D, Reg8::A); // DE = signed index
+    a.ex_de_hl();
+    a.add_hl(Reg16::HL); // * 2
+    a.ex_de_hl();
+    a.ld_from(Reg16::HL, l.vm_fp());
+    a.add_hl(Reg16::DE);
 }
 
 /// Emit code to push DE onto VM stack
-fn emit_vm_push_de(code: &mut Vec<u8>, vm_sp_addr: u16) {
-    // LD HL,(vm_sp)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
-    // DEC HL; LD (HL),D
-    code.push(DEC_HL);
-    code.push(LD_HL_D);
-    // DEC HL; LD (HL),E
-    code.push(DEC_HL);
-    code.push(LD_HL_E);
-    // LD (vm_sp),HL
-    code.push(LD_NN_HL);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
+/// Check HEAP_PTR, just moved to HL, against HEAP_LIMIT where the layout
+/// has one
+fn emit_heap_check(a: &mut Asm, heap_check: Option<Label>) {
+    if let Some(check) = heap_check {
+        a.call(check);
+    }
+}
+
+fn emit_vm_push_de(a: &mut Asm, l: &Layout) {
+    a.ld_from(Reg16::HL, l.vm_sp());
+    a.dec16(Reg16::HL);
+    a.ld(Reg8::HLInd, Reg8::D);
+    a.dec16(Reg16::HL);
+    a.ld(Reg8::HLInd, Reg8::E);
+    a.ld_to(l.vm_sp(), Reg16::HL);
 }
 
 /// Emit code to pop from VM stack into DE
-fn emit_vm_pop_de(code: &mut Vec<u8>, vm_sp_addr: u16) {
-    // LD HL,(vm_sp)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
-    // LD E,(HL); INC HL
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    // LD D,(HL); INC HL
-    code.push(LD_D_HL);
-    code.push(INC_HL);
-    // LD (vm_sp),HL
-    code.push(LD_NN_HL);
-    code.push(vm_sp_addr as u8);
-    code.push((vm_sp_addr >> 8) as u8);
+fn emit_vm_pop_de(a: &mut Asm, l: &Layout) {
+    a.ld_from(Reg16::HL, l.vm_sp());
+    a.ld(Reg8::E, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::D, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.ld_to(l.vm_sp(), Reg16::HL);
 }
 
-/// Emit code to advance PC by n bytes
-fn emit_advance_pc(code: &mut Vec<u8>, vm_pc_addr: u16, n: u8) {
-    // LD HL,(vm_pc)
-    code.push(LD_HL_NN_IND);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
-    // LD DE,n
-    code.push(LD_DE_NN);
-    code.push(n);
-    code.push(0);
-    // ADD HL,DE
-    code.push(ADD_HL_DE);
-    // LD (vm_pc),HL
-    code.push(LD_NN_HL);
-    code.push(vm_pc_addr as u8);
-    code.push((vm_pc_addr >> 8) as u8);
+/// Emit code to pop a binary operator's operands: HL = b (top), DE = a
+fn emit_vm_pop_operands(a: &mut Asm, l: &Layout) {
+    emit_vm_pop_de(a, l);
+    a.push(StackReg::DE);
+    emit_vm_pop_de(a, l);
+    a.pop(StackReg::HL);
+}
+
+/// The natives the runtime answers over the storage ports, in the order
+/// `emit_file_natives` takes their labels
+const FILE_NATIVES: [NativeFunc; 5] = [NativeFunc::Open, NativeFunc::Close, NativeFunc::Read, NativeFunc::Write, NativeFunc::Eof];
+
+/// Emit open, close, readline, write and eof at `labels`, each entered with
+/// its arguments on the VM stack. They send a command to the storage device
+/// as `storage` describes and push what the VM's do: open a handle and
+/// readline a line, 0 if they fail, close and write whether they worked,
+/// and eof true unless the device says otherwise. A bad open mode halts.
+fn emit_file_natives(a: &mut Asm, l: &Layout, labels: [Label; 5], main_loop: Label, halt: Label, heap_check: Option<Label>) {
+    let [open, close, read, write, eof] = labels;
+    let send = a.label("file_send");
+    let ok = a.label("file_ok");
+    let push = a.label("file_push");
+
+    // The mode, then the name
+    a.bind(open);
+    emit_vm_pop_de(a, l);
+    let mode_string = a.label("file_mode_string");
+    a.ld(Reg8::A, Reg8::D);
+    a.cp_n(0x10);
+    a.jp_cc(Cond::C, halt);
+    a.jr_cc(Cond::NZ, mode_string);
+    a.ld(Reg8::A, Reg8::E);
+    a.or(Reg8::A);
+    a.jp_cc(Cond::Z, halt);
+    a.bind(mode_string);
+    a.ex_de_hl();
+    a.ld(Reg8::B, Reg8::HLInd);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    let (one, mode) = (a.label("file_mode_one"), a.label("file_mode"));
+    a.dec(Reg8::B);
+    a.jr_cc(Cond::Z, one);
+    // ">>" appends
+    a.dec(Reg8::B);
+    a.jp_cc(Cond::NZ, halt);
+    a.cp_n(b'>');
+    a.jp_cc(Cond::NZ, halt);
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.cp_n(b'>');
+    a.jp_cc(Cond::NZ, halt);
+    a.ld_n(Reg8::A, b'a');
+    a.jr(mode);
+    a.bind(one);
+    for (symbol, code) in [(b'<', b'r'), (b'>', b'w')] {
+        let next = a.label("file_mode_next");
+        a.cp_n(symbol);
+        a.jr_cc(Cond::NZ, next);
+        a.ld_n(Reg8::A, code);
+        a.jr(mode);
+        a.bind(next);
+    }
+    for code in [b'r', b'w'] {
+        a.cp_n(code);
+        a.jr_cc(Cond::Z, mode);
+    }
+    a.cp_n(b'a');
+    a.jp_cc(Cond::NZ, halt);
+    a.bind(mode);
+    a.out_n(storage::PORT_FILE_DATA);
+    emit_vm_pop_de(a, l);
+    a.call(send);
+    a.ld_n(Reg8::A, storage::OPEN);
+    a.out_n(storage::PORT_FILE_CMD);
+    // The handle, if there is one
+    a.in_n(storage::PORT_FILE_CMD);
+    a.ld_nn(Reg16::DE, 0);
+    a.cp_n(storage::STATUS_READY);
+    a.jp_cc(Cond::NZ, push);
+    a.in_n(storage::PORT_FILE_DATA);
+    a.ld(Reg8::E, Reg8::A);
+    a.jp(push);
+
+    a.bind(close);
+    emit_vm_pop_de(a, l);
+    a.ld(Reg8::A, Reg8::E);
+    a.out_n(storage::PORT_FILE_DATA);
+    a.ld_n(Reg8::A, storage::CLOSE);
+    a.jp(ok);
+
+    // The handle, then the text
+    a.bind(write);
+    emit_vm_pop_de(a, l);
+    a.push(StackReg::DE);
+    emit_vm_pop_de(a, l);
+    a.ld(Reg8::A, Reg8::E);
+    a.out_n(storage::PORT_FILE_DATA);
+    a.pop(StackReg::DE);
+    a.call(send);
+    a.ld_n(Reg8::A, storage::WRITE);
+    a.jp(ok);
+
+    a.bind(eof);
+    emit_vm_pop_de(a, l);
+    a.ld(Reg8::A, Reg8::E);
+    a.out_n(storage::PORT_FILE_DATA);
+    a.ld_n(Reg8::A, storage::EOF);
+    a.out_n(storage::PORT_FILE_CMD);
+    a.in_n(storage::PORT_FILE_CMD);
+    a.ld_nn(Reg16::DE, 1);
+    a.cp_n(storage::STATUS_READY);
+    a.jp_cc(Cond::NZ, push);
+    a.in_n(storage::PORT_FILE_DATA);
+    a.or(Reg8::A);
+    a.jp_cc(Cond::NZ, push);
+    a.dec(Reg8::E);
+    a.jp(push);
+
+    // The line goes on the heap as a string, like Input's
+    a.bind(read);
+    emit_vm_pop_de(a, l);
+    a.ld(Reg8::A, Reg8::E);
+    a.out_n(storage::PORT_FILE_DATA);
+    a.ld_n(Reg8::A, storage::READ);
+    a.out_n(storage::PORT_FILE_CMD);
+    a.in_n(storage::PORT_FILE_CMD);
+    a.ld_nn(Reg16::DE, 0);
+    a.alu_n(Alu::And, storage::STATUS_FAILED);
+    a.jr_cc(Cond::NZ, push);
+    a.ld_from(Reg16::HL, l.heap_ptr());
+    a.ld(Reg8::D, Reg8::H);
+    a.ld(Reg8::E, Reg8::L);
+    a.inc16(Reg16::HL);
+    a.ld_n(Reg8::B, 0);
+    let read_loop = a.here_label("file_read_loop");
+    let read_done = a.label("file_read_done");
+    a.in_n(storage::PORT_FILE_CMD);
+    a.alu_n(Alu::And, storage::STATUS_READY);
+    a.jr_cc(Cond::Z, read_done);
+    a.ld(Reg8::A, Reg8::B);
+    a.cp_n(0xFF); // Length byte limit
+    a.jr_cc(Cond::Z, read_done);
+    a.in_n(storage::PORT_FILE_DATA);
+    a.ld(Reg8::HLInd, Reg8::A);
+    a.inc16(Reg16::HL);
+    a.inc(Reg8::B);
+    a.jr(read_loop);
+    a.bind(read_done);
+    a.ld(Reg8::A, Reg8::B);
+    a.ld_ind_a(Reg16::DE);
+    a.ld_to(l.heap_ptr(), Reg16::HL);
+    emit_heap_check(a, heap_check);
+    a.jr(push);
+
+    // Run command A and push whether it worked
+    a.bind(ok);
+    a.out_n(storage::PORT_FILE_CMD);
+    a.in_n(storage::PORT_FILE_CMD);
+    a.ld_nn(Reg16::DE, 0);
+    a.alu_n(Alu::And, storage::STATUS_FAILED);
+    a.jr_cc(Cond::NZ, push);
+    a.inc(Reg8::E);
+    a.bind(push);
+    emit_vm_push_de(a, l);
+    emit_next(a, l, 2, main_loop);
+
+    // Send the text of the value in DE as argument bytes
+    a.bind(send);
+    let number = a.label("file_send_number");
+    a.ld(Reg8::A, Reg8::D);
+    a.cp_n(0x10);
+    a.jr_cc(Cond::C, number);
+    a.ex_de_hl();
+    a.ld(Reg8::B, Reg8::HLInd);
+    a.ld(Reg8::A, Reg8::B);
+    a.or(Reg8::A);
+    a.ret_cc(Cond::Z);
+    let send_loop = a.here_label("file_send_loop");
+    a.inc16(Reg16::HL);
+    a.ld(Reg8::A, Reg8::HLInd);
+    a.out_n(storage::PORT_FILE_DATA);
+    a.djnz(send_loop);
+    a.ret();
+    a.bind(number);
+    a.ex_de_hl();
+    emit_decimal(a, "file_send", &[1000, 100, 10], |a| a.out_n(storage::PORT_FILE_DATA));
+    a.ret();
+}
+
+/// Emit HL in decimal without leading zeros: a digit for each of `powers`
+/// then the units, each passed to `write` in A. Uses B, C, DE and HL.
+fn emit_decimal(a: &mut Asm, name: &str, powers: &[u16], write: impl Fn(&mut Asm)) {
+    a.ld_n(Reg8::C, 0); // Nonzero once a digit is written
+    for &power in powers {
+        a.ld_nn(Reg16::DE, power);
+        a.ld_n(Reg8::B, b'0' - 1);
+        let count = a.here_label(&format!("{}_count", name));
+        a.inc(Reg8::B);
+        a.or(Reg8::A);
+        a.sbc_hl(Reg16::DE);
+        a.jr_cc(Cond::NC, count);
+        a.add_hl(Reg16::DE); // Restore remainder
+        let digit = a.label(&format!("{}_digit", name));
+        let skip = a.label(&format!("{}_skip", name));
+        a.ld(Reg8::A, Reg8::B);
+        a.cp_n(b'0');
+        a.jr_cc(Cond::NZ, digit);
+        a.ld(Reg8::A, Reg8::C);
+        a.or(Reg8::A);
+        a.jr_cc(Cond::Z, skip);
+        a.ld(Reg8::A, Reg8::B);
+        a.bind(digit);
+        a.ld(Reg8::C, Reg8::A);
+        write(a);
+        a.bind(skip);
+    }
+    a.ld(Reg8::A, Reg8::L);
+    a.alu_n(Alu::Add, b'0');
+    write(a);
+}
+
+/// Emit code to advance PC by n bytes and continue with the next
+/// instruction. Instructions are at most 3 bytes, for which an INC each is
+/// shorter and faster than adding
+fn emit_next(a: &mut Asm, l: &Layout, n: u8, main_loop: Label) {
+    a.ld_from(Reg16::HL, l.vm_pc());
+    for _ in 0..n {
+        a.inc16(Reg16::HL);
+    }
+    a.ld_to(l.vm_pc(), Reg16::HL);
+    a.jp(main_loop);
+}
+
+#[cfg(all(test, feature = "host-vm"))]
+mod tests {
+    use super::*;
+    #[cfg(feature = "target-cpc")]
+    use crate::backend::CPC_TXT_OUTPUT;
+    #[cfg(feature = "target-ti83")]
+    use crate::backend::{TI_BCALL, TI_NEWLINE, TI_PUTC};
+    #[cfg(feature = "target-trs80")]
+    use crate::backend::TRS80_DSP;
+    #[cfg(feature = "target-spectrum")]
+    use crate::backend::{ZX_CHAN_OPEN, ZX_PRINT};
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::z80emu::{Exit, Io, Machine};
+    #[cfg(any(feature = "target-spectrum", feature = "target-cpc", feature = "target-trs80", feature = "target-ti83"))]
+    use crate::z80emu::Console;
+
+    fn compile(code: &str) -> Module {
+        let mut lexer = Lexer::new(code);
+        Compiler::new().compile(&Parser::new(lexer.tokenize()).parse().unwrap()).unwrap()
+    }
+
+    /// Serial chip at 0x80/0x81 that is only ready to transmit on every
+    /// other status read
+    struct Uart {
+        tx_ready: u8,
+        busy: bool,
+        control: Vec<u8>,
+        sent: Vec<u8>,
+    }
+
+    impl Io for Uart {
+        fn input(&mut self, port: u8) -> u8 {
+            assert_eq!(port, 0x80);
+            self.busy = !self.busy;
+            if self.busy { 0 } else { self.tx_ready }
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            match port {
+                0x80 => self.control.push(value),
+                0x81 => self.sent.push(value),
+                _ => panic!("write to port 0x{:02X}", port),
+            }
+        }
+    }
+
+    fn run_rc2014(target: Target, tx_ready: u8) -> Uart {
+        let options = RomOptions { target, ..RomOptions::default() };
+        let rom = generate_rom(&compile("print \"hi\\n\";"), &options);
+        let uart = Uart { tx_ready, busy: false, control: Vec::new(), sent: Vec::new() };
+        let mut machine = Machine::new(&rom, uart);
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.read16(RC2014.vm_sp()), RC2014.vm_stack);
+        machine.io
+    }
+
+    #[test]
+    fn test_coverage_counters() {
+        let program = Parser::new(Lexer::new("my $i = 0;\nwhile ($i < 300) {\n    $i++;\n}\n").tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_coverage(true);
+        let module = compiler.compile(&program).unwrap();
+        let options = RomOptions { coverage: true, ..RomOptions::default() };
+        let mut machine = Machine::new(&generate_rom(&module, &options), crate::z80emu::Console::scripted(b""));
+        // RAM is not cleared on real hardware
+        let counters = RETROSHIELD.counters();
+        for i in 0..2 * COUNTERS as u16 {
+            machine.write(counters + i, 0xA5);
+        }
+        assert_eq!(machine.run(Some(10_000_000)), Exit::Halted);
+        assert_eq!(machine.read16(counters), 1);
+        assert_eq!(machine.read16(counters + 2), 300);
+        assert_eq!(machine.read16(counters + 4), 0);
+        assert_eq!(machine.read16(RETROSHIELD.heap_ptr()), RETROSHIELD.heap_base + 2 * COUNTERS as u16);
+    }
+
+    #[test]
+    fn test_bounds_check() {
+        // A two-element array at the heap base, indexed at 1 and then 2
+        let arr = RETROSHIELD.heap_base;
+        let mut module = Module::new();
+        for idx in [1, 2] {
+            module.emit_word(Op::Push, arr);
+            module.emit_word(Op::Push, idx);
+            module.emit(Op::CheckIdx);
+            module.emit(Op::Pop);
+            module.emit(Op::Pop);
+        }
+        module.emit(Op::Halt);
+        let options = RomOptions { bounds_check: true, ..RomOptions::default() };
+        let mut machine = Machine::new(&generate_rom(&module, &options), crate::z80emu::Console::scripted(b""));
+        machine.write(arr, 2);
+        machine.write(arr + 1, 0);
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        // Stopped on the second CheckIdx, with its operands still there
+        assert_eq!(machine.read16(VM_PC), 9 + 6);
+        assert_eq!(machine.read16(RETROSHIELD.vm_sp()), RETROSHIELD.vm_stack - 4);
+
+        // Only built in when asked for
+        assert!(runtime_size(&options) > runtime_size(&RomOptions::default()));
+    }
+
+    #[test]
+    fn test_args_block() {
+        let block = args_block(0x3020, &["ab".to_string(), String::new()]).unwrap();
+        assert_eq!(block, vec![0x41, 0x47, 2, 0, 2, 0, 0x28, 0x30, 0x2C, 0x30, 0x2F, 0x30, 2, b'a', b'b', 0]);
+        assert_eq!(args_block(0x3020, &[]).unwrap(), vec![0x41, 0x47, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            args_block(0x3020, &vec!["x".repeat(100); 2]).unwrap_err(),
+            "Arguments take 214 bytes, the limit is 160"
+        );
+
+        // Argv pushes the block's address for the array ops to read
+        for layout in [RETROSHIELD, RC2014] {
+            assert!(layout.args() + ARGS_SIZE as u16 <= layout.stack_low());
+            assert!(layout.stack_low() + 2 <= layout.rx_buf);
+        }
+        let mut module = Module::new();
+        module.emit(Op::Argv);
+        module.emit(Op::Halt);
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let sp = machine.read16(RETROSHIELD.vm_sp());
+        assert_eq!(machine.read16(sp), RETROSHIELD.argv());
+
+        // Without the magic, whatever RAM held at reset is no array
+        let module = compile("my $n = 0; foreach my $a (@ARGV) { $n++; } print $n, \" \", $ARGV[0];");
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        let block = args_block(RETROSHIELD.args(), &["ab".to_string()]).unwrap();
+        for (i, &b) in block.iter().enumerate().skip(2) {
+            machine.write(RETROSHIELD.args() + i as u16, b);
+        }
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(String::from_utf8_lossy(machine.io.output()), "0 0");
+    }
+
+    #[test]
+    fn test_shell_table() {
+        let module = compile("my $n = 1; my $total = 2;");
+        assert_eq!(shell_table(&module), b"\x01n\x00\x05total\x01\x00");
+    }
+
+    #[test]
+    fn test_rom_shell() {
+        let module = compile("my $n = 41; my $big = 3000; print \"hi\\n\";");
+        let options = RomOptions { shell: true, ..RomOptions::default() };
+        let input = b"$n + 1\n$n = $n + 100\n $n\n$big + $n\n$big + $n % 7 == 5\n$nn\n1 +\n\n$n<=2\r12x\x08\n";
+        let mut machine = Machine::new(&generate_rom(&module, &options), crate::z80emu::Console::scripted(input));
+        assert_eq!(machine.run(Some(10_000_000)), Exit::InputExhausted);
+        let output = String::from_utf8_lossy(machine.io.output()).into_owned();
+        assert_eq!(output, "hi\n> $n + 1\n42\n> $n = $n + 100\n>  $n\n141\n> $big + $n\n3141\n\
+                            > $big + $n % 7 == 5\n1\n\
+                            > $nn\n?\n> 1 +\n?\n> \n> $n<=2\n0\n> 12x\x08 \x08\n12\n> ");
+    }
+
+    #[test]
+    fn test_input_arrives_later() {
+        // Input; Print; PushStr "\n"; Print; InputChar; Print; Halt
+        let mut module = Module::new();
+        let newline = module.add_string("\n");
+        module.emit(Op::Input);
+        module.emit(Op::Print);
+        module.emit_word(Op::PushStr, newline);
+        module.emit(Op::Print);
+        module.emit(Op::InputChar);
+        module.emit(Op::Print);
+        module.emit(Op::Halt);
+        let console = crate::z80emu::Console::scripted(b"").with_input_at(40_000, b"A").with_input_at(20_000, b"hello\n");
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), console);
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let timed = machine.io.timed_output();
+        assert_eq!(timed.iter().map(|(_, line)| line.as_str()).collect::<Vec<_>>(), ["hello\n", "65"]);
+        assert!((20_000..40_000).contains(&timed[0].0));
+        assert!(timed[1].0 >= 40_000);
+    }
+
+    #[test]
+    fn test_tasks() {
+        let module = compile(
+            "sub worker { print \"w\"; yield(); print \"x\"; }\nsub idle { }\n\
+             print spawn(\\&worker), spawn(\\&idle), spawn(\\&idle), spawn(\\&idle);\n\
+             yield();\nprint \"m\";\nyield();\nyield();\nprint \"e\";",
+        );
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let mut vm = crate::vm::Vm::new(&module, crate::z80emu::Console::scripted(b""));
+        vm.run(Some(1000)).unwrap();
+        assert_eq!(machine.io.output(), b"1230wmxe");
+        assert_eq!(vm.io.output(), machine.io.output());
+
+        // Waiting for input runs the other tasks: ...; InputChar; Print; Halt
+        let mut module = compile("sub worker { while (1) { print \"w\"; yield(); } }\nspawn(\\&worker);");
+        assert_eq!(module.code.pop(), Some(Op::Halt as u8));
+        module.emit(Op::InputChar);
+        module.emit(Op::Print);
+        module.emit(Op::Halt);
+        let console = || crate::z80emu::Console::scripted(b"").with_input_at(20_000, b"A");
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), console());
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let mut vm = crate::vm::Vm::new(&module, console());
+        vm.run(Some(100_000)).unwrap();
+        for output in [machine.io.output(), vm.io.output()] {
+            let output = String::from_utf8_lossy(output);
+            assert!(output.starts_with("ww") && output.ends_with("w65"), "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_menu() {
+        // A menu stub runs on the runtime as on the VM, and comes back
+        // after each program
+        let mut lexer = Lexer::new("sub hello { print \"hi\"; }\nsub count { print 1, 2, 3; }\nprint \"main\";");
+        let mut compiler = Compiler::new();
+        compiler.set_menu(vec!["hello".to_string(), "count".to_string()]);
+        let module = compiler.compile(&Parser::new(lexer.tokenize()).parse().unwrap()).unwrap();
+        let console = || crate::z80emu::Console::scripted(b"2x1");
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), console());
+        assert_eq!(machine.run(Some(1_000_000)), Exit::InputExhausted);
+        let mut vm = crate::vm::Vm::new(&module, console());
+        assert_eq!(vm.run(Some(10_000)), Ok(crate::vm::Exit::InputExhausted));
+        let menu = "1) hello\n2) count\n? ";
+        assert_eq!(String::from_utf8_lossy(machine.io.output()), format!("{0}2\n123{0}1\nhi{0}", menu));
+        assert_eq!(vm.io.output(), machine.io.output());
+
+        // A bundle's jumper port skips the menu
+        let programs = [("a".to_string(), compile("print 1;")), ("b".to_string(), compile("print 2;"))];
+        let module = crate::linker::bundle(&programs, Some(0x40)).unwrap();
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b"").with_port(0x40, 2));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"2");
+    }
+
+    #[test]
+    fn test_port_io() {
+        // The VM and the runtime drive the same ports
+        let module = compile("my $leds = port_in(64) + 1;\nport_out(65, $leds);\nprint port_out(66, 300), port_in(65);");
+        let console = || crate::z80emu::Console::scripted(b"").with_port(0x40, 0x5A);
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), console());
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let mut vm = crate::vm::Vm::new(&module, console());
+        vm.run(Some(1000)).unwrap();
+        for (writes, output) in [(machine.io.port_writes(), machine.io.output()), (vm.io.port_writes(), vm.io.output())] {
+            assert_eq!(writes, [(0x41, 0x5B), (0x42, 44)]);
+            assert_eq!(output, b"4491");
+        }
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let source = "my $at = 28672;\npoke($at, 300);\npoke16($at + 2, 1234);\nprint peek($at), \" \", peek16($at + 2), \" \", peek($at + 3), \" \", poke16($at + 4, 1);\npoke($at + 256, 1);";
+        let mut compiler = Compiler::new();
+        compiler.set_poke_range(Some((28672, 28927)));
+        let module = compiler.compile(&Parser::new(Lexer::new(source).tokenize()).parse().unwrap()).unwrap();
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let mut vm = crate::vm::Vm::new(&module, crate::z80emu::Console::scripted(b""));
+        let error = vm.run(Some(1000)).unwrap_err();
+        assert!(error.starts_with("Poke at 7100 outside --poke-range"), "{}", error);
+        assert_eq!(machine.io.output(), b"44 1234 4 1");
+        assert_eq!(vm.io.output(), machine.io.output());
+        assert_eq!(machine.read16(0x7002), 1234);
+        // Stopped on the last poke's check
+        let pc = machine.read16(RETROSHIELD.vm_pc());
+        assert_eq!(halt_error(&module, pc).as_deref(), Some("Runtime error: Poke outside --poke-range"));
+    }
+
+    #[test]
+    fn test_timer() {
+        // 250 * 16 T-states a tick on channel 0
+        let module = compile("ctc_timer(136, 250, 16);\nmy $t = 0;\nwhile ($t < 5) { $t = ticks(); }\nprint $t;");
+        let options = RomOptions { timer: true, ..Default::default() };
+        let mut machine = Machine::new(&generate_rom(&module, &options), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"5");
+        assert!((20_000..40_000).contains(&machine.cpu.cycles), "{}", machine.cpu.cycles);
+        let mut vm = crate::vm::Vm::new(&module, crate::z80emu::Console::scripted(b""));
+        vm.run(Some(100_000)).unwrap();
+        assert_eq!(vm.io.output(), b"5");
+
+        // Without the ISR the runtime stops at the first ticks()
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        let pc = machine.read16(RETROSHIELD.vm_pc());
+        assert_eq!(halt_error(&module, pc).as_deref(), Some("Runtime error: ticks() needs --timer or the host VM (run)"));
+        let both = RomOptions { timer: true, irq_input: true, ..Default::default() };
+        assert!(both.check().is_err());
+    }
+
+    #[test]
+    fn test_native_subs() {
+        let module = compile(
+            "sub fib($n) :native { return $n < 2 ? $n : fib($n - 1) + fib($n - 2); }
+             sub gcd($a, $b) :native { while ($b != 0) { my $t = $a % $b; $a = $b; $b = $t; } return $a; }
+             sub bits($x) :native { my $n = 0; while ($x) { if ($x % 2 == 0) { $x = $x / 2; next; } $n++; $x = $x / 2; } return $n; }
+             sub mix($a) :native { return ($a <=> 3) + ($a <= 3) * 10 + ($a > 4) * 20 + ($a && 0) + ($a || 0) + !$a - $a * 100 / 7 + -$a * 2 + ($a != 2) + 100; }
+             print fib(18), \" \", gcd(1071, 462), \" \", bits(61681), \" \", mix(5), \" \", mix(2);"
+        );
+        assert_eq!(module.native.len(), 4);
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(50_000_000)), Exit::Halted);
+        assert_eq!(String::from_utf8_lossy(machine.io.output()), "2584 21 9 42 78");
+        assert!(generate_asm(&module, &RomOptions::default()).contains("\nnative:\n        DB 0x2A,"));
+    }
+
+    #[test]
+    fn test_native_division_by_zero() {
+        let module = compile("sub d($a) :native { return 100 / $a; } print d(7); print d(0); print 1;");
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"14");
+        let pc = machine.read16(RETROSHIELD.vm_pc()) as usize;
+        assert_eq!(Op::from_byte(module.code[pc]), Op::Native);
+    }
+
+    #[test]
+    fn test_asm_source_covers_runtime() {
+        let module = compile("my $x = 1; print $x;");
+        let options = RomOptions { irq_input: true, ..RomOptions::default() };
+        let src = generate_asm(&module, &options);
+        let rom = generate_rom(&module, &options);
+
+        // The address and bytes comments of the runtime lines, in order, are
+        // the runtime itself
+        let mut runtime = Vec::new();
+        let mut labels = std::collections::HashSet::new();
+        for line in src.lines().take_while(|l| *l != "bytecode:") {
+            if let Some(label) = line.strip_suffix(':') {
+                assert!(labels.insert(label), "label {} defined twice", label);
+            }
+            let Some((_, comment)) = line.split_once("; ").filter(|_| !line.starts_with(';')) else {
+                continue;
+            };
+            let (addr, bytes) = comment.split_once("  ").unwrap();
+            assert_eq!(u16::from_str_radix(addr, 16).unwrap() as usize, runtime.len());
+            runtime.extend(bytes.split(' ').map(|b| u8::from_str_radix(b, 16).unwrap()));
+        }
+        assert_eq!(runtime, &rom[..runtime.len()]);
+        assert!(src.contains("        JP init "));
+        assert!(src.contains("        LD (VM_PC),HL "));
+        assert!(src.contains("\n        DS BYTECODE_ORG-$,0x00\n"));
+        assert!(src.contains("        DB 0x78                 ; 0007  Print\n"));
+    }
+
+    #[test]
+    fn test_listing_and_map() {
+        let module = compile("sub f($n) { print $n; }\nf(\"a long string\");");
+        let options = RomOptions::default();
+        let main_loop = runtime_symbol(&options, "main_loop").unwrap();
+
+        let listing = generate_listing(&module, &options);
+        assert!(listing.contains(&format!("main_loop:\n  {:04X}  ", main_loop)));
+        assert!(listing.contains("bytecode:\n  1000  4D 50 4C 02   magic\n"));
+        assert!(listing.contains("  100A  08 00         VM stack 0x0008\n; line 1\n  100C  60 0A 00      0000  Jump 0x000A\n; sub f (1 params)\n"));
+        // The 14-byte string runs over four rows
+        assert!(listing.contains("  101F  0D 61 20 6C   [0] \"a long string\"\n  1023  6F 6E 67 20\n"));
+        assert!(listing.ends_with("  102B  6E 67\n"));
+
+        let map = generate_map(&module, &options);
+        let sub = BYTECODE_ORG + HEADER as u16 + module.subs[0].1;
+        assert!(map.contains(&format!("\n{:04X}  main_loop\n", main_loop)));
+        assert!(map.contains(&format!("\n; Subs\n{:04X}  f\n", sub)));
+        assert!(map.contains(&format!("\n{:04X}  VM_PC\n", VM_PC)));
+        assert!(map.contains("\n; Instructions\n100C  0000 Jump 0x000A -> 1016\n"));
+    }
+
+    #[test]
+    fn test_address_map() {
+        let module = compile("my $i = 0;\nwhile ($i < 3) {\n    $i++;\n}\n");
+        let map = AddressMap::new(&module, &RomOptions::default());
+        assert_eq!(map.address(0x0005), BYTECODE_ORG + HEADER as u16 + 5);
+        // Any byte of an instruction finds its start
+        assert_eq!(map.offset(0x1012), Some(0x0005));
+        assert_eq!(map.offset(0x100C), Some(0));
+        assert_eq!(map.offset(0x100B), None);
+        assert_eq!(map.offset(map.address(module.code.len() as u16)), None);
+        assert_eq!(map.line(0x0012), Some(3));
+        // The jump back to the test is the loop's
+        assert_eq!(map.line(0x0013), Some(2));
+        assert_eq!(map.line_offsets(2), [0x0005, 0x0013]);
+        let last = map.instructions().last().unwrap();
+        assert_eq!((last as usize + 1, module.code[last as usize]), (module.code.len(), Op::Halt as u8));
+        assert_eq!(map.describe(0x0013), "0013 Jump 0x0005 -> 1011");
+    }
+
+    #[test]
+    fn test_rc2014_acia() {
+        let uart = run_rc2014(Target::Rc2014Acia, 0x02);
+        assert_eq!(uart.control, vec![0x03, 0x16]);
+        assert_eq!(uart.sent, b"hi\n");
+    }
+
+    #[test]
+    fn test_rc2014_sio() {
+        let uart = run_rc2014(Target::Rc2014Sio, 0x04);
+        assert_eq!(uart.control, vec![0x18, 0x04, 0xC4, 0x01, 0x00, 0x03, 0xE1, 0x05, 0xEA]);
+        assert_eq!(uart.sent, b"hi\n");
+    }
+
+    /// The UART plus a page register that maps 16K of `rom` into the window
+    struct BankedBoard {
+        uart: Uart,
+        port: u8,
+        page: Option<u8>,
+        switches: usize,
+    }
+
+    impl Io for BankedBoard {
+        fn input(&mut self, port: u8) -> u8 {
+            self.uart.input(port)
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            if port == self.port {
+                self.switches += (self.page != Some(value)) as usize;
+                self.page = Some(value);
+            } else {
+                self.uart.output(port, value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_runtime_is_stable() {
+        let options = RomOptions::default();
+        assert!(std::ptr::eq(runtime(&options), runtime(&options)));
+        let named = RomOptions { program_name: "another".into(), ..RomOptions::default() };
+        assert!(std::ptr::eq(runtime(&options), runtime(&named)));
+        assert_eq!(runtime(&options), assemble_runtime(&options).finish());
+        // Changing the runtime's bytes needs a new RUNTIME_VERSION
+        let fnv = runtime(&options).iter().fold(0x811C_9DC5u32, |h, &b| (h ^ b as u32).wrapping_mul(0x0100_0193));
+        assert_eq!((RUNTIME_VERSION, runtime(&options).len(), fnv), (21, 3357, 0xD908_5A35));
+    }
+
+    #[test]
+    fn test_channels_only_for_stderr() {
+        // A program that never selects STDERR gets the plain console, and
+        // a failed assert still goes to the error port
+        let plain = compile("print \"a\";\nassert 0, \"no\";");
+        let stderr = compile("print \"a\";\nprint STDERR \"b\";\nassert 0, \"no\";");
+        let options = RomOptions::default();
+        assert!(runtime_size(&options.for_module(&plain)) < runtime_size(&options.for_module(&stderr)));
+        for (module, errors) in [(plain, "Assertion failed: no\n"), (stderr, "bAssertion failed: no\n")] {
+            let mut machine = Machine::new(&generate_rom(&module, &options), crate::z80emu::Console::scripted(b""));
+            assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+            assert_eq!(machine.io.output(), b"a");
+            assert_eq!(machine.io.errors(), errors.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_self_test() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        let options = RomOptions { self_test: true, program_name: "seven".into(), ..RomOptions::default() };
+        let rom = generate_rom(&compile("print 7;"), &options);
+        let run = |rom: &[u8]| {
+            let mut machine = Machine::new(rom, crate::z80emu::Console::scripted(b""));
+            assert_eq!(machine.run(Some(5_000_000)), Exit::Halted);
+            (String::from_utf8_lossy(machine.io.output()).into_owned(), machine.read16(RETROSHIELD.vm_pc()))
+        };
+        assert_eq!(run(&rom).0, format!("MicroPerl {} seven at 0x100C\n7", env!("CARGO_PKG_VERSION")));
+
+        let mut bad = rom.clone();
+        bad[RETROSHIELD.bytecode_org as usize + HEADER] ^= 1;
+        assert_eq!(run(&bad), ("Self-test failed: bad program CRC\n".to_string(), BOOT_FAILED));
+        bad[RETROSHIELD.bytecode_org as usize] = 0xFF;
+        assert_eq!(run(&bad).0, "Self-test failed: no MicroPerl program\n");
+        assert!(generate_asm(&compile("print 7;"), &options).contains("\nself_test:\n        DB "));
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        let module = compile("print 1;\nmy $n = 200;\nmy $s = \"ab\" x $n;\nprint 2;");
+        let rom = generate_rom(&module, &RomOptions::default());
+        let run = |depth: u16| {
+            let mut rom = rom.clone();
+            let at = RETROSHIELD.bytecode_org as usize + 10;
+            rom[at..at + 2].copy_from_slice(&depth.to_le_bytes());
+            let mut machine = Machine::new(&rom, crate::z80emu::Console::scripted(b""));
+            assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+            let heap = machine.read16(RETROSHIELD.heap_ptr());
+            let error = heap_error(&RETROSHIELD, heap, machine.read16(RETROSHIELD.heap_limit()));
+            (String::from_utf8_lossy(machine.io.output()).into_owned(), machine.read16(RETROSHIELD.vm_pc()), error)
+        };
+        assert_eq!(run(crate::bytecode::UNBOUNDED_STACK), ("12".to_string(), module.code.len() as u16 - 1, None));
+        assert_eq!(run(0x5000).0, "12");
+
+        // No room for the stack, then a heap that runs into it on the x
+        let (output, pc, _) = run(0x6001);
+        assert_eq!((output.as_str(), pc), ("Out of memory: no room for the VM stack\n", BOOT_FAILED));
+        let (output, pc, error) = run(0x6000);
+        assert_eq!(output, "1");
+        assert_eq!(Op::from_byte(module.code[pc as usize]), Op::Repeat);
+        assert_eq!(error.as_deref(), Some("Runtime error: Out of memory: the heap ran into the 24576 bytes kept for the VM stack"));
+    }
+
+    #[test]
+    fn test_trace_rom() {
+        let module = compile("my $x = 6; print $x + 36;");
+        let run = |options: &RomOptions| {
+            let console = crate::z80emu::Console::scripted(b"").with_trace_port(0x40);
+            let mut machine = Machine::new(&generate_rom(&module, options), console);
+            assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+            assert_eq!(machine.io.output(), b"42");
+            machine.io.trace().to_vec()
+        };
+        let traced = RomOptions { trace_port: Some(0x40), ..RomOptions::default() };
+        let ops: Vec<Op> = run(&traced).into_iter().map(Op::from_byte).collect();
+        assert_eq!(ops.first(), Some(&Op::Push));
+        assert_eq!(ops.last(), Some(&Op::Halt));
+        assert!(ops.contains(&Op::Add));
+
+        // The Print sees 42 on top of the stack
+        let log = run(&RomOptions { trace_stack: true, ..traced.clone() });
+        assert_eq!(log.len(), 3 * ops.len());
+        assert!(log.chunks(3).any(|r| r == [Op::Print as u8, 42, 0]));
+        assert!(run(&RomOptions::default()).is_empty());
+
+        let stack_only = RomOptions { trace_stack: true, ..RomOptions::default() };
+        assert_eq!(stack_only.check().unwrap_err(), "--trace-stack needs --trace-rom");
+    }
+
+    #[test]
+    fn test_mem_stats() {
+        let module = compile("sub f($x) { memstats(); }\nf(1);\nmy $n = 2;\nmy $s = \"ab\" x $n;\nmemstats();");
+        let options = RomOptions { mem_stats: true, ..RomOptions::default() };
+        let mut machine = Machine::new(&generate_rom(&module, &options), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+
+        // The same report as the host VM's
+        let mut vm = crate::vm::Vm::new(&module, crate::z80emu::Console::scripted(b""));
+        assert_eq!(vm.run(None), Ok(crate::vm::Exit::Halted));
+        assert_eq!(machine.io.output(), vm.io.output());
+        assert_eq!(machine.read16(RETROSHIELD.stack_low()), vm.stack_low);
+        assert_eq!(machine.read16(RETROSHIELD.heap_ptr()), vm.heap);
+
+        // Without it, the runtime stops on the native
+        let mut machine = Machine::new(&generate_rom(&module, &RomOptions::default()), crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert!(machine.io.output().is_empty());
+    }
+
+    #[test]
+    fn test_suspend_and_resume() {
+        let code = r#"my $n = 5; print resumed(); if (suspend()) { print "back", $n, resumed(); } print "end";"#;
+        let rom = generate_rom(&compile(code), &RomOptions::default());
+        let mut machine = Machine::new(&rom, crate::z80emu::Console::scripted(b""));
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"0");
+        let snapshot: Vec<u8> = (0..SNAPSHOT_SIZE as u16).map(|i| machine.read(RETROSHIELD.snapshot() + i)).collect();
+
+        // Power cycle with the RAM kept
+        machine.cpu = crate::z80emu::Cpu::new();
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"0back51end");
+        machine.cpu = crate::z80emu::Cpu::new();
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"0back51end0");
+
+        // Another program starts afresh
+        let rom = generate_rom(&compile(&format!("print 1; {}", code)), &RomOptions::default());
+        let mut machine = Machine::new(&rom, crate::z80emu::Console::scripted(b""));
+        for (i, &b) in snapshot.iter().enumerate() {
+            machine.write(RETROSHIELD.snapshot() + i as u16, b);
+        }
+        assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+        assert_eq!(machine.io.output(), b"10");
+    }
+
+    #[test]
+    fn test_reproducible_banked_rom() {
+        let module = compile("print 1;");
+        let banking = Banking { port: 0x79, first_page: 2 };
+        let options = RomOptions { target: Target::Rc2014Acia, banking: Some(banking), ..RomOptions::default() };
+        let blank = generate_rom(&module, &options);
+        assert!(blank[banking::PAGE_SIZE..2 * banking::PAGE_SIZE].iter().all(|&b| b == 0xFF));
+        let rom = generate_rom(&module, &RomOptions { reproducible: true, ..options });
+        assert!(rom[banking::PAGE_SIZE..2 * banking::PAGE_SIZE].iter().all(|&b| b == 0));
+        assert!(rom.iter().zip(&blank).all(|(&b, &was)| b == was || (b, was) == (0, 0xFF)));
+    }
+
+    #[test]
+    fn test_banked_code_runs_across_pages() {
+        // About 20K of code, so it spans two pages
+        let mut source = String::from("my $x = 0;\n");
+        for _ in 0..3142 {
+            source.push_str("$x = $x + 1;\n");
+        }
+        // The runtime prints numbers up to 99
+        source.push_str("$x = $x % 100;\nprint $x, \"\\n\";\n");
+        let banking = Banking { port: 0x7A, first_page: 3 };
+        let options = RomOptions { target: Target::Rc2014Acia, banking: Some(banking), ..RomOptions::default() };
+        let rom = generate_rom(&compile(&source), &options);
+        assert!(rom.len() > 4 * banking::PAGE_SIZE + banking::PAGE_SIZE / 4);
+
+        let uart = Uart { tx_ready: 0x02, busy: false, control: Vec::new(), sent: Vec::new() };
+        let board = BankedBoard { uart, port: 0x7A, page: None, switches: 0 };
+        let mut machine = Machine::new(&rom[..banking::PAGE_SIZE], board);
+        let mut mapped = None;
+        while machine.stopped(Some(50_000_000)).is_none() {
+            machine.step();
+            if machine.io.page != mapped {
+                mapped = machine.io.page;
+                let start = mapped.unwrap() as usize * banking::PAGE_SIZE;
+                let page = &rom[start..(start + banking::PAGE_SIZE).min(rom.len())];
+                let window = banking::WINDOW as usize;
+                machine.mem[window..window + banking::PAGE_SIZE].fill(0xFF);
+                machine.mem[window..window + page.len()].copy_from_slice(page);
+            }
+        }
+        assert_eq!(machine.stopped(None), Some(Exit::Halted));
+        assert_eq!(machine.io.uart.sent, b"42\n");
+        assert_eq!(machine.io.switches, 2);
+    }
+
+    /// Run the counting loop on a stand-in for a hosted target's system:
+    /// call the program at 0 like the system would and halt when it returns,
+    /// with `stubs` patched over the system's console routines
+    #[cfg(any(feature = "target-spectrum", feature = "target-cpc", feature = "target-trs80", feature = "target-ti83"))]
+    fn run_hosted(target: Target, stubs: &[(u16, &[u8])]) -> Machine<Console> {
+        let module = compile("my $i = 0; while ($i < 3) { print $i, \"\\n\"; $i++; }");
+        let options = RomOptions { target, ..RomOptions::default() };
+        let l = target.layout();
+
+        let mut rom = vec![0; 0x4000];
+        rom[..4].copy_from_slice(&[0xCD, l.runtime_org as u8, (l.runtime_org >> 8) as u8, 0x76]);
+        let mut machine = Machine::new(&rom, Console::scripted(b""));
+        for (addr, code) in stubs {
+            machine.mem[*addr as usize..*addr as usize + code.len()].copy_from_slice(code);
+        }
+        machine.cpu.sp = 0xFFF0;
+        let image = generate_rom(&module, &options);
+        let start = l.runtime_org as usize;
+        machine.mem[start..start + image.len()].copy_from_slice(&image);
+
+        assert_eq!(machine.run(Some(10_000_000)), Exit::Halted);
+        machine
+    }
+
+    /// OUT (PORT_CONSOLE),A; RET
+    #[cfg(any(feature = "target-spectrum", feature = "target-cpc", feature = "target-trs80"))]
+    const PRINT_STUB: &[u8] = &[0xD3, PORT_CONSOLE, 0xC9];
+
+    #[test]
+    #[cfg(feature = "target-spectrum")]
+    fn test_spectrum_runtime_on_stub_rom() {
+        let machine = run_hosted(Target::Spectrum, &[(ZX_PRINT as u16, PRINT_STUB), (ZX_CHAN_OPEN, &[0xC9])]);
+        assert_eq!(machine.io.output(), b"0\r1\r2\r");
+        // Returned to the caller's stack
+        assert_eq!(machine.cpu.sp, 0xFFF0);
+    }
+
+    #[test]
+    #[cfg(feature = "target-cpc")]
+    fn test_cpc_runtime_on_stub_firmware() {
+        let machine = run_hosted(Target::Cpc, &[(CPC_TXT_OUTPUT, PRINT_STUB)]);
+        assert_eq!(machine.io.output(), b"0\r\n1\r\n2\r\n");
+        assert_eq!(machine.cpu.sp, 0xFFF0);
+    }
+
+    #[test]
+    #[cfg(feature = "target-ti83")]
+    fn test_ti83_runtime_on_stub_bcalls() {
+        // RST 28h: skip the inline address and print for _PutC and _NewLine
+        let mut b = Asm::new(TI_BCALL as u16);
+        let (putc, newline) = (b.label("putc"), b.label("newline"));
+        b.ex_sp_hl();
+        b.ld(Reg8::E, Reg8::HLInd);
+        b.inc16(Reg16::HL);
+        b.inc16(Reg16::HL);
+        b.ex_sp_hl();
+        b.push(StackReg::AF);
+        b.ld(Reg8::A, Reg8::E);
+        b.cp_n(TI_PUTC as u8);
+        b.jr_cc(Cond::Z, putc);
+        b.cp_n(TI_NEWLINE as u8);
+        b.jr_cc(Cond::Z, newline);
+        b.pop(StackReg::AF);
+        b.ret();
+        b.bind(newline);
+        b.pop(StackReg::AF);
+        b.ld_n(Reg8::A, b'\n');
+        b.out_n(PORT_CONSOLE);
+        b.ret();
+        b.bind(putc);
+        b.pop(StackReg::AF);
+        b.out_n(PORT_CONSOLE);
+        b.ret();
+
+        let machine = run_hosted(Target::Ti83, &[(TI_BCALL as u16, &b.finish())]);
+        assert_eq!(machine.io.output(), b"0\n1\n2\n");
+        assert_eq!(machine.cpu.sp, 0xFFF0);
+    }
+
+    #[test]
+    #[cfg(feature = "target-trs80")]
+    fn test_trs80_runtime_on_stub_rom() {
+        // DOS Ready is a HALT here
+        let machine = run_hosted(Target::Trs80, &[(TRS80_DSP, PRINT_STUB), (TRS80_EXIT, &[0x76])]);
+        assert_eq!(machine.io.output(), b"0\r1\r2\r");
+        assert_eq!(machine.cpu.pc, TRS80_EXIT + 1);
+    }
 }
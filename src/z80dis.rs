@@ -0,0 +1,536 @@
+//! Z80 disassembler
+//!
+//! Decodes Z80 machine code (including CB, ED, DD/FD and DDCB/FDCB prefixed
+//! instructions) into Zilog mnemonics. Used to inspect the generated runtime.
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const ACC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+const IM: [&str; 8] = ["0", "0", "1", "2", "0", "0", "1", "2"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+/// A decoded instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instr {
+    /// Address of the first byte
+    pub addr: u16,
+    /// Encoded bytes
+    pub bytes: Vec<u8>,
+    /// Mnemonic and operands
+    pub text: String,
+}
+
+/// Byte reader over the code buffer
+struct Reader<'a> {
+    code: &'a [u8],
+    start: usize,
+    pos: usize,
+    org: u16,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> u8 {
+        let b = self.code.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        b
+    }
+
+    fn word(&mut self) -> u16 {
+        let lo = self.byte() as u16;
+        let hi = self.byte() as u16;
+        lo | (hi << 8)
+    }
+
+    fn rel(&mut self) -> u16 {
+        let d = self.byte() as i8;
+        let next = self.org as i32 + self.pos as i32;
+        (next + d as i32) as u16
+    }
+
+    fn addr(&self) -> u16 {
+        self.org.wrapping_add(self.start as u16)
+    }
+}
+
+/// Index register in effect for a DD/FD prefixed instruction
+#[derive(Clone, Copy)]
+enum Index {
+    None,
+    IX,
+    IY,
+}
+
+impl Index {
+    fn hl(self) -> &'static str {
+        match self {
+            Index::None => "HL",
+            Index::IX => "IX",
+            Index::IY => "IY",
+        }
+    }
+}
+
+/// Decode the instruction at `pos` in `code`, where code[0] lives at `org`.
+/// Returns None if `pos` is past the end of the buffer.
+pub fn decode(code: &[u8], pos: usize, org: u16) -> Option<Instr> {
+    if pos >= code.len() {
+        return None;
+    }
+    let mut rd = Reader { code, start: pos, pos, org };
+    let text = decode_main(&mut rd, Index::None);
+    // Instructions running off the end of the buffer are shown as data
+    let end = rd.pos;
+    if end > code.len() {
+        return Some(Instr {
+            addr: rd.addr(),
+            bytes: code[pos..].to_vec(),
+            text: db(&code[pos..]),
+        });
+    }
+    Some(Instr {
+        addr: rd.addr(),
+        bytes: code[pos..end].to_vec(),
+        text,
+    })
+}
+
+/// Decode every instruction in `code`
+pub fn disassemble(code: &[u8], org: u16) -> Vec<Instr> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while let Some(instr) = decode(code, pos, org) {
+        pos += instr.bytes.len();
+        out.push(instr);
+    }
+    out
+}
+
+/// Render a disassembly listing with addresses, bytes and mnemonics, with
+/// `symbols` (name, address) shown as labels and the `data` spans (address,
+/// length) shown as DB
+pub fn render(code: &[u8], org: u16, symbols: &[(String, u16)], data: &[(u16, usize)]) -> String {
+    let mut out = String::new();
+    for instr in split(code, org, symbols, data) {
+        for (name, _) in symbols.iter().filter(|(_, a)| *a == instr.addr) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        let bytes: Vec<String> = instr.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        out.push_str(&format!("  {:04X}  {:<12}  {}\n", instr.addr, bytes.join(" "), instr.text));
+    }
+    out
+}
+
+/// Render `code` as assembler source for sjasmplus or zasm. `symbols` become
+/// labels and the targets of jumps and calls, `equates` name the addresses
+/// of memory operands, and the `data` spans (address, length) are written as
+/// DB. Each line keeps its address and bytes in a comment.
+pub fn render_source(
+    code: &[u8],
+    org: u16,
+    symbols: &[(String, u16)],
+    equates: &[(&str, u16)],
+    data: &[(u16, usize)],
+) -> String {
+    let names = unique_names(symbols);
+    let mut out = String::new();
+    for instr in split(code, org, &names, data) {
+        for (name, _) in names.iter().filter(|(_, a)| *a == instr.addr) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        let hex: Vec<String> = instr.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let text = symbolic(&instr.text, &names, equates);
+        out.push_str(&format!("        {:<24}; {:04X}  {}\n", text, instr.addr, hex.join(" ")));
+    }
+    out
+}
+
+/// Decode `code` into instructions and rows of at most 8 data bytes.
+/// Nothing runs past a label or into a data span, so bytes an instruction
+/// would straddle them with are shown as DB.
+fn split(code: &[u8], org: u16, symbols: &[(String, u16)], data: &[(u16, usize)]) -> Vec<Instr> {
+    let offset = |addr: u16| addr.wrapping_sub(org) as usize;
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < code.len() {
+        let limit = symbols
+            .iter()
+            .map(|(_, a)| offset(*a))
+            .chain(data.iter().map(|(a, _)| offset(*a)))
+            .filter(|&p| p > pos && p < code.len())
+            .min()
+            .unwrap_or(code.len());
+
+        let addr = org.wrapping_add(pos as u16);
+        match data.iter().find(|(a, len)| (offset(*a)..offset(*a) + len).contains(&pos)) {
+            Some((a, len)) => {
+                let end = (offset(*a) + len).min(limit).min(pos + 8);
+                out.push(Instr { addr, bytes: code[pos..end].to_vec(), text: db(&code[pos..end]) });
+                pos = end;
+            }
+            None => {
+                out.extend(disassemble(&code[pos..limit], addr));
+                pos = limit;
+            }
+        }
+    }
+    out
+}
+
+/// Symbols with repeated names numbered so each label is defined once
+pub fn unique_names(symbols: &[(String, u16)]) -> Vec<(String, u16)> {
+    let mut seen = std::collections::HashMap::new();
+    symbols
+        .iter()
+        .map(|(name, addr)| {
+            let count = seen.entry(name.as_str()).or_insert(0);
+            *count += 1;
+            match *count {
+                1 => (name.clone(), *addr),
+                n => (format!("{}_{}", name, n), *addr),
+            }
+        })
+        .collect()
+}
+
+/// Replace the target of a jump or call with its label, or a memory operand
+/// `(nn)` with its equate
+fn symbolic(text: &str, names: &[(String, u16)], equates: &[(&str, u16)]) -> String {
+    let Some(hex) = text.find("0x") else {
+        return text.to_string();
+    };
+    let digits = text[hex + 2..].chars().take_while(char::is_ascii_hexdigit).count();
+    if digits != 4 {
+        return text.to_string();
+    }
+    let value = u16::from_str_radix(&text[hex + 2..hex + 6], 16).unwrap();
+    let branch = ["JP ", "JR ", "CALL ", "DJNZ "].iter().any(|m| text.starts_with(m));
+    let memory = text[..hex].ends_with('(');
+    let name = if branch {
+        names.iter().find(|(_, a)| *a == value).map(|(n, _)| n.as_str())
+    } else if memory {
+        equates.iter().find(|(_, a)| *a == value).map(|(n, _)| *n)
+    } else {
+        None
+    };
+    match name {
+        Some(name) => format!("{}{}{}", &text[..hex], name, &text[hex + 6..]),
+        None => text.to_string(),
+    }
+}
+
+fn db(bytes: &[u8]) -> String {
+    let list: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+    format!("DB {}", list.join(","))
+}
+
+fn n8(n: u8) -> String {
+    format!("0x{:02X}", n)
+}
+
+fn n16(n: u16) -> String {
+    format!("0x{:04X}", n)
+}
+
+/// Render an 8-bit register operand, applying the index prefix
+fn reg(rd: &mut Reader, i: u8, idx: Index, allow_half: bool) -> String {
+    match (i, idx) {
+        (6, Index::None) => "(HL)".to_string(),
+        (6, _) => {
+            let d = rd.byte() as i8;
+            if d < 0 {
+                format!("({}-0x{:02X})", idx.hl(), -(d as i16))
+            } else {
+                format!("({}+0x{:02X})", idx.hl(), d)
+            }
+        }
+        (4, Index::IX) if allow_half => "IXH".to_string(),
+        (5, Index::IX) if allow_half => "IXL".to_string(),
+        (4, Index::IY) if allow_half => "IYH".to_string(),
+        (5, Index::IY) if allow_half => "IYL".to_string(),
+        _ => R[i as usize].to_string(),
+    }
+}
+
+fn rp(p: u8, idx: Index) -> &'static str {
+    if p == 2 { idx.hl() } else { RP[p as usize] }
+}
+
+fn rp2(p: u8, idx: Index) -> &'static str {
+    if p == 2 { idx.hl() } else { RP2[p as usize] }
+}
+
+fn decode_main(rd: &mut Reader, idx: Index) -> String {
+    let op = rd.byte();
+    let x = op >> 6;
+    let y = (op >> 3) & 7;
+    let z = op & 7;
+    let p = y >> 1;
+    let q = y & 1;
+
+    match x {
+        0 => match z {
+            0 => match y {
+                0 => "NOP".to_string(),
+                1 => "EX AF,AF'".to_string(),
+                2 => format!("DJNZ {}", n16(rd.rel())),
+                3 => format!("JR {}", n16(rd.rel())),
+                _ => format!("JR {},{}", CC[(y - 4) as usize], n16(rd.rel())),
+            },
+            1 => {
+                if q == 0 {
+                    format!("LD {},{}", rp(p, idx), n16(rd.word()))
+                } else {
+                    format!("ADD {},{}", idx.hl(), rp(p, idx))
+                }
+            }
+            2 => match (q, p) {
+                (0, 0) => "LD (BC),A".to_string(),
+                (0, 1) => "LD (DE),A".to_string(),
+                (0, 2) => format!("LD ({}),{}", n16(rd.word()), idx.hl()),
+                (0, _) => format!("LD ({}),A", n16(rd.word())),
+                (_, 0) => "LD A,(BC)".to_string(),
+                (_, 1) => "LD A,(DE)".to_string(),
+                (_, 2) => format!("LD {},({})", idx.hl(), n16(rd.word())),
+                (_, _) => format!("LD A,({})", n16(rd.word())),
+            },
+            3 => {
+                let m = if q == 0 { "INC" } else { "DEC" };
+                format!("{} {}", m, rp(p, idx))
+            }
+            4 => format!("INC {}", reg(rd, y, idx, true)),
+            5 => format!("DEC {}", reg(rd, y, idx, true)),
+            6 => {
+                let dst = reg(rd, y, idx, true);
+                format!("LD {},{}", dst, n8(rd.byte()))
+            }
+            _ => ACC[y as usize].to_string(),
+        },
+        1 => {
+            if y == 6 && z == 6 {
+                "HALT".to_string()
+            } else {
+                // With (IX+d) the other operand keeps plain H/L
+                let half = y != 6 && z != 6;
+                let dst = reg(rd, y, idx, half);
+                let src = reg(rd, z, idx, half);
+                format!("LD {},{}", dst, src)
+            }
+        }
+        2 => format!("{}{}", ALU[y as usize], reg(rd, z, idx, true)),
+        _ => match z {
+            0 => format!("RET {}", CC[y as usize]),
+            1 => {
+                if q == 0 {
+                    format!("POP {}", rp2(p, idx))
+                } else {
+                    match p {
+                        0 => "RET".to_string(),
+                        1 => "EXX".to_string(),
+                        2 => format!("JP ({})", idx.hl()),
+                        _ => format!("LD SP,{}", idx.hl()),
+                    }
+                }
+            }
+            2 => format!("JP {},{}", CC[y as usize], n16(rd.word())),
+            3 => match y {
+                0 => format!("JP {}", n16(rd.word())),
+                1 => decode_cb(rd, idx),
+                2 => format!("OUT ({}),A", n8(rd.byte())),
+                3 => format!("IN A,({})", n8(rd.byte())),
+                4 => format!("EX (SP),{}", idx.hl()),
+                5 => "EX DE,HL".to_string(),
+                6 => "DI".to_string(),
+                _ => "EI".to_string(),
+            },
+            4 => format!("CALL {},{}", CC[y as usize], n16(rd.word())),
+            5 => {
+                if q == 0 {
+                    format!("PUSH {}", rp2(p, idx))
+                } else {
+                    match p {
+                        0 => format!("CALL {}", n16(rd.word())),
+                        1 => decode_main(rd, Index::IX),
+                        2 => decode_ed(rd),
+                        _ => decode_main(rd, Index::IY),
+                    }
+                }
+            }
+            6 => format!("{}{}", ALU[y as usize], n8(rd.byte())),
+            _ => format!("RST {}", n8(y * 8)),
+        },
+    }
+}
+
+fn decode_cb(rd: &mut Reader, idx: Index) -> String {
+    // DDCB/FDCB: the displacement comes before the opcode
+    let operand = match idx {
+        Index::None => None,
+        _ => Some(reg(rd, 6, idx, false)),
+    };
+    let op = rd.byte();
+    let x = op >> 6;
+    let y = (op >> 3) & 7;
+    let z = op & 7;
+    let target = match &operand {
+        Some(indexed) => indexed.clone(),
+        None => R[z as usize].to_string(),
+    };
+    match x {
+        0 => format!("{} {}", ROT[y as usize], target),
+        1 => format!("BIT {},{}", y, target),
+        2 => format!("RES {},{}", y, target),
+        _ => format!("SET {},{}", y, target),
+    }
+}
+
+fn decode_ed(rd: &mut Reader) -> String {
+    let op = rd.byte();
+    let x = op >> 6;
+    let y = (op >> 3) & 7;
+    let z = op & 7;
+    let p = y >> 1;
+    let q = y & 1;
+
+    match x {
+        1 => match z {
+            0 => {
+                if y == 6 {
+                    "IN (C)".to_string()
+                } else {
+                    format!("IN {},(C)", R[y as usize])
+                }
+            }
+            1 => {
+                if y == 6 {
+                    "OUT (C),0".to_string()
+                } else {
+                    format!("OUT (C),{}", R[y as usize])
+                }
+            }
+            2 => {
+                let m = if q == 0 { "SBC" } else { "ADC" };
+                format!("{} HL,{}", m, RP[p as usize])
+            }
+            3 => {
+                if q == 0 {
+                    format!("LD ({}),{}", n16(rd.word()), RP[p as usize])
+                } else {
+                    format!("LD {},({})", RP[p as usize], n16(rd.word()))
+                }
+            }
+            4 => "NEG".to_string(),
+            5 => {
+                if y == 1 { "RETI".to_string() } else { "RETN".to_string() }
+            }
+            6 => format!("IM {}", IM[y as usize]),
+            _ => match y {
+                0 => "LD I,A".to_string(),
+                1 => "LD R,A".to_string(),
+                2 => "LD A,I".to_string(),
+                3 => "LD A,R".to_string(),
+                4 => "RRD".to_string(),
+                5 => "RLD".to_string(),
+                _ => db(&[0xED, op]),
+            },
+        },
+        2 if z <= 3 && y >= 4 => BLOCK[(y - 4) as usize][z as usize].to_string(),
+        _ => db(&[0xED, op]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(code: &[u8]) -> Vec<String> {
+        disassemble(code, 0).into_iter().map(|i| i.text).collect()
+    }
+
+    #[test]
+    fn test_basic_instructions() {
+        assert_eq!(
+            dis(&[0x31, 0xFE, 0xFF, 0xF3, 0x7E, 0xFE, 0xF0, 0xCA, 0x34, 0x12, 0x76]),
+            vec!["LD SP,0xFFFE", "DI", "LD A,(HL)", "CP 0xF0", "JP Z,0x1234", "HALT"]
+        );
+    }
+
+    #[test]
+    fn test_prefixed_instructions() {
+        assert_eq!(
+            dis(&[0xED, 0x5B, 0x06, 0x30, 0xED, 0x52, 0xCB, 0x7C, 0xED, 0x4D]),
+            vec!["LD DE,(0x3006)", "SBC HL,DE", "BIT 7,H", "RETI"]
+        );
+    }
+
+    #[test]
+    fn test_index_registers() {
+        assert_eq!(
+            dis(&[0xDD, 0x21, 0x00, 0x40, 0xDD, 0x7E, 0xFE, 0xFD, 0x66, 0x03, 0xDD, 0xCB, 0x02, 0x46]),
+            vec!["LD IX,0x4000", "LD A,(IX-0x02)", "LD H,(IY+0x03)", "BIT 0,(IX+0x02)"]
+        );
+    }
+
+    #[test]
+    fn test_relative_jumps() {
+        let instrs = disassemble(&[0x00, 0x18, 0xFD, 0x10, 0xFB], 0x100);
+        assert_eq!(instrs[1].text, "JR 0x0100");
+        assert_eq!(instrs[2].text, "DJNZ 0x0100");
+    }
+
+    #[test]
+    fn test_render_source() {
+        // JP over a data word and a RET at a duplicated label
+        let code = [0xC3, 0x05, 0x01, 0x34, 0x12, 0x18, 0xFE, 0xC9];
+        let symbols = vec![
+            ("start".to_string(), 0x100),
+            ("loop".to_string(), 0x105),
+            ("loop".to_string(), 0x107),
+        ];
+        let src = render_source(&code, 0x100, &symbols, &[], &[(0x103, 2)]);
+        let lines: Vec<&str> = src.lines().collect();
+        assert_eq!(lines, vec![
+            "start:",
+            "        JP loop                 ; 0100  C3 05 01",
+            "        DB 0x34,0x12            ; 0103  34 12",
+            "loop:",
+            "        JR loop                 ; 0105  18 FE",
+            "loop_2:",
+            "        RET                     ; 0107  C9",
+        ]);
+    }
+
+    #[test]
+    fn test_render_source_equates() {
+        // LD (nn),HL and LD A,(nn) use the equate, LD HL,nn keeps the number
+        let code = [0x22, 0x00, 0x30, 0x3A, 0x02, 0x30, 0x21, 0x00, 0x30];
+        let src = render_source(&code, 0, &[], &[("VM_SP", 0x3000), ("VM_FP", 0x3002)], &[]);
+        let text: Vec<&str> = src.lines().map(|l| l.split(';').next().unwrap().trim()).collect();
+        assert_eq!(text, vec!["LD (VM_SP),HL", "LD A,(VM_FP)", "LD HL,0x3000"]);
+    }
+
+    #[test]
+    fn test_render_source_splits_at_labels() {
+        // A label inside what would decode as LD HL,nn keeps the bytes as data
+        let src = render_source(&[0x21, 0x00, 0xC9], 0, &[("mid".to_string(), 2)], &[], &[]);
+        assert_eq!(src.lines().collect::<Vec<_>>(), vec![
+            "        DB 0x21,0x00            ; 0000  21 00",
+            "mid:",
+            "        RET                     ; 0002  C9",
+        ]);
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let instrs = disassemble(&[0x00, 0xC3, 0x12], 0);
+        assert_eq!(instrs[1].text, "DB 0xC3,0x12");
+    }
+}
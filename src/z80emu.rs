@@ -0,0 +1,1536 @@
+//! Embedded Z80 emulator
+//!
+//! A cycle-counted Z80 core with the RetroShield console on ports 0 and 1,
+//! used to run generated ROMs without external hardware or emulators.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::storage::{self, Bridge, Storage};
+
+/// Console data port
+const PORT_CONSOLE: u8 = 0x00;
+
+/// Console status port (bit 0 set when a received byte is waiting)
+const PORT_STATUS: u8 = 0x01;
+
+/// Error output port by default
+const PORT_ERROR: u8 = 0x02;
+
+/// Port a `--trace-rom` runtime writes opcodes to by default
+const PORT_TRACE: u8 = 0x03;
+
+/// Channel 0 of the CTC, as on the RC2014 CTC module; channels 1 to 3
+/// follow
+pub const PORT_CTC: u8 = 0x88;
+
+/// Flag register bits
+const FLAG_C: u8 = 0x01;
+const FLAG_N: u8 = 0x02;
+const FLAG_PV: u8 = 0x04;
+const FLAG_X: u8 = 0x08;
+const FLAG_H: u8 = 0x10;
+const FLAG_Y: u8 = 0x20;
+const FLAG_Z: u8 = 0x40;
+const FLAG_S: u8 = 0x80;
+
+/// T-states for unprefixed opcodes (conditional branches use the not-taken
+/// time; the taken penalty is added when executing)
+const CYCLES: [u8; 256] = [
+    4, 10, 7, 6, 4, 4, 7, 4, 4, 11, 7, 6, 4, 4, 7, 4, // 0x00
+    8, 10, 7, 6, 4, 4, 7, 4, 12, 11, 7, 6, 4, 4, 7, 4, // 0x10
+    7, 10, 16, 6, 4, 4, 7, 4, 7, 11, 16, 6, 4, 4, 7, 4, // 0x20
+    7, 10, 13, 6, 11, 11, 10, 4, 7, 11, 13, 6, 4, 4, 7, 4, // 0x30
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x40
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x50
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x60
+    7, 7, 7, 7, 7, 7, 4, 7, 4, 4, 4, 4, 4, 4, 7, 4, // 0x70
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x80
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x90
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0xA0
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0xB0
+    5, 10, 10, 10, 10, 11, 7, 11, 5, 10, 10, 0, 10, 17, 7, 11, // 0xC0
+    5, 10, 10, 11, 10, 11, 7, 11, 5, 4, 10, 11, 10, 0, 7, 11, // 0xD0
+    5, 10, 10, 19, 10, 11, 7, 11, 5, 4, 10, 4, 10, 0, 7, 11, // 0xE0
+    5, 10, 10, 4, 10, 11, 7, 11, 5, 6, 10, 4, 10, 0, 7, 11, // 0xF0
+];
+
+/// Port I/O devices attached to the CPU
+pub trait Io {
+    /// Read from an I/O port
+    fn input(&mut self, port: u8) -> u8;
+
+    /// Write to an I/O port
+    fn output(&mut self, port: u8, value: u8);
+
+    /// Whether a device is asserting the maskable interrupt line
+    fn interrupt(&mut self) -> bool {
+        false
+    }
+
+    /// Whether the program is waiting for input that will never arrive
+    fn input_exhausted(&self) -> bool {
+        false
+    }
+
+    /// Whether no input is waiting but more may still arrive, so a program
+    /// can do something else meanwhile
+    fn input_pending(&mut self) -> bool {
+        false
+    }
+
+    /// The machine has run `cycles` T-states in all
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+/// Z80 register file
+#[derive(Debug, Clone)]
+pub struct Cpu {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub af_alt: u16,
+    pub bc_alt: u16,
+    pub de_alt: u16,
+    pub hl_alt: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+    pub halted: bool,
+    /// T-states executed since reset
+    pub cycles: u64,
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Cpu {
+            a: 0xFF,
+            f: 0xFF,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            af_alt: 0,
+            bc_alt: 0,
+            de_alt: 0,
+            hl_alt: 0,
+            ix: 0,
+            iy: 0,
+            sp: 0xFFFF,
+            pc: 0,
+            i: 0,
+            r: 0,
+            iff1: false,
+            iff2: false,
+            im: 0,
+            halted: false,
+            cycles: 0,
+        }
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_le_bytes([self.c, self.b])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_le_bytes([self.e, self.d])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_le_bytes([self.l, self.h])
+    }
+
+    pub fn af(&self) -> u16 {
+        u16::from_le_bytes([self.f, self.a])
+    }
+
+    pub fn set_bc(&mut self, v: u16) {
+        [self.c, self.b] = v.to_le_bytes();
+    }
+
+    pub fn set_de(&mut self, v: u16) {
+        [self.e, self.d] = v.to_le_bytes();
+    }
+
+    pub fn set_hl(&mut self, v: u16) {
+        [self.l, self.h] = v.to_le_bytes();
+    }
+
+    pub fn set_af(&mut self, v: u16) {
+        [self.f, self.a] = v.to_le_bytes();
+    }
+
+    fn flag(&self, mask: u8) -> bool {
+        self.f & mask != 0
+    }
+
+    /// Evaluate condition code cc (NZ, Z, NC, C, PO, PE, P, M)
+    fn condition(&self, cc: u8) -> bool {
+        match cc {
+            0 => !self.flag(FLAG_Z),
+            1 => self.flag(FLAG_Z),
+            2 => !self.flag(FLAG_C),
+            3 => self.flag(FLAG_C),
+            4 => !self.flag(FLAG_PV),
+            5 => self.flag(FLAG_PV),
+            6 => !self.flag(FLAG_S),
+            _ => self.flag(FLAG_S),
+        }
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// HALT executed with interrupts disabled
+    Halted,
+    /// The cycle budget ran out
+    CycleLimit,
+    /// The program polled for input after the input ended
+    InputExhausted,
+}
+
+/// Register used in place of HL by DD/FD prefixed instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+/// A Z80 with 64K of memory, the low part of which is write-protected ROM
+pub struct Machine<T: Io> {
+    pub cpu: Cpu,
+    pub mem: Vec<u8>,
+    pub io: T,
+    rom_len: usize,
+    /// Effective address of the (IX+d) operand of the current instruction
+    ea: Option<u16>,
+    /// Interrupts are not accepted in the instruction following EI
+    after_ei: bool,
+}
+
+impl<T: Io> Machine<T> {
+    /// Create a machine with `rom` mapped at 0x0000
+    pub fn new(rom: &[u8], io: T) -> Self {
+        let mut mem = vec![0; 0x10000];
+        let rom_len = rom.len().min(mem.len());
+        mem[..rom_len].copy_from_slice(&rom[..rom_len]);
+        Machine {
+            cpu: Cpu::new(),
+            mem,
+            io,
+            rom_len,
+            ea: None,
+            after_ei: false,
+        }
+    }
+
+    /// Run until the CPU halts, the input runs dry or `max_cycles` T-states
+    /// have elapsed
+    pub fn run(&mut self, max_cycles: Option<u64>) -> Exit {
+        loop {
+            if let Some(exit) = self.stopped(max_cycles) {
+                return exit;
+            }
+            self.step();
+        }
+    }
+
+    /// Why the machine can make no further progress, if it can't
+    pub fn stopped(&self, max_cycles: Option<u64>) -> Option<Exit> {
+        if self.cpu.halted && !self.cpu.iff1 {
+            Some(Exit::Halted)
+        } else if self.io.input_exhausted() {
+            Some(Exit::InputExhausted)
+        } else if max_cycles.is_some_and(|max| self.cpu.cycles >= max) {
+            Some(Exit::CycleLimit)
+        } else {
+            None
+        }
+    }
+
+    /// Execute one instruction (or one halted cycle) and service a pending
+    /// interrupt. Returns the T-states taken.
+    pub fn step(&mut self) -> u32 {
+        let mut t = if self.cpu.halted {
+            self.refresh();
+            4
+        } else {
+            let op = self.m1();
+            self.execute(op, Index::HL)
+        };
+        if self.after_ei {
+            self.after_ei = false;
+        } else if self.cpu.iff1 && self.io.interrupt() {
+            t += self.accept_interrupt();
+        }
+        self.cpu.cycles += t as u64;
+        self.io.tick(self.cpu.cycles);
+        t
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        if addr as usize >= self.rom_len {
+            self.mem[addr as usize] = value;
+        }
+    }
+
+    pub fn read16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    pub fn write16(&mut self, addr: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
+    fn refresh(&mut self) {
+        self.cpu.r = (self.cpu.r & 0x80) | (self.cpu.r.wrapping_add(1) & 0x7F);
+    }
+
+    /// Opcode fetch (M1 cycle)
+    fn m1(&mut self) -> u8 {
+        self.refresh();
+        self.fetch()
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let b = self.read(self.cpu.pc);
+        self.cpu.pc = self.cpu.pc.wrapping_add(1);
+        b
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let lo = self.fetch();
+        let hi = self.fetch();
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn push(&mut self, value: u16) {
+        self.cpu.sp = self.cpu.sp.wrapping_sub(2);
+        self.write16(self.cpu.sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let v = self.read16(self.cpu.sp);
+        self.cpu.sp = self.cpu.sp.wrapping_add(2);
+        v
+    }
+
+    fn accept_interrupt(&mut self) -> u32 {
+        self.cpu.halted = false;
+        self.cpu.iff1 = false;
+        self.cpu.iff2 = false;
+        self.refresh();
+        self.push(self.cpu.pc);
+        if self.cpu.im == 2 {
+            // The data bus floats high, so the vector low byte is 0xFF
+            let vector = u16::from_le_bytes([0xFF, self.cpu.i]);
+            self.cpu.pc = self.read16(vector);
+            19
+        } else {
+            // IM 0 sees RST 38h on the floating bus, same as IM 1
+            self.cpu.pc = 0x0038;
+            13
+        }
+    }
+
+    fn index_reg(&self, idx: Index) -> u16 {
+        match idx {
+            Index::HL => self.cpu.hl(),
+            Index::IX => self.cpu.ix,
+            Index::IY => self.cpu.iy,
+        }
+    }
+
+    fn set_index_reg(&mut self, idx: Index, v: u16) {
+        match idx {
+            Index::HL => self.cpu.set_hl(v),
+            Index::IX => self.cpu.ix = v,
+            Index::IY => self.cpu.iy = v,
+        }
+    }
+
+    /// Address of the memory operand: HL, or IX/IY plus a displacement that
+    /// is fetched once per instruction
+    fn operand_addr(&mut self, idx: Index) -> u16 {
+        if idx == Index::HL {
+            return self.cpu.hl();
+        }
+        if let Some(addr) = self.ea {
+            return addr;
+        }
+        let d = self.fetch() as i8;
+        let addr = self.index_reg(idx).wrapping_add(d as u16);
+        self.ea = Some(addr);
+        addr
+    }
+
+    /// Read 8-bit register r (B, C, D, E, H, L, (HL), A)
+    fn get_r(&mut self, r: u8, idx: Index) -> u8 {
+        match r {
+            0 => self.cpu.b,
+            1 => self.cpu.c,
+            2 => self.cpu.d,
+            3 => self.cpu.e,
+            4 => (self.index_reg(idx) >> 8) as u8,
+            5 => self.index_reg(idx) as u8,
+            6 => {
+                let addr = self.operand_addr(idx);
+                self.read(addr)
+            }
+            _ => self.cpu.a,
+        }
+    }
+
+    fn set_r(&mut self, r: u8, idx: Index, v: u8) {
+        match r {
+            0 => self.cpu.b = v,
+            1 => self.cpu.c = v,
+            2 => self.cpu.d = v,
+            3 => self.cpu.e = v,
+            4 => {
+                let w = self.index_reg(idx);
+                self.set_index_reg(idx, (w & 0x00FF) | ((v as u16) << 8));
+            }
+            5 => {
+                let w = self.index_reg(idx);
+                self.set_index_reg(idx, (w & 0xFF00) | v as u16);
+            }
+            6 => {
+                let addr = self.operand_addr(idx);
+                self.write(addr, v);
+            }
+            _ => self.cpu.a = v,
+        }
+    }
+
+    /// Register pair rp (BC, DE, HL, SP)
+    fn get_rp(&self, p: u8, idx: Index) -> u16 {
+        match p {
+            0 => self.cpu.bc(),
+            1 => self.cpu.de(),
+            2 => self.index_reg(idx),
+            _ => self.cpu.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, idx: Index, v: u16) {
+        match p {
+            0 => self.cpu.set_bc(v),
+            1 => self.cpu.set_de(v),
+            2 => self.set_index_reg(idx, v),
+            _ => self.cpu.sp = v,
+        }
+    }
+
+    /// Register pair rp2 (BC, DE, HL, AF)
+    fn get_rp2(&self, p: u8, idx: Index) -> u16 {
+        if p == 3 { self.cpu.af() } else { self.get_rp(p, idx) }
+    }
+
+    fn set_rp2(&mut self, p: u8, idx: Index, v: u16) {
+        if p == 3 { self.cpu.set_af(v) } else { self.set_rp(p, idx, v) }
+    }
+
+    fn jump_relative(&mut self, d: i8) {
+        self.cpu.pc = self.cpu.pc.wrapping_add(d as u16);
+    }
+
+    fn execute(&mut self, op: u8, idx: Index) -> u32 {
+        match op {
+            0xCB if idx == Index::HL => return self.execute_cb(),
+            0xCB => return self.execute_index_cb(idx),
+            // A later prefix overrides an earlier one
+            0xDD => {
+                let op = self.m1();
+                return 4 + self.execute(op, Index::IX);
+            }
+            0xFD => {
+                let op = self.m1();
+                return 4 + self.execute(op, Index::IY);
+            }
+            0xED => {
+                let op = self.m1();
+                return self.execute_ed(op);
+            }
+            _ => {}
+        }
+
+        self.ea = None;
+        let t = self.execute_base(op, idx);
+        match self.ea {
+            Some(_) if op == 0x36 => t + 5,
+            Some(_) => t + 8,
+            None => t,
+        }
+    }
+
+    fn execute_base(&mut self, op: u8, idx: Index) -> u32 {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        let t = CYCLES[op as usize] as u32;
+
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => {}
+                    1 => {
+                        let af = self.cpu.af();
+                        self.cpu.set_af(self.cpu.af_alt);
+                        self.cpu.af_alt = af;
+                    }
+                    2 => {
+                        let d = self.fetch() as i8;
+                        self.cpu.b = self.cpu.b.wrapping_sub(1);
+                        if self.cpu.b != 0 {
+                            self.jump_relative(d);
+                            return t + 5;
+                        }
+                    }
+                    3 => {
+                        let d = self.fetch() as i8;
+                        self.jump_relative(d);
+                    }
+                    _ => {
+                        let d = self.fetch() as i8;
+                        if self.cpu.condition(y - 4) {
+                            self.jump_relative(d);
+                            return t + 5;
+                        }
+                    }
+                },
+                1 => {
+                    if q == 0 {
+                        let nn = self.fetch16();
+                        self.set_rp(p, idx, nn);
+                    } else {
+                        let v = self.add16(self.index_reg(idx), self.get_rp(p, idx));
+                        self.set_index_reg(idx, v);
+                    }
+                }
+                2 => match (q, p) {
+                    (0, 0) => self.write(self.cpu.bc(), self.cpu.a),
+                    (0, 1) => self.write(self.cpu.de(), self.cpu.a),
+                    (0, 2) => {
+                        let nn = self.fetch16();
+                        self.write16(nn, self.index_reg(idx));
+                    }
+                    (0, _) => {
+                        let nn = self.fetch16();
+                        self.write(nn, self.cpu.a);
+                    }
+                    (_, 0) => self.cpu.a = self.read(self.cpu.bc()),
+                    (_, 1) => self.cpu.a = self.read(self.cpu.de()),
+                    (_, 2) => {
+                        let nn = self.fetch16();
+                        let v = self.read16(nn);
+                        self.set_index_reg(idx, v);
+                    }
+                    (_, _) => {
+                        let nn = self.fetch16();
+                        self.cpu.a = self.read(nn);
+                    }
+                },
+                3 => {
+                    let v = self.get_rp(p, idx);
+                    let v = if q == 0 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                    self.set_rp(p, idx, v);
+                }
+                4 => {
+                    let v = self.get_r(y, idx);
+                    let r = self.inc8(v);
+                    self.set_r(y, idx, r);
+                }
+                5 => {
+                    let v = self.get_r(y, idx);
+                    let r = self.dec8(v);
+                    self.set_r(y, idx, r);
+                }
+                6 => {
+                    // The displacement precedes the immediate
+                    if y == 6 {
+                        self.operand_addr(idx);
+                    }
+                    let n = self.fetch();
+                    self.set_r(y, idx, n);
+                }
+                _ => self.accumulator_op(y),
+            },
+            1 => {
+                if y == 6 && z == 6 {
+                    self.cpu.halted = true;
+                } else {
+                    // With (IX+d) the other operand keeps plain H/L
+                    let half = if y == 6 || z == 6 { Index::HL } else { idx };
+                    let v = self.get_r(z, if z == 6 { idx } else { half });
+                    self.set_r(y, if y == 6 { idx } else { half }, v);
+                }
+            }
+            2 => {
+                let v = self.get_r(z, idx);
+                self.alu(y, v);
+            }
+            _ => match z {
+                0 => {
+                    if self.cpu.condition(y) {
+                        self.cpu.pc = self.pop();
+                        return t + 6;
+                    }
+                }
+                1 => {
+                    if q == 0 {
+                        let v = self.pop();
+                        self.set_rp2(p, idx, v);
+                    } else {
+                        match p {
+                            0 => self.cpu.pc = self.pop(),
+                            1 => {
+                                let (bc, de, hl) = (self.cpu.bc(), self.cpu.de(), self.cpu.hl());
+                                self.cpu.set_bc(self.cpu.bc_alt);
+                                self.cpu.set_de(self.cpu.de_alt);
+                                self.cpu.set_hl(self.cpu.hl_alt);
+                                self.cpu.bc_alt = bc;
+                                self.cpu.de_alt = de;
+                                self.cpu.hl_alt = hl;
+                            }
+                            2 => self.cpu.pc = self.index_reg(idx),
+                            _ => self.cpu.sp = self.index_reg(idx),
+                        }
+                    }
+                }
+                2 => {
+                    let nn = self.fetch16();
+                    if self.cpu.condition(y) {
+                        self.cpu.pc = nn;
+                    }
+                }
+                3 => match y {
+                    0 => self.cpu.pc = self.fetch16(),
+                    2 => {
+                        let n = self.fetch();
+                        self.io.output(n, self.cpu.a);
+                    }
+                    3 => {
+                        let n = self.fetch();
+                        self.cpu.a = self.io.input(n);
+                    }
+                    4 => {
+                        let v = self.read16(self.cpu.sp);
+                        self.write16(self.cpu.sp, self.index_reg(idx));
+                        self.set_index_reg(idx, v);
+                    }
+                    5 => {
+                        let de = self.cpu.de();
+                        self.cpu.set_de(self.cpu.hl());
+                        self.cpu.set_hl(de);
+                    }
+                    6 => {
+                        self.cpu.iff1 = false;
+                        self.cpu.iff2 = false;
+                    }
+                    7 => {
+                        self.cpu.iff1 = true;
+                        self.cpu.iff2 = true;
+                        self.after_ei = true;
+                    }
+                    _ => unreachable!("CB prefix handled in execute"),
+                },
+                4 => {
+                    let nn = self.fetch16();
+                    if self.cpu.condition(y) {
+                        self.push(self.cpu.pc);
+                        self.cpu.pc = nn;
+                        return t + 7;
+                    }
+                }
+                5 => {
+                    if q == 0 {
+                        self.push(self.get_rp2(p, idx));
+                    } else {
+                        // p == 0: the other values are prefixes handled in execute
+                        let nn = self.fetch16();
+                        self.push(self.cpu.pc);
+                        self.cpu.pc = nn;
+                    }
+                }
+                6 => {
+                    let n = self.fetch();
+                    self.alu(y, n);
+                }
+                _ => {
+                    self.push(self.cpu.pc);
+                    self.cpu.pc = (y as u16) * 8;
+                }
+            },
+        }
+        t
+    }
+
+    fn execute_cb(&mut self) -> u32 {
+        let op = self.m1();
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let v = self.get_r(z, Index::HL);
+        match x {
+            0 => {
+                let r = self.rotate(y, v);
+                self.set_r(z, Index::HL, r);
+            }
+            1 => {
+                self.bit(y, v, v);
+                return if z == 6 { 12 } else { 8 };
+            }
+            2 => self.set_r(z, Index::HL, v & !(1 << y)),
+            _ => self.set_r(z, Index::HL, v | (1 << y)),
+        }
+        if z == 6 { 15 } else { 8 }
+    }
+
+    /// DDCB/FDCB: displacement, then opcode. Results are also copied to
+    /// the register named by the low bits (undocumented but relied upon).
+    fn execute_index_cb(&mut self, idx: Index) -> u32 {
+        let d = self.fetch() as i8;
+        let op = self.fetch();
+        let addr = self.index_reg(idx).wrapping_add(d as u16);
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let v = self.read(addr);
+        let r = match x {
+            0 => self.rotate(y, v),
+            1 => {
+                self.bit(y, v, (addr >> 8) as u8);
+                return 16;
+            }
+            2 => v & !(1 << y),
+            _ => v | (1 << y),
+        };
+        self.write(addr, r);
+        if z != 6 {
+            self.set_r(z, Index::HL, r);
+        }
+        19
+    }
+
+    fn execute_ed(&mut self, op: u8) -> u32 {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match (x, z) {
+            (1, 0) => {
+                let v = self.io.input(self.cpu.c);
+                self.cpu.f = (self.cpu.f & FLAG_C) | szp(v);
+                if y != 6 {
+                    self.set_r(y, Index::HL, v);
+                }
+                12
+            }
+            (1, 1) => {
+                let v = if y == 6 { 0 } else { self.get_r(y, Index::HL) };
+                self.io.output(self.cpu.c, v);
+                12
+            }
+            (1, 2) => {
+                let hl = self.cpu.hl();
+                let rp = self.get_rp(p, Index::HL);
+                let v = if q == 0 { self.sbc16(hl, rp) } else { self.adc16(hl, rp) };
+                self.cpu.set_hl(v);
+                15
+            }
+            (1, 3) => {
+                let nn = self.fetch16();
+                if q == 0 {
+                    self.write16(nn, self.get_rp(p, Index::HL));
+                } else {
+                    let v = self.read16(nn);
+                    self.set_rp(p, Index::HL, v);
+                }
+                20
+            }
+            (1, 4) => {
+                self.cpu.a = self.sub8(0, self.cpu.a, 0);
+                8
+            }
+            (1, 5) => {
+                // RETN and RETI both restore IFF1 from IFF2
+                self.cpu.pc = self.pop();
+                self.cpu.iff1 = self.cpu.iff2;
+                14
+            }
+            (1, 6) => {
+                self.cpu.im = [0, 0, 1, 2][(y & 3) as usize];
+                8
+            }
+            (1, 7) => match y {
+                0 => {
+                    self.cpu.i = self.cpu.a;
+                    9
+                }
+                1 => {
+                    self.cpu.r = self.cpu.a;
+                    9
+                }
+                2 | 3 => {
+                    let v = if y == 2 { self.cpu.i } else { self.cpu.r };
+                    self.cpu.a = v;
+                    let pv = if self.cpu.iff2 { FLAG_PV } else { 0 };
+                    self.cpu.f = (self.cpu.f & FLAG_C) | sz(v) | pv;
+                    9
+                }
+                4 | 5 => {
+                    let hl = self.cpu.hl();
+                    let m = self.read(hl);
+                    let a = self.cpu.a;
+                    let (m, a) = if y == 4 {
+                        ((a << 4) | (m >> 4), (a & 0xF0) | (m & 0x0F))
+                    } else {
+                        ((m << 4) | (a & 0x0F), (a & 0xF0) | (m >> 4))
+                    };
+                    self.write(hl, m);
+                    self.cpu.a = a;
+                    self.cpu.f = (self.cpu.f & FLAG_C) | szp(a);
+                    18
+                }
+                _ => 8,
+            },
+            (2, 0..=3) if y >= 4 => self.block_op(y, z),
+            _ => 8,
+        }
+    }
+
+    /// LDI/CPI/INI/OUTI and their decrementing and repeating forms
+    fn block_op(&mut self, y: u8, z: u8) -> u32 {
+        let step: u16 = if y & 1 == 0 { 1 } else { 0xFFFF };
+        let repeat = y >= 6;
+        let hl = self.cpu.hl();
+        self.cpu.set_hl(hl.wrapping_add(step));
+
+        let again = match z {
+            0 => {
+                let v = self.read(hl);
+                let de = self.cpu.de();
+                self.write(de, v);
+                self.cpu.set_de(de.wrapping_add(step));
+                let bc = self.cpu.bc().wrapping_sub(1);
+                self.cpu.set_bc(bc);
+                let n = v.wrapping_add(self.cpu.a);
+                let pv = if bc != 0 { FLAG_PV } else { 0 };
+                self.cpu.f = (self.cpu.f & (FLAG_S | FLAG_Z | FLAG_C))
+                    | pv
+                    | (n & FLAG_X)
+                    | ((n << 4) & FLAG_Y);
+                bc != 0
+            }
+            1 => {
+                let v = self.read(hl);
+                let r = self.cpu.a.wrapping_sub(v);
+                let bc = self.cpu.bc().wrapping_sub(1);
+                self.cpu.set_bc(bc);
+                let h = if (self.cpu.a ^ v ^ r) & 0x10 != 0 { FLAG_H } else { 0 };
+                let pv = if bc != 0 { FLAG_PV } else { 0 };
+                self.cpu.f = (self.cpu.f & FLAG_C) | FLAG_N | sz(r) | h | pv;
+                bc != 0 && r != 0
+            }
+            2 => {
+                let v = self.io.input(self.cpu.c);
+                self.write(hl, v);
+                self.cpu.b = self.cpu.b.wrapping_sub(1);
+                self.cpu.f = sz(self.cpu.b) | FLAG_N;
+                self.cpu.b != 0
+            }
+            _ => {
+                let v = self.read(hl);
+                self.cpu.b = self.cpu.b.wrapping_sub(1);
+                self.io.output(self.cpu.c, v);
+                self.cpu.f = sz(self.cpu.b) | FLAG_N;
+                self.cpu.b != 0
+            }
+        };
+
+        if repeat && again {
+            self.cpu.pc = self.cpu.pc.wrapping_sub(2);
+            21
+        } else {
+            16
+        }
+    }
+
+    /// RLCA, RRCA, RLA, RRA, DAA, CPL, SCF, CCF
+    fn accumulator_op(&mut self, y: u8) {
+        let a = self.cpu.a;
+        let f = self.cpu.f;
+        let keep = f & (FLAG_S | FLAG_Z | FLAG_PV);
+        let carry = f & FLAG_C;
+        match y {
+            0..=3 => {
+                let (r, c) = match y {
+                    0 => (a.rotate_left(1), a >> 7),
+                    1 => (a.rotate_right(1), a & 1),
+                    2 => ((a << 1) | carry, a >> 7),
+                    _ => ((a >> 1) | (carry << 7), a & 1),
+                };
+                self.cpu.a = r;
+                self.cpu.f = keep | xy(r) | c;
+            }
+            4 => {
+                let mut correction = 0;
+                let mut c = carry;
+                if f & FLAG_H != 0 || a & 0x0F > 9 {
+                    correction |= 0x06;
+                }
+                if carry != 0 || a > 0x99 {
+                    correction |= 0x60;
+                    c = FLAG_C;
+                }
+                let (r, h) = if f & FLAG_N != 0 {
+                    (a.wrapping_sub(correction), f & FLAG_H != 0 && a & 0x0F < 6)
+                } else {
+                    (a.wrapping_add(correction), a & 0x0F > 9)
+                };
+                self.cpu.a = r;
+                self.cpu.f = szp(r) | (f & FLAG_N) | c | if h { FLAG_H } else { 0 };
+            }
+            5 => {
+                self.cpu.a = !a;
+                self.cpu.f = (f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C)) | FLAG_H | FLAG_N | xy(!a);
+            }
+            6 => self.cpu.f = keep | xy(a) | FLAG_C,
+            _ => {
+                let h = if carry != 0 { FLAG_H } else { 0 };
+                self.cpu.f = keep | xy(a) | h | (carry ^ FLAG_C);
+            }
+        }
+    }
+
+    /// ADD, ADC, SUB, SBC, AND, XOR, OR, CP against A
+    fn alu(&mut self, op: u8, v: u8) {
+        let a = self.cpu.a;
+        let carry = self.cpu.f & FLAG_C;
+        match op {
+            0 => self.cpu.a = self.add8(a, v, 0),
+            1 => self.cpu.a = self.add8(a, v, carry),
+            2 => self.cpu.a = self.sub8(a, v, 0),
+            3 => self.cpu.a = self.sub8(a, v, carry),
+            4 => {
+                self.cpu.a = a & v;
+                self.cpu.f = szp(self.cpu.a) | FLAG_H;
+            }
+            5 => {
+                self.cpu.a = a ^ v;
+                self.cpu.f = szp(self.cpu.a);
+            }
+            6 => {
+                self.cpu.a = a | v;
+                self.cpu.f = szp(self.cpu.a);
+            }
+            _ => {
+                self.sub8(a, v, 0);
+                self.cpu.f = (self.cpu.f & !(FLAG_X | FLAG_Y)) | xy(v);
+            }
+        }
+    }
+
+    fn add8(&mut self, a: u8, b: u8, carry: u8) -> u8 {
+        let wide = a as u16 + b as u16 + carry as u16;
+        let r = wide as u8;
+        let h = if (a ^ b ^ r) & 0x10 != 0 { FLAG_H } else { 0 };
+        let v = if (a ^ !b) & (a ^ r) & 0x80 != 0 { FLAG_PV } else { 0 };
+        let c = if wide > 0xFF { FLAG_C } else { 0 };
+        self.cpu.f = sz(r) | h | v | c;
+        r
+    }
+
+    fn sub8(&mut self, a: u8, b: u8, carry: u8) -> u8 {
+        let wide = (a as u16).wrapping_sub(b as u16).wrapping_sub(carry as u16);
+        let r = wide as u8;
+        let h = if (a ^ b ^ r) & 0x10 != 0 { FLAG_H } else { 0 };
+        let v = if (a ^ b) & (a ^ r) & 0x80 != 0 { FLAG_PV } else { 0 };
+        let c = if wide > 0xFF { FLAG_C } else { 0 };
+        self.cpu.f = sz(r) | h | v | c | FLAG_N;
+        r
+    }
+
+    fn inc8(&mut self, v: u8) -> u8 {
+        let r = v.wrapping_add(1);
+        let h = if v & 0x0F == 0x0F { FLAG_H } else { 0 };
+        let pv = if v == 0x7F { FLAG_PV } else { 0 };
+        self.cpu.f = (self.cpu.f & FLAG_C) | sz(r) | h | pv;
+        r
+    }
+
+    fn dec8(&mut self, v: u8) -> u8 {
+        let r = v.wrapping_sub(1);
+        let h = if v & 0x0F == 0 { FLAG_H } else { 0 };
+        let pv = if v == 0x80 { FLAG_PV } else { 0 };
+        self.cpu.f = (self.cpu.f & FLAG_C) | sz(r) | h | pv | FLAG_N;
+        r
+    }
+
+    fn add16(&mut self, a: u16, b: u16) -> u16 {
+        let wide = a as u32 + b as u32;
+        let r = wide as u16;
+        let h = if (a ^ b ^ r) & 0x1000 != 0 { FLAG_H } else { 0 };
+        let c = if wide > 0xFFFF { FLAG_C } else { 0 };
+        self.cpu.f = (self.cpu.f & (FLAG_S | FLAG_Z | FLAG_PV)) | xy((r >> 8) as u8) | h | c;
+        r
+    }
+
+    fn adc16(&mut self, a: u16, b: u16) -> u16 {
+        let wide = a as u32 + b as u32 + (self.cpu.f & FLAG_C) as u32;
+        let r = wide as u16;
+        let h = if (a ^ b ^ r) & 0x1000 != 0 { FLAG_H } else { 0 };
+        let v = if !(a ^ b) & (a ^ r) & 0x8000 != 0 { FLAG_PV } else { 0 };
+        let c = if wide > 0xFFFF { FLAG_C } else { 0 };
+        self.cpu.f = sz16(r) | h | v | c;
+        r
+    }
+
+    fn sbc16(&mut self, a: u16, b: u16) -> u16 {
+        let wide = (a as u32).wrapping_sub(b as u32).wrapping_sub((self.cpu.f & FLAG_C) as u32);
+        let r = wide as u16;
+        let h = if (a ^ b ^ r) & 0x1000 != 0 { FLAG_H } else { 0 };
+        let v = if (a ^ b) & (a ^ r) & 0x8000 != 0 { FLAG_PV } else { 0 };
+        let c = if wide > 0xFFFF { FLAG_C } else { 0 };
+        self.cpu.f = sz16(r) | h | v | c | FLAG_N;
+        r
+    }
+
+    /// CB-prefixed shifts and rotates
+    fn rotate(&mut self, op: u8, v: u8) -> u8 {
+        let carry = self.cpu.f & FLAG_C;
+        let (r, c) = match op {
+            0 => (v.rotate_left(1), v >> 7),
+            1 => (v.rotate_right(1), v & 1),
+            2 => ((v << 1) | carry, v >> 7),
+            3 => ((v >> 1) | (carry << 7), v & 1),
+            4 => (v << 1, v >> 7),
+            5 => ((v >> 1) | (v & 0x80), v & 1),
+            6 => ((v << 1) | 1, v >> 7),
+            _ => (v >> 1, v & 1),
+        };
+        self.cpu.f = szp(r) | c;
+        r
+    }
+
+    /// BIT n: X/Y come from `xy_src`, which differs for (IX+d)
+    fn bit(&mut self, n: u8, v: u8, xy_src: u8) {
+        let set = v & (1 << n) != 0;
+        let zpv = if set { 0 } else { FLAG_Z | FLAG_PV };
+        let s = if n == 7 && set { FLAG_S } else { 0 };
+        self.cpu.f = (self.cpu.f & FLAG_C) | FLAG_H | zpv | s | xy(xy_src);
+    }
+}
+
+/// Undocumented X and Y flags copied from a result
+fn xy(v: u8) -> u8 {
+    v & (FLAG_X | FLAG_Y)
+}
+
+/// Sign, zero, X and Y flags for an 8-bit result
+fn sz(v: u8) -> u8 {
+    let z = if v == 0 { FLAG_Z } else { 0 };
+    (v & FLAG_S) | z | xy(v)
+}
+
+/// Sign, zero and parity flags for an 8-bit result
+fn szp(v: u8) -> u8 {
+    let p = if v.count_ones() & 1 == 0 { FLAG_PV } else { 0 };
+    sz(v) | p
+}
+
+/// Sign, zero, X and Y flags for a 16-bit result
+fn sz16(v: u16) -> u8 {
+    let z = if v == 0 { FLAG_Z } else { 0 };
+    ((v >> 8) as u8 & FLAG_S) | z | xy((v >> 8) as u8)
+}
+
+/// Timer mode of a Z80 CTC: each channel programmed with a control word
+/// and a time constant counts down every 16 or 256 T-states and, with its
+/// interrupt enabled, interrupts when it reaches zero
+#[derive(Default)]
+struct Ctc {
+    channels: [Channel; 4],
+    /// Channels with an interrupt raised and not yet taken, one bit each
+    pending: u8,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Channel {
+    control: u8,
+    /// The next write is the time constant
+    loading: bool,
+    /// T-states between interrupts, 0 when stopped
+    period: u64,
+    /// T-state of the next interrupt
+    next: u64,
+}
+
+impl Ctc {
+    fn output(&mut self, channel: usize, value: u8, now: u64) {
+        let c = &mut self.channels[channel];
+        if c.loading {
+            let constant = if value == 0 { 256 } else { value as u64 };
+            let prescaler = if c.control & 0x20 != 0 { 256 } else { 16 };
+            c.loading = false;
+            c.period = constant * prescaler;
+            c.next = now + c.period;
+        } else if value & 0x01 != 0 {
+            // A control word; with bit 0 clear it is channel 0's vector
+            c.control = value;
+            c.loading = value & 0x04 != 0;
+            if value & 0x02 != 0 {
+                c.period = 0;
+            }
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        for (i, c) in self.channels.iter_mut().enumerate() {
+            while c.period != 0 && c.next <= now {
+                c.next += c.period;
+                if c.control & 0x80 != 0 {
+                    self.pending |= 1 << i;
+                }
+            }
+        }
+    }
+
+    /// Take a pending interrupt, channel 0 first as in the daisy chain
+    fn interrupt(&mut self) -> bool {
+        let taken = self.pending != 0;
+        self.pending &= self.pending.wrapping_sub(1);
+        taken
+    }
+}
+
+/// RetroShield console: data on port 0, status on port 1, errors out on
+/// port 2, a log of the trace port, a CTC from `PORT_CTC`, and optionally
+/// file storage on the `storage` ports. Output is
+/// streamed to a writer or captured; input comes from a script or a live
+/// stream read on a background thread.
+pub struct Console {
+    input: VecDeque<u8>,
+    /// Scripted input not typed yet, in the order it arrives, by T-state
+    pending: VecDeque<(u64, u8)>,
+    source: Option<Receiver<u8>>,
+    sink: Option<Box<dyn Write>>,
+    output: Vec<u8>,
+    /// T-state count when each captured output byte was written
+    stamps: Vec<u64>,
+    now: u64,
+    error_sink: Option<Box<dyn Write>>,
+    errors: Vec<u8>,
+    error_port: u8,
+    trace: Vec<u8>,
+    trace_port: u8,
+    files: Option<Bridge>,
+    /// Bytes written to the other ports, which read back as the last one
+    /// written to them, 0xFF before that
+    ports: Vec<(u8, u8)>,
+    latches: HashMap<u8, u8>,
+    ctc: Ctc,
+    irq: bool,
+    exhausted: bool,
+}
+
+impl Console {
+    /// Console wired to the process's stdin, stdout and stderr
+    pub fn stdio() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in BufReader::new(io::stdin()).bytes() {
+                match byte {
+                    Ok(b) if tx.send(b).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Console {
+            input: VecDeque::new(),
+            pending: VecDeque::new(),
+            source: Some(rx),
+            sink: Some(Box::new(io::stdout())),
+            output: Vec::new(),
+            stamps: Vec::new(),
+            now: 0,
+            error_sink: Some(Box::new(io::stderr())),
+            errors: Vec::new(),
+            error_port: PORT_ERROR,
+            trace: Vec::new(),
+            trace_port: PORT_TRACE,
+            files: None,
+            ports: Vec::new(),
+            latches: HashMap::new(),
+            ctc: Ctc::default(),
+            irq: false,
+            exhausted: false,
+        }
+    }
+
+    /// Console with fixed input whose output is captured
+    pub fn scripted(input: &[u8]) -> Self {
+        Console {
+            input: input.iter().copied().collect(),
+            pending: VecDeque::new(),
+            source: None,
+            sink: None,
+            output: Vec::new(),
+            stamps: Vec::new(),
+            now: 0,
+            error_sink: None,
+            errors: Vec::new(),
+            error_port: PORT_ERROR,
+            trace: Vec::new(),
+            trace_port: PORT_TRACE,
+            files: None,
+            ports: Vec::new(),
+            latches: HashMap::new(),
+            ctc: Ctc::default(),
+            irq: false,
+            exhausted: false,
+        }
+    }
+
+    /// Type `bytes` once the machine has run `cycles` T-states, after the
+    /// input already scripted. A program polling for input waits for them
+    /// rather than finding the input ended.
+    pub fn with_input_at(mut self, cycles: u64, bytes: &[u8]) -> Self {
+        let at = self.pending.partition_point(|&(time, _)| time <= cycles);
+        for (i, &b) in bytes.iter().enumerate() {
+            self.pending.insert(at + i, (cycles, b));
+        }
+        self
+    }
+
+    /// Make `port` read as `value`, as a device there would
+    pub fn with_port(mut self, port: u8, value: u8) -> Self {
+        self.latches.insert(port, value);
+        self
+    }
+
+    /// Raise an interrupt whenever input is waiting (for `--irq-input` ROMs)
+    pub fn with_irq(mut self, irq: bool) -> Self {
+        self.irq = irq;
+        self
+    }
+
+    /// Take STDERR from `port` instead of port 2 (for `--error-port` ROMs)
+    pub fn with_error_port(mut self, port: u8) -> Self {
+        self.error_port = port;
+        self
+    }
+
+    /// Log the trace from `port` instead of port 3
+    pub fn with_trace_port(mut self, port: u8) -> Self {
+        self.trace_port = port;
+        self
+    }
+
+    /// Serve the file natives from `storage`
+    pub fn with_files(mut self, storage: impl Storage + 'static) -> Self {
+        self.files = Some(Bridge::new(storage));
+        self
+    }
+
+    /// Output captured so far (empty when streaming to a writer)
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Captured output split into lines, each with the T-state count when
+    /// the instruction writing its first byte began
+    pub fn timed_output(&self) -> Vec<(u64, String)> {
+        let mut out: Vec<(u64, String)> = Vec::new();
+        for (&b, &time) in self.output.iter().zip(&self.stamps) {
+            match out.last_mut() {
+                Some((_, line)) if !line.ends_with('\n') => line.push(b as char),
+                _ => out.push((time, (b as char).to_string())),
+            }
+        }
+        out
+    }
+
+    /// Error output captured so far (empty when streaming to a writer)
+    pub fn errors(&self) -> &[u8] {
+        &self.errors
+    }
+
+    /// (port, byte) for each write to a port the console doesn't use
+    pub fn port_writes(&self) -> &[(u8, u8)] {
+        &self.ports
+    }
+
+    /// Bytes written to the trace port
+    pub fn trace(&self) -> &[u8] {
+        &self.trace
+    }
+
+    /// Flush streamed output
+    pub fn flush(&mut self) {
+        for sink in [&mut self.sink, &mut self.error_sink].into_iter().flatten() {
+            let _ = sink.flush();
+        }
+    }
+
+    /// Make sure a byte is queued if one is available, optionally waiting
+    /// for the live stream. Returns whether input is waiting.
+    fn fill(&mut self, block: bool) -> bool {
+        if !self.input.is_empty() {
+            return true;
+        }
+        let Some(source) = &self.source else {
+            return false;
+        };
+        let next = if block {
+            if let Some(sink) = &mut self.sink {
+                let _ = sink.flush();
+            }
+            source.recv().ok()
+        } else {
+            match source.try_recv() {
+                Ok(b) => Some(b),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        match next {
+            Some(b) => {
+                self.input.push_back(b);
+                true
+            }
+            None => {
+                self.source = None;
+                false
+            }
+        }
+    }
+}
+
+impl Io for Console {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            PORT_STATUS => {
+                // A polling program waits here, so block for live input
+                if self.fill(!self.irq) {
+                    0x01
+                } else {
+                    if !self.irq && self.source.is_none() && self.pending.is_empty() {
+                        self.exhausted = true;
+                    }
+                    0x00
+                }
+            }
+            PORT_CONSOLE => self.input.pop_front().unwrap_or(0),
+            storage::PORT_FILE_DATA | storage::PORT_FILE_CMD => match &mut self.files {
+                Some(files) => files.input(port),
+                None => storage::STATUS_FAILED,
+            },
+            _ => self.latches.get(&port).copied().unwrap_or(0xFF),
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        let (sink, captured) = match port {
+            PORT_CONSOLE => (&mut self.sink, &mut self.output),
+            _ if port == self.error_port => {
+                // Keep the two streams in order on a terminal
+                if let Some(sink) = &mut self.sink {
+                    let _ = sink.flush();
+                }
+                (&mut self.error_sink, &mut self.errors)
+            }
+            storage::PORT_FILE_DATA | storage::PORT_FILE_CMD => {
+                if let Some(files) = &mut self.files {
+                    files.output(port, value);
+                }
+                return;
+            }
+            _ if port == self.trace_port => {
+                self.trace.push(value);
+                return;
+            }
+            _ if port.wrapping_sub(PORT_CTC) < 4 => {
+                self.ctc.output((port - PORT_CTC) as usize, value, self.now);
+                return;
+            }
+            _ => {
+                self.ports.push((port, value));
+                self.latches.insert(port, value);
+                return;
+            }
+        };
+        match sink {
+            Some(sink) => {
+                let _ = sink.write_all(&[value]);
+            }
+            None => {
+                captured.push(value);
+                if port == PORT_CONSOLE {
+                    self.stamps.push(self.now);
+                }
+            }
+        }
+    }
+
+    fn interrupt(&mut self) -> bool {
+        (self.irq && self.fill(false)) || self.ctc.interrupt()
+    }
+
+    fn input_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    fn input_pending(&mut self) -> bool {
+        !self.fill(false) && (self.source.is_some() || !self.pending.is_empty())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.now = cycles;
+        self.ctc.tick(cycles);
+        while let Some(&(_, b)) = self.pending.front().filter(|&&(time, _)| time <= cycles) {
+            self.input.push_back(b);
+            self.pending.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &[u8]) -> Machine<Console> {
+        let mut m = Machine::new(code, Console::scripted(b""));
+        assert_eq!(m.run(Some(100_000)), Exit::Halted);
+        m
+    }
+
+    #[test]
+    fn test_output_and_halt() {
+        // LD A,'H'; OUT (0),A; LD A,'i'; OUT (0),A; HALT
+        let m = run(&[0x3E, b'H', 0xD3, 0x00, 0x3E, b'i', 0xD3, 0x00, 0x76]);
+        assert_eq!(m.io.output(), b"Hi");
+        assert_eq!(m.cpu.cycles, 7 + 11 + 7 + 11 + 4);
+    }
+
+    #[test]
+    fn test_djnz_loop_and_flags() {
+        // LD B,5; XOR A; loop: ADD A,3; DJNZ loop; CP 15; HALT
+        let m = run(&[0x06, 0x05, 0xAF, 0xC6, 0x03, 0x10, 0xFC, 0xFE, 0x0F, 0x76]);
+        assert_eq!(m.cpu.a, 15);
+        assert!(m.cpu.f & FLAG_Z != 0);
+        assert_eq!(m.cpu.b, 0);
+    }
+
+    #[test]
+    fn test_call_ret_and_16bit_ops() {
+        // LD SP,0x8000; LD HL,0x1234; LD DE,0x0034; CALL sub; HALT
+        // sub: OR A; SBC HL,DE; RET
+        let code = [
+            0x31, 0x00, 0x80, 0x21, 0x34, 0x12, 0x11, 0x34, 0x00, 0xCD, 0x0D, 0x00, 0x76,
+            0xB7, 0xED, 0x52, 0xC9,
+        ];
+        let m = run(&code);
+        assert_eq!(m.cpu.hl(), 0x1200);
+        assert_eq!(m.cpu.sp, 0x8000);
+    }
+
+    #[test]
+    fn test_index_registers() {
+        // LD IX,0x4000; LD (IX+2),0x55; LD A,(IX+2); INC (IX+2); LD B,(IX+2); HALT
+        let code = [
+            0xDD, 0x21, 0x00, 0x40, 0xDD, 0x36, 0x02, 0x55, 0xDD, 0x7E, 0x02, 0xDD, 0x34,
+            0x02, 0xDD, 0x46, 0x02, 0x76,
+        ];
+        let m = run(&code);
+        assert_eq!(m.cpu.a, 0x55);
+        assert_eq!(m.cpu.b, 0x56);
+        assert_eq!(m.cpu.cycles, 14 + 19 + 19 + 23 + 19 + 4);
+    }
+
+    #[test]
+    fn test_rom_is_write_protected() {
+        // LD A,0x99; LD (0x0000),A; LD (0x4000),A; HALT
+        let m = run(&[0x3E, 0x99, 0x32, 0x00, 0x00, 0x32, 0x00, 0x40, 0x76]);
+        assert_eq!(m.read(0x0000), 0x3E);
+        assert_eq!(m.read(0x4000), 0x99);
+    }
+
+    #[test]
+    fn test_polled_input() {
+        // loop: IN A,(1); BIT 0,A; JR Z,loop; IN A,(0); OUT (0),A; JR loop
+        let code = [0xDB, 0x01, 0xCB, 0x47, 0x28, 0xFA, 0xDB, 0x00, 0xD3, 0x00, 0x18, 0xF4];
+        let mut m = Machine::new(&code, Console::scripted(b"abc"));
+        assert_eq!(m.run(Some(100_000)), Exit::InputExhausted);
+        assert_eq!(m.io.output(), b"abc");
+    }
+
+    #[test]
+    fn test_timed_input() {
+        // Echo as before, then each byte arrives only once its time comes
+        let code = [0xDB, 0x01, 0xCB, 0x47, 0x28, 0xFA, 0xDB, 0x00, 0xD3, 0x00, 0x18, 0xF4];
+        let console = Console::scripted(b"a").with_input_at(5000, b"b\n").with_input_at(2000, b"c");
+        let mut m = Machine::new(&code, console);
+        assert_eq!(m.run(Some(100_000)), Exit::InputExhausted);
+        assert_eq!(m.io.output(), b"acb\n");
+        let timed = m.io.timed_output();
+        assert_eq!(timed.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>(), ["acb\n"]);
+        assert!(timed[0].0 < 100);
+        assert!(m.io.stamps[1] >= 2000 && m.io.stamps[1] < 2100);
+        assert!(m.io.stamps[2] >= 5000 && m.io.stamps[2] < 5100);
+    }
+
+    #[test]
+    fn test_im1_interrupt() {
+        // 0x00: LD SP,0x8000; IM 1; EI; loop: JR loop (until the ISR halts)
+        // 0x38: IN A,(0); OUT (0),A; HALT
+        let mut code = vec![0x31, 0x00, 0x80, 0xED, 0x56, 0xFB, 0x18, 0xFE];
+        code.resize(0x38, 0);
+        code.extend_from_slice(&[0xDB, 0x00, 0xD3, 0x00, 0x76]);
+        let mut m = Machine::new(&code, Console::scripted(b"x").with_irq(true));
+        assert_eq!(m.run(Some(100_000)), Exit::Halted);
+        assert_eq!(m.io.output(), b"x");
+    }
+
+    #[test]
+    fn test_cycle_limit() {
+        // loop: JR loop
+        let mut m = Machine::new(&[0x18, 0xFE], Console::scripted(b""));
+        assert_eq!(m.run(Some(1000)), Exit::CycleLimit);
+        assert!(m.cpu.cycles >= 1000);
+    }
+}
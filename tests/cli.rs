@@ -0,0 +1,761 @@
+//! Command line tests
+//!
+//! These run the microperl binary the way a user would and check its
+//! output and exit status.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run microperl with `args`, feeding `stdin` to it
+fn microperl(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_microperl"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run microperl");
+    // It may exit on a bad option before reading any of it
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().expect("Failed to wait for microperl")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn test_one_liner() {
+    let output = microperl(&["-e", r#"print "hi\n";"#, "--run"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hi\n");
+}
+
+#[test]
+fn test_program_from_stdin() {
+    let output = microperl(&["run", "-"], "print 1 + 2;");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3");
+}
+
+#[test]
+fn test_one_liner_with_file() {
+    let output = microperl(&["-e", "print 1;", "prog.mpl"], "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_syntax_check() {
+    let ok = microperl(&["-c", "-"], "my $x = 1;\nprint $x;\n");
+    assert!(ok.status.success());
+    assert_eq!(stdout(&ok), "- syntax OK\n");
+
+    let undeclared = microperl(&["-c", "-"], "my $x = 1;\nprint $y;\n");
+    assert!(!undeclared.status.success());
+    assert_eq!(String::from_utf8_lossy(&undeclared.stderr), "-:2: Undefined variable: $y\n");
+
+    let arity = microperl(&["-c", "-e", "sub f($a) { return $a; }\nf(1, 2);"], "");
+    assert!(!arity.status.success());
+    assert!(String::from_utf8_lossy(&arity.stderr).starts_with("-e:2: Sub f takes 1 argument but"));
+}
+
+#[test]
+fn test_json_diagnostics() {
+    let output = microperl(&["--diagnostics", "json", "-c", "-"], "sub f($a) { return $a; }\nf();\n");
+    assert!(!output.status.success());
+    let json = String::from_utf8_lossy(&output.stderr);
+    assert!(json.starts_with(r#"[{"file":"-","severity":"error","code":"sub-arity","line":2,"column":1,"#));
+
+    let output = microperl(&["--diagnostics", "json", "-c", "-"], "my $x = 5;\nprint 0 < $x < 10;\n");
+    let json = String::from_utf8_lossy(&output.stderr);
+    assert!(json.starts_with(r#"[{"file":"-","severity":"error","code":"chained-comparison","line":2,"column":14,"#), "{}", json);
+
+    // Comparisons of different levels compare the result, as in Perl
+    let output = microperl(&["run", "-"], "my $a = 1;\nmy $b = 2;\nmy $c = 1;\nprint $a < $b == $c;\n");
+    assert_eq!(stdout(&output), "1");
+}
+
+#[test]
+fn test_fmt() {
+    let output = microperl(&["fmt", "-"], "my $x=1;  # one\nif($x){print $x;}\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "my $x = 1;  # one\nif ($x) {\n    print $x;\n}\n");
+
+    let check = microperl(&["fmt", "--check", "-"], "my $x=1;\n");
+    assert!(!check.status.success());
+    assert_eq!(stdout(&check), "-\n");
+}
+
+#[test]
+fn test_use_library() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_use_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("Twice.mpl"), "sub twice($n) { return $n * 2; }\n").unwrap();
+    let include = format!("-I{}", dir.display());
+
+    let output = microperl(&["run", &include, "-"], "use Twice;\nprint twice(21);\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "42");
+
+    std::fs::write(dir.join("hi.mpl"), "print \"hi\";\n").unwrap();
+    let output = microperl(&["run", &include, "-"], "do \"hi.mpl\";\nrequire \"hi.mpl\";\nrequire \"hi.mpl\";\n");
+    assert_eq!(stdout(&output), "hihi");
+
+    let missing = microperl(&["run", "-"], "use Twice;\n");
+    assert!(!missing.status.success());
+    assert!(String::from_utf8_lossy(&missing.stderr).starts_with("error[use-error]: Can't locate Twice.mpl"));
+
+    // Empty components of MPLLIB are dropped, and a bare file name's
+    // directory is the current one
+    std::fs::write(dir.join("main.pl"), "use Missing;\n").unwrap();
+    let missing = Command::new(env!("CARGO_BIN_EXE_microperl"))
+        .args(["run", "main.pl"])
+        .current_dir(&dir)
+        .env("MPLLIB", "lib::")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&missing.stderr);
+    assert!(stderr.starts_with("error[use-error]: Can't locate Missing.mpl in the include path (lib, .)"), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_precompiled_library() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_mpb_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("Greet.mpl");
+    let library = dir.join("Greet.mpb");
+    std::fs::write(&source, "our $greeting = \"hello \";\nsub greet($n) { print $greeting, $n; }\n").unwrap();
+
+    let build = microperl(&[source.to_str().unwrap(), "--mpb", library.to_str().unwrap()], "");
+    assert!(build.status.success());
+    // The program links the module; the source is no longer needed
+    std::fs::remove_file(&source).unwrap();
+    let include = format!("-I{}", dir.display());
+    let output = microperl(&["run", &include, "-"], "use Greet;\ngreet(\"world\");\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hello world");
+
+    let not_library = microperl(&["-", "--mpb", library.to_str().unwrap()], "print 1;\n");
+    assert!(!not_library.status.success());
+}
+
+#[test]
+fn test_disasm_artifacts() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_disasm_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let source = "sub twice($n) { return $n + $n; }\nprint twice(2), \"\\n\";\n";
+    let build = microperl(&["-", "-o", &path("p.bin"), "--rom", &path("p.rom")], source);
+    assert!(build.status.success());
+    let library = "sub twice($n) { return $n + $n; }\n";
+    assert!(microperl(&["-", "--mpb", &path("p.mpb")], library).status.success());
+    let listing = stdout(&microperl(&["-", "--bytecode"], source));
+
+    // The image and ROM have no sub table; the library keeps it
+    let code = |text: &str| text[text.find("Bytecode (").unwrap()..].to_string();
+    for (command, file) in [("disasm", "p.bin"), ("disasm", "p.rom"), ("--disasm", "p.rom")] {
+        let output = microperl(&[command, &path(file)], "");
+        assert!(output.status.success());
+        assert_eq!(code(&stdout(&output)), code(&listing));
+    }
+    let rom = stdout(&microperl(&["disasm", &path("p.rom")], ""));
+    assert!(rom.starts_with("Bytecode image at offset 0x"));
+    let linked = stdout(&microperl(&["disasm", &path("p.mpb")], ""));
+    assert!(linked.contains("  twice @ 0x0003 (1 params)\n"));
+
+    std::fs::write(dir.join("junk.bin"), b"not a program").unwrap();
+    let junk = microperl(&["disasm", &path("junk.bin")], "");
+    assert!(!junk.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_subcommands() {
+    let source = "print 6 * 7, \"\\n\";\n";
+    assert_eq!(stdout(&microperl(&["run", "-"], source)), "42\n");
+    assert_eq!(stdout(&microperl(&["check", "-"], source)), "- syntax OK\n");
+    let built = microperl(&["build", "-", "--bytecode"], source);
+    assert_eq!(stdout(&built), stdout(&microperl(&["-", "--bytecode"], source)));
+
+    // Each command lists only the options it takes
+    let help = microperl(&["run", "--help"], "");
+    assert!(help.status.success());
+    let help = stdout(&help);
+    assert!(help.starts_with("Usage: microperl run [options] <file.mpl>\n"));
+    assert!(help.contains("  --max-steps <n>") && !help.contains("--rom <file>"));
+    assert!(stdout(&microperl(&["help", "debug"], "")).contains("  --watch <var>"));
+    // Past --args, --help is the program's
+    let args = microperl(&["run", "-", "--args", "--help"], "print $ARGV[0];\n");
+    assert_eq!(stdout(&args), "--help");
+
+    let refused = microperl(&["run", "-", "--rom", "x.rom"], source);
+    assert!(!refused.status.success());
+    assert_eq!(String::from_utf8_lossy(&refused.stderr), "--rom does not apply to `microperl run`\n");
+    assert!(!microperl(&["-c", "-", "-o", "x.bin"], source).status.success());
+}
+
+#[test]
+fn test_upload_intel_hex() {
+    let port = std::env::temp_dir().join(format!("microperl_cli_upload_{}.hex", std::process::id()));
+    let port = port.to_str().unwrap();
+    let output = microperl(&["upload", "--port", port, "-"], "print 1;\n");
+    assert!(output.status.success());
+    let hex = std::fs::read_to_string(port).unwrap();
+    assert!(hex.starts_with(":10000000"));
+    assert!(hex.ends_with(":00000001FF\n"));
+    std::fs::remove_file(port).unwrap();
+
+    assert!(!microperl(&["upload", "-"], "print 1;\n").status.success());
+    let hosted = microperl(&["upload", "--target", "spectrum", "--port", port, "-"], "print 1;\n");
+    assert!(!hosted.status.success());
+}
+
+#[test]
+fn test_strict_types() {
+    let source = "my $name = \"bob\";\nif ($name < \"carol\") { print 1; }\nmy $n = 2;\nprint $n + 1, $name . \"!\";\n";
+    let output = microperl(&["-c", "--strict-types", "-"], source);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "-:2: warning: Numeric `<` on a string compares its address; use `lt`\n");
+    let output = microperl(&["-c", "-"], source);
+    assert!(output.stderr.is_empty());
+
+    // -W turns the check on, and makes its warnings fail the build
+    let output = microperl(&["-c", "-W", "type-mix", "-Werror", "-"], source);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("-: warnings are errors with -W error\n"));
+    let output = microperl(&["-c", "--strict-types", "-Wno-type-mix", "-Werror", "-"], source);
+    assert!(output.status.success());
+    let output = microperl(&["-c", "-W", "no-such", "-"], source);
+    assert_eq!(output.status.code(), Some(2));
+
+    // Every warning's code is a setting
+    let unused = "my $n = 1;\nmy $left = 2;\nprint $n;\n";
+    let output = microperl(&["-c", "-W", "unused", "-"], unused);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "-:2: warning: Variable left is declared but never used\n");
+    let output = microperl(&["-c", "-W", "unused", "-W", "no-unused", "-Werror", "-"], unused);
+    assert!(output.status.success() && output.stderr.is_empty());
+}
+
+#[test]
+fn test_rom_size_limit() {
+    let fits = microperl(&["-", "--max-rom-size", "0x8000"], "print 1;\n");
+    assert!(fits.status.success());
+
+    let output = microperl(&["-", "--max-rom-size", "2048"], "print 1;\n");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("error[rom-size]: ROM image is "));
+    assert!(stderr.contains("over the limit of 2048\n  runtime "));
+}
+
+#[test]
+fn test_exit_codes() {
+    let status = |args: &[&str], stdin: &str| microperl(args, stdin).status.code();
+    assert_eq!(status(&["-e", "print 1;", "--bogus"], ""), Some(2));
+    assert_eq!(status(&["no_such_file.mpl"], ""), Some(3));
+    assert_eq!(status(&["-c", "-"], "print 1 ` 2;"), Some(4));
+    assert_eq!(status(&["-c", "-"], "print (;"), Some(5));
+    assert_eq!(status(&["-c", "-"], "use NoSuchLibrary;"), Some(6));
+    assert_eq!(status(&["-c", "-"], "print $y;"), Some(7));
+    assert_eq!(status(&["-", "--max-rom-size", "16"], "print 1;"), Some(9));
+    assert_eq!(status(&["run", "-", "--max-steps", "10"], "while (1) { }"), Some(1));
+}
+
+#[test]
+fn test_define() {
+    let source = "use constant GREETING => \"hi\";\nuse constant TIMES => 2;\nprint GREETING, TIMES * 3;\n";
+    assert_eq!(stdout(&microperl(&["run", "-"], source)), "hi6");
+    let output = microperl(&["run", "--define", "GREETING=yo", "-DTIMES=5", "-"], source);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "yo15");
+}
+
+#[test]
+fn test_rendered_diagnostic() {
+    let output = microperl(&["--color", "never", "-"], "my $x = 1;\nprint $y;\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "error[undefined-variable]: Undefined variable: $y\n --> -:2:7\n  |\n2 | print $y;\n  |       ^^\n  \
+         = note: declare it with `my` or `our` before using it\n"
+    );
+    let colored = microperl(&["--color", "always", "-"], "print $y;\n");
+    assert!(String::from_utf8_lossy(&colored.stderr).starts_with("\x1b[1;31merror[undefined-variable]"));
+}
+
+#[test]
+fn test_ast_formats() {
+    let json = microperl(&["--ast-format", "json", "-"], "print 1;\n");
+    assert_eq!(stdout(&json), "[{\"type\":\"Print\",\"line\":1,\"handle\":null,\"args\":[{\"type\":\"Integer\",\"value\":1}]}]\n");
+    let sexp = microperl(&["--ast-format", "sexp", "-"], "print 1;\n");
+    assert_eq!(stdout(&sexp), "(Print :line 1 :handle nil :args ((Integer :value 1)))\n");
+    assert_eq!(microperl(&["--ast-format", "xml", "-"], "").status.code(), Some(2));
+}
+
+#[test]
+fn test_profile_trace() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_trace_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("loop.mpl");
+    let trace = dir.join("loop.trace");
+    std::fs::write(&source, "my $i = 0;\nwhile ($i < 3) {\n    $i++;\n}\n").unwrap();
+    let source = source.to_str().unwrap();
+
+    let measured = microperl(&[source, "--cycles", "--trace", trace.to_str().unwrap()], "");
+    assert!(measured.status.success());
+    assert!(stdout(&measured).contains("\nBy opcode:\n"));
+    let profiled = microperl(&[source, "--profile", trace.to_str().unwrap()], "");
+    assert!(profiled.status.success());
+    assert_eq!(stdout(&profiled), stdout(&measured));
+
+    let without_cycles = microperl(&[source, "--trace", trace.to_str().unwrap()], "");
+    assert_eq!(without_cycles.status.code(), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wcet() {
+    let output = microperl(&["-", "--wcet"], "my $i = 0;\nwhile ($i < 3) {\n    $i++;\n}\n");
+    assert!(output.status.success());
+    let report = stdout(&output);
+    assert!(report.starts_with("Static estimate, T-states per call or pass"), "{}", report);
+    assert!(report.contains("\n  loop                   2  "), "{}", report);
+    let hosted = microperl(&["-", "--wcet", "--target", "spectrum"], "print 1;");
+    assert_eq!(hosted.status.code(), Some(2));
+}
+
+#[test]
+fn test_where() {
+    let source = "my $i = 0;\nwhile ($i < 3) {\n    $i++;\n}\n";
+    let by_line = microperl(&["-", "--where", "line:3"], source);
+    assert!(by_line.status.success());
+    assert_eq!(stdout(&by_line), "101A  000E LoadLocal 0x00  line 3\n");
+    let by_pc = microperl(&["-", "--where", "pc:0x13"], source);
+    assert_eq!(stdout(&by_pc), "101F  0013 Jump 0x0005 -> 1011  line 2\n");
+    let by_address = microperl(&["-", "--where", "0x101B"], source);
+    assert_eq!(stdout(&by_address), stdout(&by_line));
+    for spec in ["0x0100", "pc:1", "line:9", "line:x"] {
+        assert_eq!(microperl(&["-", "--where", spec], source).status.code(), Some(2), "{}", spec);
+    }
+}
+
+#[test]
+fn test_entry() {
+    let source = "sub hello { print \"hi\"; }\nsub count { print 1, 2; }\nprint \"main\";";
+    let entry = microperl(&["-e", source, "--entry", "count", "--run"], "");
+    assert!(entry.status.success());
+    assert_eq!(stdout(&entry), "12");
+    let menu = microperl(&["run", "-e", source, "--menu", "hello,count"], "1");
+    assert_eq!(stdout(&menu), "1) hello\n2) count\n? 1\nhi1) hello\n2) count\n? ");
+    let missing = microperl(&["-e", source, "--entry", "main"], "");
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("Entry sub main is not defined"));
+    assert_eq!(microperl(&["-e", source, "--entry", "hello", "--menu", "count"], "").status.code(), Some(2));
+}
+
+#[test]
+fn test_bundle() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_bundle_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let hello = dir.join("hello.mpl");
+    let count = dir.join("count.mpl");
+    std::fs::write(&hello, "my $n = \"hi\";\nprint $n;\n").unwrap();
+    std::fs::write(&count, "my $n = 0;\nwhile ($n < 3) { print $n; $n++; }\n").unwrap();
+    let files = [hello.to_str().unwrap(), count.to_str().unwrap()];
+
+    let menu = "1) hello\n2) count\n? ";
+    let run = microperl(&["--bundle", files[0], files[1], "--run"], "x2");
+    assert!(run.status.success());
+    assert_eq!(stdout(&run), format!("{}2\n012", menu));
+    let vm = microperl(&["run", "--bundle", files[0], files[1]], "1");
+    assert_eq!(stdout(&vm), format!("{}1\nhi", menu));
+    let rom = dir.join("demo.rom");
+    let build = microperl(&["--bundle", files[0], files[1], "--select-port", "0x40", "--rom", rom.to_str().unwrap()], "");
+    assert!(build.status.success());
+
+    std::fs::write(&count, "print $undefined;\n").unwrap();
+    let error = microperl(&["--bundle", files[0], files[1], "--rom", rom.to_str().unwrap()], "");
+    assert_eq!(error.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&error.stderr).contains("count.mpl"));
+    assert_eq!(microperl(&["--select-port", "64", files[0]], "").status.code(), Some(2));
+    assert_eq!(microperl(&["--bundle", files[0], "--lst", "x.lst"], "").status.code(), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dump_after() {
+    let source = "my $x = 1;\nif ($x) { print 2 * 3; } else { }\n";
+    let output = microperl(&["-", "--dump-after", "fold", "--dump-after", "peephole"], source);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    let (fold, peephole) = text.split_once("--- after peephole ---\n").unwrap();
+    assert!(fold.starts_with("--- after fold ---\n"));
+    // The optimizer drops the else branch's Jump to the next instruction
+    assert!(fold.contains("Bytecode (18 bytes)") && peephole.contains("Bytecode (15 bytes)"), "{}", text);
+    assert!(fold.contains("Push 0x0006"));
+
+    let link = microperl(&["-", "--dump-after", "link"], source);
+    assert!(stdout(&link).contains("  1013  0007 JumpIfNot 0x000E -> 101A  line 2\n"), "{}", stdout(&link));
+    let lexer = microperl(&["-", "--dump-after", "lexer"], source);
+    assert!(stdout(&lexer).starts_with("--- after lexer ---\n  My at 1:1\n"));
+    assert_eq!(microperl(&["-", "--dump-after", "codegen"], source).status.code(), Some(2));
+    assert_eq!(microperl(&["run", "-", "--dump-after", "fold"], source).status.code(), Some(2));
+}
+
+#[test]
+fn test_coverage() {
+    let source = "my $x = 1;\nif ($x) {\n    print \"yes\";\n} else {\n    print \"no\";\n}\n";
+    for args in [&["-", "--coverage", "--run"][..], &["run", "-", "--coverage"][..]] {
+        let output = microperl(args, source);
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "yes");
+        let report = String::from_utf8_lossy(&output.stderr);
+        assert!(report.starts_with("Coverage: 3 of 4 lines (75.0%)\n"), "{}", report);
+        assert!(report.contains("\n   #####      5      print \"no\";\n"));
+    }
+    let crosscheck = microperl(&["-", "--coverage", "--crosscheck"], source);
+    assert_eq!(crosscheck.status.code(), Some(2));
+}
+
+#[test]
+fn test_assert() {
+    let source = "my $x = 3;\nassert $x < 5;\nassert $x < 2, \"x is too big\";\nprint \"done\";\n";
+    let output = microperl(&["run", "-"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":3: Runtime error: Assertion failed: x is too big\n"));
+
+    // The Z80 prints the message to STDERR, and the host says where it
+    // stopped
+    let output = microperl(&["-", "--run"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("Assertion failed: x is too big\n-:3: Program died\n"));
+
+    for args in [&["run", "-", "--release"][..], &["-", "--run", "-D", "NDEBUG=1"][..]] {
+        let output = microperl(args, source);
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "done");
+    }
+}
+
+#[test]
+fn test_checked_arithmetic() {
+    let source = "my $x = 32000;\nprint \"a\";\n$x += 1000;\nprint \"b\";\n";
+    let output = microperl(&["run", "-", "--checked"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "a");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":3: Runtime error: Integer overflow: 32000 + 1000\n"));
+
+    let output = microperl(&["-", "--run", "--checked"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":3: Runtime error: Integer overflow\n"));
+
+    // Without the flag it wraps
+    assert_eq!(stdout(&microperl(&["run", "-"], source)), "ab");
+}
+
+#[test]
+fn test_division_by_zero() {
+    let source = "my $x = 100;\nmy $y = 7;\nprint $x / $y, \" \", $x % $y;\n$y = 0;\nprint $x % $y;\n";
+    let output = microperl(&["run", "-"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "14 2");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":5: Runtime error: Illegal division by zero at line 5\n"));
+
+    // The ROM stops on the instruction the same way
+    let output = microperl(&["-", "--run"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "14 2");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":5: Runtime error: Illegal division by zero at line 5\n"));
+    let output = microperl(&["-", "--run"], "my $y = 0;\nprint 100 / $y;\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":2: Runtime error: Illegal division by zero at line 2\n"));
+}
+
+#[test]
+fn test_missing_handlers() {
+    let source = "my $x = 3;\nprint $x;\nprint $x - 1;\nprint $x - 2;\n";
+    let output = microperl(&["-", "--run"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "3");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(":3: Runtime error: Sub is not in the Z80 runtime\n"));
+    // Hashes, whose order only the host VM keeps: an image refuses them
+    let hashes = "print 1;\nmy %h;\n$h{\"a\"} = 1;\n";
+    let output = microperl(&["-", "--run"], hashes);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(":3: Runtime error: "));
+    let output = microperl(&["-", "--target", "cpc", "--rom", "/dev/null"], hashes);
+    assert_eq!(output.status.code(), Some(7));
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("error[host-vm-only]: Hashes need the host VM (run): the cpc runtime has none"), "{}", stderr);
+    assert!(stderr.contains("3 | $h{\"a\"} = 1;"), "{}", stderr);
+
+    // Building the image warns once for each, at its first use
+    let dir = std::env::temp_dir().join(format!("microperl_missing_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rom = dir.join("sub.rom");
+    let output = microperl(&["--diagnostics", "json", "-", "--rom", rom.to_str().unwrap()], source);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(stderr.matches("\"not-in-runtime\"").count(), 1, "{}", stderr);
+    assert!(stderr.contains("\"line\":3"), "{}", stderr);
+    let output = microperl(&["-", "--rom", rom.to_str().unwrap(), "-W", "error"], source);
+    assert_eq!(output.status.code(), Some(7));
+    let output = microperl(&["-", "--rom", rom.to_str().unwrap(), "-W", "no-not-in-runtime", "-W", "error"], source);
+    assert!(output.status.success() && output.stderr.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_print_stderr() {
+    let source = "print \"out\\n\";\nprint STDERR \"err\\n\";\nprint \"more\\n\";\n";
+    let output = microperl(&["run", "-"], source);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "out\nmore\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "err\n");
+
+    // The RetroShield runtime writes STDERR to port 2, or --error-port
+    for args in [&["-", "--run"][..], &["-", "--run", "--error-port", "0x40"][..]] {
+        let output = microperl(args, source);
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "out\nmore\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "err\n");
+    }
+
+    // Other targets need one given
+    let output = microperl(&["-", "--target", "rc2014-acia", "--rom", "/dev/null"], source);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("STDERR needs a target with an error port"));
+    let output = microperl(&["-", "--target", "spectrum", "--error-port", "2", "-o", "/dev/null"], source);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--error-port needs the retroshield"));
+}
+
+#[test]
+fn test_files() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_files_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("in.txt"), "a\nb\n").unwrap();
+    let source = "my $in = open(\"in.txt\", \"<\");\nmy $out = open(\"out.txt\", \">\");\n\
+                  while (!eof($in)) { write($out, \"+\" . readline($in)); }\nclose($out);\n";
+    let output = microperl(&["run", "--files", dir.to_str().unwrap(), "-"], source);
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "+a\n+b\n");
+
+    // The ROM runtime talks to the same ports
+    let source = "my $in = open(\"in.txt\", \"<\");\nmy $out = open(\"rom.txt\", \"w\");\n\
+                  while (!eof($in)) { write($out, readline($in)); }\nwrite($out, 42);\n\
+                  print close($out), close(9), open(\"missing.txt\", \"<\"), readline(9), eof(9);\n";
+    for command in [&["run", "--files", dir.to_str().unwrap(), "-"][..], &["--files", dir.to_str().unwrap(), "-", "--run"]] {
+        let output = microperl(command, source);
+        assert!(output.status.success(), "{:?}", command);
+        assert_eq!(stdout(&output), "10001");
+        assert_eq!(std::fs::read_to_string(dir.join("rom.txt")).unwrap(), "a\nb\n42");
+        std::fs::remove_file(dir.join("rom.txt")).unwrap();
+    }
+    let output = microperl(&["-", "--run"], "my $fh = open(\"in.txt\", \"+<\");\n");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "-:1: Runtime error: Bad open mode\n");
+    let output = microperl(&["--files", dir.to_str().unwrap(), "-c", "-"], "print 1;");
+    assert_eq!(output.status.code(), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_args() {
+    let source = "print $ARGV[1], \",\", $ARGV[0];\nforeach my $a (@ARGV) { print \" \", $a; }\n";
+    for run in [&["run", "-", "--args"][..], &["-", "--run", "--args"][..]] {
+        let output = microperl(&[run, &["one", "--two"]].concat(), source);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(stdout(&output), "--two,one one --two");
+    }
+
+    let output = microperl(&["run", "-", "--args", &"x".repeat(200)], source);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "--args: Arguments take 211 bytes, the limit is 160\n");
+}
+
+#[test]
+fn test_env() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_env_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("microperl.toml"), "[env]\nNAME = \"board\"\nPORT = 128\n").unwrap();
+    let program = dir.join("show.mpl");
+    std::fs::write(&program, "print $ENV{\"NAME\"}, \":\", $ENV{\"PORT\"} + 1;\n").unwrap();
+
+    let output = microperl(&["run", program.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "board:129");
+    let output = microperl(&["run", "--env", "NAME=desk", program.to_str().unwrap()], "");
+    assert_eq!(stdout(&output), "desk:129");
+}
+
+#[test]
+fn test_rom_shell() {
+    let output = microperl(&["-e", "my $n = 6;", "--rom-shell", "--run"], "$n + 1\n$n = 2\n$n == 2\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "> $n + 1\n7\n> $n = 2\n> $n == 2\n1\n> \n");
+
+    for flag in ["--self-test", "--mem-stats"] {
+        let output = microperl(&["-e", "print 1;", "--rom-shell", flag, "--run"], "1 + 2\n");
+        assert!(output.status.success(), "{}", flag);
+        assert!(stdout(&output).ends_with("> 1 + 2\n3\n> \n"), "{}", flag);
+    }
+    let output = microperl(&["-e", "print 1;", "--rom-shell", "--self-test", "--mem-stats", "--coverage", "--timer"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error[invalid-options]: these options need a "));
+}
+
+#[test]
+fn test_caller_and_line() {
+    let source = "sub where { our @c = caller(); print $c[0], \" \", $c[1], \"\\n\"; }\nwhere();\nprint __FILE__, \":\", __LINE__;";
+    let output = microperl(&["run", "-"], source);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "main 2\n-:3");
+
+    let output = microperl(&["-", "--run"], source);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("-:1: Runtime error: caller() needs the host VM (run)"));
+}
+
+#[test]
+fn test_native_sub() {
+    let source = "sub gcd($a, $b) :native {\n    while ($b != 0) { my $t = $a % $b; $a = $b; $b = $t; }\n    return $a;\n}\nsub ratio($a, $b) :native { return $a / $b; }\nprint gcd(1071, 462), \" \", gcd(5, 0);\nprint ratio(0, gcd(0, 0));";
+    let output = microperl(&["run", "-"], source);
+    assert_eq!(stdout(&output), "21 5");
+
+    let output = microperl(&["-", "--run"], source);
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "21 5");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("-:5: Runtime error: Illegal division by zero"));
+
+    // The arguments keep their order on both
+    let source = "sub s($a, $b) :native { return $a - $b; }\nsub q($a, $b) :native { return $a / $b; }\nprint s(17, 5) + 100, \" \", q(20, 4);";
+    for args in [&["run", "-"][..], &["-", "--run"]] {
+        assert_eq!(stdout(&microperl(args, source)), "112 5");
+    }
+
+    let output = microperl(&["-c", "--diagnostics", "json", "-"], "sub f :native {\n    print 1;\n}");
+    assert!(String::from_utf8_lossy(&output.stderr).contains(r#""code":"native-sub""#));
+}
+
+#[test]
+fn test_native_program() {
+    let source = "sub sq($n) { return $n * $n; }\nmy $s = \"x\";\n$s .= sq(-12);\nsay $s, \" \", -5;\nprint 1 / ($s eq \"y\");";
+    let output = microperl(&["--native", "--run", "-"], source);
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "x144 -5\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("-: Runtime error: Illegal division by zero at line 5\n"));
+
+    let output = microperl(&["--native", "-c", "--diagnostics", "json", "-"], "my $x = 1;\nmy %h;\n$h{1} = 2;");
+    assert!(String::from_utf8_lossy(&output.stderr).contains(r#""code":"native-program","line":3"#));
+
+    let output = microperl(&["--native", "--target", "spectrum", "-"], "print 1;");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--native needs the retroshield"));
+}
+
+#[test]
+fn test_reproducible() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_reproducible_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("where.mpl");
+    std::fs::write(&program, "print __FILE__;").unwrap();
+    let program = program.to_str().unwrap();
+
+    let output = microperl(&["--reproducible", program, "--run"], "");
+    assert_eq!(stdout(&output), "where.mpl");
+    let output = microperl(&[program, "--run"], "");
+    assert_eq!(stdout(&output), program);
+
+    let rom = dir.join("where.bin");
+    let sketch = dir.join("where.ino");
+    microperl(&["--reproducible", program, "--rom", rom.to_str().unwrap(), "--ino", sketch.to_str().unwrap()], "");
+    let first = std::fs::read(&rom).unwrap();
+    microperl(&["--reproducible", program, "--rom", rom.to_str().unwrap()], "");
+    assert_eq!(std::fs::read(&rom).unwrap(), first);
+    assert!(std::fs::read_to_string(&sketch).unwrap().starts_with("// MicroPerl ROM for where.mpl:"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_self_test() {
+    let output = microperl(&["--self-test", "-e", "print 1;", "--run"], "");
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with("MicroPerl "));
+    assert!(stdout(&output).ends_with(" microperl at 0x100C\n1"));
+
+    let output = microperl(&["--self-test", "--target", "rc2014-acia", "--banked", "-e", "print 1;"], "");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_patch() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_patch_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (old, new, update, applier) = (dir.join("old.rom"), dir.join("new.rom"), dir.join("update.mpp"), dir.join("apply.bin"));
+    microperl(&["-e", "print 1;", "--rom", old.to_str().unwrap()], "");
+    microperl(&["-e", "print 2;", "--rom", new.to_str().unwrap()], "");
+
+    let output = microperl(
+        &["patch", old.to_str().unwrap(), new.to_str().unwrap(), "-o", update.to_str().unwrap(), "--applier", applier.to_str().unwrap()],
+        "",
+    );
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("(call 0x2000)"));
+    let (old, new) = (std::fs::read(&old).unwrap(), std::fs::read(&new).unwrap());
+    let patch = std::fs::read(&update).unwrap();
+    assert!(patch.len() < 64);
+    assert_eq!(kz80_microperl::patch::apply(&old, &patch).unwrap(), new);
+    assert!(!std::fs::read(&applier).unwrap().is_empty());
+
+    let output = microperl(&["patch", update.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(2));
+    // The spelling from before the commands
+    let (old, new) = (dir.join("old.rom"), dir.join("new.rom"));
+    let output = microperl(&["--patch", old.to_str().unwrap(), new.to_str().unwrap(), "-o", update.to_str().unwrap()], "");
+    assert!(output.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_out_dir() {
+    let dir = std::env::temp_dir().join(format!("microperl_cli_out_dir_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("shapes.mpl");
+    std::fs::write(&program, "sub area($w) { return $w * $w; }\n").unwrap();
+    let build = dir.join("build");
+
+    let output = microperl(&[program.to_str().unwrap(), "--out-dir", build.to_str().unwrap()], "");
+    assert!(output.status.success());
+    let summary = kz80_microperl::json::parse(&stdout(&output)).unwrap();
+    assert_eq!(summary.get("target").and_then(|t| t.as_str()), Some("retroshield"));
+    assert_eq!(summary.get("bytecode_address").and_then(|a| a.as_usize()), Some(0x1000));
+    let subs = summary.get("subs").and_then(|s| s.as_array()).unwrap();
+    assert_eq!(subs[0].get("name").and_then(|n| n.as_str()), Some("area"));
+    let artifacts = summary.get("artifacts").and_then(|a| a.as_array()).unwrap();
+    let kinds: Vec<&str> = artifacts.iter().filter_map(|a| a.get("kind")?.as_str()).collect();
+    assert_eq!(kinds, ["bytecode", "rom", "hex", "lst", "map", "mpb"]);
+    for artifact in artifacts {
+        let path = artifact.get("path").and_then(|p| p.as_str()).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(artifact.get("size").and_then(|s| s.as_usize()), Some(bytes.len()));
+        assert_eq!(artifact.get("crc16").and_then(|c| c.as_usize()), Some(kz80_microperl::z80::crc16(&bytes) as usize));
+    }
+    assert!(std::fs::read_to_string(build.join("shapes.hex")).unwrap().ends_with(":00000001FF\n"));
+    let output = microperl(&["disasm", build.join("shapes.bc").to_str().unwrap()], "");
+    assert!(stdout(&output).contains("Mul"));
+
+    let output = microperl(&[program.to_str().unwrap(), "--out-dir", build.to_str().unwrap(), "--rom", "x.rom"], "");
+    assert_eq!(output.status.code(), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_trace_rom() {
+    let output = microperl(&["--trace-rom", "-e", "print 5;", "--run"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "5");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "trace: Push\ntrace: Print\ntrace: Halt\n");
+
+    let output = microperl(&["--trace-stack", "-e", "print 5;", "--run"], "");
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,154 @@
+//! Tests for the library API
+//!
+//! These use the crate the way other tools would: compile a source string
+//! with `compile_source` and run the result on the built-in Z80 emulator.
+
+use kz80_microperl::z80emu::{Console, Exit, Machine};
+use kz80_microperl::{compile_bytes, compile_source, z80, ErrorKind, Options, Stage, Target};
+
+#[test]
+fn test_compile_and_run_image() {
+    let artifacts = compile_source("my $n = 40 + 2;\nprint \"n=\", $n, \"\\n\";", Options::default())
+        .expect("program compiles");
+    assert_eq!(artifacts.program.statements.len(), 2);
+    assert_eq!(&artifacts.bytecode[..4], b"MPL\x02");
+    assert_eq!(artifacts.bytecode, z80::generate_bytecode_image(&artifacts.module));
+
+    let mut machine = Machine::new(&artifacts.image, Console::scripted(b""));
+    assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+    assert_eq!(machine.io.output(), b"n=42\n");
+}
+
+#[test]
+fn test_image_for_target() {
+    let options = Options {
+        rom: z80::RomOptions { target: Target::Trs80, ..Default::default() },
+        name: "demo".to_string(),
+        ..Options::default()
+    };
+    let artifacts = compile_source("print \"hi\";", options).unwrap();
+    // /CMD name record
+    assert_eq!(&artifacts.image[..6], b"\x05\x04DEMO");
+}
+
+#[test]
+fn test_parse_error_location() {
+    let errors = compile_source("my $x = 1;\nprint (;\n", Options::default()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].stage, Stage::Parse);
+    assert_eq!(errors[0].line, Some(2));
+    assert!(errors[0].column.is_some());
+    assert!(errors[0].to_string().starts_with("Parse error at line 2, column "));
+}
+
+#[test]
+fn test_invalid_options() {
+    let options = Options {
+        rom: z80::RomOptions { irq_input: true, target: Target::Spectrum, ..Default::default() },
+        ..Options::default()
+    };
+    let errors = compile_source("print 1;", options).unwrap_err();
+    assert_eq!(errors[0].stage, Stage::Options);
+    assert_eq!(errors[0].line, None);
+}
+
+#[test]
+fn test_diagnostic_json() {
+    let source = "my $x = 1;\n  print \"a\", $y;\n";
+    let errors = compile_source(source, Options::default()).unwrap_err();
+    let (start, end) = errors[0].span.unwrap();
+    assert_eq!(&source[start..end], "$y");
+    assert_eq!(errors[0].code, "undefined-variable");
+    assert_eq!(
+        errors[0].to_json("a\"b.mpl"),
+        r#"{"file":"a\"b.mpl","severity":"error","code":"undefined-variable","line":2,"column":14,"span":{"start":24,"end":26},"message":"Undefined variable: $y"}"#
+    );
+
+    // The call, not the statement, and the variable by the sigil it is
+    // written with
+    let source = "sub f($a) { return $a; }
+print 1, f(1, 2);
+";
+    let errors = compile_source(source, Options::default()).unwrap_err();
+    let (start, end) = errors[0].span.unwrap();
+    assert_eq!((&source[start..end], errors[0].column), ("f", Some(10)));
+    let source = "my $n = 1;
+print $n, $ab[0];
+";
+    let (start, end) = compile_source(source, Options::default()).unwrap_err()[0].span.unwrap();
+    assert_eq!(&source[start..end], "$ab");
+    // An error about the whole statement still covers it
+    let source = "print 1;
+my $x = 1;
+use constant X => $x;
+";
+    let (start, end) = compile_source(source, Options::default()).unwrap_err()[0].span.unwrap();
+    assert_eq!(&source[start..end], "use constant X => $x;");
+
+    let errors = compile_source("print (1 2);", Options::default()).unwrap_err();
+    let (start, end) = errors[0].span.unwrap();
+    assert_eq!(errors[0].code, "syntax-error");
+    assert_eq!(&"print (1 2);"[start..end], "2");
+}
+
+#[test]
+fn test_error_kinds() {
+    let kind = |source: &str, options: Options| compile_source(source, options).unwrap_err()[0].kind();
+    assert_eq!(kind("print 1 ` 2;", Options::default()), ErrorKind::Lex);
+    assert_eq!(kind("print (;", Options::default()), ErrorKind::Parse);
+    assert_eq!(kind("use NoSuchLibrary;", Options::default()), ErrorKind::Library);
+    assert_eq!(kind("print $y;", Options::default()), ErrorKind::Compile);
+    let limits = kz80_microperl::budget::Limits { rom: Some(16), ..Default::default() };
+    assert_eq!(kind("print 1;", Options { limits, ..Options::default() }), ErrorKind::Size);
+
+    let errors = compile_source("print 1 ` 2;", Options::default()).unwrap_err();
+    assert_eq!(errors[0].code, "unexpected-character");
+    assert_eq!(errors[0].span, Some((8, 9)));
+    assert_eq!(ErrorKind::Runtime.exit_code(), 1);
+    assert_eq!(ErrorKind::Size.exit_code(), 9);
+
+    // Each code comes from where the error was raised, not its wording
+    let code = |source: &str| compile_source(source, Options::default()).unwrap_err()[0].code;
+    assert_eq!(code("print 0 < 1 < 2;"), "chained-comparison");
+    assert_eq!(code("sub f($a) { return $a; }\nf();"), "sub-arity");
+    assert_eq!(code("print g();"), "undefined-sub");
+    assert_eq!(code("print @n;"), "undefined-variable");
+    assert_eq!(code("sub f(@a) :native { return 1; }"), "native-sub");
+    assert_eq!(code("my $x = 1;\nuse constant X => $x;"), "compile-error");
+}
+
+#[test]
+fn test_file_name() {
+    let options = Options { file: Some("demo.mpl".to_string()), ..Options::default() };
+    let artifacts = compile_source("print __FILE__;", options).unwrap();
+    let mut machine = Machine::new(&artifacts.image, Console::scripted(b""));
+    assert_eq!(machine.run(Some(1_000_000)), Exit::Halted);
+    assert_eq!(machine.io.output(), b"demo.mpl");
+}
+
+#[test]
+fn test_warnings_as_errors() {
+    let source = "my $s = \"a\";\nprint $s < 3;";
+    let strict = Options { strict_types: true, ..Options::default() };
+    let artifacts = compile_source(source, strict.clone()).unwrap();
+    assert_eq!(artifacts.warnings[0].code, "type-mix");
+
+    let errors = compile_source(source, Options { warnings_as_errors: true, ..strict }).unwrap_err();
+    assert!(errors[0].is_warning());
+    assert_eq!(errors[0].kind(), ErrorKind::Compile);
+    assert!(compile_source(source, Options { warnings_as_errors: true, ..Options::default() }).is_ok());
+}
+
+#[test]
+fn test_compile_bytes() {
+    let errors = compile_bytes(b"print 1;\n  print \"\xff\";\n", Options::default()).unwrap_err();
+    assert_eq!(errors[0].stage, Stage::Lex);
+    assert_eq!((errors[0].line, errors[0].column), (Some(2), Some(10)));
+    assert_eq!(errors[0].message, "Invalid UTF-8 byte 0xFF");
+
+    let source = include_bytes!("../examples/fizzbuzz.mpl");
+    assert!(compile_bytes(source, Options::default()).is_ok());
+    for end in 0..source.len() {
+        let _ = compile_bytes(&source[..end], Options::default());
+    }
+}
@@ -1,48 +1,17 @@
 //! Integration tests for regex functionality
 //!
-//! These tests compile MicroPerl programs and run them through the Z80 emulator
-//! to verify end-to-end regex behavior.
+//! These tests compile MicroPerl programs and run them on the built-in Z80 emulator
+//! to verify end-to-end regex behavior, in process through `testing`.
 
-use std::process::Command;
-use std::fs;
-use std::sync::atomic::{AtomicU64, Ordering};
-
-static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+use kz80_microperl::testing::run_z80;
+use kz80_microperl::Options;
 
 fn compile_and_run(code: &str) -> String {
-    // Use unique temp files per test to avoid race conditions
-    let id = TEST_COUNTER.fetch_add(1, Ordering::SeqCst);
-    let temp_src = format!("/tmp/microperl_test_{}.pl", id);
-    let temp_rom = format!("/tmp/microperl_test_{}.rom", id);
-
-    fs::write(&temp_src, code).expect("Failed to write test file");
-
-    // Compile
-    let compile_output = Command::new("./target/release/microperl")
-        .args(&[&temp_src, "--rom", &temp_rom])
-        .current_dir(env!("CARGO_MANIFEST_DIR"))
-        .output()
-        .expect("Failed to run compiler");
-
-    if !compile_output.status.success() {
-        panic!(
-            "Compilation failed: {}",
-            String::from_utf8_lossy(&compile_output.stderr)
-        );
+    let outcome = run_z80(code, &Options::default(), b"").unwrap_or_else(|e| panic!("Compilation failed: {}", e));
+    if !outcome.success() {
+        panic!("Run failed ({:?}): {}", outcome.status, outcome.stderr);
     }
-
-    // Run in emulator with timeout
-    let run_output = Command::new("timeout")
-        .args(&["2", "../emulator/retroshield", &temp_rom])
-        .current_dir(env!("CARGO_MANIFEST_DIR"))
-        .output()
-        .expect("Failed to run emulator");
-
-    // Cleanup temp files
-    let _ = fs::remove_file(&temp_src);
-    let _ = fs::remove_file(&temp_rom);
-
-    String::from_utf8_lossy(&run_output.stdout).to_string()
+    outcome.stdout
 }
 
 // === Core regex functionality tests ===
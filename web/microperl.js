@@ -0,0 +1,65 @@
+// MicroPerl in the browser
+//
+// Loads microperl.wasm (built with
+// `cargo build --lib --target wasm32-unknown-unknown --release
+// --no-default-features --features host-vm`) and wraps its exports:
+//
+//   const mpl = await MicroPerl.load("microperl.wasm");
+//   mpl.compile(source)           // { ok, diagnostics, size | rendered }
+//   mpl.disassemble(source)       // bytecode listing or rendered errors
+//   mpl.run(source, input, steps) // { ok, output, errors, exit, error, steps }
+
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+export class MicroPerl {
+  constructor(instance) {
+    this.exports = instance.exports;
+  }
+
+  static async load(url) {
+    const response = await fetch(url);
+    const { instance } = await WebAssembly.instantiateStreaming(response, {});
+    return new MicroPerl(instance);
+  }
+
+  compile(source) {
+    return JSON.parse(this.#call("mpl_compile", source));
+  }
+
+  disassemble(source) {
+    return this.#call("mpl_disassemble", source);
+  }
+
+  // steps of 0 uses the built-in limit of ten million instructions
+  run(source, input = "", steps = 0) {
+    return JSON.parse(this.#call("mpl_run", source, input, steps));
+  }
+
+  // Copy the strings into wasm memory, call `name` with (ptr, len) for
+  // each plus any numbers, and decode the result it leaves behind
+  #call(name, ...args) {
+    const buffers = [];
+    const params = [];
+    try {
+      for (const arg of args) {
+        if (typeof arg !== "string") {
+          params.push(arg);
+          continue;
+        }
+        const bytes = encoder.encode(arg);
+        const ptr = this.exports.mpl_alloc(bytes.length);
+        new Uint8Array(this.exports.memory.buffer, ptr, bytes.length).set(bytes);
+        buffers.push([ptr, bytes.length]);
+        params.push(ptr, bytes.length);
+      }
+      const len = this.exports[name](...params);
+      const ptr = this.exports.mpl_result();
+      return decoder.decode(new Uint8Array(this.exports.memory.buffer, ptr, len));
+    } finally {
+      for (const [ptr, len] of buffers) {
+        this.exports.mpl_free(ptr, len);
+      }
+    }
+  }
+}